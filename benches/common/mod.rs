@@ -0,0 +1,69 @@
+//! Shared benchmark support: a loopback target serving a RAM disk
+
+use iscsi_target::{IscsiClient, IscsiTarget, MemBlockDevice};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// A target serving a RAM disk on a free loopback port for the life of the value
+pub struct LoopbackTarget {
+    addr: SocketAddr,
+    target_name: String,
+    target: Arc<IscsiTarget<MemBlockDevice>>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LoopbackTarget {
+    /// Start a target with `blocks` 512-byte blocks
+    ///
+    /// The listener is bound to port 0 here and its connections handed to
+    /// the target, so the port cannot be taken by anyone else in between.
+    pub fn start(blocks: u64) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("no free loopback port");
+        let addr = listener.local_addr().expect("listener has no local address");
+        let target_name = "iqn.2025-12.bench:ramdisk".to_string();
+
+        let target = IscsiTarget::builder()
+            .bind_addr(&addr.to_string())
+            .target_name(&target_name)
+            .max_recv_data_segment_length(262144)
+            .build(MemBlockDevice::new(blocks, 512))
+            .expect("failed to build loopback target");
+        let target = Arc::new(target);
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let (runner, stop) = (Arc::clone(&target), Arc::clone(&stopping));
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = stream.expect("accept failed");
+                runner.serve_stream(stream).expect("loopback target failed");
+            }
+        });
+
+        Self { addr, target_name, target, stopping, thread: Some(thread) }
+    }
+
+    /// Connect and log in a new session
+    pub fn client(&self) -> IscsiClient {
+        let mut client = IscsiClient::connect(&self.addr.to_string()).expect("connect failed");
+        client.login("iqn.2025-12.bench:initiator", &self.target_name).expect("login failed");
+        client
+    }
+}
+
+impl Drop for LoopbackTarget {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.target.stop();
+        // Wake the accept loop so it sees the stop
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! End-to-end data path over loopback TCP against a RAM disk
+//!
+//! Covers login latency, 4 KiB random read/write IOPS and large sequential
+//! transfers. Run with `cargo bench --bench data_path`.
+
+mod common;
+
+use common::LoopbackTarget;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rand::Rng;
+
+/// RAM disk size: 64 MiB of 512-byte blocks
+const DISK_BLOCKS: u64 = 64 * 1024 * 1024 / 512;
+
+fn data_path(c: &mut Criterion) {
+    let target = LoopbackTarget::start(DISK_BLOCKS);
+
+    c.bench_function("login/normal_session", |b| {
+        b.iter(|| {
+            let mut client = target.client();
+            client.logout().expect("logout failed");
+        })
+    });
+
+    let mut client = target.client();
+    let mut rng = rand::thread_rng();
+    let mut io = c.benchmark_group("io");
+
+    // 4 KiB = 8 blocks, aligned to 4 KiB
+    let block = vec![0x5Au8; 4096];
+    let random_lba = |rng: &mut rand::rngs::ThreadRng| rng.gen_range(0..DISK_BLOCKS / 8) * 8;
+    io.throughput(Throughput::Bytes(4096));
+    io.bench_function("4k_random_write", |b| {
+        b.iter(|| client.write_blocks(random_lba(&mut rng), &block).expect("write failed"))
+    });
+    io.bench_function("4k_random_read", |b| {
+        b.iter(|| client.read_blocks(random_lba(&mut rng), 8).expect("read failed"))
+    });
+
+    // Sequential transfers, wrapping at the end of the disk. The client sends
+    // write data as immediate data only, so writes are capped at FirstBurstLength.
+    let chunk = vec![0xC3u8; 64 * 1024];
+    let chunk_blocks = (chunk.len() / 512) as u64;
+    let mut lba = 0u64;
+    io.throughput(Throughput::Bytes(chunk.len() as u64));
+    io.bench_function("sequential_write_64k", |b| {
+        b.iter(|| {
+            client.write_blocks(lba, &chunk).expect("write failed");
+            lba = (lba + chunk_blocks) % DISK_BLOCKS;
+        })
+    });
+    let read_blocks = 1024 * 1024 / 512;
+    let mut lba = 0u64;
+    io.throughput(Throughput::Bytes(1024 * 1024));
+    io.bench_function("sequential_read_1m", |b| {
+        b.iter(|| {
+            let data = client.read_blocks(lba, read_blocks as u32).expect("read failed");
+            lba = (lba + read_blocks) % DISK_BLOCKS;
+            data
+        })
+    });
+    io.finish();
+
+    client.logout().expect("logout failed");
+}
+
+criterion_group!(benches, data_path);
+criterion_main!(benches);
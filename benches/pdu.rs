@@ -0,0 +1,47 @@
+//! PDU parse and serialize throughput
+//!
+//! Run with `cargo bench --bench pdu`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use iscsi_target::pdu::{Digests, IscsiPdu, PduLimits};
+use std::hint::black_box;
+
+fn pdu(c: &mut Criterion) {
+    let limits = PduLimits::default();
+    let mut group = c.benchmark_group("pdu");
+
+    // A READ(10) SCSI Command: header only
+    let mut command = IscsiPdu::new();
+    command.opcode = iscsi_target::pdu::opcode::SCSI_COMMAND;
+    command.flags = 0x80 | 0x40;
+    command.itt = 1;
+    command.specific[0..4].copy_from_slice(&4096u32.to_be_bytes());
+    command.specific[12..22].copy_from_slice(&[0x28, 0, 0, 0, 0x10, 0, 0, 0, 8, 0]);
+    let command_bytes = command.to_bytes();
+
+    group.throughput(Throughput::Bytes(command_bytes.len() as u64));
+    group.bench_function("serialize/scsi_command", |b| b.iter(|| command.to_bytes()));
+    group.bench_function("parse/scsi_command", |b| {
+        b.iter(|| IscsiPdu::parse(black_box(&command_bytes), &limits).unwrap())
+    });
+
+    // Data-In PDUs carrying 8 KiB and 256 KiB
+    for size in [8 * 1024usize, 256 * 1024] {
+        let data_in = IscsiPdu::scsi_data_in(1, 0xFFFF_FFFF, 1, 1, 1, 0, 0, vec![0xA5; size], true, Some(0));
+        let bytes = data_in.to_bytes();
+        let kib = size / 1024;
+
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function(format!("serialize/data_in_{}k", kib), |b| b.iter(|| data_in.to_bytes()));
+        group.bench_function(format!("serialize/data_in_{}k_crc32c", kib), |b| {
+            b.iter(|| data_in.to_bytes_with_digests(Digests { header: true, data: true }))
+        });
+        group.bench_function(format!("parse/data_in_{}k", kib), |b| {
+            b.iter(|| IscsiPdu::parse(black_box(&bytes), &limits).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pdu);
+criterion_main!(benches);
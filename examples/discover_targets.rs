@@ -20,9 +20,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("No targets discovered");
     } else {
         println!("\nDiscovered {} target(s):", targets.len());
-        for (iqn, addr) in &targets {
-            println!("  TargetName: {}", iqn);
-            println!("  TargetAddress: {}", addr);
+        for target in &targets {
+            println!("  TargetName: {}", target.iqn);
+            for (addr, tpgt) in &target.portals {
+                println!("  TargetAddress: {},{}", addr, tpgt);
+            }
             println!();
         }
     }
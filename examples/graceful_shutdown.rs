@@ -4,55 +4,14 @@
 //! 1. Start an iSCSI target
 //! 2. Handle a shutdown signal (e.g., Ctrl+C)
 //! 3. Reject new logins with SERVICE_UNAVAILABLE
-//! 4. Allow existing sessions to complete
+//! 4. Ask existing sessions to log out, waiting up to a timeout
 //! 5. Stop the target cleanly
 
-use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+use iscsi_target::{IscsiTarget, MemBlockDevice};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-/// Simple in-memory storage
-struct MemoryStorage {
-    data: Vec<u8>,
-}
-
-impl MemoryStorage {
-    fn new(size_mb: usize) -> Self {
-        MemoryStorage {
-            data: vec![0u8; size_mb * 1024 * 1024],
-        }
-    }
-}
-
-impl ScsiBlockDevice for MemoryStorage {
-    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-        let offset = (lba * block_size as u64) as usize;
-        let len = (blocks * block_size) as usize;
-        if offset + len > self.data.len() {
-            return Err(iscsi_target::IscsiError::Scsi("Read out of bounds".into()));
-        }
-        Ok(self.data[offset..offset + len].to_vec())
-    }
-
-    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-        let offset = (lba * block_size as u64) as usize;
-        if offset + data.len() > self.data.len() {
-            return Err(iscsi_target::IscsiError::Scsi("Write out of bounds".into()));
-        }
-        self.data[offset..offset + data.len()].copy_from_slice(data);
-        Ok(())
-    }
-
-    fn capacity(&self) -> u64 {
-        (self.data.len() / 512) as u64
-    }
-
-    fn block_size(&self) -> u32 {
-        512
-    }
-}
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
@@ -73,7 +32,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!();
 
     // Create storage and target
-    let storage = MemoryStorage::new(100); // 100 MB
+    let storage = MemBlockDevice::new(100 * 2048, 512); // 100 MB
     let target = IscsiTarget::builder()
         .bind_addr(&bind_addr)
         .target_name("iqn.2025-12.local:storage.graceful-shutdown-demo")
@@ -119,20 +78,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Initiating graceful shutdown...");
     println!("===========================================");
     println!("- New logins will be rejected with SERVICE_UNAVAILABLE (0x0301)");
-    println!("- Existing sessions can continue to operate");
+    println!("- Existing sessions are asked to log out");
     println!();
 
-    target.shutdown_gracefully();
-
-    // Wait a bit for any sessions to complete
-    println!("Waiting 10 seconds for sessions to complete...");
-    thread::sleep(Duration::from_secs(10));
-
-    // Stop the target
-    println!("\n===========================================");
-    println!("Stopping target...");
-    println!("===========================================");
-    target.stop();
+    // Ask sessions to log out, then close whatever is left after 10 seconds
+    println!("Waiting up to 10 seconds for sessions to log out...");
+    let report = target.shutdown_and_wait(Duration::from_secs(10));
+    println!("  Logged out: {} session(s)", report.logged_out.len());
+    for session in &report.terminated {
+        println!("  Terminated: {} (TSIH {})", session.initiator_name, session.tsih);
+    }
 
     // Wait for target thread to finish
     let _ = target_thread.join();
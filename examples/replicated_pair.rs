@@ -0,0 +1,530 @@
+//! Active/passive replicated iSCSI target pair
+//!
+//! Runs one node of a two-node pair backed by image files:
+//!
+//! - The **primary** copies its whole image to the standby when the pair
+//!   forms, then serves iSCSI and synchronously mirrors every write and flush
+//!   to the standby before completing it to the initiator.
+//! - The **standby** applies the replication stream to its own image and does
+//!   not serve iSCSI. When the replication link drops or goes quiet it
+//!   promotes itself and starts serving the same target name from its copy
+//!   of the data.
+//!
+//! Initiators should log in to both portals (e.g. with dm-multipath in
+//! failover mode) so I/O moves to the standby once it takes over.
+//!
+//! ```text
+//! # node B (start first)
+//! cargo run --example replicated_pair -- standby 0.0.0.0:3260 0.0.0.0:3270 /var/lib/b.img
+//! # node A
+//! cargo run --example replicated_pair -- primary 0.0.0.0:3260 nodeb:3270 /var/lib/a.img
+//! ```
+//!
+//! # Fencing
+//!
+//! The primary only serves I/O while it holds a lease, renewed by every
+//! acknowledged frame (heartbeats keep it alive when idle). Once the link
+//! fails or the lease runs out, the primary fails every command with NOT
+//! READY and never serves again; the standby waits twice the lease before
+//! promoting, so the two never serve at once.
+//!
+//! Each node keeps an epoch next to its image (`<image>.epoch`), bumped on
+//! promotion. A standby refuses a primary with an older epoch than its own:
+//! that primary was failed over from and its image is stale. To rejoin it,
+//! restart it as the standby of the promoted node, which resyncs it.
+
+use iscsi_target::{IscsiError, IscsiTarget, ProtocolErrorKind, ScsiBlockDevice, ScsiDeviceError, ScsiResult};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TARGET_NAME: &str = "iqn.2025-12.local:storage.replicated-disk";
+const IMAGE_SIZE_MB: u64 = 100;
+const BLOCK_SIZE: u32 = 512;
+
+/// Replication frame opcodes
+const OP_HELLO: u8 = 0;
+const OP_WRITE: u8 = 1;
+const OP_FLUSH: u8 = 2;
+const OP_RESYNC: u8 = 3;
+const OP_HEARTBEAT: u8 = 4;
+/// Acknowledgement byte sent by the standby once a frame is applied
+const ACK: u8 = 0x06;
+/// Sent instead of ACK to a primary whose epoch is older than the standby's
+const NAK: u8 = 0x15;
+
+/// Largest data payload of one frame; larger writes are split
+const MAX_FRAME_DATA: usize = 1024 * 1024;
+/// How often an idle primary renews its lease
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long an acknowledged frame lets the primary keep serving
+const LEASE: Duration = Duration::from_secs(3);
+/// Silence on the link after which the standby promotes itself
+const PROMOTE_AFTER: Duration = Duration::from_secs(6);
+
+// ============================================================================
+// File-backed storage
+// ============================================================================
+
+/// Block device stored in a regular file
+struct FileStorage {
+    /// Locked for each access, since reads and writes move the file position
+    file: Mutex<File>,
+    blocks: u64,
+    block_size: u32,
+}
+
+impl FileStorage {
+    /// Open (or create) an image file of `size_mb` megabytes
+    fn open(path: &str, size_mb: u64, block_size: u32) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let size_bytes = size_mb * 1024 * 1024;
+        if file.metadata()?.len() < size_bytes {
+            file.set_len(size_bytes)?;
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+            blocks: size_bytes / block_size as u64,
+            block_size,
+        })
+    }
+
+    fn check_range(&self, lba: u64, bytes: usize) -> ScsiResult<u64> {
+        let offset = lba * self.block_size as u64;
+        if offset + bytes as u64 > self.blocks * self.block_size as u64 {
+            return Err(ScsiDeviceError::OutOfRange { lba }.into());
+        }
+        Ok(offset)
+    }
+}
+
+impl ScsiBlockDevice for FileStorage {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        let len = (blocks * block_size) as usize;
+        let offset = self.check_range(lba, len)?;
+        let mut buf = vec![0u8; len];
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], _block_size: u32) -> ScsiResult<()> {
+        let offset = self.check_range(lba, data.len())?;
+        let file = self.file.get_mut().unwrap_or_else(|e| e.into_inner());
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.blocks
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        self.file.get_mut().unwrap_or_else(|e| e.into_inner()).sync_data()?;
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Replication
+// ============================================================================
+
+/// Write one frame: `[op: u8][lba: u64][len: u32][data]` (big-endian)
+///
+/// `OP_HELLO` carries the sender's epoch in the LBA field.
+fn send_frame(stream: &mut TcpStream, op: u8, lba: u64, data: &[u8]) -> std::io::Result<()> {
+    let mut header = [0u8; 13];
+    header[0] = op;
+    header[1..9].copy_from_slice(&lba.to_be_bytes());
+    header[9..13].copy_from_slice(&(data.len() as u32).to_be_bytes());
+    stream.write_all(&header)?;
+    stream.write_all(data)
+}
+
+/// Read one frame, refusing payloads over `MAX_FRAME_DATA` before allocating
+fn recv_frame(stream: &mut TcpStream) -> std::io::Result<(u8, u64, Vec<u8>)> {
+    let mut header = [0u8; 13];
+    stream.read_exact(&mut header)?;
+    let lba = u64::from_be_bytes(header[1..9].try_into().unwrap());
+    let len = u32::from_be_bytes(header[9..13].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_DATA {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("replication frame of {} bytes exceeds {}", len, MAX_FRAME_DATA),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data)?;
+    Ok((header[0], lba, data))
+}
+
+/// The primary's end of the replication link
+struct Link {
+    /// None once the link has failed: the primary is fenced for good
+    stream: Option<TcpStream>,
+    /// The primary may serve I/O until then
+    lease_until: Instant,
+}
+
+impl Link {
+    /// Send a frame and wait for the ACK, renewing the lease
+    ///
+    /// Any failure fences the primary.
+    fn exchange(&mut self, op: u8, lba: u64, data: &[u8]) -> ScsiResult<()> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(ScsiDeviceError::NotReady.into());
+        };
+        // The lease runs from when the frame was sent: the standby has heard
+        // from us no earlier than that
+        let sent = Instant::now();
+        let result = send_frame(stream, op, lba, data).and_then(|()| {
+            let mut ack = [0u8; 1];
+            stream.read_exact(&mut ack)?;
+            match ack[0] {
+                ACK => Ok(()),
+                NAK => Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "standby has a newer epoch")),
+                _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad ACK from standby")),
+            }
+        });
+        match result {
+            Ok(()) => {
+                self.lease_until = sent + LEASE;
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Replication link lost ({}), fencing this node", e);
+                self.stream = None;
+                Err(ScsiDeviceError::NotReady.into())
+            }
+        }
+    }
+
+    /// Whether the primary may still serve I/O
+    fn check_lease(&mut self) -> ScsiResult<()> {
+        if self.stream.is_some() && Instant::now() >= self.lease_until {
+            log::error!("Replication lease expired, fencing this node");
+            self.stream = None;
+        }
+        match self.stream {
+            Some(_) => Ok(()),
+            None => Err(ScsiDeviceError::NotReady.into()),
+        }
+    }
+}
+
+/// Wraps a device and mirrors writes/flushes to a standby node
+///
+/// The standby replies to each frame with a single ACK byte once it has been
+/// applied. Nothing completes unreplicated: once the link fails every command
+/// fails with NOT READY.
+struct ReplicatedStorage<D: ScsiBlockDevice> {
+    local: D,
+    link: Arc<Mutex<Link>>,
+}
+
+impl<D: ScsiBlockDevice> ReplicatedStorage<D> {
+    /// Pair with the standby on `peer`: introduce ourselves with `epoch`,
+    /// copy the whole image across and start heartbeats
+    fn connect(local: D, peer: TcpStream, epoch: u64) -> ScsiResult<Self> {
+        peer.set_read_timeout(Some(LEASE))?;
+        peer.set_write_timeout(Some(LEASE))?;
+        let mut link = Link { stream: Some(peer), lease_until: Instant::now() + LEASE };
+        link.exchange(OP_HELLO, epoch, &[]).map_err(|_| {
+            IscsiError::Config(format!("standby refused epoch {}: this node's image is stale", epoch))
+        })?;
+
+        log::info!("Resynchronising {} blocks to the standby", local.capacity());
+        let block_size = local.block_size();
+        let chunk_blocks = (MAX_FRAME_DATA / block_size as usize) as u64;
+        let mut lba = 0;
+        while lba < local.capacity() {
+            let blocks = chunk_blocks.min(local.capacity() - lba) as u32;
+            let data = local.read(lba, blocks, block_size)?;
+            link.exchange(OP_RESYNC, lba, &data)?;
+            lba += blocks as u64;
+        }
+        link.exchange(OP_FLUSH, 0, &[])?;
+
+        let link = Arc::new(Mutex::new(link));
+        let heartbeat = Arc::downgrade(&link);
+        thread::spawn(move || loop {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            let Some(link) = heartbeat.upgrade() else { return };
+            let mut link = link.lock().unwrap_or_else(|e| e.into_inner());
+            if link.stream.is_none() || link.exchange(OP_HEARTBEAT, 0, &[]).is_err() {
+                return;
+            }
+        });
+        Ok(Self { local, link })
+    }
+
+    fn link(&self) -> std::sync::MutexGuard<'_, Link> {
+        self.link.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<D: ScsiBlockDevice> ScsiBlockDevice for ReplicatedStorage<D> {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        self.link().check_lease()?;
+        self.local.read(lba, blocks, block_size)
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        let mut link = self.link.lock().unwrap_or_else(|e| e.into_inner());
+        link.check_lease()?;
+        self.local.write(lba, data, block_size)?;
+        for (index, chunk) in data.chunks(MAX_FRAME_DATA).enumerate() {
+            let chunk_lba = lba + (index * MAX_FRAME_DATA / block_size as usize) as u64;
+            link.exchange(OP_WRITE, chunk_lba, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.local.capacity()
+    }
+
+    fn block_size(&self) -> u32 {
+        self.local.block_size()
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        let mut link = self.link.lock().unwrap_or_else(|e| e.into_inner());
+        link.check_lease()?;
+        self.local.flush()?;
+        link.exchange(OP_FLUSH, 0, &[])
+    }
+}
+
+/// Read the primary's HELLO, returning its epoch
+///
+/// A primary with an older epoch than ours was failed over from: it is
+/// refused with a NAK.
+fn accept_primary(stream: &mut TcpStream, epoch: u64) -> ScsiResult<u64> {
+    let (op, primary_epoch, _) = recv_frame(stream)?;
+    if op != OP_HELLO {
+        return Err(IscsiError::protocol(ProtocolErrorKind::UnexpectedPdu, format!("expected HELLO, got opcode {}", op)));
+    }
+    if primary_epoch < epoch {
+        stream.write_all(&[NAK])?;
+        return Err(IscsiError::Config(format!(
+            "primary epoch {} is older than ours ({}), refusing it",
+            primary_epoch, epoch
+        )));
+    }
+    stream.write_all(&[ACK])?;
+    Ok(primary_epoch)
+}
+
+/// Apply replication frames from the primary until the link drops or stays
+/// quiet for `PROMOTE_AFTER`
+fn apply_replication_stream<D: ScsiBlockDevice>(stream: &mut TcpStream, storage: &mut D) -> ScsiResult<()> {
+    stream.set_read_timeout(Some(PROMOTE_AFTER))?;
+    loop {
+        let (op, lba, data) = match recv_frame(stream) {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        match op {
+            OP_WRITE | OP_RESYNC => storage.write(lba, &data, storage.block_size())?,
+            OP_FLUSH => storage.flush()?,
+            OP_HEARTBEAT => {}
+            op => {
+                return Err(IscsiError::protocol(ProtocolErrorKind::UnexpectedPdu, format!("unknown replication opcode {}", op)));
+            }
+        }
+        stream.write_all(&[ACK])?;
+    }
+}
+
+/// The node's epoch, kept next to its image
+fn read_epoch(image: &str) -> u64 {
+    std::fs::read_to_string(format!("{}.epoch", image))
+        .ok()
+        .and_then(|text| text.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn write_epoch(image: &str, epoch: u64) -> std::io::Result<()> {
+    let path = format!("{}.epoch", image);
+    let mut file = File::create(&path)?;
+    writeln!(file, "{}", epoch)?;
+    file.sync_all()
+}
+
+// ============================================================================
+// Node roles
+// ============================================================================
+
+fn connect_to_standby(addr: &str) -> std::io::Result<TcpStream> {
+    let mut last_err = None;
+    for _ in 0..30 {
+        match TcpStream::connect(addr) {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => {
+                println!("Waiting for standby at {} ({})", addr, e);
+                last_err = Some(e);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+fn serve<D: ScsiBlockDevice + Send + 'static>(iscsi_addr: &str, device: D) -> Result<(), Box<dyn std::error::Error>> {
+    let target = IscsiTarget::builder()
+        .bind_addr(iscsi_addr)
+        .target_name(TARGET_NAME)
+        .build(device)?;
+
+    println!("Serving {} on {}", TARGET_NAME, iscsi_addr);
+    target.run()?;
+    println!("Target stopped gracefully");
+    Ok(())
+}
+
+fn run_primary(iscsi_addr: &str, standby_addr: &str, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let storage = FileStorage::open(image, IMAGE_SIZE_MB, BLOCK_SIZE)?;
+    let epoch = read_epoch(image);
+    let peer = connect_to_standby(standby_addr)?;
+    println!("Replicating to standby at {} (epoch {})", standby_addr, epoch);
+
+    serve(iscsi_addr, ReplicatedStorage::connect(storage, peer, epoch)?)
+}
+
+fn run_standby(iscsi_addr: &str, replication_addr: &str, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut storage = FileStorage::open(image, IMAGE_SIZE_MB, BLOCK_SIZE)?;
+    let mut epoch = read_epoch(image);
+    let listener = TcpListener::bind(replication_addr)?;
+    println!("Standby waiting for primary on {} (epoch {})", replication_addr, epoch);
+
+    let mut stream = loop {
+        let (mut stream, primary) = listener.accept()?;
+        match accept_primary(&mut stream, epoch) {
+            Ok(primary_epoch) => {
+                println!("Primary {} connected (epoch {}), applying replication stream", primary, primary_epoch);
+                epoch = primary_epoch;
+                write_epoch(image, epoch)?;
+                break stream;
+            }
+            Err(e) => eprintln!("Refusing primary {}: {}", primary, e),
+        }
+    };
+    drop(listener);
+
+    match apply_replication_stream(&mut stream, &mut storage) {
+        Ok(()) => println!("Primary closed the replication link"),
+        Err(e) => eprintln!("Replication link failed: {}", e),
+    }
+    drop(stream);
+
+    // The primary's lease has run out by now, or it saw the link fail
+    storage.flush()?;
+    epoch += 1;
+    write_epoch(image, epoch)?;
+    println!("Promoting standby to active (epoch {})", epoch);
+    serve(iscsi_addr, storage)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 5 {
+        eprintln!("Usage:");
+        eprintln!("  {} primary <iscsi-addr> <standby-replication-addr> <image>", args[0]);
+        eprintln!("  {} standby <iscsi-addr> <replication-listen-addr> <image>", args[0]);
+        std::process::exit(2);
+    }
+
+    match args[1].as_str() {
+        "primary" => run_primary(&args[2], &args[3], &args[4]),
+        "standby" => run_standby(&args[2], &args[3], &args[4]),
+        role => {
+            eprintln!("Unknown role '{}', expected 'primary' or 'standby'", role);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iscsi_target::MemBlockDevice;
+
+    /// Both ends of a replication link: (primary, standby)
+    fn link() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (standby, _) = listener.accept().unwrap();
+        (primary, standby)
+    }
+
+    #[test]
+    fn test_resync_then_promotion() {
+        let (primary_end, mut standby_end) = link();
+        let standby = thread::spawn(move || {
+            let mut storage = MemBlockDevice::new(4096, 512);
+            let epoch = accept_primary(&mut standby_end, 2).unwrap();
+            let result = apply_replication_stream(&mut standby_end, &mut storage);
+            (epoch, result.is_ok(), storage)
+        });
+
+        // Written before the pair formed: only the resync carries it across
+        let mut local = MemBlockDevice::new(4096, 512);
+        local.write(5, &[0xAB; 512], 512).unwrap();
+        let cut = primary_end.try_clone().unwrap();
+        let mut primary = ReplicatedStorage::connect(local, primary_end, 3).unwrap();
+
+        // Larger than one frame
+        let data = vec![0xCD; MAX_FRAME_DATA + 4096];
+        primary.write(1000, &data, 512).unwrap();
+        primary.flush().unwrap();
+
+        // The link fails: the primary is fenced, the standby promotes
+        cut.shutdown(std::net::Shutdown::Both).unwrap();
+        let (epoch, clean, storage) = standby.join().unwrap();
+        assert_eq!(epoch, 3);
+        assert!(clean);
+        assert!(primary.write(0, &[0xEE; 512], 512).is_err());
+        assert!(primary.read(5, 1, 512).is_err());
+
+        assert_eq!(storage.read(5, 1, 512).unwrap(), vec![0xAB; 512]);
+        assert_eq!(storage.read(1000, data.len() as u32 / 512, 512).unwrap(), data);
+        assert_eq!(storage.read(0, 1, 512).unwrap(), vec![0; 512]);
+    }
+
+    #[test]
+    fn test_stale_primary_is_refused() {
+        // The standby was promoted to epoch 4; the old primary is still at 3
+        let (primary_end, mut standby_end) = link();
+        let standby = thread::spawn(move || accept_primary(&mut standby_end, 4));
+        assert!(ReplicatedStorage::connect(MemBlockDevice::new(64, 512), primary_end, 3).is_err());
+        assert!(standby.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_oversized_frame_is_refused() {
+        let (mut primary_end, mut standby_end) = link();
+        let mut header = [0u8; 13];
+        header[0] = OP_WRITE;
+        header[9..13].copy_from_slice(&u32::MAX.to_be_bytes());
+        primary_end.write_all(&header).unwrap();
+        let mut storage = MemBlockDevice::new(64, 512);
+        assert!(apply_replication_stream(&mut standby_end, &mut storage).is_err());
+    }
+}
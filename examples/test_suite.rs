@@ -0,0 +1,111 @@
+//! Run the conformance test suite against a target
+//!
+//! ```text
+//! cargo run --features testsuite --example test_suite -- [options] <config_file>
+//! ```
+//!
+//! The config file is an INI file of the C `iscsi-test-suite` or
+//! `test-config.toml`. With `--internal` the suite tests an in-memory target
+//! in this process instead of connecting to the configured portal.
+
+use iscsi_target::testsuite::{SuiteConfig, TestStatus, TestSuite};
+use iscsi_target::{IscsiTarget, MemBlockDevice};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+const USAGE: &str = "Usage: test_suite [options] <config_file>
+
+Options:
+  -v, --verbose      Verbose output
+  -q, --quiet        Quiet mode (only show failures)
+  -f, --fail-fast    Stop on first failure
+  -c, --category CAT Run a category (discovery, commands, io, all) or test ID
+      --internal     Test an in-memory target instead of the configured portal
+  -h, --help         Show this help message";
+
+fn main() -> ExitCode {
+    env_logger::init();
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run() -> Result<bool, Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let (mut verbosity, mut fail_fast, mut internal) = (None, false, false);
+    let (mut categories, mut config_file) = (Vec::new(), None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-v" | "--verbose" => verbosity = Some(2),
+            "-q" | "--quiet" => verbosity = Some(0),
+            "-f" | "--fail-fast" => fail_fast = true,
+            "-c" | "--category" => categories.push(args.next().ok_or("--category needs a value")?),
+            "--internal" => internal = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(true);
+            }
+            _ if config_file.is_none() && !arg.starts_with('-') => config_file = Some(arg),
+            _ => return Err(format!("Unexpected argument {}", arg).into()),
+        }
+    }
+
+    let mut config = SuiteConfig::load(config_file.ok_or("Config file required")?)?;
+    config.verbosity = verbosity.unwrap_or(config.verbosity);
+    config.stop_on_fail |= fail_fast;
+
+    let mut suite = if internal {
+        let target = IscsiTarget::builder()
+            .target_name(&config.iqn)
+            .build(MemBlockDevice::new(32768, 512))?;
+        config.portal = "in-process".to_string();
+        TestSuite::for_target(config.clone(), Arc::new(target))
+    } else {
+        TestSuite::new(config.clone())
+    };
+    for category in &categories {
+        suite = suite.only(category)?;
+    }
+
+    println!("\niSCSI Target Test Suite");
+    println!("=======================");
+    println!("Target: {}", config.portal);
+    if !config.iqn.is_empty() {
+        println!("IQN: {}", config.iqn);
+    }
+    println!("LUN: {}", config.lun);
+
+    let mut category = "";
+    let report = suite.run(|result| {
+        let failed = matches!(result.status, TestStatus::Fail | TestStatus::Error);
+        if config.verbosity == 0 && !failed {
+            return;
+        }
+        if result.category != category {
+            category = result.category;
+            println!("\n[{}]", category);
+        }
+        if result.status == TestStatus::Pass && config.verbosity < 2 {
+            // Messages of passed tests are only shown verbosely
+            println!("{}", result.to_string().lines().next().unwrap_or_default());
+        } else {
+            println!("{}", result);
+        }
+    });
+    if config.stop_on_fail && !report.success() {
+        println!("\nStopping on first failure (stop_on_fail=true)");
+    }
+
+    println!("\n=======================");
+    println!("{}", report.summary());
+    if config.generate_report {
+        let path = report.write_report("reports")?;
+        println!("\nDetailed report saved to: {}", path.display());
+    }
+    Ok(report.success())
+}
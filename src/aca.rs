@@ -0,0 +1,135 @@
+//! Auto Contingent Allegiance (NormACA)
+//!
+//! When ACA is enabled, a command with the NACA bit set in its CDB control
+//! byte that ends in CHECK CONDITION puts its logical unit into ACA. Until
+//! the faulting I_T nexus clears it with the CLEAR ACA task management
+//! function, every command without the ACA task attribute is answered with
+//! ACA ACTIVE, and only the faulting nexus may send ACA tasks. A logical
+//! unit or target reset clears it too, as does the loss of the faulting nexus.
+
+use crate::pdu::task_attribute;
+use crate::scsi::{scsi_status, ScsiResponse};
+use std::sync::{Mutex, MutexGuard};
+
+/// Whether the NACA bit is set in the control byte of `cdb`
+///
+/// The CDB may be padded (to 16 bytes in the BHS), so the control byte is
+/// found from the length the operation code's group implies.
+pub(crate) fn naca(cdb: &[u8]) -> bool {
+    let control = match cdb.first().map(|opcode| opcode >> 5) {
+        Some(0) => 5,
+        Some(1 | 2) => 9,
+        // Variable-length CDBs carry the control byte second
+        Some(3) => 1,
+        Some(4) => 15,
+        Some(5) => 11,
+        _ => return false,
+    };
+    cdb.get(control).is_some_and(|control| control & 0x04 != 0)
+}
+
+/// ACA ACTIVE status, with no data or sense
+pub(crate) fn aca_active() -> ScsiResponse {
+    ScsiResponse {
+        status: scsi_status::ACA_ACTIVE,
+        data: Vec::new(),
+        sense: None,
+    }
+}
+
+/// ACA condition of one logical unit
+#[derive(Debug, Default)]
+pub(crate) struct AcaState {
+    /// The I_T nexus whose failed command established ACA
+    faulted: Mutex<Option<String>>,
+}
+
+impl AcaState {
+    fn lock(&self) -> MutexGuard<'_, Option<String>> {
+        self.faulted.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The I_T nexus the unit is in ACA for, if any
+    pub(crate) fn holder(&self) -> Option<String> {
+        self.lock().clone()
+    }
+
+    /// Enter ACA for a command from `nexus` that failed with NACA set
+    pub(crate) fn establish(&self, nexus: &str) {
+        log::info!("LUN entered ACA for {}", nexus);
+        *self.lock() = Some(nexus.to_string());
+    }
+
+    /// Whether a task from `nexus` with `attribute` must get ACA ACTIVE
+    pub(crate) fn blocks(&self, nexus: &str, attribute: u8) -> bool {
+        self.lock()
+            .as_deref()
+            .is_some_and(|holder| holder != nexus || attribute != task_attribute::ACA)
+    }
+
+    /// CLEAR ACA from `nexus`; only the faulting nexus can clear the condition
+    pub(crate) fn clear(&self, nexus: &str) {
+        let mut faulted = self.lock();
+        if faulted.as_deref() == Some(nexus) {
+            log::info!("ACA cleared by {}", nexus);
+            *faulted = None;
+        }
+    }
+
+    /// Leave ACA whoever established it (logical unit or target reset)
+    pub(crate) fn reset(&self) {
+        *self.lock() = None;
+    }
+
+    /// Leave ACA if `nexus` established it (I_T nexus loss)
+    pub(crate) fn release_nexus(&self, nexus: &str) {
+        let mut faulted = self.lock();
+        if faulted.as_deref() == Some(nexus) {
+            log::info!("ACA of {} cleared on nexus loss", nexus);
+            *faulted = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST_A: &str = "iqn.test:a,i,0x023d00000001";
+    const HOST_B: &str = "iqn.test:b,i,0x023d00000001";
+
+    #[test]
+    fn test_naca_bit() {
+        assert!(naca(&[0x00, 0, 0, 0, 0, 0x04]));
+        assert!(!naca(&[0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0]));
+        // Padded to 16 bytes, as taken from the BHS
+        assert!(naca(&[0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0x04, 0, 0, 0, 0, 0, 0]));
+        assert!(naca(&[0x7F, 0x04, 0, 0, 0, 0, 0, 0x18, 0, 0x09]));
+        assert!(!naca(&[]));
+    }
+
+    #[test]
+    fn test_aca_blocks_until_cleared() {
+        let aca = AcaState::default();
+        assert!(!aca.blocks(HOST_A, task_attribute::SIMPLE));
+
+        aca.establish(HOST_A);
+        assert!(aca.blocks(HOST_A, task_attribute::SIMPLE));
+        assert!(!aca.blocks(HOST_A, task_attribute::ACA), "the faulting nexus may send ACA tasks");
+        assert!(aca.blocks(HOST_B, task_attribute::ACA));
+
+        // Only the faulting nexus clears it, or its loss, or a reset
+        aca.clear(HOST_B);
+        assert_eq!(aca.holder().as_deref(), Some(HOST_A));
+        aca.clear(HOST_A);
+        assert_eq!(aca.holder(), None);
+        aca.establish(HOST_B);
+        aca.release_nexus(HOST_A);
+        assert!(aca.holder().is_some());
+        aca.release_nexus(HOST_B);
+        assert_eq!(aca.holder(), None);
+        aca.establish(HOST_B);
+        aca.reset();
+        assert!(!aca.blocks(HOST_A, task_attribute::SIMPLE));
+    }
+}
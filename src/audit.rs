@@ -0,0 +1,372 @@
+//! SCSI command auditing
+//!
+//! Register an `AuditSink` with the target builder to receive a record for
+//! every SCSI command the target completes: who sent it, what it addressed,
+//! how it ended and how long it took. Records are collected per connection
+//! and handed to the sink in batches, so the per-command cost is a table
+//! insert and a push onto a reused buffer. Without a sink nothing is tracked.
+//!
+//! With the `serde` feature `AuditRecord` implements `serde::Serialize`.
+
+use crate::lun;
+use crate::pdu::{flags, opcode, tmf_function, IscsiPdu};
+use crate::scsi::ScsiHandler;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Records collected before the batch is handed to the sink
+pub const AUDIT_BATCH_SIZE: usize = 64;
+
+/// A batch holding records older than this is handed over with the next completion
+pub const AUDIT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One completed SCSI command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// InitiatorName of the session that sent the command
+    pub initiator_name: Arc<str>,
+    /// LUN the command addressed
+    pub lun: u64,
+    /// CDB operation code
+    pub opcode: u8,
+    /// First LBA, for commands that address a block range
+    pub lba: Option<u64>,
+    /// Blocks addressed (0 when `lba` is None)
+    pub blocks: u32,
+    /// SCSI status returned to the initiator
+    pub status: u8,
+    /// Time from receiving the command to sending its status
+    pub latency: Duration,
+    /// When the status was sent
+    pub completed_at: SystemTime,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for AuditRecord {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let completed_at = self.completed_at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let mut record = serializer.serialize_struct("AuditRecord", 8)?;
+        record.serialize_field("initiator_name", &*self.initiator_name)?;
+        record.serialize_field("lun", &self.lun)?;
+        record.serialize_field("opcode", &self.opcode)?;
+        record.serialize_field("lba", &self.lba)?;
+        record.serialize_field("blocks", &self.blocks)?;
+        record.serialize_field("status", &self.status)?;
+        record.serialize_field("latency_us", &(self.latency.as_micros() as u64))?;
+        record.serialize_field("completed_at_us", &(completed_at.as_micros() as u64))?;
+        record.end()
+    }
+}
+
+/// Receiver for audit records
+///
+/// Called from connection threads with a batch of records at a time, at
+/// most `AUDIT_BATCH_SIZE` long. The slice is reused afterwards, so clone
+/// anything that must outlive the call.
+pub trait AuditSink: Send + Sync {
+    /// Handle a batch of completed commands, oldest first
+    fn record(&self, records: &[AuditRecord]);
+}
+
+impl fmt::Debug for dyn AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuditSink")
+    }
+}
+
+/// A command received but not yet answered
+struct InFlight {
+    lun: u64,
+    opcode: u8,
+    lba: Option<u64>,
+    blocks: u32,
+    received: Instant,
+}
+
+/// A task management request not yet answered
+struct PendingTmf {
+    function: u8,
+    lun: u64,
+    referenced_itt: u32,
+}
+
+/// Per-connection audit state: commands in flight and the pending batch
+///
+/// A command leaves `in_flight` when its status is sent, when a Reject
+/// ends it, or when a task management function that aborts it completes;
+/// aborted and rejected commands are not recorded.
+pub(crate) struct AuditLog {
+    sink: Arc<dyn AuditSink>,
+    initiator_name: Arc<str>,
+    in_flight: HashMap<u32, InFlight>,
+    tmfs: HashMap<u32, PendingTmf>,
+    batch: Vec<AuditRecord>,
+    batch_started: Option<Instant>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(sink: Arc<dyn AuditSink>, initiator_name: &str) -> Self {
+        Self {
+            sink,
+            initiator_name: Arc::from(initiator_name),
+            in_flight: HashMap::new(),
+            tmfs: HashMap::new(),
+            batch: Vec::with_capacity(AUDIT_BATCH_SIZE),
+            batch_started: None,
+        }
+    }
+
+    /// Note a PDU from the initiator; SCSI Commands start being timed
+    pub(crate) fn received(&mut self, pdu: &IscsiPdu) {
+        if pdu.opcode == opcode::TASK_MANAGEMENT_REQUEST {
+            self.tmfs.insert(pdu.itt, PendingTmf {
+                function: pdu.flags & 0x7F,
+                lun: pdu.lun,
+                referenced_itt: u32::from_be_bytes([pdu.specific[0], pdu.specific[1], pdu.specific[2], pdu.specific[3]]),
+            });
+            return;
+        }
+        if pdu.opcode != opcode::SCSI_COMMAND {
+            return;
+        }
+        // The first 16 CDB bytes are in the BHS, enough for the opcode and LBA range
+        let cdb = &pdu.specific[12..28];
+        let range = ScsiHandler::lba_range(cdb);
+        self.in_flight.insert(pdu.itt, InFlight {
+            lun: pdu.lun,
+            opcode: cdb[0],
+            lba: range.map(|(lba, _)| lba),
+            blocks: range.map_or(0, |(_, blocks)| blocks),
+            received: Instant::now(),
+        });
+    }
+
+    /// Note a PDU sent to the initiator; one carrying SCSI status completes its command
+    pub(crate) fn sent(&mut self, pdu: &IscsiPdu) {
+        let status = match pdu.opcode {
+            opcode::SCSI_RESPONSE => pdu.specific[1],
+            opcode::SCSI_DATA_IN if pdu.flags & flags::STATUS != 0 => pdu.version_or_reserved as u8,
+            opcode::TASK_MANAGEMENT_RESPONSE => return self.tmf_answered(pdu),
+            // A rejected SCSI Command ends there; its header comes back as the data
+            opcode::REJECT if pdu.data.len() >= 20 && pdu.data[0] & 0x3F == opcode::SCSI_COMMAND => {
+                self.in_flight.remove(&u32::from_be_bytes([pdu.data[16], pdu.data[17], pdu.data[18], pdu.data[19]]));
+                return;
+            }
+            _ => return,
+        };
+        let Some(command) = self.in_flight.remove(&pdu.itt) else { return };
+
+        let now = Instant::now();
+        self.batch.push(AuditRecord {
+            initiator_name: Arc::clone(&self.initiator_name),
+            lun: command.lun,
+            opcode: command.opcode,
+            lba: command.lba,
+            blocks: command.blocks,
+            status,
+            latency: now.duration_since(command.received),
+            completed_at: SystemTime::now(),
+        });
+        let batch_started = *self.batch_started.get_or_insert(now);
+        if self.batch.len() >= AUDIT_BATCH_SIZE || now.duration_since(batch_started) >= AUDIT_FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Forget the commands a completed task management function aborted
+    ///
+    /// Commands answered before the response are already recorded; those
+    /// left will get no status.
+    fn tmf_answered(&mut self, pdu: &IscsiPdu) {
+        let Some(tmf) = self.tmfs.remove(&pdu.itt) else { return };
+        // Response (byte 2) 0: function complete
+        if pdu.version_or_reserved >> 8 != 0 {
+            return;
+        }
+        match tmf.function {
+            tmf_function::ABORT_TASK => {
+                self.in_flight.remove(&tmf.referenced_itt);
+            }
+            tmf_function::ABORT_TASK_SET | tmf_function::CLEAR_TASK_SET | tmf_function::LOGICAL_UNIT_RESET => {
+                let lun = lun::decode_lun(tmf.lun);
+                self.in_flight.retain(|_, command| lun::decode_lun(command.lun) != lun);
+            }
+            tmf_function::TARGET_WARM_RESET | tmf_function::TARGET_COLD_RESET => self.in_flight.clear(),
+            _ => {}
+        }
+    }
+
+    /// Hand the pending batch to the sink
+    pub(crate) fn flush(&mut self) {
+        if !self.batch.is_empty() {
+            self.sink.record(&self.batch);
+            self.batch.clear();
+        }
+        self.batch_started = None;
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("initiator_name", &self.initiator_name)
+            .field("in_flight", &self.in_flight.len())
+            .field("pending", &self.batch.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdu::scsi_status;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        batches: Mutex<Vec<Vec<AuditRecord>>>,
+    }
+
+    impl AuditSink for RecordingSink {
+        fn record(&self, records: &[AuditRecord]) {
+            self.batches.lock().unwrap().push(records.to_vec());
+        }
+    }
+
+    fn command(itt: u32, cdb: &[u8]) -> IscsiPdu {
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::SCSI_COMMAND;
+        pdu.itt = itt;
+        pdu.specific[12..12 + cdb.len()].copy_from_slice(cdb);
+        pdu
+    }
+
+    #[test]
+    fn test_audit_log_records_completions() {
+        let sink = Arc::new(RecordingSink::default());
+        let mut log = AuditLog::new(sink.clone(), "iqn.test:host");
+
+        // READ(10) of 8 blocks at LBA 32, answered with Data-In carrying status
+        log.received(&command(1, &[0x28, 0, 0, 0, 0, 32, 0, 0, 8, 0]));
+        // TEST UNIT READY, answered with CHECK CONDITION
+        log.received(&command(2, &[0x00, 0, 0, 0, 0, 0]));
+
+        log.sent(&IscsiPdu::scsi_data_in(1, 0xFFFF_FFFF, 0, 0, 0, 0, 0, vec![0; 512], false, None));
+        log.sent(&IscsiPdu::scsi_response(2, 1, 0, 0, scsi_status::CHECK_CONDITION, 0, 0, None));
+        log.sent(&IscsiPdu::scsi_data_in(1, 0xFFFF_FFFF, 2, 0, 0, 1, 512, vec![0; 512], true, Some(scsi_status::GOOD)));
+        // Status for a command never seen is ignored
+        log.sent(&IscsiPdu::scsi_response(9, 3, 0, 0, scsi_status::GOOD, 0, 0, None));
+
+        assert!(sink.batches.lock().unwrap().is_empty(), "records are batched");
+        drop(log);
+
+        let batches = sink.batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        let records = &batches[0];
+        assert_eq!(records.len(), 2);
+        assert_eq!(&*records[0].initiator_name, "iqn.test:host");
+        assert_eq!((records[0].opcode, records[0].lba, records[0].blocks), (0x00, None, 0));
+        assert_eq!(records[0].status, scsi_status::CHECK_CONDITION);
+        assert_eq!((records[1].opcode, records[1].lba, records[1].blocks), (0x28, Some(32), 8));
+        assert_eq!(records[1].status, scsi_status::GOOD);
+    }
+
+    #[test]
+    fn test_audit_log_flushes_full_batches() {
+        let sink = Arc::new(RecordingSink::default());
+        let mut log = AuditLog::new(sink.clone(), "iqn.test:host");
+
+        for itt in 0..AUDIT_BATCH_SIZE as u32 + 1 {
+            log.received(&command(itt, &[0x00, 0, 0, 0, 0, 0]));
+            log.sent(&IscsiPdu::scsi_response(itt, itt, 0, 0, scsi_status::GOOD, 0, 0, None));
+        }
+        assert_eq!(sink.batches.lock().unwrap().len(), 1);
+        assert_eq!(sink.batches.lock().unwrap()[0].len(), AUDIT_BATCH_SIZE);
+
+        log.flush();
+        assert_eq!(sink.batches.lock().unwrap()[1].len(), 1);
+    }
+
+    #[test]
+    fn test_audit_log_forgets_ended_commands() {
+        let sink = Arc::new(RecordingSink::default());
+        let mut log = AuditLog::new(sink.clone(), "iqn.test:host");
+        let tmf = |itt: u32, function: u8, lun: u64, referenced_itt: u32| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::TASK_MANAGEMENT_REQUEST;
+            pdu.flags = flags::FINAL | function;
+            pdu.itt = itt;
+            pdu.lun = lun;
+            pdu.specific[0..4].copy_from_slice(&referenced_itt.to_be_bytes());
+            pdu
+        };
+        let tmf_response = |itt: u32| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::TASK_MANAGEMENT_RESPONSE;
+            pdu.itt = itt;
+            pdu
+        };
+
+        for itt in 1..=4 {
+            log.received(&command(itt, &[0x00, 0, 0, 0, 0, 0]));
+        }
+        let mut lun1 = command(5, &[0x00, 0, 0, 0, 0, 0]);
+        lun1.lun = lun::encode_lun(1);
+        log.received(&lun1);
+
+        // ABORT TASK takes effect when it completes, not when it arrives
+        log.received(&tmf(10, tmf_function::ABORT_TASK, 0, 1));
+        log.sent(&IscsiPdu::scsi_response(2, 1, 0, 0, scsi_status::GOOD, 0, 0, None));
+        assert_eq!(log.in_flight.len(), 4);
+        log.sent(&tmf_response(10));
+        assert!(!log.in_flight.contains_key(&1));
+
+        // A Reject of the command's header ends it
+        let header = command(3, &[0x00, 0, 0, 0, 0, 0]).to_bytes();
+        log.sent(&IscsiPdu::reject(crate::pdu::reject_reason::PROTOCOL_ERROR, 2, 0, 0, &header[..48]));
+        assert!(!log.in_flight.contains_key(&3));
+
+        // LOGICAL UNIT RESET ends only the tasks of its LUN
+        log.received(&tmf(11, tmf_function::LOGICAL_UNIT_RESET, lun::encode_lun(1), 0));
+        log.sent(&tmf_response(11));
+        assert_eq!(log.in_flight.keys().copied().collect::<Vec<_>>(), vec![4]);
+
+        log.received(&tmf(12, tmf_function::TARGET_WARM_RESET, 0, 0));
+        log.sent(&tmf_response(12));
+        assert!(log.in_flight.is_empty() && log.tmfs.is_empty());
+
+        // Only the command answered with status is recorded
+        drop(log);
+        let batches = sink.batches.lock().unwrap();
+        assert_eq!(batches.iter().map(Vec::len).sum::<usize>(), 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_audit_record_serializes() {
+        let record = AuditRecord {
+            initiator_name: Arc::from("iqn.test:host"),
+            lun: 0,
+            opcode: 0x28,
+            lba: Some(32),
+            blocks: 8,
+            status: scsi_status::GOOD,
+            latency: Duration::from_micros(250),
+            completed_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+        };
+        let text = toml::to_string(&record).unwrap();
+        assert!(text.contains("initiator_name = \"iqn.test:host\""));
+        assert!(text.contains("latency_us = 250"));
+        assert!(text.contains("completed_at_us = 1000000"));
+    }
+}
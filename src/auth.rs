@@ -2,27 +2,96 @@
 //!
 //! RFC 3720 Section 8.2 - CHAP Algorithm
 
-use crate::error::{IscsiError, ScsiResult};
+use crate::error::{AuthFailure, IscsiError, ScsiResult};
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
 use rand::Rng;
-
-/// CHAP algorithm identifier (RFC 1994)
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use sha2::Digest;
+use std::collections::HashMap;
+use std::fmt;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// CHAP algorithm identifier (RFC 1994, RFC 7143 updates)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ChapAlgorithm {
     /// MD5 algorithm (algorithm identifier 5)
     Md5 = 5,
+    /// SHA-256 algorithm (algorithm identifier 7)
+    Sha256 = 7,
+    /// SHA3-256 algorithm (algorithm identifier 8)
+    Sha3_256 = 8,
+}
+
+impl FromStr for ChapAlgorithm {
+    type Err = IscsiError;
+
+    /// Parse a CHAP_A value
+    fn from_str(s: &str) -> ScsiResult<Self> {
+        match s.trim() {
+            "5" => Ok(ChapAlgorithm::Md5),
+            "7" => Ok(ChapAlgorithm::Sha256),
+            "8" => Ok(ChapAlgorithm::Sha3_256),
+            _ => Err(IscsiError::auth(
+                AuthFailure::MethodNotAccepted,
+                format!("Unsupported CHAP algorithm: CHAP_A={}", s),
+            )),
+        }
+    }
 }
 
 impl ChapAlgorithm {
-    pub fn from_str(s: &str) -> Option<Self> {
-        match s {
-            "5" => Some(ChapAlgorithm::Md5),
-            _ => None,
+    /// Every supported algorithm, strongest first
+    pub const ALL: [ChapAlgorithm; 3] = [ChapAlgorithm::Sha3_256, ChapAlgorithm::Sha256, ChapAlgorithm::Md5];
+
+    /// CHAP_A value for this algorithm
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// Digest length in bytes
+    pub fn digest_len(self) -> usize {
+        match self {
+            ChapAlgorithm::Md5 => 16,
+            ChapAlgorithm::Sha256 | ChapAlgorithm::Sha3_256 => 32,
         }
     }
+
+    /// Hash `data` with this algorithm
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ChapAlgorithm::Md5 => md5::compute(data).0.to_vec(),
+            ChapAlgorithm::Sha256 => sha2::Sha256::digest(data).to_vec(),
+            ChapAlgorithm::Sha3_256 => sha3::Sha3_256::digest(data).to_vec(),
+        }
+    }
+
+    /// Pick the first algorithm of an initiator's CHAP_A list that is allowed
+    ///
+    /// The list is in the initiator's order of preference (RFC 3720 Section
+    /// 5.2.2). Unknown values are skipped, as are algorithms not in `allowed`.
+    pub fn negotiate(offered: &str, allowed: &[ChapAlgorithm]) -> Option<Self> {
+        offered.split(',')
+            .filter_map(|value| value.parse().ok())
+            .find(|algorithm| allowed.contains(algorithm))
+    }
 }
 
+/// Minimum CHAP secret length in bytes (RFC 3720 Section 8.2.1: 96 bits)
+pub const MIN_CHAP_SECRET_LEN: usize = 12;
+
+/// Plain-text secrets shorter than this trigger a weak-secret warning
+const WEAK_ASCII_SECRET_LEN: usize = 16;
+
 /// CHAP credentials for authentication
+///
+/// The secret may be plain text or an RFC 3720 binary value: `0x` followed by
+/// hex digits, or `0b` followed by base64.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ChapCredentials {
     /// Username for CHAP authentication
     pub username: String,
@@ -37,12 +106,192 @@ impl ChapCredentials {
             secret: secret.into(),
         }
     }
+
+    /// Create credentials with a binary secret
+    pub fn from_bytes(username: impl Into<String>, secret: &[u8]) -> Self {
+        Self::new(username, format!("0x{}", hex::encode(secret)))
+    }
+
+    /// Create credentials from a hex-encoded secret (with or without `0x`)
+    pub fn from_hex(username: impl Into<String>, secret: &str) -> ScsiResult<Self> {
+        let digits = secret.strip_prefix("0x").or_else(|| secret.strip_prefix("0X")).unwrap_or(secret);
+        let bytes = hex::decode(digits).map_err(|e| {
+            IscsiError::Config(format!("Invalid hex CHAP secret: {}", e))
+        })?;
+        Ok(Self::from_bytes(username, &bytes))
+    }
+
+    /// Create credentials from a base64-encoded secret (with or without `0b`)
+    pub fn from_base64(username: impl Into<String>, secret: &str) -> ScsiResult<Self> {
+        let digits = secret.strip_prefix("0b").or_else(|| secret.strip_prefix("0B")).unwrap_or(secret);
+        let bytes = BASE64.decode(digits).map_err(|e| {
+            IscsiError::Config(format!("Invalid base64 CHAP secret: {}", e))
+        })?;
+        Ok(Self::from_bytes(username, &bytes))
+    }
+
+    /// Secret bytes as used in the CHAP response calculation
+    pub fn secret_bytes(&self) -> Vec<u8> {
+        chap_secret_bytes(&self.secret)
+    }
+
+    /// Check the credentials against RFC 3720 recommendations
+    ///
+    /// Errors for an empty username, undecodable or short (< 12 byte) secrets,
+    /// and secrets equal to the username. Short plain-text secrets only log a
+    /// warning.
+    pub fn validate(&self) -> ScsiResult<()> {
+        if self.username.is_empty() {
+            return Err(IscsiError::Config("CHAP username must not be empty".to_string()));
+        }
+
+        let secret = decode_chap_secret(&self.secret)?;
+        if secret.len() < MIN_CHAP_SECRET_LEN {
+            return Err(IscsiError::Config(format!(
+                "CHAP secret for '{}' is {} bytes; RFC 3720 requires at least {}",
+                self.username, secret.len(), MIN_CHAP_SECRET_LEN
+            )));
+        }
+        if secret == self.username.as_bytes() {
+            return Err(IscsiError::Config(format!(
+                "CHAP secret for '{}' must not equal the username", self.username
+            )));
+        }
+
+        let is_encoded = secret != self.secret.as_bytes();
+        if !is_encoded && secret.is_ascii() && secret.len() < WEAK_ASCII_SECRET_LEN {
+            log::warn!(
+                "CHAP secret for '{}' is a short plain-text string ({} bytes); consider {}+ bytes or a random hex secret",
+                self.username, secret.len(), WEAK_ASCII_SECRET_LEN
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Decode a CHAP secret string into bytes
+///
+/// RFC 3720 Section 5.1 binary values: `0x`/`0X` prefix = hex, `0b`/`0B`
+/// prefix = base64. Anything else is used as-is.
+pub fn decode_chap_secret(secret: &str) -> ScsiResult<Vec<u8>> {
+    if let Some(digits) = secret.strip_prefix("0x").or_else(|| secret.strip_prefix("0X")) {
+        hex::decode(digits).map_err(|e| {
+            IscsiError::Config(format!("Invalid hex CHAP secret: {}", e))
+        })
+    } else if let Some(digits) = secret.strip_prefix("0b").or_else(|| secret.strip_prefix("0B")) {
+        BASE64.decode(digits).map_err(|e| {
+            IscsiError::Config(format!("Invalid base64 CHAP secret: {}", e))
+        })
+    } else {
+        Ok(secret.as_bytes().to_vec())
+    }
+}
+
+/// Decode a secret for hashing, treating undecodable values as plain text
+fn chap_secret_bytes(secret: &str) -> Vec<u8> {
+    decode_chap_secret(secret).unwrap_or_else(|_| secret.as_bytes().to_vec())
+}
+
+/// Standard base64, padded or not
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Source of CHAP secrets used to authenticate initiators
+///
+/// Implement this to look up per-initiator accounts from an external store
+/// (database, vault, HSM). `verify` can be overridden for stores that never
+/// hand out plaintext secrets; the default implementation computes the
+/// expected response from `secret()`.
+pub trait ChapSecretProvider: Send + Sync {
+    /// Get the secret for a CHAP username (CHAP_N), or None if the account is unknown
+    fn secret(&self, username: &str) -> Option<String>;
+
+    /// Check whether an account exists for this username
+    fn contains(&self, username: &str) -> bool {
+        self.secret(username).is_some()
+    }
+
+    /// Verify an initiator's CHAP response against the issued challenge
+    fn verify(&self, username: &str, state: &ChapAuthState, response: &[u8]) -> bool {
+        match self.secret(username) {
+            Some(secret) => state.validate_response(response, &secret),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for dyn ChapSecretProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ChapSecretProvider")
+    }
+}
+
+/// A single set of credentials acts as a one-account provider
+impl ChapSecretProvider for ChapCredentials {
+    fn secret(&self, username: &str) -> Option<String> {
+        (username == self.username).then(|| self.secret.clone())
+    }
+}
+
+/// In-memory table of initiator CHAP accounts (username -> secret)
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChapAccounts {
+    accounts: HashMap<String, String>,
+}
+
+impl ChapAccounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace an account
+    pub fn add(&mut self, username: impl Into<String>, secret: impl Into<String>) {
+        self.accounts.insert(username.into(), secret.into());
+    }
+
+    /// Remove an account, returning true if it existed
+    pub fn remove(&mut self, username: &str) -> bool {
+        self.accounts.remove(username).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// Validate every account (see `ChapCredentials::validate`)
+    pub fn validate(&self) -> ScsiResult<()> {
+        let mut usernames: Vec<&String> = self.accounts.keys().collect();
+        usernames.sort();
+        for username in usernames {
+            ChapCredentials::new(username.as_str(), self.accounts[username].as_str()).validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl ChapSecretProvider for ChapAccounts {
+    fn secret(&self, username: &str) -> Option<String> {
+        self.accounts.get(username).cloned()
+    }
 }
 
 /// Authentication configuration
-#[derive(Debug, Clone)]
+///
+/// With the `serde` feature the CHAP secrets are serialized in the clear.
+/// `ChapProvider` holds a live provider and cannot be serialized.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AuthConfig {
     /// No authentication required
+    #[default]
     None,
     /// CHAP authentication (one-way: initiator authenticates to target)
     Chap {
@@ -56,12 +305,14 @@ pub enum AuthConfig {
         /// Initiator credentials (for target to prove identity)
         initiator_credentials: ChapCredentials,
     },
-}
-
-impl Default for AuthConfig {
-    fn default() -> Self {
-        AuthConfig::None
-    }
+    /// CHAP with initiator accounts looked up through a secret provider
+    #[cfg_attr(feature = "serde", serde(skip))]
+    ChapProvider {
+        /// Source of initiator account secrets
+        provider: Arc<dyn ChapSecretProvider>,
+        /// Credentials the target presents for mutual CHAP (None = one-way)
+        mutual_credentials: Option<ChapCredentials>,
+    },
 }
 
 impl AuthConfig {
@@ -74,13 +325,98 @@ impl AuthConfig {
     pub fn auth_method(&self) -> &str {
         match self {
             AuthConfig::None => "None",
-            AuthConfig::Chap { .. } | AuthConfig::MutualChap { .. } => "CHAP",
+            AuthConfig::Chap { .. } | AuthConfig::MutualChap { .. } | AuthConfig::ChapProvider { .. } => "CHAP",
         }
     }
 
     /// Check if mutual CHAP is required
     pub fn is_mutual(&self) -> bool {
-        matches!(self, AuthConfig::MutualChap { .. })
+        self.mutual_credentials().is_some()
+    }
+
+    /// Validate configured credentials (see `ChapCredentials::validate`)
+    ///
+    /// For mutual CHAP the two directions must also use different secrets
+    /// (RFC 3720 Section 8.2.1). Secrets behind a custom provider cannot be
+    /// checked here.
+    pub fn validate(&self) -> ScsiResult<()> {
+        match self {
+            AuthConfig::None => Ok(()),
+            AuthConfig::Chap { credentials } => credentials.validate(),
+            AuthConfig::MutualChap { target_credentials, initiator_credentials } => {
+                target_credentials.validate()?;
+                initiator_credentials.validate()?;
+                if target_credentials.secret_bytes() == initiator_credentials.secret_bytes() {
+                    return Err(IscsiError::Config(
+                        "Mutual CHAP must use different secrets in each direction".to_string()
+                    ));
+                }
+                Ok(())
+            }
+            AuthConfig::ChapProvider { mutual_credentials, .. } => {
+                mutual_credentials.as_ref().map_or(Ok(()), ChapCredentials::validate)
+            }
+        }
+    }
+
+    /// Get the provider used to validate initiator CHAP responses
+    pub fn secret_provider(&self) -> Option<&dyn ChapSecretProvider> {
+        match self {
+            AuthConfig::None => None,
+            AuthConfig::Chap { credentials } => Some(credentials),
+            AuthConfig::MutualChap { target_credentials, .. } => Some(target_credentials),
+            AuthConfig::ChapProvider { provider, .. } => Some(provider.as_ref()),
+        }
+    }
+
+    /// Get the credentials the target answers with when challenged (mutual CHAP)
+    pub fn mutual_credentials(&self) -> Option<&ChapCredentials> {
+        match self {
+            AuthConfig::MutualChap { initiator_credentials, .. } => Some(initiator_credentials),
+            AuthConfig::ChapProvider { mutual_credentials, .. } => mutual_credentials.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Challenges a target has issued in CHAP exchanges still in progress
+///
+/// Shared by every connection of the target, so a challenge issued on one
+/// connection cannot be reflected back to the target on another.
+#[derive(Debug, Default)]
+pub(crate) struct ChallengeRegistry {
+    /// Live exchanges, keyed by exchange
+    exchanges: Mutex<BTreeMap<u64, LiveExchange>>,
+    /// Source of `exchanges` keys
+    next_exchange: AtomicU64,
+}
+
+impl ChallengeRegistry {
+    fn exchanges(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, LiveExchange>> {
+        self.exchanges.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A target CHAP exchange in a `ChallengeRegistry`
+#[derive(Debug)]
+struct LiveExchange {
+    /// Current CHAP identifier
+    identifier: u8,
+    /// Every challenge issued in the exchange
+    challenges: Vec<Vec<u8>>,
+}
+
+/// Keeps an exchange's challenges live until the last clone of its
+/// `ChapAuthState` is dropped
+#[derive(Debug)]
+struct LiveRegistration {
+    registry: Arc<ChallengeRegistry>,
+    exchange: u64,
+}
+
+impl Drop for LiveRegistration {
+    fn drop(&mut self) {
+        self.registry.exchanges().remove(&self.exchange);
     }
 }
 
@@ -93,36 +429,132 @@ pub struct ChapAuthState {
     pub challenge: Vec<u8>,
     /// Whether this is for mutual CHAP (target authenticating to initiator)
     pub is_target_auth: bool,
+    /// Hash algorithm negotiated through CHAP_A
+    pub algorithm: ChapAlgorithm,
+    /// Every challenge issued with this state, including the current one
+    pub issued_challenges: Vec<Vec<u8>>,
+    /// Entry in the target's `ChallengeRegistry` for challenges it issues
+    registration: Option<Arc<LiveRegistration>>,
 }
 
 impl ChapAuthState {
-    /// Generate a new CHAP challenge
+    /// Generate a new MD5 CHAP challenge
     pub fn new(is_target_auth: bool) -> Self {
-        let mut rng = rand::thread_rng();
+        Self::with_algorithm(is_target_auth, ChapAlgorithm::Md5)
+    }
 
-        // Generate random identifier
-        let identifier = rng.gen::<u8>();
+    /// Generate a new CHAP challenge for `algorithm`
+    ///
+    /// The challenge is at least 16 bytes and never shorter than the digest.
+    /// A target challenge (`is_target_auth == false`) made this way is only
+    /// kept apart from the state's own earlier challenges; targets use
+    /// `registered` to keep their connections' exchanges apart.
+    pub fn with_algorithm(is_target_auth: bool, algorithm: ChapAlgorithm) -> Self {
+        let registry = (!is_target_auth).then(Arc::default);
+        Self::issue(is_target_auth, algorithm, registry)
+    }
 
-        // Generate random challenge (16-256 bytes, we'll use 16 for simplicity)
-        let mut challenge = vec![0u8; 16];
-        rng.fill(&mut challenge[..]);
+    /// Generate a new target CHAP challenge for `algorithm`, registered with
+    /// the target's other live exchanges
+    pub(crate) fn registered(registry: &Arc<ChallengeRegistry>, algorithm: ChapAlgorithm) -> Self {
+        Self::issue(false, algorithm, Some(registry.clone()))
+    }
 
-        Self {
-            identifier,
-            challenge,
+    fn issue(is_target_auth: bool, algorithm: ChapAlgorithm, registry: Option<Arc<ChallengeRegistry>>) -> Self {
+        let registration = registry.map(|registry| {
+            let exchange = registry.next_exchange.fetch_add(1, Ordering::Relaxed);
+            Arc::new(LiveRegistration { registry, exchange })
+        });
+        let mut state = Self {
+            identifier: 0,
+            challenge: Vec::new(),
             is_target_auth,
+            algorithm,
+            issued_challenges: Vec::new(),
+            registration,
+        };
+        state.reissue();
+        state
+    }
+
+    /// State for answering a challenge received from the peer
+    pub fn for_challenge(identifier: u8, challenge: Vec<u8>, is_target_auth: bool, algorithm: ChapAlgorithm) -> Self {
+        Self { identifier, challenge, is_target_auth, algorithm, issued_challenges: Vec::new(), registration: None }
+    }
+
+    /// Replace the challenge with a fresh one
+    ///
+    /// The new identifier differs from the previous one and the new challenge
+    /// from every challenge issued before, so a response to an earlier
+    /// challenge can never be replayed against this one. Target challenges
+    /// (`is_target_auth == false`) are also kept apart from those of every
+    /// other live exchange in the same registry, identifiers included while
+    /// any are free.
+    pub fn reissue(&mut self) {
+        let mut rng = rand::thread_rng();
+        let previous_identifier = self.identifier;
+        let first = self.issued_challenges.is_empty();
+
+        let registration = self.registration.clone();
+        let mut live = registration.as_ref().map(|registration| registration.registry.exchanges());
+        let own = registration.as_ref().map(|registration| registration.exchange);
+        let others = || live.iter().flat_map(|live| live.iter())
+            .filter(move |(id, _)| Some(**id) != own)
+            .map(|(_, exchange)| exchange);
+
+        let taken: Vec<u8> = others().map(|exchange| exchange.identifier).collect();
+        let identifier_free = taken.len() < 255;
+        loop {
+            self.identifier = rng.gen::<u8>();
+            if (first || self.identifier != previous_identifier)
+                && !(identifier_free && taken.contains(&self.identifier))
+            {
+                break;
+            }
         }
+
+        let mut challenge = vec![0u8; self.algorithm.digest_len().max(16)];
+        loop {
+            rng.fill(&mut challenge[..]);
+            if !self.issued_challenges.contains(&challenge)
+                && !others().any(|exchange| exchange.challenges.contains(&challenge))
+            {
+                break;
+            }
+        }
+        self.challenge = challenge.clone();
+        self.issued_challenges.push(challenge);
+
+        if let (Some(live), Some(id)) = (live.as_mut(), own) {
+            live.insert(id, LiveExchange { identifier: self.identifier, challenges: self.issued_challenges.clone() });
+        }
+    }
+
+    /// Whether a challenge received from the peer is one we issued
+    ///
+    /// A peer that echoes our challenge back in mutual CHAP is attempting a
+    /// reflection attack: it wants us to compute the response it owes us.
+    /// The challenges of the target's other live exchanges count too, so the
+    /// peer cannot reflect a challenge across connections.
+    pub fn is_reflection(&self, challenge: &[u8]) -> bool {
+        self.issued_challenges.iter().any(|issued| issued.as_slice() == challenge)
+            || self.registration.as_ref().is_some_and(|registration| {
+                registration.registry.exchanges().values()
+                    .any(|exchange| exchange.challenges.iter().any(|issued| issued.as_slice() == challenge))
+            })
     }
 
     /// Calculate the expected CHAP response
-    /// Response = MD5(identifier + secret + challenge)
+    /// Response = H(identifier + secret + challenge), H being the negotiated algorithm
+    ///
+    /// `0x`/`0b` encoded secrets are decoded first (see `decode_chap_secret`).
     pub fn calculate_response(&self, secret: &str) -> Vec<u8> {
         let mut data = Vec::new();
         data.push(self.identifier);
-        data.extend_from_slice(secret.as_bytes());
+        data.extend_from_slice(&chap_secret_bytes(secret));
         data.extend_from_slice(&self.challenge);
 
-        md5::compute(&data).0.to_vec()
+        self.algorithm.digest(&data)
     }
 
     /// Validate a CHAP response
@@ -159,7 +591,7 @@ pub fn parse_chap_response(hex_str: &str) -> ScsiResult<Vec<u8>> {
     // Strip "0x" prefix if present
     let cleaned = hex_str.strip_prefix("0x").unwrap_or(hex_str);
     hex::decode(cleaned).map_err(|e| {
-        IscsiError::Auth(format!("Invalid CHAP response hex: {}", e))
+        IscsiError::auth(AuthFailure::MalformedMessage, format!("Invalid CHAP response hex: {}", e))
     })
 }
 
@@ -187,14 +619,95 @@ mod tests {
         assert!(!state.validate_response(&response, "wrongsecret"));
     }
 
+    #[test]
+    fn test_chap_algorithms() {
+        assert_eq!(" 8".parse::<ChapAlgorithm>().ok(), Some(ChapAlgorithm::Sha3_256));
+        let unknown = "6".parse::<ChapAlgorithm>().unwrap_err();
+        assert_eq!(unknown.auth_failure(), Some(AuthFailure::MethodNotAccepted));
+        assert_eq!(ChapAlgorithm::negotiate("5", &ChapAlgorithm::ALL), Some(ChapAlgorithm::Md5));
+        assert_eq!(ChapAlgorithm::negotiate("5,7", &ChapAlgorithm::ALL), Some(ChapAlgorithm::Md5));
+        assert_eq!(ChapAlgorithm::negotiate("7,8,5", &ChapAlgorithm::ALL), Some(ChapAlgorithm::Sha256));
+        assert_eq!(ChapAlgorithm::negotiate("99,8,7", &ChapAlgorithm::ALL), Some(ChapAlgorithm::Sha3_256));
+        assert_eq!(ChapAlgorithm::negotiate("6,99", &ChapAlgorithm::ALL), None);
+        let no_md5 = [ChapAlgorithm::Sha3_256, ChapAlgorithm::Sha256];
+        assert_eq!(ChapAlgorithm::negotiate("5", &no_md5), None);
+        assert_eq!(ChapAlgorithm::negotiate("5,7", &no_md5), Some(ChapAlgorithm::Sha256));
+
+        for algorithm in ChapAlgorithm::ALL {
+            let state = ChapAuthState::with_algorithm(false, algorithm);
+            let response = state.calculate_response("mysecret");
+            assert_eq!(response.len(), algorithm.digest_len());
+            assert!(state.challenge.len() >= algorithm.digest_len());
+            assert!(state.validate_response(&response, "mysecret"));
+
+            let mut data = vec![state.identifier];
+            data.extend_from_slice(b"mysecret");
+            data.extend_from_slice(&state.challenge);
+            assert_eq!(response, algorithm.digest(&data));
+        }
+
+        // FIPS 180-4 / FIPS 202 test vectors
+        assert_eq!(
+            hex::encode(ChapAlgorithm::Sha256.digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex::encode(ChapAlgorithm::Sha3_256.digest(b"abc")),
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"
+        );
+    }
+
     #[test]
     fn test_chap_challenge_generation() {
-        let state1 = ChapAuthState::new(false);
-        let state2 = ChapAuthState::new(false);
+        let registry = Arc::new(ChallengeRegistry::default());
+        let state1 = ChapAuthState::registered(&registry, ChapAlgorithm::Md5);
+        let state2 = ChapAuthState::registered(&registry, ChapAlgorithm::Md5);
 
         // Challenges should be different
         assert_ne!(state1.identifier, state2.identifier);
         assert_ne!(state1.challenge, state2.challenge);
+
+        // Reissuing never repeats the identifier or an earlier challenge
+        let mut state = ChapAuthState::new(false);
+        for _ in 0..32 {
+            let (identifier, challenge) = (state.identifier, state.challenge.clone());
+            state.reissue();
+            assert_ne!(state.identifier, identifier);
+            assert_ne!(state.challenge, challenge);
+        }
+        assert_eq!(state.issued_challenges.len(), 33);
+        let mut unique = state.issued_challenges.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 33);
+    }
+
+    #[test]
+    fn test_chap_reflection_detection() {
+        let registry = Arc::new(ChallengeRegistry::default());
+        let mut state = ChapAuthState::registered(&registry, ChapAlgorithm::Md5);
+        let first = state.challenge.clone();
+        state.reissue();
+
+        assert!(state.is_reflection(&state.challenge.clone()));
+        assert!(state.is_reflection(&first));
+        assert!(!state.is_reflection(&[0u8; 16]));
+        assert!(!state.is_reflection(&first[..8]));
+
+        // Another live target exchange's challenge is a reflection too
+        let other = ChapAuthState::registered(&registry, ChapAlgorithm::Md5);
+        assert!(state.is_reflection(&other.challenge));
+        let stale = other.challenge.clone();
+        drop(other);
+        assert!(!state.is_reflection(&stale));
+
+        // Another target's exchanges are not
+        let elsewhere = ChapAuthState::registered(&Arc::new(ChallengeRegistry::default()), ChapAlgorithm::Md5);
+        assert!(!state.is_reflection(&elsewhere.challenge));
+
+        // Challenges the initiator side issues are not the target's
+        let initiator = ChapAuthState::new(true);
+        assert!(!state.is_reflection(&initiator.challenge));
     }
 
     #[test]
@@ -218,4 +731,104 @@ mod tests {
         assert_eq!(mutual.auth_method(), "CHAP");
         assert!(mutual.is_mutual());
     }
+
+    #[test]
+    fn test_chap_accounts_provider() {
+        let mut accounts = ChapAccounts::new();
+        accounts.add("host-a", "secret-a");
+        accounts.add("host-b", "secret-b");
+        assert_eq!(accounts.len(), 2);
+
+        let state = ChapAuthState::new(false);
+        let response_a = state.calculate_response("secret-a");
+
+        assert!(accounts.contains("host-a"));
+        assert!(!accounts.contains("host-c"));
+        assert!(accounts.verify("host-a", &state, &response_a));
+        assert!(!accounts.verify("host-b", &state, &response_a));
+        assert!(!accounts.verify("host-c", &state, &response_a));
+
+        assert!(accounts.remove("host-a"));
+        assert!(!accounts.verify("host-a", &state, &response_a));
+    }
+
+    #[test]
+    fn test_auth_config_provider() {
+        let mut accounts = ChapAccounts::new();
+        accounts.add("host-a", "secret-a");
+
+        let one_way = AuthConfig::ChapProvider {
+            provider: Arc::new(accounts.clone()),
+            mutual_credentials: None,
+        };
+        assert!(one_way.requires_auth());
+        assert_eq!(one_way.auth_method(), "CHAP");
+        assert!(!one_way.is_mutual());
+        assert!(one_way.secret_provider().unwrap().contains("host-a"));
+
+        let mutual = AuthConfig::ChapProvider {
+            provider: Arc::new(accounts),
+            mutual_credentials: Some(ChapCredentials::new("target", "target-secret")),
+        };
+        assert!(mutual.is_mutual());
+        assert_eq!(mutual.mutual_credentials().unwrap().username, "target");
+
+        // Legacy single-account configs expose their credentials as a provider
+        let chap = AuthConfig::Chap {
+            credentials: ChapCredentials::new("user", "secret"),
+        };
+        let provider = chap.secret_provider().unwrap();
+        assert!(provider.contains("user"));
+        assert!(!provider.contains("other"));
+    }
+
+    #[test]
+    fn test_chap_secret_encodings() {
+        let plain = ChapCredentials::new("user", "0123456789abcdef");
+        assert_eq!(plain.secret_bytes(), b"0123456789abcdef");
+
+        let hex = ChapCredentials::from_hex("user", "0x000102030405060708090a0b").unwrap();
+        assert_eq!(hex.secret_bytes(), (0u8..12).collect::<Vec<_>>());
+
+        let b64 = ChapCredentials::from_base64("user", "0bAAECAwQFBgcICQoL").unwrap();
+        assert_eq!(b64.secret_bytes(), hex.secret_bytes());
+        assert_eq!(BASE64.decode("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(BASE64.decode("aGVsbG8").unwrap(), b"hello");
+        assert!(BASE64.decode("a").is_err());
+        assert!(ChapCredentials::from_hex("user", "0xzz").is_err());
+
+        // Binary secrets hash their decoded bytes
+        let state = ChapAuthState::new(false);
+        let expected = {
+            let mut data = vec![state.identifier];
+            data.extend((0u8..12).collect::<Vec<_>>());
+            data.extend_from_slice(&state.challenge);
+            md5::compute(&data).0.to_vec()
+        };
+        assert_eq!(state.calculate_response(&hex.secret), expected);
+    }
+
+    #[test]
+    fn test_chap_credentials_validation() {
+        assert!(ChapCredentials::new("user", "long-enough-secret").validate().is_ok());
+        assert!(ChapCredentials::from_bytes("user", &[7u8; 12]).validate().is_ok());
+
+        assert!(ChapCredentials::new("user", "short").validate().is_err());
+        assert!(ChapCredentials::new("", "long-enough-secret").validate().is_err());
+        assert!(ChapCredentials::new("same-as-secret", "same-as-secret").validate().is_err());
+        assert!(ChapCredentials::new("user", "0xnothex").validate().is_err());
+        assert!(ChapCredentials::from_bytes("user", &[7u8; 11]).validate().is_err());
+
+        let mutual = AuthConfig::MutualChap {
+            target_credentials: ChapCredentials::new("target", "shared-secret-123"),
+            initiator_credentials: ChapCredentials::new("initiator", "shared-secret-123"),
+        };
+        assert!(mutual.validate().is_err());
+
+        let mut accounts = ChapAccounts::new();
+        accounts.add("host-a", "secret-a-123456");
+        assert!(accounts.validate().is_ok());
+        accounts.add("host-b", "weak");
+        assert!(accounts.validate().is_err());
+    }
 }
@@ -0,0 +1,321 @@
+//! One logical unit made of several member devices
+
+use crate::error::{IscsiError, ScsiDeviceError, ScsiResult, SenseCode};
+use crate::scsi::ScsiBlockDevice;
+use crate::vpd::BlockLimits;
+
+/// How a `CompositeBlockDevice` lays its blocks out over the members
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompositeLayout {
+    /// Members follow one another, each contributing its whole capacity
+    Concatenated,
+    /// Consecutive runs of `stripe_blocks` blocks go to each member in turn
+    /// (RAID 0)
+    Striped { stripe_blocks: u32 },
+}
+
+/// A run of blocks that lies on a single member
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Extent {
+    member: usize,
+    lba: u64,
+    blocks: u32,
+}
+
+/// Stripes or concatenates several devices into one larger device
+///
+/// Every member must have the same block size. Commands that cross a member
+/// or stripe boundary are split into one request per member. A striped
+/// device uses the same number of whole stripes on every member, so blocks
+/// past the last whole stripe of the smallest member go unused.
+/// Bidirectional commands cannot be split, so they reach a member only when
+/// it is the sole one.
+///
+/// # Example
+/// ```
+/// use iscsi_target::backends::{CompositeBlockDevice, MemBlockDevice};
+/// use iscsi_target::ScsiBlockDevice;
+///
+/// # fn main() -> iscsi_target::ScsiResult<()> {
+/// let members = vec![MemBlockDevice::new(64, 512), MemBlockDevice::new(64, 512)];
+/// let mut device = CompositeBlockDevice::striped(members, 8)?;
+/// assert_eq!(device.capacity(), 128);
+/// // Blocks 6..10 straddle the first stripe on each member
+/// device.write(6, &[0xAB; 4 * 512], 512)?;
+/// assert_eq!(device.members()[1].read(0, 2, 512)?, vec![0xAB; 1024]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct CompositeBlockDevice<D: ScsiBlockDevice> {
+    members: Vec<D>,
+    layout: CompositeLayout,
+    block_size: u32,
+    /// First LBA of each member (concatenated) or blocks used per member (striped)
+    offsets: Vec<u64>,
+    capacity: u64,
+}
+
+impl<D: ScsiBlockDevice> CompositeBlockDevice<D> {
+    /// Members one after another, in order
+    pub fn concatenated(members: Vec<D>) -> ScsiResult<Self> {
+        Self::new(members, CompositeLayout::Concatenated)
+    }
+
+    /// Members striped in runs of `stripe_blocks` blocks
+    pub fn striped(members: Vec<D>, stripe_blocks: u32) -> ScsiResult<Self> {
+        Self::new(members, CompositeLayout::Striped { stripe_blocks })
+    }
+
+    /// Combine `members` with `layout`
+    pub fn new(members: Vec<D>, layout: CompositeLayout) -> ScsiResult<Self> {
+        let Some(first) = members.first() else {
+            return Err(IscsiError::Config("a composite device needs at least one member".to_string()));
+        };
+        let block_size = first.block_size();
+        if let Some(i) = members.iter().position(|member| member.block_size() != block_size) {
+            return Err(IscsiError::Config(format!(
+                "member {} has {}-byte blocks, member 0 has {}-byte blocks",
+                i, members[i].block_size(), block_size
+            )));
+        }
+
+        let (offsets, capacity) = match layout {
+            CompositeLayout::Concatenated => {
+                let mut start = 0u64;
+                let offsets = members.iter()
+                    .map(|member| {
+                        let offset = start;
+                        start += member.capacity();
+                        offset
+                    })
+                    .collect();
+                (offsets, start)
+            }
+            CompositeLayout::Striped { stripe_blocks: 0 } => {
+                return Err(IscsiError::Config("stripe size must be at least one block".to_string()));
+            }
+            CompositeLayout::Striped { stripe_blocks } => {
+                let stripe = u64::from(stripe_blocks);
+                let smallest = members.iter().map(|member| member.capacity()).min().unwrap_or(0);
+                let used = smallest / stripe * stripe;
+                (vec![used; members.len()], used * members.len() as u64)
+            }
+        };
+        Ok(CompositeBlockDevice { members, layout, block_size, offsets, capacity })
+    }
+
+    /// The layout the members are combined with
+    pub fn layout(&self) -> CompositeLayout {
+        self.layout
+    }
+
+    /// The member devices, in order
+    pub fn members(&self) -> &[D] {
+        &self.members
+    }
+
+    /// Unwrap the member devices
+    pub fn into_members(self) -> Vec<D> {
+        self.members
+    }
+
+    /// Split `blocks` blocks at `lba` into runs on single members
+    fn extents(&self, lba: u64, blocks: u32) -> ScsiResult<Vec<Extent>> {
+        lba.checked_add(blocks.into())
+            .filter(|end| *end <= self.capacity)
+            .ok_or(ScsiDeviceError::OutOfRange { lba })?;
+
+        let mut extents = Vec::new();
+        let (mut lba, mut remaining) = (lba, blocks);
+        while remaining > 0 {
+            let extent = match self.layout {
+                CompositeLayout::Concatenated => {
+                    // The last member starting at or before `lba`; skips empty members
+                    let member = self.offsets.partition_point(|&start| start <= lba) - 1;
+                    let end = self.offsets.get(member + 1).copied().unwrap_or(self.capacity);
+                    let blocks = (end - lba).min(remaining.into()) as u32;
+                    Extent { member, lba: lba - self.offsets[member], blocks }
+                }
+                CompositeLayout::Striped { stripe_blocks } => {
+                    let stripe = u64::from(stripe_blocks);
+                    let (index, within) = (lba / stripe, lba % stripe);
+                    let members = self.members.len() as u64;
+                    let blocks = (stripe - within).min(remaining.into()) as u32;
+                    Extent { member: (index % members) as usize, lba: index / members * stripe + within, blocks }
+                }
+            };
+            lba += u64::from(extent.blocks);
+            remaining -= extent.blocks;
+            extents.push(extent);
+        }
+        Ok(extents)
+    }
+
+    fn check_block_size(&self, block_size: u32) -> ScsiResult<()> {
+        if block_size != self.block_size {
+            return Err(IscsiError::scsi(format!(
+                "block size mismatch: expected {}, got {}",
+                self.block_size, block_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<D: ScsiBlockDevice> ScsiBlockDevice for CompositeBlockDevice<D> {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        self.check_block_size(block_size)?;
+        let mut data = Vec::with_capacity(blocks as usize * block_size as usize);
+        for extent in self.extents(lba, blocks)? {
+            data.extend(self.members[extent.member].read(extent.lba, extent.blocks, block_size)?);
+        }
+        Ok(data)
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        self.check_block_size(block_size)?;
+        if !data.len().is_multiple_of(block_size as usize) {
+            return Err(IscsiError::scsi(format!(
+                "{} bytes is not a whole number of {}-byte blocks",
+                data.len(), block_size
+            )));
+        }
+        let blocks = u32::try_from(data.len() / block_size as usize).map_err(|_| ScsiDeviceError::OutOfRange { lba })?;
+        let mut data = data;
+        for extent in self.extents(lba, blocks)? {
+            let (chunk, rest) = data.split_at(extent.blocks as usize * block_size as usize);
+            self.members[extent.member].write(extent.lba, chunk, block_size)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Flushes every member, reporting the first failure
+    fn flush(&mut self) -> ScsiResult<()> {
+        let mut result = Ok(());
+        for member in &mut self.members {
+            let flushed = member.flush();
+            if result.is_ok() {
+                result = flushed;
+            }
+        }
+        result
+    }
+
+    fn write_cache_enabled(&self) -> bool {
+        self.members.iter().any(|member| member.write_cache_enabled())
+    }
+
+    fn prefetch(&self, lba: u64, blocks: u32) -> ScsiResult<bool> {
+        let mut cached = true;
+        for extent in self.extents(lba, blocks)? {
+            cached &= self.members[extent.member].prefetch(extent.lba, extent.blocks)?;
+        }
+        Ok(cached)
+    }
+
+    fn abort_write(&mut self, lba: u64, blocks: u32) -> ScsiResult<()> {
+        for extent in self.extents(lba, blocks)? {
+            self.members[extent.member].abort_write(extent.lba, extent.blocks)?;
+        }
+        Ok(())
+    }
+
+    fn bidirectional(&mut self, cdb: &[u8], data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+        match self.members.as_mut_slice() {
+            [member] => member.bidirectional(cdb, data_out),
+            _ => Err(ScsiDeviceError::Custom(SenseCode::INVALID_COMMAND_OPERATION_CODE).into()),
+        }
+    }
+
+    fn physical_block_exponent(&self) -> u8 {
+        self.members.iter().map(|member| member.physical_block_exponent()).max().unwrap_or(0)
+    }
+
+    fn block_limits(&self) -> BlockLimits {
+        // Any member's limit bounds a command that lands entirely on it
+        let max_transfer_length = self.members.iter()
+            .map(|member| member.block_limits().max_transfer_length)
+            .filter(|&max| max != 0)
+            .min()
+            .unwrap_or(0);
+        match self.layout {
+            CompositeLayout::Concatenated => BlockLimits { max_transfer_length, ..self.members[0].block_limits() },
+            // A full stripe row keeps every member busy
+            CompositeLayout::Striped { stripe_blocks } => BlockLimits {
+                max_transfer_length,
+                optimal_transfer_length: stripe_blocks.saturating_mul(self.members.len() as u32),
+                optimal_transfer_granularity: u16::try_from(stripe_blocks).unwrap_or(0),
+            },
+        }
+    }
+
+    fn medium_rotation_rate(&self) -> u16 {
+        let rate = self.members[0].medium_rotation_rate();
+        if self.members.iter().all(|member| member.medium_rotation_rate() == rate) { rate } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemBlockDevice;
+
+    fn pattern(blocks: usize) -> Vec<u8> {
+        (0..blocks * 512).map(|i| (i / 512) as u8).collect()
+    }
+
+    #[test]
+    fn test_concatenated_boundaries() {
+        let members = vec![MemBlockDevice::new(10, 512), MemBlockDevice::new(0, 512), MemBlockDevice::new(6, 512)];
+        let mut device = CompositeBlockDevice::concatenated(members).unwrap();
+        assert_eq!(device.capacity(), 16);
+
+        // Blocks 8..12 span the end of member 0 and the start of member 2
+        device.write(8, &pattern(4), 512).unwrap();
+        assert_eq!(device.read(8, 4, 512).unwrap(), pattern(4));
+        assert_eq!(device.members()[0].read(8, 2, 512).unwrap(), pattern(2));
+        assert_eq!(device.members()[2].read(0, 2, 512).unwrap(), pattern(4)[1024..]);
+
+        assert!(matches!(device.read(14, 3, 512), Err(IscsiError::Device(ScsiDeviceError::OutOfRange { lba: 14 }))));
+        assert!(device.write(15, &pattern(2), 512).is_err());
+    }
+
+    #[test]
+    fn test_striped_layout() {
+        let members = vec![MemBlockDevice::new(20, 512), MemBlockDevice::new(17, 512), MemBlockDevice::new(30, 512)];
+        let mut device = CompositeBlockDevice::striped(members, 4).unwrap();
+        // Four whole stripes of the smallest member on each of three members
+        assert_eq!(device.capacity(), 48);
+
+        device.write(0, &pattern(48), 512).unwrap();
+        assert_eq!(device.read(0, 48, 512).unwrap(), pattern(48));
+        assert_eq!(device.read(10, 7, 512).unwrap(), pattern(48)[10 * 512..17 * 512]);
+        // Stripe 4 is the second stripe on member 1
+        let block = |n: u8| vec![n; 512];
+        assert_eq!(device.members()[1].read(4, 1, 512).unwrap(), block(16));
+        assert_eq!(device.members()[2].read(7, 1, 512).unwrap(), block(23));
+
+        let limits = device.block_limits();
+        assert_eq!((limits.optimal_transfer_length, limits.optimal_transfer_granularity), (12, 4));
+    }
+
+    #[test]
+    fn test_composite_rejects_bad_members() {
+        let none: Vec<MemBlockDevice> = Vec::new();
+        assert!(matches!(CompositeBlockDevice::concatenated(none), Err(IscsiError::Config(_))));
+        let mixed = vec![MemBlockDevice::new(8, 512), MemBlockDevice::new(8, 4096)];
+        assert!(matches!(CompositeBlockDevice::concatenated(mixed), Err(IscsiError::Config(_))));
+        let members = vec![MemBlockDevice::new(8, 512)];
+        assert!(matches!(CompositeBlockDevice::striped(members, 0), Err(IscsiError::Config(_))));
+    }
+}
@@ -0,0 +1,298 @@
+//! Fault and latency injection for testing error handling
+
+use crate::error::{ScsiDeviceError, ScsiResult};
+use crate::scsi::{LbaStatus, ModeParameters, PowerCondition, ScsiBlockDevice};
+use crate::vpd::{BlockLimits, Designator};
+use rand::Rng;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Faults currently armed on a `FaultyBlockDevice`
+#[derive(Debug, Clone, Default)]
+struct Faults {
+    /// Fail every nth write with this error
+    write_failure: Option<(u64, ScsiDeviceError)>,
+    /// Reads and writes touching these LBAs fail with MEDIUM ERROR
+    bad_blocks: Vec<Range<u64>>,
+    latency: Duration,
+    jitter: Duration,
+}
+
+/// Handle for arming and clearing the faults of a `FaultyBlockDevice`
+///
+/// Clones share the device's state, so faults can be changed while the
+/// target owns the device.
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    faults: Arc<Mutex<Faults>>,
+    writes: Arc<AtomicU64>,
+    injected: Arc<AtomicU64>,
+}
+
+impl FaultInjector {
+    /// Fail every `n`th write from now on with `error`; 0 stops failing writes
+    pub fn fail_every_nth_write(&self, n: u64, error: ScsiDeviceError) {
+        self.writes.store(0, Ordering::SeqCst);
+        self.lock().write_failure = (n > 0).then_some((n, error));
+    }
+
+    /// Fail reads and writes that touch `lbas` with MEDIUM ERROR
+    pub fn medium_error(&self, lbas: Range<u64>) {
+        self.lock().bad_blocks.push(lbas);
+    }
+
+    /// Delay every read and write by `latency` plus up to `jitter` more
+    pub fn latency(&self, latency: Duration, jitter: Duration) {
+        let mut faults = self.lock();
+        faults.latency = latency;
+        faults.jitter = jitter;
+    }
+
+    /// Disarm every fault and remove the latency
+    pub fn clear(&self) {
+        *self.lock() = Faults::default();
+    }
+
+    /// Number of commands failed on purpose so far
+    pub fn injected_faults(&self) -> u64 {
+        self.injected.load(Ordering::SeqCst)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Faults> {
+        self.faults.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sleep for the configured latency, then fail if `lba..lba + blocks`
+    /// holds a bad block
+    fn before_io(&self, lba: u64, blocks: u64) -> ScsiResult<()> {
+        let faults = self.lock().clone();
+        let mut delay = faults.latency;
+        if !faults.jitter.is_zero() {
+            delay += faults.jitter.mul_f64(rand::thread_rng().gen::<f64>());
+        }
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+        let end = lba.saturating_add(blocks);
+        if faults.bad_blocks.iter().any(|bad| bad.start < end && lba < bad.end) {
+            self.injected.fetch_add(1, Ordering::SeqCst);
+            return Err(ScsiDeviceError::MediumError.into());
+        }
+        Ok(())
+    }
+
+    /// Count a write, failing it if it is the nth
+    fn before_write(&self) -> ScsiResult<()> {
+        let failure = self.lock().write_failure;
+        let count = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        match failure {
+            Some((n, error)) if count.is_multiple_of(n) => {
+                self.injected.fetch_add(1, Ordering::SeqCst);
+                Err(error.into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Wraps a device to fail commands and slow them down on demand
+///
+/// For testing how initiators recover from device errors and exercising the
+/// target's own error paths: writes can fail every nth time with a chosen
+/// `ScsiDeviceError`, LBA ranges can return MEDIUM ERROR, and reads and
+/// writes can be delayed with random jitter. Faults are armed through the
+/// `FaultInjector` from `injector()`. Protection information is not passed
+/// through.
+///
+/// # Example
+/// ```
+/// use iscsi_target::backends::{FaultyBlockDevice, MemBlockDevice};
+/// use iscsi_target::{ScsiBlockDevice, ScsiDeviceError};
+///
+/// let mut device = FaultyBlockDevice::new(MemBlockDevice::new(64, 512));
+/// let faults = device.injector();
+/// faults.fail_every_nth_write(2, ScsiDeviceError::MediumError);
+/// assert!(device.write(0, &[1; 512], 512).is_ok());
+/// assert!(device.write(1, &[1; 512], 512).is_err());
+/// faults.medium_error(10..12);
+/// assert!(device.read(8, 4, 512).is_err());
+/// ```
+#[derive(Debug)]
+pub struct FaultyBlockDevice<D: ScsiBlockDevice> {
+    inner: D,
+    injector: FaultInjector,
+}
+
+impl<D: ScsiBlockDevice> FaultyBlockDevice<D> {
+    /// Wrap `inner` with no faults armed
+    pub fn new(inner: D) -> Self {
+        FaultyBlockDevice { inner, injector: FaultInjector::default() }
+    }
+
+    /// Handle for arming faults, usable after the device is handed to a target
+    pub fn injector(&self) -> FaultInjector {
+        self.injector.clone()
+    }
+
+    /// The wrapped device
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwrap the inner device
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: ScsiBlockDevice> ScsiBlockDevice for FaultyBlockDevice<D> {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        self.injector.before_io(lba, blocks.into())?;
+        self.inner.read(lba, blocks, block_size)
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        self.injector.before_io(lba, (data.len() / block_size.max(1) as usize) as u64)?;
+        self.injector.before_write()?;
+        self.inner.write(lba, data, block_size)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.inner.capacity()
+    }
+
+    fn block_size(&self) -> u32 {
+        self.inner.block_size()
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        self.inner.flush()
+    }
+
+    fn write_cache_enabled(&self) -> bool {
+        self.inner.write_cache_enabled()
+    }
+
+    fn set_write_cache(&mut self, enabled: bool) -> ScsiResult<()> {
+        self.inner.set_write_cache(enabled)
+    }
+
+    fn write_cache_changeable(&self) -> bool {
+        self.inner.write_cache_changeable()
+    }
+
+    fn power_condition(&self) -> PowerCondition {
+        self.inner.power_condition()
+    }
+
+    fn start_stop_unit(&mut self, condition: PowerCondition, load_eject: bool) -> ScsiResult<()> {
+        self.inner.start_stop_unit(condition, load_eject)
+    }
+
+    fn prefetch(&self, lba: u64, blocks: u32) -> ScsiResult<bool> {
+        self.inner.prefetch(lba, blocks)
+    }
+
+    fn abort_write(&mut self, lba: u64, blocks: u32) -> ScsiResult<()> {
+        self.inner.abort_write(lba, blocks)
+    }
+
+    fn bidirectional(&mut self, cdb: &[u8], data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+        self.inner.bidirectional(cdb, data_out)
+    }
+
+    fn physical_block_exponent(&self) -> u8 {
+        self.inner.physical_block_exponent()
+    }
+
+    fn lowest_aligned_lba(&self) -> u16 {
+        self.inner.lowest_aligned_lba()
+    }
+
+    fn requires_aligned_writes(&self) -> bool {
+        self.inner.requires_aligned_writes()
+    }
+
+    fn thin_provisioned(&self) -> bool {
+        self.inner.thin_provisioned()
+    }
+
+    fn unmapped_reads_zero(&self) -> bool {
+        self.inner.unmapped_reads_zero()
+    }
+
+    fn lba_status(&self, lba: u64, blocks: u64) -> ScsiResult<Vec<LbaStatus>> {
+        self.inner.lba_status(lba, blocks)
+    }
+
+    fn vendor_id(&self) -> &str {
+        self.inner.vendor_id()
+    }
+
+    fn product_id(&self) -> &str {
+        self.inner.product_id()
+    }
+
+    fn product_rev(&self) -> &str {
+        self.inner.product_rev()
+    }
+
+    fn serial_number(&self) -> &str {
+        self.inner.serial_number()
+    }
+
+    fn designators(&self) -> Vec<Designator> {
+        self.inner.designators()
+    }
+
+    fn block_limits(&self) -> BlockLimits {
+        self.inner.block_limits()
+    }
+
+    fn medium_rotation_rate(&self) -> u16 {
+        self.inner.medium_rotation_rate()
+    }
+
+    fn mode_parameters(&self) -> ModeParameters {
+        self.inner.mode_parameters()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemBlockDevice;
+    use crate::error::IscsiError;
+    use std::time::Instant;
+
+    #[test]
+    fn test_injected_faults() {
+        let mut device = FaultyBlockDevice::new(MemBlockDevice::new(32, 512));
+        let faults = device.injector();
+
+        faults.fail_every_nth_write(3, ScsiDeviceError::NotReady);
+        let results: Vec<bool> = (0..6).map(|lba| device.write(lba, &[1; 512], 512).is_ok()).collect();
+        assert_eq!(results, [true, true, false, true, true, false]);
+        assert!(matches!(device.write(0, &[1; 512], 512), Ok(())));
+
+        faults.medium_error(20..22);
+        assert!(matches!(device.read(18, 3, 512), Err(IscsiError::Device(ScsiDeviceError::MediumError))));
+        assert!(device.read(22, 4, 512).is_ok());
+        assert_eq!(faults.injected_faults(), 3);
+
+        faults.clear();
+        assert!(device.read(18, 4, 512).is_ok());
+        assert_eq!(device.into_inner().read(2, 1, 512).unwrap(), vec![0; 512]);
+    }
+
+    #[test]
+    fn test_injected_latency() {
+        let device = FaultyBlockDevice::new(MemBlockDevice::new(8, 512));
+        device.injector().latency(Duration::from_millis(20), Duration::from_millis(10));
+        let start = Instant::now();
+        device.read(0, 1, 512).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
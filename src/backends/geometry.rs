@@ -0,0 +1,323 @@
+//! Per-LUN logical and physical block sizes over any block device
+
+use crate::error::{IscsiError, ScsiDeviceError, ScsiResult, SenseCode};
+use crate::scsi::{LbaStatus, ModeParameters, PowerCondition, ScsiBlockDevice};
+use crate::vpd::{BlockLimits, Designator};
+
+/// Block sizes a logical unit reports to initiators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockGeometry {
+    /// Bytes per logical block, the unit of LBAs and transfer lengths
+    pub logical_block_size: u32,
+    /// Bytes per physical block, a power-of-two multiple of the logical size
+    pub physical_block_size: u32,
+    /// First LBA that starts a physical block
+    pub lowest_aligned_lba: u16,
+    /// Fail writes that do not cover whole physical blocks with UNALIGNED
+    /// WRITE COMMAND instead of leaving the backend to read-modify-write
+    pub aligned_writes: bool,
+}
+
+impl BlockGeometry {
+    /// 4096-byte logical and physical blocks (4Kn)
+    pub fn native_4k() -> Self {
+        BlockGeometry {
+            logical_block_size: 4096,
+            physical_block_size: 4096,
+            lowest_aligned_lba: 0,
+            aligned_writes: false,
+        }
+    }
+
+    /// 512-byte logical blocks on 4096-byte physical blocks (512e)
+    pub fn emulated_512() -> Self {
+        BlockGeometry {
+            logical_block_size: 512,
+            physical_block_size: 4096,
+            lowest_aligned_lba: 0,
+            aligned_writes: false,
+        }
+    }
+
+    /// Logical blocks per physical block, as a power of two
+    fn physical_block_exponent(&self) -> u8 {
+        (self.physical_block_size / self.logical_block_size).trailing_zeros() as u8
+    }
+}
+
+/// Presents the inner device with the block sizes of a `BlockGeometry`
+///
+/// Each logical block maps onto whole blocks of the inner device, so the
+/// inner block size must divide the logical block size: a 4Kn LUN can sit on
+/// a 512-byte backend, while a 512e LUN needs a backend with 512-byte blocks.
+/// Protection information is not passed through. Bidirectional commands
+/// reach the inner device only when logical and inner blocks are the same
+/// size, since their CDBs cannot be translated.
+///
+/// # Example
+/// ```
+/// use iscsi_target::backends::{BlockGeometry, GeometryBlockDevice, MemBlockDevice};
+/// use iscsi_target::ScsiBlockDevice;
+///
+/// # fn main() -> iscsi_target::ScsiResult<()> {
+/// let mut device = GeometryBlockDevice::new(MemBlockDevice::new(64, 512), BlockGeometry::native_4k())?;
+/// assert_eq!((device.capacity(), device.block_size()), (8, 4096));
+/// device.write(1, &[0xAB; 4096], 4096)?;
+/// assert_eq!(device.inner().read(8, 8, 512)?, vec![0xAB; 4096]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct GeometryBlockDevice<D: ScsiBlockDevice> {
+    inner: D,
+    geometry: BlockGeometry,
+    /// Inner blocks per logical block
+    ratio: u32,
+}
+
+impl<D: ScsiBlockDevice> GeometryBlockDevice<D> {
+    /// Wrap `inner`, reporting `geometry` to initiators
+    pub fn new(inner: D, geometry: BlockGeometry) -> ScsiResult<Self> {
+        let logical = geometry.logical_block_size;
+        let physical = geometry.physical_block_size;
+        let inner_block_size = inner.block_size();
+        if logical == 0 || inner_block_size == 0 || !logical.is_multiple_of(inner_block_size) {
+            return Err(IscsiError::Config(format!(
+                "logical block size {} is not a multiple of the {}-byte inner blocks",
+                logical, inner_block_size
+            )));
+        }
+        if !physical.is_multiple_of(logical) || !(physical / logical).is_power_of_two() || physical / logical > 1 << 15 {
+            return Err(IscsiError::Config(format!(
+                "physical block size {} is not a power-of-two multiple of the {}-byte logical blocks",
+                physical, logical
+            )));
+        }
+        if u32::from(geometry.lowest_aligned_lba) >= physical / logical {
+            return Err(IscsiError::Config(format!(
+                "lowest aligned LBA {} is not within the first physical block",
+                geometry.lowest_aligned_lba
+            )));
+        }
+        Ok(GeometryBlockDevice { inner, geometry, ratio: logical / inner_block_size })
+    }
+
+    /// The reported geometry
+    pub fn geometry(&self) -> BlockGeometry {
+        self.geometry
+    }
+
+    /// The wrapped device
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwrap the wrapped device
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Inner LBA and block count for `blocks` logical blocks at `lba`
+    fn to_inner(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<(u64, u32)> {
+        if block_size != self.geometry.logical_block_size {
+            return Err(IscsiError::scsi(format!(
+                "block size mismatch: expected {}, got {}",
+                self.geometry.logical_block_size, block_size
+            )));
+        }
+        let inner_lba = lba.checked_mul(self.ratio.into());
+        let inner_blocks = blocks.checked_mul(self.ratio);
+        inner_lba.zip(inner_blocks).ok_or_else(|| IscsiError::scsi(format!(
+            "{} blocks at LBA {} overflow the inner device's addressing",
+            blocks, lba
+        )))
+    }
+}
+
+impl<D: ScsiBlockDevice> ScsiBlockDevice for GeometryBlockDevice<D> {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        let (lba, blocks) = self.to_inner(lba, blocks, block_size)?;
+        self.inner.read(lba, blocks, self.inner.block_size())
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        let (lba, _) = self.to_inner(lba, 0, block_size)?;
+        let inner_block_size = self.inner.block_size();
+        self.inner.write(lba, data, inner_block_size)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.inner.capacity() / u64::from(self.ratio)
+    }
+
+    fn block_size(&self) -> u32 {
+        self.geometry.logical_block_size
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        self.inner.flush()
+    }
+
+    fn write_cache_enabled(&self) -> bool {
+        self.inner.write_cache_enabled()
+    }
+
+    fn set_write_cache(&mut self, enabled: bool) -> ScsiResult<()> {
+        self.inner.set_write_cache(enabled)
+    }
+
+    fn write_cache_changeable(&self) -> bool {
+        self.inner.write_cache_changeable()
+    }
+
+    fn power_condition(&self) -> PowerCondition {
+        self.inner.power_condition()
+    }
+
+    fn start_stop_unit(&mut self, condition: PowerCondition, load_eject: bool) -> ScsiResult<()> {
+        self.inner.start_stop_unit(condition, load_eject)
+    }
+
+    fn prefetch(&self, lba: u64, blocks: u32) -> ScsiResult<bool> {
+        let (lba, blocks) = self.to_inner(lba, blocks, self.geometry.logical_block_size)?;
+        self.inner.prefetch(lba, blocks)
+    }
+
+    fn abort_write(&mut self, lba: u64, blocks: u32) -> ScsiResult<()> {
+        let (lba, blocks) = self.to_inner(lba, blocks, self.geometry.logical_block_size)?;
+        self.inner.abort_write(lba, blocks)
+    }
+
+    fn bidirectional(&mut self, cdb: &[u8], data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+        if self.ratio != 1 {
+            return Err(ScsiDeviceError::Custom(SenseCode::INVALID_COMMAND_OPERATION_CODE).into());
+        }
+        self.inner.bidirectional(cdb, data_out)
+    }
+
+    fn physical_block_exponent(&self) -> u8 {
+        self.geometry.physical_block_exponent()
+    }
+
+    fn lowest_aligned_lba(&self) -> u16 {
+        self.geometry.lowest_aligned_lba
+    }
+
+    fn requires_aligned_writes(&self) -> bool {
+        self.geometry.aligned_writes
+    }
+
+    fn thin_provisioned(&self) -> bool {
+        self.inner.thin_provisioned()
+    }
+
+    fn unmapped_reads_zero(&self) -> bool {
+        self.inner.unmapped_reads_zero()
+    }
+
+    fn lba_status(&self, lba: u64, blocks: u64) -> ScsiResult<Vec<LbaStatus>> {
+        let ratio = u64::from(self.ratio);
+        let runs = self.inner.lba_status(lba.saturating_mul(ratio), blocks.saturating_mul(ratio))?;
+        // A logical block takes the status of the run holding its first inner block
+        Ok(runs.into_iter()
+            .filter_map(|run| {
+                let start = run.lba.div_ceil(ratio);
+                let end = (run.lba + u64::from(run.blocks)).div_ceil(ratio);
+                (end > start).then(|| LbaStatus { lba: start, blocks: (end - start) as u32, status: run.status })
+            })
+            .collect())
+    }
+
+    fn vendor_id(&self) -> &str {
+        self.inner.vendor_id()
+    }
+
+    fn product_id(&self) -> &str {
+        self.inner.product_id()
+    }
+
+    fn product_rev(&self) -> &str {
+        self.inner.product_rev()
+    }
+
+    fn serial_number(&self) -> &str {
+        self.inner.serial_number()
+    }
+
+    fn designators(&self) -> Vec<Designator> {
+        self.inner.designators()
+    }
+
+    fn block_limits(&self) -> BlockLimits {
+        // The inner limits count inner blocks
+        let limits = self.inner.block_limits();
+        let scale = |blocks: u32| match blocks {
+            0 => 0,
+            blocks => (blocks / self.ratio).max(1),
+        };
+        BlockLimits {
+            max_transfer_length: scale(limits.max_transfer_length),
+            optimal_transfer_length: scale(limits.optimal_transfer_length),
+            optimal_transfer_granularity: 0,
+        }
+    }
+
+    fn medium_rotation_rate(&self) -> u16 {
+        self.inner.medium_rotation_rate()
+    }
+
+    fn mode_parameters(&self) -> ModeParameters {
+        self.inner.mode_parameters()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemBlockDevice;
+    use crate::scsi::ProvisioningStatus;
+
+    #[test]
+    fn test_native_4k_over_512_byte_blocks() {
+        let mut device = GeometryBlockDevice::new(MemBlockDevice::new(64, 512), BlockGeometry::native_4k()).unwrap();
+        assert_eq!(device.capacity(), 8);
+        assert_eq!(device.block_size(), 4096);
+        assert_eq!(device.physical_block_exponent(), 0);
+
+        device.write(2, &[0xCD; 8192], 4096).unwrap();
+        assert_eq!(device.read(3, 1, 4096).unwrap(), vec![0xCD; 4096]);
+        assert_eq!(device.inner().read(16, 16, 512).unwrap(), vec![0xCD; 8192]);
+        assert_eq!(device.inner().read(15, 1, 512).unwrap(), vec![0; 512]);
+        assert!(device.read(0, 1, 512).is_err());
+
+        let status = device.lba_status(0, 8).unwrap();
+        assert_eq!(status[0].lba, 0);
+        assert_eq!(status[0].blocks, 8);
+        assert_eq!(status[0].status, ProvisioningStatus::Mapped);
+        assert_eq!(device.block_limits().max_transfer_length, 65535 / 8);
+    }
+
+    #[test]
+    fn test_emulated_512_reports_physical_blocks() {
+        let geometry = BlockGeometry { lowest_aligned_lba: 7, aligned_writes: true, ..BlockGeometry::emulated_512() };
+        let device = GeometryBlockDevice::new(MemBlockDevice::new(64, 512), geometry).unwrap();
+        assert_eq!(device.capacity(), 64);
+        assert_eq!(device.block_size(), 512);
+        assert_eq!(device.physical_block_exponent(), 3);
+        assert_eq!(device.lowest_aligned_lba(), 7);
+        assert!(device.requires_aligned_writes());
+    }
+
+    #[test]
+    fn test_geometry_rejects_unusable_sizes() {
+        let new = |inner_block_size, logical_block_size, physical_block_size, lowest_aligned_lba| {
+            let geometry = BlockGeometry { logical_block_size, physical_block_size, lowest_aligned_lba, aligned_writes: false };
+            GeometryBlockDevice::new(MemBlockDevice::new(64, inner_block_size), geometry)
+        };
+        // 512e over a 4096-byte backend would need read-modify-write
+        assert!(matches!(new(4096, 512, 4096, 0), Err(IscsiError::Config(_))));
+        assert!(matches!(new(512, 4096, 512, 0), Err(IscsiError::Config(_))));
+        assert!(matches!(new(512, 512, 1536, 0), Err(IscsiError::Config(_))));
+        assert!(matches!(new(512, 512, 4096, 8), Err(IscsiError::Config(_))));
+        assert!(new(512, 1024, 4096, 3).is_ok());
+    }
+}
@@ -0,0 +1,727 @@
+//! Crash-consistent writes for any block device
+
+use crate::error::{IscsiError, ScsiDeviceError, ScsiResult};
+use crate::pdu::crc32c;
+use crate::protection::{ProtectionInfo, PI_SIZE};
+use crate::scsi::{LbaStatus, ModeParameters, PowerCondition, ScsiBlockDevice};
+use crate::vpd::{BlockLimits, Designator};
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Identifies the journal header block
+const HEADER_MAGIC: &[u8; 8] = b"ISCSIJNL";
+/// Identifies a write record
+const RECORD_MAGIC: &[u8; 4] = b"JREC";
+/// Journal format version
+const VERSION: u32 = 2;
+/// Journal blocks holding the two alternating header copies
+const HEADER_SLOTS: u64 = 2;
+
+/// Where the journal lives
+enum JournalStore {
+    /// The last blocks of the inner device, from this LBA on
+    Region(u64),
+    /// A separate file
+    File(File),
+}
+
+/// Logs every write to a journal before applying it to the inner device
+///
+/// Each WRITE becomes one record (a header block, the data and any
+/// protection information) appended to the journal and made durable before
+/// the inner device sees it. Opening the device replays the records written
+/// since the last checkpoint, so a write interrupted by a crash is either
+/// applied completely or not at all, and an acknowledged write survives even
+/// if the inner device lost it from a volatile cache. SYNCHRONIZE CACHE
+/// (`flush`) flushes the inner device and checkpoints the journal, as does a
+/// full journal.
+///
+/// A checkpoint writes the journal header to one of two slots in turn, each
+/// with its generation and a CRC, so a header torn by a crash leaves the
+/// other one to open from. Bidirectional commands are passed to the inner
+/// device without being journaled.
+///
+/// A WRITE whose data arrives in several Data-Out PDUs reaches the device as
+/// several writes, each journaled and applied as it arrives. If the WRITE
+/// is aborted part way, `abort_write` does not roll back the parts already
+/// applied.
+///
+/// The journal lives either in the last blocks of the inner device, which are
+/// then hidden from the initiator, or in a separate file. A single write
+/// must fit in the journal; MAXIMUM TRANSFER LENGTH is lowered accordingly.
+///
+/// # Example
+/// ```
+/// use iscsi_target::backends::{JournaledBlockDevice, MemBlockDevice};
+/// use iscsi_target::ScsiBlockDevice;
+///
+/// # fn main() -> iscsi_target::ScsiResult<()> {
+/// let mut device = JournaledBlockDevice::in_region(MemBlockDevice::new(64, 512), 16)?;
+/// assert_eq!(device.capacity(), 48);
+/// device.write(3, &[0xAB; 512], 512)?;
+/// device.flush()?;
+///
+/// // Reopening replays nothing after a checkpoint
+/// let device = JournaledBlockDevice::in_region(device.into_inner(), 16)?;
+/// assert_eq!(device.replayed(), 0);
+/// assert_eq!(device.read(3, 1, 512)?, vec![0xAB; 512]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct JournaledBlockDevice<D: ScsiBlockDevice> {
+    inner: D,
+    store: JournalStore,
+    /// Journal size in blocks, header included
+    journal_blocks: u64,
+    block_size: u32,
+    /// Records of older generations are stale
+    generation: u64,
+    /// Journal block the next record goes to
+    next: u64,
+    /// Records replayed when the device was opened
+    replayed: usize,
+}
+
+impl<D: ScsiBlockDevice> JournaledBlockDevice<D> {
+    /// Journal in the last `journal_blocks` blocks of `inner`, replaying any
+    /// writes left there by an unclean shutdown
+    pub fn in_region(inner: D, journal_blocks: u64) -> ScsiResult<Self> {
+        let capacity = inner.capacity();
+        if journal_blocks >= capacity {
+            return Err(IscsiError::Config(format!(
+                "journal of {} blocks does not fit a device of {} blocks",
+                journal_blocks, capacity
+            )));
+        }
+        Self::open(inner, JournalStore::Region(capacity - journal_blocks), journal_blocks)
+    }
+
+    /// Journal of `journal_blocks` blocks in the file at `path`, created if
+    /// missing, replaying any writes left there by an unclean shutdown
+    pub fn with_file(inner: D, path: impl AsRef<Path>, journal_blocks: u64) -> ScsiResult<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Self::open(inner, JournalStore::File(file), journal_blocks)
+    }
+
+    fn open(inner: D, store: JournalStore, journal_blocks: u64) -> ScsiResult<Self> {
+        // Both header slots plus one record of at least one block
+        if journal_blocks < HEADER_SLOTS + 2 {
+            return Err(IscsiError::Config(format!(
+                "journal needs at least {} blocks, got {}",
+                HEADER_SLOTS + 2, journal_blocks
+            )));
+        }
+        let block_size = inner.block_size();
+        if (block_size as usize) < 32 {
+            return Err(IscsiError::Config(format!("block size {} is too small to journal", block_size)));
+        }
+        let mut device = JournaledBlockDevice {
+            inner,
+            store,
+            journal_blocks,
+            block_size,
+            generation: 0,
+            next: HEADER_SLOTS,
+            replayed: 0,
+        };
+        device.replay()?;
+        Ok(device)
+    }
+
+    /// Records replayed when the device was opened
+    pub fn replayed(&self) -> usize {
+        self.replayed
+    }
+
+    /// The device the journaled writes are applied to
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwrap the inner device without a checkpoint, leaving the journal to
+    /// be replayed when it is opened again
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Apply the records of the newest intact generation, then checkpoint
+    fn replay(&mut self) -> ScsiResult<()> {
+        let mut newest = None;
+        let mut damaged = false;
+        for slot in 0..HEADER_SLOTS {
+            match self.read_header(slot)? {
+                HeaderSlot::Blank => {}
+                HeaderSlot::Torn => damaged = true,
+                HeaderSlot::Valid(generation) => newest = newest.max(Some(generation)),
+            }
+        }
+        let Some(generation) = newest else {
+            if damaged {
+                return Err(IscsiError::Config("journal headers are corrupt".to_string()));
+            }
+            // A new journal
+            self.generation = 1;
+            return self.write_header();
+        };
+        if damaged {
+            log::warn!("Journal header slot is damaged, opening generation {} from the other", generation);
+        }
+        self.generation = generation;
+
+        while let Some(record) = self.read_record(self.next)? {
+            let blocks = (record.data.len() / self.block_size as usize) as u64;
+            if record.pi.is_empty() {
+                self.inner.write(record.lba, &record.data, self.block_size)?;
+                self.next += 1 + blocks;
+            } else {
+                self.inner.write_with_pi(record.lba, &record.data, &record.pi, self.block_size)?;
+                self.next += 1 + blocks + self.pi_blocks(blocks);
+            }
+            self.replayed += 1;
+        }
+        if self.replayed > 0 {
+            log::info!("Journal replayed {} write(s)", self.replayed);
+        }
+        self.checkpoint()
+    }
+
+    /// The header in `slot`
+    fn read_header(&mut self, slot: u64) -> ScsiResult<HeaderSlot> {
+        let Some(header) = self.journal_read(slot, 1)? else {
+            return Ok(HeaderSlot::Blank);
+        };
+        if header[..8] != HEADER_MAGIC[..] {
+            return Ok(HeaderSlot::Blank);
+        }
+        if crc32c(&header[..24]) != BigEndian::read_u32(&header[24..28]) {
+            return Ok(HeaderSlot::Torn);
+        }
+        if BigEndian::read_u32(&header[8..12]) != VERSION || BigEndian::read_u32(&header[12..16]) != self.block_size {
+            return Err(IscsiError::Config("journal header is from another version or device".to_string()));
+        }
+        Ok(HeaderSlot::Valid(BigEndian::read_u64(&header[16..24])))
+    }
+
+    /// The record at journal block `at`, if it is complete and current
+    fn read_record(&mut self, at: u64) -> ScsiResult<Option<Record>> {
+        if at + 1 >= self.journal_blocks {
+            return Ok(None);
+        }
+        let Some(header) = self.journal_read(at, 1)? else {
+            return Ok(None);
+        };
+        if header[..4] != RECORD_MAGIC[..] || BigEndian::read_u64(&header[8..16]) != self.generation {
+            return Ok(None);
+        }
+        let with_pi = BigEndian::read_u32(&header[4..8]) != 0;
+        let lba = BigEndian::read_u64(&header[16..24]);
+        let blocks = BigEndian::read_u32(&header[24..28]);
+        let pi_blocks = if with_pi { self.pi_blocks(blocks as u64) } else { 0 };
+        if blocks == 0 || at + 1 + blocks as u64 + pi_blocks > self.journal_blocks {
+            return Ok(None);
+        }
+        let Some(mut body) = self.journal_read(at + 1, blocks + pi_blocks as u32)? else {
+            return Ok(None);
+        };
+        let mut checked = header[..28].to_vec();
+        checked.extend_from_slice(&body);
+        if crc32c(&checked) != BigEndian::read_u32(&header[28..32]) {
+            // Torn by a crash before it was acknowledged
+            return Ok(None);
+        }
+        let stored_pi = body.split_off(blocks as usize * self.block_size as usize);
+        let pi = stored_pi
+            .chunks_exact(PI_SIZE)
+            .take(if with_pi { blocks as usize } else { 0 })
+            .map(|bytes| ProtectionInfo::from_bytes(bytes.try_into().expect("PI_SIZE bytes")))
+            .collect();
+        Ok(Some(Record { lba, data: body, pi }))
+    }
+
+    /// Journal `data` (with `pi`, if any) as one record, then apply it
+    fn append(&mut self, lba: u64, data: &[u8], pi: &[ProtectionInfo]) -> ScsiResult<()> {
+        let block_size = self.block_size;
+        if !data.len().is_multiple_of(block_size as usize) {
+            return Err(IscsiError::scsi(format!(
+                "{} bytes is not a whole number of {}-byte journaled blocks",
+                data.len(), block_size
+            )));
+        }
+        let blocks = (data.len() / block_size as usize) as u64;
+        if blocks == 0 {
+            return Ok(());
+        }
+        if lba.saturating_add(blocks) > self.capacity() {
+            return Err(ScsiDeviceError::OutOfRange { lba }.into());
+        }
+        let pi_blocks = if pi.is_empty() { 0 } else { self.pi_blocks(blocks) };
+        if blocks + pi_blocks > self.max_record_blocks() {
+            return Err(IscsiError::scsi(format!(
+                "write of {} blocks exceeds the {}-block journal",
+                blocks, self.max_record_blocks()
+            )));
+        }
+        if self.next + 1 + blocks + pi_blocks > self.journal_blocks {
+            self.checkpoint()?;
+        }
+
+        let mut record = vec![0u8; block_size as usize];
+        record[..4].copy_from_slice(RECORD_MAGIC);
+        BigEndian::write_u32(&mut record[4..8], u32::from(!pi.is_empty()));
+        BigEndian::write_u64(&mut record[8..16], self.generation);
+        BigEndian::write_u64(&mut record[16..24], lba);
+        BigEndian::write_u32(&mut record[24..28], blocks as u32);
+        record.extend_from_slice(data);
+        pi.iter().for_each(|info| record.extend_from_slice(&info.to_bytes()));
+        record.resize((1 + blocks + pi_blocks) as usize * block_size as usize, 0);
+        let mut checked = record[..28].to_vec();
+        checked.extend_from_slice(&record[block_size as usize..]);
+        BigEndian::write_u32(&mut record[28..32], crc32c(&checked));
+
+        self.journal_write(self.next, &record)?;
+        self.journal_sync()?;
+        self.next += 1 + blocks + pi_blocks;
+        if pi.is_empty() {
+            self.inner.write(lba, data, block_size)
+        } else {
+            self.inner.write_with_pi(lba, data, pi, block_size)
+        }
+    }
+
+    /// Flush the inner device and start a new, empty generation
+    fn checkpoint(&mut self) -> ScsiResult<()> {
+        self.inner.flush()?;
+        if self.next > HEADER_SLOTS {
+            self.generation += 1;
+            self.write_header()?;
+        }
+        self.next = HEADER_SLOTS;
+        Ok(())
+    }
+
+    /// Write the header of the current generation to its slot, leaving the
+    /// previous generation's header in the other
+    fn write_header(&mut self) -> ScsiResult<()> {
+        let mut header = vec![0u8; self.block_size as usize];
+        header[..8].copy_from_slice(HEADER_MAGIC);
+        BigEndian::write_u32(&mut header[8..12], VERSION);
+        BigEndian::write_u32(&mut header[12..16], self.block_size);
+        BigEndian::write_u64(&mut header[16..24], self.generation);
+        let crc = crc32c(&header[..24]);
+        BigEndian::write_u32(&mut header[24..28], crc);
+        self.journal_write(self.generation % HEADER_SLOTS, &header)?;
+        self.journal_sync()
+    }
+
+    /// Read whole journal blocks; `None` past the end of a journal file
+    fn journal_read(&mut self, at: u64, blocks: u32) -> ScsiResult<Option<Vec<u8>>> {
+        match &mut self.store {
+            JournalStore::Region(start) => Ok(Some(self.inner.read(*start + at, blocks, self.block_size)?)),
+            JournalStore::File(file) => {
+                let mut data = vec![0u8; (blocks * self.block_size) as usize];
+                file.seek(SeekFrom::Start(at * self.block_size as u64))?;
+                match file.read_exact(&mut data) {
+                    Ok(()) => Ok(Some(data)),
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    }
+
+    fn journal_write(&mut self, at: u64, data: &[u8]) -> ScsiResult<()> {
+        match &mut self.store {
+            JournalStore::Region(start) => self.inner.write(*start + at, data, self.block_size),
+            JournalStore::File(file) => {
+                file.seek(SeekFrom::Start(at * self.block_size as u64))?;
+                Ok(file.write_all(data)?)
+            }
+        }
+    }
+
+    fn journal_sync(&mut self) -> ScsiResult<()> {
+        match &mut self.store {
+            JournalStore::Region(_) => self.inner.flush(),
+            JournalStore::File(file) => Ok(file.sync_data()?),
+        }
+    }
+
+    /// Blocks a single record's data and PI may span
+    fn max_record_blocks(&self) -> u64 {
+        self.journal_blocks - HEADER_SLOTS - 1
+    }
+
+    /// Blocks holding the PI of `blocks` journaled blocks
+    fn pi_blocks(&self, blocks: u64) -> u64 {
+        (blocks * PI_SIZE as u64).div_ceil(self.block_size as u64)
+    }
+
+    /// Largest write that fits one record, with PI when the device has it
+    fn max_write_blocks(&self) -> u64 {
+        let max = self.max_record_blocks();
+        if self.inner.protection_type() == 0 {
+            return max;
+        }
+        let mut blocks = max * self.block_size as u64 / (self.block_size as u64 + PI_SIZE as u64);
+        while blocks + self.pi_blocks(blocks) > max {
+            blocks -= 1;
+        }
+        blocks
+    }
+}
+
+/// A journaled write read back for replay
+struct Record {
+    lba: u64,
+    data: Vec<u8>,
+    /// Empty for a write without protection information
+    pi: Vec<ProtectionInfo>,
+}
+
+/// What one header slot of the journal holds
+enum HeaderSlot {
+    /// Never written
+    Blank,
+    /// Damaged, such as by a crash while it was written
+    Torn,
+    /// Intact, of this generation
+    Valid(u64),
+}
+
+impl<D: ScsiBlockDevice> ScsiBlockDevice for JournaledBlockDevice<D> {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        if lba.saturating_add(blocks as u64) > self.capacity() {
+            return Err(ScsiDeviceError::OutOfRange { lba }.into());
+        }
+        self.inner.read(lba, blocks, block_size)
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        if block_size != self.block_size {
+            return Err(IscsiError::scsi(format!("{}-byte blocks written to a {}-byte journal", block_size, self.block_size)));
+        }
+        self.append(lba, data, &[])
+    }
+
+    fn capacity(&self) -> u64 {
+        match self.store {
+            JournalStore::Region(start) => start,
+            JournalStore::File(_) => self.inner.capacity(),
+        }
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        self.checkpoint()
+    }
+
+    fn write_cache_enabled(&self) -> bool {
+        self.inner.write_cache_enabled()
+    }
+
+    fn set_write_cache(&mut self, enabled: bool) -> ScsiResult<()> {
+        self.inner.set_write_cache(enabled)
+    }
+
+    fn write_cache_changeable(&self) -> bool {
+        self.inner.write_cache_changeable()
+    }
+
+    fn power_condition(&self) -> PowerCondition {
+        self.inner.power_condition()
+    }
+
+    fn start_stop_unit(&mut self, condition: PowerCondition, load_eject: bool) -> ScsiResult<()> {
+        self.inner.start_stop_unit(condition, load_eject)
+    }
+
+    fn prefetch(&self, lba: u64, blocks: u32) -> ScsiResult<bool> {
+        self.inner.prefetch(lba, blocks)
+    }
+
+    fn abort_write(&mut self, lba: u64, blocks: u32) -> ScsiResult<()> {
+        self.inner.abort_write(lba, blocks)
+    }
+
+    fn physical_block_exponent(&self) -> u8 {
+        self.inner.physical_block_exponent()
+    }
+
+    fn lowest_aligned_lba(&self) -> u16 {
+        self.inner.lowest_aligned_lba()
+    }
+
+    fn requires_aligned_writes(&self) -> bool {
+        self.inner.requires_aligned_writes()
+    }
+
+    fn thin_provisioned(&self) -> bool {
+        self.inner.thin_provisioned()
+    }
+
+    fn unmapped_reads_zero(&self) -> bool {
+        self.inner.unmapped_reads_zero()
+    }
+
+    fn lba_status(&self, lba: u64, blocks: u64) -> ScsiResult<Vec<LbaStatus>> {
+        self.inner.lba_status(lba, blocks)
+    }
+
+    fn protection_type(&self) -> u8 {
+        self.inner.protection_type()
+    }
+
+    fn read_with_pi(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<(Vec<u8>, Vec<ProtectionInfo>)> {
+        if lba.saturating_add(blocks as u64) > self.capacity() {
+            return Err(ScsiDeviceError::OutOfRange { lba }.into());
+        }
+        self.inner.read_with_pi(lba, blocks, block_size)
+    }
+
+    fn write_with_pi(&mut self, lba: u64, data: &[u8], pi: &[ProtectionInfo], block_size: u32) -> ScsiResult<()> {
+        if block_size != self.block_size {
+            return Err(IscsiError::scsi(format!("{}-byte blocks written to a {}-byte journal", block_size, self.block_size)));
+        }
+        self.append(lba, data, pi)
+    }
+
+    fn bidirectional(&mut self, cdb: &[u8], data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+        self.inner.bidirectional(cdb, data_out)
+    }
+
+    fn vendor_id(&self) -> &str {
+        self.inner.vendor_id()
+    }
+
+    fn product_id(&self) -> &str {
+        self.inner.product_id()
+    }
+
+    fn product_rev(&self) -> &str {
+        self.inner.product_rev()
+    }
+
+    fn serial_number(&self) -> &str {
+        self.inner.serial_number()
+    }
+
+    fn designators(&self) -> Vec<Designator> {
+        self.inner.designators()
+    }
+
+    fn block_limits(&self) -> BlockLimits {
+        let mut limits = self.inner.block_limits();
+        let max = self.max_write_blocks().min(u32::MAX as u64) as u32;
+        if limits.max_transfer_length == 0 || limits.max_transfer_length > max {
+            limits.max_transfer_length = max;
+        }
+        limits.optimal_transfer_length = limits.optimal_transfer_length.min(max);
+        limits
+    }
+
+    fn medium_rotation_rate(&self) -> u16 {
+        self.inner.medium_rotation_rate()
+    }
+
+    fn mode_parameters(&self) -> ModeParameters {
+        self.inner.mode_parameters()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemBlockDevice;
+
+    #[test]
+    fn test_replay_after_crash() {
+        let mut device = JournaledBlockDevice::in_region(MemBlockDevice::new(64, 512), 16).unwrap();
+        assert_eq!(device.capacity(), 48);
+        assert_eq!(device.block_limits().max_transfer_length, 13);
+        device.write(1, &[0x11; 1024], 512).unwrap();
+        device.write(5, &[0x22; 512], 512).unwrap();
+        assert!(device.write(48, &[0u8; 512], 512).is_err());
+
+        // The inner device lost the writes from its volatile cache
+        let mut inner = device.into_inner();
+        inner.write(1, &[0u8; 3 * 512], 512).unwrap();
+        inner.write(5, &[0u8; 512], 512).unwrap();
+
+        let device = JournaledBlockDevice::in_region(inner, 16).unwrap();
+        assert_eq!(device.replayed(), 2);
+        assert_eq!(device.read(1, 2, 512).unwrap(), vec![0x11; 1024]);
+        assert_eq!(device.read(5, 1, 512).unwrap(), vec![0x22; 512]);
+
+        // Replay ends with a checkpoint
+        let device = JournaledBlockDevice::in_region(device.into_inner(), 16).unwrap();
+        assert_eq!(device.replayed(), 0);
+    }
+
+    #[test]
+    fn test_torn_record_is_discarded() {
+        let mut device = JournaledBlockDevice::in_region(MemBlockDevice::new(64, 512), 16).unwrap();
+        device.write(1, &[0x11; 512], 512).unwrap();
+        device.write(2, &[0x22; 512], 512).unwrap();
+
+        // Crash before the second record was complete and applied
+        let mut inner = device.into_inner();
+        inner.write(2, &[0u8; 512], 512).unwrap();
+        inner.write(48 + 5, &[0xFF; 512], 512).unwrap();
+
+        let device = JournaledBlockDevice::in_region(inner, 16).unwrap();
+        assert_eq!(device.replayed(), 1);
+        assert_eq!(device.read(1, 1, 512).unwrap(), vec![0x11; 512]);
+        assert_eq!(device.read(2, 1, 512).unwrap(), vec![0u8; 512]);
+    }
+
+    #[test]
+    fn test_full_journal_checkpoints() {
+        let mut device = JournaledBlockDevice::in_region(MemBlockDevice::new(32, 512), 8).unwrap();
+        for lba in 0..10u8 {
+            device.write(lba as u64, &[lba; 1024], 512).unwrap();
+        }
+        assert!(device.write(0, &[0u8; 7 * 512], 512).is_err());
+
+        // Only the writes since the last checkpoint are replayed
+        let device = JournaledBlockDevice::in_region(device.into_inner(), 8).unwrap();
+        assert!(device.replayed() < 10);
+        for lba in 0..10u8 {
+            assert_eq!(device.read(lba as u64, 1, 512).unwrap(), vec![lba; 512]);
+        }
+    }
+
+    #[test]
+    fn test_journal_file() {
+        let path = std::env::temp_dir().join(format!("iscsi-journal-{}.jnl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut device = JournaledBlockDevice::with_file(MemBlockDevice::new(16, 512), &path, 8).unwrap();
+        assert_eq!(device.capacity(), 16);
+        device.write(15, &[0x33; 512], 512).unwrap();
+
+        let mut inner = device.into_inner();
+        inner.write(15, &[0u8; 512], 512).unwrap();
+        let mut device = JournaledBlockDevice::with_file(inner, &path, 8).unwrap();
+        assert_eq!(device.replayed(), 1);
+        assert_eq!(device.read(15, 1, 512).unwrap(), vec![0x33; 512]);
+
+        device.flush().unwrap();
+        let device = JournaledBlockDevice::with_file(device.into_inner(), &path, 8).unwrap();
+        assert_eq!(device.replayed(), 0);
+
+        // With both header slots damaged the journal is refused rather
+        // than silently reset
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        for slot in 0..2 {
+            file.seek(SeekFrom::Start(slot * 512 + 20)).unwrap();
+            file.write_all(&[0xFF]).unwrap();
+        }
+        drop(file);
+        assert!(matches!(
+            JournaledBlockDevice::with_file(device.into_inner(), &path, 8),
+            Err(IscsiError::Config(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_header_opens_other_slot() {
+        let mut device = JournaledBlockDevice::in_region(MemBlockDevice::new(64, 512), 16).unwrap();
+        device.write(1, &[0x11; 512], 512).unwrap();
+
+        // Crash while the checkpoint wrote the generation 2 header to slot 0
+        device.flush().unwrap();
+        let mut inner = device.into_inner();
+        let mut header = inner.read(48, 1, 512).unwrap();
+        header[20] ^= 0xFF;
+        inner.write(48, &header, 512).unwrap();
+        inner.write(1, &[0u8; 512], 512).unwrap();
+
+        // Generation 1 in slot 1 is replayed, and the checkpoint rewrites slot 0
+        let device = JournaledBlockDevice::in_region(inner, 16).unwrap();
+        assert_eq!(device.replayed(), 1);
+        assert_eq!(device.read(1, 1, 512).unwrap(), vec![0x11; 512]);
+        let mut device = JournaledBlockDevice::in_region(device.into_inner(), 16).unwrap();
+        assert_eq!(device.replayed(), 0);
+
+        // Headers alternate, so the next generation leaves slot 0 intact
+        device.write(2, &[0x22; 512], 512).unwrap();
+        device.flush().unwrap();
+        let mut inner = device.into_inner();
+        let mut header = inner.read(49, 1, 512).unwrap();
+        header[20] ^= 0xFF;
+        inner.write(49, &header, 512).unwrap();
+        let device = JournaledBlockDevice::in_region(inner, 16).unwrap();
+        assert_eq!(device.read(2, 1, 512).unwrap(), vec![0x22; 512]);
+    }
+
+    #[test]
+    fn test_protection_information_is_journaled() {
+        /// Keeps the PI written with each block
+        struct PiDevice {
+            inner: MemBlockDevice,
+            pi: Vec<ProtectionInfo>,
+        }
+
+        impl ScsiBlockDevice for PiDevice {
+            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                self.inner.read(lba, blocks, block_size)
+            }
+
+            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+                self.inner.write(lba, data, block_size)
+            }
+
+            fn capacity(&self) -> u64 {
+                self.inner.capacity()
+            }
+
+            fn block_size(&self) -> u32 {
+                self.inner.block_size()
+            }
+
+            fn protection_type(&self) -> u8 {
+                1
+            }
+
+            fn read_with_pi(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<(Vec<u8>, Vec<ProtectionInfo>)> {
+                let pi = self.pi[lba as usize..lba as usize + blocks as usize].to_vec();
+                Ok((self.read(lba, blocks, block_size)?, pi))
+            }
+
+            fn write_with_pi(&mut self, lba: u64, data: &[u8], pi: &[ProtectionInfo], block_size: u32) -> ScsiResult<()> {
+                self.pi[lba as usize..lba as usize + pi.len()].copy_from_slice(pi);
+                self.write(lba, data, block_size)
+            }
+        }
+
+        let inner = PiDevice { inner: MemBlockDevice::new(64, 512), pi: vec![ProtectionInfo::default(); 64] };
+        let mut device = JournaledBlockDevice::in_region(inner, 16).unwrap();
+        assert_eq!(device.protection_type(), 1);
+        // 12 blocks of data and their PI fill the 13 record blocks
+        assert_eq!(device.block_limits().max_transfer_length, 12);
+
+        let data = [0x5A; 1024];
+        let pi: Vec<_> = data.chunks(512).zip(3..).map(|(block, lba)| ProtectionInfo::generate(block, 0x77, lba)).collect();
+        device.write_with_pi(3, &data, &pi, 512).unwrap();
+
+        // The inner device lost the write, PI included
+        let mut inner = device.into_inner();
+        inner.pi[3..5].fill(ProtectionInfo::default());
+        inner.write(3, &[0u8; 1024], 512).unwrap();
+
+        let device = JournaledBlockDevice::in_region(inner, 16).unwrap();
+        assert_eq!(device.replayed(), 1);
+        assert_eq!(device.read_with_pi(3, 2, 512).unwrap(), (data.to_vec(), pi));
+    }
+}
@@ -0,0 +1,189 @@
+//! In-memory reference backends
+
+use crate::error::{IscsiError, ScsiDeviceError, ScsiResult};
+use crate::scsi::{LbaStatus, ProvisioningStatus, ScsiBlockDevice};
+
+/// Byte range of `blocks` blocks at `lba`, checked against the device geometry
+fn block_range(lba: u64, blocks: u64, block_size: u32, expected_block_size: u32, capacity: u64) -> ScsiResult<std::ops::Range<usize>> {
+    if block_size != expected_block_size {
+        return Err(IscsiError::scsi(format!(
+            "block size mismatch: expected {}, got {}",
+            expected_block_size, block_size
+        )));
+    }
+    let end = lba.checked_add(blocks)
+        .filter(|end| *end <= capacity)
+        .ok_or(ScsiDeviceError::OutOfRange { lba })?;
+    Ok((lba * block_size as u64) as usize..(end * block_size as u64) as usize)
+}
+
+/// Number of whole blocks in `data`, or an error if it ends mid-block
+fn whole_blocks(data: &[u8], block_size: u32) -> ScsiResult<u64> {
+    if block_size == 0 || !data.len().is_multiple_of(block_size as usize) {
+        return Err(IscsiError::scsi(format!(
+            "{} bytes is not a whole number of {}-byte blocks",
+            data.len(), block_size
+        )));
+    }
+    Ok((data.len() / block_size as usize) as u64)
+}
+
+/// RAM disk, either fully preallocated or grown as blocks are written
+///
+/// A growable disk reports its full capacity but only allocates up to the
+/// highest block written; blocks beyond that read as zeros.
+#[derive(Debug, Clone)]
+pub struct MemBlockDevice {
+    data: Vec<u8>,
+    capacity: u64,
+    block_size: u32,
+}
+
+impl MemBlockDevice {
+    /// Zero-filled disk of `blocks` blocks, allocated up front
+    pub fn new(blocks: u64, block_size: u32) -> Self {
+        let mut device = Self::growable(blocks, block_size);
+        device.data = vec![0u8; (blocks * block_size as u64) as usize];
+        device
+    }
+
+    /// Disk of `blocks` blocks that allocates memory as it is written
+    pub fn growable(blocks: u64, block_size: u32) -> Self {
+        assert!(block_size > 0, "block size must be non-zero");
+        MemBlockDevice {
+            data: Vec::new(),
+            capacity: blocks,
+            block_size,
+        }
+    }
+
+    /// Bytes currently allocated (the whole disk unless growable)
+    pub fn allocated_bytes(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl ScsiBlockDevice for MemBlockDevice {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        let range = block_range(lba, blocks.into(), block_size, self.block_size, self.capacity)?;
+        let mut data = vec![0u8; range.len()];
+        if range.start < self.data.len() {
+            let stored = &self.data[range.start..range.end.min(self.data.len())];
+            data[..stored.len()].copy_from_slice(stored);
+        }
+        Ok(data)
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        let blocks = whole_blocks(data, block_size)?;
+        let range = block_range(lba, blocks, block_size, self.block_size, self.capacity)?;
+        if range.end > self.data.len() {
+            self.data.resize(range.end, 0);
+        }
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+}
+
+/// Discards writes and reads back zeros, for benchmarks and protocol tests
+#[derive(Debug, Clone, Copy)]
+pub struct NullBlockDevice {
+    capacity: u64,
+    block_size: u32,
+}
+
+impl NullBlockDevice {
+    /// Device reporting `blocks` blocks of `block_size` bytes
+    pub fn new(blocks: u64, block_size: u32) -> Self {
+        assert!(block_size > 0, "block size must be non-zero");
+        NullBlockDevice { capacity: blocks, block_size }
+    }
+}
+
+impl ScsiBlockDevice for NullBlockDevice {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        let range = block_range(lba, blocks.into(), block_size, self.block_size, self.capacity)?;
+        Ok(vec![0u8; range.len()])
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        let blocks = whole_blocks(data, block_size)?;
+        block_range(lba, blocks, block_size, self.block_size, self.capacity)?;
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn thin_provisioned(&self) -> bool {
+        true
+    }
+
+    fn unmapped_reads_zero(&self) -> bool {
+        true
+    }
+
+    fn lba_status(&self, lba: u64, blocks: u64) -> ScsiResult<Vec<LbaStatus>> {
+        // Nothing is ever stored
+        Ok(vec![LbaStatus {
+            lba,
+            blocks: blocks.min(u32::MAX as u64) as u32,
+            status: ProvisioningStatus::Deallocated,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mem_block_device_bounds() {
+        for mut device in [MemBlockDevice::new(8, 512), MemBlockDevice::growable(8, 512)] {
+            device.write(6, &[0xAA; 1024], 512).unwrap();
+            assert_eq!(device.read(6, 2, 512).unwrap(), vec![0xAA; 1024]);
+            assert_eq!(device.read(0, 1, 512).unwrap(), vec![0u8; 512]);
+
+            let err = device.read(7, 2, 512).unwrap_err();
+            assert!(matches!(err, IscsiError::Device(ScsiDeviceError::OutOfRange { lba: 7 })));
+            assert!(device.read(u64::MAX, 1, 512).is_err());
+            assert!(device.write(8, &[0u8; 512], 512).is_err());
+            assert!(device.write(0, &[0u8; 100], 512).is_err());
+            assert!(device.read(0, 1, 4096).is_err());
+        }
+    }
+
+    #[test]
+    fn test_growable_allocates_on_write() {
+        let mut device = MemBlockDevice::growable(1 << 30, 4096);
+        assert_eq!(device.allocated_bytes(), 0);
+        assert_eq!(device.read(1000, 1, 4096).unwrap(), vec![0u8; 4096]);
+
+        device.write(3, &[1u8; 4096], 4096).unwrap();
+        assert_eq!(device.allocated_bytes(), 4 * 4096);
+        assert_eq!(device.read(2, 3, 4096).unwrap()[4096..8192], [1u8; 4096]);
+        assert_eq!(device.capacity(), 1 << 30);
+    }
+
+    #[test]
+    fn test_null_block_device() {
+        let mut device = NullBlockDevice::new(16, 512);
+        device.write(0, &[0xFF; 512], 512).unwrap();
+        assert_eq!(device.read(0, 1, 512).unwrap(), vec![0u8; 512]);
+        assert!(device.write(16, &[0u8; 512], 512).is_err());
+        assert!(device.read(15, 2, 512).is_err());
+    }
+}
@@ -0,0 +1,552 @@
+//! Mirroring over several block devices (RAID 1)
+
+use crate::error::{IscsiError, ScsiDeviceError, ScsiResult};
+use crate::events::{TargetEvent, TargetObserver};
+use crate::protection::ProtectionInfo;
+use crate::scsi::{ModeParameters, ScsiBlockDevice};
+use crate::vpd::{BlockLimits, Designator};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Blocks copied per request by `MirroredBlockDevice::rebuild`
+const REBUILD_CHUNK_BLOCKS: u32 = 256;
+
+/// Writes every block to all members and reads from the first in-sync one
+///
+/// A write goes to every healthy member before its outcome is decided. A
+/// member that fails a command with a device fault (anything but BUSY, NOT
+/// READY or an error in the request itself) is marked failed and left out
+/// of later I/O; a read it failed is retried on the next member. A write
+/// succeeds as long as one in-sync member took it; healthy members that
+/// refused it are then out of sync and are no longer read from, though they
+/// keep taking writes. If no in-sync member took the write, the command
+/// fails with the first transient or request error, or else the last fault.
+/// The observer, if any, is told with `TargetEvent::MirrorMemberFailed` and
+/// `TargetEvent::MirrorMemberOutOfSync`. A failed or out-of-sync member
+/// rejoins once `rebuild` has copied the mirror onto it (or onto its
+/// replacement).
+///
+/// The members must have the same block size and protection type; the
+/// mirror's capacity is that of the smallest member. INQUIRY identity and
+/// mode parameters come from the first member.
+///
+/// # Example
+/// ```
+/// use iscsi_target::backends::{MemBlockDevice, MirroredBlockDevice};
+/// use iscsi_target::ScsiBlockDevice;
+///
+/// # fn main() -> iscsi_target::ScsiResult<()> {
+/// let mut mirror = MirroredBlockDevice::new(vec![MemBlockDevice::new(64, 512), MemBlockDevice::new(64, 512)])?;
+/// mirror.write(0, &[7; 512], 512)?;
+/// assert_eq!(mirror.members()[1].read(0, 1, 512)?, vec![7; 512]);
+/// assert!(!mirror.is_degraded());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MirroredBlockDevice<D: ScsiBlockDevice> {
+    members: Vec<D>,
+    /// Set once a member fails; reads take `&self`, so this is atomic
+    failed: Vec<AtomicBool>,
+    /// Set once a healthy member misses a write; cleared by `rebuild`
+    out_of_sync: Vec<AtomicBool>,
+    capacity: u64,
+    block_size: u32,
+    observer: Option<Arc<dyn TargetObserver>>,
+}
+
+impl<D: ScsiBlockDevice> MirroredBlockDevice<D> {
+    /// Mirror `members`, all starting out healthy
+    pub fn new(members: Vec<D>) -> ScsiResult<Self> {
+        let Some(first) = members.first() else {
+            return Err(IscsiError::Config("a mirror needs at least one member".to_string()));
+        };
+        let block_size = first.block_size();
+        if let Some(i) = members.iter().position(|member| member.block_size() != block_size) {
+            return Err(IscsiError::Config(format!(
+                "member {} has {}-byte blocks, member 0 has {}-byte blocks",
+                i, members[i].block_size(), block_size
+            )));
+        }
+        let protection_type = first.protection_type();
+        if let Some(i) = members.iter().position(|member| member.protection_type() != protection_type) {
+            return Err(IscsiError::Config(format!(
+                "member {} has protection type {}, member 0 has type {}",
+                i, members[i].protection_type(), protection_type
+            )));
+        }
+        let capacity = members.iter().map(|member| member.capacity()).min().unwrap_or(0);
+        let failed = members.iter().map(|_| AtomicBool::new(false)).collect();
+        let out_of_sync = members.iter().map(|_| AtomicBool::new(false)).collect();
+        Ok(MirroredBlockDevice { members, failed, out_of_sync, capacity, block_size, observer: None })
+    }
+
+    /// Report member failures to `observer`
+    pub fn observer(mut self, observer: Arc<dyn TargetObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// The member devices, in order
+    pub fn members(&self) -> &[D] {
+        &self.members
+    }
+
+    /// Unwrap the member devices
+    pub fn into_members(self) -> Vec<D> {
+        self.members
+    }
+
+    /// Whether `member` is still taking I/O
+    pub fn is_healthy(&self, member: usize) -> bool {
+        self.failed.get(member).is_some_and(|failed| !failed.load(Ordering::Acquire))
+    }
+
+    /// Indices of the members still taking I/O
+    pub fn healthy_members(&self) -> Vec<usize> {
+        (0..self.members.len()).filter(|&i| self.is_healthy(i)).collect()
+    }
+
+    /// Whether `member` is healthy and holds everything written to the mirror
+    pub fn is_in_sync(&self, member: usize) -> bool {
+        self.is_healthy(member) && !self.out_of_sync[member].load(Ordering::Acquire)
+    }
+
+    /// Indices of the members reads are served from
+    pub fn in_sync_members(&self) -> Vec<usize> {
+        (0..self.members.len()).filter(|&i| self.is_in_sync(i)).collect()
+    }
+
+    /// Whether any member has failed or fallen out of sync
+    pub fn is_degraded(&self) -> bool {
+        self.in_sync_members().len() < self.members.len()
+    }
+
+    /// Take `member` out of the mirror, e.g. before replacing its device
+    pub fn fail_member(&self, member: usize) {
+        if let Some(failed) = self.failed.get(member) {
+            failed.store(true, Ordering::Release);
+        }
+    }
+
+    /// Swap in a new device for a failed member; it stays failed until rebuilt
+    pub fn replace_member(&mut self, member: usize, device: D) -> ScsiResult<D> {
+        if self.is_healthy(member) || member >= self.members.len() {
+            return Err(IscsiError::Config(format!("member {} is not a failed member of the mirror", member)));
+        }
+        if device.block_size() != self.block_size || device.capacity() < self.capacity {
+            return Err(IscsiError::Config(format!(
+                "replacement has {} blocks of {} bytes, the mirror needs {} of {}",
+                device.capacity(), device.block_size(), self.capacity, self.block_size
+            )));
+        }
+        Ok(std::mem::replace(&mut self.members[member], device))
+    }
+
+    /// Copy the mirror's contents onto a failed or out-of-sync member and
+    /// return it to service
+    pub fn rebuild(&mut self, member: usize) -> ScsiResult<()> {
+        if self.is_in_sync(member) || member >= self.members.len() {
+            return Err(IscsiError::Config(format!("member {} is not a failed member of the mirror", member)));
+        }
+        let mut lba = 0;
+        while lba < self.capacity {
+            let blocks = (self.capacity - lba).min(REBUILD_CHUNK_BLOCKS.into()) as u32;
+            let data = self.read(lba, blocks, self.block_size)?;
+            self.members[member].write(lba, &data, self.block_size)?;
+            lba += u64::from(blocks);
+        }
+        self.members[member].flush()?;
+        self.out_of_sync[member].store(false, Ordering::Release);
+        self.failed[member].store(false, Ordering::Release);
+        log::info!("Mirror member {} rebuilt", member);
+        Ok(())
+    }
+
+    /// Record that `member` failed with `error`
+    fn member_failed(&self, member: usize, error: &IscsiError) {
+        if self.failed[member].swap(true, Ordering::AcqRel) {
+            return;
+        }
+        log::error!("Mirror member {} failed: {}", member, error);
+        if let Some(observer) = &self.observer {
+            observer.on_event(&TargetEvent::MirrorMemberFailed { member, error: error.to_string() });
+        }
+    }
+
+    /// Record that healthy `member` missed a write the others took
+    fn member_out_of_sync(&self, member: usize, error: &IscsiError) {
+        if self.out_of_sync[member].swap(true, Ordering::AcqRel) {
+            return;
+        }
+        log::warn!("Mirror member {} is out of sync: {}", member, error);
+        if let Some(observer) = &self.observer {
+            observer.on_event(&TargetEvent::MirrorMemberOutOfSync { member, error: error.to_string() });
+        }
+    }
+
+    /// Run `op` on every healthy member, then decide the outcome
+    ///
+    /// Returns the first in-sync member's result if any in-sync member
+    /// succeeded; healthy members that did not are marked out of sync.
+    fn on_all<T>(&mut self, mut op: impl FnMut(&mut D) -> ScsiResult<T>) -> ScsiResult<T> {
+        let mut results = Vec::new();
+        for member in 0..self.members.len() {
+            if self.is_healthy(member) {
+                results.push((member, self.is_in_sync(member), op(&mut self.members[member])));
+            }
+        }
+
+        let succeeded = results.iter().any(|(_, in_sync, result)| *in_sync && result.is_ok());
+        let mut value = None;
+        let mut refused = None;
+        let mut fault = None;
+        for (member, in_sync, result) in results {
+            match result {
+                Ok(result) => {
+                    if in_sync && value.is_none() {
+                        value = Some(result);
+                    }
+                }
+                Err(e) if is_member_fault(&e) => {
+                    self.member_failed(member, &e);
+                    fault = Some(e);
+                }
+                Err(e) => {
+                    if succeeded {
+                        self.member_out_of_sync(member, &e);
+                    }
+                    refused.get_or_insert(e);
+                }
+            }
+        }
+        match (value, refused, fault) {
+            (Some(value), _, _) => Ok(value),
+            (None, Some(e), _) | (None, None, Some(e)) => Err(e),
+            (None, None, None) => Err(ScsiDeviceError::NotReady.into()),
+        }
+    }
+
+    /// Run `op` on each in-sync member in turn until one succeeds
+    ///
+    /// A member fault moves on to the next member; other errors are
+    /// returned as they are.
+    fn on_first<T>(&self, op: impl Fn(&D) -> ScsiResult<T>) -> ScsiResult<T> {
+        let mut last_error = None;
+        for member in self.in_sync_members() {
+            match op(&self.members[member]) {
+                Ok(value) => return Ok(value),
+                Err(e) if is_member_fault(&e) => {
+                    self.member_failed(member, &e);
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or(ScsiDeviceError::NotReady.into()))
+    }
+
+    /// First in-sync member, which answers PRE-FETCH
+    fn primary(&self) -> &D {
+        let member = self.in_sync_members().first().copied().unwrap_or(0);
+        &self.members[member]
+    }
+
+    fn check_request(&self, lba: u64, blocks: u64, block_size: u32) -> ScsiResult<()> {
+        if block_size != self.block_size {
+            return Err(IscsiError::scsi(format!(
+                "block size mismatch: expected {}, got {}",
+                self.block_size, block_size
+            )));
+        }
+        lba.checked_add(blocks)
+            .filter(|end| *end <= self.capacity)
+            .ok_or(ScsiDeviceError::OutOfRange { lba })?;
+        Ok(())
+    }
+}
+
+impl<D: ScsiBlockDevice> ScsiBlockDevice for MirroredBlockDevice<D> {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        self.check_request(lba, blocks.into(), block_size)?;
+        self.on_first(|member| member.read(lba, blocks, block_size))
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        self.check_request(lba, (data.len() / block_size.max(1) as usize) as u64, block_size)?;
+        self.on_all(|member| member.write(lba, data, block_size))
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        self.on_all(|member| member.flush())
+    }
+
+    fn write_cache_enabled(&self) -> bool {
+        self.members.iter().any(|member| member.write_cache_enabled())
+    }
+
+    fn prefetch(&self, lba: u64, blocks: u32) -> ScsiResult<bool> {
+        self.primary().prefetch(lba, blocks)
+    }
+
+    fn abort_write(&mut self, lba: u64, blocks: u32) -> ScsiResult<()> {
+        self.on_all(|member| member.abort_write(lba, blocks))
+    }
+
+    fn protection_type(&self) -> u8 {
+        self.members[0].protection_type()
+    }
+
+    fn read_with_pi(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<(Vec<u8>, Vec<ProtectionInfo>)> {
+        self.check_request(lba, blocks.into(), block_size)?;
+        self.on_first(|member| member.read_with_pi(lba, blocks, block_size))
+    }
+
+    fn write_with_pi(&mut self, lba: u64, data: &[u8], pi: &[ProtectionInfo], block_size: u32) -> ScsiResult<()> {
+        self.check_request(lba, (data.len() / block_size.max(1) as usize) as u64, block_size)?;
+        self.on_all(|member| member.write_with_pi(lba, data, pi, block_size))
+    }
+
+    fn bidirectional(&mut self, cdb: &[u8], data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+        self.on_all(|member| member.bidirectional(cdb, data_out))
+    }
+
+    fn physical_block_exponent(&self) -> u8 {
+        self.members.iter().map(|member| member.physical_block_exponent()).max().unwrap_or(0)
+    }
+
+    fn vendor_id(&self) -> &str {
+        self.members[0].vendor_id()
+    }
+
+    fn product_id(&self) -> &str {
+        self.members[0].product_id()
+    }
+
+    fn product_rev(&self) -> &str {
+        self.members[0].product_rev()
+    }
+
+    fn serial_number(&self) -> &str {
+        self.members[0].serial_number()
+    }
+
+    fn designators(&self) -> Vec<Designator> {
+        self.members[0].designators()
+    }
+
+    fn block_limits(&self) -> BlockLimits {
+        let max_transfer_length = self.members.iter()
+            .map(|member| member.block_limits().max_transfer_length)
+            .filter(|&max| max != 0)
+            .min()
+            .unwrap_or(0);
+        BlockLimits { max_transfer_length, ..self.members[0].block_limits() }
+    }
+
+    fn mode_parameters(&self) -> ModeParameters {
+        self.members[0].mode_parameters()
+    }
+}
+
+/// Whether `error` means the member itself is faulty, rather than busy for
+/// now or refusing the request as any member would
+fn is_member_fault(error: &IscsiError) -> bool {
+    !matches!(
+        error,
+        IscsiError::Device(ScsiDeviceError::Busy | ScsiDeviceError::NotReady | ScsiDeviceError::OutOfRange { .. } | ScsiDeviceError::Custom(_))
+            | IscsiError::Scsi { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemBlockDevice;
+    use std::sync::Mutex;
+
+    /// Memory device that fails every command once `broken` is set
+    #[derive(Debug)]
+    struct Flaky {
+        inner: MemBlockDevice,
+        broken: Arc<AtomicBool>,
+    }
+
+    impl Flaky {
+        fn new(blocks: u64) -> (Self, Arc<AtomicBool>) {
+            let broken = Arc::new(AtomicBool::new(false));
+            (Flaky { inner: MemBlockDevice::new(blocks, 512), broken: broken.clone() }, broken)
+        }
+
+        fn check(&self) -> ScsiResult<()> {
+            if self.broken.load(Ordering::SeqCst) {
+                return Err(ScsiDeviceError::MediumError.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl ScsiBlockDevice for Flaky {
+        fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+            self.check()?;
+            self.inner.read(lba, blocks, block_size)
+        }
+
+        fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+            self.check()?;
+            self.inner.write(lba, data, block_size)
+        }
+
+        fn capacity(&self) -> u64 {
+            self.inner.capacity()
+        }
+
+        fn block_size(&self) -> u32 {
+            512
+        }
+    }
+
+    /// Memory device that answers BUSY while `busy` is set
+    #[derive(Debug)]
+    struct Stalling {
+        inner: MemBlockDevice,
+        busy: Arc<AtomicBool>,
+    }
+
+    impl ScsiBlockDevice for Stalling {
+        fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+            self.inner.read(lba, blocks, block_size)
+        }
+
+        fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+            if self.busy.load(Ordering::SeqCst) {
+                return Err(ScsiDeviceError::Busy.into());
+            }
+            self.inner.write(lba, data, block_size)
+        }
+
+        fn capacity(&self) -> u64 {
+            self.inner.capacity()
+        }
+
+        fn block_size(&self) -> u32 {
+            512
+        }
+
+        fn mode_parameters(&self) -> ModeParameters {
+            ModeParameters { read_retry_count: 7, ..ModeParameters::default() }
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<TargetEvent>>,
+    }
+
+    impl TargetObserver for RecordingObserver {
+        fn on_event(&self, event: &TargetEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_mirror_survives_member_failure() {
+        let (first, first_broken) = Flaky::new(32);
+        let (second, second_broken) = Flaky::new(40);
+        let observer = Arc::new(RecordingObserver::default());
+        let mut mirror = MirroredBlockDevice::new(vec![first, second]).unwrap().observer(observer.clone());
+        assert_eq!(mirror.capacity(), 32);
+
+        mirror.write(4, &[1; 1024], 512).unwrap();
+        first_broken.store(true, Ordering::SeqCst);
+        // The read fails over to the second member
+        assert_eq!(mirror.read(4, 2, 512).unwrap(), vec![1; 1024]);
+        assert_eq!(mirror.healthy_members(), vec![1]);
+        assert!(mirror.is_degraded());
+        mirror.write(6, &[2; 512], 512).unwrap();
+
+        assert_eq!(*observer.events.lock().unwrap(), vec![TargetEvent::MirrorMemberFailed {
+            member: 0,
+            error: "Device error: unrecovered medium error".to_string(),
+        }]);
+
+        // Repair the first member and copy the blocks it missed back onto it
+        first_broken.store(false, Ordering::SeqCst);
+        mirror.rebuild(0).unwrap();
+        assert!(!mirror.is_degraded());
+        assert_eq!(mirror.members()[0].read(6, 1, 512).unwrap(), vec![2; 512]);
+
+        first_broken.store(true, Ordering::SeqCst);
+        second_broken.store(true, Ordering::SeqCst);
+        assert!(mirror.write(0, &[3; 512], 512).is_err());
+        assert!(mirror.read(0, 1, 512).is_err());
+    }
+
+    #[test]
+    fn test_mirror_busy_member_falls_out_of_sync() {
+        let stalling = || {
+            let busy = Arc::new(AtomicBool::new(false));
+            (Stalling { inner: MemBlockDevice::new(16, 512), busy: busy.clone() }, busy)
+        };
+        let (first, first_busy) = stalling();
+        let (second, second_busy) = stalling();
+        let observer = Arc::new(RecordingObserver::default());
+        let mut mirror = MirroredBlockDevice::new(vec![first, second]).unwrap().observer(observer.clone());
+        assert_eq!(mirror.mode_parameters().read_retry_count, 7);
+
+        // Busy everywhere: the write fails as BUSY and nothing diverges
+        first_busy.store(true, Ordering::SeqCst);
+        second_busy.store(true, Ordering::SeqCst);
+        assert!(matches!(mirror.write(0, &[1; 512], 512), Err(IscsiError::Device(ScsiDeviceError::Busy))));
+        assert!(!mirror.is_degraded());
+
+        // The first member is busy, the second takes the write: the first is
+        // kept but no longer read from
+        second_busy.store(false, Ordering::SeqCst);
+        mirror.write(0, &[2; 512], 512).unwrap();
+        assert_eq!(mirror.healthy_members(), vec![0, 1]);
+        assert_eq!(mirror.in_sync_members(), vec![1]);
+        assert_eq!(mirror.read(0, 1, 512).unwrap(), vec![2; 512]);
+        assert_eq!(*observer.events.lock().unwrap(), vec![TargetEvent::MirrorMemberOutOfSync {
+            member: 0,
+            error: "Device error: device busy".to_string(),
+        }]);
+
+        // A command every member refuses fails no member
+        assert!(mirror.bidirectional(&[0x53; 10], &[0; 512]).is_err());
+        assert_eq!(mirror.healthy_members(), vec![0, 1]);
+
+        first_busy.store(false, Ordering::SeqCst);
+        mirror.rebuild(0).unwrap();
+        assert!(!mirror.is_degraded());
+        assert_eq!(mirror.members()[0].read(0, 1, 512).unwrap(), vec![2; 512]);
+    }
+
+    #[test]
+    fn test_mirror_replace_member() {
+        let members = vec![MemBlockDevice::new(16, 512), MemBlockDevice::new(16, 512)];
+        let mut mirror = MirroredBlockDevice::new(members).unwrap();
+        mirror.write(15, &[9; 512], 512).unwrap();
+        assert!(matches!(mirror.read(15, 2, 512), Err(IscsiError::Device(ScsiDeviceError::OutOfRange { lba: 15 }))));
+        assert!(!mirror.is_degraded());
+
+        assert!(mirror.replace_member(1, MemBlockDevice::new(16, 512)).is_err());
+        mirror.fail_member(1);
+        assert!(mirror.replace_member(1, MemBlockDevice::new(8, 512)).is_err());
+        mirror.replace_member(1, MemBlockDevice::new(16, 512)).unwrap();
+        mirror.rebuild(1).unwrap();
+        assert_eq!(mirror.members()[1].read(15, 1, 512).unwrap(), vec![9; 512]);
+        assert!(matches!(mirror.rebuild(1), Err(IscsiError::Config(_))));
+
+        let mixed = vec![MemBlockDevice::new(8, 512), MemBlockDevice::new(8, 4096)];
+        assert!(matches!(MirroredBlockDevice::new(mixed), Err(IscsiError::Config(_))));
+    }
+}
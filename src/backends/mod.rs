@@ -0,0 +1,32 @@
+//! Reusable `ScsiBlockDevice` implementations and adapters
+//!
+//! `MemBlockDevice` (a RAM disk) and `NullBlockDevice` (discards writes,
+//! reads zeros) are reference backends for examples and tests. Adapters wrap
+//! another device to add behaviour without the backend having to implement
+//! it: `EncryptedBlockDevice` encrypts data at rest, `JournaledBlockDevice`
+//! makes writes crash-consistent, `GeometryBlockDevice` reports configured
+//! logical and physical block sizes. `CompositeBlockDevice` stripes or
+//! concatenates several devices into one, `MirroredBlockDevice` mirrors
+//! them. `FaultyBlockDevice` injects errors and latency for testing. On Linux, `RawBlockDevice` re-exports a
+//! host block device.
+
+mod composite;
+mod encrypted;
+mod faulty;
+mod geometry;
+mod journal;
+mod memory;
+mod mirror;
+#[cfg(target_os = "linux")]
+mod raw;
+mod xts;
+
+pub use composite::{CompositeBlockDevice, CompositeLayout};
+pub use encrypted::EncryptedBlockDevice;
+pub use faulty::{FaultInjector, FaultyBlockDevice};
+pub use geometry::{BlockGeometry, GeometryBlockDevice};
+pub use journal::JournaledBlockDevice;
+pub use memory::{MemBlockDevice, NullBlockDevice};
+pub use mirror::MirroredBlockDevice;
+#[cfg(target_os = "linux")]
+pub use raw::RawBlockDevice;
@@ -0,0 +1,207 @@
+//! Medium-access CDB decoding
+//!
+//! `Cdb::decode` turns the CDB of every command that addresses a range of
+//! blocks into a typed command. The SCSI handlers, the target's WRITE path
+//! and read-ahead all use it, so a new opcode or CDB size is added here once.
+
+use crate::scsi::variable_length;
+use byteorder::{BigEndian, ByteOrder};
+
+/// READ (6), (10), (12), (16) or (32)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsedRead {
+    /// First logical block
+    pub lba: u64,
+    /// Blocks to transfer
+    pub blocks: u32,
+    /// RDPROTECT (always 0 for READ (6))
+    pub rdprotect: u8,
+    /// Disable page out: the data is not worth caching
+    pub dpo: bool,
+    /// Force unit access: read from the medium, not a cache
+    pub fua: bool,
+}
+
+/// WRITE (6), (10), (12), (16) or (32)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsedWrite {
+    /// First logical block
+    pub lba: u64,
+    /// Blocks to transfer
+    pub blocks: u32,
+    /// WRPROTECT (always 0 for WRITE (6))
+    pub wrprotect: u8,
+    /// Disable page out: the data is not worth caching
+    pub dpo: bool,
+    /// Force unit access: the data must be on stable storage before status
+    pub fua: bool,
+}
+
+/// VERIFY (10) or (16)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsedVerify {
+    /// First logical block
+    pub lba: u64,
+    /// Blocks to verify
+    pub blocks: u32,
+    /// BYTCHK: 00 medium only, 01 compare Data-Out, 11 compare one block
+    pub bytchk: u8,
+}
+
+/// PRE-FETCH (10) or (16)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsedPreFetch {
+    /// First logical block
+    pub lba: u64,
+    /// Blocks to prefetch (0 = through the last block)
+    pub blocks: u32,
+}
+
+/// A decoded medium-access command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Cdb {
+    Read(ParsedRead),
+    Write(ParsedWrite),
+    Verify(ParsedVerify),
+    PreFetch(ParsedPreFetch),
+}
+
+impl Cdb {
+    /// Decode a medium-access CDB. Returns None for other commands and for
+    /// CDBs too short to carry their fields.
+    pub fn decode(cdb: &[u8]) -> Option<Self> {
+        let opcode = *cdb.first()?;
+        let (lba, blocks) = match opcode {
+            0x08 | 0x0A if cdb.len() >= 6 => {
+                let lba = (u64::from(cdb[1] & 0x1F) << 16) | (u64::from(cdb[2]) << 8) | u64::from(cdb[3]);
+                // A transfer length of 0 means 256 blocks for the 6-byte forms
+                let blocks = if cdb[4] == 0 { 256 } else { u32::from(cdb[4]) };
+                (lba, blocks)
+            }
+            0x28 | 0x2A | 0x2F | 0x34 if cdb.len() >= 10 => {
+                (u64::from(BigEndian::read_u32(&cdb[2..6])), u32::from(BigEndian::read_u16(&cdb[7..9])))
+            }
+            0xA8 | 0xAA if cdb.len() >= 12 => {
+                (u64::from(BigEndian::read_u32(&cdb[2..6])), BigEndian::read_u32(&cdb[6..10]))
+            }
+            0x88 | 0x8A | 0x8F | 0x90 if cdb.len() >= 16 => {
+                (BigEndian::read_u64(&cdb[2..10]), BigEndian::read_u32(&cdb[10..14]))
+            }
+            0x7F if cdb.len() >= 32 => (BigEndian::read_u64(&cdb[12..20]), BigEndian::read_u32(&cdb[28..32])),
+            _ => return None,
+        };
+
+        // The 6-byte forms have no PROTECT, DPO or FUA bits; the 32-byte forms
+        // carry them in byte 10
+        let flags = match opcode {
+            0x08 | 0x0A => 0,
+            0x7F => cdb[10],
+            _ => cdb[1],
+        };
+        let (protect, dpo, fua) = (flags >> 5, flags & 0x10 != 0, flags & 0x08 != 0);
+        Some(match opcode {
+            0x08 | 0x28 | 0xA8 | 0x88 => Cdb::Read(ParsedRead { lba, blocks, rdprotect: protect, dpo, fua }),
+            0x0A | 0x2A | 0xAA | 0x8A => Cdb::Write(ParsedWrite { lba, blocks, wrprotect: protect, dpo, fua }),
+            0x2F | 0x8F => Cdb::Verify(ParsedVerify { lba, blocks, bytchk: (cdb[1] >> 1) & 0x03 }),
+            0x34 | 0x90 => Cdb::PreFetch(ParsedPreFetch { lba, blocks }),
+            _ => match BigEndian::read_u16(&cdb[8..10]) {
+                variable_length::READ_32 => Cdb::Read(ParsedRead { lba, blocks, rdprotect: protect, dpo, fua }),
+                variable_length::WRITE_32 => Cdb::Write(ParsedWrite { lba, blocks, wrprotect: protect, dpo, fua }),
+                _ => return None,
+            },
+        })
+    }
+
+    /// Starting LBA and block count of the command
+    pub fn lba_range(&self) -> (u64, u32) {
+        match *self {
+            Cdb::Read(ParsedRead { lba, blocks, .. })
+            | Cdb::Write(ParsedWrite { lba, blocks, .. })
+            | Cdb::Verify(ParsedVerify { lba, blocks, .. })
+            | Cdb::PreFetch(ParsedPreFetch { lba, blocks }) => (lba, blocks),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_write_forms() {
+        let read = |lba, blocks, rdprotect| Some(Cdb::Read(ParsedRead { lba, blocks, rdprotect, dpo: false, fua: false }));
+        let write = |lba, blocks, wrprotect| Some(Cdb::Write(ParsedWrite { lba, blocks, wrprotect, dpo: false, fua: false }));
+
+        // 6-byte forms: 21-bit LBA, 0 blocks means 256
+        assert_eq!(Cdb::decode(&[0x08, 0xE1, 0x23, 0x45, 0, 0]), read(0x12345, 256, 0));
+        assert_eq!(Cdb::decode(&[0x0A, 0, 0, 7, 2, 0]), write(7, 2, 0));
+        assert_eq!(Cdb::decode(&[0x28, 0x20, 0, 0, 1, 0, 0, 0, 8, 0]), read(256, 8, 1));
+        assert_eq!(Cdb::decode(&[0x2A, 0, 0, 0, 0, 9, 0, 0, 1, 0]), write(9, 1, 0));
+        // 12-byte forms carry a 32-bit transfer length
+        assert_eq!(Cdb::decode(&[0xA8, 0x60, 0, 0, 0, 5, 0, 1, 0, 0, 0, 0]), read(5, 0x10000, 3));
+        assert_eq!(Cdb::decode(&[0xAA, 0, 0, 0, 0, 5, 0, 0, 0, 3, 0, 0]), write(5, 3, 0));
+
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x8A;
+        cdb[2..10].copy_from_slice(&(1u64 << 40).to_be_bytes());
+        cdb[10..14].copy_from_slice(&4u32.to_be_bytes());
+        assert_eq!(Cdb::decode(&cdb), write(1 << 40, 4, 0));
+
+        let mut cdb = [0u8; 32];
+        cdb[0] = 0x7F;
+        cdb[8..10].copy_from_slice(&variable_length::READ_32.to_be_bytes());
+        cdb[10] = 0x20;
+        cdb[12..20].copy_from_slice(&77u64.to_be_bytes());
+        cdb[28..32].copy_from_slice(&2u32.to_be_bytes());
+        assert_eq!(Cdb::decode(&cdb), read(77, 2, 1));
+        // Other variable-length service actions are not medium access
+        cdb[8..10].copy_from_slice(&0x0001u16.to_be_bytes());
+        assert_eq!(Cdb::decode(&cdb), None);
+    }
+
+    #[test]
+    fn test_dpo_and_fua_bits() {
+        let bits = |cdb: &[u8]| match Cdb::decode(cdb) {
+            Some(Cdb::Read(read)) => (read.dpo, read.fua),
+            Some(Cdb::Write(write)) => (write.dpo, write.fua),
+            other => panic!("not a READ or WRITE: {:?}", other),
+        };
+        assert_eq!(bits(&[0x2A, 0x08, 0, 0, 0, 9, 0, 0, 1, 0]), (false, true));
+        assert_eq!(bits(&[0x28, 0x10, 0, 0, 0, 9, 0, 0, 1, 0]), (true, false));
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x8A;
+        cdb[1] = 0x38;
+        assert_eq!(bits(&cdb), (true, true));
+        // Byte 1 of the 6-byte forms is part of the LBA
+        assert_eq!(bits(&[0x0A, 0x18, 0, 7, 2, 0]), (false, false));
+
+        let mut cdb = [0u8; 32];
+        cdb[0] = 0x7F;
+        cdb[8..10].copy_from_slice(&variable_length::WRITE_32.to_be_bytes());
+        cdb[10] = 0x08;
+        assert_eq!(bits(&cdb), (false, true));
+    }
+
+    #[test]
+    fn test_verify_and_prefetch_forms() {
+        assert_eq!(
+            Cdb::decode(&[0x2F, 0x06, 0, 0, 0, 9, 0, 0, 1, 0]),
+            Some(Cdb::Verify(ParsedVerify { lba: 9, blocks: 1, bytchk: 3 }))
+        );
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x90;
+        cdb[9] = 4;
+        cdb[13] = 8;
+        assert_eq!(Cdb::decode(&cdb), Some(Cdb::PreFetch(ParsedPreFetch { lba: 4, blocks: 8 })));
+        assert_eq!(Cdb::decode(&cdb).unwrap().lba_range(), (4, 8));
+    }
+
+    #[test]
+    fn test_short_and_other_cdbs() {
+        assert_eq!(Cdb::decode(&[0xA8, 0, 0, 0, 0, 5, 0, 0, 0, 3]), None);
+        assert_eq!(Cdb::decode(&[0x2A, 0, 0, 0, 0, 9]), None);
+        assert_eq!(Cdb::decode(&[0x00, 0, 0, 0, 0, 0]), None);
+        assert_eq!(Cdb::decode(&[]), None);
+    }
+}
@@ -9,8 +9,10 @@
 //! - Raw TCP socket connection to iSCSI targets
 //! - PDU transmission and reception
 //! - Session state management
-//! - Login/logout phases
-//! - SCSI command execution
+//! - Login/logout phases, with optional CHAP authentication
+//! - CRC32C data digests
+//! - SCSI command execution, including typed helpers such as `read_blocks`,
+//!   `write_blocks`, `inquiry` and `read_capacity`
 //! - Arbitrary PDU transmission for testing edge cases
 //!
 //! # Example: Basic Connection and Login
@@ -24,7 +26,10 @@
 //!     "iqn.2025-12.local:initiator",
 //!     "iqn.2025-12.local:storage.disk1",
 //! )?;
-//! // ... send SCSI commands ...
+//! let inquiry = client.inquiry()?;
+//! println!("{} {}", inquiry.vendor, inquiry.product);
+//! let data = client.read_blocks(0, 8)?;
+//! client.write_blocks(0, &data)?;
 //! client.logout()?;
 //! # Ok(())
 //! # }
@@ -50,23 +55,156 @@
 //! # }
 //! ```
 
-use crate::error::{IscsiError, ScsiResult, decode_login_status};
-use crate::pdu::{self, IscsiPdu, opcode, flags, BHS_SIZE};
+use crate::auth::{ChapAlgorithm, ChapAuthState};
+use crate::error::{AuthFailure, IscsiError, ProtocolErrorKind, ScsiResult, SenseCode, decode_login_status};
+use crate::pdu::builder::PduBuilder;
+use crate::pdu::{self, Ahs, IscsiPdu, PduLimits, opcode, flags, BHS_SIZE, Digests, DIGEST_SIZE};
+use crate::scsi::scsi_status;
+use crate::session::SessionParams;
+use byteorder::{BigEndian, ByteOrder};
 use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::time::Duration;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Initiator Task Tag used for all login PDUs of a connection
+const LOGIN_ITT: u32 = 0;
+
+/// MaxRecvDataSegmentLength the client declares at login
+const CLIENT_MAX_RECV_DATA_SEGMENT_LENGTH: u32 = 8192;
+
+/// Capacity reported by READ CAPACITY
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    /// Number of logical blocks
+    pub blocks: u64,
+    /// Logical block size in bytes
+    pub block_size: u32,
+}
+
+/// A target reported by SendTargets discovery (see `IscsiClient::discover`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredTarget {
+    /// Target name (IQN, EUI or NAA)
+    pub iqn: String,
+    /// Portals and their target portal group tags, in the order the target
+    /// prefers; empty when the target is only reachable through the portal
+    /// the discovery session used
+    pub portals: Vec<(SocketAddr, u16)>,
+}
+
+/// Identification fields of a standard INQUIRY response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InquiryData {
+    /// Peripheral device type (0x00 = direct access block device)
+    pub peripheral_device_type: u8,
+    /// T10 vendor identification, trailing spaces removed
+    pub vendor: String,
+    /// Product identification, trailing spaces removed
+    pub product: String,
+    /// Product revision level, trailing spaces removed
+    pub revision: String,
+}
+
+/// Operational keys proposed by `IscsiClient::login_with_options`
+///
+/// The defaults are what `login()` proposes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginOptions {
+    /// HeaderDigest list, most preferred first (e.g. `"CRC32C,None"`)
+    pub header_digest: String,
+    /// DataDigest list, most preferred first
+    pub data_digest: String,
+    /// Largest data segment the client accepts from the target
+    pub max_recv_data_segment_length: u32,
+    pub max_burst_length: u32,
+    pub first_burst_length: u32,
+    pub immediate_data: bool,
+    pub initial_r2t: bool,
+    /// Further keys, sent verbatim; one named like a key above replaces it
+    pub extra: Vec<(String, String)>,
+}
+
+impl Default for LoginOptions {
+    fn default() -> Self {
+        LoginOptions {
+            header_digest: "None".to_string(),
+            data_digest: "None".to_string(),
+            max_recv_data_segment_length: CLIENT_MAX_RECV_DATA_SEGMENT_LENGTH,
+            max_burst_length: 262144,
+            first_burst_length: 65536,
+            immediate_data: true,
+            initial_r2t: true,
+            extra: Vec::new(),
+        }
+    }
+}
+
+impl LoginOptions {
+    /// The Login Request keys, in the order they are sent
+    fn keys(&self) -> Vec<(String, String)> {
+        let yes_no = |value: bool| if value { "Yes" } else { "No" }.to_string();
+        let mut keys: Vec<(String, String)> = [
+            ("HeaderDigest", self.header_digest.clone()),
+            ("DataDigest", self.data_digest.clone()),
+            ("MaxRecvDataSegmentLength", self.max_recv_data_segment_length.to_string()),
+            ("MaxBurstLength", self.max_burst_length.to_string()),
+            ("FirstBurstLength", self.first_burst_length.to_string()),
+            ("DefaultTime2Wait", "2".to_string()),
+            ("DefaultTime2Retain", "20".to_string()),
+            ("MaxOutstandingR2T", "1".to_string()),
+            ("ImmediateData", yes_no(self.immediate_data)),
+            ("InitialR2T", yes_no(self.initial_r2t)),
+            ("DataPDUInOrder", "Yes".to_string()),
+            ("DataSequenceInOrder", "Yes".to_string()),
+            ("ErrorRecoveryLevel", "0".to_string()),
+        ].into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+        for (key, value) in &self.extra {
+            match keys.iter_mut().find(|(existing, _)| existing == key) {
+                Some(existing) => existing.1 = value.clone(),
+                None => keys.push((key.clone(), value.clone())),
+            }
+        }
+        keys
+    }
+}
+
+/// Result of an `X-diagnostic.echo` round trip (see `IscsiClient::diagnostic_echo`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoReply {
+    /// Payload echoed by the target
+    pub payload: String,
+    /// Time from sending the Text Request to receiving the Text Response
+    pub round_trip: Duration,
+    /// Target receive time in microseconds since the UNIX epoch, if reported
+    pub target_timestamp_us: Option<u64>,
+}
+
+/// Byte stream the client talks to the target over
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
 
 /// iSCSI Client for connecting to targets and sending/receiving PDUs
 ///
-/// The client maintains a TCP connection to the target and handles
-/// PDU serialization/deserialization.
+/// The client maintains a connection to the target, normally TCP, and
+/// handles PDU serialization/deserialization.
 pub struct IscsiClient {
-    stream: TcpStream,
+    stream: Box<dyn Stream>,
     cmd_sn: u32,
     exp_stat_sn: u32,
     max_cmd_sn: u32,
-    stat_sn: u32,
     initialized: bool,
+    isid: [u8; 6],
+    next_itt: u32,
+    digests: Digests,
+    request_data_digest: bool,
+    max_xmit_data_segment_length: u32,
+    first_burst_length: u32,
+    immediate_data: bool,
+    block_size: Option<u32>,
+    lun: u64,
+    limits: PduLimits,
+    negotiated: Option<SessionParams>,
 }
 
 impl IscsiClient {
@@ -80,25 +218,76 @@ impl IscsiClient {
     ///
     /// Returns an error if the TCP connection fails
     pub fn connect(addr: &str) -> ScsiResult<Self> {
-        let stream = TcpStream::connect(addr)
+        Self::connect_timeout(addr, Duration::from_secs(10))
+    }
+
+    /// Connect like `connect`, with `timeout` for connecting, reads and writes
+    pub fn connect_timeout(addr: &str, timeout: Duration) -> ScsiResult<Self> {
+        let addr = addr.to_socket_addrs()
+            .map_err(IscsiError::Io)?
+            .next()
+            .ok_or_else(|| IscsiError::Config(format!("{} does not resolve to an address", addr)))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)
             .map_err(IscsiError::Io)?;
 
         // Set blocking mode and timeouts
         stream.set_nonblocking(false)
             .map_err(IscsiError::Io)?;
-        stream.set_read_timeout(Some(Duration::from_secs(10)))
+        stream.set_read_timeout(Some(timeout))
             .map_err(IscsiError::Io)?;
-        stream.set_write_timeout(Some(Duration::from_secs(10)))
+        stream.set_write_timeout(Some(timeout))
             .map_err(IscsiError::Io)?;
 
-        Ok(IscsiClient {
-            stream,
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Use an already connected stream, such as the initiator end of `duplex()`
+    ///
+    /// Reads block for as long as the stream does; set any timeouts on it first.
+    pub fn from_stream(stream: impl Read + Write + Send + 'static) -> Self {
+        IscsiClient {
+            stream: Box::new(stream),
             cmd_sn: 0,
             exp_stat_sn: 0,
             max_cmd_sn: u32::MAX,
-            stat_sn: 0,
             initialized: false,
-        })
+            isid: random_isid(),
+            next_itt: 1,
+            digests: Digests::NONE,
+            request_data_digest: false,
+            max_xmit_data_segment_length: 8192,
+            first_burst_length: 65536,
+            immediate_data: false,
+            block_size: None,
+            lun: 0,
+            limits: PduLimits::default(),
+            negotiated: None,
+        }
+    }
+
+    /// Request CRC32C data digests at the next login (default: off)
+    ///
+    /// The digest is only used if the target agrees; see `data_digest()`.
+    pub fn set_data_digest(&mut self, enabled: bool) {
+        self.request_data_digest = enabled;
+    }
+
+    /// Address SCSI commands to `lun` (default: LUN 0)
+    ///
+    /// The block size remembered from `read_capacity` is forgotten.
+    pub fn set_lun(&mut self, lun: u64) {
+        self.lun = lun;
+        self.block_size = None;
+    }
+
+    /// Set the length limits applied to PDUs received from the target
+    pub fn set_pdu_limits(&mut self, limits: PduLimits) {
+        self.limits = limits;
+    }
+
+    /// Whether data digests are in use on this connection
+    pub fn data_digest(&self) -> bool {
+        self.digests.data
     }
 
     /// Perform iSCSI login (security negotiation + operational negotiation + full feature phase)
@@ -112,82 +301,266 @@ impl IscsiClient {
     ///
     /// Returns an error if login fails at any phase
     pub fn login(&mut self, initiator_name: &str, target_name: &str) -> ScsiResult<()> {
+        let options = self.default_login_options();
+        self.login_with_options(initiator_name, target_name, &options).map(|_| ())
+    }
+
+    /// Log in without authentication, proposing `options`
+    ///
+    /// Returns the keys the target answered with in the operational
+    /// negotiation stage, so tests can check how each proposal was settled.
+    /// Negotiated digests and lengths take effect as they do for `login()`.
+    pub fn login_with_options(
+        &mut self,
+        initiator_name: &str,
+        target_name: &str,
+        options: &LoginOptions,
+    ) -> ScsiResult<Vec<(String, String)>> {
         // Phase 1: Security Negotiation
-        self.login_phase(
-            initiator_name,
-            target_name,
+        self.login_request(
             flags::CSG_SECURITY_NEG,
             flags::NSG_LOGIN_OP_NEG,
             false,
+            &[
+                ("InitiatorName", initiator_name),
+                ("TargetName", target_name),
+                ("SessionType", "Normal"),
+                ("AuthMethod", "None"),
+            ],
         )?;
 
         // Phase 2: Operational Negotiation (transitions to Full Feature Phase)
-        self.login_phase(
-            initiator_name,
-            target_name,
-            flags::CSG_LOGIN_OP_NEG,
-            flags::NSG_FULL_FEATURE,
+        self.operational_negotiation(initiator_name, target_name, options)
+    }
+
+    /// What `login()` proposes, honouring `set_data_digest()`
+    fn default_login_options(&self) -> LoginOptions {
+        let data_digest = if self.request_data_digest { "CRC32C,None" } else { "None" };
+        LoginOptions { data_digest: data_digest.to_string(), ..LoginOptions::default() }
+    }
+
+    /// Perform iSCSI login authenticating with one-way CHAP (MD5)
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - CHAP name (CHAP_N) configured on the target
+    /// * `secret` - CHAP secret; `0x`/`0b` prefixed secrets are decoded first
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the target does not offer CHAP or rejects the response
+    pub fn login_chap(
+        &mut self,
+        initiator_name: &str,
+        target_name: &str,
+        username: &str,
+        secret: &str,
+    ) -> ScsiResult<()> {
+        self.chap_login(initiator_name, target_name, username, secret, None)
+    }
+
+    /// Perform iSCSI login with mutual CHAP
+    ///
+    /// Authenticates to the target like `login_chap`, then challenges the
+    /// target and checks that it answers as `target_username` with
+    /// `target_secret`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either side fails to authenticate
+    pub fn login_mutual_chap(
+        &mut self,
+        initiator_name: &str,
+        target_name: &str,
+        username: &str,
+        secret: &str,
+        target_username: &str,
+        target_secret: &str,
+    ) -> ScsiResult<()> {
+        self.chap_login(initiator_name, target_name, username, secret, Some((target_username, target_secret)))
+    }
+
+    /// CHAP login, challenging the target too when `target` credentials are given
+    fn chap_login(
+        &mut self,
+        initiator_name: &str,
+        target_name: &str,
+        username: &str,
+        secret: &str,
+        target: Option<(&str, &str)>,
+    ) -> ScsiResult<()> {
+        let reply = self.login_request(
+            flags::CSG_SECURITY_NEG,
+            flags::NSG_LOGIN_OP_NEG,
+            false,
+            &[
+                ("InitiatorName", initiator_name),
+                ("TargetName", target_name),
+                ("SessionType", "Normal"),
+                ("AuthMethod", "CHAP,None"),
+            ],
+        )?;
+        if text_value(&reply, "AuthMethod") != Some("CHAP") {
+            return Err(IscsiError::auth(AuthFailure::MethodNotAccepted, format!(
+                "Target did not select CHAP (AuthMethod={})",
+                text_value(&reply, "AuthMethod").unwrap_or("<missing>")
+            )));
+        }
+
+        // Offer every supported algorithm, strongest first
+        let offered = ChapAlgorithm::ALL.iter()
+            .map(|algorithm| algorithm.code().to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let challenge = self.login_request(
+            flags::CSG_SECURITY_NEG,
+            flags::NSG_LOGIN_OP_NEG,
+            false,
+            &[("CHAP_A", &offered)],
+        )?;
+        let algorithm = text_value(&challenge, "CHAP_A")
+            .and_then(|value| value.parse::<ChapAlgorithm>().ok())
+            .ok_or_else(|| IscsiError::auth(AuthFailure::MethodNotAccepted, format!(
+                "Target selected an unsupported CHAP algorithm (CHAP_A={})",
+                text_value(&challenge, "CHAP_A").unwrap_or("<missing>")
+            )))?;
+        let identifier = text_value(&challenge, "CHAP_I")
+            .and_then(|id| id.parse::<u8>().ok())
+            .ok_or_else(|| IscsiError::auth(AuthFailure::MalformedMessage, "Missing or invalid CHAP_I from target".to_string()))?;
+        let challenge = text_value(&challenge, "CHAP_C")
+            .ok_or_else(|| IscsiError::auth(AuthFailure::MalformedMessage, "Missing CHAP_C from target".to_string()))
+            .and_then(crate::auth::decode_chap_secret)?;
+
+        let chap = ChapAuthState::for_challenge(identifier, challenge, false, algorithm);
+        let response = format!("0x{}", hex::encode(chap.calculate_response(secret)));
+        let Some((target_username, target_secret)) = target else {
+            self.login_request(
+                flags::CSG_SECURITY_NEG,
+                flags::NSG_LOGIN_OP_NEG,
+                true,
+                &[("CHAP_N", username), ("CHAP_R", &response)],
+            )?;
+            let options = self.default_login_options();
+            return self.operational_negotiation(initiator_name, target_name, &options).map(|_| ());
+        };
+
+        // Challenge the target with the same algorithm, never reusing its challenge
+        let mut ours = ChapAuthState::with_algorithm(true, algorithm);
+        while ours.challenge == chap.challenge {
+            ours.reissue();
+        }
+        let identifier = ours.identifier_str();
+        let challenge = ours.challenge_hex();
+        let (transited, reply) = self.login_exchange(
+            flags::CSG_SECURITY_NEG,
+            flags::NSG_LOGIN_OP_NEG,
             true,
+            &[("CHAP_N", username), ("CHAP_R", &response), ("CHAP_I", &identifier), ("CHAP_C", &challenge)],
         )?;
+        let answer = text_value(&reply, "CHAP_R")
+            .ok_or_else(|| IscsiError::auth(AuthFailure::MalformedMessage, "Target did not answer the mutual CHAP challenge".to_string()))
+            .and_then(crate::auth::parse_chap_response)?;
+        if text_value(&reply, "CHAP_N") != Some(target_username) || !ours.validate_response(&answer, target_secret) {
+            return Err(IscsiError::auth(AuthFailure::BadResponse, format!(
+                "Target failed mutual CHAP (CHAP_N={})",
+                text_value(&reply, "CHAP_N").unwrap_or("<missing>")
+            )));
+        }
+
+        // A target that answered without transiting is asked again to leave the security stage
+        if !transited {
+            self.login_request(flags::CSG_SECURITY_NEG, flags::NSG_LOGIN_OP_NEG, true, &[])?;
+        }
+        let options = self.default_login_options();
+        self.operational_negotiation(initiator_name, target_name, &options).map(|_| ())
+    }
+
+    /// Negotiate operational parameters and enter Full Feature Phase,
+    /// returning the target's reply keys
+    fn operational_negotiation(
+        &mut self,
+        initiator_name: &str,
+        target_name: &str,
+        options: &LoginOptions,
+    ) -> ScsiResult<Vec<(String, String)>> {
+        // InitiatorName, TargetName and SessionType went with the first
+        // request; declaring a key twice is a protocol error
+        let offered = options.keys();
+        let offered: Vec<(&str, &str)> = offered.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+        let reply = self.login_request(flags::CSG_LOGIN_OP_NEG, flags::NSG_FULL_FEATURE, true, &offered)?;
+
+        // Keys the target does not answer keep the value we offered
+        let mut negotiated = SessionParams {
+            initiator_name: initiator_name.to_string(),
+            target_name: target_name.to_string(),
+            ..SessionParams::default()
+        };
+        for (key, value) in offered.iter().copied().chain(reply.iter().map(|(key, value)| (key.as_str(), value.as_str()))) {
+            negotiated.set_negotiated(key, value);
+        }
+        negotiated.max_recv_data_segment_length = options.max_recv_data_segment_length;
+        negotiated.max_xmit_data_segment_length = text_value(&reply, "MaxRecvDataSegmentLength")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(negotiated.max_xmit_data_segment_length);
+        self.negotiated = Some(negotiated);
 
         // After Phase 2 completes with transit=true, we're in Full Feature Phase
         // No Phase 3 needed - you can't send login PDUs with CSG=3 (FullFeature)
+        if let Some(length) = text_value(&reply, "MaxRecvDataSegmentLength").and_then(|v| v.parse().ok()) {
+            self.max_xmit_data_segment_length = length;
+        }
+        if let Some(length) = text_value(&reply, "FirstBurstLength").and_then(|v| v.parse().ok()) {
+            self.first_burst_length = length;
+        }
+        self.immediate_data = self.negotiated.as_ref().is_some_and(|negotiated| negotiated.immediate_data);
+        // Digests start with the first PDU after the final Login Response
+        self.digests.header = text_value(&reply, "HeaderDigest") == Some("CRC32C");
+        self.digests.data = text_value(&reply, "DataDigest") == Some("CRC32C");
 
         self.initialized = true;
-        Ok(())
+        Ok(reply)
     }
 
-    /// Perform a single login phase
-    fn login_phase(
+    /// Send one Login Request and return the target's text parameters
+    ///
+    /// Login Requests are immediate, so CmdSN is not advanced.
+    fn login_request(
         &mut self,
-        initiator_name: &str,
-        target_name: &str,
         csg: u8,
         nsg: u8,
         transit: bool,
-    ) -> ScsiResult<()> {
-        // Build login request parameters
-        let mut params = String::new();
-        params.push_str(&format!("InitiatorName={}\0", initiator_name));
-        params.push_str(&format!("TargetName={}\0", target_name));
-
-        if csg == flags::CSG_SECURITY_NEG {
-            params.push_str("AuthMethod=None\0");
-        }
-
-        if csg == flags::CSG_LOGIN_OP_NEG {
-            params.push_str("HeaderDigest=None\0");
-            params.push_str("DataDigest=None\0");
-            params.push_str("MaxRecvDataSegmentLength=8192\0");
-            params.push_str("MaxBurstLength=262144\0");
-            params.push_str("FirstBurstLength=65536\0");
-            params.push_str("DefaultTime2Wait=2\0");
-            params.push_str("DefaultTime2Retain=20\0");
-            params.push_str("MaxOutstandingR2T=1\0");
-            params.push_str("ImmediateData=Yes\0");
-            params.push_str("InitialR2TIOV=0\0");
-            params.push_str("DataPDUInOrder=Yes\0");
-            params.push_str("DataSequenceInOrder=Yes\0");
-            params.push_str("ErrorRecoveryLevel=0\0");
-            params.push_str("SessionType=Normal\0");
-        }
-
-        // Pad to 4-byte boundary
-        while params.len() % 4 != 0 {
-            params.push('\0');
-        }
+        params: &[(&str, &str)],
+    ) -> ScsiResult<Vec<(String, String)>> {
+        self.login_exchange(csg, nsg, transit, params).map(|(_, reply)| reply)
+    }
+
+    /// Like `login_request`, also returning whether the target set the
+    /// Transit bit in its response
+    fn login_exchange(
+        &mut self,
+        csg: u8,
+        nsg: u8,
+        transit: bool,
+        params: &[(&str, &str)],
+    ) -> ScsiResult<(bool, Vec<(String, String)>)> {
+        let params: Vec<(String, String)> = params.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
 
         // Create login request PDU
         let mut pdu = IscsiPdu::new();
         pdu.opcode = opcode::LOGIN_REQUEST;
         pdu.immediate = true;
         pdu.flags = if transit { flags::TRANSIT } else { 0 };
-        pdu.flags |= (csg & 0x03) << 2; // Current stage
-        pdu.flags |= nsg & 0x03;        // Next stage
-        pdu.itt = self.cmd_sn; // Use cmd_sn as itt
-        pdu.specific[0] = 0; // Version max
-        pdu.specific[1] = 0; // Version active
-        pdu.data = params.into_bytes();
+        pdu.flags |= csg | nsg; // Current and next stage
+        pdu.itt = LOGIN_ITT;
+        // ISID in bytes 8-13, TSIH (0 for a new session) in bytes 14-15
+        let mut isid_tsih = [0u8; 8];
+        isid_tsih[0..6].copy_from_slice(&self.isid);
+        pdu.lun = u64::from_be_bytes(isid_tsih);
+        pdu.specific[4..8].copy_from_slice(&self.cmd_sn.to_be_bytes());
+        pdu.specific[8..12].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
+        pdu.data = pdu::serialize_text_parameters(&params);
 
         // Send login request
         self.send_pdu(&pdu)?;
@@ -210,35 +583,33 @@ impl IscsiClient {
 
         if status_class != pdu::login_status::SUCCESS {
             let decoded_message = decode_login_status(status_class, status_detail);
-            return Err(IscsiError::Protocol(format!(
+            return Err(IscsiError::protocol(ProtocolErrorKind::LoginRejected { status_class, status_detail }, format!(
                 "Login failed (class=0x{:02x}, detail=0x{:02x})\n\n{}",
                 status_class, status_detail, decoded_message
             )));
         }
 
-        // Update sequence numbers from response
-        // exp_cmd_sn: specific[4:8]
-        // max_cmd_sn: specific[8:12]
-        // stat_sn: specific[12:16]
-        if response.specific.len() >= 16 {
-            self.exp_stat_sn = u32::from_be_bytes([
-                response.specific[4],
-                response.specific[5],
-                response.specific[6],
-                response.specific[7],
-            ]);
-            self.max_cmd_sn = u32::from_be_bytes([
-                response.specific[8],
-                response.specific[9],
-                response.specific[10],
-                response.specific[11],
-            ]);
-        }
-
-        // Increment cmd_sn for next command
-        self.cmd_sn = self.cmd_sn.wrapping_add(1);
+        self.update_sequence_numbers(&response);
+        let transited = response.flags & flags::TRANSIT != 0;
+        pdu::parse_text_parameters(&response.data).map(|reply| (transited, reply))
+    }
 
-        Ok(())
+    /// Track StatSN and the command window from a target response
+    ///
+    /// StatSN, ExpCmdSN and MaxCmdSN are at bytes 24-35 (specific[4..16]) in
+    /// every response PDU that carries them.
+    fn update_sequence_numbers(&mut self, response: &IscsiPdu) {
+        let stat_sn = BigEndian::read_u32(&response.specific[4..8]);
+        self.exp_stat_sn = stat_sn.wrapping_add(1);
+        self.max_cmd_sn = BigEndian::read_u32(&response.specific[12..16]);
+    }
+
+    /// Allocate an Initiator Task Tag
+    fn next_itt(&mut self) -> u32 {
+        let itt = self.next_itt;
+        // 0xFFFFFFFF is reserved
+        self.next_itt = self.next_itt.wrapping_add(1) % 0xFFFF_FFFF;
+        itt
     }
 
     /// Discover available targets at the connected portal
@@ -251,7 +622,8 @@ impl IscsiClient {
     ///
     /// # Returns
     ///
-    /// A vector of tuples containing (target_iqn, target_address)
+    /// One entry per target, with every portal it advertised. Host names in
+    /// TargetAddress are resolved; portals that do not resolve are skipped.
     ///
     /// # Example
     ///
@@ -261,66 +633,81 @@ impl IscsiClient {
     /// # fn test() -> Result<(), Box<dyn std::error::Error>> {
     /// let mut client = IscsiClient::connect("127.0.0.1:3260")?;
     /// let targets = client.discover("iqn.2025-12.local:initiator")?;
-    /// for (iqn, addr) in targets {
-    ///     println!("Target: {} at {}", iqn, addr);
+    /// for target in targets {
+    ///     for (addr, tpgt) in &target.portals {
+    ///         println!("Target: {} at {} (TPGT {})", target.iqn, addr, tpgt);
+    ///     }
     /// }
     /// # Ok(())
     /// # }
     /// ```
-    pub fn discover(&mut self, initiator_name: &str) -> ScsiResult<Vec<(String, String)>> {
+    pub fn discover(&mut self, initiator_name: &str) -> ScsiResult<Vec<DiscoveredTarget>> {
         // Perform discovery login (SessionType=Discovery)
         self.discovery_login(initiator_name)?;
 
         // Send SendTargets Text Request
         let mut params = String::new();
         params.push_str("SendTargets=All\0");
-        while params.len() % 4 != 0 {
+        while !params.len().is_multiple_of(4) {
             params.push('\0');
         }
 
-        let mut pdu = IscsiPdu::new();
-        pdu.opcode = opcode::TEXT_REQUEST;
-        pdu.flags = flags::FINAL;
-        pdu.itt = self.cmd_sn;
+        let itt = self.next_itt();
+        let mut request = params.into_bytes();
         // TTT = 0xFFFFFFFF for new request
-        pdu.specific[0..4].copy_from_slice(&0xFFFF_FFFFu32.to_be_bytes());
-        // CmdSN
-        pdu.specific[4..8].copy_from_slice(&self.cmd_sn.to_be_bytes());
-        // ExpStatSN
-        pdu.specific[8..12].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
-        pdu.data = params.into_bytes();
-
-        // Send text request
-        self.send_pdu(&pdu)?;
-
-        // Receive text response
-        let response = self.recv_pdu()?;
+        let mut ttt = 0xFFFF_FFFFu32;
+        let mut text = Vec::new();
+        loop {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::TEXT_REQUEST;
+            pdu.flags = flags::FINAL;
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            // CmdSN
+            pdu.specific[4..8].copy_from_slice(&self.cmd_sn.to_be_bytes());
+            // ExpStatSN
+            pdu.specific[8..12].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
+            pdu.data = std::mem::take(&mut request);
+
+            // Send text request
+            self.send_pdu(&pdu)?;
+            self.cmd_sn = self.cmd_sn.wrapping_add(1);
+
+            // Receive text response
+            let response = self.recv_pdu()?;
+
+            if response.opcode != opcode::TEXT_RESPONSE {
+                return Err(IscsiError::InvalidPdu(format!(
+                    "Expected TEXT_RESPONSE (0x24), got opcode 0x{:02x}",
+                    response.opcode
+                )));
+            }
+            self.exp_stat_sn = BigEndian::read_u32(&response.specific[4..8]).wrapping_add(1);
+            text.extend_from_slice(&response.data);
 
-        if response.opcode != opcode::TEXT_RESPONSE {
-            return Err(IscsiError::InvalidPdu(format!(
-                "Expected TEXT_RESPONSE (0x24), got opcode 0x{:02x}",
-                response.opcode
-            )));
+            // C bit: ask for the rest with an empty request echoing the TTT
+            if response.flags & flags::CONTINUE == 0 {
+                break;
+            }
+            ttt = BigEndian::read_u32(&response.specific[0..4]);
         }
 
         // Parse response parameters
-        let params = pdu::parse_text_parameters(&response.data)?;
-
-        // Extract target information
-        let mut targets = Vec::new();
-        let mut current_target: Option<String> = None;
+        let params = pdu::parse_text_parameters(&text)?;
 
+        // Each TargetName starts a target; the TargetAddress keys after it are its portals
+        let mut targets: Vec<DiscoveredTarget> = Vec::new();
         for (key, value) in params {
             match key.as_str() {
-                "TargetName" => {
-                    current_target = Some(value);
-                }
+                "TargetName" => targets.push(DiscoveredTarget { iqn: value, portals: Vec::new() }),
                 "TargetAddress" => {
-                    if let Some(iqn) = current_target.take() {
-                        // TargetAddress format is "host:port,portal-group-tag"
-                        // We just need the host:port part
-                        let addr = value.split(',').next().unwrap_or(&value).to_string();
-                        targets.push((iqn, addr));
+                    let Some(target) = targets.last_mut() else {
+                        log::warn!("Ignoring TargetAddress {} before any TargetName", value);
+                        continue;
+                    };
+                    match parse_target_address(&value) {
+                        Ok(portal) => target.portals.push(portal),
+                        Err(e) => log::warn!("Skipping portal of {}: {}", target.iqn, e),
                     }
                 }
                 _ => {}
@@ -336,121 +723,42 @@ impl IscsiClient {
     /// Perform discovery login (SessionType=Discovery)
     fn discovery_login(&mut self, initiator_name: &str) -> ScsiResult<()> {
         // Phase 1: Security Negotiation
-        self.discovery_login_phase(
-            initiator_name,
+        self.login_request(
             flags::CSG_SECURITY_NEG,
             flags::NSG_LOGIN_OP_NEG,
             false,
+            &[
+                ("InitiatorName", initiator_name),
+                ("AuthMethod", "None"),
+                ("SessionType", "Discovery"),
+            ],
         )?;
 
         // Phase 2: Operational Negotiation with SessionType=Discovery
-        self.discovery_login_phase(
-            initiator_name,
+        self.login_request(
             flags::CSG_LOGIN_OP_NEG,
             flags::NSG_FULL_FEATURE,
             true,
+            &[
+                ("HeaderDigest", "None"),
+                ("DataDigest", "None"),
+                ("MaxRecvDataSegmentLength", &CLIENT_MAX_RECV_DATA_SEGMENT_LENGTH.to_string()),
+                ("DefaultTime2Wait", "2"),
+                ("DefaultTime2Retain", "20"),
+                ("ErrorRecoveryLevel", "0"),
+            ],
         )?;
 
         self.initialized = true;
         Ok(())
     }
 
-    /// Perform a single discovery login phase
-    fn discovery_login_phase(
-        &mut self,
-        initiator_name: &str,
-        csg: u8,
-        nsg: u8,
-        transit: bool,
-    ) -> ScsiResult<()> {
-        // Build login request parameters
-        let mut params = String::new();
-        params.push_str(&format!("InitiatorName={}\0", initiator_name));
-
-        if csg == flags::CSG_SECURITY_NEG {
-            params.push_str("AuthMethod=None\0");
-            params.push_str("SessionType=Discovery\0");
-        }
-
-        if csg == flags::CSG_LOGIN_OP_NEG {
-            params.push_str("HeaderDigest=None\0");
-            params.push_str("DataDigest=None\0");
-            params.push_str("MaxRecvDataSegmentLength=8192\0");
-            params.push_str("DefaultTime2Wait=2\0");
-            params.push_str("DefaultTime2Retain=20\0");
-            params.push_str("ErrorRecoveryLevel=0\0");
-        }
-
-        // Pad to 4-byte boundary
-        while params.len() % 4 != 0 {
-            params.push('\0');
-        }
-
-        // Create login request PDU
-        let mut pdu = IscsiPdu::new();
-        pdu.opcode = opcode::LOGIN_REQUEST;
-        pdu.immediate = true;
-        pdu.flags = if transit { flags::TRANSIT } else { 0 };
-        pdu.flags |= (csg & 0x03) << 2; // Current stage
-        pdu.flags |= nsg & 0x03;        // Next stage
-        pdu.itt = self.cmd_sn; // Use cmd_sn as itt
-        pdu.specific[0] = 0; // Version max
-        pdu.specific[1] = 0; // Version active
-        pdu.data = params.into_bytes();
-
-        // Send login request
-        self.send_pdu(&pdu)?;
-
-        // Receive login response
-        let response = self.recv_pdu()?;
-
-        // Verify login response
-        if response.opcode != opcode::LOGIN_RESPONSE {
-            return Err(IscsiError::InvalidPdu(format!(
-                "Expected LOGIN_RESPONSE (0x23), got opcode 0x{:02x}",
-                response.opcode
-            )));
-        }
-
-        // Extract status from response
-        let status_class = response.specific[0];
-        let status_detail = response.specific[1];
-
-        if status_class != pdu::login_status::SUCCESS {
-            let decoded_message = decode_login_status(status_class, status_detail);
-            return Err(IscsiError::Protocol(format!(
-                "Discovery login failed (class=0x{:02x}, detail=0x{:02x})\n\n{}",
-                status_class, status_detail, decoded_message
-            )));
-        }
-
-        // Update sequence numbers from response
-        if response.specific.len() >= 16 {
-            self.exp_stat_sn = u32::from_be_bytes([
-                response.specific[4],
-                response.specific[5],
-                response.specific[6],
-                response.specific[7],
-            ]);
-            self.max_cmd_sn = u32::from_be_bytes([
-                response.specific[8],
-                response.specific[9],
-                response.specific[10],
-                response.specific[11],
-            ]);
-        }
-
-        // Increment cmd_sn for next command
-        self.cmd_sn = self.cmd_sn.wrapping_add(1);
-
-        Ok(())
-    }
-
     /// Send a PDU to the target
     ///
-    /// Serializes the PDU to bytes and writes it to the TCP stream.
+    /// Serializes the PDU to bytes (with any negotiated digests) and writes
+    /// it to the stream.
     pub fn send_pdu(&mut self, pdu: &IscsiPdu) -> ScsiResult<()> {
-        let bytes = pdu.to_bytes();
+        let bytes = pdu.to_bytes_with_digests(self.digests);
         self.stream.write_all(&bytes)
             .map_err(IscsiError::Io)?;
         Ok(())
@@ -463,96 +771,470 @@ impl IscsiClient {
         self.send_pdu(pdu)
     }
 
+    /// Send the bytes of a deliberately malformed PDU and return the target's answer
+    ///
+    /// The bytes go out verbatim, without digests. An `Io` error means the
+    /// target closed the connection (or did not answer within the read
+    /// timeout) instead of responding.
+    pub fn send_malformed(&mut self, pdu: &PduBuilder) -> ScsiResult<IscsiPdu> {
+        self.send_raw_bytes(&pdu.to_bytes())?;
+        self.recv_pdu()
+    }
+
+    /// Write `bytes` to the target as they are
+    pub fn send_raw_bytes(&mut self, bytes: &[u8]) -> ScsiResult<()> {
+        self.stream.write_all(bytes).map_err(IscsiError::Io)
+    }
+
+    /// Initiator Session ID used in this client's Login Requests
+    pub fn isid(&self) -> [u8; 6] {
+        self.isid
+    }
+
     /// Receive a PDU from the target
     ///
-    /// Reads the 48-byte BHS and any data segment from the TCP stream.
+    /// Reads the 48-byte BHS, any AHS and data segment from the stream,
+    /// checking negotiated digests.
     pub fn recv_pdu(&mut self) -> ScsiResult<IscsiPdu> {
-        let mut buf = vec![0u8; BHS_SIZE];
-        self.stream.read_exact(&mut buf)
+        let mut bhs = [0u8; BHS_SIZE];
+        self.stream.read_exact(&mut bhs)
             .map_err(IscsiError::Io)?;
 
-        // Parse BHS to get data length
-        if buf.len() < BHS_SIZE {
-            return Err(IscsiError::InvalidPdu(format!(
-                "BHS too short: {} bytes",
-                buf.len()
-            )));
-        }
-
-        // Extract data segment length from bytes 5-7
-        let data_len = ((buf[5] as u32) << 16)
-            | ((buf[6] as u32) << 8)
-            | (buf[7] as u32);
+        // AHS and data segment lengths, checked before allocating for them
+        let (ahs_len, data_len) = self.limits.check_header(&bhs)?;
+        let mut buf = bhs.to_vec();
 
         // Calculate padded length (rounded up to 4-byte boundary)
-        let padded_len = ((data_len + 3) / 4) * 4;
+        let padded_len = data_len.div_ceil(4) as usize * 4;
+
+        buf.resize(BHS_SIZE + ahs_len, 0);
+        self.stream.read_exact(&mut buf[BHS_SIZE..])
+            .map_err(IscsiError::Io)?;
+        if self.digests.header {
+            self.check_digest(&buf, "Header")?;
+        }
 
-        if padded_len > 0 {
-            let mut data_buf = vec![0u8; padded_len as usize];
-            self.stream.read_exact(&mut data_buf)
-                .map_err(IscsiError::Io)?;
-            buf.extend_from_slice(&data_buf);
+        let data_start = buf.len();
+        buf.resize(data_start + padded_len, 0);
+        self.stream.read_exact(&mut buf[data_start..])
+            .map_err(IscsiError::Io)?;
+        if self.digests.data && data_len > 0 {
+            self.check_digest(&buf[data_start..], "Data")?;
         }
 
         // Parse complete PDU
-        IscsiPdu::from_bytes(&buf)
+        Ok(IscsiPdu::parse(&buf, &self.limits)?)
+    }
+
+    /// Read a digest from the stream and compare it with the CRC32C of `covered`
+    fn check_digest(&mut self, covered: &[u8], kind: &str) -> ScsiResult<()> {
+        let mut digest = [0u8; DIGEST_SIZE];
+        self.stream.read_exact(&mut digest)
+            .map_err(IscsiError::Io)?;
+        if pdu::crc32c(covered).to_le_bytes() != digest {
+            return Err(IscsiError::protocol(ProtocolErrorKind::Digest, format!("{} digest error in PDU from target", kind)));
+        }
+        Ok(())
     }
 
     /// Send a SCSI command and receive the response
     ///
+    /// Sends a single command PDU, with `data_out` as immediate data, and
+    /// returns the first PDU the target answers with (a Data-In for reads).
+    /// R2Ts are not answered; use `execute_command` or the typed helpers
+    /// such as `read_blocks` and `write_blocks` to run whole transfers.
+    ///
     /// # Arguments
     ///
     /// * `cdb` - SCSI Command Descriptor Block
     /// * `data_out` - Optional data to send with command (for WRITE operations)
     pub fn send_scsi_command(&mut self, cdb: &[u8], data_out: Option<&[u8]>) -> ScsiResult<IscsiPdu> {
+        let pdu = self.scsi_command_pdu(cdb, data_out, 0)?;
+        self.send_pdu(&pdu)?;
+        self.cmd_sn = self.cmd_sn.wrapping_add(1);
+
+        // For simplicity, receive one response
+        // In real implementation, might need to handle multiple responses
+        self.recv_pdu()
+    }
+
+    /// Build a SCSI Command PDU for the current LUN with a SIMPLE task attribute
+    ///
+    /// `expected_in` is the Data-In length expected; with `data_out` as well
+    /// the command is bidirectional and carries it in an AHS.
+    fn scsi_command_pdu(&mut self, cdb: &[u8], data_out: Option<&[u8]>, expected_in: u32) -> ScsiResult<IscsiPdu> {
         if !self.initialized {
             return Err(IscsiError::Session(
                 "Not logged in. Call login() first.".to_string(),
             ));
         }
+        if cdb.len() > 260 {
+            return Err(IscsiError::InvalidPdu(format!(
+                "CDB too long: {} bytes (max 260)",
+                cdb.len()
+            )));
+        }
 
-        // Create SCSI command PDU
         let mut pdu = IscsiPdu::new();
         pdu.opcode = opcode::SCSI_COMMAND;
-        pdu.flags = flags::FINAL | flags::WRITE; // Mark as final
-        pdu.itt = self.cmd_sn;
-        pdu.lun = 0; // LUN 0
-
-        // CDB length in bytes 28-31 (high byte), command in 32-47
-        pdu.specific[0] = (cdb.len() as u8) & 0x0F;
-        pdu.specific[1] = 0; // Attribute
-        pdu.specific[4] = (cdb.len() as u8) & 0xFF; // CDB length
-
-        // Copy CDB into specific field
-        let cdb_start = 16;
-        if cdb.len() <= 16 {
-            pdu.specific[cdb_start..cdb_start + cdb.len()].copy_from_slice(cdb);
-        } else {
-            return Err(IscsiError::InvalidPdu(format!(
-                "CDB too long: {} bytes (max 16)",
-                cdb.len()
+        pdu.flags = flags::FINAL | pdu::task_attribute::SIMPLE;
+        pdu.itt = self.next_itt();
+        pdu.lun = crate::lun::encode_lun(self.lun);
+
+        // Expected Data Transfer Length: specific[0:4]
+        let expected_length = match data_out {
+            Some(data) => {
+                pdu.flags |= flags::WRITE;
+                if expected_in > 0 {
+                    pdu.flags |= flags::READ;
+                    pdu.ahs.push(Ahs::BidiReadDataLength(expected_in));
+                }
+                pdu.data = data.to_vec();
+                data.len() as u32
+            }
+            None if expected_in > 0 => {
+                pdu.flags |= flags::READ;
+                expected_in
+            }
+            None => 0,
+        };
+        pdu.specific[0..4].copy_from_slice(&expected_length.to_be_bytes());
+
+        // CmdSN: specific[4:8], ExpStatSN: specific[8:12]
+        pdu.specific[4..8].copy_from_slice(&self.cmd_sn.to_be_bytes());
+        pdu.specific[8..12].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
+
+        // CDB: specific[12:28], the rest in an Extended CDB AHS
+        let (head, extended) = cdb.split_at(cdb.len().min(16));
+        pdu.specific[12..12 + head.len()].copy_from_slice(head);
+        if !extended.is_empty() {
+            pdu.ahs.push(Ahs::ExtendedCdb(extended.to_vec()));
+        }
+
+        Ok(pdu)
+    }
+
+    /// Run a SCSI command to completion and return its Data-In
+    ///
+    /// `data_out` goes as immediate data and unsolicited Data-Out as far as
+    /// the negotiated ImmediateData, InitialR2T and FirstBurstLength allow;
+    /// the rest is sent in answer to the target's R2Ts.
+    /// Fails with `IscsiError::CommandFailed` if the command does not complete with
+    /// GOOD status.
+    fn execute(&mut self, cdb: &[u8], data_out: Option<&[u8]>, expected_in: u32) -> ScsiResult<Vec<u8>> {
+        let write = data_out.unwrap_or_default();
+        let (immediate_end, unsolicited_end) = self.unsolicited_lengths(write.len() as u32);
+
+        let mut command = self.scsi_command_pdu(cdb, data_out, expected_in)?;
+        command.data.truncate(immediate_end as usize);
+        if unsolicited_end > immediate_end {
+            // F bit clear: unsolicited Data-Out follows
+            command.flags &= !flags::FINAL;
+        }
+        self.send_pdu(&command)?;
+        self.cmd_sn = self.cmd_sn.wrapping_add(1);
+        self.send_data_out(&command, write, 0xFFFF_FFFF, immediate_end, unsolicited_end)?;
+
+        let mut data = Vec::new();
+        loop {
+            let response = self.recv_pdu()?;
+            match response.opcode {
+                opcode::SCSI_DATA_IN if response.itt == command.itt => {
+                    // Buffer Offset: specific[20:24]
+                    let offset = BigEndian::read_u32(&response.specific[20..24]) as usize;
+                    let end = offset + response.data.len();
+                    if data.len() < end {
+                        data.resize(end, 0);
+                    }
+                    data[offset..end].copy_from_slice(&response.data);
+
+                    // S bit: status is carried in byte 3
+                    if response.flags & 0x01 != 0 {
+                        self.update_sequence_numbers(&response);
+                        check_scsi_status(cdb[0], response.version_or_reserved as u8, &[])?;
+                        return Ok(data);
+                    }
+                }
+                opcode::SCSI_RESPONSE if response.itt == command.itt => {
+                    self.update_sequence_numbers(&response);
+                    // Status in byte 3
+                    let sense = sense_bytes(&response.data);
+                    check_scsi_status(cdb[0], response.version_or_reserved as u8, sense)?;
+                    return Ok(data);
+                }
+                opcode::NOP_IN if response.itt == 0xFFFF_FFFF => self.answer_ping(&response)?,
+                opcode::R2T if response.itt == command.itt => {
+                    // R2T does not advance StatSN, but carries the command window
+                    self.max_cmd_sn = BigEndian::read_u32(&response.specific[12..16]);
+                    let ttt = BigEndian::read_u32(&response.specific[0..4]);
+                    let offset = BigEndian::read_u32(&response.specific[20..24]);
+                    let length = BigEndian::read_u32(&response.specific[24..28]);
+                    let max_burst_length = self.negotiated.as_ref().map_or(u32::MAX, |params| params.max_burst_length);
+                    let end = offset.checked_add(length)
+                        .filter(|&end| end as usize <= write.len() && length <= max_burst_length)
+                        .ok_or_else(|| IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                            "R2T for {} bytes at offset {} is outside the {}-byte write or MaxBurstLength {}",
+                            length, offset, write.len(), max_burst_length
+                        )))?;
+                    self.send_data_out(&command, write, ttt, offset, end)?;
+                }
+                other => {
+                    return Err(IscsiError::InvalidPdu(format!(
+                        "Unexpected opcode 0x{:02x} while waiting for SCSI command ITT 0x{:08x}",
+                        other, command.itt
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Immediate data end and unsolicited data end for a write of `length` bytes
+    fn unsolicited_lengths(&self, length: u32) -> (u32, u32) {
+        let first_burst = length.min(self.first_burst_length);
+        let immediate = if self.immediate_data { first_burst.min(self.max_xmit_data_segment_length) } else { 0 };
+        let initial_r2t = self.negotiated.as_ref().is_none_or(|params| params.initial_r2t);
+        (immediate, if initial_r2t { immediate } else { first_burst })
+    }
+
+    /// Send `data[offset..end]` as one Data-Out sequence for `command`
+    ///
+    /// `ttt` is the R2T's Target Transfer Tag, or 0xFFFFFFFF for
+    /// unsolicited data. DataSN starts at 0 and the F bit marks the last
+    /// PDU; each carries at most the target's MaxRecvDataSegmentLength.
+    fn send_data_out(&mut self, command: &IscsiPdu, data: &[u8], ttt: u32, offset: u32, end: u32) -> ScsiResult<()> {
+        let segment = self.max_xmit_data_segment_length.max(1);
+        let (mut start, mut data_sn) = (offset, 0u32);
+        while start < end {
+            let stop = end.min(start.saturating_add(segment));
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_DATA_OUT;
+            pdu.flags = if stop == end { flags::FINAL } else { 0 };
+            pdu.itt = command.itt;
+            pdu.lun = command.lun;
+            // TTT: specific[0:4], ExpStatSN: specific[8:12], DataSN: specific[16:20], Buffer Offset: specific[20:24]
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            pdu.specific[8..12].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
+            pdu.specific[16..20].copy_from_slice(&data_sn.to_be_bytes());
+            pdu.specific[20..24].copy_from_slice(&start.to_be_bytes());
+            pdu.data = data[start as usize..stop as usize].to_vec();
+            self.send_pdu(&pdu)?;
+            start = stop;
+            data_sn += 1;
+        }
+        Ok(())
+    }
+
+    /// Reply to a target-initiated NOP-In ping
+    fn answer_ping(&mut self, ping: &IscsiPdu) -> ScsiResult<()> {
+        let mut reply = IscsiPdu::new();
+        reply.opcode = opcode::NOP_OUT;
+        reply.immediate = true;
+        reply.flags = flags::FINAL;
+        reply.itt = 0xFFFF_FFFF;
+        // Echo the Target Transfer Tag
+        reply.specific[0..4].copy_from_slice(&ping.specific[0..4]);
+        reply.specific[4..8].copy_from_slice(&self.cmd_sn.to_be_bytes());
+        reply.specific[8..12].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
+        self.send_pdu(&reply)
+    }
+
+    // ========================================================================
+    // Typed SCSI helpers
+    // ========================================================================
+
+    /// Send a standard INQUIRY and decode the identification fields
+    pub fn inquiry(&mut self) -> ScsiResult<InquiryData> {
+        let cdb = [0x12, 0, 0, 0, 96, 0];
+        let data = self.execute(&cdb, None, 96)?;
+        if data.len() < 36 {
+            return Err(IscsiError::scsi(format!("INQUIRY returned {} bytes, need 36", data.len())));
+        }
+
+        let ascii = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_end().to_string();
+        Ok(InquiryData {
+            peripheral_device_type: data[0] & 0x1F,
+            vendor: ascii(&data[8..16]),
+            product: ascii(&data[16..32]),
+            revision: ascii(&data[32..36]),
+        })
+    }
+
+    /// Read the capacity, using READ CAPACITY (16) for devices over 2^32 blocks
+    ///
+    /// The block size is remembered for `read_blocks` and `write_blocks`.
+    pub fn read_capacity(&mut self) -> ScsiResult<Capacity> {
+        let data = self.execute(&[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0], None, 8)?;
+        if data.len() < 8 {
+            return Err(IscsiError::scsi(format!("READ CAPACITY (10) returned {} bytes, need 8", data.len())));
+        }
+
+        let capacity = match BigEndian::read_u32(&data[0..4]) {
+            0xFFFF_FFFF => {
+                let mut cdb = [0u8; 16];
+                cdb[0] = 0x9E;
+                cdb[1] = 0x10; // READ CAPACITY (16) service action
+                cdb[13] = 32;  // Allocation length
+                let data = self.execute(&cdb, None, 32)?;
+                if data.len() < 12 {
+                    return Err(IscsiError::scsi(format!("READ CAPACITY (16) returned {} bytes, need 12", data.len())));
+                }
+                Capacity {
+                    blocks: BigEndian::read_u64(&data[0..8]) + 1,
+                    block_size: BigEndian::read_u32(&data[8..12]),
+                }
+            }
+            last_lba => Capacity {
+                blocks: last_lba as u64 + 1,
+                block_size: BigEndian::read_u32(&data[4..8]),
+            },
+        };
+
+        self.block_size = Some(capacity.block_size);
+        Ok(capacity)
+    }
+
+    /// Read `blocks` logical blocks starting at `lba`
+    pub fn read_blocks(&mut self, lba: u64, blocks: u32) -> ScsiResult<Vec<u8>> {
+        let block_size = self.block_size()?;
+        let cdb = rw_cdb(0x28, 0x88, lba, blocks);
+        let data = self.execute(&cdb, None, blocks * block_size)?;
+        if data.len() != (blocks * block_size) as usize {
+            return Err(IscsiError::scsi(format!(
+                "READ returned {} bytes, expected {}",
+                data.len(), blocks * block_size
             )));
         }
+        Ok(data)
+    }
+
+    /// Write whole logical blocks starting at `lba`
+    ///
+    /// Data beyond the first burst is sent in answer to the target's R2Ts.
+    pub fn write_blocks(&mut self, lba: u64, data: &[u8]) -> ScsiResult<()> {
+        let block_size = self.block_size()?;
+        if data.is_empty() || !data.len().is_multiple_of(block_size as usize) {
+            return Err(IscsiError::scsi(format!(
+                "Write length {} is not a whole number of {}-byte blocks",
+                data.len(), block_size
+            )));
+        }
+
+        let blocks = (data.len() / block_size as usize) as u32;
+        let cdb = rw_cdb(0x2A, 0x8A, lba, blocks);
+        self.execute(&cdb, Some(data), 0)?;
+        Ok(())
+    }
+
+    /// Run a bidirectional command, such as XDWRITEREAD (10), returning its Data-In
+    ///
+    /// `data_out` is sent like `write_blocks` sends its data, and
+    /// `read_length` is the Expected Bidirectional Read Data Length.
+    pub fn execute_bidirectional(&mut self, cdb: &[u8], data_out: &[u8], read_length: u32) -> ScsiResult<Vec<u8>> {
+        self.execute(cdb, Some(data_out), read_length)
+    }
+
+    /// Run any SCSI command to completion and return its Data-In
+    ///
+    /// `read_length` is the Expected Data Transfer Length for Data-In.
+    /// Fails with `IscsiError::CommandFailed`, carrying the sense, unless
+    /// the command completes with GOOD status.
+    pub fn execute_command(&mut self, cdb: &[u8], data_out: Option<&[u8]>, read_length: u32) -> ScsiResult<Vec<u8>> {
+        self.execute(cdb, data_out, read_length)
+    }
+
+    /// Largest write sent whole as immediate data, or 0 without immediate data
+    pub fn max_immediate_data(&self) -> u32 {
+        if self.immediate_data {
+            self.max_xmit_data_segment_length.min(self.first_burst_length)
+        } else {
+            0
+        }
+    }
 
-        // Add data segment if needed
-        if let Some(data) = data_out {
-            pdu.data = data.to_vec();
-            pdu.flags |= flags::WRITE;
+    /// Block size from the last `read_capacity`, fetching it if needed
+    fn block_size(&mut self) -> ScsiResult<u32> {
+        match self.block_size {
+            Some(block_size) => Ok(block_size),
+            None => Ok(self.read_capacity()?.block_size),
         }
+    }
 
-        // Set sequence numbers
-        // CmdSN: specific[20:24]
-        // ExpStatSN: specific[24:28]
-        pdu.specific[20..24].copy_from_slice(&self.cmd_sn.to_be_bytes());
-        pdu.specific[24..28].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
+    /// Measure in-band round-trip latency with the `X-diagnostic.echo` Text key
+    ///
+    /// Must be called after `login()`. Large payloads can be used to probe
+    /// path MTU problems; the target rejects payloads that do not fit in a
+    /// single Text Response.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the payload contains NUL bytes, the target
+    /// rejects the key, or the response is malformed.
+    pub fn diagnostic_echo(&mut self, payload: &str) -> ScsiResult<EchoReply> {
+        if payload.contains('\0') {
+            return Err(IscsiError::protocol(ProtocolErrorKind::Negotiation, "Echo payload must not contain NUL bytes".to_string()));
+        }
 
-        // Send command
+        let mut data = pdu::serialize_text_parameters(&[
+            (crate::session::DIAGNOSTIC_ECHO_KEY.to_string(), payload.to_string()),
+        ]);
+        while !data.len().is_multiple_of(4) {
+            data.push(0);
+        }
+
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::TEXT_REQUEST;
+        pdu.flags = flags::FINAL;
+        pdu.itt = self.next_itt();
+        // TTT = 0xFFFFFFFF for new request
+        pdu.specific[0..4].copy_from_slice(&0xFFFF_FFFFu32.to_be_bytes());
+        // CmdSN
+        pdu.specific[4..8].copy_from_slice(&self.cmd_sn.to_be_bytes());
+        // ExpStatSN
+        pdu.specific[8..12].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
+        pdu.data = data;
+
+        let start = Instant::now();
         self.send_pdu(&pdu)?;
+        let response = self.recv_pdu()?;
+        let round_trip = start.elapsed();
+
         self.cmd_sn = self.cmd_sn.wrapping_add(1);
 
-        // For simplicity, receive one response
-        // In real implementation, might need to handle multiple responses
-        self.recv_pdu()
+        if response.opcode != opcode::TEXT_RESPONSE {
+            return Err(IscsiError::InvalidPdu(format!(
+                "Expected TEXT_RESPONSE (0x24), got opcode 0x{:02x}",
+                response.opcode
+            )));
+        }
+
+        // StatSN: specific[4:8]
+        let stat_sn = u32::from_be_bytes([
+            response.specific[4],
+            response.specific[5],
+            response.specific[6],
+            response.specific[7],
+        ]);
+        self.exp_stat_sn = stat_sn.wrapping_add(1);
+
+        let params = pdu::parse_text_parameters(&response.data)?;
+        let echoed = params.iter()
+            .find(|(k, _)| k == crate::session::DIAGNOSTIC_ECHO_KEY)
+            .map(|(_, v)| v.clone())
+            .ok_or_else(|| IscsiError::protocol(ProtocolErrorKind::Negotiation, "Target did not answer X-diagnostic.echo".to_string()))?;
+
+        if echoed == "Reject" && payload != "Reject" {
+            return Err(IscsiError::protocol(ProtocolErrorKind::Negotiation, "Target rejected X-diagnostic.echo".to_string()));
+        }
+
+        let target_timestamp_us = params.iter()
+            .find(|(k, _)| k == crate::session::DIAGNOSTIC_TIMESTAMP_KEY)
+            .and_then(|(_, v)| v.parse().ok());
+
+        Ok(EchoReply {
+            payload: echoed,
+            round_trip,
+            target_timestamp_us,
+        })
     }
 
     /// Perform iSCSI logout
@@ -561,19 +1243,34 @@ impl IscsiClient {
         pdu.opcode = opcode::LOGOUT_REQUEST;
         pdu.immediate = true;
         pdu.flags = flags::FINAL;
-        pdu.itt = self.cmd_sn;
+        pdu.itt = self.next_itt();
 
-        // Set sequence numbers
-        pdu.specific[20..24].copy_from_slice(&self.cmd_sn.to_be_bytes());
-        pdu.specific[24..28].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
+        // CmdSN: specific[4:8], ExpStatSN: specific[8:12]
+        pdu.specific[4..8].copy_from_slice(&self.cmd_sn.to_be_bytes());
+        pdu.specific[8..12].copy_from_slice(&self.exp_stat_sn.to_be_bytes());
 
         self.send_pdu(&pdu)?;
-        let _response = self.recv_pdu()?;
+        let response = self.recv_pdu()?;
+        if response.opcode == opcode::LOGOUT_RESPONSE && response.logout_response_code() != 0 {
+            return Err(IscsiError::Session(format!(
+                "Logout failed with response code {}",
+                response.logout_response_code()
+            )));
+        }
 
         self.initialized = false;
+        self.negotiated = None;
         Ok(())
     }
 
+    /// Parameters negotiated at login, while logged in
+    ///
+    /// MaxRecvDataSegmentLength is the client's own and
+    /// MaxXmitDataSegmentLength the target's.
+    pub fn negotiated_params(&self) -> Option<&SessionParams> {
+        self.negotiated.as_ref()
+    }
+
     /// Get the current command sequence number
     pub fn cmd_sn(&self) -> u32 {
         self.cmd_sn
@@ -595,10 +1292,137 @@ impl IscsiClient {
     }
 }
 
+/// Random-format ISID (RFC 3720 Section 10.12.5, type 0x80)
+fn random_isid() -> [u8; 6] {
+    let mut isid = [0u8; 6];
+    rand::Rng::fill(&mut rand::thread_rng(), &mut isid[1..]);
+    isid[0] = 0x80;
+    isid
+}
+
+/// Look up a key in a parsed login/text response
+fn text_value<'a>(pairs: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+}
+
+/// Parse a TargetAddress value, `host[:port][,tpgt]` (RFC 3720 Section 12.8)
+///
+/// IPv6 literals may be bracketed; the port defaults to 3260 and the portal
+/// group tag to 1. Host names are resolved to their first address.
+fn parse_target_address(value: &str) -> ScsiResult<(SocketAddr, u16)> {
+    let invalid = || IscsiError::InvalidPdu(format!("Invalid TargetAddress {:?}", value));
+    let (address, tpgt) = match value.rsplit_once(',') {
+        Some((address, tag)) => (address, tag.trim().parse().map_err(|_| invalid())?),
+        None => (value, 1),
+    };
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+    let (host, port) = match address.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']').ok_or_else(invalid)? {
+            (host, "") => (host, 3260),
+            (host, port) => (host, parse_port(port.strip_prefix(':').ok_or_else(invalid)?)?),
+        },
+        None => match address.rsplit_once(':') {
+            // More than one colon is an unbracketed IPv6 literal without a port
+            Some((host, port)) if !host.contains(':') => (host, parse_port(port)?),
+            _ => (address, 3260),
+        },
+    };
+    let addr = (host, port).to_socket_addrs()?.next().ok_or_else(invalid)?;
+    Ok((addr, tpgt))
+}
+
+/// READ/WRITE CDB, using the 16-byte form when LBA or length exceed the 10-byte one
+fn rw_cdb(opcode10: u8, opcode16: u8, lba: u64, blocks: u32) -> Vec<u8> {
+    if lba <= u32::MAX as u64 && blocks <= u16::MAX as u32 {
+        let mut cdb = vec![0u8; 10];
+        cdb[0] = opcode10;
+        BigEndian::write_u32(&mut cdb[2..6], lba as u32);
+        BigEndian::write_u16(&mut cdb[7..9], blocks as u16);
+        cdb
+    } else {
+        let mut cdb = vec![0u8; 16];
+        cdb[0] = opcode16;
+        BigEndian::write_u64(&mut cdb[2..10], lba);
+        BigEndian::write_u32(&mut cdb[10..14], blocks);
+        cdb
+    }
+}
+
+/// Sense data from a SCSI Response data segment
+///
+/// RFC 3720 prefixes the sense data with a 2-byte SenseLength; this crate's
+/// target sends the sense data bare, so accept both forms.
+fn sense_bytes(data: &[u8]) -> &[u8] {
+    match data.first() {
+        Some(code) if (0x70..=0x73).contains(&(code & 0x7F)) => data,
+        _ => data.get(2..).unwrap_or(&[]),
+    }
+}
+
+/// Turn a non-GOOD SCSI status into an error carrying the sense key and ASC/ASCQ
+fn check_scsi_status(operation: u8, status: u8, sense: &[u8]) -> ScsiResult<()> {
+    if status == scsi_status::GOOD {
+        return Ok(());
+    }
+
+    // Fixed format sense data: key in byte 2, ASC/ASCQ in bytes 12/13
+    let sense = (sense.len() >= 14).then(|| SenseCode::new(sense[2] & 0x0F, sense[12], sense[13]));
+    Err(IscsiError::CommandFailed { operation, status, sense })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_target_address() {
+        let portal = |value: &str| parse_target_address(value).map(|(addr, tpgt)| (addr.to_string(), tpgt)).ok();
+        assert_eq!(portal("10.0.0.5:3261,2"), Some(("10.0.0.5:3261".to_string(), 2)));
+        assert_eq!(portal("10.0.0.5"), Some(("10.0.0.5:3260".to_string(), 1)));
+        assert_eq!(portal("[fd00::7]:3262,4"), Some(("[fd00::7]:3262".to_string(), 4)));
+        assert_eq!(portal("[fd00::7],5"), Some(("[fd00::7]:3260".to_string(), 5)));
+        assert_eq!(portal("fd00::7"), Some(("[fd00::7]:3260".to_string(), 1)));
+        assert_eq!(portal("localhost:3263,1").map(|(addr, _)| addr.ends_with(":3263")), Some(true));
+        assert_eq!(portal("10.0.0.5:3260,tag"), None);
+        assert_eq!(portal("[fd00::7"), None);
+        assert_eq!(portal("10.0.0.5:port"), None);
+    }
+
+    #[test]
+    fn test_rw_cdb_selects_form() {
+        let cdb = rw_cdb(0x28, 0x88, 0x1234, 8);
+        assert_eq!(cdb, vec![0x28, 0, 0, 0, 0x12, 0x34, 0, 0, 8, 0]);
+
+        let cdb = rw_cdb(0x28, 0x88, 1 << 32, 8);
+        assert_eq!(cdb.len(), 16);
+        assert_eq!(cdb[0], 0x88);
+        assert_eq!(BigEndian::read_u64(&cdb[2..10]), 1 << 32);
+        assert_eq!(BigEndian::read_u32(&cdb[10..14]), 8);
+    }
+
+    #[test]
+    fn test_check_scsi_status_reports_sense() {
+        assert!(check_scsi_status(0x28, scsi_status::GOOD, &[]).is_ok());
+
+        let mut sense = vec![0u8; 18];
+        sense[0] = 0x70;
+        sense[2] = 0x05;
+        sense[12] = 0x21;
+        let err = check_scsi_status(0x28, scsi_status::CHECK_CONDITION, &sense).unwrap_err();
+        assert!(err.to_string().contains("sense key 0x05, ASC/ASCQ 0x21/0x00"));
+    }
+
+    #[test]
+    fn test_sense_bytes_accepts_both_forms() {
+        let sense = [0x70, 0, 0x05, 0, 0, 0, 0, 10];
+        assert_eq!(sense_bytes(&sense), &sense);
+
+        let mut prefixed = vec![0, sense.len() as u8];
+        prefixed.extend_from_slice(&sense);
+        assert_eq!(sense_bytes(&prefixed), &sense);
+        assert!(sense_bytes(&[]).is_empty());
+    }
+
     #[test]
     fn test_client_creation() {
         // This test requires a running target
@@ -0,0 +1,1050 @@
+//! Runtime control of a running target
+//!
+//! `TargetControl` is a cloneable handle to a target's live configuration.
+//! `apply_config()` swaps in a new `TargetConfig`: new logins use it at once,
+//! established sessions it still admits keep running, and sessions it no
+//! longer admits (renamed target, initiator dropped from the ACL) are asked to
+//! log out.
+//!
+//! It also carries session state across restarts: `session_snapshots()`
+//! captures the established sessions and `restore_sessions()` lets
+//! initiators resume them on the next run. Sessions logged out for
+//! connection recovery are held the same way until their Time2Retain ends.
+//!
+//! Finally it is the target's session registry: it allocates TSIHs that are
+//! unique across every connection, maps each initiator's ISID to its TSIH,
+//! and reinstates a session when the same initiator logs in again with an
+//! ISID already in use (RFC 3720 Section 5.3.5).
+//!
+//! LUNs can be added and removed on the running target with `add_lun()` and
+//! `remove_lun()`, and changes to their backing store reported with
+//! `notify_lun_changed()`; see the `lun` module. `apply_config()` can remove
+//! LUNs too, but only `add_lun()` can add them.
+//!
+//! For health checks, `ping_session()` measures a session's round trip with
+//! a NOP-In, and `sessions()` reports when each initiator was last heard
+//! from with a NOP-Out.
+
+use crate::auth::{AuthConfig, ChallengeRegistry};
+use crate::error::{IscsiError, ScsiResult};
+use crate::log_context::PduLogLevel;
+use crate::lun::{ChangeKind, LunRegistry, LunTable};
+use crate::portal::IpNetwork;
+use crate::scsi::ScsiBlockDevice;
+use crate::session::{SessionParams, SessionSnapshot, Strictness};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicU8, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+/// Target settings that can be changed while the target is running
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TargetConfig {
+    /// Target IQN, EUI or NAA name
+    pub target_name: String,
+    /// TargetAlias reported during login
+    pub target_alias: String,
+    /// Authentication for new logins
+    pub auth: AuthConfig,
+    /// Whether CHAP may fall back to MD5 (CHAP_A=5)
+    pub allow_md5_chap: bool,
+    /// Initiator names allowed to log in (None = any); see `initiator_matches`
+    pub allowed_initiators: Option<Vec<String>>,
+    /// Networks initiators may connect from (None = any)
+    pub allowed_networks: Option<Vec<IpNetwork>>,
+    /// How new connections treat protocol violations
+    pub strictness: Strictness,
+    /// LUNs the target exports (None = leave the LUN table as it is)
+    ///
+    /// `apply_config()` stops exporting LUNs missing from the list but
+    /// cannot add any, since a configuration carries no devices: listing a
+    /// LUN that is not exported is an error. Use `TargetControl::add_lun()`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub luns: Option<Vec<u64>>,
+}
+
+impl TargetConfig {
+    /// Configuration with no authentication or ACL
+    pub fn new(target_name: &str) -> Self {
+        Self {
+            target_name: target_name.to_string(),
+            target_alias: "iSCSI Target".to_string(),
+            auth: AuthConfig::None,
+            allow_md5_chap: true,
+            allowed_initiators: None,
+            allowed_networks: None,
+            strictness: Strictness::Permissive,
+            luns: None,
+        }
+    }
+
+    /// Check the configuration is usable
+    pub fn validate(&self) -> ScsiResult<()> {
+        let name = &self.target_name;
+        if !name.starts_with("iqn.") && !name.starts_with("eui.") && !name.starts_with("naa.") {
+            return Err(IscsiError::Config(
+                "target_name must be in IQN, EUI, or NAA format (e.g., iqn.2025-12.local:storage.disk1)".to_string()
+            ));
+        }
+        self.auth.validate()
+    }
+
+    /// Whether a session logged in to `target_name` from `initiator_name`
+    /// at `initiator_addr` may continue
+    fn admits(&self, target_name: &str, initiator_name: &str, initiator_addr: Option<IpAddr>) -> bool {
+        target_name == self.target_name
+            && self.allowed_initiators.as_ref()
+                .is_none_or(|allowed| allowed.iter().any(|pattern| initiator_matches(pattern, initiator_name)))
+            && self.allowed_networks.as_ref()
+                .is_none_or(|networks| initiator_addr.is_some_and(|addr| networks.iter().any(|net| net.contains(addr))))
+    }
+}
+
+/// Whether `name` matches an `allowed_initiators` entry
+///
+/// Entries are exact names unless they contain wildcards: `*` matches any
+/// run of characters and `?` any single one, so
+/// `iqn.2025-12.example:host-*` admits every host in that naming authority.
+pub fn initiator_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it is matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // Let the last `*` absorb one more character and retry
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Summary of what `TargetControl::apply_config()` changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigChanges {
+    /// Target name changed: the old target is removed and the new one added
+    pub target_renamed: bool,
+    /// TargetAlias changed
+    pub alias_changed: bool,
+    /// Initiator ACL changed
+    pub acl_changed: bool,
+    /// LUNs no longer exported because the new configuration does not list them
+    pub luns_removed: Vec<u64>,
+    /// Sessions asked to log out because the new configuration does not admit them
+    pub drained_sessions: usize,
+}
+
+/// An established session as seen by the session registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionInfo {
+    /// Target Session Identifying Handle allocated at login
+    pub tsih: u16,
+    /// Initiator Session ID
+    pub isid: [u8; 6],
+    /// Connection ID of the session's connection
+    pub cid: u16,
+    /// Initiator name (IQN)
+    pub initiator_name: String,
+    /// Target name the session logged in to
+    pub target_name: String,
+    /// Asked to log out (configuration change or reinstatement)
+    pub draining: bool,
+    /// Parameters negotiated at login: digests, burst lengths, ERL, ...
+    pub params: SessionParams,
+    /// When the initiator last sent a NOP-Out, its own or answering a ping
+    ///
+    /// Not serialized: an `Instant` means nothing outside this process.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub last_heartbeat: Option<Instant>,
+}
+
+/// Outcome of looking up the session a login with a non-zero TSIH continues
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SessionLookup {
+    /// Continue this session on the new connection
+    Resume(SessionSnapshot),
+    /// The session exists but cannot take another connection
+    TooManyConnections,
+    /// No session with this ISID, TSIH and initiator name
+    NotFound,
+}
+
+/// A session claimed by a login that has authenticated
+///
+/// Handed back with `TargetControl::unclaim()` if the login fails before
+/// reaching full feature phase, so the real initiator can still resume it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ClaimedSession {
+    /// A snapshot from a previous run
+    Restored(SessionSnapshot),
+    /// A snapshot retained for connection recovery until the deadline
+    Retained(SessionSnapshot, Instant),
+    /// A live session whose old connection has been asked to log out
+    Reinstated(SessionSnapshot),
+}
+
+impl ClaimedSession {
+    pub(crate) fn snapshot(&self) -> &SessionSnapshot {
+        match self {
+            ClaimedSession::Restored(snapshot)
+            | ClaimedSession::Retained(snapshot, _)
+            | ClaimedSession::Reinstated(snapshot) => snapshot,
+        }
+    }
+}
+
+/// Cloneable handle for reconfiguring a running target
+///
+/// Obtained from `IscsiTarget::control()`.
+#[derive(Clone)]
+pub struct TargetControl {
+    inner: Arc<ControlState>,
+}
+
+struct ControlState {
+    config: RwLock<TargetConfig>,
+    sessions: Mutex<Vec<RegisteredSession>>,
+    next_session_id: AtomicU64,
+    /// Snapshots from a previous run not yet resumed by an initiator
+    restored: Mutex<Vec<SessionSnapshot>>,
+    /// Sessions logged out for connection recovery, with their Time2Retain deadline
+    retained: Mutex<Vec<(SessionSnapshot, Instant)>>,
+    /// TSIHs handed out to logins that have not registered yet
+    reserved_tsihs: Mutex<HashSet<u16>>,
+    /// Where the next TSIH search starts
+    next_tsih: AtomicU16,
+    /// The target's LUN table
+    luns: OnceLock<Arc<dyn LunRegistry>>,
+    /// `PduLogLevel` of every connection, read for each PDU
+    pdu_logging: AtomicU8,
+    /// Challenges issued in the target's CHAP exchanges still in progress
+    challenges: Arc<ChallengeRegistry>,
+}
+
+/// A session in FullFeaturePhase, with the callback that starts draining it
+struct RegisteredSession {
+    id: u64,
+    cid: u16,
+    target_name: String,
+    initiator_name: String,
+    initiator_addr: Option<IpAddr>,
+    params: SessionParams,
+    drain: Box<dyn Fn() + Send>,
+    /// Asks the connection to send a NOP-In and report the round trip
+    ping: Option<Box<dyn Fn(mpsc::Sender<Duration>) + Send>>,
+    draining: bool,
+    snapshot: Arc<Mutex<SessionSnapshot>>,
+    heartbeat: Arc<Mutex<Option<Instant>>>,
+}
+
+impl std::fmt::Debug for TargetControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TargetControl")
+            .field("config", &self.config())
+            .field("sessions", &self.session_count())
+            .finish()
+    }
+}
+
+impl TargetControl {
+    pub(crate) fn new(config: TargetConfig) -> Self {
+        Self {
+            inner: Arc::new(ControlState {
+                config: RwLock::new(config),
+                sessions: Mutex::new(Vec::new()),
+                next_session_id: AtomicU64::new(0),
+                restored: Mutex::new(Vec::new()),
+                retained: Mutex::new(Vec::new()),
+                reserved_tsihs: Mutex::new(HashSet::new()),
+                next_tsih: AtomicU16::new(1),
+                luns: OnceLock::new(),
+                pdu_logging: AtomicU8::new(PduLogLevel::default().as_u8()),
+                challenges: Arc::default(),
+            }),
+        }
+    }
+
+    /// Snapshot of the current configuration
+    ///
+    /// `luns` lists the LUNs exported right now, or is None if no target is
+    /// attached to this handle.
+    pub fn config(&self) -> TargetConfig {
+        let mut config = self.inner.config.read().unwrap_or_else(|e| e.into_inner()).clone();
+        config.luns = self.inner.luns.get().map(|luns| luns.luns());
+        config
+    }
+
+    /// How much of each PDU the target logs at debug level
+    pub fn pdu_logging(&self) -> PduLogLevel {
+        PduLogLevel::from_u8(self.inner.pdu_logging.load(Ordering::Relaxed))
+    }
+
+    /// Change how much of each PDU is logged; applies to established
+    /// connections from their next PDU
+    pub fn set_pdu_logging(&self, level: PduLogLevel) {
+        self.inner.pdu_logging.store(level.as_u8(), Ordering::Relaxed);
+    }
+
+    /// Number of established sessions, including ones being drained
+    pub fn session_count(&self) -> usize {
+        self.registered().len()
+    }
+
+    /// The established sessions, including ones being drained
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.registered().iter()
+            .map(|session| {
+                let snapshot = session.snapshot.lock().unwrap_or_else(|e| e.into_inner());
+                SessionInfo {
+                    tsih: snapshot.tsih,
+                    isid: snapshot.isid,
+                    cid: session.cid,
+                    initiator_name: session.initiator_name.clone(),
+                    target_name: session.target_name.clone(),
+                    draining: session.draining,
+                    params: session.params.clone(),
+                    last_heartbeat: *session.heartbeat.lock().unwrap_or_else(|e| e.into_inner()),
+                }
+            })
+            .collect()
+    }
+
+    /// TSIH of the established session `initiator_name` opened with `isid`
+    pub fn find_session(&self, initiator_name: &str, isid: [u8; 6]) -> Option<u16> {
+        self.sessions().into_iter()
+            .find(|session| !session.draining && session.initiator_name == initiator_name && session.isid == isid)
+            .map(|session| session.tsih)
+    }
+
+    /// Replace the running configuration
+    ///
+    /// CHAP and ACL changes apply to new logins immediately. Established
+    /// sessions are left alone unless the new configuration would refuse
+    /// them, in which case they receive an Async Message asking them to log
+    /// out and are dropped if they do not.
+    ///
+    /// If `luns` is set, exported LUNs it does not list are removed as with
+    /// `remove_lun()`. It cannot add LUNs: a listed LUN that is not exported
+    /// is a `Config` error. On error nothing is changed.
+    pub fn apply_config(&self, mut new_config: TargetConfig) -> ScsiResult<ConfigChanges> {
+        new_config.validate()?;
+
+        let mut config = self.inner.config.write().unwrap_or_else(|e| e.into_inner());
+        // Removing the LUNs is the only step that can fail, so it goes first
+        // and removes all of them or none
+        let luns_removed = match new_config.luns.take() {
+            Some(wanted) => self.lun_registry()?.retain(&wanted)?,
+            None => Vec::new(),
+        };
+        let mut changes = ConfigChanges {
+            target_renamed: config.target_name != new_config.target_name,
+            alias_changed: config.target_alias != new_config.target_alias,
+            acl_changed: config.allowed_initiators != new_config.allowed_initiators
+                || config.allowed_networks != new_config.allowed_networks,
+            luns_removed,
+            drained_sessions: 0,
+        };
+        if changes.target_renamed {
+            log::info!("Target renamed from {} to {}", config.target_name, new_config.target_name);
+        }
+        *config = new_config;
+
+        // Drain while still holding the config lock so a session registering
+        // concurrently sees either the old config here or the new one itself
+        for session in self.registered().iter_mut() {
+            if !session.draining && !config.admits(&session.target_name, &session.initiator_name, session.initiator_addr) {
+                log::info!(
+                    "Draining session from {} on {}: no longer admitted by configuration",
+                    session.initiator_name, session.target_name
+                );
+                session.draining = true;
+                (session.drain)();
+                changes.drained_sessions += 1;
+            }
+        }
+
+        log::info!("Applied target configuration: {:?}", changes);
+        Ok(changes)
+    }
+
+    /// Give the handle access to the target's LUN table
+    pub(crate) fn attach_luns(&self, luns: Arc<dyn LunRegistry>) {
+        let _ = self.inner.luns.set(luns);
+    }
+
+    fn lun_registry(&self) -> ScsiResult<&Arc<dyn LunRegistry>> {
+        self.inner.luns.get()
+            .ok_or_else(|| IscsiError::Config("no target is attached to this handle".to_string()))
+    }
+
+    /// LUNs the target exports, in ascending order
+    pub fn luns(&self) -> Vec<u64> {
+        self.inner.luns.get().map(|luns| luns.luns()).unwrap_or_default()
+    }
+
+    /// Export `device` as `lun` while the target runs
+    ///
+    /// `device` must be of the target's device type. Every session gets a
+    /// REPORTED LUNS DATA HAS CHANGED unit attention on its next command.
+    pub fn add_lun<D: ScsiBlockDevice + 'static>(&self, lun: u64, device: D) -> ScsiResult<()> {
+        let registry = self.lun_registry()?;
+        let table = registry.as_any().downcast_ref::<LunTable<D>>().ok_or_else(|| {
+            IscsiError::Config(format!("LUN device type {} differs from the target's", std::any::type_name::<D>()))
+        })?;
+        table.insert(lun, device)
+    }
+
+    /// Stop exporting `lun` while the target runs
+    ///
+    /// Commands to it that are queued or still waiting on Data-Out fail with
+    /// LOGICAL UNIT NOT SUPPORTED; other LUNs are not disturbed. Every session
+    /// gets a REPORTED LUNS DATA HAS CHANGED unit attention on its next command.
+    pub fn remove_lun(&self, lun: u64) -> ScsiResult<()> {
+        self.lun_registry()?.remove(lun)
+    }
+
+    /// Report that the backing store of `lun` changed outside the target
+    ///
+    /// Every session gets the unit attention for `kind` on its next command
+    /// to the LUN. For `ChangeKind::MediumChanged`, commands to it that are
+    /// queued or still waiting on Data-Out fail with that unit attention too.
+    pub fn notify_lun_changed(&self, lun: u64, kind: ChangeKind) -> ScsiResult<()> {
+        self.lun_registry()?.notify_changed(lun, kind)
+    }
+
+    /// Ask every established session to log out, returning how many were asked
+    pub(crate) fn drain_all(&self) -> usize {
+        let mut drained = 0;
+        for session in self.registered().iter_mut().filter(|session| !session.draining) {
+            session.draining = true;
+            (session.drain)();
+            drained += 1;
+        }
+        drained
+    }
+
+    /// Snapshots of the established sessions, for persisting before a restart
+    pub fn session_snapshots(&self) -> Vec<SessionSnapshot> {
+        self.registered().iter()
+            .map(|session| session.snapshot.lock().unwrap_or_else(|e| e.into_inner()).clone())
+            .collect()
+    }
+
+    /// Allow sessions snapshotted by a previous run to be resumed
+    ///
+    /// Call before `IscsiTarget::run()`. An initiator logging in with a
+    /// snapshot's ISID, TSIH and initiator name continues that session; each
+    /// snapshot can be resumed once. Replaces any earlier restored snapshots.
+    pub fn restore_sessions(&self, snapshots: Vec<SessionSnapshot>) {
+        log::info!("{} session(s) available for resumption", snapshots.len());
+        *self.inner.restored.lock().unwrap_or_else(|e| e.into_inner()) = snapshots;
+    }
+
+    /// Hold a session logged out for connection recovery for `time2retain`
+    pub(crate) fn retain_session(&self, snapshot: SessionSnapshot, time2retain: Duration) {
+        log::info!("Retaining session TSIH {} for {:?} for recovery", snapshot.tsih, time2retain);
+        let mut retained = self.inner.retained.lock().unwrap_or_else(|e| e.into_inner());
+        retained.push((snapshot, Instant::now() + time2retain));
+    }
+
+    /// The restored or retained snapshot a login continuing a session matches
+    fn find_restored(&self, isid: [u8; 6], tsih: u16, initiator_name: &str) -> Option<SessionSnapshot> {
+        let matches = self.snapshot_matcher(isid, tsih, initiator_name);
+        let restored = self.inner.restored.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(snapshot) = restored.iter().find(|snapshot| matches(snapshot)) {
+            return Some(snapshot.clone());
+        }
+        drop(restored);
+
+        let now = Instant::now();
+        let retained = self.inner.retained.lock().unwrap_or_else(|e| e.into_inner());
+        retained.iter()
+            .find(|(snapshot, deadline)| *deadline > now && matches(snapshot))
+            .map(|(snapshot, _)| snapshot.clone())
+    }
+
+    /// Claim the restored or retained snapshot for a login continuing a session
+    pub(crate) fn take_restored(&self, isid: [u8; 6], tsih: u16, initiator_name: &str) -> Option<ClaimedSession> {
+        let matches = self.snapshot_matcher(isid, tsih, initiator_name);
+        let mut restored = self.inner.restored.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(index) = restored.iter().position(&matches) {
+            return Some(ClaimedSession::Restored(restored.swap_remove(index)));
+        }
+        drop(restored);
+
+        let mut retained = self.inner.retained.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        retained.retain(|(_, deadline)| *deadline > now);
+        let index = retained.iter().position(|(snapshot, _)| matches(snapshot))?;
+        let (snapshot, deadline) = retained.swap_remove(index);
+        Some(ClaimedSession::Retained(snapshot, deadline))
+    }
+
+    fn snapshot_matcher<'a>(&self, isid: [u8; 6], tsih: u16, initiator_name: &'a str) -> impl Fn(&SessionSnapshot) -> bool + 'a {
+        let target_name = self.config().target_name;
+        move |snapshot: &SessionSnapshot| {
+            snapshot.isid == isid
+                && snapshot.tsih == tsih
+                && snapshot.initiator_name == initiator_name
+                && snapshot.target_name == target_name
+        }
+    }
+
+    /// Challenges issued in the target's CHAP exchanges still in progress
+    pub(crate) fn challenges(&self) -> &Arc<ChallengeRegistry> {
+        &self.inner.challenges
+    }
+
+    /// Allocate a TSIH not used by any established, restored or retained session
+    ///
+    /// The TSIH stays reserved until the session registers or
+    /// `release_tsih()` is called. Returns None if all 65535 are in use.
+    pub(crate) fn allocate_tsih(&self) -> Option<u16> {
+        let mut in_use: HashSet<u16> = self.registered().iter()
+            .map(|session| session.snapshot.lock().unwrap_or_else(|e| e.into_inner()).tsih)
+            .collect();
+        in_use.extend(self.inner.restored.lock().unwrap_or_else(|e| e.into_inner()).iter().map(|s| s.tsih));
+        in_use.extend(self.inner.retained.lock().unwrap_or_else(|e| e.into_inner()).iter().map(|(s, _)| s.tsih));
+
+        let mut reserved = self.inner.reserved_tsihs.lock().unwrap_or_else(|e| e.into_inner());
+        for _ in 0..u16::MAX {
+            // TSIH 0 is reserved for new sessions (RFC 3720 Section 10.12.6)
+            let tsih = self.inner.next_tsih.fetch_add(1, Ordering::Relaxed);
+            if tsih != 0 && !in_use.contains(&tsih) && reserved.insert(tsih) {
+                return Some(tsih);
+            }
+        }
+        log::warn!("No free TSIH: all {} are in use", u16::MAX);
+        None
+    }
+
+    /// Return a TSIH from `allocate_tsih()` whose login never registered
+    pub(crate) fn release_tsih(&self, tsih: u16) {
+        self.inner.reserved_tsihs.lock().unwrap_or_else(|e| e.into_inner()).remove(&tsih);
+    }
+
+    /// Find the session a login with a non-zero TSIH continues (RFC 3720 Section 5.3.4)
+    ///
+    /// Only looks: nothing is claimed or drained until the login has
+    /// authenticated and calls `claim_session()`. A live session is continued
+    /// only by a login reusing its CID (connection reinstatement); any other
+    /// CID would add a second connection, which MaxConnections=1 does not allow.
+    pub(crate) fn lookup_session(&self, isid: [u8; 6], tsih: u16, initiator_name: &str, cid: u16) -> SessionLookup {
+        if let Some(snapshot) = self.find_restored(isid, tsih, initiator_name) {
+            return SessionLookup::Resume(snapshot);
+        }
+
+        let mut sessions = self.registered();
+        let Some(session) = self.find_live(&mut sessions, isid, tsih, initiator_name) else {
+            return SessionLookup::NotFound;
+        };
+        if session.cid != cid {
+            log::warn!("Login for TSIH {} would add connection {} (MaxConnections=1)", tsih, cid);
+            return SessionLookup::TooManyConnections;
+        }
+        let snapshot = session.snapshot.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        SessionLookup::Resume(snapshot)
+    }
+
+    /// Claim the session an authenticated login continues
+    ///
+    /// Restored and retained snapshots are claimed first. Otherwise the live
+    /// session's connection is reinstated: the old connection is drained and
+    /// the session moves to the new one. None if the session went away since
+    /// `lookup_session()`.
+    pub(crate) fn claim_session(&self, isid: [u8; 6], tsih: u16, initiator_name: &str, cid: u16) -> Option<ClaimedSession> {
+        if let Some(claim) = self.take_restored(isid, tsih, initiator_name) {
+            return Some(claim);
+        }
+
+        let mut sessions = self.registered();
+        let session = self.find_live(&mut sessions, isid, tsih, initiator_name)
+            .filter(|session| session.cid == cid)?;
+        log::info!("Reinstating connection {} of session TSIH {} for {}", cid, tsih, initiator_name);
+        session.draining = true;
+        (session.drain)();
+        let snapshot = session.snapshot.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        Some(ClaimedSession::Reinstated(snapshot))
+    }
+
+    /// Put back a session claimed by a login that failed before full feature phase
+    ///
+    /// Restored and retained snapshots can be resumed again; a reinstated
+    /// connection has already been asked to log out and stays drained.
+    pub(crate) fn unclaim(&self, claim: ClaimedSession) {
+        log::info!("Login continuing session TSIH {} failed, releasing it", claim.snapshot().tsih);
+        match claim {
+            ClaimedSession::Restored(snapshot) => {
+                self.inner.restored.lock().unwrap_or_else(|e| e.into_inner()).push(snapshot);
+            }
+            ClaimedSession::Retained(snapshot, deadline) => {
+                self.inner.retained.lock().unwrap_or_else(|e| e.into_inner()).push((snapshot, deadline));
+            }
+            ClaimedSession::Reinstated(_) => {}
+        }
+    }
+
+    /// The established session, not being drained, with this ISID, TSIH and initiator name
+    fn find_live<'a>(
+        &self,
+        sessions: &'a mut [RegisteredSession],
+        isid: [u8; 6],
+        tsih: u16,
+        initiator_name: &str,
+    ) -> Option<&'a mut RegisteredSession> {
+        let target_name = self.config().target_name;
+        sessions.iter_mut().find(|session| {
+            let snapshot = session.snapshot.lock().unwrap_or_else(|e| e.into_inner());
+            !session.draining
+                && snapshot.isid == isid
+                && snapshot.tsih == tsih
+                && session.initiator_name == initiator_name
+                && session.target_name == target_name
+        })
+    }
+
+    /// Track a session that has entered FullFeaturePhase
+    ///
+    /// `params` are those negotiated at login and `initiator_addr` is where
+    /// its connection came from. `drain` is called (at most once) if a configuration change stops
+    /// admitting the session, including one applied while it was logging in.
+    /// An existing session with the same initiator name and ISID is
+    /// reinstated: it is drained in favour of this one. The session is
+    /// forgotten when the returned registration is dropped.
+    pub(crate) fn register_session(
+        &self,
+        snapshot: SessionSnapshot,
+        cid: u16,
+        params: SessionParams,
+        initiator_addr: Option<IpAddr>,
+        drain: impl Fn() + Send + 'static,
+    ) -> SessionRegistration {
+        let config = self.inner.config.read().unwrap_or_else(|e| e.into_inner());
+        let admitted = config.admits(&snapshot.target_name, &snapshot.initiator_name, initiator_addr);
+        if !admitted {
+            log::info!("Session from {} logged in under a replaced configuration, draining", snapshot.initiator_name);
+            drain();
+        }
+
+        self.release_tsih(snapshot.tsih);
+
+        let id = self.inner.next_session_id.fetch_add(1, Ordering::Relaxed);
+        let registration = SessionRegistration {
+            control: self.clone(),
+            id,
+            snapshot: Arc::new(Mutex::new(snapshot.clone())),
+            heartbeat: Arc::new(Mutex::new(None)),
+        };
+        let mut sessions = self.registered();
+        for session in sessions.iter_mut() {
+            let existing = session.snapshot.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            if !session.draining && existing.isid == snapshot.isid && existing.initiator_name == snapshot.initiator_name {
+                log::warn!(
+                    "Duplicate session from {} (TSIH {}): reinstating as TSIH {}",
+                    snapshot.initiator_name, existing.tsih, snapshot.tsih
+                );
+                session.draining = true;
+                (session.drain)();
+            }
+        }
+        sessions.push(RegisteredSession {
+            id,
+            cid,
+            target_name: snapshot.target_name,
+            initiator_name: snapshot.initiator_name,
+            initiator_addr,
+            params,
+            drain: Box::new(drain),
+            ping: None,
+            draining: !admitted,
+            snapshot: Arc::clone(&registration.snapshot),
+            heartbeat: Arc::clone(&registration.heartbeat),
+        });
+        registration
+    }
+
+    /// Send the session with `tsih` a NOP-In and wait up to `timeout` for
+    /// the initiator's NOP-Out echo, returning the round-trip time
+    pub fn ping_session(&self, tsih: u16, timeout: Duration) -> ScsiResult<Duration> {
+        let (reply, round_trip) = mpsc::channel();
+        {
+            let sessions = self.registered();
+            let session = sessions.iter()
+                .find(|session| session.snapshot.lock().unwrap_or_else(|e| e.into_inner()).tsih == tsih)
+                .ok_or_else(|| IscsiError::Session(format!("No session with TSIH {}", tsih)))?;
+            let ping = session.ping.as_ref()
+                .ok_or_else(|| IscsiError::Session(format!("Session {} cannot be pinged", tsih)))?;
+            ping(reply);
+        }
+        round_trip.recv_timeout(timeout).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => IscsiError::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("no NOP-Out from session {} within {:?}", tsih, timeout),
+            )),
+            mpsc::RecvTimeoutError::Disconnected => IscsiError::Session(format!("Session {} closed before answering the ping", tsih)),
+        })
+    }
+
+    fn registered(&self) -> std::sync::MutexGuard<'_, Vec<RegisteredSession>> {
+        self.inner.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Keeps a session registered with its `TargetControl` until dropped
+pub(crate) struct SessionRegistration {
+    control: TargetControl,
+    id: u64,
+    snapshot: Arc<Mutex<SessionSnapshot>>,
+    heartbeat: Arc<Mutex<Option<Instant>>>,
+}
+
+impl SessionRegistration {
+    /// Record the session's current sequence numbers
+    pub(crate) fn update(&self, exp_cmd_sn: u32, max_cmd_sn: u32, stat_sn: u32) {
+        let mut snapshot = self.snapshot.lock().unwrap_or_else(|e| e.into_inner());
+        snapshot.exp_cmd_sn = exp_cmd_sn;
+        snapshot.max_cmd_sn = max_cmd_sn;
+        snapshot.stat_sn = stat_sn;
+    }
+
+    /// Record a NOP-Out from the initiator
+    pub(crate) fn heartbeat(&self, at: Instant) {
+        *self.heartbeat.lock().unwrap_or_else(|e| e.into_inner()) = Some(at);
+    }
+
+    /// Let `ping_session()` reach the session's connection
+    pub(crate) fn set_ping(&self, ping: impl Fn(mpsc::Sender<Duration>) + Send + 'static) {
+        if let Some(session) = self.control.registered().iter_mut().find(|session| session.id == self.id) {
+            session.ping = Some(Box::new(ping));
+        }
+    }
+}
+
+impl Drop for SessionRegistration {
+    fn drop(&mut self) {
+        self.control.registered().retain(|session| session.id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ChapCredentials;
+    use std::sync::atomic::AtomicUsize;
+
+    fn snapshot(target_name: &str, initiator_name: &str) -> SessionSnapshot {
+        SessionSnapshot {
+            isid: [0x80, 0, 0, 0, 0, 1],
+            tsih: 7,
+            initiator_name: initiator_name.to_string(),
+            target_name: target_name.to_string(),
+            exp_cmd_sn: 1,
+            max_cmd_sn: 32,
+            stat_sn: 1,
+            params: Vec::new(),
+        }
+    }
+
+    fn counter() -> (Arc<AtomicUsize>, impl Fn() + Send + 'static) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let drained = Arc::clone(&count);
+        (count, move || { drained.fetch_add(1, Ordering::SeqCst); })
+    }
+
+    #[test]
+    fn test_apply_config_drains_only_affected_sessions() {
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let (alice_drained, alice_drain) = counter();
+        let (bob_drained, bob_drain) = counter();
+        let _alice = control.register_session(snapshot("iqn.2025-12.test:disk1", "iqn.test:alice"), 0, SessionParams::default(), None, alice_drain);
+        let _bob = control.register_session(snapshot("iqn.2025-12.test:disk1", "iqn.test:bob"), 0, SessionParams::default(), None, bob_drain);
+
+        // New CHAP secret and alias: nobody is drained
+        let mut config = control.config();
+        config.target_alias = "Renamed".to_string();
+        config.auth = AuthConfig::Chap {
+            credentials: ChapCredentials::new("user", "new-secret-123"),
+        };
+        let changes = control.apply_config(config.clone()).unwrap();
+        assert!(changes.alias_changed);
+        assert_eq!(changes.drained_sessions, 0);
+
+        // Bob is removed from the ACL
+        config.allowed_initiators = Some(vec!["iqn.test:alice".to_string()]);
+        let changes = control.apply_config(config.clone()).unwrap();
+        assert!(changes.acl_changed);
+        assert_eq!(changes.drained_sessions, 1);
+        assert_eq!(alice_drained.load(Ordering::SeqCst), 0);
+        assert_eq!(bob_drained.load(Ordering::SeqCst), 1);
+
+        // Renaming removes the old target: Alice drains, Bob is not drained twice
+        config.target_name = "iqn.2025-12.test:disk2".to_string();
+        let changes = control.apply_config(config).unwrap();
+        assert!(changes.target_renamed);
+        assert_eq!(changes.drained_sessions, 1);
+        assert_eq!(alice_drained.load(Ordering::SeqCst), 1);
+        assert_eq!(bob_drained.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_target_config_serializes() {
+        let mut config = TargetConfig::new("iqn.2025-12.test:disk1");
+        config.auth = AuthConfig::Chap { credentials: ChapCredentials::new("user", "secret-12345") };
+        config.allowed_initiators = Some(vec!["iqn.test:host-*".to_string()]);
+        config.allowed_networks = Some(vec!["10.0.0.0/8".parse().unwrap()]);
+        config.strictness = Strictness::Strict;
+
+        let text = toml::to_string(&config).unwrap();
+        assert!(text.contains("allowed_networks = [\"10.0.0.0/8\"]"));
+        let restored: TargetConfig = toml::from_str(&text).unwrap();
+        assert_eq!(restored.target_name, config.target_name);
+        assert!(matches!(restored.auth, AuthConfig::Chap { credentials } if credentials.secret == "secret-12345"));
+        assert_eq!(restored.allowed_networks, config.allowed_networks);
+        assert_eq!(restored.strictness, Strictness::Strict);
+
+        // A live secret provider cannot be written out
+        config.auth = AuthConfig::ChapProvider { provider: Arc::new(crate::auth::ChapAccounts::new()), mutual_credentials: None };
+        assert!(toml::to_string(&config).is_err());
+    }
+
+    #[test]
+    fn test_initiator_patterns() {
+        assert!(initiator_matches("iqn.test:alice", "iqn.test:alice"));
+        assert!(!initiator_matches("iqn.test:alice", "iqn.test:alice2"));
+        assert!(initiator_matches("iqn.2025-12.example:host-*", "iqn.2025-12.example:host-0042"));
+        assert!(initiator_matches("iqn.2025-12.example:host-*", "iqn.2025-12.example:host-"));
+        assert!(!initiator_matches("iqn.2025-12.example:host-*", "iqn.2025-12.example:db-1"));
+        assert!(initiator_matches("iqn.*.example:*-?", "iqn.2025-12.example:rack-a-7"));
+        assert!(!initiator_matches("iqn.*.example:*-?", "iqn.2025-12.example:rack-a-17"));
+        assert!(initiator_matches("*", ""));
+    }
+
+    #[test]
+    fn test_apply_config_drains_sessions_outside_networks() {
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let (near_drained, near_drain) = counter();
+        let (far_drained, far_drain) = counter();
+        let near = Some("10.1.2.3".parse().unwrap());
+        let far = Some("192.168.7.7".parse().unwrap());
+        let _near = control.register_session(snapshot("iqn.2025-12.test:disk1", "iqn.test:host-1"), 0, SessionParams::default(), near, near_drain);
+        let _far = control.register_session(snapshot("iqn.2025-12.test:disk1", "iqn.test:host-2"), 0, SessionParams::default(), far, far_drain);
+
+        let mut config = control.config();
+        config.allowed_initiators = Some(vec!["iqn.test:host-*".to_string()]);
+        config.allowed_networks = Some(vec!["10.0.0.0/8".parse().unwrap()]);
+        let changes = control.apply_config(config).unwrap();
+        assert!(changes.acl_changed);
+        assert_eq!(changes.drained_sessions, 1);
+        assert_eq!(near_drained.load(Ordering::SeqCst), 0);
+        assert_eq!(far_drained.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_apply_config_rejects_invalid_config() {
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let result = control.apply_config(TargetConfig::new("not-an-iqn"));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+        assert_eq!(control.config().target_name, "iqn.2025-12.test:disk1");
+    }
+
+    #[test]
+    fn test_registration_tracks_session_lifetime() {
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let (drained, drain) = counter();
+
+        // A login that completed against the old name is drained straight away
+        let registration = control.register_session(snapshot("iqn.2025-12.test:old", "iqn.test:alice"), 0, SessionParams::default(), None, drain);
+        assert_eq!(drained.load(Ordering::SeqCst), 1);
+        assert_eq!(control.session_count(), 1);
+
+        registration.update(5, 36, 4);
+        let snapshots = control.session_snapshots();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!((snapshots[0].exp_cmd_sn, snapshots[0].max_cmd_sn, snapshots[0].stat_sn), (5, 36, 4));
+
+        drop(registration);
+        assert_eq!(control.session_count(), 0);
+    }
+
+    #[test]
+    fn test_restored_sessions_are_claimed_once() {
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let saved = snapshot("iqn.2025-12.test:disk1", "iqn.test:alice");
+        control.restore_sessions(vec![saved.clone()]);
+
+        assert!(control.take_restored(saved.isid, saved.tsih, "iqn.test:mallory").is_none());
+        assert!(control.take_restored(saved.isid, 8, "iqn.test:alice").is_none());
+        // Looking does not claim
+        assert_eq!(control.lookup_session(saved.isid, saved.tsih, "iqn.test:alice", 0), SessionLookup::Resume(saved.clone()));
+        let claim = control.take_restored(saved.isid, saved.tsih, "iqn.test:alice");
+        assert_eq!(claim, Some(ClaimedSession::Restored(saved.clone())));
+        assert!(control.take_restored(saved.isid, saved.tsih, "iqn.test:alice").is_none());
+
+        // A login that fails after claiming puts the snapshot back
+        control.unclaim(claim.unwrap());
+        assert_eq!(control.take_restored(saved.isid, saved.tsih, "iqn.test:alice"), Some(ClaimedSession::Restored(saved.clone())));
+    }
+
+    #[test]
+    fn test_retained_sessions_expire() {
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let saved = snapshot("iqn.2025-12.test:disk1", "iqn.test:alice");
+
+        control.retain_session(saved.clone(), Duration::from_secs(20));
+        let claim = control.take_restored(saved.isid, saved.tsih, "iqn.test:alice").unwrap();
+        assert!(matches!(&claim, ClaimedSession::Retained(snapshot, _) if *snapshot == saved));
+        assert!(control.take_restored(saved.isid, saved.tsih, "iqn.test:alice").is_none());
+
+        // Put back with its original deadline
+        control.unclaim(claim.clone());
+        assert_eq!(control.take_restored(saved.isid, saved.tsih, "iqn.test:alice"), Some(claim));
+
+        // Time2Retain of zero: gone before anyone can resume it
+        control.retain_session(saved.clone(), Duration::ZERO);
+        assert!(control.take_restored(saved.isid, saved.tsih, "iqn.test:alice").is_none());
+    }
+
+    #[test]
+    fn test_tsih_allocation_is_unique() {
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let restored = snapshot("iqn.2025-12.test:disk1", "iqn.test:alice");
+        control.restore_sessions(vec![restored.clone()]);
+        let mut registered = snapshot("iqn.2025-12.test:disk1", "iqn.test:bob");
+        registered.tsih = 3;
+        let _bob = control.register_session(registered, 0, SessionParams::default(), None, || {});
+
+        let allocated: Vec<u16> = (0..100).map(|_| control.allocate_tsih().unwrap()).collect();
+        let unique: HashSet<u16> = allocated.iter().copied().collect();
+        assert_eq!(unique.len(), allocated.len());
+        assert!(!unique.contains(&0) && !unique.contains(&3) && !unique.contains(&restored.tsih));
+
+        // Every other TSIH is taken until one is released
+        while control.allocate_tsih().is_some() {}
+        control.release_tsih(allocated[0]);
+        assert_eq!(control.allocate_tsih(), Some(allocated[0]));
+    }
+
+    #[test]
+    fn test_duplicate_session_is_reinstated() {
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let (drained, drain) = counter();
+        let _old = control.register_session(snapshot("iqn.2025-12.test:disk1", "iqn.test:alice"), 0, SessionParams::default(), None, drain);
+        assert_eq!(control.find_session("iqn.test:alice", [0x80, 0, 0, 0, 0, 1]), Some(7));
+
+        // Same initiator and ISID logs in again: the old session is drained
+        let mut again = snapshot("iqn.2025-12.test:disk1", "iqn.test:alice");
+        again.tsih = 8;
+        let _new = control.register_session(again, 0, SessionParams::default(), None, || {});
+        assert_eq!(drained.load(Ordering::SeqCst), 1);
+        assert_eq!(control.find_session("iqn.test:alice", [0x80, 0, 0, 0, 0, 1]), Some(8));
+
+        let sessions = control.sessions();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().any(|session| session.tsih == 7 && session.draining));
+        assert!(control.find_session("iqn.test:alice", [0x80, 0, 0, 0, 0, 2]).is_none());
+    }
+
+    #[test]
+    fn test_lookup_session_for_nonzero_tsih() {
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let saved = snapshot("iqn.2025-12.test:disk1", "iqn.test:alice");
+        let (drained, drain) = counter();
+        let _live = control.register_session(saved.clone(), 1, SessionParams::default(), None, drain);
+
+        assert_eq!(control.lookup_session(saved.isid, 99, "iqn.test:alice", 1), SessionLookup::NotFound);
+        assert_eq!(control.lookup_session(saved.isid, saved.tsih, "iqn.test:mallory", 1), SessionLookup::NotFound);
+
+        // A second connection is refused; reusing the CID reinstates the
+        // connection, but only once the login claims the session
+        assert_eq!(control.lookup_session(saved.isid, saved.tsih, "iqn.test:alice", 2), SessionLookup::TooManyConnections);
+        assert_eq!(control.lookup_session(saved.isid, saved.tsih, "iqn.test:alice", 1), SessionLookup::Resume(saved.clone()));
+        assert_eq!(drained.load(Ordering::SeqCst), 0);
+        assert!(control.claim_session(saved.isid, saved.tsih, "iqn.test:alice", 2).is_none());
+        assert_eq!(control.claim_session(saved.isid, saved.tsih, "iqn.test:alice", 1), Some(ClaimedSession::Reinstated(saved.clone())));
+        assert_eq!(drained.load(Ordering::SeqCst), 1);
+
+        // The drained connection cannot be reinstated twice
+        assert_eq!(control.lookup_session(saved.isid, saved.tsih, "iqn.test:alice", 1), SessionLookup::NotFound);
+        assert!(control.claim_session(saved.isid, saved.tsih, "iqn.test:alice", 1).is_none());
+    }
+
+    #[test]
+    fn test_hot_plug_luns() {
+        use crate::backends::{MemBlockDevice, NullBlockDevice};
+        use crate::stats::CountingDevice;
+
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        assert!(matches!(control.add_lun(1, MemBlockDevice::new(8, 512)), Err(IscsiError::Config(_))));
+
+        let device = Arc::new(RwLock::new(CountingDevice::new(MemBlockDevice::new(8, 512))));
+        control.attach_luns(Arc::new(LunTable::new(device)));
+        control.add_lun(1, MemBlockDevice::new(8, 512)).unwrap();
+        assert_eq!(control.luns(), vec![0, 1]);
+
+        // Devices of another type cannot join the table
+        assert!(matches!(control.add_lun(2, NullBlockDevice::new(8, 512)), Err(IscsiError::Config(_))));
+
+        control.notify_lun_changed(1, ChangeKind::Resized).unwrap();
+        control.remove_lun(1).unwrap();
+        assert!(matches!(control.remove_lun(1), Err(IscsiError::Config(_))));
+        assert!(matches!(control.notify_lun_changed(1, ChangeKind::MediumChanged), Err(IscsiError::Config(_))));
+        assert_eq!(control.luns(), vec![0]);
+    }
+
+    #[test]
+    fn test_apply_config_removes_unlisted_luns() {
+        use crate::backends::MemBlockDevice;
+        use crate::stats::CountingDevice;
+
+        let control = TargetControl::new(TargetConfig::new("iqn.2025-12.test:disk1"));
+        let mut config = control.config();
+        assert_eq!(config.luns, None);
+        config.luns = Some(vec![0]);
+        assert!(matches!(control.apply_config(config), Err(IscsiError::Config(_))));
+
+        let device = Arc::new(RwLock::new(CountingDevice::new(MemBlockDevice::new(8, 512))));
+        control.attach_luns(Arc::new(LunTable::new(device)));
+        control.add_lun(1, MemBlockDevice::new(8, 512)).unwrap();
+        control.add_lun(2, MemBlockDevice::new(8, 512)).unwrap();
+        assert_eq!(control.config().luns, Some(vec![0, 1, 2]));
+
+        // A LUN the configuration cannot supply a device for is refused outright
+        let mut config = control.config();
+        config.target_alias = "Renamed".to_string();
+        config.luns = Some(vec![0, 3]);
+        assert!(matches!(control.apply_config(config.clone()), Err(IscsiError::Config(_))));
+        assert_eq!(control.config().target_alias, "iSCSI Target");
+        assert_eq!(control.luns(), vec![0, 1, 2]);
+
+        config.luns = Some(vec![0, 2]);
+        let changes = control.apply_config(config.clone()).unwrap();
+        assert_eq!(changes.luns_removed, vec![1]);
+        assert_eq!(control.luns(), vec![0, 2]);
+
+        // Leaving `luns` unset keeps the table as it is
+        config.luns = None;
+        let changes = control.apply_config(config).unwrap();
+        assert!(changes.luns_removed.is_empty());
+        assert_eq!(control.luns(), vec![0, 2]);
+    }
+}
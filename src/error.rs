@@ -1,5 +1,11 @@
 //! Error types for iSCSI target operations
+//!
+//! Variants carry typed detail where a caller may need to act on it: the
+//! sense a failed SCSI operation should report, the kind of protocol
+//! violation, and why authentication failed. The Display output keeps the
+//! human-readable message.
 
+use std::fmt;
 use thiserror::Error;
 
 /// iSCSI target errors
@@ -8,11 +14,16 @@ pub enum IscsiError {
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 
-    #[error("Protocol error: {0}")]
-    Protocol(String),
+    #[error("Protocol error: {message}")]
+    Protocol { kind: ProtocolErrorKind, message: String },
 
-    #[error("SCSI error: {0}")]
-    Scsi(String),
+    /// A SCSI operation failed; `sense`, when present, is what the initiator should see
+    #[error("SCSI error: {message}{}", sense_suffix(.sense))]
+    Scsi { sense: Option<SenseCode>, message: String },
+
+    /// A command sent by `IscsiClient` completed with a status other than GOOD
+    #[error("Command 0x{operation:02x} failed with status 0x{status:02x}{}", sense_suffix(.sense))]
+    CommandFailed { operation: u8, status: u8, sense: Option<SenseCode> },
 
     #[error("Session error: {0}")]
     Session(String),
@@ -23,8 +34,215 @@ pub enum IscsiError {
     #[error("Configuration error: {0}")]
     Config(String),
 
-    #[error("Authentication error: {0}")]
-    Auth(String),
+    #[error("Authentication error: {message}")]
+    Auth { reason: AuthFailure, message: String },
+
+    #[error("Malformed PDU: {0}")]
+    MalformedPdu(#[from] PduError),
+
+    /// A block device failure the initiator sees as a specific status or sense
+    #[error("Device error: {0}")]
+    Device(#[from] ScsiDeviceError),
+}
+
+impl IscsiError {
+    /// SCSI error without specific sense (reported as MEDIUM ERROR)
+    pub fn scsi(message: impl Into<String>) -> Self {
+        IscsiError::Scsi { sense: None, message: message.into() }
+    }
+
+    /// SCSI error reported to the initiator with the given sense
+    pub fn sense(sense: SenseCode, message: impl Into<String>) -> Self {
+        IscsiError::Scsi { sense: Some(sense), message: message.into() }
+    }
+
+    /// Protocol violation of the given kind
+    pub fn protocol(kind: ProtocolErrorKind, message: impl Into<String>) -> Self {
+        IscsiError::Protocol { kind, message: message.into() }
+    }
+
+    /// Authentication failure for the given reason
+    pub fn auth(reason: AuthFailure, message: impl Into<String>) -> Self {
+        IscsiError::Auth { reason, message: message.into() }
+    }
+
+    /// Sense carried by a `Scsi`, `CommandFailed` or `Device` error
+    pub fn sense_code(&self) -> Option<SenseCode> {
+        match self {
+            IscsiError::Scsi { sense, .. } | IscsiError::CommandFailed { sense, .. } => *sense,
+            IscsiError::Device(device) => device.sense_code(),
+            _ => None,
+        }
+    }
+
+    /// Kind of a `Protocol` error
+    pub fn protocol_kind(&self) -> Option<ProtocolErrorKind> {
+        match self {
+            IscsiError::Protocol { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+
+    /// Reason of an `Auth` error
+    pub fn auth_failure(&self) -> Option<AuthFailure> {
+        match self {
+            IscsiError::Auth { reason, .. } => Some(*reason),
+            _ => None,
+        }
+    }
+}
+
+/// Sense key and additional sense code of a SCSI error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SenseCode {
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+impl SenseCode {
+    /// NOT READY / LOGICAL UNIT NOT READY, CAUSE NOT REPORTABLE
+    pub const NOT_READY: SenseCode = SenseCode::new(0x02, 0x04, 0x00);
+    /// MEDIUM ERROR / UNRECOVERED READ ERROR
+    pub const MEDIUM_ERROR: SenseCode = SenseCode::new(0x03, 0x11, 0x00);
+    /// MEDIUM ERROR / WRITE ERROR
+    pub const WRITE_ERROR: SenseCode = SenseCode::new(0x03, 0x0C, 0x00);
+    /// DATA PROTECT / WRITE PROTECTED
+    pub const WRITE_PROTECTED: SenseCode = SenseCode::new(0x07, 0x27, 0x00);
+    /// ILLEGAL REQUEST / INVALID COMMAND OPERATION CODE
+    pub const INVALID_COMMAND_OPERATION_CODE: SenseCode = SenseCode::new(0x05, 0x20, 0x00);
+    /// ILLEGAL REQUEST / LOGICAL BLOCK ADDRESS OUT OF RANGE
+    pub const LBA_OUT_OF_RANGE: SenseCode = SenseCode::new(0x05, 0x21, 0x00);
+    /// ILLEGAL REQUEST / INVALID FIELD IN CDB
+    pub const INVALID_FIELD_IN_CDB: SenseCode = SenseCode::new(0x05, 0x24, 0x00);
+    /// ILLEGAL REQUEST / INVALID FIELD IN PARAMETER LIST
+    pub const INVALID_FIELD_IN_PARAMETER_LIST: SenseCode = SenseCode::new(0x05, 0x26, 0x00);
+    /// ILLEGAL REQUEST / LOGICAL UNIT NOT SUPPORTED
+    pub const LOGICAL_UNIT_NOT_SUPPORTED: SenseCode = SenseCode::new(0x05, 0x25, 0x00);
+    /// HARDWARE ERROR / INTERNAL TARGET FAILURE
+    pub const INTERNAL_TARGET_FAILURE: SenseCode = SenseCode::new(0x04, 0x44, 0x00);
+
+    pub const fn new(key: u8, asc: u8, ascq: u8) -> Self {
+        SenseCode { key, asc, ascq }
+    }
+}
+
+impl fmt::Display for SenseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sense key 0x{:02x}, ASC/ASCQ 0x{:02x}/0x{:02x}", self.key, self.asc, self.ascq)
+    }
+}
+
+/// Block device failures, as a `ScsiBlockDevice` should report them
+///
+/// Return one (converted with `?` or `.into()`) to tell the initiator
+/// exactly what went wrong: NOT READY and BUSY invite a retry, while a
+/// medium error or write protection fails the command. Other errors from a
+/// device are reported as MEDIUM ERROR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ScsiDeviceError {
+    /// The medium is temporarily unavailable (NOT READY, cause not reportable)
+    #[error("logical unit not ready")]
+    NotReady,
+
+    /// Data could not be read or written (MEDIUM ERROR)
+    #[error("unrecovered medium error")]
+    MediumError,
+
+    /// `lba` lies beyond the end of the device (LOGICAL BLOCK ADDRESS OUT OF RANGE)
+    #[error("LBA {lba} is out of range")]
+    OutOfRange { lba: u64 },
+
+    /// The device refuses writes (DATA PROTECT / WRITE PROTECTED)
+    #[error("write protected")]
+    WriteProtected,
+
+    /// The device cannot take the command now; reported with BUSY status and no sense
+    #[error("device busy")]
+    Busy,
+
+    /// Any other condition, reported with this sense
+    #[error("{0}")]
+    Custom(SenseCode),
+}
+
+impl ScsiDeviceError {
+    /// Sense reported for this error; None for `Busy`
+    pub fn sense_code(&self) -> Option<SenseCode> {
+        match *self {
+            ScsiDeviceError::NotReady => Some(SenseCode::NOT_READY),
+            ScsiDeviceError::MediumError => Some(SenseCode::MEDIUM_ERROR),
+            ScsiDeviceError::OutOfRange { .. } => Some(SenseCode::LBA_OUT_OF_RANGE),
+            ScsiDeviceError::WriteProtected => Some(SenseCode::WRITE_PROTECTED),
+            ScsiDeviceError::Busy => None,
+            ScsiDeviceError::Custom(sense) => Some(sense),
+        }
+    }
+}
+
+fn sense_suffix(sense: &Option<SenseCode>) -> String {
+    sense.map(|sense| format!(" ({})", sense)).unwrap_or_default()
+}
+
+/// What kind of protocol violation a `Protocol` error reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolErrorKind {
+    /// Header or data digest mismatch
+    Digest,
+    /// Data-Out offsets, lengths or sequencing outside the negotiated limits
+    DataSequence,
+    /// Login Request sequencing (C/T bits, continued text)
+    LoginSequence,
+    /// The target rejected a login with this status
+    LoginRejected { status_class: u8, status_detail: u8 },
+    /// A text key exchange the peer refused or did not answer
+    Negotiation,
+    /// A PDU or opcode not valid at this point
+    UnexpectedPdu,
+    /// Something this implementation does not support
+    Unsupported,
+}
+
+/// Why authentication failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// The peer did not select or offer an acceptable method or algorithm
+    MethodNotAccepted,
+    /// A CHAP key was missing or malformed
+    MalformedMessage,
+    /// CHAP_N names a user the target does not know
+    UnknownUser,
+    /// CHAP_R does not match the expected response
+    BadResponse,
+    /// The initiator reflected the target's own challenge
+    ReflectedChallenge,
+    /// Authentication is required but no credentials are configured
+    NotConfigured,
+}
+
+/// Structural problems found while parsing a PDU from the wire
+///
+/// Returned by [`IscsiPdu::parse`](crate::pdu::IscsiPdu::parse) so callers,
+/// including fuzz harnesses, can tell which length check failed.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PduError {
+    #[error("{length} bytes is shorter than the 48-byte BHS")]
+    TooShort { length: usize },
+
+    #[error("{length} bytes, but the header describes {needed}")]
+    Incomplete { length: usize, needed: usize },
+
+    #[error("TotalAHSLength of {length} bytes exceeds the limit of {limit}")]
+    AhsTooLong { length: usize, limit: usize },
+
+    #[error("DataSegmentLength of {length} bytes exceeds the limit of {limit}")]
+    DataSegmentTooLong { length: u32, limit: u32 },
+
+    #[error("AHS at offset {offset}: {reason}")]
+    MalformedAhs { offset: usize, reason: &'static str },
+
+    #[error("extended CDB of {length} bytes exceeds the limit of {limit}")]
+    ExtendedCdbTooLong { length: usize, limit: usize },
 }
 
 /// Result type for SCSI operations
@@ -206,3 +424,32 @@ pub fn decode_login_status(status_class: u8, status_detail: u8) -> String {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_structured_errors() {
+        let err = IscsiError::sense(SenseCode::LBA_OUT_OF_RANGE, "LBA 9 is beyond 8 blocks");
+        assert_eq!(err.sense_code(), Some(SenseCode::LBA_OUT_OF_RANGE));
+        assert_eq!(
+            err.to_string(),
+            "SCSI error: LBA 9 is beyond 8 blocks (sense key 0x05, ASC/ASCQ 0x21/0x00)"
+        );
+        assert_eq!(IscsiError::scsi("device offline").to_string(), "SCSI error: device offline");
+        assert_eq!(IscsiError::scsi("device offline").sense_code(), None);
+
+        let err = IscsiError::CommandFailed { operation: 0x28, status: 0x18, sense: None };
+        assert_eq!(err.to_string(), "Command 0x28 failed with status 0x18");
+
+        let err = IscsiError::protocol(ProtocolErrorKind::Digest, "header digest mismatch");
+        assert_eq!(err.protocol_kind(), Some(ProtocolErrorKind::Digest));
+        assert_eq!(err.auth_failure(), None);
+        assert_eq!(err.to_string(), "Protocol error: header digest mismatch");
+
+        let err = IscsiError::auth(AuthFailure::UnknownUser, "Unknown user 'bob'");
+        assert_eq!(err.auth_failure(), Some(AuthFailure::UnknownUser));
+        assert_eq!(err.to_string(), "Authentication error: Unknown user 'bob'");
+    }
+}
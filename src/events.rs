@@ -0,0 +1,88 @@
+//! Target event notifications
+//!
+//! Applications can register a `TargetObserver` with the target builder to be
+//! told about significant events (aborted tasks, etc.) without polling.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Why an in-flight task was aborted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    /// The initiator logged out while the task was outstanding
+    Logout,
+    /// The connection dropped while the task was outstanding
+    ConnectionLost,
+    /// The initiator violated the R2T/Data-Out sequencing rules
+    ProtocolError,
+    /// The initiator stopped sending Data-Out and R2T retransmissions ran out
+    Timeout,
+    /// The task's logical unit was removed from the target
+    LunRemoved,
+    /// The backing store of the task's logical unit was swapped
+    LunChanged,
+}
+
+/// Event emitted by the target
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TargetEvent {
+    /// A WRITE waiting on R2T/Data-Out was abandoned before all data arrived
+    WriteAborted {
+        /// Initiator Task Tag of the aborted command
+        itt: u32,
+        /// LUN the command was addressed to
+        lun: u64,
+        /// Starting LBA of the command
+        lba: u64,
+        /// Transfer length in blocks
+        transfer_length: u32,
+        /// Bytes that had already been written to the device
+        bytes_received: u32,
+        /// Why the command was aborted
+        reason: AbortReason,
+    },
+    /// A command overran the target's command deadline and was answered
+    /// without waiting for the device, which still has it
+    CommandTimedOut {
+        /// Initiator Task Tag of the command
+        itt: u32,
+        /// LUN the command was addressed to
+        lun: u64,
+        /// Operation code of the command
+        opcode: u8,
+        /// How long the command, or the device call it was waiting on, had
+        /// been running
+        elapsed: Duration,
+    },
+    /// A member of a `MirroredBlockDevice` failed and was taken out of the mirror
+    MirrorMemberFailed {
+        /// Index of the member in the mirror
+        member: usize,
+        /// The error the member returned
+        error: String,
+    },
+    /// A member of a `MirroredBlockDevice` missed a write the others took
+    /// and is out of sync until rebuilt
+    MirrorMemberOutOfSync {
+        /// Index of the member in the mirror
+        member: usize,
+        /// The error the member returned
+        error: String,
+    },
+}
+
+/// Receiver for target events
+///
+/// Called synchronously from connection threads, so implementations should
+/// return quickly.
+pub trait TargetObserver: Send + Sync {
+    /// Handle a target event
+    fn on_event(&self, event: &TargetEvent);
+}
+
+impl fmt::Debug for dyn TargetObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TargetObserver")
+    }
+}
@@ -0,0 +1,229 @@
+//! PDU interception for fault injection and protocol experiments
+//!
+//! A `PduInterceptor` registered with
+//! [`IscsiTargetBuilder::pdu_interceptor`](crate::IscsiTargetBuilder::pdu_interceptor)
+//! sees every PDU a connection receives before the target processes it, and
+//! every PDU the target sends before it is framed. It may change the PDU,
+//! drop it, or give it a bad data digest, so tests can lose every Nth
+//! Data-In or send parameters the initiator under test would never send
+//! without patching the crate.
+//!
+//! PDU traces record received PDUs as they arrived and sent PDUs as they
+//! left, so both show what was on the wire.
+
+use crate::error::ScsiResult;
+use crate::pdu::{Digests, IscsiPdu, PduLimits, BHS_SIZE};
+use crate::trace::ConnectionTrace;
+use crate::transport::{PduTransport, ReceivedPdu};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What to do with an intercepted PDU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intercept {
+    /// Deliver the PDU, including any changes made to it
+    Pass,
+    /// Discard the PDU as if the network lost it
+    Drop,
+    /// Deliver the PDU with a bad data digest
+    ///
+    /// A received PDU is handled as a data digest error, a sent one goes out
+    /// with its digest inverted. Same as `Pass` for PDUs without data or
+    /// when DataDigest is not in use.
+    CorruptDataDigest,
+}
+
+/// Hook inspecting each PDU of every connection
+///
+/// Called from connection threads, so implementations keep any state (such
+/// as a count of Data-In PDUs seen) behind atomics or locks.
+pub trait PduInterceptor: Send + Sync {
+    /// Inspect a PDU received from the initiator
+    fn on_receive(&self, _pdu: &mut IscsiPdu) -> Intercept {
+        Intercept::Pass
+    }
+
+    /// Inspect a PDU the target is about to send
+    fn on_send(&self, _pdu: &mut IscsiPdu) -> Intercept {
+        Intercept::Pass
+    }
+}
+
+impl fmt::Debug for dyn PduInterceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PduInterceptor")
+    }
+}
+
+/// A connection's transport with the target's interceptor, if any, applied
+pub(crate) struct Intercepted<T> {
+    inner: T,
+    interceptor: Option<Arc<dyn PduInterceptor>>,
+}
+
+impl<T: PduTransport> Intercepted<T> {
+    pub(crate) fn new(inner: T, interceptor: Option<Arc<dyn PduInterceptor>>) -> Self {
+        Intercepted { inner, interceptor }
+    }
+}
+
+impl<T: PduTransport> PduTransport for Intercepted<T> {
+    fn recv_pdu(
+        &mut self,
+        max_data_segment: u32,
+        digests: Digests,
+        limits: &PduLimits,
+        trace: Option<&ConnectionTrace>,
+    ) -> ScsiResult<ReceivedPdu> {
+        let Some(interceptor) = &self.interceptor else {
+            return self.inner.recv_pdu(max_data_segment, digests, limits, trace);
+        };
+        loop {
+            let mut pdu = match self.inner.recv_pdu(max_data_segment, digests, limits, trace)? {
+                ReceivedPdu::Pdu(pdu) => pdu,
+                other => return Ok(other),
+            };
+            match interceptor.on_receive(&mut pdu) {
+                Intercept::Drop => log::debug!("Interceptor dropped received {} ITT=0x{:08x}", pdu.opcode_name(), pdu.itt),
+                Intercept::CorruptDataDigest if digests.data && !pdu.data.is_empty() => {
+                    let mut header = [0u8; BHS_SIZE];
+                    header.copy_from_slice(&pdu.to_bytes()[..BHS_SIZE]);
+                    return Ok(ReceivedPdu::DataDigestError { header });
+                }
+                Intercept::Pass | Intercept::CorruptDataDigest => return Ok(ReceivedPdu::Pdu(pdu)),
+            }
+        }
+    }
+
+    fn send_pdu(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>) -> ScsiResult<()> {
+        let Some(interceptor) = &self.interceptor else {
+            return self.inner.send_pdu(pdu, digests, trace);
+        };
+        let mut pdu = pdu.clone();
+        match interceptor.on_send(&mut pdu) {
+            Intercept::Pass => self.inner.send_pdu(&pdu, digests, trace),
+            Intercept::Drop => {
+                log::debug!("Interceptor dropped sent {} ITT=0x{:08x}", pdu.opcode_name(), pdu.itt);
+                Ok(())
+            }
+            Intercept::CorruptDataDigest => self.inner.send_pdu_with_bad_data_digest(&pdu, digests, trace),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Intercepted { inner: self.inner.try_clone()?, interceptor: self.interceptor.clone() })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.inner.close()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn send_pdu_with_bad_data_digest(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>) -> ScsiResult<()> {
+        self.inner.send_pdu_with_bad_data_digest(pdu, digests, trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdu::opcode;
+    use crate::transport::{duplex, Framed};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Drops received ITT 0, corrupts ITT 1 and renumbers the rest; drops
+    /// every second PDU sent and corrupts those carrying "bad"
+    #[derive(Default)]
+    struct Faults {
+        sent: AtomicU32,
+    }
+
+    impl PduInterceptor for Faults {
+        fn on_receive(&self, pdu: &mut IscsiPdu) -> Intercept {
+            match pdu.itt {
+                0 => Intercept::Drop,
+                1 => Intercept::CorruptDataDigest,
+                _ => {
+                    pdu.itt += 100;
+                    Intercept::Pass
+                }
+            }
+        }
+
+        fn on_send(&self, pdu: &mut IscsiPdu) -> Intercept {
+            if pdu.data == b"bad" {
+                return Intercept::CorruptDataDigest;
+            }
+            if self.sent.fetch_add(1, Ordering::SeqCst) % 2 == 1 {
+                return Intercept::Drop;
+            }
+            Intercept::Pass
+        }
+    }
+
+    fn nop(itt: u32, data: &[u8]) -> IscsiPdu {
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::NOP_IN;
+        pdu.itt = itt;
+        pdu.data = data.to_vec();
+        pdu
+    }
+
+    #[test]
+    fn test_interceptor_changes_drops_and_corrupts() {
+        let (initiator, target) = duplex();
+        let mut initiator = Framed::new(initiator);
+        let mut target = Intercepted::new(Framed::new(target), Some(Arc::new(Faults::default())));
+        let digests = Digests { header: true, data: true };
+
+        // Received: dropped, reported as a data digest error, rewritten
+        for itt in [0, 1, 2] {
+            initiator.send_pdu(&nop(itt, b"ping"), digests, None).unwrap();
+        }
+        let received = target.recv_pdu(8192, digests, &PduLimits::default(), None).unwrap();
+        assert!(matches!(received, ReceivedPdu::DataDigestError { header } if header[16..20] == 1u32.to_be_bytes()));
+        let received = target.recv_pdu(8192, digests, &PduLimits::default(), None).unwrap();
+        assert!(matches!(received, ReceivedPdu::Pdu(pdu) if pdu.itt == 102));
+
+        // Sent: every second PDU is lost, a corrupted one fails its digest
+        for itt in [10, 11, 12] {
+            target.send_pdu(&nop(itt, b"pong"), digests, None).unwrap();
+        }
+        target.send_pdu(&nop(13, b"bad"), digests, None).unwrap();
+        let received = initiator.recv_pdu(8192, digests, &PduLimits::default(), None).unwrap();
+        assert!(matches!(received, ReceivedPdu::Pdu(pdu) if pdu.itt == 10));
+        let received = initiator.recv_pdu(8192, digests, &PduLimits::default(), None).unwrap();
+        assert!(matches!(received, ReceivedPdu::Pdu(pdu) if pdu.itt == 12));
+        let received = initiator.recv_pdu(8192, digests, &PduLimits::default(), None).unwrap();
+        assert!(matches!(received, ReceivedPdu::DataDigestError { .. }));
+    }
+
+    #[test]
+    fn test_no_interceptor_passes_through() {
+        let (initiator, target) = duplex();
+        let mut initiator = Framed::new(initiator);
+        let mut target = Intercepted::new(Framed::new(target), None);
+
+        initiator.send_pdu(&nop(0, b"ping"), Digests::NONE, None).unwrap();
+        let received = target.recv_pdu(8192, Digests::NONE, &PduLimits::default(), None).unwrap();
+        assert!(matches!(received, ReceivedPdu::Pdu(pdu) if pdu.itt == 0 && pdu.data == b"ping"));
+    }
+}
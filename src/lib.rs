@@ -45,20 +45,93 @@
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! # Initiator
+//!
+//! `IscsiClient` is a minimal initiator for tests and tooling. It supports
+//! plain and CHAP login, CRC32C data digests and typed block I/O:
+//!
+//! ```no_run
+//! use iscsi_target::IscsiClient;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut client = IscsiClient::connect("127.0.0.1:3260")?;
+//! client.set_data_digest(true);
+//! client.login_chap(
+//!     "iqn.2025-12.local:initiator",
+//!     "iqn.2025-12.local:storage.disk1",
+//!     "user",
+//!     "secret",
+//! )?;
+//! let capacity = client.read_capacity()?;
+//! let block = client.read_blocks(capacity.blocks - 1, 1)?;
+//! client.write_blocks(0, &block)?;
+//! client.logout()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The `testsuite` module, enabled by the `testsuite` feature, runs the
+//! conformance tests of the C `iscsi-test-suite` through it, against a
+//! remote or in-process target.
+//!
+//! # Logging
+//!
+//! Diagnostics go through the `log` crate. With the `tracing` feature each
+//! connection also runs in an `iscsi_connection` span recording the
+//! initiator's address, IQN, ISID, TSIH and CID, so subscribers that forward
+//! `log` records (`tracing-log`) can filter and group them by session.
 
+pub mod audit;
 pub mod auth;
+pub mod backends;
+pub mod cdb;
 pub mod client;
+pub mod control;
 pub mod error;
+pub mod events;
+pub mod intercept;
+pub mod lun;
 pub mod pdu;
+pub mod portal;
+pub mod protection;
 pub mod scsi;
 pub mod session;
+pub mod stats;
 pub mod target;
+#[cfg(feature = "testsuite")]
+pub mod testsuite;
+pub mod trace;
+pub mod transport;
+pub mod vpd;
+mod aca;
+mod log_context;
+mod outbound;
+mod readahead;
+mod reservation;
+mod worker;
 
-pub use auth::{AuthConfig, ChapCredentials};
-pub use client::IscsiClient;
-pub use error::{IscsiError, ScsiResult};
-pub use scsi::ScsiBlockDevice;
-pub use target::{IscsiTarget, IscsiTargetBuilder};
+pub use audit::{AuditRecord, AuditSink};
+pub use auth::{AuthConfig, ChapAccounts, ChapCredentials, ChapSecretProvider};
+pub use backends::{BlockGeometry, CompositeBlockDevice, CompositeLayout, EncryptedBlockDevice, FaultInjector, FaultyBlockDevice, GeometryBlockDevice, JournaledBlockDevice, MemBlockDevice, MirroredBlockDevice, NullBlockDevice};
+#[cfg(target_os = "linux")]
+pub use backends::RawBlockDevice;
+pub use client::{Capacity, DiscoveredTarget, InquiryData, IscsiClient, LoginOptions};
+pub use control::{ConfigChanges, SessionInfo, TargetConfig, TargetControl};
+pub use error::{AuthFailure, IscsiError, PduError, ProtocolErrorKind, ScsiDeviceError, ScsiResult, SenseCode};
+pub use events::{AbortReason, TargetEvent, TargetObserver};
+pub use intercept::{Intercept, PduInterceptor};
+pub use log_context::PduLogLevel;
+pub use lun::{ChangeKind, DeviceProvider};
+pub use portal::{IpNetwork, Portal, PortalPreference, PortalStatsSnapshot};
+pub use protection::{PiTransfer, ProtectionInfo};
+pub use scsi::{LbaStatus, ModeParameters, PowerCondition, ProvisioningStatus, ScsiBlockDevice};
+pub use session::{SessionSnapshot, Strictness};
+pub use stats::LunStatsSnapshot;
+pub use target::{CommandDeadline, IscsiTarget, IscsiTargetBuilder, Keepalive, R2tRetransmit, ReadAhead, SendQueueLimits, SessionLimits, ShutdownReport};
+pub use trace::PduTrace;
+pub use transport::{duplex, DuplexStream, Framed, PduTransport, ReceivedPdu, SocketOptions, TcpKeepalive, Transport};
+pub use vpd::{BlockLimits, Designator};
 
 /// Version of this library
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,106 @@
+//! Per-connection logging context
+//!
+//! With the `tracing` feature each connection runs inside an
+//! `iscsi_connection` span carrying the initiator's address, and its IQN,
+//! ISID, TSIH and CID once login completes. The connection, reader and
+//! worker threads all enter it, so `log` lines forwarded with `tracing-log`
+//! and the crate's own events are attributed to their session. Without the
+//! feature the identifiers are logged once at login, next to the address
+//! that the connection's other lines already carry.
+//!
+//! Each PDU sent or received is logged at debug level, with as much of its
+//! encoding as the target's `PduLogLevel` asks for.
+
+use crate::pdu::{IscsiPdu, BHS_SIZE};
+use std::net::SocketAddr;
+
+/// How much of each PDU a target dumps at debug level
+///
+/// Hex dumps cost an encoding of every PDU whenever debug logging is on,
+/// so they are off by default. Set with `IscsiTargetBuilder::pdu_logging`
+/// and change on the running target with `TargetControl::set_pdu_logging`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PduLogLevel {
+    /// One line naming each PDU
+    #[default]
+    None,
+    /// Also the Basic Header Segment in hex
+    Headers,
+    /// Also the AHS and data segment in hex, including CHAP exchanges and
+    /// block contents
+    Full,
+}
+
+impl PduLogLevel {
+    pub(crate) fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            1 => PduLogLevel::Headers,
+            2 => PduLogLevel::Full,
+            _ => PduLogLevel::None,
+        }
+    }
+}
+
+/// Log a PDU `direction` ("Received" or "Sending") as `level` asks
+pub(crate) fn log_pdu(level: PduLogLevel, direction: &str, pdu: &IscsiPdu) {
+    log::debug!("{} PDU: {} (opcode 0x{:02x})", direction, pdu.opcode_name(), pdu.opcode);
+    if level == PduLogLevel::None || !log::log_enabled!(log::Level::Debug) {
+        return;
+    }
+    let bytes = pdu.to_bytes();
+    log::debug!("  BHS: {}", hex::encode(&bytes[..BHS_SIZE]));
+    if level == PduLogLevel::Full && bytes.len() > BHS_SIZE {
+        log::debug!("  AHS and data ({} bytes): {}", bytes.len() - BHS_SIZE, hex::encode(&bytes[BHS_SIZE..]));
+    }
+}
+
+/// Logging context shared by the threads serving one connection
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionContext {
+    peer: SocketAddr,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl ConnectionContext {
+    pub(crate) fn new(peer: SocketAddr) -> Self {
+        ConnectionContext {
+            peer,
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "iscsi_connection",
+                %peer,
+                initiator = tracing::field::Empty,
+                isid = tracing::field::Empty,
+                tsih = tracing::field::Empty,
+                cid = tracing::field::Empty,
+            ),
+        }
+    }
+
+    /// Run `f` with the connection's span entered
+    pub(crate) fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "tracing")]
+        return self.span.in_scope(f);
+        #[cfg(not(feature = "tracing"))]
+        f()
+    }
+
+    /// Record the identifiers settled by login
+    pub(crate) fn set_session(&self, initiator_name: &str, isid: [u8; 6], tsih: u16, cid: u16) {
+        let isid = hex::encode(isid);
+        log::info!("Session from {}: initiator={} isid={} tsih={} cid={}", self.peer, initiator_name, isid, tsih, cid);
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("initiator", initiator_name);
+            self.span.record("isid", isid.as_str());
+            self.span.record("tsih", tsih);
+            self.span.record("cid", cid);
+        }
+    }
+}
@@ -0,0 +1,630 @@
+//! Logical units of a target
+//!
+//! The LUN table maps LUN numbers to devices and can change while the target
+//! runs (`TargetControl::add_lun` / `remove_lun`). Every change bumps the
+//! table's generation; a session that has not yet seen the current generation
+//! gets a REPORTED LUNS DATA HAS CHANGED unit attention.
+//!
+//! The embedding application reports changes to a LUN's backing store with
+//! `TargetControl::notify_lun_changed`. Each notice is numbered; a session
+//! that has not seen a LUN's latest notice gets the unit attention for it on
+//! its next command to that LUN.
+//!
+//! A LUN either has one device shared by every initiator, or its devices come
+//! from a `DeviceProvider`, which opens a separate device for each session.
+
+use crate::aca::AcaState;
+use crate::error::{IscsiError, ScsiResult};
+use crate::reservation::Reservation;
+use crate::scsi::{ModeParameters, ScsiBlockDevice, SenseData};
+use crate::stats::CountingDevice;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+/// Highest LUN the target addresses (flat space addressing)
+pub const MAX_LUN: u64 = 0x3FFF;
+
+/// A logical unit's device, shared by every session
+pub(crate) type SharedDevice<D> = Arc<RwLock<CountingDevice<D>>>;
+
+/// How the backing store of a LUN changed outside the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChangeKind {
+    /// The device grew or shrank (CAPACITY DATA HAS CHANGED)
+    Resized,
+    /// Different media now backs the LUN (NOT READY TO READY CHANGE, MEDIUM
+    /// MAY HAVE CHANGED); commands still in flight to it fail
+    MediumChanged,
+    /// The contents changed underneath the initiator, e.g. a snapshot was
+    /// restored, so cached data must be dropped (MEDIUM MAY HAVE CHANGED)
+    ContentsInvalidated,
+}
+
+impl ChangeKind {
+    /// Unit attention sessions get for the change
+    pub(crate) fn sense(self) -> SenseData {
+        match self {
+            ChangeKind::Resized => SenseData::capacity_data_changed(),
+            ChangeKind::MediumChanged | ChangeKind::ContentsInvalidated => SenseData::medium_may_have_changed(),
+        }
+    }
+
+    /// Whether commands queued or waiting on Data-Out when the change is
+    /// reported fail with its unit attention
+    pub fn fails_in_flight(self) -> bool {
+        matches!(self, ChangeKind::MediumChanged)
+    }
+}
+
+/// Opens a session's device for a LUN from the initiator name and LUN number
+pub(crate) type DeviceOpener<D> = Arc<dyn Fn(&str, u64) -> ScsiResult<D> + Send + Sync>;
+
+/// Source of per-initiator devices
+///
+/// Implement this to give each initiator its own view of a LUN (per-client
+/// home volumes, per-session clones) instead of one device shared by all.
+/// Build the target with `IscsiTargetBuilder::build_with_provider()`.
+pub trait DeviceProvider: Send + Sync {
+    /// Open the device `initiator_iqn` sees as `lun`
+    ///
+    /// Called on a session's first command to the LUN; the device is dropped
+    /// when the session ends. An error fails that command with the error's
+    /// status and sense.
+    fn open(&self, initiator_iqn: &str, lun: u64) -> ScsiResult<Box<dyn ScsiBlockDevice>>;
+
+    /// LUNs the provider serves (default: LUN 0)
+    fn luns(&self) -> Vec<u64> {
+        vec![0]
+    }
+}
+
+impl fmt::Debug for dyn DeviceProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DeviceProvider")
+    }
+}
+
+/// Single level LUN addressing methods (SAM-5 4.7.7)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressMethod {
+    /// Peripheral device addressing, bus 0: LUNs 0 to 255
+    Peripheral,
+    /// Flat space addressing: LUNs 0 to 16383
+    Flat,
+    /// Logical unit addressing, target 0 and bus 0: LUNs 0 to 31
+    LogicalUnit,
+}
+
+impl AddressMethod {
+    /// Highest LUN the method can address
+    pub fn max_lun(self) -> u64 {
+        match self {
+            AddressMethod::Peripheral => 0xFF,
+            AddressMethod::Flat => MAX_LUN,
+            AddressMethod::LogicalUnit => 0x1F,
+        }
+    }
+}
+
+/// Encode a LUN number as the 8-byte LUN field of a PDU or REPORT LUNS
+///
+/// LUNs below 256 use peripheral device addressing, larger ones flat space
+/// addressing (SAM-5 4.7).
+pub fn encode_lun(lun: u64) -> u64 {
+    let method = if lun < 256 { AddressMethod::Peripheral } else { AddressMethod::Flat };
+    encode_lun_as(lun & MAX_LUN, method).unwrap_or_default()
+}
+
+/// Encode a LUN number with a given addressing method, or None if the
+/// method cannot address it
+pub fn encode_lun_as(lun: u64, method: AddressMethod) -> Option<u64> {
+    if lun > method.max_lun() {
+        return None;
+    }
+    let address = match method {
+        AddressMethod::Peripheral => lun,
+        AddressMethod::Flat => 0x4000 | lun,
+        AddressMethod::LogicalUnit => 0x8000 | lun,
+    };
+    Some(address << 48)
+}
+
+/// Decode the 8-byte LUN field, or None for a hierarchical or extended
+/// address the target does not use
+pub fn decode_lun(field: u64) -> Option<u64> {
+    decode_lun_address(field).map(|(_, lun)| lun)
+}
+
+/// Decode the 8-byte LUN field along with the addressing method it uses
+///
+/// Peripheral and logical unit addresses naming a bus or target other than
+/// 0 reach no LUN of this target.
+pub fn decode_lun_address(field: u64) -> Option<(AddressMethod, u64)> {
+    if field & 0x0000_FFFF_FFFF_FFFF != 0 {
+        return None;
+    }
+    let address = field >> 48;
+    match address >> 14 {
+        0b00 if address >> 8 == 0 => Some((AddressMethod::Peripheral, address)),
+        0b01 => Some((AddressMethod::Flat, address & MAX_LUN)),
+        0b10 if address & 0x3FE0 == 0 => Some((AddressMethod::LogicalUnit, address & 0x1F)),
+        _ => None,
+    }
+}
+
+/// INQUIRY identification reported in place of the devices' own
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Identity {
+    pub(crate) vendor: Option<String>,
+    pub(crate) product: Option<String>,
+    pub(crate) revision: Option<String>,
+    pub(crate) serial: Option<String>,
+    /// Report NormACA in INQUIRY and honour the NACA bit
+    pub(crate) norm_aca: bool,
+    /// Read-only mode page values reported in place of the devices' own
+    pub(crate) mode_parameters: Option<ModeParameters>,
+}
+
+impl Identity {
+    /// Check the fields fit their INQUIRY and VPD fields
+    pub(crate) fn validate(&self) -> ScsiResult<()> {
+        let fields = [("vendor", &self.vendor, 8), ("product", &self.product, 16), ("revision", &self.revision, 4), ("serial", &self.serial, 64)];
+        for (name, value, max_len) in fields {
+            let Some(value) = value else { continue };
+            if value.is_empty() || value.len() > max_len || !value.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+                return Err(IscsiError::Config(format!(
+                    "SCSI {} {:?} must be 1 to {} printable ASCII characters",
+                    name, value, max_len
+                )));
+            }
+        }
+        if let Some(params) = &self.mode_parameters {
+            if params.mrie > 6 {
+                return Err(IscsiError::Config(format!("MRIE {} must be 0 to 6", params.mrie)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The identity `lun` reports; LUNs after 0 append their number to the
+    /// serial so multipath can tell them apart
+    fn for_lun(&self, lun: u64) -> Identity {
+        let serial = self.serial.as_ref().map(|serial| match lun {
+            0 => serial.clone(),
+            lun => format!("{}-{}", serial, lun),
+        });
+        Identity { serial, ..self.clone() }
+    }
+}
+
+/// Logical unit state shared by every session, whichever device each
+/// session reaches the unit through
+#[derive(Debug, Default)]
+pub(crate) struct UnitState {
+    /// SCSI-2 reservation of the unit
+    pub(crate) reservation: Reservation,
+    /// ACA condition of the unit
+    pub(crate) aca: AcaState,
+}
+
+/// An exported LUN
+struct Lun<D: ScsiBlockDevice> {
+    /// Generation of the table that added the LUN, telling apart a LUN
+    /// removed and added again
+    added: u64,
+    /// The shared device, or None if each session opens its own
+    device: Option<SharedDevice<D>>,
+    /// Reservation and ACA state of the unit
+    unit: Arc<UnitState>,
+    /// Number and kind of the latest change notice for the LUN
+    changed: Option<(u64, ChangeKind)>,
+}
+
+/// The target's LUN table
+pub(crate) struct LunTable<D: ScsiBlockDevice> {
+    luns: RwLock<BTreeMap<u64, Lun<D>>>,
+    generation: AtomicU64,
+    /// Number of the latest change notice, for any LUN
+    changes: AtomicU64,
+    opener: Option<DeviceOpener<D>>,
+    /// Identification every LUN reports instead of its device's
+    identity: Identity,
+}
+
+impl<D: ScsiBlockDevice> LunTable<D> {
+    /// Table exporting `device` as LUN 0
+    pub(crate) fn new(device: SharedDevice<D>) -> Self {
+        LunTable {
+            luns: RwLock::new(BTreeMap::from([(0, Lun { added: 0, device: Some(device), unit: Arc::default(), changed: None })])),
+            generation: AtomicU64::new(0),
+            changes: AtomicU64::new(0),
+            opener: None,
+            identity: Identity::default(),
+        }
+    }
+
+    /// Table exporting `luns` with devices opened by each session
+    pub(crate) fn with_opener(luns: &[u64], opener: DeviceOpener<D>) -> ScsiResult<Self> {
+        if luns.is_empty() {
+            return Err(IscsiError::Config("a device provider must serve at least one LUN".to_string()));
+        }
+        if let Some(lun) = luns.iter().find(|&&lun| lun > MAX_LUN) {
+            return Err(IscsiError::Config(format!("LUN {} exceeds the maximum of {}", lun, MAX_LUN)));
+        }
+        Ok(LunTable {
+            luns: RwLock::new(luns.iter().map(|&lun| (lun, Lun { added: 0, device: None, unit: Arc::default(), changed: None })).collect()),
+            generation: AtomicU64::new(0),
+            changes: AtomicU64::new(0),
+            opener: Some(opener),
+            identity: Identity::default(),
+        })
+    }
+
+    /// Report `identity` from every LUN, present and future
+    pub(crate) fn set_identity(&mut self, identity: Identity) {
+        for (lun, entry) in self.luns.get_mut().unwrap_or_else(|e| e.into_inner()).iter() {
+            if let Some(device) = &entry.device {
+                device.write().unwrap_or_else(|e| e.into_inner()).set_identity(identity.for_lun(*lun));
+            }
+        }
+        self.identity = identity;
+    }
+
+    /// Wrap a device newly exported as `lun`
+    fn share(&self, lun: u64, device: D) -> SharedDevice<D> {
+        let mut device = CountingDevice::new(device);
+        device.set_identity(self.identity.for_lun(lun));
+        Arc::new(RwLock::new(device))
+    }
+
+    /// Shared device of a LUN number, if that LUN exists and has one
+    pub(crate) fn device(&self, lun: u64) -> Option<SharedDevice<D>> {
+        self.table().get(&lun)?.device.clone()
+    }
+
+    /// Reservation and ACA state of a LUN number, if that LUN exists
+    pub(crate) fn unit(&self, lun: u64) -> Option<Arc<UnitState>> {
+        self.table().get(&lun).map(|entry| Arc::clone(&entry.unit))
+    }
+
+    /// Exported LUN numbers in ascending order
+    pub(crate) fn luns(&self) -> Vec<u64> {
+        self.table().keys().copied().collect()
+    }
+
+    /// Incremented on every change to the table
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Export `device` as `lun`
+    pub(crate) fn insert(&self, lun: u64, device: D) -> ScsiResult<()> {
+        if lun > MAX_LUN {
+            return Err(IscsiError::Config(format!("LUN {} exceeds the maximum of {}", lun, MAX_LUN)));
+        }
+        let mut luns = self.luns.write().unwrap_or_else(|e| e.into_inner());
+        if luns.contains_key(&lun) {
+            return Err(IscsiError::Config(format!("LUN {} already exists", lun)));
+        }
+        let added = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        luns.insert(lun, Lun { added, device: Some(self.share(lun, device)), unit: Arc::default(), changed: None });
+        log::info!("Added LUN {}", lun);
+        Ok(())
+    }
+
+    /// Stop exporting `lun`
+    pub(crate) fn remove(&self, lun: u64) -> ScsiResult<()> {
+        let mut luns = self.luns.write().unwrap_or_else(|e| e.into_inner());
+        if luns.remove(&lun).is_none() {
+            return Err(IscsiError::Config(format!("LUN {} does not exist", lun)));
+        }
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        log::info!("Removed LUN {}", lun);
+        Ok(())
+    }
+
+    /// Stop exporting every LUN not in `keep`, returning the ones removed
+    ///
+    /// Fails without removing anything if `keep` lists a LUN that does not exist.
+    pub(crate) fn retain(&self, keep: &[u64]) -> ScsiResult<Vec<u64>> {
+        let mut luns = self.luns.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(lun) = keep.iter().find(|lun| !luns.contains_key(lun)) {
+            return Err(IscsiError::Config(format!("LUN {} does not exist", lun)));
+        }
+        let removed: Vec<u64> = luns.keys().copied().filter(|lun| !keep.contains(lun)).collect();
+        if !removed.is_empty() {
+            luns.retain(|lun, _| keep.contains(lun));
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            log::info!("Removed LUNs {:?}", removed);
+        }
+        Ok(removed)
+    }
+
+    /// Record a change to the backing store of `lun`
+    pub(crate) fn notify_changed(&self, lun: u64, kind: ChangeKind) -> ScsiResult<()> {
+        let mut luns = self.luns.write().unwrap_or_else(|e| e.into_inner());
+        let Some(entry) = luns.get_mut(&lun) else {
+            return Err(IscsiError::Config(format!("LUN {} does not exist", lun)));
+        };
+        let notice = self.changes.fetch_add(1, Ordering::SeqCst) + 1;
+        entry.changed = Some((notice, kind));
+        log::info!("LUN {} changed: {:?}", lun, kind);
+        Ok(())
+    }
+
+    /// Number of the latest change notice, for any LUN
+    pub(crate) fn changes(&self) -> u64 {
+        self.changes.load(Ordering::SeqCst)
+    }
+
+    fn table(&self) -> std::sync::RwLockReadGuard<'_, BTreeMap<u64, Lun<D>>> {
+        self.luns.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A session's view of the LUN table, holding the devices it opened
+pub(crate) struct SessionLuns<D: ScsiBlockDevice> {
+    table: Arc<LunTable<D>>,
+    initiator: OnceLock<String>,
+    /// Devices opened for this session, with the generation their LUN was added at
+    opened: Mutex<HashMap<u64, (u64, SharedDevice<D>)>>,
+}
+
+impl<D: ScsiBlockDevice> SessionLuns<D> {
+    pub(crate) fn new(table: Arc<LunTable<D>>) -> Self {
+        SessionLuns { table, initiator: OnceLock::new(), opened: Mutex::new(HashMap::new()) }
+    }
+
+    /// Set the initiator that devices are opened for, once it has logged in
+    pub(crate) fn set_initiator(&self, initiator: &str) {
+        let _ = self.initiator.set(initiator.to_string());
+    }
+
+    /// Device of `lun`, if that LUN exists and its device is open
+    pub(crate) fn get(&self, lun: u64) -> Option<SharedDevice<D>> {
+        let table = self.table.table();
+        let entry = table.get(&lun)?;
+        match &entry.device {
+            Some(device) => Some(Arc::clone(device)),
+            None => self.opened()
+                .get(&lun)
+                .filter(|(added, _)| *added == entry.added)
+                .map(|(_, device)| Arc::clone(device)),
+        }
+    }
+
+    /// Device of `lun`, opening the session's own device if needed
+    pub(crate) fn open(&self, lun: u64) -> ScsiResult<Option<SharedDevice<D>>> {
+        if let Some(device) = self.get(lun) {
+            return Ok(Some(device));
+        }
+        let Some(opener) = &self.table.opener else {
+            return Ok(None);
+        };
+        let Some(added) = self.table.table().get(&lun).map(|entry| entry.added) else {
+            return Ok(None);
+        };
+        let initiator = self.initiator.get().map_or("", String::as_str);
+        let device = self.table.share(lun, opener(initiator, lun)?);
+        log::info!("Opened LUN {} for {}", lun, initiator);
+        self.opened().insert(lun, (added, Arc::clone(&device)));
+        Ok(Some(device))
+    }
+
+    /// Reservation and ACA state of `lun`, if that LUN exists
+    pub(crate) fn unit(&self, lun: u64) -> Option<Arc<UnitState>> {
+        self.table.unit(lun)
+    }
+
+    /// Reservation and ACA state of every LUN
+    pub(crate) fn units(&self) -> Vec<Arc<UnitState>> {
+        self.table.table().values().map(|entry| Arc::clone(&entry.unit)).collect()
+    }
+
+    /// Whether `device` is still exported as `lun`
+    pub(crate) fn is_current(&self, lun: u64, device: &SharedDevice<D>) -> bool {
+        self.get(lun).is_some_and(|current| Arc::ptr_eq(&current, device))
+    }
+
+    pub(crate) fn luns(&self) -> Vec<u64> {
+        self.table.luns()
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.table.generation()
+    }
+
+    pub(crate) fn changes(&self) -> u64 {
+        self.table.changes()
+    }
+
+    /// Latest change notice for `lun` numbered after `seen`
+    pub(crate) fn changed_since(&self, lun: u64, seen: u64) -> Option<(u64, ChangeKind)> {
+        self.table.table().get(&lun)?.changed.filter(|(notice, _)| *notice > seen)
+    }
+
+    fn opened(&self) -> std::sync::MutexGuard<'_, HashMap<u64, (u64, SharedDevice<D>)>> {
+        self.opened.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A LUN table without its device type, as held by `TargetControl`
+pub(crate) trait LunRegistry: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn luns(&self) -> Vec<u64>;
+    fn remove(&self, lun: u64) -> ScsiResult<()>;
+    fn retain(&self, keep: &[u64]) -> ScsiResult<Vec<u64>>;
+    fn notify_changed(&self, lun: u64, kind: ChangeKind) -> ScsiResult<()>;
+}
+
+impl<D: ScsiBlockDevice + 'static> LunRegistry for LunTable<D> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn luns(&self) -> Vec<u64> {
+        LunTable::luns(self)
+    }
+
+    fn remove(&self, lun: u64) -> ScsiResult<()> {
+        LunTable::remove(self, lun)
+    }
+
+    fn retain(&self, keep: &[u64]) -> ScsiResult<Vec<u64>> {
+        LunTable::retain(self, keep)
+    }
+
+    fn notify_changed(&self, lun: u64, kind: ChangeKind) -> ScsiResult<()> {
+        LunTable::notify_changed(self, lun, kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemBlockDevice;
+    use crate::vpd::Designator;
+
+    #[test]
+    fn test_lun_addressing() {
+        assert_eq!(encode_lun(0), 0);
+        assert_eq!(encode_lun(5), 0x0005_0000_0000_0000);
+        assert_eq!(encode_lun(300), 0x412C_0000_0000_0000);
+        for lun in [0, 1, 255, 256, 300, MAX_LUN] {
+            assert_eq!(decode_lun(encode_lun(lun)), Some(lun));
+        }
+        // Flat space addressing of a small LUN is accepted too
+        assert_eq!(decode_lun(0x4005_0000_0000_0000), Some(5));
+        // Bus identifiers and second-level addresses are not used
+        assert_eq!(decode_lun(0x0105_0000_0000_0000), None);
+        assert_eq!(decode_lun(0x0005_0001_0000_0000), None);
+
+        // Every method addresses LUN 5 the same; each has its own range
+        for (method, field) in [
+            (AddressMethod::Peripheral, 0x0005_0000_0000_0000),
+            (AddressMethod::Flat, 0x4005_0000_0000_0000),
+            (AddressMethod::LogicalUnit, 0x8005_0000_0000_0000),
+        ] {
+            assert_eq!(encode_lun_as(5, method), Some(field));
+            assert_eq!(decode_lun_address(field), Some((method, 5)));
+            assert_eq!(encode_lun_as(method.max_lun() + 1, method), None);
+        }
+        assert_eq!(encode_lun(99), encode_lun_as(99, AddressMethod::Peripheral).unwrap());
+        // Logical unit addressing of another target or bus
+        assert_eq!(decode_lun(0x8105_0000_0000_0000), None);
+        assert_eq!(decode_lun(0x8025_0000_0000_0000), None);
+        // Extended logical unit addressing
+        assert_eq!(decode_lun(0xD205_0000_0000_0000), None);
+    }
+
+    #[test]
+    fn test_lun_table_changes() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MemBlockDevice::new(8, 512))));
+        let table = Arc::new(LunTable::new(device));
+        let session = SessionLuns::new(Arc::clone(&table));
+        assert_eq!(table.luns(), vec![0]);
+        assert_eq!(table.generation(), 0);
+
+        table.insert(300, MemBlockDevice::new(8, 512)).unwrap();
+        assert!(table.insert(300, MemBlockDevice::new(8, 512)).is_err());
+        assert!(table.insert(MAX_LUN + 1, MemBlockDevice::new(8, 512)).is_err());
+        assert_eq!(table.luns(), vec![0, 300]);
+        assert_eq!(table.generation(), 1);
+
+        let lun300 = session.get(300).unwrap();
+        assert!(session.is_current(300, &lun300));
+        table.remove(300).unwrap();
+        assert!(table.remove(300).is_err());
+        assert!(!session.is_current(300, &lun300));
+        assert_eq!(table.generation(), 2);
+    }
+
+    #[test]
+    fn test_lun_change_notices() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MemBlockDevice::new(8, 512))));
+        let table = Arc::new(LunTable::new(device));
+        table.insert(1, MemBlockDevice::new(8, 512)).unwrap();
+        let session = SessionLuns::new(Arc::clone(&table));
+        assert_eq!(session.changes(), 0);
+        assert!(table.notify_changed(2, ChangeKind::Resized).is_err());
+
+        table.notify_changed(1, ChangeKind::Resized).unwrap();
+        table.notify_changed(1, ChangeKind::MediumChanged).unwrap();
+        assert_eq!(session.changes(), 2);
+        // Only the latest notice is kept, and only for the LUN it names
+        assert_eq!(session.changed_since(1, 0), Some((2, ChangeKind::MediumChanged)));
+        assert_eq!(session.changed_since(1, 2), None);
+        assert_eq!(session.changed_since(0, 0), None);
+
+        // A LUN added again starts without notices
+        table.remove(1).unwrap();
+        table.insert(1, MemBlockDevice::new(8, 512)).unwrap();
+        assert_eq!(session.changed_since(1, 0), None);
+        assert!(ChangeKind::MediumChanged.fails_in_flight());
+        assert!(!ChangeKind::Resized.fails_in_flight());
+    }
+
+    #[test]
+    fn test_identity_overrides() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MemBlockDevice::new(8, 512))));
+        let mut table = LunTable::new(device);
+        table.set_identity(Identity {
+            vendor: Some("ACME".to_string()),
+            serial: Some("ACME-0042".to_string()),
+            ..Identity::default()
+        });
+        table.insert(3, MemBlockDevice::new(8, 512)).unwrap();
+
+        let lun0 = table.device(0).unwrap();
+        let lun0 = lun0.read().unwrap();
+        assert_eq!((lun0.vendor_id(), lun0.product_id(), lun0.serial_number()), ("ACME", "Virtual Disk    ", "ACME-0042"));
+        assert_eq!(lun0.designators(), vec![
+            Designator::T10VendorId { vendor: "ACME".to_string(), identifier: "ACME-0042".to_string() },
+            Designator::naa_from_serial("ACME-0042"),
+        ]);
+        assert_eq!(table.device(3).unwrap().read().unwrap().serial_number(), "ACME-0042-3");
+
+        assert!(Identity { vendor: Some("TOO-LONG-VENDOR".to_string()), ..Identity::default() }.validate().is_err());
+        assert!(Identity { serial: Some(String::new()), ..Identity::default() }.validate().is_err());
+        assert!(Identity { product: Some("Disk\n".to_string()), ..Identity::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_devices_opened_per_session() {
+        let opener: DeviceOpener<MemBlockDevice> = Arc::new(|initiator: &str, lun: u64| {
+            if initiator.ends_with(":denied") {
+                return Err(IscsiError::Config("no volume".to_string()));
+            }
+            Ok(MemBlockDevice::new(8 + lun, 512))
+        });
+        let table = Arc::new(LunTable::with_opener(&[0, 1], opener).unwrap());
+        assert!(table.device(0).is_none());
+
+        let alice = SessionLuns::new(Arc::clone(&table));
+        alice.set_initiator("iqn.test:alice");
+        let bob = SessionLuns::new(Arc::clone(&table));
+        bob.set_initiator("iqn.test:bob");
+
+        // Nothing is opened until a session needs it, then once per session
+        assert!(alice.get(1).is_none());
+        let device = alice.open(1).unwrap().unwrap();
+        assert_eq!(device.read().unwrap().capacity(), 9);
+        assert!(Arc::ptr_eq(&alice.open(1).unwrap().unwrap(), &device));
+        assert!(!Arc::ptr_eq(&bob.open(1).unwrap().unwrap(), &device));
+        assert!(alice.open(2).unwrap().is_none());
+
+        // Removing the LUN retires the opened device
+        table.remove(1).unwrap();
+        assert!(!alice.is_current(1, &device));
+
+        let denied = SessionLuns::new(Arc::clone(&table));
+        denied.set_initiator("iqn.test:denied");
+        assert!(denied.open(0).is_err());
+
+        assert!(LunTable::with_opener(&[], Arc::new(|_: &str, _| Ok(MemBlockDevice::new(8, 512)))).is_err());
+    }
+}
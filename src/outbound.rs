@@ -0,0 +1,395 @@
+//! Per-connection send queue
+//!
+//! The connection thread queues the PDUs it sends and a writer thread puts
+//! them on the wire, so an initiator that stops reading (a zero TCP window)
+//! stalls only the writer, not command processing. The queue is bounded in
+//! bytes: once it is half full the connection holds the initiator's command
+//! window still until the writer drains it below half again, and a send that
+//! finds it full for the stall timeout tears the connection down. Closing the connection sends whatever is still queued first.
+
+use crate::error::{IscsiError, ScsiResult};
+use crate::log_context::ConnectionContext;
+use crate::pdu::{Digests, IscsiPdu, PduLimits, BHS_SIZE};
+use crate::target::SendQueueLimits;
+use crate::trace::ConnectionTrace;
+use crate::transport::{PduTransport, ReceivedPdu};
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A PDU waiting for the writer
+struct Queued {
+    pdu: IscsiPdu,
+    digests: Digests,
+    trace: Option<ConnectionTrace>,
+    bad_data_digest: bool,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Queued>,
+    /// Bytes queued, including the PDU being written
+    bytes: usize,
+    /// Why the connection can no longer send, once it cannot
+    failed: Option<io::ErrorKind>,
+    /// Every handle is gone: the writer exits once the queue is empty
+    closed: bool,
+    /// Called by the writer when the queue drains out of congestion
+    on_drained: Option<Box<dyn Fn() + Send>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    /// Queued bytes from which the connection counts as congested
+    congested_at: usize,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait on `state` until `deadline`; false once it has passed
+    fn wait<'a>(&self, state: MutexGuard<'a, State>, deadline: Instant) -> (MutexGuard<'a, State>, bool) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return (state, false);
+        }
+        (self.changed.wait_timeout(state, remaining).unwrap_or_else(|e| e.into_inner()).0, true)
+    }
+}
+
+/// Stops the writer once the last handle of the connection is dropped
+struct WriterHandle(Arc<Shared>);
+
+impl Drop for WriterHandle {
+    fn drop(&mut self) {
+        self.0.lock().closed = true;
+        self.0.changed.notify_all();
+    }
+}
+
+/// A connection's transport with its sends queued for a writer thread
+pub(crate) struct Outbound<T> {
+    inner: T,
+    shared: Arc<Shared>,
+    _writer: Arc<WriterHandle>,
+    limits: SendQueueLimits,
+}
+
+impl<T: PduTransport> Outbound<T> {
+    /// Queue sends on `inner`, starting its writer thread
+    pub(crate) fn new(inner: T, limits: SendQueueLimits, context: ConnectionContext) -> ScsiResult<Self> {
+        let writer = inner.try_clone().map_err(IscsiError::Io)?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+            congested_at: limits.capacity / 2,
+        });
+        let writer_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("iscsi-writer".to_string())
+            .spawn(move || context.in_scope(|| write_queued(writer, &writer_shared)))
+            .map_err(IscsiError::Io)?;
+        Ok(Outbound { inner, _writer: Arc::new(WriterHandle(Arc::clone(&shared))), shared, limits })
+    }
+
+    /// Whether the queue is at least half full, so the initiator should be
+    /// held back from sending more commands
+    pub(crate) fn congested(&self) -> bool {
+        self.shared.lock().bytes >= self.shared.congested_at
+    }
+
+    /// Call `wake` from the writer each time the queue drains below half
+    /// full, so a connection holding the command window can reopen it
+    pub(crate) fn on_drained(&self, wake: impl Fn() + Send + 'static) {
+        self.shared.lock().on_drained = Some(Box::new(wake));
+    }
+
+    fn enqueue(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>, bad_data_digest: bool) -> ScsiResult<()> {
+        let size = BHS_SIZE + pdu.data.len();
+        let deadline = Instant::now() + self.limits.stall_timeout;
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(kind) = state.failed {
+                return Err(IscsiError::Io(io::Error::new(kind, "connection can no longer send")));
+            }
+            // A PDU larger than the whole queue still goes out on its own
+            if state.bytes == 0 || state.bytes + size <= self.limits.capacity {
+                break;
+            }
+            let waited;
+            (state, waited) = self.shared.wait(state, deadline);
+            if !waited {
+                log::warn!(
+                    "Initiator has not taken {} queued bytes for {:?}, dropping connection",
+                    state.bytes, self.limits.stall_timeout
+                );
+                state.failed = Some(io::ErrorKind::TimedOut);
+                state.queue.clear();
+                drop(state);
+                self.shared.changed.notify_all();
+                let _ = self.inner.close();
+                return Err(IscsiError::Io(io::Error::new(io::ErrorKind::TimedOut, "send queue stalled")));
+            }
+        }
+        state.queue.push_back(Queued { pdu: pdu.clone(), digests, trace: trace.cloned(), bad_data_digest });
+        state.bytes += size;
+        drop(state);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Wait up to the stall timeout for everything queued to be sent
+    fn flush(&self) {
+        let deadline = Instant::now() + self.limits.stall_timeout;
+        let mut state = self.shared.lock();
+        while state.bytes > 0 && state.failed.is_none() {
+            let waited;
+            (state, waited) = self.shared.wait(state, deadline);
+            if !waited {
+                log::warn!("Closing connection with {} bytes unsent", state.bytes);
+                break;
+            }
+        }
+    }
+}
+
+/// Writer thread: send queued PDUs until the connection fails or every
+/// handle is gone and the queue is empty
+fn write_queued<T: PduTransport>(mut writer: T, shared: &Shared) {
+    loop {
+        let mut state = shared.lock();
+        while state.queue.is_empty() && !state.closed && state.failed.is_none() {
+            state = shared.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        if state.failed.is_some() {
+            return;
+        }
+        let Some(queued) = state.queue.pop_front() else {
+            return;
+        };
+        drop(state);
+
+        let size = BHS_SIZE + queued.pdu.data.len();
+        let sent = if queued.bad_data_digest {
+            writer.send_pdu_with_bad_data_digest(&queued.pdu, queued.digests, queued.trace.as_ref())
+        } else {
+            writer.send_pdu(&queued.pdu, queued.digests, queued.trace.as_ref())
+        };
+
+        let mut state = shared.lock();
+        state.bytes -= size;
+        if let Err(e) = sent {
+            log::warn!("Sending {} failed: {}", queued.pdu.opcode_name(), e);
+            state.failed = Some(match e {
+                IscsiError::Io(e) => e.kind(),
+                _ => io::ErrorKind::Other,
+            });
+            state.queue.clear();
+            state.bytes = 0;
+            drop(state);
+            shared.changed.notify_all();
+            let _ = writer.close();
+            return;
+        }
+        if state.bytes < shared.congested_at && state.bytes + size >= shared.congested_at {
+            if let Some(wake) = &state.on_drained {
+                wake();
+            }
+        }
+        drop(state);
+        shared.changed.notify_all();
+    }
+}
+
+impl<T: PduTransport> PduTransport for Outbound<T> {
+    fn recv_pdu(
+        &mut self,
+        max_data_segment: u32,
+        digests: Digests,
+        limits: &PduLimits,
+        trace: Option<&ConnectionTrace>,
+    ) -> ScsiResult<ReceivedPdu> {
+        self.inner.recv_pdu(max_data_segment, digests, limits, trace)
+    }
+
+    fn send_pdu(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>) -> ScsiResult<()> {
+        self.enqueue(pdu, digests, trace, false)
+    }
+
+    fn send_pdu_with_bad_data_digest(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>) -> ScsiResult<()> {
+        self.enqueue(pdu, digests, trace, true)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Outbound {
+            inner: self.inner.try_clone()?,
+            shared: Arc::clone(&self.shared),
+            _writer: Arc::clone(&self._writer),
+            limits: self.limits,
+        })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.flush();
+        self.inner.close()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdu::opcode;
+    use crate::transport::{duplex, Framed};
+    use std::sync::mpsc;
+
+    fn nop_in(itt: u32, data: usize) -> IscsiPdu {
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::NOP_IN;
+        pdu.itt = itt;
+        pdu.data = vec![0; data];
+        pdu
+    }
+
+    fn context() -> ConnectionContext {
+        ConnectionContext::new(([127, 0, 0, 1], 0).into())
+    }
+
+    #[test]
+    fn test_queued_pdus_sent_in_order() {
+        let (initiator, target) = duplex();
+        let mut initiator = Framed::new(initiator);
+        let limits = SendQueueLimits { capacity: 4096, stall_timeout: Duration::from_secs(5) };
+        let mut target = Outbound::new(Framed::new(target), limits, context()).unwrap();
+
+        for itt in 0..8 {
+            target.send_pdu(&nop_in(itt, 512), Digests::NONE, None).unwrap();
+        }
+        target.close().unwrap();
+        for itt in 0..8 {
+            let ReceivedPdu::Pdu(pdu) = initiator.recv_pdu(8192, Digests::NONE, &PduLimits::default(), None).unwrap() else {
+                panic!("oversized PDU");
+            };
+            assert_eq!(pdu.itt, itt);
+        }
+    }
+
+    /// Transport whose sends block until the test lets each one through
+    struct Stalled {
+        gate: Arc<Mutex<mpsc::Receiver<()>>>,
+        closed: Arc<Mutex<bool>>,
+    }
+
+    impl PduTransport for Stalled {
+        fn recv_pdu(&mut self, _: u32, _: Digests, _: &PduLimits, _: Option<&ConnectionTrace>) -> ScsiResult<ReceivedPdu> {
+            Err(IscsiError::Io(io::ErrorKind::UnexpectedEof.into()))
+        }
+
+        fn send_pdu(&mut self, _: &IscsiPdu, _: Digests, _: Option<&ConnectionTrace>) -> ScsiResult<()> {
+            self.gate.lock().unwrap().recv().map_err(|_| IscsiError::Io(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn try_clone(&self) -> io::Result<Self> {
+            Ok(Stalled { gate: Arc::clone(&self.gate), closed: Arc::clone(&self.closed) })
+        }
+
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn close(&self) -> io::Result<()> {
+            *self.closed.lock().unwrap() = true;
+            Ok(())
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(crate::transport::LOCAL_TARGET_ADDR)
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(crate::transport::LOCAL_INITIATOR_ADDR)
+        }
+    }
+
+    #[test]
+    fn test_stalled_initiator_drops_connection() {
+        let (release, gate) = mpsc::channel();
+        let closed = Arc::new(Mutex::new(false));
+        let stalled = Stalled { gate: Arc::new(Mutex::new(gate)), closed: Arc::clone(&closed) };
+        let size = BHS_SIZE + 464;
+        let limits = SendQueueLimits { capacity: 4 * size, stall_timeout: Duration::from_millis(50) };
+        let mut target = Outbound::new(stalled, limits, context()).unwrap();
+
+        // Sends do not wait on the initiator until the queue is full
+        target.send_pdu(&nop_in(0, 464), Digests::NONE, None).unwrap();
+        assert!(!target.congested());
+        target.send_pdu(&nop_in(1, 464), Digests::NONE, None).unwrap();
+        assert!(target.congested());
+        target.send_pdu(&nop_in(2, 464), Digests::NONE, None).unwrap();
+        target.send_pdu(&nop_in(3, 464), Digests::NONE, None).unwrap();
+
+        // One PDU going out makes room for another
+        release.send(()).unwrap();
+        target.send_pdu(&nop_in(4, 464), Digests::NONE, None).unwrap();
+
+        // A queue that stays full tears the connection down
+        let started = Instant::now();
+        let error = target.send_pdu(&nop_in(5, 464), Digests::NONE, None).unwrap_err();
+        assert!(matches!(error, IscsiError::Io(e) if e.kind() == io::ErrorKind::TimedOut));
+        assert!(started.elapsed() >= limits.stall_timeout);
+        assert!(*closed.lock().unwrap());
+        assert!(target.send_pdu(&nop_in(6, 0), Digests::NONE, None).is_err());
+    }
+
+    #[test]
+    fn test_drained_queue_wakes_connection() {
+        let (release, gate) = mpsc::channel();
+        let stalled = Stalled { gate: Arc::new(Mutex::new(gate)), closed: Arc::new(Mutex::new(false)) };
+        let size = BHS_SIZE + 464;
+        let limits = SendQueueLimits { capacity: 4 * size, stall_timeout: Duration::from_secs(5) };
+        let mut target = Outbound::new(stalled, limits, context()).unwrap();
+        let (wake, woken) = mpsc::channel();
+        target.on_drained(move || { let _ = wake.send(()); });
+
+        for itt in 0..3 {
+            target.send_pdu(&nop_in(itt, 464), Digests::NONE, None).unwrap();
+        }
+        assert!(target.congested());
+
+        // Still half full after one PDU goes out
+        release.send(()).unwrap();
+        assert!(woken.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // Dropping below half wakes the connection once
+        release.send(()).unwrap();
+        woken.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(!target.congested());
+        release.send(()).unwrap();
+        assert!(woken.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
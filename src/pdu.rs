@@ -6,13 +6,133 @@
 // Protocol functions require many parameters per RFC 3720
 #![allow(clippy::too_many_arguments)]
 
-use crate::error::{IscsiError, ScsiResult};
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
-use std::io::Cursor;
+use crate::error::{IscsiError, PduError, ScsiResult};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+pub mod builder;
 
 /// BHS (Basic Header Segment) size in bytes
 pub const BHS_SIZE: usize = 48;
 
+/// AHS types (RFC 3720 Section 10.2.2.1)
+pub mod ahs_type {
+    pub const EXTENDED_CDB: u8 = 1;
+    pub const BIDI_READ_DATA_LENGTH: u8 = 2;
+}
+
+/// Length limits enforced while parsing PDUs
+///
+/// Checked against the BHS before anything else is read or allocated, so a
+/// peer cannot make the parser reserve more than these limits allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PduLimits {
+    /// Largest TotalAHSLength accepted, in bytes (default: 1020, the field maximum)
+    pub max_ahs_length: usize,
+    /// Largest DataSegmentLength accepted, in bytes (default: 16 MiB - 1, the field maximum)
+    pub max_data_segment_length: u32,
+    /// Largest Extended CDB AHS, in CDB bytes beyond the first 16 (default: 244)
+    pub max_extended_cdb_length: usize,
+}
+
+impl Default for PduLimits {
+    fn default() -> Self {
+        PduLimits {
+            max_ahs_length: 255 * 4,
+            max_data_segment_length: 0x00FF_FFFF,
+            // SCSI CDBs are at most 260 bytes
+            max_extended_cdb_length: 260 - 16,
+        }
+    }
+}
+
+impl PduLimits {
+    /// Check the length fields of a BHS, returning the AHS and data segment lengths
+    pub fn check_header(&self, bhs: &[u8; BHS_SIZE]) -> Result<(usize, u32), PduError> {
+        let ahs_length = bhs[4] as usize * 4;
+        if ahs_length > self.max_ahs_length {
+            return Err(PduError::AhsTooLong { length: ahs_length, limit: self.max_ahs_length });
+        }
+        let data_length = BigEndian::read_u24(&bhs[5..8]);
+        if data_length > self.max_data_segment_length {
+            return Err(PduError::DataSegmentTooLong { length: data_length, limit: self.max_data_segment_length });
+        }
+        Ok((ahs_length, data_length))
+    }
+
+    /// Parse the AHS entries, checking each fits and is within limits
+    fn parse_ahs(&self, ahs: &[u8]) -> Result<Vec<Ahs>, PduError> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < ahs.len() {
+            if ahs.len() - offset < 4 {
+                return Err(PduError::MalformedAhs { offset, reason: "truncated AHS header" });
+            }
+            let length = BigEndian::read_u16(&ahs[offset..offset + 2]) as usize;
+            let kind = ahs[offset + 2];
+            // AHSLength counts the bytes after AHSType, excluding padding
+            let entry = (3 + length).div_ceil(4) * 4;
+            if entry > ahs.len() - offset {
+                return Err(PduError::MalformedAhs { offset, reason: "AHSLength overruns TotalAHSLength" });
+            }
+            let body = &ahs[offset + 3..offset + 3 + length];
+            entries.push(match kind {
+                ahs_type::EXTENDED_CDB => {
+                    // One reserved byte precedes the CDB bytes
+                    let cdb = body.get(1..).unwrap_or_default();
+                    if cdb.is_empty() {
+                        return Err(PduError::MalformedAhs { offset, reason: "empty extended CDB" });
+                    }
+                    if cdb.len() > self.max_extended_cdb_length {
+                        return Err(PduError::ExtendedCdbTooLong {
+                            length: cdb.len(),
+                            limit: self.max_extended_cdb_length,
+                        });
+                    }
+                    Ahs::ExtendedCdb(cdb.to_vec())
+                }
+                ahs_type::BIDI_READ_DATA_LENGTH => {
+                    if length != 5 {
+                        return Err(PduError::MalformedAhs { offset, reason: "bidirectional read length AHS must be 5 bytes" });
+                    }
+                    Ahs::BidiReadDataLength(BigEndian::read_u32(&body[1..5]))
+                }
+                ahs_type => Ahs::Other { ahs_type, data: body.to_vec() },
+            });
+            offset += entry;
+        }
+        Ok(entries)
+    }
+}
+
+/// An Additional Header Segment (RFC 3720 Section 10.2.2)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ahs {
+    /// CDB bytes beyond the 16 carried in the BHS
+    ExtendedCdb(Vec<u8>),
+    /// Expected Bidirectional Read Data Length
+    BidiReadDataLength(u32),
+    /// Any other type, with the bytes following AHSType
+    Other { ahs_type: u8, data: Vec<u8> },
+}
+
+impl Ahs {
+    /// Append this AHS, padded to a 4-byte boundary
+    fn encode(&self, buf: &mut Vec<u8>) {
+        // AHSType and the bytes following it, which AHSLength counts
+        let (kind, body) = match self {
+            Ahs::ExtendedCdb(cdb) => (ahs_type::EXTENDED_CDB, [&[0u8][..], cdb].concat()),
+            Ahs::BidiReadDataLength(length) => {
+                (ahs_type::BIDI_READ_DATA_LENGTH, [&[0u8][..], &length.to_be_bytes()].concat())
+            }
+            Ahs::Other { ahs_type, data } => (*ahs_type, data.clone()),
+        };
+        buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buf.push(kind);
+        buf.extend_from_slice(&body);
+        buf.resize(buf.len().div_ceil(4) * 4, 0);
+    }
+}
+
 /// iSCSI PDU Opcodes (RFC 3720 Section 10)
 pub mod opcode {
     // Initiator opcodes (client → target)
@@ -48,6 +168,15 @@ pub mod flags {
     pub const READ: u8 = 0x40;
     pub const WRITE: u8 = 0x20;
 
+    // SCSI Response / Data-In residual flags
+    pub const RESIDUAL_OVERFLOW: u8 = 0x04;
+    pub const RESIDUAL_UNDERFLOW: u8 = 0x02;
+    pub const STATUS: u8 = 0x01;
+
+    // SCSI Response bidirectional read residual flags
+    pub const BIDI_READ_RESIDUAL_OVERFLOW: u8 = 0x10;
+    pub const BIDI_READ_RESIDUAL_UNDERFLOW: u8 = 0x08;
+
     // Login flags
     pub const TRANSIT: u8 = 0x80;
     pub const CONTINUE_LOGIN: u8 = 0x40;
@@ -61,6 +190,28 @@ pub mod flags {
     pub const NSG_FULL_FEATURE: u8 = 0x03;
 }
 
+/// SCSI Command task attributes (flags bits 0-2, RFC 3720 Section 10.3.1)
+pub mod task_attribute {
+    pub const MASK: u8 = 0x07;
+    pub const UNTAGGED: u8 = 0;
+    pub const SIMPLE: u8 = 1;
+    pub const ORDERED: u8 = 2;
+    pub const HEAD_OF_QUEUE: u8 = 3;
+    pub const ACA: u8 = 4;
+}
+
+/// Task management functions (RFC 3720 Section 10.5.1)
+pub mod tmf_function {
+    pub const ABORT_TASK: u8 = 1;
+    pub const ABORT_TASK_SET: u8 = 2;
+    pub const CLEAR_ACA: u8 = 3;
+    pub const CLEAR_TASK_SET: u8 = 4;
+    pub const LOGICAL_UNIT_RESET: u8 = 5;
+    pub const TARGET_WARM_RESET: u8 = 6;
+    pub const TARGET_COLD_RESET: u8 = 7;
+    pub const TASK_REASSIGN: u8 = 8;
+}
+
 /// Login status classes (RFC 3720 Section 10.13.5)
 pub mod login_status {
     pub const SUCCESS: u8 = 0x00;
@@ -136,6 +287,8 @@ pub struct IscsiPdu {
     /// For other PDUs this is reserved/opcode-specific
     pub version_or_reserved: u16,
     /// Total AHS (Additional Header Segment) length (4-byte units)
+    ///
+    /// As received; `to_bytes` derives it from `ahs`.
     pub ahs_length: u8,
     /// Data segment length (bytes)
     pub data_length: u32,
@@ -145,6 +298,8 @@ pub struct IscsiPdu {
     pub itt: u32,
     /// Opcode-specific fields (bytes 20-47, 28 bytes)
     pub specific: [u8; 28],
+    /// Additional Header Segments, at most 1020 bytes once encoded
+    pub ahs: Vec<Ahs>,
     /// Data segment (variable length)
     pub data: Vec<u8>,
 }
@@ -168,6 +323,7 @@ impl IscsiPdu {
             lun: 0,
             itt: 0,
             specific: [0u8; 28],
+            ahs: Vec::new(),
             data: Vec::new(),
         }
     }
@@ -175,86 +331,72 @@ impl IscsiPdu {
     /// Parse a PDU from bytes
     ///
     /// The input buffer must contain at least the 48-byte BHS.
-    /// If the PDU has data, the buffer must also contain the data segment.
+    /// If the PDU has AHS or data, the buffer must contain those too.
+    /// Uses the default [`PduLimits`].
     pub fn from_bytes(buf: &[u8]) -> ScsiResult<Self> {
-        if buf.len() < BHS_SIZE {
-            return Err(IscsiError::InvalidPdu(format!(
-                "PDU too short: {} bytes, need at least {}",
-                buf.len(),
-                BHS_SIZE
-            )));
-        }
-
-        let mut cursor = Cursor::new(buf);
-
-        // Byte 0: Immediate flag (bit 6) and Opcode (bits 0-5)
-        let byte0 = cursor.read_u8().map_err(IscsiError::Io)?;
-        let immediate = (byte0 & 0x40) != 0;
-        let opcode = byte0 & 0x3F;
-
-        // Byte 1: Flags (opcode-specific)
-        let flags = cursor.read_u8().map_err(IscsiError::Io)?;
-
-        // Bytes 2-3: For Login Request, this is version info; for others, reserved
-        let version_or_reserved = cursor.read_u16::<BigEndian>().map_err(IscsiError::Io)?;
-
-        // Byte 4: Total AHS Length (4-byte units)
-        let ahs_length = cursor.read_u8().map_err(IscsiError::Io)?;
-
-        // Bytes 5-7: Data Segment Length (3 bytes, big-endian)
-        let ds_len_high = cursor.read_u8().map_err(IscsiError::Io)? as u32;
-        let ds_len_low = cursor.read_u16::<BigEndian>().map_err(IscsiError::Io)? as u32;
-        let data_length = (ds_len_high << 16) | ds_len_low;
-
-        // Bytes 8-15: LUN
-        let lun = cursor.read_u64::<BigEndian>().map_err(IscsiError::Io)?;
-
-        // Bytes 16-19: Initiator Task Tag
-        let itt = cursor.read_u32::<BigEndian>().map_err(IscsiError::Io)?;
-
-        // Bytes 20-47: Opcode-specific fields
-        let mut specific = [0u8; 28];
-        std::io::Read::read_exact(&mut cursor, &mut specific).map_err(IscsiError::Io)?;
+        Ok(Self::parse(buf, &PduLimits::default())?)
+    }
 
-        // Calculate total expected length (BHS + AHS + data + padding)
-        let ahs_bytes = (ahs_length as usize) * 4;
-        let padded_data_len = (data_length as usize).div_ceil(4) * 4; // Pad to 4-byte boundary
+    /// Parse a PDU from bytes, enforcing `limits`
+    ///
+    /// Never panics and allocates no more than the limits allow, whatever
+    /// the input, which makes it a suitable cargo-fuzz target:
+    ///
+    /// ```
+    /// use iscsi_target::pdu::{IscsiPdu, PduLimits};
+    ///
+    /// // fuzz_target!(|data: &[u8]| { let _ = IscsiPdu::parse(data, &PduLimits::default()); });
+    /// let mut bhs = [0u8; 48];
+    /// bhs[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    /// assert!(IscsiPdu::parse(&bhs, &PduLimits::default()).is_err());
+    /// ```
+    pub fn parse(buf: &[u8], limits: &PduLimits) -> Result<Self, PduError> {
+        let bhs: &[u8; BHS_SIZE] = buf.get(..BHS_SIZE)
+            .and_then(|bhs| bhs.try_into().ok())
+            .ok_or(PduError::TooShort { length: buf.len() })?;
+        let (ahs_bytes, data_length) = limits.check_header(bhs)?;
+
+        // Total expected length (BHS + AHS + data + padding)
+        let padded_data_len = (data_length as usize).div_ceil(4) * 4;
         let total_len = BHS_SIZE + ahs_bytes + padded_data_len;
-
         if buf.len() < total_len {
-            return Err(IscsiError::InvalidPdu(format!(
-                "PDU incomplete: {} bytes, need {} (BHS={}, AHS={}, data={})",
-                buf.len(),
-                total_len,
-                BHS_SIZE,
-                ahs_bytes,
-                padded_data_len
-            )));
+            return Err(PduError::Incomplete { length: buf.len(), needed: total_len });
         }
 
-        // Extract data segment (skip AHS for now)
+        let ahs = limits.parse_ahs(&buf[BHS_SIZE..BHS_SIZE + ahs_bytes])?;
+
         let data_start = BHS_SIZE + ahs_bytes;
-        let data = buf[data_start..data_start + data_length as usize].to_vec();
+        let mut specific = [0u8; 28];
+        specific.copy_from_slice(&bhs[20..48]);
 
         Ok(IscsiPdu {
-            opcode,
-            immediate,
-            flags,
-            version_or_reserved,
-            ahs_length,
+            // Byte 0: Immediate flag (bit 6) and Opcode (bits 0-5)
+            opcode: bhs[0] & 0x3F,
+            immediate: (bhs[0] & 0x40) != 0,
+            // Byte 1: Flags (opcode-specific)
+            flags: bhs[1],
+            // Bytes 2-3: For Login Request, this is version info; for others, reserved
+            version_or_reserved: BigEndian::read_u16(&bhs[2..4]),
+            // Byte 4: Total AHS Length (4-byte units)
+            ahs_length: bhs[4],
+            // Bytes 5-7: Data Segment Length
             data_length,
-            lun,
-            itt,
+            // Bytes 8-15: LUN
+            lun: BigEndian::read_u64(&bhs[8..16]),
+            // Bytes 16-19: Initiator Task Tag
+            itt: BigEndian::read_u32(&bhs[16..20]),
+            // Bytes 20-47: Opcode-specific fields
             specific,
-            data,
+            ahs,
+            data: buf[data_start..data_start + data_length as usize].to_vec(),
         })
     }
 
     /// Serialize PDU to bytes
     pub fn to_bytes(&self) -> Vec<u8> {
-        let ahs_bytes = (self.ahs_length as usize) * 4;
+        let ahs = self.encode_ahs();
         let padded_data_len = self.data.len().div_ceil(4) * 4;
-        let total_len = BHS_SIZE + ahs_bytes + padded_data_len;
+        let total_len = BHS_SIZE + ahs.len() + padded_data_len;
 
         let mut buf = Vec::with_capacity(total_len);
 
@@ -269,14 +411,17 @@ impl IscsiPdu {
         // Special case for SCSI Response: bytes 2-3 are Response and Status
         // Special case for SCSI Data-In: byte 3 is Status if S bit is set
         // Special case for Login Request/Response: bytes 2-3 are version info
+        // Special case for Reject and Logout Response: byte 2 is the reason/response code
         if self.opcode == opcode::SCSI_RESPONSE {
             buf.push(self.specific[0]); // Response (byte 2)
             buf.push(self.specific[1]); // Status (byte 3)
         } else if self.opcode == opcode::SCSI_DATA_IN && (self.flags & 0x01) != 0 {
             buf.push(0); // Reserved (byte 2)
-            buf.push(self.specific[27]); // Status (byte 3) if S bit is set
-        } else if self.opcode == opcode::LOGIN_REQUEST || self.opcode == opcode::LOGIN_RESPONSE {
-            // Write version_or_reserved for Login PDUs
+            buf.push(self.version_or_reserved as u8); // Status (byte 3) if S bit is set
+        } else if self.opcode == opcode::LOGIN_REQUEST || self.opcode == opcode::LOGIN_RESPONSE
+            || self.opcode == opcode::REJECT || self.opcode == opcode::LOGOUT_RESPONSE
+        {
+            // Write version_or_reserved for Login, Reject and Logout Response PDUs
             buf.push((self.version_or_reserved >> 8) as u8); // High byte (version-max or active version)
             buf.push((self.version_or_reserved & 0xFF) as u8); // Low byte (version-min or reserved)
         } else {
@@ -285,7 +430,7 @@ impl IscsiPdu {
         }
 
         // Byte 4: Total AHS Length
-        buf.push(self.ahs_length);
+        buf.push((ahs.len() / 4) as u8);
 
         // Bytes 5-7: Data Segment Length (3 bytes, big-endian)
         let data_len = self.data.len() as u32;
@@ -297,8 +442,13 @@ impl IscsiPdu {
         // - SCSI Command PDU
         // - SCSI Data-Out PDU
         // - Task Management Function PDU
+        // Login PDUs carry ISID and TSIH here instead.
         // All other PDUs should have reserved/0 in this field
-        let write_lun = matches!(self.opcode, opcode::SCSI_COMMAND | opcode::SCSI_DATA_OUT | opcode::TASK_MANAGEMENT_REQUEST);
+        let write_lun = matches!(
+            self.opcode,
+            opcode::SCSI_COMMAND | opcode::SCSI_DATA_OUT | opcode::TASK_MANAGEMENT_REQUEST
+                | opcode::LOGIN_REQUEST | opcode::LOGIN_RESPONSE
+        );
         if write_lun {
             buf.write_u64::<BigEndian>(self.lun).unwrap();
         } else {
@@ -312,7 +462,8 @@ impl IscsiPdu {
         // Bytes 20-47: Opcode-specific fields
         buf.extend_from_slice(&self.specific);
 
-        // AHS (if any) - not implemented yet, would go here
+        // AHS (if any)
+        buf.extend_from_slice(&ahs);
 
         // Data segment
         buf.extend_from_slice(&self.data);
@@ -352,9 +503,17 @@ impl IscsiPdu {
 
     /// Get the total PDU length including headers and padded data
     pub fn total_length(&self) -> usize {
-        let ahs_bytes = (self.ahs_length as usize) * 4;
         let padded_data_len = self.data.len().div_ceil(4) * 4;
-        BHS_SIZE + ahs_bytes + padded_data_len
+        BHS_SIZE + self.encode_ahs().len() + padded_data_len
+    }
+
+    /// Encode the AHS entries as they appear after the BHS
+    fn encode_ahs(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for ahs in &self.ahs {
+            ahs.encode(&mut buf);
+        }
+        buf
     }
 }
 
@@ -534,18 +693,27 @@ impl IscsiPdu {
 
         let expected_data_length = BigEndian::read_u32(&self.specific[0..4]);
 
-        // CDB is in specific[12..28] (16 bytes)
-        let mut cdb = [0u8; 16];
-        cdb.copy_from_slice(&self.specific[12..28]);
+        // CDB is in specific[12..28] (16 bytes), continued in an Extended CDB AHS
+        let mut cdb = self.specific[12..28].to_vec();
+        let mut bidi_read_data_length = None;
+        for ahs in &self.ahs {
+            match ahs {
+                Ahs::ExtendedCdb(extra) => cdb.extend_from_slice(extra),
+                Ahs::BidiReadDataLength(length) => bidi_read_data_length = Some(*length),
+                Ahs::Other { .. } => {}
+            }
+        }
 
         Ok(ScsiCommandPdu {
             lun: self.lun,
             itt: self.itt,
             expected_data_length,
             cdb,
+            bidi_read_data_length,
             read,
             write,
             final_flag,
+            task_attribute: self.flags & task_attribute::MASK,
         })
     }
 
@@ -594,8 +762,8 @@ impl IscsiPdu {
             pdu.data_length = pdu.data.len() as u32;
         }
 
-        // Residual count at bytes 40-43 (specific[20..24])
-        pdu.specific[20..24].copy_from_slice(&residual_count.to_be_bytes());
+        // Residual count at bytes 44-47 (specific[24..28])
+        pdu.specific[24..28].copy_from_slice(&residual_count.to_be_bytes());
 
         pdu
     }
@@ -622,7 +790,7 @@ impl IscsiPdu {
             flags_byte |= flags::FINAL;
         }
         if status.is_some() {
-            flags_byte |= 0x01; // S bit - status included
+            flags_byte |= flags::STATUS; // S bit - status included
         }
         pdu.flags = flags_byte;
 
@@ -640,13 +808,11 @@ impl IscsiPdu {
         pdu.specific[16..20].copy_from_slice(&data_sn.to_be_bytes());
         // Buffer Offset
         pdu.specific[20..24].copy_from_slice(&buffer_offset.to_be_bytes());
-        // Residual count (for underflow/overflow)
-        // pdu.specific[24..28] - residual count if needed
+        // Residual count (bytes 44-47) is filled in by set_residual()
 
         if let Some(s) = status {
             // Status byte goes in BHS byte 3 (not part of specific array)
-            // We store it in specific[27] temporarily and to_bytes() will move it
-            pdu.specific[27] = s;
+            pdu.version_or_reserved = s as u16;
         }
 
         pdu.data = data;
@@ -655,6 +821,50 @@ impl IscsiPdu {
         pdu
     }
 
+    /// Set the U/O flags and residual count of a SCSI Response or status-bearing
+    /// Data-In PDU from the initiator's ExpectedDataTransferLength and the
+    /// number of bytes the command actually produced
+    pub fn set_residual(&mut self, expected_length: u32, transfer_length: u32) {
+        self.flags &= !(flags::RESIDUAL_OVERFLOW | flags::RESIDUAL_UNDERFLOW);
+        let residual = if transfer_length > expected_length {
+            self.flags |= flags::RESIDUAL_OVERFLOW;
+            transfer_length - expected_length
+        } else {
+            if transfer_length < expected_length {
+                self.flags |= flags::RESIDUAL_UNDERFLOW;
+            }
+            expected_length - transfer_length
+        };
+        self.specific[24..28].copy_from_slice(&residual.to_be_bytes());
+    }
+
+    /// Residual count of a SCSI Response or Data-In PDU
+    pub fn residual_count(&self) -> u32 {
+        BigEndian::read_u32(&self.specific[24..28])
+    }
+
+    /// Set the o/u flags and Bidirectional Read Residual Count of a SCSI
+    /// Response from the Expected Bidirectional Read Data Length and the
+    /// number of bytes of Data-In the command produced
+    pub fn set_bidi_read_residual(&mut self, expected_length: u32, transfer_length: u32) {
+        self.flags &= !(flags::BIDI_READ_RESIDUAL_OVERFLOW | flags::BIDI_READ_RESIDUAL_UNDERFLOW);
+        let residual = if transfer_length > expected_length {
+            self.flags |= flags::BIDI_READ_RESIDUAL_OVERFLOW;
+            transfer_length - expected_length
+        } else {
+            if transfer_length < expected_length {
+                self.flags |= flags::BIDI_READ_RESIDUAL_UNDERFLOW;
+            }
+            expected_length - transfer_length
+        };
+        self.specific[20..24].copy_from_slice(&residual.to_be_bytes());
+    }
+
+    /// Bidirectional Read Residual Count of a SCSI Response
+    pub fn bidi_read_residual_count(&self) -> u32 {
+        BigEndian::read_u32(&self.specific[20..24])
+    }
+
     /// Parse SCSI Data-Out PDU (data from initiator to target)
     pub fn parse_scsi_data_out(&self) -> ScsiResult<ScsiDataOutPdu> {
         if self.opcode != opcode::SCSI_DATA_OUT {
@@ -689,10 +899,15 @@ pub struct ScsiCommandPdu {
     pub lun: u64,
     pub itt: u32,
     pub expected_data_length: u32,
-    pub cdb: [u8; 16],
+    /// The full CDB: 16 bytes from the BHS plus any Extended CDB AHS
+    pub cdb: Vec<u8>,
+    /// Expected Bidirectional Read Data Length, if the AHS carried one
+    pub bidi_read_data_length: Option<u32>,
     pub read: bool,
     pub write: bool,
     pub final_flag: bool,
+    /// Task attribute (see `task_attribute`)
+    pub task_attribute: u8,
 }
 
 /// Parsed SCSI Data-Out
@@ -880,8 +1095,8 @@ impl IscsiPdu {
         pdu.flags = flags::FINAL;
         pdu.itt = itt;
 
-        // Response code
-        pdu.specific[0] = response;
+        // Response (byte 2)
+        pdu.version_or_reserved = (response as u16) << 8;
         // StatSN
         pdu.specific[4..8].copy_from_slice(&stat_sn.to_be_bytes());
         // ExpCmdSN
@@ -895,6 +1110,11 @@ impl IscsiPdu {
 
         pdu
     }
+
+    /// Response code of a Logout Response
+    pub fn logout_response_code(&self) -> u8 {
+        (self.version_or_reserved >> 8) as u8
+    }
 }
 
 /// Parsed Logout Request
@@ -981,6 +1201,221 @@ pub struct TextRequest {
     pub parameters: Vec<(String, String)>,
 }
 
+// ============================================================================
+// SNACK Request PDU helpers
+// ============================================================================
+
+/// SNACK types (RFC 3720 Section 10.16.1)
+pub mod snack_type {
+    pub const DATA_R2T: u8 = 0;
+    pub const STATUS: u8 = 1;
+    pub const DATA_ACK: u8 = 2;
+    pub const RDATA: u8 = 3;
+}
+
+impl IscsiPdu {
+    /// Parse SNACK Request
+    pub fn parse_snack_request(&self) -> ScsiResult<SnackRequest> {
+        if self.opcode != opcode::SNACK_REQUEST {
+            return Err(IscsiError::InvalidPdu(format!(
+                "Expected SNACK Request opcode 0x10, got 0x{:02x}",
+                self.opcode
+            )));
+        }
+
+        Ok(SnackRequest {
+            itt: self.itt,
+            snack_type: self.flags & 0x0F,
+            lun: self.lun,
+            ttt: BigEndian::read_u32(&self.specific[0..4]),
+            exp_stat_sn: BigEndian::read_u32(&self.specific[8..12]),
+            beg_run: BigEndian::read_u32(&self.specific[20..24]),
+            run_length: BigEndian::read_u32(&self.specific[24..28]),
+        })
+    }
+}
+
+/// Parsed SNACK Request
+#[derive(Debug, Clone)]
+pub struct SnackRequest {
+    pub itt: u32,
+    pub snack_type: u8,
+    pub lun: u64,
+    /// Target Transfer Tag (DataACK and R-Data SNACKs)
+    pub ttt: u32,
+    pub exp_stat_sn: u32,
+    /// First DataSN, R2TSN or StatSN requested
+    pub beg_run: u32,
+    /// Number of PDUs requested (0 = all from BegRun on)
+    pub run_length: u32,
+}
+
+// ============================================================================
+// Reject PDU helpers
+// ============================================================================
+
+/// Reject reason codes (RFC 3720 Section 10.17.1)
+pub mod reject_reason {
+    pub const DATA_DIGEST_ERROR: u8 = 0x02;
+    pub const SNACK_REJECT: u8 = 0x03;
+    pub const PROTOCOL_ERROR: u8 = 0x04;
+    pub const COMMAND_NOT_SUPPORTED: u8 = 0x05;
+    pub const IMMEDIATE_COMMAND_REJECT: u8 = 0x06;
+    pub const TASK_IN_PROGRESS: u8 = 0x07;
+    pub const INVALID_DATA_ACK: u8 = 0x08;
+    pub const INVALID_PDU_FIELD: u8 = 0x09;
+    pub const LONG_OPERATION_REJECT: u8 = 0x0a;
+    pub const NEGOTIATION_RESET: u8 = 0x0b;
+    pub const WAITING_FOR_LOGOUT: u8 = 0x0c;
+}
+
+/// Async Message event codes (RFC 3720 Section 10.9.1)
+pub mod async_event {
+    pub const SCSI_ASYNC_EVENT: u8 = 0x00;
+    pub const LOGOUT_REQUEST: u8 = 0x01;
+    pub const CONNECTION_DROP: u8 = 0x02;
+    pub const SESSION_DROP: u8 = 0x03;
+    pub const NEGOTIATION_REQUEST: u8 = 0x04;
+}
+
+impl IscsiPdu {
+    /// Create a Reject PDU
+    ///
+    /// `rejected_header` is the 48-byte BHS of the PDU being rejected; it is
+    /// returned to the initiator as the data segment.
+    pub fn reject(
+        reason: u8,
+        stat_sn: u32,
+        exp_cmd_sn: u32,
+        max_cmd_sn: u32,
+        rejected_header: &[u8],
+    ) -> Self {
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::REJECT;
+        pdu.flags = flags::FINAL;
+        // Reason (byte 2)
+        pdu.version_or_reserved = (reason as u16) << 8;
+        // ITT is reserved and must be 0xFFFFFFFF
+        pdu.itt = 0xFFFF_FFFF;
+
+        // StatSN
+        pdu.specific[4..8].copy_from_slice(&stat_sn.to_be_bytes());
+        // ExpCmdSN
+        pdu.specific[8..12].copy_from_slice(&exp_cmd_sn.to_be_bytes());
+        // MaxCmdSN
+        pdu.specific[12..16].copy_from_slice(&max_cmd_sn.to_be_bytes());
+
+        pdu.data = rejected_header[..rejected_header.len().min(BHS_SIZE)].to_vec();
+        pdu.data_length = pdu.data.len() as u32;
+
+        pdu
+    }
+
+    /// Create an Async Message PDU asking the initiator to log out
+    ///
+    /// `logout_within` (Parameter3) is how many seconds the initiator has to
+    /// log out before the target drops the connection.
+    pub fn async_logout_request(
+        logout_within: u16,
+        stat_sn: u32,
+        exp_cmd_sn: u32,
+        max_cmd_sn: u32,
+    ) -> Self {
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::ASYNC_MESSAGE;
+        pdu.flags = flags::FINAL;
+        // ITT is reserved and must be 0xFFFFFFFF
+        pdu.itt = 0xFFFF_FFFF;
+
+        // StatSN
+        pdu.specific[4..8].copy_from_slice(&stat_sn.to_be_bytes());
+        // ExpCmdSN
+        pdu.specific[8..12].copy_from_slice(&exp_cmd_sn.to_be_bytes());
+        // MaxCmdSN
+        pdu.specific[12..16].copy_from_slice(&max_cmd_sn.to_be_bytes());
+        // AsyncEvent (byte 36)
+        pdu.specific[16] = async_event::LOGOUT_REQUEST;
+        // Parameter3 (bytes 42-43)
+        pdu.specific[22..24].copy_from_slice(&logout_within.to_be_bytes());
+
+        pdu
+    }
+
+    /// Reject reason code (Reject PDUs only)
+    pub fn reject_reason(&self) -> u8 {
+        (self.version_or_reserved >> 8) as u8
+    }
+}
+
+// ============================================================================
+// Header and data digests (RFC 3720 Section 12.1)
+// ============================================================================
+
+/// Size of a header or data digest on the wire
+pub const DIGEST_SIZE: usize = 4;
+
+/// Digests in effect on a connection
+///
+/// Negotiated during login and applied from the first PDU after the final
+/// Login Response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Digests {
+    /// CRC32C after the BHS and AHS
+    pub header: bool,
+    /// CRC32C after each non-empty data segment (including padding)
+    pub data: bool,
+}
+
+impl Digests {
+    /// No digests, as used during login
+    pub const NONE: Digests = Digests { header: false, data: false };
+}
+
+/// Lookup table for the reflected Castagnoli polynomial 0x82F63B78
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32C (Castagnoli) checksum used for iSCSI digests
+///
+/// Digests are sent least significant byte first (`to_le_bytes`).
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+impl IscsiPdu {
+    /// Serialize PDU to bytes, inserting the given digests
+    pub fn to_bytes_with_digests(&self, digests: Digests) -> Vec<u8> {
+        let mut bytes = self.to_bytes();
+        let header_len = BHS_SIZE + self.ahs_length as usize * 4;
+
+        if digests.data && !self.data.is_empty() {
+            let digest = crc32c(&bytes[header_len..]);
+            bytes.extend_from_slice(&digest.to_le_bytes());
+        }
+        if digests.header {
+            let digest = crc32c(&bytes[..header_len]);
+            bytes.splice(header_len..header_len, digest.to_le_bytes());
+        }
+
+        bytes
+    }
+}
+
 // ============================================================================
 // Utility functions
 // ============================================================================
@@ -1083,6 +1518,117 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_parse_enforces_limits() {
+        let limits = PduLimits { max_ahs_length: 8, max_data_segment_length: 1024, ..PduLimits::default() };
+
+        let mut bhs = [0u8; BHS_SIZE];
+        bhs[5..8].copy_from_slice(&[0x00, 0x04, 0x01]);
+        assert_eq!(
+            IscsiPdu::parse(&bhs, &limits).unwrap_err(),
+            PduError::DataSegmentTooLong { length: 1025, limit: 1024 }
+        );
+
+        let mut bhs = [0u8; BHS_SIZE];
+        bhs[4] = 3;
+        assert_eq!(
+            IscsiPdu::parse(&bhs, &limits).unwrap_err(),
+            PduError::AhsTooLong { length: 12, limit: 8 }
+        );
+
+        // Lengths within limits but beyond the buffer
+        let mut bhs = [0u8; BHS_SIZE];
+        bhs[7] = 5;
+        assert_eq!(
+            IscsiPdu::parse(&bhs, &limits).unwrap_err(),
+            PduError::Incomplete { length: 48, needed: 56 }
+        );
+        assert_eq!(IscsiPdu::parse(&bhs[..20], &limits).unwrap_err(), PduError::TooShort { length: 20 });
+
+        // Structured errors surface through from_bytes too
+        assert!(matches!(
+            IscsiPdu::from_bytes(&bhs),
+            Err(IscsiError::MalformedPdu(PduError::Incomplete { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_parse_ahs() {
+        // 32-byte CDB: 16 bytes in the BHS, 16 in an Extended CDB AHS
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::SCSI_COMMAND;
+        pdu.specific[12] = 0x7F;
+        pdu.ahs = vec![
+            Ahs::ExtendedCdb(vec![0xAB; 16]),
+            Ahs::BidiReadDataLength(4096),
+            Ahs::Other { ahs_type: 60, data: vec![1, 2, 3, 4, 5] },
+        ];
+        pdu.data = vec![1, 2, 3];
+        pdu.data_length = 3;
+
+        let bytes = pdu.to_bytes();
+        // 20 + 8 + 8 bytes of AHS
+        assert_eq!(bytes[4], 9);
+        assert_eq!(&bytes[BHS_SIZE..BHS_SIZE + 4], &[0x00, 17, ahs_type::EXTENDED_CDB, 0]);
+        assert_eq!(bytes.len(), BHS_SIZE + 36 + 4);
+        assert_eq!(pdu.total_length(), bytes.len());
+        let parsed = IscsiPdu::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.ahs_length, 9);
+        assert_eq!(parsed.ahs, pdu.ahs);
+        assert_eq!(parsed.data, vec![1, 2, 3]);
+
+        let cmd = parsed.parse_scsi_command().unwrap();
+        assert_eq!(cmd.cdb.len(), 32);
+        assert_eq!(cmd.cdb[0], 0x7F);
+        assert_eq!(&cmd.cdb[16..], &[0xAB; 16]);
+        assert_eq!(cmd.bidi_read_data_length, Some(4096));
+
+        let limits = PduLimits { max_extended_cdb_length: 8, ..PduLimits::default() };
+        assert_eq!(
+            IscsiPdu::parse(&bytes, &limits).unwrap_err(),
+            PduError::ExtendedCdbTooLong { length: 16, limit: 8 }
+        );
+
+        // AHSLength running past TotalAHSLength
+        let mut bad = bytes.clone();
+        bad[BHS_SIZE + 1] = 40;
+        assert!(matches!(
+            IscsiPdu::from_bytes(&bad),
+            Err(IscsiError::MalformedPdu(PduError::MalformedAhs { offset: 0, .. }))
+        ));
+
+        // Bidirectional read length AHS has a fixed size
+        let mut bad = bytes;
+        bad[BHS_SIZE + 1] = 4;
+        bad[BHS_SIZE + 2] = ahs_type::BIDI_READ_DATA_LENGTH;
+        assert!(matches!(
+            IscsiPdu::from_bytes(&bad),
+            Err(IscsiError::MalformedPdu(PduError::MalformedAhs { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_parse_arbitrary_input() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x15C5);
+        let limits = PduLimits { max_data_segment_length: 4096, ..PduLimits::default() };
+
+        for _ in 0..2000 {
+            let len = rng.gen_range(0..600);
+            let mut buf: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            if buf.len() >= BHS_SIZE && rng.gen_bool(0.5) {
+                // Plausible lengths make it past the header checks more often
+                buf[4] = rng.gen_range(0..8);
+                buf[5] = 0;
+                buf[6] = 0;
+            }
+            if let Ok(pdu) = IscsiPdu::parse(&buf, &limits) {
+                assert!(pdu.total_length() <= buf.len());
+                assert_eq!(pdu.data.len(), pdu.data_length as usize);
+            }
+        }
+    }
+
     #[test]
     fn test_parse_text_parameters() {
         let data = b"Key1=Value1\0Key2=Value2\0";
@@ -1123,6 +1669,41 @@ mod tests {
         assert_eq!(pdu.opcode, opcode::LOGIN_RESPONSE);
         assert_eq!(pdu.flags & flags::TRANSIT, flags::TRANSIT);
         assert_eq!(pdu.itt, 0x1234);
+
+        // ISID and TSIH survive serialization
+        let parsed = IscsiPdu::from_bytes(&pdu.to_bytes()).unwrap();
+        assert_eq!(parsed.lun.to_be_bytes(), [0x00, 0x02, 0x3D, 0x00, 0x00, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_parse_snack_request() {
+        let mut bytes = [0u8; BHS_SIZE];
+        bytes[0] = opcode::SNACK_REQUEST;
+        bytes[1] = flags::FINAL | snack_type::STATUS;
+        bytes[16..20].copy_from_slice(&0xFFFF_FFFFu32.to_be_bytes());
+        bytes[28..32].copy_from_slice(&9u32.to_be_bytes());
+        bytes[40..44].copy_from_slice(&7u32.to_be_bytes());
+        bytes[44..48].copy_from_slice(&2u32.to_be_bytes());
+
+        let snack = IscsiPdu::from_bytes(&bytes).unwrap().parse_snack_request().unwrap();
+        assert_eq!(snack.snack_type, snack_type::STATUS);
+        assert_eq!(snack.itt, 0xFFFF_FFFF);
+        assert_eq!((snack.exp_stat_sn, snack.beg_run, snack.run_length), (9, 7, 2));
+
+        assert!(IscsiPdu::new().parse_snack_request().is_err());
+    }
+
+    #[test]
+    fn test_async_logout_request() {
+        let pdu = IscsiPdu::async_logout_request(10, 5, 6, 37);
+        let bytes = pdu.to_bytes();
+
+        assert_eq!(bytes[0], opcode::ASYNC_MESSAGE);
+        assert_eq!(&bytes[16..20], &[0xFF; 4]);
+        assert_eq!(BigEndian::read_u32(&bytes[24..28]), 5);
+        assert_eq!(BigEndian::read_u32(&bytes[32..36]), 37);
+        assert_eq!(bytes[36], async_event::LOGOUT_REQUEST);
+        assert_eq!(BigEndian::read_u16(&bytes[42..44]), 10);
     }
 
     #[test]
@@ -1165,6 +1746,49 @@ mod tests {
         assert_eq!(pdu.data, data);
     }
 
+    #[test]
+    fn test_residual_flags() {
+        let mut pdu = IscsiPdu::scsi_data_in(
+            0x1234, 0xFFFF_FFFF, 1, 1, 1, 0, 0, vec![0; 36], true, Some(scsi_status::GOOD),
+        );
+
+        // INQUIRY returning 36 bytes of a 255-byte allocation
+        pdu.set_residual(255, 36);
+        assert_eq!(pdu.flags & flags::RESIDUAL_UNDERFLOW, flags::RESIDUAL_UNDERFLOW);
+        assert_eq!(pdu.residual_count(), 219);
+
+        pdu.set_residual(16, 36);
+        assert_eq!(pdu.flags & (flags::RESIDUAL_OVERFLOW | flags::RESIDUAL_UNDERFLOW), flags::RESIDUAL_OVERFLOW);
+        assert_eq!(pdu.residual_count(), 20);
+
+        // Status and residual survive the wire in separate fields
+        let parsed = IscsiPdu::from_bytes(&pdu.to_bytes()).unwrap();
+        assert_eq!(parsed.flags & flags::STATUS, flags::STATUS);
+        assert_eq!(parsed.version_or_reserved as u8, scsi_status::GOOD);
+        assert_eq!(parsed.residual_count(), 20);
+
+        pdu.set_residual(36, 36);
+        assert_eq!(pdu.flags & (flags::RESIDUAL_OVERFLOW | flags::RESIDUAL_UNDERFLOW), 0);
+        assert_eq!(pdu.residual_count(), 0);
+    }
+
+    #[test]
+    fn test_bidi_read_residual() {
+        let mut pdu = IscsiPdu::scsi_response(0x1234, 1, 1, 1, scsi_status::GOOD, 0, 0, None);
+        pdu.set_residual(512, 512);
+        pdu.set_bidi_read_residual(1024, 512);
+
+        // Separate flags and count from the write residual, intact on the wire
+        let parsed = IscsiPdu::from_bytes(&pdu.to_bytes()).unwrap();
+        assert_eq!(parsed.flags & 0x1E, flags::BIDI_READ_RESIDUAL_UNDERFLOW);
+        assert_eq!(parsed.bidi_read_residual_count(), 512);
+        assert_eq!(parsed.residual_count(), 0);
+
+        pdu.set_bidi_read_residual(256, 512);
+        assert_eq!(pdu.flags & 0x18, flags::BIDI_READ_RESIDUAL_OVERFLOW);
+        assert_eq!(pdu.bidi_read_residual_count(), 256);
+    }
+
     #[test]
     fn test_nop_in_creation() {
         let pdu = IscsiPdu::nop_in(
@@ -1188,13 +1812,20 @@ mod tests {
             1,       // stat_sn
             1,       // exp_cmd_sn
             1,       // max_cmd_sn
-            logout_response::SUCCESS,
+            logout_response::CID_NOT_FOUND,
             2,       // time2wait
             20,      // time2retain
         );
 
         assert_eq!(pdu.opcode, opcode::LOGOUT_RESPONSE);
-        assert_eq!(pdu.specific[0], logout_response::SUCCESS);
+        assert_eq!(pdu.logout_response_code(), logout_response::CID_NOT_FOUND);
+
+        // The response code travels in BHS byte 2
+        let bytes = pdu.to_bytes();
+        assert_eq!(bytes[2], logout_response::CID_NOT_FOUND);
+        assert_eq!(&bytes[40..44], &[0, 2, 0, 20]);
+        let parsed = IscsiPdu::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.logout_response_code(), logout_response::CID_NOT_FOUND);
     }
 
     #[test]
@@ -1227,6 +1858,35 @@ mod tests {
         assert!(parsed.immediate);
     }
 
+    #[test]
+    fn test_crc32c_vectors() {
+        // RFC 3720 Appendix B.4
+        assert_eq!(crc32c(&[0u8; 32]), 0x8A91_36AA);
+        assert_eq!(crc32c(&[0xFFu8; 32]), 0x62A8_AB43);
+        let ascending: Vec<u8> = (0..32).collect();
+        assert_eq!(crc32c(&ascending), 0x46DD_794E);
+    }
+
+    #[test]
+    fn test_to_bytes_with_digests() {
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::NOP_OUT;
+        pdu.data = vec![1, 2, 3];
+
+        let plain = pdu.to_bytes();
+        assert_eq!(pdu.to_bytes_with_digests(Digests::NONE), plain);
+
+        let bytes = pdu.to_bytes_with_digests(Digests { header: true, data: true });
+        assert_eq!(bytes.len(), BHS_SIZE + DIGEST_SIZE + 4 + DIGEST_SIZE);
+        assert_eq!(bytes[BHS_SIZE..BHS_SIZE + 4], crc32c(&plain[..BHS_SIZE]).to_le_bytes());
+        assert_eq!(bytes[BHS_SIZE + 8..], crc32c(&plain[BHS_SIZE..]).to_le_bytes());
+
+        // No data digest without a data segment
+        pdu.data.clear();
+        let bytes = pdu.to_bytes_with_digests(Digests { header: false, data: true });
+        assert_eq!(bytes.len(), BHS_SIZE);
+    }
+
     #[test]
     fn test_data_padding() {
         let mut pdu = IscsiPdu::new();
@@ -1237,4 +1897,23 @@ mod tests {
         assert_eq!(bytes.len() % 4, 0);
         assert_eq!(bytes.len(), BHS_SIZE + 4); // BHS + 4 bytes (padded data)
     }
+
+    #[test]
+    fn test_reject_creation() {
+        let mut rejected = IscsiPdu::new();
+        rejected.opcode = opcode::SCSI_COMMAND;
+        rejected.itt = 0x42;
+        let header = rejected.to_bytes();
+
+        let pdu = IscsiPdu::reject(reject_reason::PROTOCOL_ERROR, 7, 8, 9, &header[..BHS_SIZE]);
+        let bytes = pdu.to_bytes();
+        assert_eq!(bytes[0] & 0x3F, opcode::REJECT);
+        assert_eq!(bytes[2], reject_reason::PROTOCOL_ERROR);
+
+        let parsed = IscsiPdu::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.reject_reason(), reject_reason::PROTOCOL_ERROR);
+        assert_eq!(parsed.itt, 0xFFFF_FFFF);
+        assert_eq!(parsed.data_length, BHS_SIZE as u32);
+        assert_eq!(&parsed.data[16..20], &0x42u32.to_be_bytes());
+    }
 }
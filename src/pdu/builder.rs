@@ -0,0 +1,204 @@
+//! Construction of intentionally invalid PDUs for negative testing
+//!
+//! `IscsiPdu::to_bytes` always produces a well-formed PDU: it derives the
+//! length fields from the data and zeroes reserved fields. `PduBuilder`
+//! starts from such a PDU and then lets a test break it: claim a wrong
+//! DataSegmentLength, set reserved login stages, repeat or contradict text
+//! keys, or overwrite any byte of the encoding. Send the result with
+//! `IscsiClient::send_malformed`.
+//!
+//! ```
+//! use iscsi_target::pdu::builder::PduBuilder;
+//!
+//! // A Login Request claiming more data than it carries
+//! let bytes = PduBuilder::login([0x80, 0, 0, 0, 0, 1], 0, 1, true)
+//!     .key("InitiatorName", "iqn.2025-12.local:initiator")
+//!     .data_length(0x00FF_FFFF)
+//!     .to_bytes();
+//! assert_eq!(&bytes[5..8], &[0xFF, 0xFF, 0xFF]);
+//! ```
+
+use super::{flags, opcode, IscsiPdu, BHS_SIZE};
+
+/// Builds a PDU whose encoding may violate RFC 7143
+#[derive(Debug, Clone)]
+pub struct PduBuilder {
+    pdu: IscsiPdu,
+    /// Text keys in the data segment, in order; duplicates are kept
+    keys: Vec<(String, String)>,
+    data_length: Option<u32>,
+    ahs_length: Option<u8>,
+    /// Byte overwrites applied to the final encoding, in order
+    patches: Vec<(usize, Vec<u8>)>,
+}
+
+impl PduBuilder {
+    /// Start from `pdu`, whatever it contains
+    pub fn from_pdu(pdu: IscsiPdu) -> Self {
+        PduBuilder { pdu, keys: Vec::new(), data_length: None, ahs_length: None, patches: Vec::new() }
+    }
+
+    /// Start from an empty PDU with `opcode`
+    pub fn new(opcode: u8) -> Self {
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode;
+        Self::from_pdu(pdu)
+    }
+
+    /// A Login Request with raw stage numbers, so reserved stage 2 or a
+    /// transition to an earlier stage can be sent
+    ///
+    /// `csg` and `nsg` are stage numbers (0 = security, 1 = operational,
+    /// 3 = full feature), not the flag constants.
+    pub fn login(isid: [u8; 6], csg: u8, nsg: u8, transit: bool) -> Self {
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::LOGIN_REQUEST;
+        pdu.immediate = true;
+        pdu.flags = (if transit { flags::TRANSIT } else { 0 }) | ((csg & 0x03) << 2) | (nsg & 0x03);
+        let mut isid_tsih = [0u8; 8];
+        isid_tsih[0..6].copy_from_slice(&isid);
+        pdu.lun = u64::from_be_bytes(isid_tsih);
+        Self::from_pdu(pdu)
+    }
+
+    /// Set the Immediate bit
+    pub fn immediate(mut self, immediate: bool) -> Self {
+        self.pdu.immediate = immediate;
+        self
+    }
+
+    /// Replace the whole flags byte
+    pub fn flags(mut self, flags: u8) -> Self {
+        self.pdu.flags = flags;
+        self
+    }
+
+    pub fn itt(mut self, itt: u32) -> Self {
+        self.pdu.itt = itt;
+        self
+    }
+
+    /// Set bytes 8-15 (LUN, or ISID and TSIH for login)
+    pub fn lun(mut self, lun: u64) -> Self {
+        self.pdu.lun = lun;
+        self
+    }
+
+    /// Set CmdSN (bytes 24-27)
+    pub fn cmd_sn(mut self, cmd_sn: u32) -> Self {
+        self.pdu.specific[4..8].copy_from_slice(&cmd_sn.to_be_bytes());
+        self
+    }
+
+    /// Set ExpStatSN (bytes 28-31)
+    pub fn exp_stat_sn(mut self, exp_stat_sn: u32) -> Self {
+        self.pdu.specific[8..12].copy_from_slice(&exp_stat_sn.to_be_bytes());
+        self
+    }
+
+    /// Append a text key; repeating a key, even with another value, is allowed
+    pub fn key(mut self, key: &str, value: &str) -> Self {
+        self.keys.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Append a key twice with different values
+    pub fn contradicting_key(self, key: &str, first: &str, second: &str) -> Self {
+        self.key(key, first).key(key, second)
+    }
+
+    /// Declare MaxRecvDataSegmentLength=0, below the RFC 7143 minimum of 512
+    pub fn zero_max_recv_data_segment_length(self) -> Self {
+        self.key("MaxRecvDataSegmentLength", "0")
+    }
+
+    /// Raw data segment, sent after any text keys
+    pub fn data(mut self, data: &[u8]) -> Self {
+        self.pdu.data = data.to_vec();
+        self
+    }
+
+    /// Claim `length` in DataSegmentLength (bytes 5-7), whatever the data is
+    ///
+    /// Only the low 24 bits fit in the field.
+    pub fn data_length(mut self, length: u32) -> Self {
+        self.data_length = Some(length);
+        self
+    }
+
+    /// Claim `length` 4-byte words in TotalAHSLength (byte 4)
+    pub fn ahs_length(mut self, length: u8) -> Self {
+        self.ahs_length = Some(length);
+        self
+    }
+
+    /// Overwrite the encoding from byte `offset`, growing it if needed
+    pub fn patch(mut self, offset: usize, bytes: &[u8]) -> Self {
+        self.patches.push((offset, bytes.to_vec()));
+        self
+    }
+
+    /// The PDU before length overrides and patches are applied
+    pub fn pdu(&self) -> IscsiPdu {
+        let mut pdu = self.pdu.clone();
+        if !self.keys.is_empty() {
+            let mut data = super::serialize_text_parameters(&self.keys);
+            data.extend_from_slice(&self.pdu.data);
+            pdu.data = data;
+        }
+        pdu.data_length = pdu.data.len() as u32;
+        pdu
+    }
+
+    /// Encode the PDU, applying the overrides; digests are never added
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.pdu().to_bytes();
+        if let Some(length) = self.ahs_length {
+            bytes[4] = length;
+        }
+        if let Some(length) = self.data_length {
+            bytes[5..8].copy_from_slice(&length.to_be_bytes()[1..4]);
+        }
+        for (offset, patch) in &self.patches {
+            let end = offset + patch.len();
+            if bytes.len() < end {
+                bytes.resize(end, 0);
+            }
+            bytes[*offset..end].copy_from_slice(patch);
+        }
+        debug_assert!(bytes.len() >= BHS_SIZE);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdu::{parse_text_parameters, PduLimits};
+
+    #[test]
+    fn test_builder_overrides() {
+        let builder = PduBuilder::login([0x80, 1, 2, 3, 4, 5], 2, 0, true)
+            .contradicting_key("HeaderDigest", "None", "CRC32C")
+            .zero_max_recv_data_segment_length()
+            .cmd_sn(7);
+        let pdu = IscsiPdu::from_bytes(&builder.to_bytes()).unwrap();
+        let request = pdu.parse_login_request().unwrap();
+        assert_eq!((request.csg, request.nsg, request.transit), (2, 0, true));
+        assert_eq!(request.isid, [0x80, 1, 2, 3, 4, 5]);
+        assert_eq!(request.cmd_sn, 7);
+        assert_eq!(parse_text_parameters(&pdu.data).unwrap(), [
+            ("HeaderDigest".to_string(), "None".to_string()),
+            ("HeaderDigest".to_string(), "CRC32C".to_string()),
+            ("MaxRecvDataSegmentLength".to_string(), "0".to_string()),
+        ]);
+
+        // A DataSegmentLength beyond the data leaves the PDU incomplete
+        let bytes = builder.clone().data_length(4096).to_bytes();
+        assert_eq!(&bytes[5..8], &[0, 0x10, 0]);
+        assert!(IscsiPdu::parse(&bytes, &PduLimits::default()).is_err());
+
+        let bytes = PduBuilder::new(opcode::NOP_OUT).ahs_length(3).patch(2, &[0xAB]).patch(48, &[1, 2]).to_bytes();
+        assert_eq!((bytes[2], bytes[4], bytes.len()), (0xAB, 3, 50));
+    }
+}
@@ -0,0 +1,543 @@
+//! Portal addressing for discovery
+//!
+//! Controls which network portals are advertised in SendTargets responses and
+//! in what order, so initiators pick the intended data path.
+
+use crate::error::{IscsiError, ScsiResult};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// An IP network in CIDR notation (e.g. `10.0.0.0/8`, `fd00::/8`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Create a network from an address and prefix length
+    pub fn new(addr: IpAddr, prefix_len: u8) -> ScsiResult<Self> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(IscsiError::Config(format!(
+                "Invalid prefix length /{} for {}", prefix_len, addr
+            )));
+        }
+        Ok(IpNetwork { addr, prefix_len })
+    }
+
+    /// Network address
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Prefix length in bits
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Check whether an address falls inside this network
+    ///
+    /// IPv4-mapped IPv6 addresses are matched against IPv4 networks.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, unmap_ipv4(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = IscsiError;
+
+    /// Parse `addr/len`, or a bare address as a single-host network
+    fn from_str(s: &str) -> ScsiResult<Self> {
+        let (addr_str, len_str) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr_str.trim().parse().map_err(|_| {
+            IscsiError::Config(format!("Invalid network address: {}", s))
+        })?;
+        let prefix_len = match len_str {
+            Some(len) => len.trim().parse().map_err(|_| {
+                IscsiError::Config(format!("Invalid prefix length: {}", s))
+            })?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+
+        IpNetwork::new(addr, prefix_len)
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Serialized in CIDR notation, the form `FromStr` accepts
+#[cfg(feature = "serde")]
+impl serde::Serialize for IpNetwork {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for IpNetwork {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let rem_bits = prefix_len % 8;
+
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rem_bits == 0 {
+        return true;
+    }
+    let mask = 0xFFu8 << (8 - rem_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+/// Convert an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to plain IPv4
+///
+/// Dual-stack sockets report IPv4 peers this way; advertising the mapped
+/// form would confuse IPv4-only initiators.
+pub fn unmap_ipv4(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        v4 => v4,
+    }
+}
+
+/// Default Target Portal Group Tag
+pub const DEFAULT_TPGT: u16 = 1;
+
+/// A network portal the target listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Portal {
+    /// Address to bind (e.g. "0.0.0.0:3260")
+    pub bind_addr: String,
+    /// Target Portal Group Tag advertised for this portal
+    pub tpgt: u16,
+    /// Only discovery sessions are accepted; never listed in SendTargets
+    pub discovery_only: bool,
+    /// Connection limit for this portal (None = only the target-wide limit applies)
+    pub max_connections: Option<u32>,
+    /// Session limit for this portal (None = only the target-wide limit applies)
+    pub max_sessions: Option<u32>,
+    /// Addresses advertised in SendTargets instead of this portal's own
+    /// (host, host:port, IP or [IPv6]:port), e.g. the public side of a NAT
+    pub advertise_addrs: Vec<String>,
+}
+
+impl Portal {
+    /// Create a portal that accepts normal and discovery sessions
+    pub fn new(bind_addr: &str, tpgt: u16) -> Self {
+        Portal {
+            bind_addr: bind_addr.to_string(),
+            tpgt,
+            discovery_only: false,
+            max_connections: None,
+            max_sessions: None,
+            advertise_addrs: Vec::new(),
+        }
+    }
+
+    /// Create a portal that only accepts discovery sessions
+    pub fn discovery(bind_addr: &str, tpgt: u16) -> Self {
+        Portal {
+            discovery_only: true,
+            ..Portal::new(bind_addr, tpgt)
+        }
+    }
+
+    /// Limit concurrent connections accepted on this portal
+    pub fn with_max_connections(mut self, max: u32) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Limit concurrent full-feature sessions established through this portal
+    pub fn with_max_sessions(mut self, max: u32) -> Self {
+        self.max_sessions = Some(max);
+        self
+    }
+
+    /// Advertise `addr` for this portal instead of its bind address
+    ///
+    /// May be called repeatedly, e.g. for an IPv4 and an IPv6 address of a
+    /// dual-stack host. A missing port defaults to the bind port.
+    pub fn with_advertise_addr(mut self, addr: &str) -> Self {
+        self.advertise_addrs.push(addr.to_string());
+        self
+    }
+
+    /// Port this portal listens on (the iSCSI port if the address has none)
+    pub fn port(&self) -> u16 {
+        self.bind_addr.rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or(3260)
+    }
+
+    /// Advertised addresses in TargetAddress form, or an error naming the bad one
+    pub fn advertised_addresses(&self) -> ScsiResult<Vec<String>> {
+        self.advertise_addrs.iter()
+            .map(|addr| normalize_target_address(addr, self.port()))
+            .collect()
+    }
+}
+
+/// Format a portal address for a TargetAddress key
+///
+/// IPv6 addresses are bracketed and their scope ID dropped, since it only
+/// has meaning on the target host.
+pub fn target_address(addr: SocketAddr) -> String {
+    match addr {
+        SocketAddr::V4(v4) => format!("{}:{}", v4.ip(), v4.port()),
+        SocketAddr::V6(v6) => format!("[{}]:{}", v6.ip(), v6.port()),
+    }
+}
+
+/// Normalize an address to advertise into `host:port` TargetAddress form
+///
+/// Accepts IP literals with or without a port (bare IPv6 included) and DNS
+/// names with an optional port; `default_port` fills in a missing port.
+pub fn normalize_target_address(addr: &str, default_port: u16) -> ScsiResult<String> {
+    let addr = addr.trim();
+    if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+        return Ok(target_address(socket_addr));
+    }
+    let unbracketed = addr.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')).unwrap_or(addr);
+    if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+        return Ok(target_address(SocketAddr::new(ip, default_port)));
+    }
+
+    let invalid = || IscsiError::Config(format!("Invalid advertised address: {}", addr));
+    let (host, port) = match addr.split_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| invalid())?),
+        None => (addr, default_port),
+    };
+    let valid_host = !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid_host || port == 0 {
+        return Err(invalid());
+    }
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Live counters for one portal
+#[derive(Debug, Default)]
+pub(crate) struct PortalStats {
+    pub(crate) active_connections: AtomicUsize,
+    pub(crate) active_sessions: AtomicUsize,
+    pub(crate) total_connections: AtomicU64,
+    pub(crate) rejected_connections: AtomicU64,
+    pub(crate) rejected_logins: AtomicU64,
+}
+
+impl PortalStats {
+    /// Take a point-in-time copy of the counters
+    pub(crate) fn snapshot(&self, portal: &Portal) -> PortalStatsSnapshot {
+        PortalStatsSnapshot {
+            bind_addr: portal.bind_addr.clone(),
+            tpgt: portal.tpgt,
+            active_connections: self.active_connections.load(Ordering::SeqCst),
+            active_sessions: self.active_sessions.load(Ordering::SeqCst),
+            total_connections: self.total_connections.load(Ordering::SeqCst),
+            rejected_connections: self.rejected_connections.load(Ordering::SeqCst),
+            rejected_logins: self.rejected_logins.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Per-portal load statistics (see `IscsiTarget::portal_stats`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortalStatsSnapshot {
+    /// Portal bind address
+    pub bind_addr: String,
+    /// Target Portal Group Tag
+    pub tpgt: u16,
+    /// Connections currently open
+    pub active_connections: usize,
+    /// Full-feature sessions currently established
+    pub active_sessions: usize,
+    /// Connections accepted since the target started (including rejected ones)
+    pub total_connections: u64,
+    /// Connections refused because a connection limit was reached
+    pub rejected_connections: u64,
+    /// Logins refused because a session limit was reached
+    pub rejected_logins: u64,
+}
+
+/// Order in which portals are listed in SendTargets responses
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PortalPreference {
+    /// Keep portals in the order they were configured
+    #[default]
+    BindOrder,
+    /// List IPv4 portals before IPv6 portals
+    Ipv4First,
+    /// List IPv6 portals before IPv4 portals
+    Ipv6First,
+    /// List portals in the given networks first, in network order
+    ///
+    /// Portals outside every network keep their relative order at the end.
+    Networks(Vec<IpNetwork>),
+}
+
+impl PortalPreference {
+    /// Sort key for a portal address (lower = listed earlier)
+    fn rank(&self, addr: &SocketAddr) -> usize {
+        let ip = unmap_ipv4(addr.ip());
+        match self {
+            PortalPreference::BindOrder => 0,
+            PortalPreference::Ipv4First => usize::from(!ip.is_ipv4()),
+            PortalPreference::Ipv6First => usize::from(!ip.is_ipv6()),
+            PortalPreference::Networks(networks) => networks.iter()
+                .position(|net| net.contains(ip))
+                .unwrap_or(networks.len()),
+        }
+    }
+}
+
+/// Discovery settings shared by all connections of a target
+#[derive(Debug, Clone, Default)]
+pub struct DiscoveryConfig {
+    /// Advertisable portal addresses and their TPGTs, in bind order
+    pub portals: Vec<(SocketAddr, u16)>,
+    /// Portal ordering for SendTargets
+    pub preference: PortalPreference,
+    /// Networks whose portals are never advertised (e.g. management-only)
+    pub excluded: Vec<IpNetwork>,
+    /// Configured advertise overrides in TargetAddress form and their TPGTs;
+    /// listed first, as configured, and not subject to exclusion or ordering
+    pub overrides: Vec<(String, u16)>,
+}
+
+impl DiscoveryConfig {
+    /// Build the advertisable portal list from the configured portals
+    ///
+    /// Discovery-only portals and addresses that are not IP literals are
+    /// skipped. Portals with advertise overrides contribute those instead of
+    /// their bind address; invalid overrides are skipped.
+    pub fn set_portals(&mut self, portals: &[Portal]) {
+        let advertised = portals.iter().filter(|portal| !portal.discovery_only);
+        self.portals = advertised.clone()
+            .filter(|portal| portal.advertise_addrs.is_empty())
+            .filter_map(|portal| portal.bind_addr.parse().ok().map(|addr| (addr, portal.tpgt)))
+            .collect();
+        self.overrides = Vec::new();
+        for portal in advertised {
+            for addr in &portal.advertise_addrs {
+                match normalize_target_address(addr, portal.port()) {
+                    Ok(addr) if !self.overrides.iter().any(|(known, _)| *known == addr) => {
+                        self.overrides.push((addr, portal.tpgt));
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("Not advertising portal {}: {}", portal.bind_addr, e),
+                }
+            }
+        }
+    }
+
+    /// Portals to advertise to an initiator connected via `local_addr` on `portal`
+    ///
+    /// The connection's own address is included so wildcard binds still
+    /// advertise a reachable portal, unless the portal has advertise overrides.
+    pub fn portals_for(&self, local_addr: SocketAddr, portal: &Portal) -> Vec<(SocketAddr, u16)> {
+        let mut candidates = self.portals.clone();
+        if !portal.discovery_only && portal.advertise_addrs.is_empty() {
+            candidates.push((local_addr, portal.tpgt));
+        }
+        self.advertised_portals(&candidates)
+    }
+
+    /// TargetAddress values (address, TPGT) for a connection via `local_addr` on `portal`
+    ///
+    /// Advertise overrides come first, then the portals from `portals_for`.
+    pub fn target_addresses(&self, local_addr: SocketAddr, portal: &Portal) -> Vec<(String, u16)> {
+        let mut addresses = self.overrides.clone();
+        for (addr, tpgt) in self.portals_for(local_addr, portal) {
+            let addr = target_address(addr);
+            if !addresses.iter().any(|(known, _)| *known == addr) {
+                addresses.push((addr, tpgt));
+            }
+        }
+        addresses
+    }
+
+    /// Filter and order candidate portals for a SendTargets response
+    ///
+    /// IPv4-mapped addresses are unmapped and duplicates removed. The sort is
+    /// stable, so portals with equal preference keep their configured order.
+    pub fn advertised_portals(&self, candidates: &[(SocketAddr, u16)]) -> Vec<(SocketAddr, u16)> {
+        let mut portals: Vec<(SocketAddr, u16)> = Vec::new();
+        for &(candidate, tpgt) in candidates {
+            let addr = SocketAddr::new(unmap_ipv4(candidate.ip()), candidate.port());
+            if addr.ip().is_unspecified() || portals.iter().any(|(known, _)| *known == addr) {
+                continue;
+            }
+            if self.excluded.iter().any(|net| net.contains(addr.ip())) {
+                log::debug!("Not advertising excluded portal {}", addr);
+                continue;
+            }
+            portals.push((addr, tpgt));
+        }
+
+        portals.sort_by_key(|(addr, _)| self.preference.rank(addr));
+        portals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<(SocketAddr, u16)> {
+        list.iter().map(|s| (s.parse().unwrap(), DEFAULT_TPGT)).collect()
+    }
+
+    #[test]
+    fn test_ip_network_parse_and_contains() {
+        let net: IpNetwork = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.9.9".parse().unwrap()));
+
+        let net: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(net.contains("fd12::1".parse().unwrap()));
+        assert!(!net.contains("fe80::1".parse().unwrap()));
+
+        let host: IpNetwork = "192.168.1.5".parse().unwrap();
+        assert_eq!(host.prefix_len(), 32);
+        assert!(host.contains("192.168.1.5".parse().unwrap()));
+        assert!(!host.contains("192.168.1.6".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("not-an-ip/8".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_portal_preference_ordering() {
+        let candidates = addrs(&["10.0.0.1:3260", "[fd00::1]:3260", "192.168.1.1:3260"]);
+
+        let config = DiscoveryConfig::default();
+        assert_eq!(config.advertised_portals(&candidates), candidates);
+
+        let config = DiscoveryConfig { preference: PortalPreference::Ipv6First, ..Default::default() };
+        assert_eq!(
+            config.advertised_portals(&candidates),
+            addrs(&["[fd00::1]:3260", "10.0.0.1:3260", "192.168.1.1:3260"])
+        );
+
+        let config = DiscoveryConfig {
+            preference: PortalPreference::Networks(vec!["192.168.0.0/16".parse().unwrap()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.advertised_portals(&candidates),
+            addrs(&["192.168.1.1:3260", "10.0.0.1:3260", "[fd00::1]:3260"])
+        );
+    }
+
+    #[test]
+    fn test_portal_exclusion_and_unmapping() {
+        let candidates = addrs(&["[::ffff:10.0.0.1]:3260", "10.0.0.1:3260", "172.16.0.1:3260"]);
+        let config = DiscoveryConfig {
+            excluded: vec!["172.16.0.0/12".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(config.advertised_portals(&candidates), addrs(&["10.0.0.1:3260"]));
+    }
+
+    #[test]
+    fn test_portals_for_wildcard_bind() {
+        let mut config = DiscoveryConfig::default();
+        let portal = Portal::new("0.0.0.0:3260", DEFAULT_TPGT);
+        config.set_portals(std::slice::from_ref(&portal));
+        assert_eq!(
+            config.portals_for("[::ffff:192.168.1.10]:3260".parse().unwrap(), &portal),
+            addrs(&["192.168.1.10:3260"])
+        );
+    }
+
+    #[test]
+    fn test_portals_for_multiple_portals() {
+        let data = Portal::new("10.0.0.1:3260", 1);
+        let replication = Portal::new("10.1.0.1:3261", 2);
+        let discovery = Portal::discovery("192.168.0.1:3260", 3);
+
+        let mut config = DiscoveryConfig::default();
+        config.set_portals(&[data.clone(), replication, discovery.clone()]);
+
+        let expected = vec![
+            ("10.0.0.1:3260".parse().unwrap(), 1),
+            ("10.1.0.1:3261".parse().unwrap(), 2),
+        ];
+        assert_eq!(config.portals_for("10.0.0.1:3260".parse().unwrap(), &data), expected);
+        assert_eq!(config.portals_for("192.168.0.1:3260".parse().unwrap(), &discovery), expected);
+    }
+
+    #[test]
+    fn test_target_address_formatting() {
+        assert_eq!(target_address("10.0.0.1:3260".parse().unwrap()), "10.0.0.1:3260");
+        assert_eq!(target_address("[fd00::1]:3260".parse().unwrap()), "[fd00::1]:3260");
+        assert_eq!(target_address("[fe80::1%2]:3261".parse().unwrap()), "[fe80::1]:3261");
+
+        assert_eq!(normalize_target_address("fd00::1", 3260).unwrap(), "[fd00::1]:3260");
+        assert_eq!(normalize_target_address("[fd00::1]", 3261).unwrap(), "[fd00::1]:3261");
+        assert_eq!(normalize_target_address("[fd00::1]:3262", 3260).unwrap(), "[fd00::1]:3262");
+        assert_eq!(normalize_target_address("203.0.113.7", 3260).unwrap(), "203.0.113.7:3260");
+        assert_eq!(normalize_target_address("san.example.com", 3260).unwrap(), "san.example.com:3260");
+        assert_eq!(normalize_target_address("san.example.com:13260", 3260).unwrap(), "san.example.com:13260");
+        for bad in ["", "san..example.com", "san.example.com:port", "host:0", "a b", "fd00::1::2"] {
+            assert!(normalize_target_address(bad, 3260).is_err(), "{:?} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn test_advertise_overrides() {
+        let natted = Portal::new("0.0.0.0:3260", 1)
+            .with_advertise_addr("203.0.113.7")
+            .with_advertise_addr("2001:db8::7");
+        let internal = Portal::new("[fd00::1]:3261", 2);
+
+        let mut config = DiscoveryConfig::default();
+        config.set_portals(&[natted.clone(), internal.clone()]);
+        assert_eq!(config.overrides, vec![
+            ("203.0.113.7:3260".to_string(), 1),
+            ("[2001:db8::7]:3260".to_string(), 1),
+        ]);
+
+        // The NAT-side local address is not leaked; other portals still follow
+        let expected = vec![
+            ("203.0.113.7:3260".to_string(), 1),
+            ("[2001:db8::7]:3260".to_string(), 1),
+            ("[fd00::1]:3261".to_string(), 2),
+        ];
+        assert_eq!(config.target_addresses("10.0.0.1:3260".parse().unwrap(), &natted), expected);
+        assert_eq!(config.target_addresses("[fd00::1]:3261".parse().unwrap(), &internal), expected);
+    }
+}
@@ -0,0 +1,352 @@
+//! T10 protection information (DIF)
+//!
+//! A unit formatted with protection keeps 8 bytes of protection information
+//! (PI) per logical block: a CRC guard over the block data, an application tag
+//! and a reference tag. The RDPROTECT/WRPROTECT field of a READ or WRITE picks
+//! whether the PI travels with the data (after each block) and which of its
+//! fields the target checks (SBC-3 4.22 and the per-command tables).
+
+use crate::cdb::Cdb;
+use crate::scsi::{asc, sense_key, SenseData};
+use byteorder::{BigEndian, ByteOrder};
+
+/// Bytes of protection information per logical block
+pub const PI_SIZE: usize = 8;
+
+/// Application tag that disables checking of a block's PI
+pub const APP_TAG_ESCAPE: u16 = 0xFFFF;
+
+/// Reference tag that, together with the escape application tag, disables
+/// checking of a Type 3 block's PI
+pub const REF_TAG_ESCAPE: u32 = 0xFFFF_FFFF;
+
+const CRC16_T10DIF_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8BB7 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-16 T10-DIF (polynomial 0x8BB7) used for the logical block guard
+pub fn crc16_t10dif(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (crc << 8) ^ CRC16_T10DIF_TABLE[((crc >> 8) as u8 ^ byte) as usize]
+    })
+}
+
+/// Protection information of one logical block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtectionInfo {
+    /// CRC-16 T10-DIF of the block data
+    pub guard: u16,
+    /// Application tag (owned by the application client)
+    pub app_tag: u16,
+    /// Reference tag (low 32 bits of the LBA for Type 1)
+    pub ref_tag: u32,
+}
+
+impl ProtectionInfo {
+    /// PI for `block` with the given tags
+    pub fn generate(block: &[u8], app_tag: u16, ref_tag: u32) -> Self {
+        ProtectionInfo {
+            guard: crc16_t10dif(block),
+            app_tag,
+            ref_tag,
+        }
+    }
+
+    /// Decode the 8 bytes following a block on the wire
+    pub fn from_bytes(bytes: &[u8; PI_SIZE]) -> Self {
+        ProtectionInfo {
+            guard: BigEndian::read_u16(&bytes[0..2]),
+            app_tag: BigEndian::read_u16(&bytes[2..4]),
+            ref_tag: BigEndian::read_u32(&bytes[4..8]),
+        }
+    }
+
+    /// Encode for the wire
+    pub fn to_bytes(&self) -> [u8; PI_SIZE] {
+        let mut bytes = [0u8; PI_SIZE];
+        BigEndian::write_u16(&mut bytes[0..2], self.guard);
+        BigEndian::write_u16(&mut bytes[2..4], self.app_tag);
+        BigEndian::write_u32(&mut bytes[4..8], self.ref_tag);
+        bytes
+    }
+}
+
+/// Split data with interleaved PI into the block data and its PI
+pub fn split(wire: &[u8], block_size: u32) -> (Vec<u8>, Vec<ProtectionInfo>) {
+    let block_size = block_size as usize;
+    let blocks = wire.len() / (block_size + PI_SIZE);
+    let mut data = Vec::with_capacity(blocks * block_size);
+    let mut pi = Vec::with_capacity(blocks);
+    for chunk in wire.chunks_exact(block_size + PI_SIZE) {
+        let (block, tuple) = chunk.split_at(block_size);
+        data.extend_from_slice(block);
+        pi.push(ProtectionInfo::from_bytes(tuple.try_into().expect("PI_SIZE bytes")));
+    }
+    (data, pi)
+}
+
+/// Interleave each block of `data` with its PI for the wire
+pub fn interleave(data: &[u8], pi: &[ProtectionInfo], block_size: u32) -> Vec<u8> {
+    let mut wire = Vec::with_capacity(data.len() + pi.len() * PI_SIZE);
+    for (block, info) in data.chunks(block_size as usize).zip(pi) {
+        wire.extend_from_slice(block);
+        wire.extend_from_slice(&info.to_bytes());
+    }
+    wire
+}
+
+/// How one READ or WRITE handles protection information
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PiTransfer {
+    /// RDPROTECT or WRPROTECT field of the CDB
+    pub protect: u8,
+    /// Protection type the unit is formatted with (1-3)
+    pub protection_type: u8,
+    /// First LBA of the command
+    pub lba: u64,
+    /// Expected reference tag of the first block
+    pub initial_ref_tag: u32,
+    /// Expected application tag (32-byte CDBs only)
+    pub app_tag: u16,
+    /// Bits of the application tag to check; 0 disables the check
+    pub app_tag_mask: u16,
+}
+
+impl PiTransfer {
+    /// Decode the protection a READ or WRITE starting at `lba` asks for
+    ///
+    /// Returns `Ok(None)` for an unprotected unit, and the sense to fail the
+    /// command with when the CDB does not fit the unit's protection type.
+    pub fn from_cdb(cdb: &[u8], lba: u64, protection_type: u8) -> Result<Option<Self>, SenseData> {
+        let extended = cdb.first() == Some(&0x7F);
+        let protect = match Cdb::decode(cdb) {
+            Some(Cdb::Read(read)) => read.rdprotect,
+            Some(Cdb::Write(write)) => write.wrprotect,
+            _ => 0,
+        };
+
+        if protection_type == 0 {
+            // READ/WRITE (32) only exist for Type 2 protection
+            if extended {
+                return Err(SenseData::invalid_command());
+            }
+            if protect != 0 {
+                return Err(SenseData::invalid_field_in_cdb());
+            }
+            return Ok(None);
+        }
+        // Type 2 takes its expected tags from 32-byte CDBs only, and those
+        // are not defined for the other types
+        if (extended && protection_type != 2) || (!extended && protection_type == 2 && protect != 0) {
+            return Err(SenseData::invalid_command());
+        }
+        if protect > 4 {
+            return Err(SenseData::invalid_field_in_cdb());
+        }
+
+        let (initial_ref_tag, app_tag, app_tag_mask) = if extended {
+            (
+                BigEndian::read_u32(&cdb[20..24]),
+                BigEndian::read_u16(&cdb[24..26]),
+                BigEndian::read_u16(&cdb[26..28]),
+            )
+        } else {
+            (lba as u32, 0, 0)
+        };
+        Ok(Some(PiTransfer {
+            protect,
+            protection_type,
+            lba,
+            initial_ref_tag,
+            app_tag,
+            app_tag_mask,
+        }))
+    }
+
+    /// Whether the PI travels with the data
+    pub fn transfers_pi(&self) -> bool {
+        self.protect != 0
+    }
+
+    /// Bytes per logical block on the wire
+    pub fn transfer_block_size(&self, block_size: u32) -> u32 {
+        if self.transfers_pi() {
+            block_size + PI_SIZE as u32
+        } else {
+            block_size
+        }
+    }
+
+    /// Expected reference tag of the block at `lba`
+    fn ref_tag(&self, lba: u64) -> u32 {
+        self.initial_ref_tag.wrapping_add(lba.wrapping_sub(self.lba) as u32)
+    }
+
+    /// PI for blocks of `data` starting at `lba`, as the target inserts it
+    /// when the initiator sends none
+    pub fn generate(&self, lba: u64, data: &[u8], block_size: u32) -> Vec<ProtectionInfo> {
+        data.chunks(block_size as usize)
+            .zip(lba..)
+            .map(|(block, lba)| ProtectionInfo::generate(block, self.app_tag, self.ref_tag(lba)))
+            .collect()
+    }
+
+    /// Check the PI of blocks starting at `lba` as the protect field asks
+    ///
+    /// Fails with ABORTED COMMAND and the LBA of the first bad block.
+    pub fn verify(&self, lba: u64, data: &[u8], pi: &[ProtectionInfo], block_size: u32) -> Result<(), SenseData> {
+        // (guard, application tag, reference tag); 0 is the READ case of
+        // checking the stored PI before stripping it
+        let (guard, app_tag, ref_tag) = match self.protect {
+            0 | 1 => (true, true, true),
+            2 => (false, true, true),
+            3 => (false, false, false),
+            _ => (true, false, false),
+        };
+        let app_tag = app_tag && self.app_tag_mask != 0;
+        let ref_tag = ref_tag && self.protection_type != 3;
+
+        for ((block, info), lba) in data.chunks(block_size as usize).zip(pi).zip(lba..) {
+            if info.app_tag == APP_TAG_ESCAPE
+                && (self.protection_type != 3 || info.ref_tag == REF_TAG_ESCAPE)
+            {
+                continue;
+            }
+            let ascq = if guard && info.guard != crc16_t10dif(block) {
+                0x01
+            } else if app_tag && (info.app_tag ^ self.app_tag) & self.app_tag_mask != 0 {
+                0x02
+            } else if ref_tag && info.ref_tag != self.ref_tag(lba) {
+                0x03
+            } else {
+                continue;
+            };
+            let sense = SenseData::new(sense_key::ABORTED_COMMAND, asc::LOGICAL_BLOCK_CHECK_FAILED, ascq);
+            return Err(match u32::try_from(lba) {
+                Ok(info) => sense.with_info(info),
+                Err(_) => sense,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read10(rdprotect: u8, lba: u32) -> Vec<u8> {
+        let mut cdb = vec![0x28, rdprotect << 5, 0, 0, 0, 0, 0, 0, 1, 0];
+        BigEndian::write_u32(&mut cdb[2..6], lba);
+        cdb
+    }
+
+    fn read32(rdprotect: u8, lba: u64, ref_tag: u32, app_tag: u16, mask: u16) -> Vec<u8> {
+        let mut cdb = vec![0u8; 32];
+        cdb[0] = 0x7F;
+        cdb[7] = 0x18;
+        BigEndian::write_u16(&mut cdb[8..10], 0x0009);
+        cdb[10] = rdprotect << 5;
+        BigEndian::write_u64(&mut cdb[12..20], lba);
+        BigEndian::write_u32(&mut cdb[20..24], ref_tag);
+        BigEndian::write_u16(&mut cdb[24..26], app_tag);
+        BigEndian::write_u16(&mut cdb[26..28], mask);
+        BigEndian::write_u32(&mut cdb[28..32], 1);
+        cdb
+    }
+
+    #[test]
+    fn test_crc16_t10dif() {
+        assert_eq!(crc16_t10dif(b"123456789"), 0xD0DB);
+        assert_eq!(crc16_t10dif(&[0u8; 512]), 0);
+    }
+
+    #[test]
+    fn test_interleave_round_trip() {
+        let data: Vec<u8> = (0..1024u32).map(|i| i as u8).collect();
+        let pi = vec![
+            ProtectionInfo::generate(&data[..512], 1, 10),
+            ProtectionInfo::generate(&data[512..], 1, 11),
+        ];
+        let wire = interleave(&data, &pi, 512);
+        assert_eq!(wire.len(), 2 * (512 + PI_SIZE));
+        assert_eq!(&wire[512..520], &pi[0].to_bytes());
+        assert_eq!(split(&wire, 512), (data, pi));
+    }
+
+    #[test]
+    fn test_from_cdb() {
+        // Unprotected unit: plain commands pass, protection is rejected
+        assert!(matches!(PiTransfer::from_cdb(&read10(0, 5), 5, 0), Ok(None)));
+        assert_eq!(PiTransfer::from_cdb(&read10(1, 5), 5, 0).unwrap_err().asc, asc::INVALID_FIELD_IN_CDB);
+        assert_eq!(
+            PiTransfer::from_cdb(&read32(0, 5, 5, 0, 0), 5, 0).unwrap_err().asc,
+            asc::INVALID_COMMAND_OPERATION_CODE
+        );
+
+        let pi = PiTransfer::from_cdb(&read10(1, 5), 5, 1).unwrap().unwrap();
+        assert_eq!((pi.protect, pi.initial_ref_tag, pi.app_tag_mask), (1, 5, 0));
+        assert_eq!(pi.transfer_block_size(512), 520);
+        assert_eq!(PiTransfer::from_cdb(&read10(5, 5), 5, 1).unwrap_err().asc, asc::INVALID_FIELD_IN_CDB);
+
+        // 32-byte CDBs belong to Type 2, which rejects RDPROTECT in the short ones
+        assert_eq!(
+            PiTransfer::from_cdb(&read32(1, 5, 5, 0, 0), 5, 1).unwrap_err().asc,
+            asc::INVALID_COMMAND_OPERATION_CODE
+        );
+        assert_eq!(
+            PiTransfer::from_cdb(&read10(1, 5), 5, 2).unwrap_err().asc,
+            asc::INVALID_COMMAND_OPERATION_CODE
+        );
+        assert!(PiTransfer::from_cdb(&read10(0, 5), 5, 2).unwrap().is_some());
+        let pi = PiTransfer::from_cdb(&read32(2, 5, 0x1000, 0xAB00, 0xFF00), 5, 2).unwrap().unwrap();
+        assert_eq!((pi.protect, pi.initial_ref_tag, pi.app_tag, pi.app_tag_mask), (2, 0x1000, 0xAB00, 0xFF00));
+    }
+
+    #[test]
+    fn test_verify() {
+        let data = vec![0x5Au8; 1024];
+        let pi = PiTransfer::from_cdb(&read10(1, 7), 7, 1).unwrap().unwrap();
+        let mut tuples = pi.generate(7, &data, 512);
+        assert_eq!(tuples[1].ref_tag, 8);
+        assert!(pi.verify(7, &data, &tuples, 512).is_ok());
+
+        // Reference tag of the second block is wrong
+        tuples[1].ref_tag = 9;
+        let sense = pi.verify(7, &data, &tuples, 512).unwrap_err();
+        assert_eq!((sense.sense_key, sense.asc, sense.ascq), (sense_key::ABORTED_COMMAND, 0x10, 0x03));
+        assert_eq!(sense.information, 8);
+
+        // ...not checked with RDPROTECT=4 (guard only), which catches bad data
+        let guard_only = PiTransfer { protect: 4, ..pi };
+        assert!(guard_only.verify(7, &data, &tuples, 512).is_ok());
+        let mut corrupt = data.clone();
+        corrupt[0] ^= 1;
+        assert_eq!(guard_only.verify(7, &corrupt, &tuples, 512).unwrap_err().ascq, 0x01);
+
+        // The escape application tag disables every check
+        tuples[1].app_tag = APP_TAG_ESCAPE;
+        assert!(pi.verify(7, &data, &tuples, 512).is_ok());
+
+        // Application tags are checked under the CDB's mask
+        let pi = PiTransfer::from_cdb(&read32(1, 7, 100, 0xAB00, 0xFF00), 7, 2).unwrap().unwrap();
+        let mut tuples = pi.generate(7, &data, 512);
+        assert_eq!(tuples[0].ref_tag, 100);
+        tuples[0].app_tag = 0xAB11;
+        assert!(pi.verify(7, &data, &tuples, 512).is_ok());
+        tuples[0].app_tag = 0xAC00;
+        assert_eq!(pi.verify(7, &data, &tuples, 512).unwrap_err().ascq, 0x02);
+    }
+}
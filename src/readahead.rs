@@ -0,0 +1,120 @@
+//! Sequential read detection for backend read-ahead
+//!
+//! Each connection watches the READs it queues per LUN. Once enough of them
+//! follow on from each other, it asks the backend to prefetch the blocks
+//! after the latest one, topping the window up as the stream advances.
+//! READs with DPO set ask for their data not to be cached, so they move the
+//! stream along without prefetching.
+
+use crate::cdb::{Cdb, ParsedRead};
+use crate::target::ReadAhead;
+use std::collections::HashMap;
+
+/// A run of READs on one LUN
+#[derive(Debug, Default, Clone, Copy)]
+struct Stream {
+    /// LBA the next READ starts at if the run continues
+    next_lba: u64,
+    /// READs so far that continued the run
+    sequential: u32,
+    /// End of the blocks already handed to the backend to prefetch
+    prefetched_to: u64,
+}
+
+/// Per-LUN sequential READ detectors for one connection
+#[derive(Debug)]
+pub(crate) struct SequentialReads {
+    config: ReadAhead,
+    streams: HashMap<u64, Stream>,
+}
+
+impl SequentialReads {
+    pub(crate) fn new(config: ReadAhead) -> Self {
+        SequentialReads { config, streams: HashMap::new() }
+    }
+
+    /// Note a command queued for `lun`, returning the range to prefetch once
+    /// it completes if it continues a streaming read
+    pub(crate) fn observe(&mut self, lun: u64, cdb: &[u8]) -> Option<(u64, u32)> {
+        let Some(Cdb::Read(ParsedRead { lba, blocks, dpo, .. })) = Cdb::decode(cdb) else {
+            return None;
+        };
+        let stream = self.streams.entry(lun).or_insert(Stream { next_lba: u64::MAX, ..Stream::default() });
+        if lba == stream.next_lba {
+            stream.sequential = stream.sequential.saturating_add(1);
+        } else {
+            *stream = Stream::default();
+        }
+        stream.next_lba = lba.saturating_add(blocks.into());
+
+        // Top the window up once less than half of it is left
+        let window = u64::from(self.config.window);
+        if dpo || stream.sequential < self.config.sequential_reads || stream.prefetched_to >= stream.next_lba.saturating_add(window / 2) {
+            return None;
+        }
+        let start = stream.prefetched_to.max(stream.next_lba);
+        stream.prefetched_to = stream.next_lba.saturating_add(window);
+        Some((start, (stream.prefetched_to - start) as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read10(lba: u32, blocks: u16) -> [u8; 10] {
+        let mut cdb = [0u8; 10];
+        cdb[0] = 0x28;
+        cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+        cdb[7..9].copy_from_slice(&blocks.to_be_bytes());
+        cdb
+    }
+
+    #[test]
+    fn test_streaming_reads_prefetch_ahead() {
+        let mut reads = SequentialReads::new(ReadAhead { sequential_reads: 2, window: 64 });
+
+        // The first READ and one follow-on are not yet a stream
+        assert_eq!(reads.observe(0, &read10(100, 8)), None);
+        assert_eq!(reads.observe(0, &read10(108, 8)), None);
+        assert_eq!(reads.observe(0, &read10(116, 8)), Some((124, 64)));
+
+        // Nothing more until less than half the window is left
+        for lba in (124..156).step_by(8) {
+            assert_eq!(reads.observe(0, &read10(lba, 8)), None);
+        }
+        assert_eq!(reads.observe(0, &read10(156, 8)), Some((188, 40)));
+
+        // Other LUNs and commands leave the stream alone
+        assert_eq!(reads.observe(1, &read10(164, 8)), None);
+        assert_eq!(reads.observe(0, &[0x00; 6]), None);
+        assert_eq!(reads.observe(0, &read10(164, 8)), None);
+    }
+
+    #[test]
+    fn test_random_reads_reset_the_stream() {
+        let mut reads = SequentialReads::new(ReadAhead { sequential_reads: 1, window: 16 });
+
+        assert_eq!(reads.observe(0, &read10(0, 4)), None);
+        assert_eq!(reads.observe(0, &read10(4, 4)), Some((8, 16)));
+        assert_eq!(reads.observe(0, &read10(500, 4)), None);
+        // A new stream prefetches from its own position
+        assert_eq!(reads.observe(0, &read10(504, 4)), Some((508, 16)));
+        // WRITEs are not reads
+        let mut write = read10(508, 4);
+        write[0] = 0x2A;
+        assert_eq!(reads.observe(0, &write), None);
+    }
+
+    #[test]
+    fn test_dpo_reads_are_not_prefetched() {
+        let mut reads = SequentialReads::new(ReadAhead { sequential_reads: 1, window: 16 });
+
+        assert_eq!(reads.observe(0, &read10(0, 4)), None);
+        let mut dpo = read10(4, 4);
+        dpo[1] = 0x10;
+        assert_eq!(reads.observe(0, &dpo), None);
+        // The stream carries on past it
+        assert_eq!(reads.observe(0, &read10(8, 4)), Some((12, 16)));
+    }
+}
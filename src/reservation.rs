@@ -0,0 +1,177 @@
+//! SCSI-2 RESERVE / RELEASE
+//!
+//! A logical unit can be reserved by one I_T nexus at a time. While the
+//! reservation is held, commands from any other nexus are answered with
+//! RESERVATION CONFLICT, apart from the few SPC-2 lets through (INQUIRY,
+//! REQUEST SENSE, REPORT LUNS and the like). The reservation is released by
+//! the holder, or when the holder's connection goes away.
+
+use crate::scsi::{scsi_status, ScsiResponse, SenseData};
+use crate::session::IscsiSession;
+use std::sync::{Mutex, MutexGuard};
+
+/// RESERVE(6) operation code
+pub(crate) const RESERVE_6: u8 = 0x16;
+/// RELEASE(6) operation code
+pub(crate) const RELEASE_6: u8 = 0x17;
+/// RESERVE(10) operation code
+pub(crate) const RESERVE_10: u8 = 0x56;
+/// RELEASE(10) operation code
+pub(crate) const RELEASE_10: u8 = 0x57;
+
+/// Whether `opcode` is one of the RESERVE / RELEASE commands
+pub(crate) fn is_reservation_command(opcode: u8) -> bool {
+    matches!(opcode, RESERVE_6 | RELEASE_6 | RESERVE_10 | RELEASE_10)
+}
+
+/// I_T nexus identifier of a session: its initiator and target ports
+///
+/// RFC 3720 names the initiator port `<InitiatorName>,i,0x<ISID>` and the
+/// target port `<TargetName>,t,0x<TPGT>`. The target name is the same for
+/// every session, so the nexus is `<InitiatorName>,i,0x<ISID>,t,0x<TPGT>`:
+/// two sessions from the same initiator are distinct nexuses, and so are
+/// sessions with the same ISID through different portal groups.
+pub(crate) fn nexus(session: &IscsiSession) -> String {
+    format!(
+        "{},i,0x{},t,0x{:04x}",
+        session.params.initiator_name,
+        hex::encode(session.isid),
+        session.params.target_portal_group_tag
+    )
+}
+
+/// RESERVATION CONFLICT status, with no data or sense
+pub(crate) fn conflict() -> ScsiResponse {
+    ScsiResponse {
+        status: scsi_status::RESERVATION_CONFLICT,
+        data: Vec::new(),
+        sense: None,
+    }
+}
+
+/// SCSI-2 reservation state of one logical unit
+#[derive(Debug, Default)]
+pub(crate) struct Reservation {
+    holder: Mutex<Option<String>>,
+}
+
+impl Reservation {
+    fn lock(&self) -> MutexGuard<'_, Option<String>> {
+        self.holder.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The I_T nexus holding the reservation, if any
+    pub(crate) fn holder(&self) -> Option<String> {
+        self.lock().clone()
+    }
+
+    /// Whether `cdb` from `nexus` must be refused because another nexus holds the unit
+    pub(crate) fn conflicts(&self, nexus: &str, cdb: &[u8]) -> bool {
+        let allowed = match cdb.first() {
+            // INQUIRY, REQUEST SENSE, LOG SENSE, REPORT LUNS and READ CAPACITY
+            Some(0x12 | 0x03 | 0x4D | 0xA0 | 0x25) => true,
+            // READ CAPACITY(16) is a SERVICE ACTION IN(16)
+            Some(0x9E) => cdb.get(1).is_some_and(|action| action & 0x1F == 0x10),
+            // A RELEASE from a non-holder succeeds without effect
+            Some(&RELEASE_6 | &RELEASE_10) => true,
+            _ => false,
+        };
+        !allowed && self.lock().as_deref().is_some_and(|holder| holder != nexus)
+    }
+
+    /// Execute RESERVE or RELEASE on behalf of `nexus`
+    pub(crate) fn execute(&self, nexus: &str, cdb: &[u8]) -> ScsiResponse {
+        let opcode = cdb.first().copied().unwrap_or(0);
+        let min_len = if matches!(opcode, RESERVE_10 | RELEASE_10) { 10 } else { 6 };
+        if cdb.len() < min_len {
+            return ScsiResponse::check_condition(SenseData::invalid_command());
+        }
+        // Extent (6-byte) and third-party (10-byte) reservations are not supported
+        let unsupported = match opcode {
+            RESERVE_6 | RELEASE_6 => cdb[1] & 0x01 != 0,
+            _ => cdb[1] & 0x10 != 0,
+        };
+        if unsupported {
+            return ScsiResponse::check_condition(SenseData::invalid_field_in_cdb());
+        }
+
+        let mut holder = self.lock();
+        match opcode {
+            RESERVE_6 | RESERVE_10 => match holder.as_deref() {
+                Some(current) if current != nexus => return conflict(),
+                Some(_) => {}
+                None => {
+                    log::info!("LUN reserved by {}", nexus);
+                    *holder = Some(nexus.to_string());
+                }
+            },
+            _ => {
+                if holder.as_deref() == Some(nexus) {
+                    log::info!("LUN released by {}", nexus);
+                    *holder = None;
+                }
+            }
+        }
+        ScsiResponse::good_no_data()
+    }
+
+    /// Drop the reservation if `nexus` holds it (I_T nexus loss)
+    pub(crate) fn release_nexus(&self, nexus: &str) {
+        let mut holder = self.lock();
+        if holder.as_deref() == Some(nexus) {
+            log::info!("LUN reservation of {} released on nexus loss", nexus);
+            *holder = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST_A: &str = "iqn.test:a,i,0x023d00000001";
+    const HOST_B: &str = "iqn.test:b,i,0x023d00000001";
+
+    #[test]
+    fn test_reserve_release() {
+        let reservation = Reservation::default();
+        let read = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        assert!(!reservation.conflicts(HOST_B, &read));
+
+        assert_eq!(reservation.execute(HOST_A, &[RESERVE_6, 0, 0, 0, 0, 0]).status, scsi_status::GOOD);
+        assert_eq!(reservation.holder().as_deref(), Some(HOST_A));
+        // The holder may reserve again; others conflict
+        assert_eq!(reservation.execute(HOST_A, &[RESERVE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]).status, scsi_status::GOOD);
+        assert_eq!(reservation.execute(HOST_B, &[RESERVE_6, 0, 0, 0, 0, 0]).status, scsi_status::RESERVATION_CONFLICT);
+
+        assert!(!reservation.conflicts(HOST_A, &read));
+        assert!(reservation.conflicts(HOST_B, &read));
+        assert!(reservation.conflicts(HOST_B, &[0x00, 0, 0, 0, 0, 0]), "TEST UNIT READY conflicts");
+        assert!(!reservation.conflicts(HOST_B, &[0x12, 0, 0, 0, 36, 0]), "INQUIRY is allowed");
+        assert!(!reservation.conflicts(HOST_B, &[0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0]));
+
+        // RELEASE from a non-holder succeeds but keeps the reservation
+        assert_eq!(reservation.execute(HOST_B, &[RELEASE_6, 0, 0, 0, 0, 0]).status, scsi_status::GOOD);
+        assert_eq!(reservation.holder().as_deref(), Some(HOST_A));
+        assert_eq!(reservation.execute(HOST_A, &[RELEASE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]).status, scsi_status::GOOD);
+        assert_eq!(reservation.holder(), None);
+        assert!(!reservation.conflicts(HOST_B, &read));
+    }
+
+    #[test]
+    fn test_reservation_unsupported_forms_and_nexus_loss() {
+        let reservation = Reservation::default();
+        // Extent and third-party reservations
+        let response = reservation.execute(HOST_A, &[RESERVE_6, 0x01, 0, 0, 0, 0]);
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        let response = reservation.execute(HOST_A, &[RESERVE_10, 0x10, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        assert_eq!(reservation.holder(), None);
+
+        reservation.execute(HOST_A, &[RESERVE_6, 0, 0, 0, 0, 0]);
+        reservation.release_nexus(HOST_B);
+        assert_eq!(reservation.holder().as_deref(), Some(HOST_A));
+        reservation.release_nexus(HOST_A);
+        assert_eq!(reservation.holder(), None);
+    }
+}
@@ -3,13 +3,26 @@
 //! This module defines the interface that storage backends must implement
 //! and handles SCSI command processing per the SCSI Block Commands (SBC) specification.
 
-use crate::error::{IscsiError, ScsiResult};
+use crate::cdb::{Cdb, ParsedPreFetch, ParsedRead, ParsedVerify, ParsedWrite};
+use crate::error::{IscsiError, ScsiDeviceError, ScsiResult, SenseCode};
+use crate::lun;
+use crate::protection::{self, PiTransfer, ProtectionInfo};
+use crate::stats::LunStatsSnapshot;
+use crate::vpd::{self, BlockLimits, Designator};
 use byteorder::{BigEndian, ByteOrder};
 
 /// SCSI block device trait
 ///
 /// Implement this trait to provide storage backend for the iSCSI target.
 /// The trait is designed to be simple and focused on block-level operations.
+///
+/// The target holds the device in a `RwLock`: methods taking `&self`
+/// (including `read`) may run concurrently from several connections and
+/// worker threads, while `&mut self` methods get exclusive access.
+///
+/// Failures are reported to the initiator according to the error returned:
+/// an `IscsiError::Device` (see `ScsiDeviceError`) or an `IscsiError::Scsi`
+/// with sense gives that status and sense, anything else MEDIUM ERROR.
 pub trait ScsiBlockDevice: Send + Sync {
     /// Read blocks from the device
     ///
@@ -42,6 +55,154 @@ pub trait ScsiBlockDevice: Send + Sync {
         Ok(())
     }
 
+    /// Whether the device has a volatile write cache enabled (reported as WCE)
+    ///
+    /// Initiators only send SYNCHRONIZE CACHE to devices with WCE set.
+    fn write_cache_enabled(&self) -> bool {
+        false
+    }
+
+    /// Enable or disable the volatile write cache (MODE SELECT, Caching page)
+    ///
+    /// The default rejects changes, which is reported to the initiator as an
+    /// invalid field in the parameter list.
+    fn set_write_cache(&mut self, _enabled: bool) -> ScsiResult<()> {
+        Err(IscsiError::sense(SenseCode::INVALID_FIELD_IN_PARAMETER_LIST, "write cache setting is not configurable"))
+    }
+
+    /// Whether `set_write_cache` can change the setting
+    ///
+    /// Reported as a changeable WCE bit in MODE SENSE. Override together
+    /// with `set_write_cache`.
+    fn write_cache_changeable(&self) -> bool {
+        false
+    }
+
+    /// Current power condition of the logical unit (default: always active)
+    ///
+    /// A `Stopped` unit fails TEST UNIT READY and medium access with NOT
+    /// READY until a START STOP UNIT starts it again.
+    fn power_condition(&self) -> PowerCondition {
+        PowerCondition::Active
+    }
+
+    /// Change the power condition or load/eject the medium (START STOP UNIT)
+    ///
+    /// `load_eject` is set when the initiator asked for the medium to be
+    /// loaded (`Active`) or ejected (`Stopped`). The default accepts and
+    /// ignores the request; an error is reported as an invalid field in the
+    /// CDB.
+    fn start_stop_unit(&mut self, _condition: PowerCondition, _load_eject: bool) -> ScsiResult<()> {
+        Ok(())
+    }
+
+    /// Hint that a range will be read soon (PRE-FETCH)
+    ///
+    /// Returns true if the whole range fits in the backend's cache, which is
+    /// reported as CONDITION MET. The default has no cache and returns false.
+    fn prefetch(&self, _lba: u64, _blocks: u32) -> ScsiResult<bool> {
+        Ok(false)
+    }
+
+    /// Called when a WRITE is abandoned after only part of its data arrived
+    ///
+    /// `lba`/`blocks` describe the whole command; some of that range may
+    /// already hold new data. Backends that can undo partial writes may roll
+    /// the range back here. None of the backends in this crate can, so an
+    /// aborted WRITE leaves the data that arrived in place and the rest of
+    /// the range unchanged.
+    fn abort_write(&mut self, _lba: u64, _blocks: u32) -> ScsiResult<()> {
+        // Default implementation: leave partially written data in place
+        Ok(())
+    }
+
+    /// Logical blocks per physical block, as a power of two (default: 0 = 1:1)
+    ///
+    /// Reported in READ CAPACITY (16), e.g. 3 for 512-byte logical blocks on
+    /// 4096-byte physical sectors.
+    fn physical_block_exponent(&self) -> u8 {
+        0
+    }
+
+    /// First LBA aligned to a physical block boundary (default: 0)
+    fn lowest_aligned_lba(&self) -> u16 {
+        0
+    }
+
+    /// Whether writes must cover whole physical blocks (default: false)
+    ///
+    /// Writes starting or ending inside a physical block then fail with
+    /// UNALIGNED WRITE COMMAND before any data is transferred.
+    fn requires_aligned_writes(&self) -> bool {
+        false
+    }
+
+    /// Whether the device is thin provisioned (reported as LBPME)
+    fn thin_provisioned(&self) -> bool {
+        false
+    }
+
+    /// Whether reads of unmapped blocks return zeros (reported as LBPRZ)
+    fn unmapped_reads_zero(&self) -> bool {
+        false
+    }
+
+    /// Provisioning status of the blocks from `lba` (GET LBA STATUS)
+    ///
+    /// `blocks` is the number of blocks from `lba` to the end of the medium.
+    /// Return contiguous runs starting at `lba`; describing fewer blocks is
+    /// fine, the initiator asks again from where the answer ends. The default
+    /// reports everything as mapped.
+    fn lba_status(&self, lba: u64, blocks: u64) -> ScsiResult<Vec<LbaStatus>> {
+        Ok(vec![LbaStatus {
+            lba,
+            blocks: blocks.min(u32::MAX as u64) as u32,
+            status: ProvisioningStatus::Mapped,
+        }])
+    }
+
+    /// T10 protection information type: 0 = none (default), 1-3 = Type 1-3
+    ///
+    /// Reported in READ CAPACITY (16) and INQUIRY. With protection, reads and
+    /// writes go through `read_with_pi` and `write_with_pi`.
+    fn protection_type(&self) -> u8 {
+        0
+    }
+
+    /// Read blocks along with their protection information
+    ///
+    /// Only called when `protection_type` is non-zero. The default reads the
+    /// data and generates PI for it (application tag 0, reference tag the low
+    /// 32 bits of the LBA), for backends that do not store PI.
+    fn read_with_pi(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<(Vec<u8>, Vec<ProtectionInfo>)> {
+        let data = self.read(lba, blocks, block_size)?;
+        let pi = data
+            .chunks(block_size as usize)
+            .zip(lba..)
+            .map(|(block, lba)| ProtectionInfo::generate(block, 0, lba as u32))
+            .collect();
+        Ok((data, pi))
+    }
+
+    /// Write blocks along with their protection information
+    ///
+    /// The target has already checked the PI as the command asked. The
+    /// default drops it and calls `write`.
+    fn write_with_pi(&mut self, lba: u64, data: &[u8], _pi: &[ProtectionInfo], block_size: u32) -> ScsiResult<()> {
+        self.write(lba, data, block_size)
+    }
+
+    /// Run a bidirectional command, such as XDWRITEREAD, that the target does
+    /// not implement itself
+    ///
+    /// Called once all of the command's Data-Out has arrived; the returned
+    /// bytes are sent as Data-In, cut to the initiator's Expected
+    /// Bidirectional Read Data Length. The default supports no such command
+    /// and fails with INVALID COMMAND OPERATION CODE.
+    fn bidirectional(&mut self, _cdb: &[u8], _data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+        Err(ScsiDeviceError::Custom(SenseCode::INVALID_COMMAND_OPERATION_CODE).into())
+    }
+
     /// Get vendor identification (8 chars max)
     fn vendor_id(&self) -> &str {
         "ISCSI   "
@@ -56,6 +217,257 @@ pub trait ScsiBlockDevice: Send + Sync {
     fn product_rev(&self) -> &str {
         "1.0 "
     }
+
+    /// Unit serial number reported in VPD page 0x80
+    ///
+    /// Should be unique per device so multipath can tell LUNs apart.
+    fn serial_number(&self) -> &str {
+        "ISCSI00000000001"
+    }
+
+    /// Identification descriptors reported in VPD page 0x83
+    ///
+    /// Defaults to a T10 vendor ID and an NAA identifier, both derived from
+    /// `serial_number()`. Add `Designator::ScsiName` to report the target IQN.
+    fn designators(&self) -> Vec<Designator> {
+        vec![
+            Designator::T10VendorId {
+                vendor: self.vendor_id().to_string(),
+                identifier: self.serial_number().to_string(),
+            },
+            Designator::naa_from_serial(self.serial_number()),
+        ]
+    }
+
+    /// Transfer limits reported in VPD page 0xB0
+    fn block_limits(&self) -> BlockLimits {
+        BlockLimits::default()
+    }
+
+    /// Medium rotation rate for VPD page 0xB1 (0 = not reported, 1 = non-rotating/SSD, else RPM)
+    fn medium_rotation_rate(&self) -> u16 {
+        0
+    }
+
+    /// Values reported in the read-only error recovery, disconnect-reconnect
+    /// and informational exceptions mode pages
+    fn mode_parameters(&self) -> ModeParameters {
+        ModeParameters::default()
+    }
+}
+
+/// A boxed device, as opened by a `DeviceProvider`
+impl<D: ScsiBlockDevice + ?Sized> ScsiBlockDevice for Box<D> {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        (**self).read(lba, blocks, block_size)
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        (**self).write(lba, data, block_size)
+    }
+
+    fn capacity(&self) -> u64 {
+        (**self).capacity()
+    }
+
+    fn block_size(&self) -> u32 {
+        (**self).block_size()
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        (**self).flush()
+    }
+
+    fn write_cache_enabled(&self) -> bool {
+        (**self).write_cache_enabled()
+    }
+
+    fn set_write_cache(&mut self, enabled: bool) -> ScsiResult<()> {
+        (**self).set_write_cache(enabled)
+    }
+
+    fn write_cache_changeable(&self) -> bool {
+        (**self).write_cache_changeable()
+    }
+
+    fn power_condition(&self) -> PowerCondition {
+        (**self).power_condition()
+    }
+
+    fn start_stop_unit(&mut self, condition: PowerCondition, load_eject: bool) -> ScsiResult<()> {
+        (**self).start_stop_unit(condition, load_eject)
+    }
+
+    fn prefetch(&self, lba: u64, blocks: u32) -> ScsiResult<bool> {
+        (**self).prefetch(lba, blocks)
+    }
+
+    fn abort_write(&mut self, lba: u64, blocks: u32) -> ScsiResult<()> {
+        (**self).abort_write(lba, blocks)
+    }
+
+    fn physical_block_exponent(&self) -> u8 {
+        (**self).physical_block_exponent()
+    }
+
+    fn lowest_aligned_lba(&self) -> u16 {
+        (**self).lowest_aligned_lba()
+    }
+
+    fn requires_aligned_writes(&self) -> bool {
+        (**self).requires_aligned_writes()
+    }
+
+    fn thin_provisioned(&self) -> bool {
+        (**self).thin_provisioned()
+    }
+
+    fn unmapped_reads_zero(&self) -> bool {
+        (**self).unmapped_reads_zero()
+    }
+
+    fn lba_status(&self, lba: u64, blocks: u64) -> ScsiResult<Vec<LbaStatus>> {
+        (**self).lba_status(lba, blocks)
+    }
+
+    fn protection_type(&self) -> u8 {
+        (**self).protection_type()
+    }
+
+    fn read_with_pi(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<(Vec<u8>, Vec<ProtectionInfo>)> {
+        (**self).read_with_pi(lba, blocks, block_size)
+    }
+
+    fn write_with_pi(&mut self, lba: u64, data: &[u8], pi: &[ProtectionInfo], block_size: u32) -> ScsiResult<()> {
+        (**self).write_with_pi(lba, data, pi, block_size)
+    }
+
+    fn bidirectional(&mut self, cdb: &[u8], data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+        (**self).bidirectional(cdb, data_out)
+    }
+
+    fn vendor_id(&self) -> &str {
+        (**self).vendor_id()
+    }
+
+    fn product_id(&self) -> &str {
+        (**self).product_id()
+    }
+
+    fn product_rev(&self) -> &str {
+        (**self).product_rev()
+    }
+
+    fn serial_number(&self) -> &str {
+        (**self).serial_number()
+    }
+
+    fn designators(&self) -> Vec<Designator> {
+        (**self).designators()
+    }
+
+    fn block_limits(&self) -> BlockLimits {
+        (**self).block_limits()
+    }
+
+    fn medium_rotation_rate(&self) -> u16 {
+        (**self).medium_rotation_rate()
+    }
+
+    fn mode_parameters(&self) -> ModeParameters {
+        (**self).mode_parameters()
+    }
+}
+
+/// Power condition of a logical unit (SBC-3 START STOP UNIT)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerCondition {
+    /// Ready to process medium access commands
+    Active,
+    /// Ready, in a reduced-power state
+    Idle,
+    /// Ready, in the lowest-power state that still processes commands
+    Standby,
+    /// Stopped (START=0); medium access fails until the unit is started
+    Stopped,
+}
+
+/// Provisioning status of a run of logical blocks (SBC-3 GET LBA STATUS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProvisioningStatus {
+    /// Backed by storage (or status unknown)
+    Mapped,
+    /// Not backed by storage
+    Deallocated,
+    /// Not backed by storage, but with resources reserved for it
+    Anchored,
+}
+
+impl ProvisioningStatus {
+    /// PROVISIONING STATUS field value
+    pub fn code(self) -> u8 {
+        match self {
+            ProvisioningStatus::Mapped => 0,
+            ProvisioningStatus::Deallocated => 1,
+            ProvisioningStatus::Anchored => 2,
+        }
+    }
+}
+
+/// A run of logical blocks sharing one provisioning status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LbaStatus {
+    /// First block of the run
+    pub lba: u64,
+    /// Blocks in the run
+    pub blocks: u32,
+    /// Provisioning status of every block in the run
+    pub status: ProvisioningStatus,
+}
+
+/// Fields of the mode pages MODE SELECT cannot change
+///
+/// Nothing here alters how the target behaves; the values are reported so
+/// initiators that probe these pages (ESXi, Solaris) find sensible ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeParameters {
+    /// Read-Write Error Recovery: automatic write reallocation (AWRE)
+    pub awre: bool,
+    /// Read-Write Error Recovery: automatic read reallocation (ARRE)
+    pub arre: bool,
+    /// Read-Write Error Recovery: read retry count
+    pub read_retry_count: u8,
+    /// Read-Write Error Recovery: write retry count
+    pub write_retry_count: u8,
+    /// Read-Write Error Recovery: recovery time limit in milliseconds (0 = vendor default)
+    pub recovery_time_limit: u16,
+    /// Disconnect-Reconnect: maximum burst size in 512-byte units (0 = no limit)
+    pub max_burst_size: u16,
+    /// Informational Exceptions Control: exception reporting disabled (DEXCPT)
+    pub dexcpt: bool,
+    /// Informational Exceptions Control: method of reporting (MRIE, 0 to 6)
+    pub mrie: u8,
+    /// Informational Exceptions Control: interval timer in 100 ms units
+    pub interval_timer: u32,
+    /// Informational Exceptions Control: report count (0 = no limit)
+    pub report_count: u32,
+}
+
+impl Default for ModeParameters {
+    fn default() -> Self {
+        Self {
+            awre: true,
+            arre: true,
+            read_retry_count: 0,
+            write_retry_count: 0,
+            recovery_time_limit: 0,
+            max_burst_size: 0,
+            dexcpt: true,
+            mrie: 0,
+            interval_timer: 0,
+            report_count: 0,
+        }
+    }
 }
 
 /// SCSI command opcodes (subset needed for basic block storage)
@@ -64,21 +476,31 @@ pub trait ScsiBlockDevice: Send + Sync {
 pub enum ScsiOpcode {
     TestUnitReady = 0x00,
     RequestSense = 0x03,
+    Read6 = 0x08,
+    Write6 = 0x0A,
     Inquiry = 0x12,
+    ModeSelect6 = 0x15,
     ModeSense6 = 0x1A,
     StartStopUnit = 0x1B,
     ReadCapacity10 = 0x25,
     Read10 = 0x28,
     Write10 = 0x2A,
     Verify10 = 0x2F,
+    PreFetch10 = 0x34,
     SynchronizeCache10 = 0x35,
+    LogSense = 0x4D,
+    ModeSelect10 = 0x55,
     ModeSense10 = 0x5A,
     Read16 = 0x88,
     Write16 = 0x8A,
     Verify16 = 0x8F,
+    PreFetch16 = 0x90,
     SynchronizeCache16 = 0x91,
+    VariableLength = 0x7F, // READ/WRITE (32)
     ServiceActionIn16 = 0x9E, // READ CAPACITY 16 uses this
     ReportLuns = 0xA0,
+    Read12 = 0xA8,
+    Write12 = 0xAA,
 }
 
 impl ScsiOpcode {
@@ -86,21 +508,31 @@ impl ScsiOpcode {
         match val {
             0x00 => Some(ScsiOpcode::TestUnitReady),
             0x03 => Some(ScsiOpcode::RequestSense),
+            0x08 => Some(ScsiOpcode::Read6),
+            0x0A => Some(ScsiOpcode::Write6),
             0x12 => Some(ScsiOpcode::Inquiry),
+            0x15 => Some(ScsiOpcode::ModeSelect6),
             0x1A => Some(ScsiOpcode::ModeSense6),
             0x1B => Some(ScsiOpcode::StartStopUnit),
             0x25 => Some(ScsiOpcode::ReadCapacity10),
             0x28 => Some(ScsiOpcode::Read10),
             0x2A => Some(ScsiOpcode::Write10),
             0x2F => Some(ScsiOpcode::Verify10),
+            0x34 => Some(ScsiOpcode::PreFetch10),
             0x35 => Some(ScsiOpcode::SynchronizeCache10),
+            0x4D => Some(ScsiOpcode::LogSense),
+            0x55 => Some(ScsiOpcode::ModeSelect10),
             0x5A => Some(ScsiOpcode::ModeSense10),
+            0x7F => Some(ScsiOpcode::VariableLength),
             0x88 => Some(ScsiOpcode::Read16),
             0x8A => Some(ScsiOpcode::Write16),
             0x8F => Some(ScsiOpcode::Verify16),
+            0x90 => Some(ScsiOpcode::PreFetch16),
             0x91 => Some(ScsiOpcode::SynchronizeCache16),
             0x9E => Some(ScsiOpcode::ServiceActionIn16),
             0xA0 => Some(ScsiOpcode::ReportLuns),
+            0xA8 => Some(ScsiOpcode::Read12),
+            0xAA => Some(ScsiOpcode::Write12),
             _ => None,
         }
     }
@@ -109,6 +541,37 @@ impl ScsiOpcode {
 // Keep the old enum name for backwards compatibility
 pub type ScsiCommand = ScsiOpcode;
 
+/// SERVICE ACTION IN (16) service actions
+pub mod service_action_in {
+    pub const READ_CAPACITY_16: u8 = 0x10;
+    pub const GET_LBA_STATUS: u8 = 0x12;
+}
+
+/// Service actions of variable length (0x7F) CDBs
+pub mod variable_length {
+    pub const READ_32: u16 = 0x0009;
+    pub const WRITE_32: u16 = 0x000B;
+}
+
+/// Mode page codes
+pub mod mode_page {
+    pub const RW_ERROR_RECOVERY: u8 = 0x01;
+    pub const DISCONNECT_RECONNECT: u8 = 0x02;
+    pub const CACHING: u8 = 0x08;
+    pub const CONTROL: u8 = 0x0A;
+    pub const INFORMATIONAL_EXCEPTIONS: u8 = 0x1C;
+    pub const ALL_PAGES: u8 = 0x3F;
+}
+
+/// Log page codes
+pub mod log_page {
+    pub const SUPPORTED_PAGES: u8 = 0x00;
+    pub const WRITE_ERROR_COUNTER: u8 = 0x02;
+    pub const READ_ERROR_COUNTER: u8 = 0x03;
+    pub const TEMPERATURE: u8 = 0x0D;
+    pub const START_STOP_CYCLE_COUNTER: u8 = 0x0E;
+}
+
 /// SCSI status codes
 pub mod scsi_status {
     pub const GOOD: u8 = 0x00;
@@ -140,14 +603,32 @@ pub mod sense_key {
 /// Additional Sense Code (ASC) values
 pub mod asc {
     pub const NO_ADDITIONAL_SENSE: u8 = 0x00;
+    pub const LOGICAL_UNIT_NOT_READY: u8 = 0x04;
+    pub const WRITE_ERROR: u8 = 0x0C;
+    /// Logical block guard (01), application tag (02) or reference tag (03) check failed
+    pub const LOGICAL_BLOCK_CHECK_FAILED: u8 = 0x10;
+    pub const UNRECOVERED_READ_ERROR: u8 = 0x11;
+    pub const MISCOMPARE_DURING_VERIFY: u8 = 0x1D;
+    pub const PARAMETER_LIST_LENGTH_ERROR: u8 = 0x1A;
     pub const INVALID_COMMAND_OPERATION_CODE: u8 = 0x20;
     pub const LBA_OUT_OF_RANGE: u8 = 0x21;
     pub const INVALID_FIELD_IN_CDB: u8 = 0x24;
     pub const LOGICAL_UNIT_NOT_SUPPORTED: u8 = 0x25;
+    pub const INVALID_FIELD_IN_PARAMETER_LIST: u8 = 0x26;
     pub const WRITE_PROTECTED: u8 = 0x27;
+    pub const NOT_READY_TO_READY_CHANGE: u8 = 0x28;
     pub const POWER_ON_RESET: u8 = 0x29;
+    /// Command timeout before (01) or during (02) processing
+    pub const INSUFFICIENT_TIME_FOR_OPERATION: u8 = 0x2E;
+    pub const PARAMETERS_CHANGED: u8 = 0x2A;
+    pub const SAVING_PARAMETERS_NOT_SUPPORTED: u8 = 0x39;
+    pub const TARGET_OPERATING_CONDITIONS_CHANGED: u8 = 0x3F;
     pub const MEDIUM_NOT_PRESENT: u8 = 0x3A;
     pub const INTERNAL_TARGET_FAILURE: u8 = 0x44;
+    pub const INVALID_MESSAGE_ERROR: u8 = 0x49;
+    /// Data phase errors, including initiator response timeout (06)
+    pub const DATA_PHASE_ERROR: u8 = 0x4B;
+    pub const LOW_POWER_CONDITION_ON: u8 = 0x5E;
 }
 
 /// SCSI sense data (fixed format)
@@ -157,6 +638,8 @@ pub struct SenseData {
     pub asc: u8,        // Additional Sense Code
     pub ascq: u8,       // Additional Sense Code Qualifier
     pub information: u32,
+    /// Whether `information` is meaningful (sets the VALID bit)
+    pub info_valid: bool,
 }
 
 impl SenseData {
@@ -166,11 +649,13 @@ impl SenseData {
             asc,
             ascq,
             information: 0,
+            info_valid: false,
         }
     }
 
     pub fn with_info(mut self, info: u32) -> Self {
         self.information = info;
+        self.info_valid = true;
         self
     }
 
@@ -180,6 +665,9 @@ impl SenseData {
 
         // Response code: 0x70 = current error, fixed format
         data[0] = 0x70;
+        if self.info_valid {
+            data[0] |= 0x80;
+        }
 
         // Sense key
         data[2] = self.sense_key & 0x0F;
@@ -202,21 +690,100 @@ impl SenseData {
         SenseData::new(sense_key::ILLEGAL_REQUEST, asc::INVALID_COMMAND_OPERATION_CODE, 0)
     }
 
-    /// Create sense data for LBA out of range
-    pub fn lba_out_of_range(lba: u32) -> Self {
-        SenseData::new(sense_key::ILLEGAL_REQUEST, asc::LBA_OUT_OF_RANGE, 0)
-            .with_info(lba)
+    /// Create sense data for an invalid field in the CDB
+    pub fn invalid_field_in_cdb() -> Self {
+        SenseData::new(sense_key::ILLEGAL_REQUEST, asc::INVALID_FIELD_IN_CDB, 0)
+    }
+
+    /// Create sense data for an invalid field in a parameter list (e.g. MODE SELECT)
+    pub fn invalid_field_in_parameter_list() -> Self {
+        SenseData::new(sense_key::ILLEGAL_REQUEST, asc::INVALID_FIELD_IN_PARAMETER_LIST, 0)
+    }
+
+    /// Create sense data for LBA out of range. The LBA is reported in the
+    /// information field when it fits in the 4 bytes fixed format allows.
+    pub fn lba_out_of_range(lba: u64) -> Self {
+        let sense = SenseData::new(sense_key::ILLEGAL_REQUEST, asc::LBA_OUT_OF_RANGE, 0);
+        match u32::try_from(lba) {
+            Ok(info) => sense.with_info(info),
+            Err(_) => sense,
+        }
+    }
+
+    /// Create sense data for a write that does not cover whole physical
+    /// blocks (UNALIGNED WRITE COMMAND), with the starting LBA when it fits
+    pub fn unaligned_write_command(lba: u64) -> Self {
+        let sense = SenseData::new(sense_key::ILLEGAL_REQUEST, asc::LBA_OUT_OF_RANGE, 0x04);
+        match u32::try_from(lba) {
+            Ok(info) => sense.with_info(info),
+            Err(_) => sense,
+        }
+    }
+
+    /// Create sense data for a command to a LUN the target does not export
+    pub fn logical_unit_not_supported() -> Self {
+        SenseData::new(sense_key::ILLEGAL_REQUEST, asc::LOGICAL_UNIT_NOT_SUPPORTED, 0)
+    }
+
+    /// Create the unit attention for a change to the LUN inventory
+    /// (REPORTED LUNS DATA HAS CHANGED)
+    pub fn reported_luns_data_changed() -> Self {
+        SenseData::new(sense_key::UNIT_ATTENTION, asc::TARGET_OPERATING_CONDITIONS_CHANGED, 0x0E)
+    }
+
+    /// Create sense data for a command that overran its deadline
+    /// (COMMAND TIMEOUT DURING PROCESSING)
+    pub fn command_timeout() -> Self {
+        SenseData::new(sense_key::ABORTED_COMMAND, asc::INSUFFICIENT_TIME_FOR_OPERATION, 0x02)
+    }
+
+    /// Create sense data for a task the initiator stopped sending data for
+    /// (INITIATOR RESPONSE TIMEOUT)
+    pub fn initiator_response_timeout() -> Self {
+        SenseData::new(sense_key::ABORTED_COMMAND, asc::DATA_PHASE_ERROR, 0x06)
+    }
+
+    /// Create the unit attention for a resized device
+    /// (CAPACITY DATA HAS CHANGED)
+    pub fn capacity_data_changed() -> Self {
+        SenseData::new(sense_key::UNIT_ATTENTION, asc::PARAMETERS_CHANGED, 0x09)
+    }
+
+    /// Create the unit attention for a media change (NOT READY TO READY
+    /// CHANGE, MEDIUM MAY HAVE CHANGED)
+    pub fn medium_may_have_changed() -> Self {
+        SenseData::new(sense_key::UNIT_ATTENTION, asc::NOT_READY_TO_READY_CHANGE, 0x00)
+    }
+
+    /// Create sense data for a stopped unit (LOGICAL UNIT NOT READY,
+    /// INITIALIZING COMMAND REQUIRED)
+    pub fn not_ready_initializing_command_required() -> Self {
+        SenseData::new(sense_key::NOT_READY, asc::LOGICAL_UNIT_NOT_READY, 0x02)
     }
 
     /// Create sense data for medium error
     pub fn medium_error() -> Self {
-        SenseData::new(sense_key::MEDIUM_ERROR, 0x11, 0x00) // Unrecovered read error
+        SenseData::new(sense_key::MEDIUM_ERROR, asc::UNRECOVERED_READ_ERROR, 0x00)
     }
 
     /// Create sense data for write protected
     pub fn write_protected() -> Self {
         SenseData::new(sense_key::DATA_PROTECT, asc::WRITE_PROTECTED, 0)
     }
+
+    /// Sense for a failed device operation: the error's own sense, or MEDIUM ERROR
+    pub fn from_error(error: &IscsiError) -> Self {
+        match error {
+            IscsiError::Device(ScsiDeviceError::OutOfRange { lba }) => SenseData::lba_out_of_range(*lba),
+            _ => error.sense_code().map_or_else(SenseData::medium_error, SenseData::from),
+        }
+    }
+}
+
+impl From<SenseCode> for SenseData {
+    fn from(code: SenseCode) -> Self {
+        SenseData::new(code.key, code.asc, code.ascq)
+    }
 }
 
 /// Result of SCSI command execution
@@ -257,8 +824,30 @@ impl ScsiResponse {
             sense: Some(sense),
         }
     }
+
+    /// Response for a failed device operation (see `ScsiDeviceError`)
+    pub fn from_error(error: &IscsiError) -> Self {
+        match error {
+            IscsiError::Device(ScsiDeviceError::Busy) => ScsiResponse {
+                status: scsi_status::BUSY,
+                data: Vec::new(),
+                sense: None,
+            },
+            _ => ScsiResponse::check_condition(SenseData::from_error(error)),
+        }
+    }
 }
 
+/// Blocks read from the medium at a time while verifying
+const VERIFY_CHUNK_BLOCKS: u32 = 256;
+
+/// Largest Data-Out buffer a byte-checking VERIFY may send (16 MiB)
+const MAX_VERIFY_DATA_LENGTH: usize = 16 * 1024 * 1024;
+
+/// DPOFUA bit of the mode parameter header's device-specific parameter:
+/// READs and WRITEs honour DPO and FUA
+const DPOFUA: u8 = 0x10;
+
 /// SCSI Command Handler
 pub struct ScsiHandler;
 
@@ -275,29 +864,61 @@ impl ScsiHandler {
 
         let opcode = cdb[0];
 
+        // Medium-access commands need a started unit and an in-range LBA
+        // before any handler runs
+        if let Err(sense) = Self::check_medium_access(cdb, device) {
+            return Ok(ScsiResponse::check_condition(sense));
+        }
+
         // Note: LUN validation is done at the target level since the LUN is in the PDU header,
         // not in the CDB. The handler receives already-validated LUN.
 
         match ScsiOpcode::from_u8(opcode) {
             Some(ScsiOpcode::TestUnitReady) => Self::handle_test_unit_ready(),
             Some(ScsiOpcode::Inquiry) => Self::handle_inquiry(cdb, device),
-            Some(ScsiOpcode::ReadCapacity10) => Self::handle_read_capacity_10(device),
+            Some(ScsiOpcode::ReadCapacity10) => Self::handle_read_capacity_10(cdb, device),
             Some(ScsiOpcode::ServiceActionIn16) => Self::handle_service_action_in_16(cdb, device),
-            Some(ScsiOpcode::Read10) => Self::handle_read_10(cdb, device),
-            Some(ScsiOpcode::Read16) => Self::handle_read_16(cdb, device),
-            Some(ScsiOpcode::Write10) => Self::handle_write_10(cdb, device, write_data),
-            Some(ScsiOpcode::Write16) => Self::handle_write_16(cdb, device, write_data),
-            Some(ScsiOpcode::ModeSense6) => Self::handle_mode_sense_6(cdb),
-            Some(ScsiOpcode::ModeSense10) => Self::handle_mode_sense_10(cdb),
-            Some(ScsiOpcode::RequestSense) => Self::handle_request_sense(cdb),
+            Some(ScsiOpcode::Read6 | ScsiOpcode::Read10 | ScsiOpcode::Read12 | ScsiOpcode::Read16) => {
+                Self::handle_read(cdb, device)
+            }
+            Some(ScsiOpcode::Write6 | ScsiOpcode::Write10 | ScsiOpcode::Write12 | ScsiOpcode::Write16) => {
+                Self::handle_write(cdb, device, write_data)
+            }
+            Some(ScsiOpcode::VariableLength) => match cdb.get(8..10).map(BigEndian::read_u16) {
+                Some(variable_length::READ_32) => Self::handle_read(cdb, device),
+                Some(variable_length::WRITE_32) => Self::handle_write(cdb, device, write_data),
+                _ => Ok(ScsiResponse::check_condition(SenseData::invalid_command())),
+            },
+            Some(ScsiOpcode::ModeSense6) => Self::handle_mode_sense_6(cdb, device),
+            Some(ScsiOpcode::ModeSense10) => Self::handle_mode_sense_10(cdb, device),
+            Some(ScsiOpcode::ModeSelect6) | Some(ScsiOpcode::ModeSelect10) => {
+                // Validation only - applying changes needs mutable access and
+                // is done by the target via handle_mode_select()
+                let params = write_data.unwrap_or(&[]);
+                Ok(match Self::parse_mode_select(cdb, params, device) {
+                    Ok(_) => ScsiResponse::good_no_data(),
+                    Err(sense) => ScsiResponse::check_condition(sense),
+                })
+            }
+            Some(ScsiOpcode::RequestSense) => Self::handle_request_sense(cdb, device),
             Some(ScsiOpcode::SynchronizeCache10) | Some(ScsiOpcode::SynchronizeCache16) => {
                 Self::handle_synchronize_cache(device)
             }
-            Some(ScsiOpcode::ReportLuns) => Self::handle_report_luns(cdb),
-            Some(ScsiOpcode::StartStopUnit) => Self::handle_start_stop_unit(cdb),
+            // The target answers with its LUN table via report_luns()
+            Some(ScsiOpcode::ReportLuns) => Self::report_luns(cdb, &[0]),
+            // The target answers with its own counters via handle_log_sense()
+            Some(ScsiOpcode::LogSense) => Self::handle_log_sense(cdb, &LunStatsSnapshot::default()),
+            Some(ScsiOpcode::StartStopUnit) => {
+                // Validation only - changing the power condition needs mutable
+                // access and is done by the target via handle_start_stop_unit()
+                Ok(match Self::parse_start_stop_unit(cdb) {
+                    Ok(_) => ScsiResponse::good_no_data(),
+                    Err(sense) => ScsiResponse::check_condition(sense),
+                })
+            }
+            Some(ScsiOpcode::PreFetch10) | Some(ScsiOpcode::PreFetch16) => Self::handle_prefetch(cdb, device),
             Some(ScsiOpcode::Verify10) | Some(ScsiOpcode::Verify16) => {
-                // VERIFY without BYTCHK just checks the medium - always succeed
-                Ok(ScsiResponse::good_no_data())
+                Self::handle_verify(cdb, device, write_data)
             }
             None => {
                 let sense = SenseData::invalid_command();
@@ -306,6 +927,68 @@ impl ScsiHandler {
         }
     }
 
+    /// Decode the starting LBA and block count of a medium-access command
+    /// (READ/WRITE 6/10/12/16/32, VERIFY 10/16, PRE-FETCH 10/16). Returns None for other commands
+    /// and for CDBs too short to carry the fields.
+    pub fn lba_range(cdb: &[u8]) -> Option<(u64, u32)> {
+        Cdb::decode(cdb).map(|command| command.lba_range())
+    }
+
+    /// Check that a command may access the medium: the unit must not be
+    /// stopped, and any LBA range must lie within the device capacity.
+    pub fn check_medium_access(cdb: &[u8], device: &dyn ScsiBlockDevice) -> Result<(), SenseData> {
+        let needs_medium = Self::lba_range(cdb).is_some()
+            || matches!(cdb.first(), Some(0x00 | 0x25 | 0x35 | 0x91))
+            || (cdb.first() == Some(&0x9E)
+                && cdb.get(1).map(|b| b & 0x1F) == Some(service_action_in::READ_CAPACITY_16));
+        if needs_medium && device.power_condition() == PowerCondition::Stopped {
+            return Err(SenseData::not_ready_initializing_command_required());
+        }
+        Self::check_lba_range(cdb, device)?;
+        Self::check_write_alignment(cdb, device)
+    }
+
+    /// Check that a WRITE covers whole physical blocks when the device
+    /// requires it. Other commands always pass.
+    pub fn check_write_alignment(cdb: &[u8], device: &dyn ScsiBlockDevice) -> Result<(), SenseData> {
+        let Some(Cdb::Write(ParsedWrite { lba, blocks, .. })) = Cdb::decode(cdb) else {
+            return Ok(());
+        };
+        if blocks == 0 {
+            return Ok(());
+        }
+        if !device.requires_aligned_writes() {
+            return Ok(());
+        }
+        let per_physical = 1u64 << device.physical_block_exponent().min(15);
+        let offset = u64::from(device.lowest_aligned_lba()) % per_physical;
+        let aligned = |lba: u64| (lba + per_physical - offset).is_multiple_of(per_physical);
+        if !aligned(lba) || !aligned(lba + u64::from(blocks)) {
+            return Err(SenseData::unaligned_write_command(lba));
+        }
+        Ok(())
+    }
+
+    /// Check a medium-access command against the device capacity.
+    ///
+    /// Returns LBA OUT OF RANGE sense (with the starting LBA in the
+    /// information field) if any addressed block lies past the end of the
+    /// medium. Commands without an LBA range always pass.
+    pub fn check_lba_range(cdb: &[u8], device: &dyn ScsiBlockDevice) -> Result<(), SenseData> {
+        let Some((lba, blocks)) = Self::lba_range(cdb) else {
+            return Ok(());
+        };
+
+        let capacity = device.capacity();
+        let past_end = lba
+            .checked_add(blocks as u64)
+            .is_none_or(|end| end > capacity);
+        if lba >= capacity || past_end {
+            return Err(SenseData::lba_out_of_range(lba));
+        }
+        Ok(())
+    }
+
     /// Handle TEST UNIT READY (0x00)
     fn handle_test_unit_ready() -> ScsiResult<ScsiResponse> {
         // Device is always ready
@@ -327,6 +1010,11 @@ impl ScsiHandler {
             return Self::handle_inquiry_vpd(page_code, alloc_len, device);
         }
 
+        // PAGE CODE is only valid with EVPD set
+        if page_code != 0 {
+            return Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_cdb()));
+        }
+
         // Standard INQUIRY response (36 bytes minimum)
         let mut data = vec![0u8; 96];
 
@@ -346,8 +1034,8 @@ impl ScsiHandler {
         // Additional length
         data[4] = 91; // Total length - 4
 
-        // Flags
-        data[5] = 0x00; // No special features
+        // Flags: PROTECT when the unit is formatted with protection
+        data[5] = if device.protection_type() > 0 { 0x01 } else { 0x00 };
         data[6] = 0x00;
         data[7] = 0x02; // CmdQue = 1 (command queuing supported)
 
@@ -388,64 +1076,23 @@ impl ScsiHandler {
     }
 
     /// Handle INQUIRY VPD pages
-    fn handle_inquiry_vpd(page_code: u8, alloc_len: usize, _device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
-        match page_code {
-            0x00 => {
-                // Supported VPD pages
-                let mut data = vec![0x00, 0x00, 0x00, 4]; // Device type, page code, reserved, page length
-                data.extend_from_slice(&[0x00, 0x80, 0x83, 0xB0]); // Supported pages
-                data.truncate(alloc_len.min(data.len()));
-                Ok(ScsiResponse::good(data))
-            }
-            0x80 => {
-                // Unit Serial Number
-                let mut data = vec![0x00, 0x80, 0x00, 16]; // Device type, page code, reserved, page length
-                data.extend_from_slice(b"ISCSI00000000001"); // 16-char serial
-                data.truncate(alloc_len.min(data.len()));
-                Ok(ScsiResponse::good(data))
-            }
-            0x83 => {
-                // Device Identification
-                let mut data = vec![0x00, 0x83, 0x00, 0x00]; // Header
-
-                // NAA descriptor
-                let naa_desc = [
-                    0x01, 0x03, 0x00, 0x08, // Code set=binary, type=NAA, length=8
-                    0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // NAA-6 identifier
-                ];
-                data.extend_from_slice(&naa_desc);
-
-                // Update page length
-                data[3] = (data.len() - 4) as u8;
-
-                data.truncate(alloc_len.min(data.len()));
-                Ok(ScsiResponse::good(data))
-            }
-            0xB0 => {
-                // Block Limits
-                let mut data = vec![0u8; 64];
-                data[0] = 0x00; // Device type
-                data[1] = 0xB0; // Page code
-                BigEndian::write_u16(&mut data[2..4], 60); // Page length
-
-                // Maximum transfer length (in blocks)
-                let max_xfer = 65535u32; // Max blocks per transfer
-                BigEndian::write_u32(&mut data[8..12], max_xfer);
-
-                // Optimal transfer length
-                BigEndian::write_u32(&mut data[12..16], 128); // 128 blocks optimal
-
+    fn handle_inquiry_vpd(page_code: u8, alloc_len: usize, device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
+        match vpd::build_page(page_code, device) {
+            Some(mut data) => {
                 data.truncate(alloc_len.min(data.len()));
                 Ok(ScsiResponse::good(data))
             }
-            _ => {
-                Ok(ScsiResponse::check_condition(SenseData::invalid_command()))
-            }
+            None => Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_cdb())),
         }
     }
 
     /// Handle READ CAPACITY (10) - 0x25
-    fn handle_read_capacity_10(device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
+    fn handle_read_capacity_10(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
+        // PMI clear requires LOGICAL BLOCK ADDRESS to be zero (SBC-3 5.15)
+        if cdb.len() >= 10 && cdb[8] & 0x01 == 0 && cdb[2..6] != [0; 4] {
+            return Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_cdb()));
+        }
+
         let capacity = device.capacity();
         let block_size = device.block_size();
 
@@ -472,11 +1119,26 @@ impl ScsiHandler {
             return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
         }
 
-        let service_action = cdb[1] & 0x1F;
+        match cdb[1] & 0x1F {
+            service_action_in::READ_CAPACITY_16 => Self::handle_read_capacity_16(cdb, device),
+            service_action_in::GET_LBA_STATUS => Self::handle_get_lba_status(cdb, device),
+            _ => Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_cdb())),
+        }
+    }
 
-        if service_action != 0x10 {
-            // 0x10 = READ CAPACITY 16
-            return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
+    /// Handle READ CAPACITY (16) - 0x9E/0x10
+    fn handle_read_capacity_16(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
+        // PMI clear requires LOGICAL BLOCK ADDRESS to be zero (SBC-3 5.16)
+        if cdb[14] & 0x01 == 0 && cdb[2..10] != [0; 8] {
+            return Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_cdb()));
+        }
+
+        let protection_type = device.protection_type();
+        if protection_type > 3 {
+            log::error!("Device reported invalid protection type {} (expected 0-3)", protection_type);
+            return Ok(ScsiResponse::check_condition(
+                SenseData::new(sense_key::HARDWARE_ERROR, asc::INTERNAL_TARGET_FAILURE, 0)
+            ));
         }
 
         let alloc_len = BigEndian::read_u32(&cdb[10..14]) as usize;
@@ -494,216 +1156,590 @@ impl ScsiHandler {
         // Block size (4 bytes)
         BigEndian::write_u32(&mut data[8..12], block_size);
 
+        // P_TYPE (bits 3-1) and PROT_EN (bit 0)
+        if protection_type > 0 {
+            data[12] = ((protection_type - 1) << 1) | 0x01;
+        }
+
+        // LOGICAL BLOCKS PER PHYSICAL BLOCK EXPONENT (bits 3-0)
+        data[13] = device.physical_block_exponent() & 0x0F;
+
+        // LBPME (bit 7), LBPRZ (bit 6), LOWEST ALIGNED LOGICAL BLOCK ADDRESS (14 bits)
+        let lowest_aligned = device.lowest_aligned_lba() & 0x3FFF;
+        BigEndian::write_u16(&mut data[14..16], lowest_aligned);
+        if device.thin_provisioned() {
+            data[14] |= 0x80;
+            if device.unmapped_reads_zero() {
+                data[14] |= 0x40;
+            }
+        }
+
         // Truncate to allocation length
         data.truncate(alloc_len.min(data.len()));
 
         Ok(ScsiResponse::good(data))
     }
 
-    /// Handle READ (10) - 0x28
-    fn handle_read_10(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
-        if cdb.len() < 10 {
-            return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
-        }
-
-        let lba = BigEndian::read_u32(&cdb[2..6]) as u64;
-        let transfer_length = BigEndian::read_u16(&cdb[7..9]) as u32;
-
-        if transfer_length == 0 {
-            return Ok(ScsiResponse::good_no_data());
-        }
-
-        // Validate LBA range
+    /// Handle GET LBA STATUS - 0x9E/0x12
+    ///
+    /// Returns one 16-byte descriptor per run the device reports, starting at
+    /// the requested LBA. Runs that are not contiguous with the previous one
+    /// end the list, and runs are clipped to the end of the medium.
+    fn handle_get_lba_status(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
+        let start = BigEndian::read_u64(&cdb[2..10]);
+        let alloc_len = BigEndian::read_u32(&cdb[10..14]) as usize;
         let capacity = device.capacity();
-        if lba + transfer_length as u64 > capacity {
-            return Ok(ScsiResponse::check_condition(SenseData::lba_out_of_range(lba as u32)));
-        }
-
-        // Read data
-        match device.read(lba, transfer_length, device.block_size()) {
-            Ok(data) => Ok(ScsiResponse::good(data)),
-            Err(_) => Ok(ScsiResponse::check_condition(SenseData::medium_error())),
-        }
-    }
-
-    /// Handle READ (16) - 0x88
-    fn handle_read_16(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
-        if cdb.len() < 16 {
-            return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
+        if start >= capacity {
+            return Ok(ScsiResponse::check_condition(SenseData::lba_out_of_range(start)));
         }
 
-        let lba = BigEndian::read_u64(&cdb[2..10]);
-        let transfer_length = BigEndian::read_u32(&cdb[10..14]);
+        let runs = match device.lba_status(start, capacity - start) {
+            Ok(runs) => runs,
+            Err(e) => {
+                log::error!("GET LBA STATUS failed at LBA {}: {}", start, e);
+                return Ok(ScsiResponse::from_error(&e));
+            }
+        };
 
-        if transfer_length == 0 {
-            return Ok(ScsiResponse::good_no_data());
+        // 8-byte header (PARAMETER DATA LENGTH, reserved), then descriptors
+        let mut data = vec![0u8; 8];
+        let mut next = start;
+        for run in runs {
+            if run.lba != next || run.blocks == 0 || run.lba >= capacity {
+                log::warn!("Ignoring LBA status run {}+{} (expected one starting at {})", run.lba, run.blocks, next);
+                break;
+            }
+            let blocks = (run.blocks as u64).min(capacity - run.lba) as u32;
+            let mut descriptor = [0u8; 16];
+            BigEndian::write_u64(&mut descriptor[0..8], run.lba);
+            BigEndian::write_u32(&mut descriptor[8..12], blocks);
+            descriptor[12] = run.status.code();
+            data.extend_from_slice(&descriptor);
+            next = run.lba + blocks as u64;
         }
-
-        // Validate LBA range
-        let capacity = device.capacity();
-        if lba + transfer_length as u64 > capacity {
+        if data.len() == 8 {
+            log::error!("Device reported no LBA status for LBA {}", start);
             return Ok(ScsiResponse::check_condition(
-                SenseData::lba_out_of_range((lba & 0xFFFF_FFFF) as u32)
+                SenseData::new(sense_key::HARDWARE_ERROR, asc::INTERNAL_TARGET_FAILURE, 0)
             ));
         }
+        let parameter_length = (data.len() - 4) as u32;
+        BigEndian::write_u32(&mut data[0..4], parameter_length);
 
-        // Read data
-        match device.read(lba, transfer_length, device.block_size()) {
-            Ok(data) => Ok(ScsiResponse::good(data)),
-            Err(_) => Ok(ScsiResponse::check_condition(SenseData::medium_error())),
-        }
+        data.truncate(alloc_len);
+        Ok(ScsiResponse::good(data))
     }
 
-    /// Handle WRITE (10) - 0x2A
-    fn handle_write_10(
+    /// Handle VERIFY (10) - 0x2F and VERIFY (16) - 0x8F
+    ///
+    /// BYTCHK=00 reads the range to confirm the medium is readable. BYTCHK=01
+    /// compares the Data-Out buffer with the medium, and BYTCHK=11 compares a
+    /// single block of Data-Out with every block in the range. A mismatch is
+    /// reported as MISCOMPARE with the offset of the first differing byte.
+    fn handle_verify(
         cdb: &[u8],
         device: &dyn ScsiBlockDevice,
         write_data: Option<&[u8]>,
     ) -> ScsiResult<ScsiResponse> {
-        if cdb.len() < 10 {
+        let Some(Cdb::Verify(ParsedVerify { lba, blocks, bytchk })) = Cdb::decode(cdb) else {
             return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
+        };
+        if bytchk == 0x02 {
+            return Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_cdb()));
+        }
+        if blocks == 0 {
+            return Ok(ScsiResponse::good_no_data());
         }
 
-        let lba = BigEndian::read_u32(&cdb[2..6]) as u64;
-        let transfer_length = BigEndian::read_u16(&cdb[7..9]) as u32;
+        let block_size = device.block_size();
+        let expected = match bytchk {
+            0x00 => None,
+            _ => {
+                let data = write_data.ok_or_else(|| {
+                    IscsiError::scsi("Verify data required but not provided")
+                })?;
+                let expected_len = Self::verify_compare_length(bytchk, blocks, block_size) as usize;
+                if data.len() < expected_len {
+                    return Err(IscsiError::scsi(format!(
+                        "Verify data too short: got {}, need {}",
+                        data.len(),
+                        expected_len
+                    )));
+                }
+                Some(&data[..expected_len])
+            }
+        };
 
-        if transfer_length == 0 {
-            return Ok(ScsiResponse::good_no_data());
+        let mut done = 0u32;
+        while done < blocks {
+            let count = (blocks - done).min(VERIFY_CHUNK_BLOCKS);
+            let medium = match device.read(lba + done as u64, count, block_size) {
+                Ok(data) => data,
+                Err(e) => return Ok(ScsiResponse::from_error(&e)),
+            };
+
+            if let Some(expected) = expected {
+                let chunk_start = done as usize * block_size as usize;
+                let mismatch = medium.iter().enumerate().find_map(|(i, byte)| {
+                    let offset = match bytchk {
+                        0x03 => i % block_size as usize,
+                        _ => chunk_start + i,
+                    };
+                    (expected[offset] != *byte).then_some(offset)
+                });
+                if let Some(offset) = mismatch {
+                    let sense = SenseData::new(sense_key::MISCOMPARE, asc::MISCOMPARE_DURING_VERIFY, 0)
+                        .with_info(offset as u32);
+                    return Ok(ScsiResponse::check_condition(sense));
+                }
+            }
+            done += count;
         }
 
-        // Validate LBA range
-        let capacity = device.capacity();
-        if lba + transfer_length as u64 > capacity {
-            return Ok(ScsiResponse::check_condition(SenseData::lba_out_of_range(lba as u32)));
+        Ok(ScsiResponse::good_no_data())
+    }
+
+    /// Bytes of Data-Out a byte-checking VERIFY compares against the medium
+    fn verify_compare_length(bytchk: u8, blocks: u32, block_size: u32) -> u64 {
+        match bytchk {
+            0x03 => block_size as u64,
+            _ => blocks as u64 * block_size as u64,
         }
+    }
 
-        // Check write data
-        let data = match write_data {
-            Some(d) => d,
-            None => {
-                return Err(IscsiError::Scsi("Write data required but not provided".into()));
-            }
+    /// Length of the Data-Out buffer a command needs in full before it runs
+    ///
+    /// Covers MODE SELECT parameter lists and the data a VERIFY with BYTCHK
+    /// compares against the medium. Returns None for commands without such a
+    /// buffer, and sense data if the buffer would address blocks past the end
+    /// of the medium or exceed what the target is willing to hold.
+    pub fn data_out_length(cdb: &[u8], device: &dyn ScsiBlockDevice) -> Result<Option<usize>, SenseData> {
+        if let Some(len) = Self::mode_select_parameter_length(cdb) {
+            return Ok(Some(len));
+        }
+        let Some(Cdb::Verify(ParsedVerify { blocks, bytchk, .. })) = Cdb::decode(cdb) else {
+            return Ok(None);
         };
+        if bytchk == 0x00 || bytchk == 0x02 || blocks == 0 {
+            return Ok(None);
+        }
 
-        let expected_len = transfer_length as usize * device.block_size() as usize;
-        if data.len() < expected_len {
-            return Err(IscsiError::Scsi(format!(
-                "Write data too short: got {}, need {}",
-                data.len(),
-                expected_len
-            )));
+        Self::check_lba_range(cdb, device)?;
+        let len = Self::verify_compare_length(bytchk, blocks, device.block_size());
+        if len > MAX_VERIFY_DATA_LENGTH as u64 {
+            return Err(SenseData::invalid_field_in_cdb());
         }
+        Ok(Some(len as usize))
+    }
 
-        // This is a read-only trait reference, so we can't actually write
-        // In a real implementation, we'd need &mut dyn ScsiBlockDevice
-        // For now, we just validate and return success
-        // The actual write happens in the target server which has mutable access
+    /// Handle READ (6) - 0x08, READ (10) - 0x28, READ (12) - 0xA8,
+    /// READ (16) - 0x88 and READ (32) - 0x7F/0x0009
+    fn handle_read(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
+        let Some(Cdb::Read(ParsedRead { lba, blocks: transfer_length, .. })) = Cdb::decode(cdb) else {
+            return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
+        };
+        let protection = match PiTransfer::from_cdb(cdb, lba, device.protection_type()) {
+            Ok(protection) => protection,
+            Err(sense) => return Ok(ScsiResponse::check_condition(sense)),
+        };
 
-        Ok(ScsiResponse::good_no_data())
+        if transfer_length == 0 {
+            return Ok(ScsiResponse::good_no_data());
+        }
+
+        let block_size = device.block_size();
+        let Some(protection) = protection else {
+            return match device.read(lba, transfer_length, block_size) {
+                Ok(data) => Ok(ScsiResponse::good(data)),
+                Err(e) => Ok(ScsiResponse::from_error(&e)),
+            };
+        };
+
+        // Check the stored PI, then strip it or pass it along
+        let (data, pi) = match device.read_with_pi(lba, transfer_length, block_size) {
+            Ok(read) => read,
+            Err(e) => return Ok(ScsiResponse::from_error(&e)),
+        };
+        if let Err(sense) = protection.verify(lba, &data, &pi, block_size) {
+            return Ok(ScsiResponse::check_condition(sense));
+        }
+        if protection.transfers_pi() {
+            Ok(ScsiResponse::good(protection::interleave(&data, &pi, block_size)))
+        } else {
+            Ok(ScsiResponse::good(data))
+        }
     }
 
-    /// Handle WRITE (16) - 0x8A
-    fn handle_write_16(
+    /// Handle WRITE (6) - 0x0A, WRITE (10) - 0x2A, WRITE (12) - 0xAA,
+    /// WRITE (16) - 0x8A and WRITE (32) - 0x7F/0x000B
+    fn handle_write(
         cdb: &[u8],
         device: &dyn ScsiBlockDevice,
         write_data: Option<&[u8]>,
     ) -> ScsiResult<ScsiResponse> {
-        if cdb.len() < 16 {
+        let Some(Cdb::Write(ParsedWrite { lba, blocks: transfer_length, .. })) = Cdb::decode(cdb) else {
             return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
-        }
-
-        let lba = BigEndian::read_u64(&cdb[2..10]);
-        let transfer_length = BigEndian::read_u32(&cdb[10..14]);
+        };
+        let protection = match PiTransfer::from_cdb(cdb, lba, device.protection_type()) {
+            Ok(protection) => protection,
+            Err(sense) => return Ok(ScsiResponse::check_condition(sense)),
+        };
 
         if transfer_length == 0 {
             return Ok(ScsiResponse::good_no_data());
         }
 
-        // Validate LBA range
-        let capacity = device.capacity();
-        if lba + transfer_length as u64 > capacity {
-            return Ok(ScsiResponse::check_condition(
-                SenseData::lba_out_of_range((lba & 0xFFFF_FFFF) as u32)
-            ));
-        }
-
         // Check write data
         let data = match write_data {
             Some(d) => d,
             None => {
-                return Err(IscsiError::Scsi("Write data required but not provided".into()));
+                return Err(IscsiError::scsi("Write data required but not provided"));
             }
         };
 
-        let expected_len = transfer_length as usize * device.block_size() as usize;
+        let block_size = device.block_size();
+        let transfer_block_size = protection.map_or(block_size, |p| p.transfer_block_size(block_size));
+        let expected_len = transfer_length as usize * transfer_block_size as usize;
         if data.len() < expected_len {
-            return Err(IscsiError::Scsi(format!(
+            return Err(IscsiError::scsi(format!(
                 "Write data too short: got {}, need {}",
                 data.len(),
                 expected_len
             )));
         }
 
+        if let Some(protection) = protection.filter(PiTransfer::transfers_pi) {
+            let (data, pi) = protection::split(&data[..expected_len], block_size);
+            if let Err(sense) = protection.verify(lba, &data, &pi, block_size) {
+                return Ok(ScsiResponse::check_condition(sense));
+            }
+        }
+
+        // This is a read-only trait reference, so we only validate here; the
+        // target writes with mutable access via write_blocks()
         Ok(ScsiResponse::good_no_data())
     }
 
+    /// Write `data` received for a WRITE at `lba`, checking and keeping its
+    /// protection information when the unit is protected
+    ///
+    /// `data` holds whole blocks, interleaved with their PI when the
+    /// command's WRPROTECT asks for it; `lba` is that of its first block.
+    pub fn write_blocks(
+        device: &mut dyn ScsiBlockDevice,
+        lba: u64,
+        data: &[u8],
+        block_size: u32,
+        protection: Option<&PiTransfer>,
+    ) -> Result<(), ScsiResponse> {
+        let result = match protection {
+            None => device.write(lba, data, block_size),
+            Some(protection) if protection.transfers_pi() => {
+                let (data, pi) = protection::split(data, block_size);
+                protection
+                    .verify(lba, &data, &pi, block_size)
+                    .map_err(ScsiResponse::check_condition)?;
+                device.write_with_pi(lba, &data, &pi, block_size)
+            }
+            Some(protection) => {
+                let pi = protection.generate(lba, data, block_size);
+                device.write_with_pi(lba, data, &pi, block_size)
+            }
+        };
+        result.map_err(|e| ScsiResponse::from_error(&e))
+    }
+
     /// Handle MODE SENSE (6) - 0x1A
-    fn handle_mode_sense_6(cdb: &[u8]) -> ScsiResult<ScsiResponse> {
+    fn handle_mode_sense_6(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
         if cdb.len() < 6 {
             return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
         }
 
-        let page_code = cdb[2] & 0x3F;
         let alloc_len = cdb[4] as usize;
+        let pages = match Self::mode_pages(cdb[2], cdb[3], device) {
+            Ok(pages) => pages,
+            Err(sense) => return Ok(ScsiResponse::check_condition(sense)),
+        };
 
-        // Return minimal mode parameter header
+        // Mode parameter header (4 bytes), no block descriptors
         let mut data = vec![0u8; 4];
-        data[0] = 3; // Mode data length (excluding this byte)
+        data[0] = (3 + pages.len()) as u8; // Mode data length (excluding this byte)
         data[1] = 0; // Medium type
-        data[2] = 0; // Device-specific parameter (not write protected)
+        data[2] = DPOFUA; // Device-specific parameter (not write protected)
         data[3] = 0; // Block descriptor length
-
-        // Add page data if requested
-        if page_code == 0x3F {
-            // Return all pages - just return header for now
-        }
+        data.extend_from_slice(&pages);
 
         data.truncate(alloc_len.min(data.len()));
         Ok(ScsiResponse::good(data))
     }
 
     /// Handle MODE SENSE (10) - 0x5A
-    fn handle_mode_sense_10(cdb: &[u8]) -> ScsiResult<ScsiResponse> {
+    fn handle_mode_sense_10(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
         if cdb.len() < 10 {
             return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
         }
 
-        let _page_code = cdb[2] & 0x3F;
         let alloc_len = BigEndian::read_u16(&cdb[7..9]) as usize;
+        let pages = match Self::mode_pages(cdb[2], cdb[3], device) {
+            Ok(pages) => pages,
+            Err(sense) => return Ok(ScsiResponse::check_condition(sense)),
+        };
 
-        // Return minimal mode parameter header (8 bytes for MODE SENSE 10)
+        // Mode parameter header (8 bytes for MODE SENSE 10), no block descriptors
         let mut data = vec![0u8; 8];
-        BigEndian::write_u16(&mut data[0..2], 6); // Mode data length
+        BigEndian::write_u16(&mut data[0..2], (6 + pages.len()) as u16); // Mode data length
         data[2] = 0; // Medium type
-        data[3] = 0; // Device-specific parameter
+        data[3] = DPOFUA; // Device-specific parameter
         data[4] = 0; // Reserved
         data[5] = 0; // Reserved
         BigEndian::write_u16(&mut data[6..8], 0); // Block descriptor length
+        data.extend_from_slice(&pages);
 
         data.truncate(alloc_len.min(data.len()));
         Ok(ScsiResponse::good(data))
     }
 
+    /// Mode pages for MODE SENSE, from CDB byte 2 (PC + page code) and byte 3 (subpage)
+    fn mode_pages(pc_page: u8, subpage: u8, device: &dyn ScsiBlockDevice) -> Result<Vec<u8>, SenseData> {
+        let page_control = pc_page >> 6;
+        let page_code = pc_page & 0x3F;
+
+        // Saved values (PC=3) are not supported
+        if page_control == 3 {
+            return Err(SenseData::new(sense_key::ILLEGAL_REQUEST, asc::SAVING_PARAMETERS_NOT_SUPPORTED, 0));
+        }
+        if subpage != 0 && subpage != 0xFF {
+            return Err(SenseData::invalid_field_in_cdb());
+        }
+
+        let changeable = page_control == 1;
+        match page_code {
+            mode_page::RW_ERROR_RECOVERY => Ok(Self::error_recovery_page(device, changeable)),
+            mode_page::DISCONNECT_RECONNECT => Ok(Self::disconnect_reconnect_page(device, changeable)),
+            mode_page::CACHING => Ok(Self::caching_page(device, changeable)),
+            mode_page::CONTROL => Ok(Self::control_page(changeable)),
+            mode_page::INFORMATIONAL_EXCEPTIONS => Ok(Self::informational_exceptions_page(device, changeable)),
+            mode_page::ALL_PAGES => {
+                let mut pages = Self::error_recovery_page(device, changeable);
+                pages.extend_from_slice(&Self::disconnect_reconnect_page(device, changeable));
+                pages.extend_from_slice(&Self::caching_page(device, changeable));
+                pages.extend_from_slice(&Self::control_page(changeable));
+                pages.extend_from_slice(&Self::informational_exceptions_page(device, changeable));
+                Ok(pages)
+            }
+            _ => Err(SenseData::invalid_field_in_cdb()),
+        }
+    }
+
+    /// Read-Write Error Recovery mode page (0x01); no fields are changeable
+    fn error_recovery_page(device: &dyn ScsiBlockDevice, changeable: bool) -> Vec<u8> {
+        let mut page = vec![0u8; 12];
+        page[0] = mode_page::RW_ERROR_RECOVERY;
+        page[1] = 0x0A; // Page length
+        if !changeable {
+            let params = device.mode_parameters();
+            if params.awre {
+                page[2] |= 0x80;
+            }
+            if params.arre {
+                page[2] |= 0x40;
+            }
+            page[3] = params.read_retry_count;
+            page[8] = params.write_retry_count;
+            BigEndian::write_u16(&mut page[10..12], params.recovery_time_limit);
+        }
+        page
+    }
+
+    /// Disconnect-Reconnect mode page (0x02); no fields are changeable
+    fn disconnect_reconnect_page(device: &dyn ScsiBlockDevice, changeable: bool) -> Vec<u8> {
+        let mut page = vec![0u8; 16];
+        page[0] = mode_page::DISCONNECT_RECONNECT;
+        page[1] = 0x0E; // Page length
+        if !changeable {
+            BigEndian::write_u16(&mut page[10..12], device.mode_parameters().max_burst_size);
+        }
+        page
+    }
+
+    /// Informational Exceptions Control mode page (0x1C); no fields are changeable
+    fn informational_exceptions_page(device: &dyn ScsiBlockDevice, changeable: bool) -> Vec<u8> {
+        let mut page = vec![0u8; 12];
+        page[0] = mode_page::INFORMATIONAL_EXCEPTIONS;
+        page[1] = 0x0A; // Page length
+        if !changeable {
+            let params = device.mode_parameters();
+            if params.dexcpt {
+                page[2] |= 0x08;
+            }
+            page[3] = params.mrie & 0x0F;
+            BigEndian::write_u32(&mut page[4..8], params.interval_timer);
+            BigEndian::write_u32(&mut page[8..12], params.report_count);
+        }
+        page
+    }
+
+    /// Caching mode page (0x08); with `changeable` set, the mask of changeable bits
+    fn caching_page(device: &dyn ScsiBlockDevice, changeable: bool) -> Vec<u8> {
+        let mut page = vec![0u8; 20];
+        page[0] = mode_page::CACHING;
+        page[1] = 0x12; // Page length
+        let wce = if changeable { device.write_cache_changeable() } else { device.write_cache_enabled() };
+        if wce {
+            page[2] |= 0x04; // WCE
+        }
+        page
+    }
+
+    /// Control mode page (0x0A); no fields are changeable
+    fn control_page(changeable: bool) -> Vec<u8> {
+        let mut page = vec![0u8; 12];
+        page[0] = mode_page::CONTROL;
+        page[1] = 0x0A; // Page length
+        if !changeable {
+            // Busy timeout period: unlimited
+            BigEndian::write_u16(&mut page[8..10], 0xFFFF);
+        }
+        page
+    }
+
+    /// Parse and apply MODE SELECT (6/10) - 0x15 / 0x55
+    ///
+    /// `params` is the parameter list sent by the initiator. A WCE change in
+    /// the Caching page is passed to `ScsiBlockDevice::set_write_cache()`.
+    pub fn handle_mode_select(
+        cdb: &[u8],
+        params: &[u8],
+        device: &mut dyn ScsiBlockDevice,
+    ) -> ScsiResult<ScsiResponse> {
+        let write_cache = match Self::parse_mode_select(cdb, params, device) {
+            Ok(write_cache) => write_cache,
+            Err(sense) => return Ok(ScsiResponse::check_condition(sense)),
+        };
+
+        if let Some(enabled) = write_cache {
+            if let Err(e) = device.set_write_cache(enabled) {
+                log::warn!("Rejecting MODE SELECT write cache change to {}: {}", enabled, e);
+                return Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_parameter_list()));
+            }
+            log::info!("Write cache {}", if enabled { "enabled" } else { "disabled" });
+        }
+
+        Ok(ScsiResponse::good_no_data())
+    }
+
+    /// Parameter list length of a MODE SELECT (6/10) CDB
+    pub fn mode_select_parameter_length(cdb: &[u8]) -> Option<usize> {
+        match cdb.first() {
+            Some(0x15) if cdb.len() >= 6 => Some(cdb[4] as usize),
+            Some(0x55) if cdb.len() >= 10 => Some(BigEndian::read_u16(&cdb[7..9]) as usize),
+            _ => None,
+        }
+    }
+
+    /// Validate a MODE SELECT parameter list
+    ///
+    /// Returns the requested write cache state if it differs from the current
+    /// one. Only WCE in the Caching page may change; other fields and pages
+    /// must match what MODE SENSE reports.
+    fn parse_mode_select(cdb: &[u8], params: &[u8], device: &dyn ScsiBlockDevice) -> Result<Option<bool>, SenseData> {
+        let param_len = Self::mode_select_parameter_length(cdb).ok_or_else(SenseData::invalid_command)?;
+        let is_10 = cdb[0] == 0x55;
+
+        // PF must be set (standard page format); SP (save pages) is not supported
+        if cdb[1] & 0x10 == 0 || cdb[1] & 0x01 != 0 {
+            return Err(SenseData::invalid_field_in_cdb());
+        }
+        if param_len == 0 {
+            return Ok(None);
+        }
+        if params.len() < param_len {
+            return Err(SenseData::new(sense_key::ILLEGAL_REQUEST, asc::PARAMETER_LIST_LENGTH_ERROR, 0));
+        }
+        let params = &params[..param_len];
+
+        // Skip the mode parameter header and any block descriptors
+        let (header_len, descriptor_len) = if is_10 {
+            if params.len() < 8 {
+                return Err(SenseData::new(sense_key::ILLEGAL_REQUEST, asc::PARAMETER_LIST_LENGTH_ERROR, 0));
+            }
+            (8, BigEndian::read_u16(&params[6..8]) as usize)
+        } else {
+            if params.len() < 4 {
+                return Err(SenseData::new(sense_key::ILLEGAL_REQUEST, asc::PARAMETER_LIST_LENGTH_ERROR, 0));
+            }
+            (4, params[3] as usize)
+        };
+        let mut offset = header_len + descriptor_len;
+        if offset > params.len() {
+            return Err(SenseData::new(sense_key::ILLEGAL_REQUEST, asc::PARAMETER_LIST_LENGTH_ERROR, 0));
+        }
+
+        let mut write_cache = None;
+        while offset < params.len() {
+            // Subpage format pages are not supported
+            if params[offset] & 0x40 != 0 || offset + 2 > params.len() {
+                return Err(SenseData::invalid_field_in_parameter_list());
+            }
+            let page_code = params[offset] & 0x3F;
+            let page_end = offset + 2 + params[offset + 1] as usize;
+            if page_end > params.len() {
+                return Err(SenseData::new(sense_key::ILLEGAL_REQUEST, asc::PARAMETER_LIST_LENGTH_ERROR, 0));
+            }
+            // PS is reserved in MODE SELECT; compare everything else
+            let mut page = params[offset..page_end].to_vec();
+            page[0] &= 0x3F;
+
+            match page_code {
+                mode_page::CACHING => {
+                    let wce = page.len() > 2 && page[2] & 0x04 != 0;
+                    let mut expected = Self::caching_page(device, false);
+                    expected[2] = (expected[2] & !0x04) | (page.get(2).copied().unwrap_or(0) & 0x04);
+                    if page != expected {
+                        return Err(SenseData::invalid_field_in_parameter_list());
+                    }
+                    if wce != device.write_cache_enabled() {
+                        write_cache = Some(wce);
+                    }
+                }
+                mode_page::CONTROL => {
+                    if page != Self::control_page(false) {
+                        return Err(SenseData::invalid_field_in_parameter_list());
+                    }
+                }
+                mode_page::RW_ERROR_RECOVERY => {
+                    if page != Self::error_recovery_page(device, false) {
+                        return Err(SenseData::invalid_field_in_parameter_list());
+                    }
+                }
+                mode_page::DISCONNECT_RECONNECT => {
+                    if page != Self::disconnect_reconnect_page(device, false) {
+                        return Err(SenseData::invalid_field_in_parameter_list());
+                    }
+                }
+                mode_page::INFORMATIONAL_EXCEPTIONS => {
+                    if page != Self::informational_exceptions_page(device, false) {
+                        return Err(SenseData::invalid_field_in_parameter_list());
+                    }
+                }
+                _ => return Err(SenseData::invalid_field_in_parameter_list()),
+            }
+            offset = page_end;
+        }
+
+        Ok(write_cache)
+    }
+
     /// Handle REQUEST SENSE - 0x03
-    fn handle_request_sense(cdb: &[u8]) -> ScsiResult<ScsiResponse> {
+    fn handle_request_sense(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
         if cdb.len() < 6 {
             return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
         }
 
         let alloc_len = cdb[4] as usize;
 
-        // Return "no sense" - no errors to report
-        let sense = SenseData::new(sense_key::NO_SENSE, asc::NO_ADDITIONAL_SENSE, 0);
+        // No errors to report - describe the power condition instead
+        let sense = Self::power_condition_sense(device);
         let mut data = sense.to_bytes();
         data.truncate(alloc_len.min(data.len()));
 
@@ -717,48 +1753,187 @@ impl ScsiHandler {
         Ok(ScsiResponse::good_no_data())
     }
 
-    /// Handle REPORT LUNS - 0xA0
-    fn handle_report_luns(cdb: &[u8]) -> ScsiResult<ScsiResponse> {
+    /// Handle LOG SENSE - 0x4D
+    ///
+    /// Serves the supported pages, write and read error counter, temperature
+    /// and start-stop cycle counter pages from `stats`. Only cumulative values
+    /// are kept, so the threshold and default page controls report zeros.
+    pub fn handle_log_sense(cdb: &[u8], stats: &LunStatsSnapshot) -> ScsiResult<ScsiResponse> {
+        if cdb.len() < 10 {
+            return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
+        }
+
+        // SP (save parameters) and PPC (parameter pointer control) are not supported
+        if cdb[1] & 0x03 != 0 || cdb[3] != 0 {
+            return Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_cdb()));
+        }
+
+        let page_control = cdb[2] >> 6;
+        let page_code = cdb[2] & 0x3F;
+        let parameter_pointer = BigEndian::read_u16(&cdb[5..7]);
+        let alloc_len = BigEndian::read_u16(&cdb[7..9]) as usize;
+
+        // Page control 01b is the current cumulative values
+        let current = |value: u64| if page_control == 1 { value } else { 0 };
+        // Log parameter: code, control byte, length, value
+        let param = |code: u16, control: u8, value: &[u8]| {
+            let mut param = vec![0u8; 4];
+            BigEndian::write_u16(&mut param[0..2], code);
+            param[2] = control;
+            param[3] = value.len() as u8;
+            param.extend_from_slice(value);
+            (code, param)
+        };
+
+        let params: Vec<(u16, Vec<u8>)> = match page_code {
+            log_page::SUPPORTED_PAGES => Vec::new(),
+            log_page::WRITE_ERROR_COUNTER | log_page::READ_ERROR_COUNTER => {
+                let (bytes, errors) = if page_code == log_page::WRITE_ERROR_COUNTER {
+                    (stats.bytes_written, stats.write_errors)
+                } else {
+                    (stats.bytes_read, stats.read_errors)
+                };
+                // Total errors corrected, total bytes processed, total uncorrected errors
+                [(0x0003, 0), (0x0005, bytes), (0x0006, errors)].into_iter()
+                    .map(|(code, value)| param(code, 0x02, &current(value).to_be_bytes()))
+                    .collect()
+            }
+            // No sensor: 0xFF reports the temperature and reference as unavailable
+            log_page::TEMPERATURE => vec![param(0x0000, 0x03, &[0, 0xFF]), param(0x0001, 0x03, &[0, 0xFF])],
+            log_page::START_STOP_CYCLE_COUNTER => {
+                let cycles = current(stats.start_stop_cycles).min(u32::MAX as u64) as u32;
+                // Specified cycle count over device lifetime (0: unspecified) and accumulated cycles
+                vec![param(0x0003, 0x03, &0u32.to_be_bytes()), param(0x0004, 0x03, &cycles.to_be_bytes())]
+            }
+            _ => return Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_cdb())),
+        };
+
+        let mut data = vec![page_code, 0, 0, 0];
+        if page_code == log_page::SUPPORTED_PAGES {
+            data.extend_from_slice(&[
+                log_page::SUPPORTED_PAGES,
+                log_page::WRITE_ERROR_COUNTER,
+                log_page::READ_ERROR_COUNTER,
+                log_page::TEMPERATURE,
+                log_page::START_STOP_CYCLE_COUNTER,
+            ]);
+        }
+        for (_, param) in params.into_iter().filter(|(code, _)| *code >= parameter_pointer) {
+            data.extend(param);
+        }
+        let page_length = (data.len() - 4) as u16;
+        BigEndian::write_u16(&mut data[2..4], page_length);
+
+        data.truncate(alloc_len.min(data.len()));
+        Ok(ScsiResponse::good(data))
+    }
+
+    /// Handle REPORT LUNS - 0xA0, listing the given LUN numbers
+    pub fn report_luns(cdb: &[u8], luns: &[u64]) -> ScsiResult<ScsiResponse> {
         if cdb.len() < 12 {
             return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
         }
 
         let alloc_len = BigEndian::read_u32(&cdb[6..10]) as usize;
 
-        // Report LUN 0 only
-        let mut data = vec![0u8; 16];
-        BigEndian::write_u32(&mut data[0..4], 8); // LUN list length (1 LUN * 8 bytes)
+        let mut data = vec![0u8; 8 + luns.len() * 8];
+        BigEndian::write_u32(&mut data[0..4], (luns.len() * 8) as u32); // LUN list length
         // data[4..8] reserved
-        // data[8..16] = LUN 0 (all zeros)
+        for (entry, &lun) in data[8..].chunks_exact_mut(8).zip(luns) {
+            BigEndian::write_u64(entry, lun::encode_lun(lun));
+        }
 
         data.truncate(alloc_len.min(data.len()));
         Ok(ScsiResponse::good(data))
     }
 
+    /// Sense data REQUEST SENSE returns when no error is pending
+    ///
+    /// Reports NO SENSE for an active unit, LOW POWER CONDITION ON for an
+    /// idle or standby one, and NOT READY for a stopped one.
+    pub fn power_condition_sense(device: &dyn ScsiBlockDevice) -> SenseData {
+        match device.power_condition() {
+            PowerCondition::Active => SenseData::new(sense_key::NO_SENSE, asc::NO_ADDITIONAL_SENSE, 0),
+            // Idle/standby condition activated by command
+            PowerCondition::Idle => SenseData::new(sense_key::NO_SENSE, asc::LOW_POWER_CONDITION_ON, 0x03),
+            PowerCondition::Standby => SenseData::new(sense_key::NO_SENSE, asc::LOW_POWER_CONDITION_ON, 0x04),
+            PowerCondition::Stopped => SenseData::not_ready_initializing_command_required(),
+        }
+    }
+
     /// Handle START STOP UNIT - 0x1B
-    fn handle_start_stop_unit(_cdb: &[u8]) -> ScsiResult<ScsiResponse> {
-        // Accept but ignore start/stop commands
+    ///
+    /// Stopping flushes the write cache first unless NO_FLUSH is set.
+    pub fn handle_start_stop_unit(cdb: &[u8], device: &mut dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
+        let (condition, load_eject, flush) = match Self::parse_start_stop_unit(cdb) {
+            Ok(request) => request,
+            Err(sense) => return Ok(ScsiResponse::check_condition(sense)),
+        };
+
+        if flush {
+            if let Err(e) = device.flush() {
+                log::error!("Flush before START STOP UNIT failed: {}", e);
+                return Ok(ScsiResponse::from_error(&e));
+            }
+        }
+        if let Err(e) = device.start_stop_unit(condition, load_eject) {
+            log::warn!("START STOP UNIT ({:?}, LOEJ={}) refused: {}", condition, load_eject, e);
+            if let IscsiError::Device(_) = e {
+                return Ok(ScsiResponse::from_error(&e));
+            }
+            return Ok(ScsiResponse::check_condition(SenseData::invalid_field_in_cdb()));
+        }
         Ok(ScsiResponse::good_no_data())
     }
 
-    /// Parse LBA and transfer length from READ/WRITE 10 CDB
-    pub fn parse_rw10_cdb(cdb: &[u8]) -> Option<(u64, u32)> {
-        if cdb.len() < 10 {
-            return None;
+    /// Decode a START STOP UNIT CDB into the requested power condition,
+    /// whether to load/eject the medium, and whether to flush first
+    fn parse_start_stop_unit(cdb: &[u8]) -> Result<(PowerCondition, bool, bool), SenseData> {
+        if cdb.len() < 6 {
+            return Err(SenseData::invalid_command());
         }
-        let lba = BigEndian::read_u32(&cdb[2..6]) as u64;
-        let length = BigEndian::read_u16(&cdb[7..9]) as u32;
-        Some((lba, length))
+
+        let start = cdb[4] & 0x01 != 0;
+        let load_eject = cdb[4] & 0x02 != 0;
+        let no_flush = cdb[4] & 0x04 != 0;
+        let condition = match cdb[4] >> 4 {
+            // START_VALID: START and LOEJ select the state
+            0x0 if start => PowerCondition::Active,
+            0x0 => PowerCondition::Stopped,
+            // ACTIVE, or LU_CONTROL handing control back to the unit
+            0x1 | 0x7 => PowerCondition::Active,
+            0x2 | 0xA => PowerCondition::Idle,
+            0x3 | 0xB => PowerCondition::Standby,
+            _ => return Err(SenseData::invalid_field_in_cdb()),
+        };
+        // LOEJ only applies with START_VALID
+        let load_eject = load_eject && cdb[4] >> 4 == 0;
+
+        Ok((condition, load_eject, condition == PowerCondition::Stopped && !no_flush))
     }
 
-    /// Parse LBA and transfer length from READ/WRITE 16 CDB
-    pub fn parse_rw16_cdb(cdb: &[u8]) -> Option<(u64, u32)> {
-        if cdb.len() < 16 {
-            return None;
+    /// Handle PRE-FETCH (10) - 0x34 and PRE-FETCH (16) - 0x90
+    fn handle_prefetch(cdb: &[u8], device: &dyn ScsiBlockDevice) -> ScsiResult<ScsiResponse> {
+        let Some(Cdb::PreFetch(ParsedPreFetch { lba, blocks })) = Cdb::decode(cdb) else {
+            return Ok(ScsiResponse::check_condition(SenseData::invalid_command()));
+        };
+
+        // A transfer length of zero prefetches through the last block
+        let blocks = if blocks == 0 {
+            u32::try_from(device.capacity() - lba).unwrap_or(u32::MAX)
+        } else {
+            blocks
+        };
+
+        match device.prefetch(lba, blocks) {
+            Ok(true) => Ok(ScsiResponse {
+                status: scsi_status::CONDITION_MET,
+                data: Vec::new(),
+                sense: None,
+            }),
+            Ok(false) => Ok(ScsiResponse::good_no_data()),
+            Err(e) => Ok(ScsiResponse::from_error(&e)),
         }
-        let lba = BigEndian::read_u64(&cdb[2..10]);
-        let length = BigEndian::read_u32(&cdb[10..14]);
-        Some((lba, length))
     }
 }
 
@@ -837,67 +2012,496 @@ mod tests {
         assert_eq!(response.data[1], 0x00); // Page code 0
     }
 
-    #[test]
-    fn test_read_capacity_10() {
-        let device = MockDevice::new(1000, 512);
-        let cdb = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
-        assert_eq!(response.status, scsi_status::GOOD);
-        assert_eq!(response.data.len(), 8);
-
-        let last_lba = BigEndian::read_u32(&response.data[0..4]);
-        let block_size = BigEndian::read_u32(&response.data[4..8]);
-        assert_eq!(last_lba, 999); // 1000 blocks, last LBA is 999
-        assert_eq!(block_size, 512);
+    fn inquiry_vpd(device: &dyn ScsiBlockDevice, page: u8) -> ScsiResponse {
+        let cdb = [0x12, 0x01, page, 0x01, 0x00, 0];
+        ScsiHandler::handle_command(&cdb, device, None).unwrap()
     }
 
     #[test]
-    fn test_read_capacity_16() {
+    fn test_inquiry_vpd_pages() {
         let device = MockDevice::new(1000, 512);
-        let cdb = [0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0];
-        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
-        assert_eq!(response.status, scsi_status::GOOD);
 
-        let last_lba = BigEndian::read_u64(&response.data[0..8]);
-        let block_size = BigEndian::read_u32(&response.data[8..12]);
-        assert_eq!(last_lba, 999);
-        assert_eq!(block_size, 512);
-    }
+        let response = inquiry_vpd(&device, 0x00);
+        assert_eq!(&response.data[4..], &[0x00, 0x80, 0x83, 0x86, 0xB0, 0xB1, 0xB2]);
 
-    #[test]
-    fn test_read_10() {
-        let device = MockDevice::new(1000, 512);
-        // READ(10): LBA=0, transfer_length=1
-        let cdb = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
-        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
-        assert_eq!(response.status, scsi_status::GOOD);
-        assert_eq!(response.data.len(), 512);
-    }
+        let response = inquiry_vpd(&device, 0x80);
+        assert_eq!(response.data[1], 0x80);
+        assert_eq!(&response.data[4..], b"ISCSI00000000001");
 
-    #[test]
-    fn test_read_10_out_of_range() {
-        let device = MockDevice::new(100, 512);
-        // READ(10): LBA=200 (out of range)
-        let cdb = [0x28, 0, 0, 0, 0, 200, 0, 0, 1, 0];
-        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        // T10 vendor ID then NAA 6 designator
+        let response = inquiry_vpd(&device, 0x83);
+        let data = &response.data;
+        assert_eq!(BigEndian::read_u16(&data[2..4]) as usize, data.len() - 4);
+        assert_eq!(&data[4..8], &[0x02, 0x01, 0x00, 24]);
+        assert_eq!(&data[8..16], b"ISCSI   ");
+        assert_eq!(&data[32..36], &[0x01, 0x03, 0x00, 16]);
+        assert_eq!(data[36] >> 4, 6);
+
+        let response = inquiry_vpd(&device, 0xB0);
+        assert_eq!(BigEndian::read_u16(&response.data[2..4]), 0x3C);
+        assert_eq!(BigEndian::read_u16(&response.data[6..8]), 1);
+        assert_eq!(BigEndian::read_u32(&response.data[8..12]), 65535);
+        assert_eq!(BigEndian::read_u32(&response.data[12..16]), 128);
+
+        let response = inquiry_vpd(&device, 0xB1);
+        assert_eq!(BigEndian::read_u16(&response.data[2..4]), 0x3C);
+
+        let response = inquiry_vpd(&device, 0xB2);
+        assert_eq!(&response.data[4..8], &[0, 0, 0, 0], "Fully provisioned by default");
+
+        // Unsupported page
+        let response = inquiry_vpd(&device, 0x89);
         assert_eq!(response.status, scsi_status::CHECK_CONDITION);
-        assert!(response.sense.is_some());
-    }
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_FIELD_IN_CDB);
 
-    #[test]
-    fn test_mode_sense_6() {
-        let device = MockDevice::new(1000, 512);
-        let cdb = [0x1A, 0, 0x3F, 0, 255, 0];
+        // Page code without EVPD
+        let cdb = [0x12, 0x00, 0x80, 0, 96, 0];
         let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
-        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
     }
 
     #[test]
-    fn test_mode_sense_10() {
-        let device = MockDevice::new(1000, 512);
-        let cdb = [0x5A, 0, 0x3F, 0, 0, 0, 0, 0, 255, 0];
+    fn test_inquiry_vpd_device_overrides() {
+        struct NamedDevice;
+
+        impl ScsiBlockDevice for NamedDevice {
+            fn read(&self, _lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                Ok(vec![0u8; (blocks * block_size) as usize])
+            }
+
+            fn write(&mut self, _lba: u64, _data: &[u8], _block_size: u32) -> ScsiResult<()> {
+                Ok(())
+            }
+
+            fn capacity(&self) -> u64 {
+                1000
+            }
+
+            fn block_size(&self) -> u32 {
+                512
+            }
+
+            fn physical_block_exponent(&self) -> u8 {
+                3
+            }
+
+            fn thin_provisioned(&self) -> bool {
+                true
+            }
+
+            fn unmapped_reads_zero(&self) -> bool {
+                true
+            }
+
+            fn serial_number(&self) -> &str {
+                "disk-42"
+            }
+
+            fn designators(&self) -> Vec<Designator> {
+                vec![Designator::ScsiName("iqn.2025-12.test:disk".to_string())]
+            }
+
+            fn medium_rotation_rate(&self) -> u16 {
+                1
+            }
+        }
+
+        let response = inquiry_vpd(&NamedDevice, 0x80);
+        assert_eq!(&response.data[4..], b"disk-42");
+
+        // iSCSI protocol, UTF-8, PIV + target device + SCSI name string, padded to 24 bytes
+        let response = inquiry_vpd(&NamedDevice, 0x83);
+        assert_eq!(&response.data[4..8], &[0x53, 0xA8, 0x00, 24]);
+        assert_eq!(&response.data[8..29], b"iqn.2025-12.test:disk");
+        assert_eq!(response.data.len(), 8 + 24);
+
+        // Granularity follows the physical block size
+        let response = inquiry_vpd(&NamedDevice, 0xB0);
+        assert_eq!(BigEndian::read_u16(&response.data[6..8]), 8);
+
+        let response = inquiry_vpd(&NamedDevice, 0xB1);
+        assert_eq!(BigEndian::read_u16(&response.data[4..6]), 1);
+
+        let response = inquiry_vpd(&NamedDevice, 0xB2);
+        assert_eq!(response.data[5], 0x04, "LBPRZ");
+        assert_eq!(response.data[6], 0x02, "Thin provisioned");
+    }
+
+    #[test]
+    fn test_mode_sense_pages() {
+        let device = MockDevice::new(1000, 512);
+
+        // Caching page, current values
+        let cdb = [0x1A, 0, 0x08, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data[0] as usize, response.data.len() - 1);
+        assert_eq!(&response.data[4..7], &[0x08, 0x12, 0x00]);
+
+        // A device whose cache cannot be configured reports WCE as not changeable
+        let cdb = [0x1A, 0, 0x48, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.data[6], 0x00);
+
+        // All pages via MODE SENSE (10)
+        let cdb = [0x5A, 0, 0x3F, 0, 0, 0, 0, 0x01, 0x00, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(BigEndian::read_u16(&response.data[0..2]) as usize, response.data.len() - 2);
+        let pages: Vec<u8> = [8, 20, 36, 56, 68].iter().map(|&offset| response.data[offset]).collect();
+        assert_eq!(pages, [
+            mode_page::RW_ERROR_RECOVERY,
+            mode_page::DISCONNECT_RECONNECT,
+            mode_page::CACHING,
+            mode_page::CONTROL,
+            mode_page::INFORMATIONAL_EXCEPTIONS,
+        ]);
+        assert_eq!(response.data.len(), 80);
+
+        // Saved values and unknown pages are rejected
+        let cdb = [0x1A, 0, 0xC8, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::SAVING_PARAMETERS_NOT_SUPPORTED);
+        let cdb = [0x1A, 0, 0x19, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+    }
+
+    #[test]
+    fn test_read_only_mode_pages() {
+        let mut device = MockDevice::new(1000, 512);
+
+        // Read-Write Error Recovery reports AWRE and ARRE by default
+        let cdb = [0x1A, 0, 0x01, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(&response.data[4..8], &[0x01, 0x0A, 0xC0, 0x00]);
+
+        // Informational Exceptions reports DEXCPT; none of its fields are changeable
+        let cdb = [0x1A, 0, 0x1C, 0, 255, 0];
         let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(&response.data[4..8], &[0x1C, 0x0A, 0x08, 0x00]);
+        let cdb = [0x1A, 0, 0x5C, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert!(response.data[6..].iter().all(|&b| b == 0));
+
+        let cdb = [0x1A, 0, 0x02, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(&response.data[4..6], &[0x02, 0x0E]);
+
+        // MODE SELECT accepts the pages unchanged and rejects any edit
+        let page = ScsiHandler::informational_exceptions_page(&device, false);
+        let mut params = vec![0u8; 4];
+        params.extend_from_slice(&page);
+        let cdb = [0x15, 0x10, 0, 0, params.len() as u8, 0];
+        let response = ScsiHandler::handle_mode_select(&cdb, &params, &mut device).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        params[7] = 4; // MRIE: unconditionally generate recovered error
+        let response = ScsiHandler::handle_mode_select(&cdb, &params, &mut device).unwrap();
+        assert_eq!(response.sense.unwrap().asc, SenseData::invalid_field_in_parameter_list().asc);
+    }
+
+    #[test]
+    fn test_mode_select_write_cache() {
+        struct CachedDevice {
+            write_cache: bool,
+        }
+
+        impl ScsiBlockDevice for CachedDevice {
+            fn read(&self, _lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                Ok(vec![0u8; (blocks * block_size) as usize])
+            }
+
+            fn write(&mut self, _lba: u64, _data: &[u8], _block_size: u32) -> ScsiResult<()> {
+                Ok(())
+            }
+
+            fn capacity(&self) -> u64 {
+                1000
+            }
+
+            fn block_size(&self) -> u32 {
+                512
+            }
+
+            fn write_cache_enabled(&self) -> bool {
+                self.write_cache
+            }
+
+            fn set_write_cache(&mut self, enabled: bool) -> ScsiResult<()> {
+                self.write_cache = enabled;
+                Ok(())
+            }
+
+            fn write_cache_changeable(&self) -> bool {
+                true
+            }
+        }
+
+        // Changeable values report WCE as changeable
+        let cdb = [0x1A, 0, 0x48, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &CachedDevice { write_cache: false }, None).unwrap();
+        assert_eq!(response.data[6], 0x04);
+
+        // MODE SELECT (6) header + Caching page with WCE set
+        let mut params = vec![0u8; 4];
+        params.extend_from_slice(&ScsiHandler::caching_page(&CachedDevice { write_cache: true }, false));
+        let cdb = [0x15, 0x10, 0, 0, params.len() as u8, 0];
+
+        let mut device = CachedDevice { write_cache: false };
+        let response = ScsiHandler::handle_mode_select(&cdb, &params, &mut device).unwrap();
         assert_eq!(response.status, scsi_status::GOOD);
+        assert!(device.write_cache);
+
+        // Control page as reported by MODE SENSE is accepted unchanged
+        let mut control = vec![0u8; 4];
+        control.extend_from_slice(&ScsiHandler::control_page(false));
+        let cdb = [0x15, 0x10, 0, 0, control.len() as u8, 0];
+        let response = ScsiHandler::handle_mode_select(&cdb, &control, &mut device).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+
+        // Changing a non-changeable field is rejected
+        control[6] = 0x04;
+        let response = ScsiHandler::handle_mode_select(&cdb, &control, &mut device).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_FIELD_IN_PARAMETER_LIST);
+
+        // SP (save pages) is not supported
+        let cdb = [0x15, 0x11, 0, 0, params.len() as u8, 0];
+        let response = ScsiHandler::handle_mode_select(&cdb, &params, &mut device).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_FIELD_IN_CDB);
+
+        // Devices without a configurable cache reject WCE changes
+        let cdb = [0x15, 0x10, 0, 0, params.len() as u8, 0];
+        let mut fixed = MockDevice::new(10, 512);
+        let response = ScsiHandler::handle_mode_select(&cdb, &params, &mut fixed).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_FIELD_IN_PARAMETER_LIST);
+    }
+
+    #[test]
+    fn test_read_capacity_10() {
+        let device = MockDevice::new(1000, 512);
+        let cdb = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data.len(), 8);
+
+        let last_lba = BigEndian::read_u32(&response.data[0..4]);
+        let block_size = BigEndian::read_u32(&response.data[4..8]);
+        assert_eq!(last_lba, 999); // 1000 blocks, last LBA is 999
+        assert_eq!(block_size, 512);
+    }
+
+    #[test]
+    fn test_read_capacity_16() {
+        let device = MockDevice::new(1000, 512);
+        let cdb = [0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+
+        let last_lba = BigEndian::read_u64(&response.data[0..8]);
+        let block_size = BigEndian::read_u32(&response.data[8..12]);
+        assert_eq!(last_lba, 999);
+        assert_eq!(block_size, 512);
+    }
+
+    #[test]
+    fn test_get_lba_status() {
+        /// Blocks below 100 deallocated, the rest mapped; one stray run after that
+        struct ProvisionedDevice;
+
+        impl ScsiBlockDevice for ProvisionedDevice {
+            fn read(&self, _lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                Ok(vec![0u8; (blocks * block_size) as usize])
+            }
+
+            fn write(&mut self, _lba: u64, _data: &[u8], _block_size: u32) -> ScsiResult<()> {
+                Ok(())
+            }
+
+            fn capacity(&self) -> u64 {
+                1000
+            }
+
+            fn block_size(&self) -> u32 {
+                512
+            }
+
+            fn lba_status(&self, lba: u64, _blocks: u64) -> ScsiResult<Vec<LbaStatus>> {
+                let mut runs = Vec::new();
+                if lba < 100 {
+                    runs.push(LbaStatus { lba, blocks: (100 - lba) as u32, status: ProvisioningStatus::Deallocated });
+                }
+                let mapped_from = lba.max(100);
+                // Overstates the run, which is clipped to the medium
+                runs.push(LbaStatus { lba: mapped_from, blocks: 5000, status: ProvisioningStatus::Mapped });
+                runs.push(LbaStatus { lba: 0, blocks: 1, status: ProvisioningStatus::Anchored });
+                Ok(runs)
+            }
+        }
+
+        let cdb = |lba: u64, alloc_len: u32| {
+            let mut cdb = [0u8; 16];
+            cdb[0] = 0x9E;
+            cdb[1] = service_action_in::GET_LBA_STATUS;
+            BigEndian::write_u64(&mut cdb[2..10], lba);
+            BigEndian::write_u32(&mut cdb[10..14], alloc_len);
+            cdb
+        };
+
+        let response = ScsiHandler::handle_command(&cdb(40, 256), &ProvisionedDevice, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data.len(), 8 + 2 * 16);
+        assert_eq!(BigEndian::read_u32(&response.data[0..4]), 36);
+        assert_eq!(BigEndian::read_u64(&response.data[8..16]), 40);
+        assert_eq!(BigEndian::read_u32(&response.data[16..20]), 60);
+        assert_eq!(response.data[20], 1, "deallocated");
+        assert_eq!(BigEndian::read_u64(&response.data[24..32]), 100);
+        assert_eq!(BigEndian::read_u32(&response.data[32..36]), 900);
+        assert_eq!(response.data[36], 0, "mapped");
+
+        // Truncated to the allocation length, with the full length still reported
+        let response = ScsiHandler::handle_command(&cdb(40, 24), &ProvisionedDevice, None).unwrap();
+        assert_eq!(response.data.len(), 24);
+        assert_eq!(BigEndian::read_u32(&response.data[0..4]), 36);
+
+        let response = ScsiHandler::handle_command(&cdb(1000, 256), &ProvisionedDevice, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        assert_eq!(response.sense.unwrap().asc, asc::LBA_OUT_OF_RANGE);
+
+        // The default reports the rest of the medium as mapped
+        let response = ScsiHandler::handle_command(&cdb(10, 256), &MockDevice::new(1000, 512), None).unwrap();
+        assert_eq!(response.data.len(), 24);
+        assert_eq!(BigEndian::read_u64(&response.data[8..16]), 10);
+        assert_eq!(BigEndian::read_u32(&response.data[16..20]), 990);
+        assert_eq!(response.data[20], 0);
+    }
+
+    /// Device with a large capacity and no backing storage
+    struct SparseDevice {
+        capacity: u64,
+        thin: bool,
+    }
+
+    impl ScsiBlockDevice for SparseDevice {
+        fn read(&self, _lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+            Ok(vec![0u8; (blocks * block_size) as usize])
+        }
+
+        fn write(&mut self, _lba: u64, _data: &[u8], _block_size: u32) -> ScsiResult<()> {
+            Ok(())
+        }
+
+        fn capacity(&self) -> u64 {
+            self.capacity
+        }
+
+        fn block_size(&self) -> u32 {
+            512
+        }
+
+        fn physical_block_exponent(&self) -> u8 {
+            3
+        }
+
+        fn lowest_aligned_lba(&self) -> u16 {
+            7
+        }
+
+        fn thin_provisioned(&self) -> bool {
+            self.thin
+        }
+
+        fn unmapped_reads_zero(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_read_capacity_oversized_device() {
+        // 4 TiB of 512-byte blocks does not fit in READ CAPACITY (10)
+        let device = SparseDevice { capacity: 1 << 33, thin: true };
+
+        let cdb = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(BigEndian::read_u32(&response.data[0..4]), 0xFFFF_FFFF);
+        assert_eq!(BigEndian::read_u32(&response.data[4..8]), 512);
+
+        let cdb = [0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data.len(), 32);
+        assert_eq!(BigEndian::read_u64(&response.data[0..8]), (1 << 33) - 1);
+        assert_eq!(response.data[12], 0, "No protection information");
+        assert_eq!(response.data[13], 3, "Physical block exponent");
+        assert_eq!(response.data[14], 0xC0, "LBPME and LBPRZ set");
+        assert_eq!(response.data[15], 7, "Lowest aligned LBA");
+
+        // LBPRZ is only meaningful with LBPME
+        let device = SparseDevice { capacity: 1000, thin: false };
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.data[14], 0);
+    }
+
+    #[test]
+    fn test_read_capacity_16_cdb_validation() {
+        let device = MockDevice::new(1000, 512);
+
+        // Allocation length truncates the parameter data
+        let cdb = [0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 12, 0, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.data.len(), 12);
+
+        // Unsupported service action
+        let cdb = [0x9E, 0x11, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_FIELD_IN_CDB);
+
+        // Non-zero LBA without PMI
+        let cdb = [0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 32, 0, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+
+        let cdb = [0x25, 0, 0, 0, 0, 5, 0, 0, 0, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+    }
+
+    #[test]
+    fn test_read_10() {
+        let device = MockDevice::new(1000, 512);
+        // READ(10): LBA=0, transfer_length=1
+        let cdb = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data.len(), 512);
+    }
+
+    #[test]
+    fn test_read_10_out_of_range() {
+        let device = MockDevice::new(100, 512);
+        // READ(10): LBA=200 (out of range)
+        let cdb = [0x28, 0, 0, 0, 0, 200, 0, 0, 1, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        assert!(response.sense.is_some());
+    }
+
+    #[test]
+    fn test_mode_sense_6() {
+        let device = MockDevice::new(1000, 512);
+        let cdb = [0x1A, 0, 0x3F, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data[2], DPOFUA);
+    }
+
+    #[test]
+    fn test_mode_sense_10() {
+        let device = MockDevice::new(1000, 512);
+        let cdb = [0x5A, 0, 0x3F, 0, 0, 0, 0, 0, 255, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data[3], DPOFUA);
     }
 
     #[test]
@@ -907,6 +2511,66 @@ mod tests {
         let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
         assert_eq!(response.status, scsi_status::GOOD);
         assert_eq!(response.data.len(), 16);
+
+        let cdb = [0xA0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0, 0];
+        let response = ScsiHandler::report_luns(&cdb, &[0, 2, 300]).unwrap();
+        assert_eq!(&response.data[0..4], &24u32.to_be_bytes());
+        assert_eq!(&response.data[16..18], &[0x00, 0x02]);
+        assert_eq!(&response.data[24..26], &[0x41, 0x2C]);
+    }
+
+    #[test]
+    fn test_log_sense_pages() {
+        let stats = LunStatsSnapshot {
+            bytes_read: 4096,
+            read_errors: 2,
+            start_stop_cycles: 3,
+            ..LunStatsSnapshot::default()
+        };
+        let log_sense = |page: u8, pointer: u16, alloc: u16| {
+            let mut cdb = [0x4D, 0, page, 0, 0, 0, 0, 0, 0, 0];
+            cdb[5..7].copy_from_slice(&pointer.to_be_bytes());
+            cdb[7..9].copy_from_slice(&alloc.to_be_bytes());
+            ScsiHandler::handle_log_sense(&cdb, &stats).unwrap()
+        };
+
+        let response = log_sense(0x40, 0, 255);
+        assert_eq!(response.data, vec![0x00, 0, 0, 5, 0x00, 0x02, 0x03, 0x0D, 0x0E]);
+
+        // Read error counter page: errors corrected, bytes processed, uncorrected errors
+        let response = log_sense(0x43, 0, 255);
+        assert_eq!(&response.data[0..4], &[0x03, 0, 0, 36]);
+        assert_eq!(BigEndian::read_u16(&response.data[16..18]), 0x0005);
+        assert_eq!(BigEndian::read_u64(&response.data[20..28]), 4096);
+        assert_eq!(BigEndian::read_u16(&response.data[28..30]), 0x0006);
+        assert_eq!(BigEndian::read_u64(&response.data[32..40]), 2);
+
+        // The parameter pointer skips lower parameter codes
+        let response = log_sense(0x43, 0x0006, 255);
+        assert_eq!(&response.data[0..4], &[0x03, 0, 0, 12]);
+
+        // Threshold values are not kept
+        let response = log_sense(0x03, 0, 255);
+        assert_eq!(BigEndian::read_u64(&response.data[20..28]), 0);
+
+        let response = log_sense(0x4D, 0, 255);
+        assert_eq!(&response.data[4..10], &[0, 0, 0x03, 2, 0, 0xFF]);
+
+        let response = log_sense(0x4E, 0, 255);
+        assert_eq!(&response.data[12..20], &[0, 0x04, 0x03, 4, 0, 0, 0, 3]);
+
+        assert_eq!(log_sense(0x4E, 0, 6).data.len(), 6);
+        assert_eq!(log_sense(0x71, 0, 255).sense.unwrap().asc, asc::INVALID_FIELD_IN_CDB);
+
+        // Saving parameters is not supported
+        let cdb = [0x4D, 0x01, 0x43, 0, 0, 0, 0, 0, 255, 0];
+        let response = ScsiHandler::handle_log_sense(&cdb, &stats).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_FIELD_IN_CDB);
+
+        // Without the target's counters every total is zero
+        let device = MockDevice::new(1000, 512);
+        let response = ScsiHandler::handle_command(&[0x4D, 0, 0x43, 0, 0, 0, 0, 0, 255, 0], &device, None).unwrap();
+        assert_eq!(BigEndian::read_u64(&response.data[20..28]), 0);
     }
 
     #[test]
@@ -952,10 +2616,97 @@ mod tests {
         assert_eq!(data[12], asc::INVALID_FIELD_IN_CDB);
     }
 
+    #[test]
+    fn test_lba_range_checked_before_dispatch() {
+        let device = MockDevice::new(100, 512);
+
+        // READ(16) whose end wraps past u64::MAX must not overflow
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x88;
+        cdb[2..10].copy_from_slice(&(u64::MAX - 1).to_be_bytes());
+        cdb[10..14].copy_from_slice(&4u32.to_be_bytes());
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        let sense = response.sense.unwrap().to_bytes();
+        assert_eq!(sense[0], 0x70); // LBA does not fit the information field
+        assert_eq!(sense[12], asc::LBA_OUT_OF_RANGE);
+
+        // VERIFY(10) straddling the last block reports the starting LBA
+        let cdb = [0x2F, 0, 0, 0, 0, 98, 0, 0, 4, 0];
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        let sense = response.sense.unwrap().to_bytes();
+        assert_eq!(sense[0], 0xF0); // VALID bit set
+        assert_eq!(BigEndian::read_u32(&sense[3..7]), 98);
+
+        // Zero-length transfers still need an LBA on the medium
+        let cdb = [0x28, 0, 0, 0, 0, 100, 0, 0, 0, 0];
+        assert!(ScsiHandler::check_lba_range(&cdb, &device).is_err());
+        let cdb = [0x28, 0, 0, 0, 0, 99, 0, 0, 1, 0];
+        assert!(ScsiHandler::check_lba_range(&cdb, &device).is_ok());
+    }
+
+    #[test]
+    fn test_read_6_and_12() {
+        let mut device = MockDevice::new(1000, 512);
+        device.data[20 * 512] = 0x5A;
+
+        let response = ScsiHandler::handle_command(&[0x08, 0, 0, 20, 1, 0], &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data.len(), 512);
+        assert_eq!(response.data[0], 0x5A);
+
+        let response = ScsiHandler::handle_command(&[0xA8, 0, 0, 0, 0, 19, 0, 0, 0, 2, 0, 0], &device, None).unwrap();
+        assert_eq!(response.data.len(), 1024);
+        assert_eq!(response.data[512], 0x5A);
+
+        // Checked against the medium like any other READ
+        let response = ScsiHandler::handle_command(&[0xA8, 0, 0, 0, 0x03, 0xE7, 0, 0, 0, 2, 0, 0], &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        assert_eq!(response.sense.unwrap().asc, asc::LBA_OUT_OF_RANGE);
+    }
+
+    #[test]
+    fn test_unaligned_writes_rejected_when_required() {
+        use crate::backends::{BlockGeometry, GeometryBlockDevice, MemBlockDevice};
+
+        // 512e with physical blocks starting at LBA 1
+        let geometry = BlockGeometry { lowest_aligned_lba: 1, aligned_writes: true, ..BlockGeometry::emulated_512() };
+        let device = GeometryBlockDevice::new(MemBlockDevice::new(64, 512), geometry).unwrap();
+        let rw10 = |opcode: u8, lba: u8, blocks: u8| [opcode, 0, 0, 0, 0, lba, 0, 0, blocks, 0];
+
+        assert!(ScsiHandler::check_medium_access(&rw10(0x2A, 9, 16), &device).is_ok());
+        let sense = ScsiHandler::check_medium_access(&rw10(0x2A, 8, 8), &device).unwrap_err();
+        assert_eq!((sense.sense_key, sense.asc, sense.ascq), (sense_key::ILLEGAL_REQUEST, asc::LBA_OUT_OF_RANGE, 0x04));
+        assert_eq!(sense.information, 8);
+        assert!(ScsiHandler::check_medium_access(&rw10(0x2A, 9, 4), &device).is_err());
+        // Reads and zero-length writes may start anywhere
+        assert!(ScsiHandler::check_medium_access(&rw10(0x28, 8, 3), &device).is_ok());
+        assert!(ScsiHandler::check_medium_access(&rw10(0x2A, 8, 0), &device).is_ok());
+
+        // READ CAPACITY (16) reports the configured geometry
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x9E;
+        cdb[1] = service_action_in::READ_CAPACITY_16;
+        cdb[13] = 32;
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(BigEndian::read_u32(&response.data[8..12]), 512);
+        assert_eq!(response.data[13], 3);
+        assert_eq!(BigEndian::read_u16(&response.data[14..16]), 1);
+
+        // Without the requirement the backend takes any write
+        let device = GeometryBlockDevice::new(MemBlockDevice::new(64, 512), BlockGeometry::emulated_512()).unwrap();
+        assert!(ScsiHandler::check_medium_access(&rw10(0x2A, 8, 1), &device).is_ok());
+
+        // WRITE(6) with length 0 addresses 256 blocks
+        assert_eq!(ScsiHandler::lba_range(&[0x0A, 0, 0, 5, 0, 0]), Some((5, 256)));
+        assert!(ScsiHandler::lba_range(&[0x12, 0, 0, 0, 36, 0]).is_none());
+    }
+
     #[test]
     fn test_parse_rw10_cdb() {
         let cdb = [0x28, 0, 0, 0, 0, 100, 0, 0, 10, 0]; // LBA=100, length=10
-        let (lba, length) = ScsiHandler::parse_rw10_cdb(&cdb).unwrap();
+        let (lba, length) = ScsiHandler::lba_range(&cdb).unwrap();
         assert_eq!(lba, 100);
         assert_eq!(length, 10);
     }
@@ -968,7 +2719,7 @@ mod tests {
             0, 0, 0, 10, // length=10
             0, 0
         ];
-        let (lba, length) = ScsiHandler::parse_rw16_cdb(&cdb).unwrap();
+        let (lba, length) = ScsiHandler::lba_range(&cdb).unwrap();
         assert_eq!(lba, 100);
         assert_eq!(length, 10);
     }
@@ -981,6 +2732,133 @@ mod tests {
         assert_eq!(response.status, scsi_status::GOOD);
     }
 
+    #[test]
+    fn test_verify_bytchk() {
+        let mut device = MockDevice::new(100, 512);
+        device.data[10 * 512 + 200] = 0xAA;
+        let verify = |bytchk: u8, lba: u8, blocks: u8| [0x2F, bytchk << 1, 0, 0, 0, lba, 0, 0, blocks, 0];
+
+        // BYTCHK=00: medium readability only
+        let response = ScsiHandler::handle_command(&verify(0, 0, 50), &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(ScsiHandler::data_out_length(&verify(0, 0, 50), &device).unwrap(), None);
+
+        // BYTCHK=01: compare every block
+        let cdb = verify(1, 9, 2);
+        assert_eq!(ScsiHandler::data_out_length(&cdb, &device).unwrap(), Some(1024));
+        let mut data = vec![0u8; 1024];
+        let response = ScsiHandler::handle_command(&cdb, &device, Some(&data)).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        let sense = response.sense.unwrap();
+        assert_eq!(sense.sense_key, sense_key::MISCOMPARE);
+        assert_eq!(sense.asc, asc::MISCOMPARE_DURING_VERIFY);
+        assert_eq!(sense.information, 712);
+
+        data[712] = 0xAA;
+        let response = ScsiHandler::handle_command(&cdb, &device, Some(&data)).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+
+        // BYTCHK=11: one block of Data-Out compared with each block
+        let cdb = verify(3, 20, 4);
+        assert_eq!(ScsiHandler::data_out_length(&cdb, &device).unwrap(), Some(512));
+        let response = ScsiHandler::handle_command(&cdb, &device, Some(&[0u8; 512])).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+
+        // BYTCHK=10 is reserved
+        let response = ScsiHandler::handle_command(&verify(2, 0, 1), &device, None).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_FIELD_IN_CDB);
+
+        // Compare data past the end of the medium is refused up front
+        assert!(ScsiHandler::data_out_length(&verify(1, 99, 2), &device).is_err());
+    }
+
+    #[test]
+    fn test_power_conditions() {
+        struct PoweredDevice {
+            inner: MockDevice,
+            condition: PowerCondition,
+            ejected: bool,
+            flushes: u32,
+        }
+
+        impl ScsiBlockDevice for PoweredDevice {
+            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                self.inner.read(lba, blocks, block_size)
+            }
+            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+                self.inner.write(lba, data, block_size)
+            }
+            fn capacity(&self) -> u64 {
+                self.inner.capacity()
+            }
+            fn block_size(&self) -> u32 {
+                self.inner.block_size()
+            }
+            fn flush(&mut self) -> ScsiResult<()> {
+                self.flushes += 1;
+                Ok(())
+            }
+            fn power_condition(&self) -> PowerCondition {
+                self.condition
+            }
+            fn start_stop_unit(&mut self, condition: PowerCondition, load_eject: bool) -> ScsiResult<()> {
+                self.condition = condition;
+                self.ejected = load_eject && condition == PowerCondition::Stopped;
+                Ok(())
+            }
+            fn prefetch(&self, _lba: u64, blocks: u32) -> ScsiResult<bool> {
+                Ok(blocks <= 16)
+            }
+        }
+
+        let mut device = PoweredDevice {
+            inner: MockDevice::new(100, 512),
+            condition: PowerCondition::Active,
+            ejected: false,
+            flushes: 0,
+        };
+        let tur = [0x00, 0, 0, 0, 0, 0];
+        let read = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        let request_sense = [0x03, 0, 0, 0, 18, 0];
+
+        // STOP with LOEJ: flush, eject, then NOT READY for medium access
+        let response = ScsiHandler::handle_start_stop_unit(&[0x1B, 0, 0, 0, 0x02, 0], &mut device).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(device.condition, PowerCondition::Stopped);
+        assert!(device.ejected);
+        assert_eq!(device.flushes, 1);
+        for cdb in [&tur[..], &read[..]] {
+            let response = ScsiHandler::handle_command(cdb, &device, None).unwrap();
+            let sense = response.sense.unwrap();
+            assert_eq!((sense.sense_key, sense.asc, sense.ascq), (sense_key::NOT_READY, asc::LOGICAL_UNIT_NOT_READY, 0x02));
+        }
+        let response = ScsiHandler::handle_command(&request_sense, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data[2], sense_key::NOT_READY);
+
+        // Standby via the POWER CONDITION field: ready, reported by REQUEST SENSE
+        ScsiHandler::handle_start_stop_unit(&[0x1B, 0, 0, 0, 0x30, 0], &mut device).unwrap();
+        assert_eq!(device.condition, PowerCondition::Standby);
+        assert_eq!(ScsiHandler::handle_command(&tur, &device, None).unwrap().status, scsi_status::GOOD);
+        let response = ScsiHandler::handle_command(&request_sense, &device, None).unwrap();
+        assert_eq!((response.data[12], response.data[13]), (asc::LOW_POWER_CONDITION_ON, 0x04));
+
+        // Starting needs no flush; reserved power conditions are rejected
+        ScsiHandler::handle_start_stop_unit(&[0x1B, 0, 0, 0, 0x01, 0], &mut device).unwrap();
+        assert_eq!(device.condition, PowerCondition::Active);
+        assert_eq!(device.flushes, 1);
+        let response = ScsiHandler::handle_start_stop_unit(&[0x1B, 0, 0, 0, 0x50, 0], &mut device).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_FIELD_IN_CDB);
+
+        // PRE-FETCH: CONDITION MET when the range fits the cache, range checked
+        let response = ScsiHandler::handle_command(&[0x34, 0, 0, 0, 0, 10, 0, 0, 8, 0], &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CONDITION_MET);
+        let response = ScsiHandler::handle_command(&[0x34, 0, 0, 0, 0, 10, 0, 0, 0, 0], &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        let response = ScsiHandler::handle_command(&[0x34, 0, 0, 0, 0, 99, 0, 0, 2, 0], &device, None).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::LBA_OUT_OF_RANGE);
+    }
+
     #[test]
     fn test_verify() {
         let device = MockDevice::new(1000, 512);
@@ -988,4 +2866,239 @@ mod tests {
         let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
         assert_eq!(response.status, scsi_status::GOOD);
     }
+
+    #[test]
+    fn test_device_errors_map_to_sense() {
+        struct FailingDevice(ScsiDeviceError);
+
+        impl ScsiBlockDevice for FailingDevice {
+            fn read(&self, _lba: u64, _blocks: u32, _block_size: u32) -> ScsiResult<Vec<u8>> {
+                Err(self.0.into())
+            }
+
+            fn write(&mut self, _lba: u64, _data: &[u8], _block_size: u32) -> ScsiResult<()> {
+                Err(self.0.into())
+            }
+
+            fn capacity(&self) -> u64 {
+                1000
+            }
+
+            fn block_size(&self) -> u32 {
+                512
+            }
+        }
+
+        let read = [0x28, 0, 0, 0, 0, 10, 0, 0, 1, 0];
+        let cases = [
+            (ScsiDeviceError::NotReady, (sense_key::NOT_READY, asc::LOGICAL_UNIT_NOT_READY, 0x00)),
+            (ScsiDeviceError::MediumError, (sense_key::MEDIUM_ERROR, asc::UNRECOVERED_READ_ERROR, 0x00)),
+            (ScsiDeviceError::OutOfRange { lba: 10 }, (sense_key::ILLEGAL_REQUEST, asc::LBA_OUT_OF_RANGE, 0x00)),
+            (ScsiDeviceError::WriteProtected, (sense_key::DATA_PROTECT, asc::WRITE_PROTECTED, 0x00)),
+            (ScsiDeviceError::Custom(SenseCode::new(sense_key::HARDWARE_ERROR, 0x3E, 0x02)), (sense_key::HARDWARE_ERROR, 0x3E, 0x02)),
+        ];
+        for (error, (key, code, qualifier)) in cases {
+            let response = ScsiHandler::handle_command(&read, &FailingDevice(error), None).unwrap();
+            assert_eq!(response.status, scsi_status::CHECK_CONDITION, "{:?}", error);
+            let sense = response.sense.unwrap();
+            assert_eq!((sense.sense_key, sense.asc, sense.ascq), (key, code, qualifier), "{:?}", error);
+        }
+
+        // The out-of-range LBA is reported in the information field
+        let response = ScsiHandler::handle_command(&read, &FailingDevice(ScsiDeviceError::OutOfRange { lba: 10 }), None).unwrap();
+        let sense = response.sense.unwrap();
+        assert!(sense.info_valid);
+        assert_eq!(sense.information, 10);
+
+        // BUSY is a status, without sense
+        let response = ScsiHandler::handle_command(&read, &FailingDevice(ScsiDeviceError::Busy), None).unwrap();
+        assert_eq!(response.status, scsi_status::BUSY);
+        assert!(response.sense.is_none());
+
+        // Errors without sense remain MEDIUM ERROR
+        let response = ScsiResponse::from_error(&IscsiError::scsi("backend failure"));
+        assert_eq!(response.sense.unwrap().sense_key, sense_key::MEDIUM_ERROR);
+    }
+
+    /// Device storing protection information next to each block
+    struct ProtectedDevice {
+        protection_type: u8,
+        data: Vec<u8>,
+        pi: Vec<ProtectionInfo>,
+    }
+
+    impl ProtectedDevice {
+        fn new(protection_type: u8) -> Self {
+            ProtectedDevice {
+                protection_type,
+                data: vec![0u8; 100 * 512],
+                pi: vec![ProtectionInfo::default(); 100],
+            }
+        }
+    }
+
+    impl ScsiBlockDevice for ProtectedDevice {
+        fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+            let offset = (lba * block_size as u64) as usize;
+            Ok(self.data[offset..offset + (blocks * block_size) as usize].to_vec())
+        }
+
+        fn write(&mut self, _lba: u64, _data: &[u8], _block_size: u32) -> ScsiResult<()> {
+            panic!("protected writes go through write_with_pi");
+        }
+
+        fn capacity(&self) -> u64 {
+            100
+        }
+
+        fn block_size(&self) -> u32 {
+            512
+        }
+
+        fn protection_type(&self) -> u8 {
+            self.protection_type
+        }
+
+        fn read_with_pi(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<(Vec<u8>, Vec<ProtectionInfo>)> {
+            let pi = self.pi[lba as usize..lba as usize + blocks as usize].to_vec();
+            Ok((self.read(lba, blocks, block_size)?, pi))
+        }
+
+        fn write_with_pi(&mut self, lba: u64, data: &[u8], pi: &[ProtectionInfo], block_size: u32) -> ScsiResult<()> {
+            let offset = (lba * block_size as u64) as usize;
+            self.data[offset..offset + data.len()].copy_from_slice(data);
+            self.pi[lba as usize..lba as usize + pi.len()].copy_from_slice(pi);
+            Ok(())
+        }
+    }
+
+    fn rw10(opcode: u8, protect: u8, lba: u32, blocks: u16) -> [u8; 10] {
+        let mut cdb = [opcode, protect << 5, 0, 0, 0, 0, 0, 0, 0, 0];
+        BigEndian::write_u32(&mut cdb[2..6], lba);
+        BigEndian::write_u16(&mut cdb[7..9], blocks);
+        cdb
+    }
+
+    fn rw32(service_action: u16, protect: u8, lba: u64, blocks: u32, ref_tag: u32) -> [u8; 32] {
+        let mut cdb = [0u8; 32];
+        cdb[0] = 0x7F;
+        cdb[7] = 0x18;
+        BigEndian::write_u16(&mut cdb[8..10], service_action);
+        cdb[10] = protect << 5;
+        BigEndian::write_u64(&mut cdb[12..20], lba);
+        BigEndian::write_u32(&mut cdb[20..24], ref_tag);
+        BigEndian::write_u32(&mut cdb[28..32], blocks);
+        cdb
+    }
+
+    #[test]
+    fn test_protection_advertised() {
+        for (protection_type, ext_byte) in [(0, 0x00), (1, 0x07), (2, 0x17), (3, 0x26)] {
+            let device = ProtectedDevice::new(protection_type);
+
+            let response = ScsiHandler::handle_command(&[0x12, 0, 0, 0, 96, 0], &device, None).unwrap();
+            assert_eq!(response.data[5] & 0x01, (protection_type > 0) as u8);
+
+            let response = ScsiHandler::handle_command(&[0x12, 1, 0x86, 0, 64, 0], &device, None).unwrap();
+            assert_eq!(response.status, scsi_status::GOOD);
+            assert_eq!(response.data[1], 0x86);
+            assert_eq!(response.data[4], ext_byte);
+            assert_eq!(response.data[5], 0x1C);
+        }
+    }
+
+    #[test]
+    fn test_protected_write_and_read() {
+        let mut device = ProtectedDevice::new(1);
+        let data = vec![0xA5u8; 1024];
+        let tagged = |lba: u64, app_tag: u16| {
+            let pi: Vec<_> = data
+                .chunks(512)
+                .zip(lba..)
+                .map(|(block, lba)| ProtectionInfo::generate(block, app_tag, lba as u32))
+                .collect();
+            protection::interleave(&data, &pi, 512)
+        };
+        let transfer = |cdb: &[u8]| PiTransfer::from_cdb(cdb, 10, 1).unwrap().unwrap();
+
+        // WRPROTECT=1: the initiator's PI is checked and stored
+        let write = transfer(&rw10(0x2A, 1, 10, 2));
+        let wire = tagged(10, 0x1234);
+        let response = ScsiHandler::handle_command(&rw10(0x2A, 1, 10, 2), &device, Some(&wire)).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        ScsiHandler::write_blocks(&mut device, 10, &wire, 512, Some(&write)).unwrap();
+        assert_eq!(device.pi[11].app_tag, 0x1234);
+
+        // RDPROTECT=1 returns it interleaved, RDPROTECT=0 strips it
+        let response = ScsiHandler::handle_command(&rw10(0x28, 1, 10, 2), &device, None).unwrap();
+        assert_eq!(response.data, wire);
+        let response = ScsiHandler::handle_command(&rw10(0x28, 0, 10, 2), &device, None).unwrap();
+        assert_eq!(response.data, data);
+
+        // A reference tag not matching the LBA is refused before reaching the device
+        let misplaced = tagged(20, 0);
+        let response = ScsiHandler::handle_command(&rw10(0x2A, 1, 10, 2), &device, Some(&misplaced)).unwrap();
+        assert_eq!(response.sense.unwrap().ascq, 0x03);
+        let response = ScsiHandler::write_blocks(&mut device, 10, &misplaced, 512, Some(&write)).unwrap_err();
+        let sense = response.sense.unwrap();
+        assert_eq!((sense.sense_key, sense.asc, sense.ascq), (sense_key::ABORTED_COMMAND, 0x10, 0x03));
+        assert_eq!(sense.information, 10);
+        assert_eq!(device.pi[10].app_tag, 0x1234);
+
+        // Corrupted data is caught on the next read
+        device.data[11 * 512] ^= 0xFF;
+        let response = ScsiHandler::handle_command(&rw10(0x28, 0, 10, 2), &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::CHECK_CONDITION);
+        let sense = response.sense.unwrap();
+        assert_eq!((sense.sense_key, sense.ascq, sense.information), (sense_key::ABORTED_COMMAND, 0x01, 11));
+        // ...unless RDPROTECT=3 disables checking
+        let response = ScsiHandler::handle_command(&rw10(0x28, 3, 10, 2), &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+
+        // WRPROTECT=0: the target inserts PI
+        let write = transfer(&rw10(0x2A, 0, 10, 2));
+        ScsiHandler::write_blocks(&mut device, 10, &data, 512, Some(&write)).unwrap();
+        assert_eq!(device.pi[11], ProtectionInfo::generate(&data[512..], 0, 11));
+    }
+
+    #[test]
+    fn test_read_write_32() {
+        let data = vec![0x3Cu8; 512];
+        let pi = [ProtectionInfo::generate(&data, 0, 0x5000)];
+        let wire = protection::interleave(&data, &pi, 512);
+
+        // Only a Type 2 unit takes 32-byte CDBs; it tags blocks from the CDB
+        let mut device = ProtectedDevice::new(2);
+        let cdb = rw32(variable_length::WRITE_32, 1, 7, 1, 0x5000);
+        let response = ScsiHandler::handle_command(&cdb, &device, Some(&wire)).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        let write = PiTransfer::from_cdb(&cdb, 7, 2).unwrap().unwrap();
+        ScsiHandler::write_blocks(&mut device, 7, &wire, 512, Some(&write)).unwrap();
+
+        let cdb = rw32(variable_length::READ_32, 1, 7, 1, 0x5000);
+        assert_eq!(ScsiHandler::lba_range(&cdb), Some((7, 1)));
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(response.data, wire);
+
+        let cdb = rw32(variable_length::READ_32, 1, 7, 1, 0x6000);
+        let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+        assert_eq!(response.sense.unwrap().ascq, 0x03);
+
+        // ...and rejects RDPROTECT in the short forms
+        let response = ScsiHandler::handle_command(&rw10(0x28, 1, 7, 1), &device, None).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_COMMAND_OPERATION_CODE);
+
+        for protection_type in [0, 1, 3] {
+            let device = ProtectedDevice::new(protection_type);
+            let cdb = rw32(variable_length::READ_32, 0, 7, 1, 7);
+            let response = ScsiHandler::handle_command(&cdb, &device, None).unwrap();
+            assert_eq!(response.sense.unwrap().asc, asc::INVALID_COMMAND_OPERATION_CODE);
+        }
+
+        // RDPROTECT on an unprotected unit is an invalid field
+        let device = ProtectedDevice::new(0);
+        let response = ScsiHandler::handle_command(&rw10(0x28, 1, 7, 1), &device, None).unwrap();
+        assert_eq!(response.sense.unwrap().asc, asc::INVALID_FIELD_IN_CDB);
+    }
 }
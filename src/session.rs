@@ -3,10 +3,31 @@
 //! This module handles session state, connection management, and parameter negotiation
 //! based on RFC 3720: https://datatracker.ietf.org/doc/html/rfc3720
 
-use crate::auth::{AuthConfig, ChapAuthState};
-use crate::error::{IscsiError, ScsiResult};
-use crate::pdu::{self, IscsiPdu, LoginRequest, serialize_text_parameters};
-use std::collections::HashMap;
+use crate::auth::{AuthConfig, ChapAlgorithm, ChapAuthState};
+use crate::control::{initiator_matches, ClaimedSession, TargetControl};
+use crate::error::{AuthFailure, IscsiError, ProtocolErrorKind, ScsiResult};
+use crate::events::{TargetEvent, TargetObserver};
+use crate::pdu::{self, Digests, IscsiPdu, LoginRequest, serialize_text_parameters};
+use crate::portal::IpNetwork;
+use crate::protection::PiTransfer;
+use crate::target::InlineDeadline;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Private Text key echoed back by the target for in-band latency/MTU checks
+pub const DIAGNOSTIC_ECHO_KEY: &str = "X-diagnostic.echo";
+/// Private Text key carrying the target's receive time (microseconds since the UNIX epoch)
+pub const DIAGNOSTIC_TIMESTAMP_KEY: &str = "X-diagnostic.timestamp";
+
+/// MaxRecvDataSegmentLength in effect during login (RFC 3720 default)
+pub const LOGIN_MAX_RECV_DATA_SEGMENT_LENGTH: u32 = 8192;
+
+/// Upper bound on Login Request text buffered across PDUs with the C bit
+pub const MAX_LOGIN_TEXT_LENGTH: usize = 65536;
 
 /// Session state machine states (RFC 3720 Section 5)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -31,6 +52,7 @@ pub enum SessionState {
 /// Session type (RFC 3720 Section 5.2)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SessionType {
     /// Normal session for SCSI commands
     #[default]
@@ -41,7 +63,8 @@ pub enum SessionType {
 
 
 /// Negotiated session parameters (RFC 3720 Section 12)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SessionParams {
     // Connection parameters
     /// Maximum data segment length target can receive (default: 8192)
@@ -86,21 +109,96 @@ pub struct SessionParams {
     pub target_alias: String,
     /// Initiator alias (optional)
     pub initiator_alias: String,
+    /// Target Portal Group Tag of the portal this connection arrived on
+    pub target_portal_group_tag: u16,
 
     // Validation tracking
     /// Invalid session type received (for error reporting)
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub(crate) invalid_session_type: Option<String>,
 }
 
 /// Digest type for header/data
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DigestType {
     #[default]
     None,
     CRC32C,
 }
 
+/// How strictly the target holds initiators to RFC 3720
+///
+/// Permissive, the default, tolerates deviations seen from real initiators.
+/// Strict rejects a login that declares a key twice (Section 5.3) or sends a
+/// malformed key name (Section 5.1), and ignores any non-immediate command
+/// whose CmdSN is not the next one expected, where Permissive executes any
+/// command within the window; those are then rejected as protocol errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Strictness {
+    /// Reject protocol violations
+    Strict,
+    /// Keep interoperating with initiators that bend the rules
+    #[default]
+    Permissive,
+}
+
+/// What to do with a request given its CmdSN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdSnCheck {
+    /// Deliver the request
+    Accepted,
+    /// Before ExpCmdSN, a request already received: drop it silently
+    Duplicate,
+    /// In the window but after ExpCmdSN: hold it until the gap fills
+    Early,
+    /// Outside the window: ignore it silently (RFC 7143 Section 4.2.2.1)
+    OutOfWindow,
+}
+
+impl SessionParams {
+    /// Record the negotiated outcome `key=value`; false if the key or value
+    /// is not one of these parameters
+    ///
+    /// MaxRecvDataSegmentLength is declared separately by each side, so it
+    /// is left to the caller.
+    pub(crate) fn set_negotiated(&mut self, key: &str, value: &str) -> bool {
+        let number = value.parse::<u32>().ok();
+        let flag = match value {
+            "Yes" => Some(true),
+            "No" => Some(false),
+            _ => None,
+        };
+        let digest = match value {
+            "CRC32C" => Some(DigestType::CRC32C),
+            "None" => Some(DigestType::None),
+            _ => None,
+        };
+        match (key, number, flag, digest) {
+            ("MaxBurstLength", Some(n), ..) => self.max_burst_length = n,
+            ("FirstBurstLength", Some(n), ..) => self.first_burst_length = n,
+            ("DefaultTime2Wait", Some(n), ..) => self.default_time2wait = n as u16,
+            ("DefaultTime2Retain", Some(n), ..) => self.default_time2retain = n as u16,
+            ("MaxOutstandingR2T", Some(n), ..) => self.max_outstanding_r2t = n,
+            ("ErrorRecoveryLevel", Some(n), ..) => self.error_recovery_level = n as u8,
+            ("TargetPortalGroupTag", Some(n), ..) => self.target_portal_group_tag = n as u16,
+            ("DataPDUInOrder", _, Some(flag), _) => self.data_pdu_in_order = flag,
+            ("DataSequenceInOrder", _, Some(flag), _) => self.data_sequence_in_order = flag,
+            ("ImmediateData", _, Some(flag), _) => self.immediate_data = flag,
+            ("InitialR2T", _, Some(flag), _) => self.initial_r2t = flag,
+            ("HeaderDigest", _, _, Some(digest)) => self.header_digest = digest,
+            ("DataDigest", _, _, Some(digest)) => self.data_digest = digest,
+            ("TargetName", ..) => self.target_name = value.to_string(),
+            ("InitiatorName", ..) => self.initiator_name = value.to_string(),
+            ("TargetAlias", ..) => self.target_alias = value.to_string(),
+            ("InitiatorAlias", ..) => self.initiator_alias = value.to_string(),
+            _ => return false,
+        }
+        true
+    }
+}
 
 impl Default for SessionParams {
     fn default() -> Self {
@@ -123,13 +221,115 @@ impl Default for SessionParams {
             initiator_name: String::new(),
             target_alias: String::new(),
             initiator_alias: String::new(),
+            target_portal_group_tag: crate::portal::DEFAULT_TPGT,
             invalid_session_type: None,
         }
     }
 }
 
+// ============================================================================
+// Key negotiation table (RFC 3720 Section 5.2 and Section 12)
+// ============================================================================
+
+/// How the outcome of a key is derived from both sides' values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NegotiationRule {
+    /// Declared by the sender; the receiver records the value as-is
+    Declarative,
+    /// Numerical result is the smaller of the two values
+    Minimum,
+    /// Numerical result is the larger of the two values
+    Maximum,
+    /// Boolean result is Yes only if both sides say Yes
+    And,
+    /// Boolean result is Yes if either side says Yes
+    Or,
+    /// Result is the target's most preferred value the initiator offered
+    List,
+}
+
+impl NegotiationRule {
+    fn number(self, ours: u32, theirs: u32) -> u32 {
+        match self {
+            NegotiationRule::Minimum => ours.min(theirs),
+            NegotiationRule::Maximum => ours.max(theirs),
+            _ => theirs,
+        }
+    }
+
+    fn boolean(self, ours: bool, theirs: bool) -> bool {
+        match self {
+            NegotiationRule::And => ours && theirs,
+            NegotiationRule::Or => ours || theirs,
+            _ => theirs,
+        }
+    }
+}
+
+/// Value domain of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyType {
+    /// Decimal number within an inclusive range
+    Number { min: u32, max: u32 },
+    /// "Yes" or "No"
+    Boolean,
+    /// Comma-separated offer; supported values in target preference order
+    List(&'static [&'static str]),
+    /// Free-form text, validated by the code that consumes it
+    Text,
+}
+
+/// A key the target understands during login and text negotiation
+#[derive(Debug, Clone, Copy)]
+struct KeyDef {
+    name: &'static str,
+    key_type: KeyType,
+    rule: NegotiationRule,
+}
+
+const fn def(name: &'static str, key_type: KeyType, rule: NegotiationRule) -> KeyDef {
+    KeyDef { name, key_type, rule }
+}
+
+const DATA_SEGMENT_RANGE: KeyType = KeyType::Number { min: 512, max: 16_777_215 };
+const DIGESTS: KeyType = KeyType::List(&["CRC32C", "None"]);
+
+/// Every key the target accepts from an initiator; anything else is NotUnderstood
+const NEGOTIATION_KEYS: &[KeyDef] = &[
+    def("InitiatorName", KeyType::Text, NegotiationRule::Declarative),
+    def("InitiatorAlias", KeyType::Text, NegotiationRule::Declarative),
+    def("TargetName", KeyType::Text, NegotiationRule::Declarative),
+    def("SessionType", KeyType::Text, NegotiationRule::Declarative),
+    // Consumed by handle_chap_auth()
+    def("AuthMethod", KeyType::Text, NegotiationRule::List),
+    def("CHAP_A", KeyType::Text, NegotiationRule::List),
+    def("CHAP_I", KeyType::Text, NegotiationRule::Declarative),
+    def("CHAP_C", KeyType::Text, NegotiationRule::Declarative),
+    def("CHAP_N", KeyType::Text, NegotiationRule::Declarative),
+    def("CHAP_R", KeyType::Text, NegotiationRule::Declarative),
+    def("HeaderDigest", DIGESTS, NegotiationRule::List),
+    def("DataDigest", DIGESTS, NegotiationRule::List),
+    def("MaxConnections", KeyType::Number { min: 1, max: 65535 }, NegotiationRule::Minimum),
+    def("MaxRecvDataSegmentLength", DATA_SEGMENT_RANGE, NegotiationRule::Declarative),
+    def("MaxBurstLength", DATA_SEGMENT_RANGE, NegotiationRule::Minimum),
+    def("FirstBurstLength", DATA_SEGMENT_RANGE, NegotiationRule::Minimum),
+    def("DefaultTime2Wait", KeyType::Number { min: 0, max: 3600 }, NegotiationRule::Maximum),
+    def("DefaultTime2Retain", KeyType::Number { min: 0, max: 3600 }, NegotiationRule::Minimum),
+    def("MaxOutstandingR2T", KeyType::Number { min: 1, max: 65535 }, NegotiationRule::Minimum),
+    def("DataPDUInOrder", KeyType::Boolean, NegotiationRule::Or),
+    def("DataSequenceInOrder", KeyType::Boolean, NegotiationRule::Or),
+    def("ErrorRecoveryLevel", KeyType::Number { min: 0, max: 2 }, NegotiationRule::Minimum),
+    def("ImmediateData", KeyType::Boolean, NegotiationRule::And),
+    def("InitialR2T", KeyType::Boolean, NegotiationRule::Or),
+    // Markers are not implemented, so they always negotiate to No
+    def("OFMarker", KeyType::Boolean, NegotiationRule::And),
+    def("IFMarker", KeyType::Boolean, NegotiationRule::And),
+    def("OFMarkInt", KeyType::Text, NegotiationRule::Declarative),
+    def("IFMarkInt", KeyType::Text, NegotiationRule::Declarative),
+];
+
 /// Pending write command information
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct PendingWrite {
     /// Logical Block Address from the WRITE command
     pub lba: u64,
@@ -139,12 +339,362 @@ pub struct PendingWrite {
     pub block_size: u32,
     /// Total bytes received so far
     pub bytes_received: u32,
-    /// Target Transfer Tag (used for R2T correlation)
-    pub ttt: u32,
     /// R2T sequence number (incremented for each R2T sent)
     pub r2t_sn: u32,
     /// LUN for this command
     pub lun: u64,
+    /// LUN field of the command as sent, echoed in its R2Ts
+    pub lun_field: u64,
+    /// End of the unsolicited data window (immediate data plus unsolicited Data-Out)
+    pub unsolicited_end: u32,
+    /// DataSN expected on the next unsolicited Data-Out
+    pub unsolicited_data_sn: u32,
+    /// Buffer offset expected on the next unsolicited Data-Out
+    pub unsolicited_offset: u32,
+    /// Offset of the first byte not yet requested by an R2T
+    pub next_r2t_offset: u32,
+    /// R2Ts whose Data-Out sequence has not completed, oldest first
+    pub outstanding_r2ts: Vec<OutstandingR2t>,
+    /// When the command or its latest Data-Out arrived
+    pub last_activity: Option<Instant>,
+    /// Times the outstanding R2Ts have been retransmitted without progress
+    pub retransmits: u32,
+    /// Protection information handling, for a unit formatted with protection
+    pub protection: Option<PiTransfer>,
+    /// Data of a protected WRITE gathered so far; blocks with interleaved PI
+    /// may straddle Data-Out PDUs, so it is written once complete
+    pub protected_data: Vec<u8>,
+    /// Latest LUN change notice when the command arrived
+    pub lun_changes: u64,
+    /// FUA was set: flush once all data is written, before the status
+    pub fua: bool,
+    /// Operation code of the WRITE command
+    pub opcode: u8,
+}
+
+/// An R2T still waiting on its solicited Data-Out sequence
+#[derive(Debug, Clone)]
+pub struct OutstandingR2t {
+    /// Target Transfer Tag of the R2T
+    pub ttt: u32,
+    /// R2TSN of the R2T (reused when it is retransmitted)
+    pub r2t_sn: u32,
+    /// Buffer offset requested by the R2T
+    pub offset: u32,
+    /// Desired Data Transfer Length requested by the R2T
+    pub length: u32,
+    /// DataSN expected on the next Data-Out of this sequence
+    pub next_data_sn: u32,
+    /// Bytes received so far for this sequence
+    pub bytes_received: u32,
+}
+
+impl PendingWrite {
+    /// Total bytes the WRITE transfers
+    pub fn total_bytes(&self) -> u32 {
+        self.transfer_length * self.transfer_block_size()
+    }
+
+    /// Copy data received at `offset` into `protected_data`
+    pub fn gather(&mut self, offset: u32, data: &[u8]) {
+        let start = offset as usize;
+        let end = start + data.len();
+        if self.protected_data.len() < end {
+            self.protected_data.resize(end, 0);
+        }
+        self.protected_data[start..end].copy_from_slice(data);
+    }
+
+    /// Bytes per block on the wire, including any interleaved PI
+    pub fn transfer_block_size(&self) -> u32 {
+        self.protection
+            .map_or(self.block_size, |protection| protection.transfer_block_size(self.block_size))
+    }
+
+    /// Validate a Data-Out PDU against the unsolicited window and the
+    /// outstanding R2Ts, and account for its data.
+    ///
+    /// Returns true if the PDU completed an R2T sequence, so another R2T may
+    /// be issued. DataSN gaps, offsets outside the window the data belongs
+    /// to, and (with DataPDUInOrder=Yes) non-contiguous offsets are protocol
+    /// errors.
+    pub fn accept_data_out(&mut self, data_out: &pdu::ScsiDataOutPdu, params: &SessionParams) -> ScsiResult<bool> {
+        let completed = check_data_out(
+            data_out,
+            params,
+            self.unsolicited_end,
+            &mut self.unsolicited_data_sn,
+            &mut self.unsolicited_offset,
+            &mut self.outstanding_r2ts,
+        )?;
+        self.bytes_received += data_out.data.len() as u32;
+        self.last_activity = Some(Instant::now());
+        self.retransmits = 0;
+        Ok(completed)
+    }
+
+    /// Restart every outstanding R2T sequence from its beginning
+    ///
+    /// Data already received for those sequences is discarded from the
+    /// accounting, since the initiator answers a retransmitted R2T with the
+    /// whole sequence again.
+    pub fn restart_r2t_sequences(&mut self, now: Instant) {
+        for r2t in &mut self.outstanding_r2ts {
+            self.bytes_received -= r2t.bytes_received;
+            r2t.bytes_received = 0;
+            r2t.next_data_sn = 0;
+        }
+        self.retransmits += 1;
+        self.last_activity = Some(now);
+    }
+}
+
+/// Validate a Data-Out PDU against its command's unsolicited window and
+/// outstanding R2Ts
+///
+/// Shared by WRITEs and parameter lists. Returns true if the PDU completed
+/// an R2T sequence.
+fn check_data_out(
+    data_out: &pdu::ScsiDataOutPdu,
+    params: &SessionParams,
+    unsolicited_end: u32,
+    unsolicited_data_sn: &mut u32,
+    unsolicited_offset: &mut u32,
+    outstanding_r2ts: &mut Vec<OutstandingR2t>,
+) -> ScsiResult<bool> {
+    let len = data_out.data.len() as u32;
+    let offset = data_out.buffer_offset;
+    let end = offset.checked_add(len)
+        .ok_or_else(|| IscsiError::protocol(ProtocolErrorKind::DataSequence, "Data-Out buffer offset overflows".to_string()))?;
+
+    let completed = if data_out.ttt == 0xFFFF_FFFF {
+        if end > unsolicited_end {
+            return Err(IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                "unsolicited Data-Out {}..{} outside unsolicited window ..{}",
+                offset, end, unsolicited_end
+            )));
+        }
+        if data_out.data_sn != *unsolicited_data_sn {
+            return Err(IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                "unsolicited Data-Out DataSN {} (expected {})",
+                data_out.data_sn, *unsolicited_data_sn
+            )));
+        }
+        if params.data_pdu_in_order && offset != *unsolicited_offset {
+            return Err(IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                "unsolicited Data-Out offset {} out of order (expected {})",
+                offset, *unsolicited_offset
+            )));
+        }
+        *unsolicited_data_sn += 1;
+        *unsolicited_offset = (*unsolicited_offset).max(end);
+        false
+    } else {
+        let index = outstanding_r2ts.iter()
+            .position(|r2t| r2t.ttt == data_out.ttt)
+            .ok_or_else(|| IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                "Data-Out TTT 0x{:08x} matches no outstanding R2T", data_out.ttt
+            )))?;
+        if params.data_sequence_in_order && index != 0 {
+            return Err(IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                "Data-Out for R2T TTT 0x{:08x} before earlier sequences completed", data_out.ttt
+            )));
+        }
+
+        let r2t = &mut outstanding_r2ts[index];
+        if data_out.data_sn != r2t.next_data_sn {
+            return Err(IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                "Data-Out DataSN {} (expected {})", data_out.data_sn, r2t.next_data_sn
+            )));
+        }
+        if offset < r2t.offset || end > r2t.offset + r2t.length {
+            return Err(IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                "Data-Out {}..{} outside R2T window {}..{}",
+                offset, end, r2t.offset, r2t.offset + r2t.length
+            )));
+        }
+        if params.data_pdu_in_order && offset != r2t.offset + r2t.bytes_received {
+            return Err(IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                "Data-Out offset {} out of order (expected {})",
+                offset, r2t.offset + r2t.bytes_received
+            )));
+        }
+        r2t.next_data_sn += 1;
+        r2t.bytes_received += len;
+
+        if data_out.final_flag {
+            if r2t.bytes_received != r2t.length {
+                return Err(IscsiError::protocol(ProtocolErrorKind::DataSequence, format!(
+                    "R2T sequence ended after {} of {} bytes", r2t.bytes_received, r2t.length
+                )));
+            }
+            outstanding_r2ts.remove(index);
+            true
+        } else {
+            false
+        }
+    };
+
+    Ok(completed)
+}
+
+/// The unsent remainder of a Text Response split across PDUs
+///
+/// The initiator asks for each further PDU with an empty Text Request
+/// carrying the ITT and TTT of the previous response.
+#[derive(Debug, Clone)]
+pub struct PendingTextResponse {
+    /// Initiator Task Tag of the original Text Request
+    pub itt: u32,
+    /// Target Transfer Tag sent with the last response PDU
+    pub ttt: u32,
+    /// Response text not yet sent
+    pub remaining: Vec<u8>,
+}
+
+/// A command whose Data-Out buffer (MODE SELECT parameter list, VERIFY
+/// compare data) is still arriving via R2T
+#[derive(Debug, Clone)]
+pub struct PendingParameterList {
+    /// CDB of the command, applied once the parameter list is complete
+    pub cdb: Vec<u8>,
+    /// Parameter list buffer (sized to the full parameter list length)
+    pub data: Vec<u8>,
+    /// Total bytes received so far
+    pub bytes_received: u32,
+    /// LUN for this command
+    pub lun: u64,
+    /// LUN field of the command as sent, echoed in its R2Ts
+    pub lun_field: u64,
+    /// End of the unsolicited data window (immediate data plus unsolicited Data-Out)
+    pub unsolicited_end: u32,
+    /// DataSN expected on the next unsolicited Data-Out
+    pub unsolicited_data_sn: u32,
+    /// Buffer offset expected on the next unsolicited Data-Out
+    pub unsolicited_offset: u32,
+    /// Offset the next R2T will request from (all requested once it reaches the length)
+    pub next_r2t_offset: u32,
+    /// R2TSN of the next R2T
+    pub r2t_sn: u32,
+    /// R2Ts whose Data-Out sequence has not yet ended, each with its own TTT
+    pub outstanding_r2ts: Vec<OutstandingR2t>,
+    /// Expected Bidirectional Read Data Length, for a bidirectional command
+    /// whose Data-Out this is
+    pub bidi_read_length: Option<u32>,
+}
+
+impl PendingParameterList {
+    /// Validate a Data-Out PDU the same way as a WRITE's (see
+    /// `PendingWrite::accept_data_out`) and copy its data into the buffer
+    ///
+    /// Returns true if the PDU completed an R2T sequence.
+    pub fn accept_data_out(&mut self, data_out: &pdu::ScsiDataOutPdu, params: &SessionParams) -> ScsiResult<bool> {
+        let completed = check_data_out(
+            data_out,
+            params,
+            self.unsolicited_end,
+            &mut self.unsolicited_data_sn,
+            &mut self.unsolicited_offset,
+            &mut self.outstanding_r2ts,
+        )?;
+        let start = data_out.buffer_offset as usize;
+        self.data[start..start + data_out.data.len()].copy_from_slice(&data_out.data);
+        self.bytes_received += data_out.data.len() as u32;
+        Ok(completed)
+    }
+}
+
+/// Session state that can be persisted across a target restart
+///
+/// Taken from a session in full feature phase with `IscsiSession::snapshot()`
+/// and handed back through `TargetControl::restore_sessions()`, so an
+/// initiator reconnecting with the same ISID and TSIH resumes the session
+/// instead of failing with SESSION_DOES_NOT_EXIST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionSnapshot {
+    /// Initiator Session ID
+    pub isid: [u8; 6],
+    /// Target Session Identifying Handle
+    pub tsih: u16,
+    /// Initiator name (IQN)
+    pub initiator_name: String,
+    /// Target name (IQN)
+    pub target_name: String,
+    /// Expected command sequence number from initiator
+    pub exp_cmd_sn: u32,
+    /// Maximum command sequence number initiator can use
+    pub max_cmd_sn: u32,
+    /// Last status sequence number sent
+    pub stat_sn: u32,
+    /// Negotiated session-wide (leading only) parameters as iSCSI text keys
+    pub params: Vec<(String, String)>,
+}
+
+/// Session-wide keys kept in a snapshot; connection keys are renegotiated
+const SNAPSHOT_PARAMS: &[&str] = &[
+    "MaxBurstLength",
+    "FirstBurstLength",
+    "DefaultTime2Wait",
+    "DefaultTime2Retain",
+    "MaxOutstandingR2T",
+    "DataPDUInOrder",
+    "DataSequenceInOrder",
+    "ErrorRecoveryLevel",
+    "ImmediateData",
+    "InitialR2T",
+];
+
+impl SessionSnapshot {
+    /// Serialize as `Key=Value` lines
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "ISID=0x{}\nTSIH={}\nInitiatorName={}\nTargetName={}\nExpCmdSN={}\nMaxCmdSN={}\nStatSN={}\n",
+            hex::encode(self.isid), self.tsih, self.initiator_name, self.target_name,
+            self.exp_cmd_sn, self.max_cmd_sn, self.stat_sn
+        );
+        for (key, value) in &self.params {
+            text.push_str(&format!("{}={}\n", key, value));
+        }
+        text
+    }
+
+    /// Parse the output of `to_text()`
+    pub fn from_text(text: &str) -> ScsiResult<Self> {
+        let invalid = |what: &str| IscsiError::Config(format!("Invalid session snapshot: {}", what));
+
+        let mut fields: HashMap<&str, &str> = HashMap::new();
+        let mut params = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once('=').ok_or_else(|| invalid(line))?;
+            if SNAPSHOT_PARAMS.contains(&key) {
+                params.push((key.to_string(), value.to_string()));
+            } else {
+                fields.insert(key, value);
+            }
+        }
+
+        let field = |key: &str| fields.get(key).copied().ok_or_else(|| invalid(&format!("missing {}", key)));
+        let number = |key: &str| field(key)?.parse::<u32>().map_err(|_| invalid(key));
+
+        let isid_bytes = field("ISID")?
+            .strip_prefix("0x")
+            .and_then(|digits| hex::decode(digits).ok())
+            .ok_or_else(|| invalid("ISID"))?;
+        let isid: [u8; 6] = isid_bytes.try_into().map_err(|_| invalid("ISID"))?;
+        let tsih = field("TSIH")?.parse::<u16>().ok().filter(|&tsih| tsih != 0).ok_or_else(|| invalid("TSIH"))?;
+
+        Ok(Self {
+            isid,
+            tsih,
+            initiator_name: field("InitiatorName")?.to_string(),
+            target_name: field("TargetName")?.to_string(),
+            exp_cmd_sn: number("ExpCmdSN")?,
+            max_cmd_sn: number("MaxCmdSN")?,
+            stat_sn: number("StatSN")?,
+            params,
+        })
+    }
 }
 
 /// iSCSI Session
@@ -182,10 +732,37 @@ pub struct IscsiSession {
     // Command tracking
     /// Pending write commands indexed by ITT (Initiator Task Tag)
     pub pending_writes: HashMap<u32, PendingWrite>,
+    /// Pending parameter list transfers (MODE SELECT) indexed by ITT
+    pub pending_parameter_lists: HashMap<u32, PendingParameterList>,
+    /// Text Response still being continued to the initiator
+    pub pending_text_response: Option<PendingTextResponse>,
+    /// Login Request text data received with the C bit, awaiting the final PDU
+    pub pending_login_text: Vec<u8>,
+    /// ITTs of commands held back behind an ORDERED task; their CmdSN is already taken
+    pub deferred_commands: HashSet<u32>,
+    /// Requests that arrived ahead of ExpCmdSN, by CmdSN, delivered once the gap fills
+    pub held_commands: HashMap<u32, IscsiPdu>,
+    /// LUNs of commands sent with the NACA bit set, by ITT, until their
+    /// status goes out
+    pub naca_tasks: HashMap<u32, u64>,
+    /// MaxCmdSN stays put while the connection cannot keep up with its responses
+    pub command_window_held: bool,
+    /// Generation of the target's LUN table the initiator has been told about
+    pub lun_generation: u64,
+    /// LUN change notices up to this number predate the session
+    pub lun_changes_baseline: u64,
+    /// Latest LUN change notice the initiator has been told about, by LUN number
+    pub lun_changes_seen: HashMap<u64, u64>,
     /// Next Target Transfer Tag (incremented for each new R2T sequence)
     pub next_ttt: u32,
+    /// TTT of the target-initiated NOP-In ping awaiting a NOP-Out reply
+    pub outstanding_ping_ttt: Option<u32>,
     /// Latest sense data to be returned by REQUEST SENSE
     pub last_sense_data: Option<Vec<u8>>,
+    /// Session left for connection recovery by a logout, kept for Time2Retain
+    pub retained_for_recovery: Option<SessionSnapshot>,
+    /// Session this login continues, put back if the login fails
+    pub(crate) claimed_session: Option<ClaimedSession>,
 
     // Authentication
     /// Authentication configuration for this session
@@ -196,8 +773,30 @@ pub struct IscsiSession {
     pub target_chap_state: Option<ChapAuthState>,
     /// Whether CHAP authentication has completed successfully (used to distinguish "never started" from "completed")
     pub chap_completed: bool,
-    /// Access Control List - allowed initiator IQNs (None = allow all)
+    /// Whether initiators may negotiate MD5 CHAP (CHAP_A=5)
+    pub allow_md5_chap: bool,
+    /// Keys already answered during login; each key is negotiated once
+    pub answered_keys: Vec<String>,
+    /// Keys the initiator has sent during login
+    pub declared_keys: Vec<String>,
+    /// How protocol violations are treated
+    pub strictness: Strictness,
+    /// Access Control List - allowed initiator IQNs or patterns (None = allow all)
     pub allowed_initiators: Option<Vec<String>>,
+    /// Networks the initiator may connect from (None = any)
+    pub allowed_networks: Option<Vec<IpNetwork>>,
+    /// Address the initiator connected from, if known
+    pub initiator_addr: Option<IpAddr>,
+    /// Connection arrived on a discovery-only portal (normal logins rejected)
+    pub discovery_only: bool,
+
+    /// Observer notified of session events (None = no notifications)
+    pub observer: Option<Arc<dyn TargetObserver>>,
+    /// Registry allocating TSIHs (None = process-wide counter)
+    pub control: Option<TargetControl>,
+    /// Runs the device calls of commands handled on the connection thread
+    /// under the command deadline (None = called directly)
+    pub(crate) inline_deadline: Option<InlineDeadline>,
 }
 
 impl Default for IscsiSession {
@@ -207,6 +806,72 @@ impl Default for IscsiSession {
 }
 
 impl IscsiSession {
+    /// Largest data segment the target accepts in the current state
+    ///
+    /// RFC 3720 Section 12.12: the declared MaxRecvDataSegmentLength applies
+    /// once the session is in full feature phase; login PDUs use the default.
+    pub fn max_recv_data_segment_limit(&self) -> u32 {
+        if self.state == SessionState::FullFeaturePhase {
+            self.params.max_recv_data_segment_length
+        } else {
+            LOGIN_MAX_RECV_DATA_SEGMENT_LENGTH
+        }
+    }
+
+    /// Digests negotiated for the connection
+    ///
+    /// They apply from the first PDU after the final Login Response.
+    /// Discovery sessions always answer `HeaderDigest=None` and `DataDigest=None`.
+    pub fn digests(&self) -> Digests {
+        if self.session_type == SessionType::Discovery {
+            return Digests::NONE;
+        }
+        Digests {
+            header: self.params.header_digest == DigestType::CRC32C,
+            data: self.params.data_digest == DigestType::CRC32C,
+        }
+    }
+
+    /// Persistable state of a normal session in full feature phase
+    pub fn snapshot(&self) -> Option<SessionSnapshot> {
+        if self.state != SessionState::FullFeaturePhase || self.session_type != SessionType::Normal || self.tsih == 0 {
+            return None;
+        }
+        let params = self.generate_response_params()
+            .into_iter()
+            .filter(|(key, _)| SNAPSHOT_PARAMS.contains(&key.as_str()))
+            .collect();
+        Some(SessionSnapshot {
+            isid: self.isid,
+            tsih: self.tsih,
+            initiator_name: self.params.initiator_name.clone(),
+            target_name: self.params.target_name.clone(),
+            exp_cmd_sn: self.exp_cmd_sn,
+            max_cmd_sn: self.max_cmd_sn,
+            stat_sn: self.stat_sn,
+            params,
+        })
+    }
+
+    /// Resume a snapshotted session on a new connection
+    ///
+    /// Called once the login continuing the session has authenticated. The
+    /// login keeps the snapshot's TSIH and continues its StatSN; the command
+    /// window follows the login's CmdSN and connection parameters are
+    /// negotiated afresh.
+    pub fn restore(&mut self, snapshot: &SessionSnapshot) {
+        self.isid = snapshot.isid;
+        self.tsih = snapshot.tsih;
+        self.params.initiator_name = snapshot.initiator_name.clone();
+        self.stat_sn = snapshot.stat_sn;
+
+        for (key, value) in &snapshot.params {
+            if !SNAPSHOT_PARAMS.contains(&key.as_str()) || !self.params.set_negotiated(key, value) {
+                log::warn!("Ignoring invalid snapshot parameter {}={}", key, value);
+            }
+        }
+    }
+
     /// Create a new session
     pub fn new() -> Self {
         IscsiSession {
@@ -222,13 +887,36 @@ impl IscsiSession {
             current_stage: 0,
             next_stage: 0,
             pending_writes: HashMap::new(),
+            pending_parameter_lists: HashMap::new(),
+            pending_text_response: None,
+            pending_login_text: Vec::new(),
+            deferred_commands: HashSet::new(),
+            held_commands: HashMap::new(),
+            naca_tasks: HashMap::new(),
+            command_window_held: false,
+            lun_generation: 0,
+            lun_changes_baseline: 0,
+            lun_changes_seen: HashMap::new(),
             next_ttt: 1, // TTT 0 is reserved for unsolicited data
+            outstanding_ping_ttt: None,
             last_sense_data: None,
+            retained_for_recovery: None,
+            claimed_session: None,
             auth_config: AuthConfig::None,
             chap_state: None,
             target_chap_state: None,
             chap_completed: false,
+            allow_md5_chap: true,
+            answered_keys: Vec::new(),
+            declared_keys: Vec::new(),
+            strictness: Strictness::Permissive,
             allowed_initiators: None,
+            allowed_networks: None,
+            initiator_addr: None,
+            discovery_only: false,
+            observer: None,
+            control: None,
+            inline_deadline: None,
         }
     }
 
@@ -243,6 +931,19 @@ impl IscsiSession {
         ttt
     }
 
+    /// End offset of the data the initiator may send without an R2T
+    ///
+    /// `received` is the immediate data that came with the command. When
+    /// InitialR2T=No and the command's F bit is clear, unsolicited Data-Out
+    /// follows up to FirstBurstLength (RFC 3720 Section 12.11); R2Ts are only
+    /// needed beyond that.
+    pub fn unsolicited_data_end(&self, final_flag: bool, received: u32, total: u32) -> u32 {
+        if self.params.initial_r2t || final_flag {
+            return received;
+        }
+        self.params.first_burst_length.max(received).min(total)
+    }
+
     /// Create session from login request
     pub fn from_login_request(login: &LoginRequest, target_name: &str) -> Self {
         let mut session = IscsiSession::new();
@@ -256,7 +957,7 @@ impl IscsiSession {
 
         // Parse initiator parameters
         for (key, value) in &login.parameters {
-            session.apply_initiator_param(key, value);
+            let _ = session.apply_initiator_param(key, value);
         }
 
         // Set initial state based on CSG
@@ -275,11 +976,57 @@ impl IscsiSession {
         self.auth_config = auth_config;
     }
 
+    /// Allow or forbid MD5 CHAP; SHA-256 and SHA3-256 are always accepted
+    pub fn set_allow_md5_chap(&mut self, allow: bool) {
+        self.allow_md5_chap = allow;
+    }
+
     /// Set ACL (Access Control List) for this session
     pub fn set_allowed_initiators(&mut self, allowed_initiators: Option<Vec<String>>) {
         self.allowed_initiators = allowed_initiators;
     }
 
+    /// Restrict the networks the initiator may connect from
+    pub fn set_allowed_networks(&mut self, allowed_networks: Option<Vec<IpNetwork>>) {
+        self.allowed_networks = allowed_networks;
+    }
+
+    /// Set how protocol violations are treated
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    /// Record the address the initiator connected from
+    pub fn set_initiator_addr(&mut self, addr: IpAddr) {
+        self.initiator_addr = Some(addr);
+    }
+
+    /// Set the observer notified of session events
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn TargetObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Set the registry that allocates this session's TSIH
+    pub fn set_control(&mut self, control: TargetControl) {
+        self.control = Some(control);
+    }
+
+    /// Emit an event to the observer, if one is registered
+    pub fn notify(&self, event: TargetEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    /// Remove all pending writes, returning them ordered by ITT
+    ///
+    /// Used when the session ends with R2T sequences still outstanding.
+    pub fn take_pending_writes(&mut self) -> Vec<(u32, PendingWrite)> {
+        let mut pending: Vec<_> = self.pending_writes.drain().collect();
+        pending.sort_by_key(|(itt, _)| *itt);
+        pending
+    }
+
     /// Handle CHAP authentication during security negotiation
     /// Returns (success, response_params)
     fn handle_chap_auth(&mut self, login_params: &[(String, String)]) -> ScsiResult<(bool, Vec<(String, String)>)> {
@@ -312,7 +1059,7 @@ impl IscsiSession {
                     Ok((true, vec![]))
                 }
             }
-            AuthConfig::Chap { credentials } | AuthConfig::MutualChap { target_credentials: credentials, .. } => {
+            AuthConfig::Chap { .. } | AuthConfig::MutualChap { .. } | AuthConfig::ChapProvider { .. } => {
                 // CHAP is required
 
                 // Handle empty transit request after CHAP completes (Mutual CHAP only)
@@ -335,16 +1082,29 @@ impl IscsiSession {
                     if chap_a.is_none() && self.chap_state.is_none() {
                         // Step 1: Acknowledge CHAP (initiator will request algorithm list next)
                         let params = vec![
-                            ("TargetPortalGroupTag".to_string(), "1".to_string()),
+                            ("TargetPortalGroupTag".to_string(), self.params.target_portal_group_tag.to_string()),
                             ("AuthMethod".to_string(), "CHAP".to_string()),
                         ];
                         log::debug!("Acknowledging CHAP authentication method");
                         Ok((false, params))
-                    } else if chap_a.is_some() && self.chap_state.is_none() {
-                        // Step 2: Initiator requested algorithm (sends CHAP_A=5), send challenge
-                        let chap_state = ChapAuthState::new(false);
+                    } else if let (Some(offered), None) = (chap_a, &self.chap_state) {
+                        // Step 2: Initiator offered algorithms (CHAP_A=8,7,5), pick its first allowed one and send challenge
+                        let allowed: Vec<ChapAlgorithm> = ChapAlgorithm::ALL.into_iter()
+                            .filter(|&algorithm| algorithm != ChapAlgorithm::Md5 || self.allow_md5_chap)
+                            .collect();
+                        let Some(algorithm) = ChapAlgorithm::negotiate(offered, &allowed) else {
+                            log::warn!("CHAP authentication failed: no acceptable algorithm in CHAP_A={}", offered);
+                            return Err(IscsiError::auth(AuthFailure::MethodNotAccepted, format!(
+                                "AUTH_FAILURE: No acceptable CHAP algorithm offered (CHAP_A={})",
+                                offered
+                            )));
+                        };
+                        let chap_state = match &self.control {
+                            Some(control) => ChapAuthState::registered(control.challenges(), algorithm),
+                            None => ChapAuthState::with_algorithm(false, algorithm),
+                        };
                         let params = vec![
-                            ("CHAP_A".to_string(), "5".to_string()), // Confirm MD5
+                            ("CHAP_A".to_string(), algorithm.code().to_string()),
                             ("CHAP_I".to_string(), chap_state.identifier_str()),
                             ("CHAP_C".to_string(), chap_state.challenge_hex()),
                         ];
@@ -364,11 +1124,13 @@ impl IscsiSession {
                             .map(|(_, v)| v.as_str());
 
                         if let (Some(username), Some(response_hex)) = (chap_n, chap_r) {
+                            let provider = self.auth_config.secret_provider()
+                                .ok_or_else(|| IscsiError::auth(AuthFailure::NotConfigured, "CHAP secret provider missing".to_string()))?;
+
                             // Validate username
-                            if username != credentials.username {
-                                log::warn!("CHAP authentication failed: unknown user '{}' (expected '{}')",
-                                    username, credentials.username);
-                                return Err(IscsiError::Auth(format!(
+                            if !provider.contains(username) {
+                                log::warn!("CHAP authentication failed: unknown user '{}'", username);
+                                return Err(IscsiError::auth(AuthFailure::UnknownUser, format!(
                                     "AUTH_FAILURE: Unknown user '{}' - check username in authentication credentials",
                                     username
                                 )));
@@ -378,7 +1140,7 @@ impl IscsiSession {
                             let response = parse_chap_response(response_hex)?;
                             let chap_state = self.chap_state.as_ref().unwrap();
 
-                            if chap_state.validate_response(&response, &credentials.secret) {
+                            if provider.verify(username, chap_state, &response) {
                                 log::info!("CHAP authentication successful for user '{}'", username);
 
                                 // TODO: Add ACL (Access Control List) check here to return AUTHORIZATION_FAILURE
@@ -391,7 +1153,7 @@ impl IscsiSession {
                                 // }
 
                                 // Check if mutual CHAP is required
-                                if let AuthConfig::MutualChap { initiator_credentials, .. } = &self.auth_config {
+                                if let Some(initiator_credentials) = self.auth_config.mutual_credentials() {
                                     // In mutual CHAP, initiator may send a challenge to target
                                     // Check if initiator sent CHAP_I and CHAP_C (target auth)
                                     let target_chap_i = login_params.iter()
@@ -408,20 +1170,26 @@ impl IscsiSession {
 
                                         // Parse challenge
                                         let identifier = chap_i.parse::<u8>().map_err(|e|
-                                            IscsiError::Auth(format!("Invalid CHAP_I: {}", e)))?;
+                                            IscsiError::auth(AuthFailure::MalformedMessage, format!("Invalid CHAP_I: {}", e)))?;
 
                                         // Remove "0x" prefix if present
                                         let chap_c_clean = chap_c_hex.strip_prefix("0x").unwrap_or(chap_c_hex);
                                         let challenge = hex::decode(chap_c_clean).map_err(|e|
-                                            IscsiError::Auth(format!("Invalid CHAP_C hex: {}", e)))?;
+                                            IscsiError::auth(AuthFailure::MalformedMessage, format!("Invalid CHAP_C hex: {}", e)))?;
+
+                                        // Refuse to answer our own challenge (reflection attack)
+                                        if chap_state.is_reflection(&challenge) {
+                                            log::warn!("Mutual CHAP: initiator reflected the target's challenge");
+                                            return Err(IscsiError::auth(AuthFailure::ReflectedChallenge,
+                                                "AUTH_FAILURE: Initiator CHAP_C repeats the target's challenge (reflection)".to_string()
+                                            ));
+                                        }
 
                                         // Calculate target's response using initiator_credentials
-                                        // (these are the credentials the initiator expects from the target)
-                                        let mut data = Vec::new();
-                                        data.push(identifier);
-                                        data.extend_from_slice(initiator_credentials.secret.as_bytes());
-                                        data.extend_from_slice(&challenge);
-                                        let target_response = md5::compute(&data).0.to_vec();
+                                        // (these are the credentials the initiator expects from the target),
+                                        // hashed with the algorithm negotiated for this login
+                                        let target_chap = ChapAuthState::for_challenge(identifier, challenge, true, chap_state.algorithm);
+                                        let target_response = target_chap.calculate_response(&initiator_credentials.secret);
 
                                         let response_hex = format!("0x{}", target_response.iter()
                                             .map(|b| format!("{:02x}", b))
@@ -449,26 +1217,26 @@ impl IscsiSession {
                                 Ok((true, vec![])) // Authenticated successfully (one-way CHAP)
                             } else {
                                 log::warn!("CHAP authentication failed: invalid password/secret for user '{}'", username);
-                                Err(IscsiError::Auth(format!(
+                                Err(IscsiError::auth(AuthFailure::BadResponse, format!(
                                     "AUTH_FAILURE: Invalid password for user '{}' - CHAP response does not match expected value",
                                     username
                                 )))
                             }
                         } else {
                             log::warn!("CHAP authentication failed: missing CHAP_N or CHAP_R parameters");
-                            Err(IscsiError::Auth(
+                            Err(IscsiError::auth(AuthFailure::MalformedMessage,
                                 "AUTH_FAILURE: Missing CHAP_N (username) or CHAP_R (response) - initiator must provide credentials".to_string()
                             ))
                         }
                     } else {
                         // Unexpected state
                         log::warn!("CHAP authentication: unexpected state");
-                        Err(IscsiError::Auth("CHAP authentication protocol error".to_string()))
+                        Err(IscsiError::auth(AuthFailure::MalformedMessage, "CHAP authentication protocol error".to_string()))
                     }
                 } else {
                     // Initiator must use CHAP but didn't request it
                     log::warn!("Authentication required but initiator didn't request CHAP (AuthMethod missing or wrong)");
-                    Err(IscsiError::Auth(
+                    Err(IscsiError::auth(AuthFailure::MethodNotAccepted,
                         "AUTH_FAILURE: CHAP authentication required but initiator did not request it - set AuthMethod=CHAP".to_string()
                     ))
                 }
@@ -477,107 +1245,105 @@ impl IscsiSession {
     }
 
     /// Apply an initiator parameter during negotiation
-    fn apply_initiator_param(&mut self, key: &str, value: &str) {
-        match key {
-            "InitiatorName" => {
-                self.params.initiator_name = value.to_string();
-            }
-            "InitiatorAlias" => {
-                self.params.initiator_alias = value.to_string();
-            }
-            "TargetName" => {
-                // Initiator requests specific target
-                self.params.target_name = value.to_string();
-            }
-            "SessionType" => {
-                // RFC 3720: Only "Discovery" and "Normal" are valid
-                match value {
-                    "Discovery" => {
-                        self.session_type = SessionType::Discovery;
+    ///
+    /// Returns an explicit reply for the key when the standard response
+    /// parameters do not cover it: "Reject" for an out-of-range or malformed
+    /// value, "NotUnderstood" for an unknown key, or the negotiated value of
+    /// a key the target does not otherwise offer.
+    fn apply_initiator_param(&mut self, key: &str, value: &str) -> Option<(String, String)> {
+        let reply = |reply: &str| Some((key.to_string(), reply.to_string()));
+        let Some(def) = NEGOTIATION_KEYS.iter().find(|def| def.name == key) else {
+            log::warn!("Unknown key {}={} - replying NotUnderstood", key, value);
+            return reply("NotUnderstood");
+        };
+
+        match def.key_type {
+            KeyType::Number { min, max } => {
+                let Some(n) = value.parse::<u32>().ok().filter(|n| (min..=max).contains(n)) else {
+                    log::warn!("Rejecting {}={}: expected a number in {}..={}", key, value, min, max);
+                    return reply("Reject");
+                };
+                let params = &mut self.params;
+                match key {
+                    // This is the initiator's max recv, which is our max xmit
+                    "MaxRecvDataSegmentLength" => params.max_xmit_data_segment_length = n,
+                    "MaxBurstLength" => params.max_burst_length = def.rule.number(params.max_burst_length, n),
+                    "FirstBurstLength" => params.first_burst_length = def.rule.number(params.first_burst_length, n),
+                    "DefaultTime2Wait" => {
+                        params.default_time2wait = def.rule.number(params.default_time2wait.into(), n) as u16;
                     }
-                    "Normal" => {
-                        self.session_type = SessionType::Normal;
+                    "DefaultTime2Retain" => {
+                        params.default_time2retain = def.rule.number(params.default_time2retain.into(), n) as u16;
                     }
-                    _ => {
-                        log::warn!("Invalid SessionType '{}' - only 'Discovery' and 'Normal' are supported", value);
-                        // Store invalid session type to reject during login processing
-                        self.params.invalid_session_type = Some(value.to_string());
+                    "MaxOutstandingR2T" => params.max_outstanding_r2t = def.rule.number(params.max_outstanding_r2t, n),
+                    "ErrorRecoveryLevel" => {
+                        params.error_recovery_level = def.rule.number(params.error_recovery_level.into(), n) as u8;
                     }
+                    // Only one connection per session is supported
+                    "MaxConnections" => return reply(&def.rule.number(1, n).to_string()),
+                    _ => {}
                 }
+                // FirstBurstLength must not exceed MaxBurstLength (RFC 3720 Section 12.14)
+                params.first_burst_length = params.first_burst_length.min(params.max_burst_length);
             }
-            "MaxRecvDataSegmentLength" => {
-                if let Ok(v) = value.parse::<u32>() {
-                    // This is initiator's max recv, which is our max xmit
-                    self.params.max_xmit_data_segment_length = v;
-                }
-            }
-            "MaxBurstLength" => {
-                if let Ok(v) = value.parse::<u32>() {
-                    self.params.max_burst_length = v.min(self.params.max_burst_length);
-                }
-            }
-            "FirstBurstLength" => {
-                if let Ok(v) = value.parse::<u32>() {
-                    self.params.first_burst_length = v.min(self.params.first_burst_length);
-                }
-            }
-            "DefaultTime2Wait" => {
-                if let Ok(v) = value.parse::<u16>() {
-                    self.params.default_time2wait = v.max(self.params.default_time2wait);
-                }
-            }
-            "DefaultTime2Retain" => {
-                if let Ok(v) = value.parse::<u16>() {
-                    self.params.default_time2retain = v.min(self.params.default_time2retain);
-                }
-            }
-            "MaxOutstandingR2T" => {
-                if let Ok(v) = value.parse::<u32>() {
-                    self.params.max_outstanding_r2t = v.min(self.params.max_outstanding_r2t);
-                }
-            }
-            "DataPDUInOrder" => {
-                self.params.data_pdu_in_order = value == "Yes";
-            }
-            "DataSequenceInOrder" => {
-                self.params.data_sequence_in_order = value == "Yes";
-            }
-            "ErrorRecoveryLevel" => {
-                if let Ok(v) = value.parse::<u8>() {
-                    self.params.error_recovery_level = v.min(self.params.error_recovery_level);
-                }
-            }
-            "ImmediateData" => {
-                // AND operation: only true if both want it
-                self.params.immediate_data = self.params.immediate_data && (value == "Yes");
-            }
-            "InitialR2T" => {
-                // OR operation: true if either wants it
-                self.params.initial_r2t = self.params.initial_r2t || (value == "Yes");
-            }
-            "HeaderDigest" => {
-                self.params.header_digest = if value.contains("CRC32C") {
-                    DigestType::CRC32C
-                } else {
-                    DigestType::None
+            KeyType::Boolean => {
+                let theirs = match value {
+                    "Yes" => true,
+                    "No" => false,
+                    _ => {
+                        log::warn!("Rejecting {}={}: expected Yes or No", key, value);
+                        return reply("Reject");
+                    }
                 };
+                let params = &mut self.params;
+                match key {
+                    "DataPDUInOrder" => params.data_pdu_in_order = def.rule.boolean(params.data_pdu_in_order, theirs),
+                    "DataSequenceInOrder" => {
+                        params.data_sequence_in_order = def.rule.boolean(params.data_sequence_in_order, theirs);
+                    }
+                    "ImmediateData" => params.immediate_data = def.rule.boolean(params.immediate_data, theirs),
+                    "InitialR2T" => params.initial_r2t = def.rule.boolean(params.initial_r2t, theirs),
+                    "OFMarker" | "IFMarker" => {
+                        return reply(if def.rule.boolean(false, theirs) { "Yes" } else { "No" });
+                    }
+                    _ => {}
+                }
             }
-            "DataDigest" => {
-                self.params.data_digest = if value.contains("CRC32C") {
-                    DigestType::CRC32C
-                } else {
-                    DigestType::None
+            KeyType::List(supported) => {
+                // The first value in the initiator's list that we support wins
+                let Some(chosen) = value.split(',').find(|offered| supported.contains(offered)) else {
+                    log::warn!("Rejecting {}={}: none of {:?} offered", key, value, supported);
+                    return reply("Reject");
                 };
+                let digest = if chosen == "CRC32C" { DigestType::CRC32C } else { DigestType::None };
+                match key {
+                    "HeaderDigest" => self.params.header_digest = digest,
+                    "DataDigest" => self.params.data_digest = digest,
+                    _ => {}
+                }
             }
-            // Authentication parameters - handled separately in handle_chap_auth()
-            "AuthMethod" | "CHAP_A" | "CHAP_I" | "CHAP_C" | "CHAP_N" | "CHAP_R" => {
-                // These are processed by handle_chap_auth, not here
-            }
-            _ => {
-                // Unknown parameter - ignore
-                log::debug!("Ignoring unknown parameter: {}={}", key, value);
-            }
+            KeyType::Text => match key {
+                "InitiatorName" => self.params.initiator_name = value.to_string(),
+                "InitiatorAlias" => self.params.initiator_alias = value.to_string(),
+                // Initiator requests specific target
+                "TargetName" => self.params.target_name = value.to_string(),
+                // RFC 3720: Only "Discovery" and "Normal" are valid
+                "SessionType" => match value {
+                    "Discovery" => self.session_type = SessionType::Discovery,
+                    "Normal" => self.session_type = SessionType::Normal,
+                    _ => {
+                        log::warn!("Invalid SessionType '{}' - only 'Discovery' and 'Normal' are supported", value);
+                        // Store invalid session type to reject during login processing
+                        self.params.invalid_session_type = Some(value.to_string());
+                    }
+                },
+                // Markers are never enabled, so their intervals do not apply
+                "OFMarkInt" | "IFMarkInt" => return reply("Irrelevant"),
+                // Authentication keys are processed by handle_chap_auth()
+                _ => {}
+            },
         }
+        None
     }
 
     /// Generate target response parameters for login
@@ -586,10 +1352,8 @@ impl IscsiSession {
 
         // Note: SessionType and TargetName are declarative (initiator-only) and should NOT be echoed back
         // Only send TargetAlias if configured
-        if self.session_type == SessionType::Normal {
-            if !self.params.target_alias.is_empty() {
-                params.push(("TargetAlias".to_string(), self.params.target_alias.clone()));
-            }
+        if self.session_type == SessionType::Normal && !self.params.target_alias.is_empty() {
+            params.push(("TargetAlias".to_string(), self.params.target_alias.clone()));
         }
 
         // Negotiated parameters
@@ -655,14 +1419,143 @@ impl IscsiSession {
         params
     }
 
+    /// Reassemble Login Request text data sent across PDUs with the C bit
+    ///
+    /// A key=value pair may be split between PDUs (RFC 3720 Section
+    /// 10.12.2), so nothing is parsed until the final PDU arrives. Returns the
+    /// request carrying the whole text, or None while more is expected; a
+    /// continued PDU with the T bit set or text past `MAX_LOGIN_TEXT_LENGTH`
+    /// is a protocol error.
+    pub fn assemble_login_request<'a>(&mut self, pdu: &'a IscsiPdu) -> ScsiResult<Option<Cow<'a, IscsiPdu>>> {
+        if pdu.flags & pdu::flags::CONTINUE_LOGIN != 0 {
+            if pdu.flags & pdu::flags::TRANSIT != 0 {
+                self.pending_login_text.clear();
+                return Err(IscsiError::protocol(ProtocolErrorKind::LoginSequence, "Login Request with both C and T bits set".to_string()));
+            }
+            if self.pending_login_text.len() + pdu.data.len() > MAX_LOGIN_TEXT_LENGTH {
+                self.pending_login_text.clear();
+                return Err(IscsiError::protocol(ProtocolErrorKind::LoginSequence, format!(
+                    "Continued login text exceeds {} bytes", MAX_LOGIN_TEXT_LENGTH
+                )));
+            }
+            self.pending_login_text.extend_from_slice(&pdu.data);
+            return Ok(None);
+        }
+        if self.pending_login_text.is_empty() {
+            return Ok(Some(Cow::Borrowed(pdu)));
+        }
+
+        let mut data = std::mem::take(&mut self.pending_login_text);
+        data.extend_from_slice(&pdu.data);
+        let mut full = pdu.clone();
+        full.data_length = data.len() as u32;
+        full.data = data;
+        Ok(Some(Cow::Owned(full)))
+    }
+
+    /// Empty Login Response asking for the next PDU of a continued request
+    pub fn create_login_continue_ack(&self, pdu: &IscsiPdu) -> IscsiPdu {
+        let lun_bytes = pdu.lun.to_be_bytes();
+        let mut isid = [0u8; 6];
+        isid.copy_from_slice(&lun_bytes[0..6]);
+        let tsih = u16::from_be_bytes([lun_bytes[6], lun_bytes[7]]);
+        // Before the first PDU is processed the session has no CmdSN window yet
+        let (exp_cmd_sn, max_cmd_sn) = if self.state == SessionState::Free {
+            let cmd_sn = u32::from_be_bytes([pdu.specific[4], pdu.specific[5], pdu.specific[6], pdu.specific[7]]);
+            (cmd_sn, cmd_sn.wrapping_add(1))
+        } else {
+            (self.exp_cmd_sn, self.max_cmd_sn)
+        };
+        IscsiPdu::login_response(
+            isid,
+            tsih,
+            self.stat_sn,
+            exp_cmd_sn,
+            max_cmd_sn,
+            pdu::login_status::SUCCESS,
+            0,
+            (pdu.flags >> 2) & 0x03,
+            pdu.flags & 0x03,
+            false,
+            pdu.itt,
+            Vec::new(),
+        )
+    }
+
+    /// Check a Login Request's stages and keys against the login state
+    /// machine - RFC 3720 Sections 5.3 and 10.12.3
+    ///
+    /// Returns why the request is illegal: a reserved or Full Feature CSG, a
+    /// transition that does not move forward, a return to an earlier stage,
+    /// security keys outside SecurityNegotiation, or operational keys in the
+    /// middle of a CHAP exchange.
+    fn check_login_stages(&self, login: &LoginRequest) -> Result<(), String> {
+        if !matches!(login.csg, 0 | 1) {
+            return Err(format!("CSG={} is not a login stage", login.csg));
+        }
+        // NSG is only meaningful with the T bit set
+        if login.transit && (login.nsg == 2 || login.nsg <= login.csg) {
+            return Err(format!("illegal transition from CSG={} to NSG={}", login.csg, login.nsg));
+        }
+        if login.csg == 0 && self.state == SessionState::LoginOperationalNegotiation {
+            return Err("SecurityNegotiation requested after the transition to LoginOperationalNegotiation".to_string());
+        }
+
+        let is_security = |key: &str| key == "AuthMethod" || key.starts_with("CHAP_");
+        let is_declarative = |key: &str| matches!(key, "InitiatorName" | "InitiatorAlias" | "TargetName" | "SessionType");
+        for (key, _) in &login.parameters {
+            if login.csg == 1 && is_security(key) {
+                return Err(format!("security key {} sent during LoginOperationalNegotiation", key));
+            }
+            // Once CHAP is under way only authentication keys may be exchanged
+            let chap_exchange = self.chap_state.is_some() && !self.chap_completed;
+            if login.csg == 0 && chap_exchange && !is_security(key) && !is_declarative(key)
+                && NEGOTIATION_KEYS.iter().any(|def| def.name == key)
+            {
+                return Err(format!("operational key {} sent during a CHAP exchange", key));
+            }
+        }
+        Ok(())
+    }
+
+    /// In strict mode, returns why a login request's keys are illegal: a
+    /// malformed key name or a key the initiator has already declared
+    fn check_login_keys(&self, login: &LoginRequest) -> Result<(), String> {
+        if self.strictness == Strictness::Permissive {
+            return Ok(());
+        }
+        for (i, (key, _)) in login.parameters.iter().enumerate() {
+            let valid = |c: char| c.is_ascii_alphanumeric() || ".-+@_".contains(c);
+            if key.is_empty() || key.len() > 63 || !key.chars().all(valid) {
+                return Err(format!("malformed key name {:?}", key));
+            }
+            if self.declared_keys.contains(key) || login.parameters[..i].iter().any(|(earlier, _)| earlier == key) {
+                return Err(format!("key {} declared more than once", key));
+            }
+        }
+        Ok(())
+    }
+
     /// Process a login request and generate response
+    ///
+    /// PDUs with the C bit are buffered and acknowledged with an empty
+    /// response; the keys are applied once the final PDU arrives.
     pub fn process_login(&mut self, pdu: &IscsiPdu, target_name: &str) -> ScsiResult<IscsiPdu> {
+        let pdu = match self.assemble_login_request(pdu) {
+            Ok(Some(pdu)) => pdu,
+            Ok(None) => return Ok(self.create_login_continue_ack(pdu)),
+            Err(e) => {
+                log::warn!("Login rejected: {}", e);
+                return self.create_initiator_error_reject(pdu.itt);
+            }
+        };
+        let pdu = pdu.as_ref();
         let login = pdu.parse_login_request()?;
 
         // Check iSCSI version compatibility - RFC 3720 Section 11.12
         // Target supports version 0x00 (RFC 3720)
         const TARGET_VERSION: u8 = 0x00;
-        if TARGET_VERSION < login.version_min || TARGET_VERSION > login.version_max {
+        if !(login.version_min..=login.version_max).contains(&TARGET_VERSION) {
             log::warn!(
                 "Login rejected: version mismatch (initiator: min=0x{:02x}, max=0x{:02x}, target=0x{:02x})",
                 login.version_min, login.version_max, TARGET_VERSION
@@ -670,6 +1563,19 @@ impl IscsiSession {
             return self.create_unsupported_version_reject(pdu.itt, login.version_max, login.version_min);
         }
 
+        // A non-zero TSIH continues an existing session, which must be known
+        // here (found by the connection before login) - RFC 3720 Section 10.12.6
+        if self.state == SessionState::Free && login.tsih != 0 && login.tsih != self.tsih {
+            log::warn!("Login rejected: no session with TSIH {}", login.tsih);
+            return self.create_session_does_not_exist_reject(pdu.itt);
+        }
+
+        if let Err(reason) = self.check_login_stages(&login).and_then(|()| self.check_login_keys(&login)) {
+            log::warn!("Login rejected: {}", reason);
+            return self.create_initiator_error_reject(pdu.itt);
+        }
+        self.declared_keys.extend(login.parameters.iter().map(|(key, _)| key.clone()));
+
         // First login - initialize session
         if self.state == SessionState::Free {
             self.isid = login.isid;
@@ -681,9 +1587,9 @@ impl IscsiSession {
 
         // Apply parameters from this login PDU
         log::debug!("Received {} login parameters: {:?}", login.parameters.len(), login.parameters);
-        for (key, value) in &login.parameters {
-            self.apply_initiator_param(key, value);
-        }
+        let mut replies: Vec<(String, String)> = login.parameters.iter()
+            .filter_map(|(key, value)| self.apply_initiator_param(key, value))
+            .collect();
 
         // Validate required parameters - RFC 3720 Section 12
         let has_initiator_name = login.parameters.iter()
@@ -742,14 +1648,25 @@ impl IscsiSession {
             );
         }
 
-        // Update stages
-        self.current_stage = login.csg;
-        self.next_stage = login.nsg;
-
+        // Discovery-only portals do not serve normal sessions
+        if self.discovery_only && self.session_type == SessionType::Normal {
+            log::warn!("Login rejected: normal session requested on a discovery-only portal");
+            return self.create_login_reject(
+                pdu.itt,
+                pdu::login_status::INITIATOR_ERROR,
+                0x09, // SESSION_TYPE_NOT_SUPPORTED (0x0209)
+            );
+        }
+
+        // Update stages
+        self.current_stage = login.csg;
+        self.next_stage = login.nsg;
+
         log::debug!("Login: CSG={}, NSG={}, Transit={}", login.csg, login.nsg, login.transit);
 
         // Handle authentication during security negotiation (CSG=0)
         // IMPORTANT: Check auth BEFORE deciding whether to honor transit request
+        let mut mutual_chap_reply = Vec::new();
         let auth_complete = if login.csg == 0 {
             // Handle auth errors by returning login reject PDU instead of propagating error
             let (auth_success, auth_params) = match self.handle_chap_auth(&login.parameters) {
@@ -767,22 +1684,23 @@ impl IscsiSession {
 
             log::debug!("After handle_chap_auth: auth_success={}, auth_params={:?}", auth_success, auth_params);
 
+            // Without authentication, AuthMethod=None is answered like any
+            // other key and does not hold up the stage transition
+            if auth_success && !self.chap_completed {
+                replies.extend(auth_params.iter().cloned());
+            }
+
             // If authentication in progress, send CHAP parameters and stay in security negotiation
-            // OR if mutual CHAP completed successfully and we need to send target's response
-            if !auth_params.is_empty() {
+            if !auth_params.is_empty() && !auth_success {
                 // Send CHAP challenge/response
+                let mut auth_params = auth_params;
+                auth_params.extend(replies.iter().cloned());
                 let response_data = serialize_text_parameters(&auth_params);
 
                 log::debug!("Sending {} auth parameters: {:?}", auth_params.len(), auth_params);
 
                 self.stat_sn = self.stat_sn.wrapping_add(1);
 
-                // For mutual CHAP, DO NOT set transit bit even if initiator sets it
-                // RFC 3720: Mutual CHAP requires the target to send its response
-                // WITHOUT transitioning, then the initiator will send another login
-                // request to complete the phase transition
-                let (csg, nsg, transit) = (0, 0, false);
-
                 return Ok(IscsiPdu::login_response(
                     self.isid,
                     self.tsih,
@@ -791,14 +1709,20 @@ impl IscsiSession {
                     self.max_cmd_sn,
                     0, // status_class: success
                     0, // status_detail: success
-                    csg, // CSG: Security Negotiation
-                    nsg, // NSG: depends on whether auth is complete
-                    transit, // transit: depends on whether auth is complete
+                    0, // CSG: Security Negotiation
+                    0, // NSG: unused without transit
+                    false, // transit: the exchange is not finished
                     pdu.itt,
                     response_data,
                 ));
             }
 
+            // Mutual CHAP completed: the target's CHAP_N/CHAP_R go in this
+            // response, which transits like any other if the initiator asked to
+            if auth_success && self.chap_completed {
+                mutual_chap_reply = auth_params;
+            }
+
             // If authentication required but failed with error, reject the login
             if !auth_success {
                 log::warn!("Login rejected: authentication failed");
@@ -821,7 +1745,7 @@ impl IscsiSession {
         if auth_complete && self.state == SessionState::Free {
             if let Some(ref allowed) = self.allowed_initiators {
                 let initiator_name = &self.params.initiator_name;
-                if !allowed.contains(initiator_name) {
+                if !allowed.iter().any(|pattern| initiator_matches(pattern, initiator_name)) {
                     log::warn!(
                         "Login rejected: initiator '{}' not in ACL (allowed: {:?})",
                         initiator_name, allowed
@@ -830,6 +1754,16 @@ impl IscsiSession {
                 }
                 log::debug!("ACL check passed for initiator '{}'", initiator_name);
             }
+            if let Some(ref networks) = self.allowed_networks {
+                let addr = self.initiator_addr;
+                if !addr.is_some_and(|addr| networks.iter().any(|net| net.contains(addr))) {
+                    log::warn!(
+                        "Login rejected: initiator '{}' connected from {:?}, outside the allowed networks",
+                        self.params.initiator_name, addr
+                    );
+                    return self.create_authorization_failure_reject(pdu.itt);
+                }
+            }
         }
 
         // Determine response transit flags
@@ -837,6 +1771,35 @@ impl IscsiSession {
         let transit = login.transit && auth_complete;
         log::debug!("Transition logic: login.transit={}, auth_complete={}, transit={}",
             login.transit, auth_complete, transit);
+        // A login continuing a session claims it only once authenticated, so
+        // a peer guessing an ISID and TSIH cannot drain or take it over
+        if transit && self.state == SessionState::Free && self.tsih != 0 {
+            if let Some(control) = &self.control {
+                match control.claim_session(self.isid, self.tsih, &self.params.initiator_name, self.cid) {
+                    Some(claim) => {
+                        let snapshot = claim.snapshot();
+                        log::info!("Resuming session TSIH {} for {}", snapshot.tsih, snapshot.initiator_name);
+                        self.restore(snapshot);
+                        self.claimed_session = Some(claim);
+                    }
+                    None => {
+                        log::warn!("Login rejected: session TSIH {} went away during login", self.tsih);
+                        return self.create_session_does_not_exist_reject(pdu.itt);
+                    }
+                }
+            }
+        }
+
+        // Only new Normal sessions entering Full Feature Phase get a TSIH, not Discovery
+        if transit && login.nsg == 3 && matches!(login.csg, 0 | 1)
+            && self.session_type == SessionType::Normal && self.tsih == 0
+        {
+            match self.generate_tsih() {
+                Some(tsih) => self.tsih = tsih,
+                None => return self.create_out_of_resources_reject(pdu.itt),
+            }
+        }
+
         let (response_csg, response_nsg, response_transit) = if transit {
             // Initiator wants to transition and auth is complete
             log::debug!("Checking transition: CSG={}, NSG={}", login.csg, login.nsg);
@@ -849,25 +1812,15 @@ impl IscsiSession {
                 (0, 3) => {
                     // Security → Full Feature Phase
                     self.state = SessionState::FullFeaturePhase;
-                    // Only assign TSIH for Normal sessions, not Discovery
-                    if self.session_type == SessionType::Normal {
-                        self.tsih = self.generate_tsih();
-                    }
                     (login.csg, login.nsg, true) // Echo back the transition
                 }
                 (1, 3) => {
                     // Login Op Neg → Full Feature Phase
                     self.state = SessionState::FullFeaturePhase;
-                    // Only assign TSIH for Normal sessions, not Discovery
-                    if self.session_type == SessionType::Normal {
-                        self.tsih = self.generate_tsih();
-                    }
                     (login.csg, login.nsg, true) // Echo back the transition
                 }
-                _ => {
-                    // Stay in current stage
-                    (login.csg, login.nsg, false)
-                }
+                // check_login_stages() has already rejected every other transition
+                _ => (login.csg, login.nsg, false),
             }
         } else {
             // Initiator not ready to transition, or auth not complete
@@ -876,39 +1829,50 @@ impl IscsiSession {
 
         log::debug!("Response: CSG={}, NSG={}, Transit={}", response_csg, response_nsg, response_transit);
 
-        // Generate response parameters
-        let response_params = if response_transit && response_nsg == 3 {
-            // Final login response
-            if self.session_type == SessionType::Discovery {
-                // Discovery sessions - only echo back operational parameters
-                let mut params = vec![];
-
-                // Include operational parameters that were negotiated
-                for (key, _value) in &login.parameters {
-                    match key.as_str() {
-                        "MaxRecvDataSegmentLength" => {
-                            params.push(("MaxRecvDataSegmentLength".to_string(),
-                                        self.params.max_recv_data_segment_length.to_string()));
-                        }
-                        "HeaderDigest" => {
-                            params.push(("HeaderDigest".to_string(), "None".to_string()));
-                        }
-                        "DataDigest" => {
-                            params.push(("DataDigest".to_string(), "None".to_string()));
-                        }
-                        _ => {}
-                    }
-                }
-                params
-            } else {
-                // Normal sessions get full parameter negotiation
-                self.generate_response_params()
-            }
+        // Answers to this PDU's keys, before explicit replies are applied
+        let standard = if self.session_type == SessionType::Discovery {
+            // Discovery sessions only negotiate what a SendTargets exchange needs
+            vec![
+                ("MaxRecvDataSegmentLength".to_string(), self.params.max_recv_data_segment_length.to_string()),
+                ("HeaderDigest".to_string(), "None".to_string()),
+                ("DataDigest".to_string(), "None".to_string()),
+            ]
         } else {
-            // Intermediate response
-            vec![]
+            self.generate_response_params()
         };
 
+        // Answer every key in the order the initiator proposed it; the
+        // Microsoft initiator stalls on answers that are missing or reordered
+        let mut response_params: Vec<(String, String)> = Vec::new();
+        for (key, _) in &login.parameters {
+            if self.answered_keys.contains(key) || response_params.iter().any(|(answered, _)| answered == key) {
+                continue;
+            }
+            // Explicit replies replace the standard answer for the same key
+            let answer = replies.iter().find(|(reply_key, _)| reply_key == key)
+                .or_else(|| standard.iter().find(|(standard_key, _)| standard_key == key));
+            if let Some(answer) = answer {
+                response_params.push(answer.clone());
+            }
+        }
+
+        // The target's mutual CHAP answer leads the response
+        if !mutual_chap_reply.is_empty() {
+            response_params.retain(|(key, _)| !mutual_chap_reply.iter().any(|(reply_key, _)| reply_key == key));
+            response_params.splice(0..0, mutual_chap_reply);
+        }
+
+        // The final response of a normal session also declares the target's
+        // values for keys the initiator left at their defaults
+        if response_transit && response_nsg == 3 && self.session_type == SessionType::Normal {
+            for param in standard {
+                if !self.answered_keys.contains(&param.0) && !response_params.iter().any(|(key, _)| *key == param.0) {
+                    response_params.push(param);
+                }
+            }
+        }
+        self.answered_keys.extend(response_params.iter().map(|(key, _)| key.clone()));
+
         let response_data = serialize_text_parameters(&response_params);
 
         log::debug!("Sending {} response parameters: {:?}", response_params.len(), response_params);
@@ -977,6 +1941,18 @@ impl IscsiSession {
         )
     }
 
+    /// Create a login reject for an unknown session - RFC 3720: SESSION_DOES_NOT_EXIST (0x020A)
+    ///
+    /// This is used when a login with a non-zero TSIH names no session the
+    /// target can continue.
+    pub fn create_session_does_not_exist_reject(&self, itt: u32) -> ScsiResult<IscsiPdu> {
+        self.create_login_reject(
+            itt,
+            pdu::login_status::INITIATOR_ERROR,
+            0x0A, // SESSION_DOES_NOT_EXIST (0x020A)
+        )
+    }
+
     /// Create a login reject for invalid request - RFC 3720: INVALID_REQUEST_DURING_LOGIN (0x020B)
     ///
     /// This is used when an initiator sends a PDU that is not allowed during the login phase,
@@ -990,6 +1966,18 @@ impl IscsiSession {
         )
     }
 
+    /// Create a login reject for a generic initiator error - RFC 3720: INITIATOR_ERROR (0x0200)
+    ///
+    /// This is used for protocol violations during login that have no more specific
+    /// status, such as a login PDU larger than MaxRecvDataSegmentLength.
+    pub fn create_initiator_error_reject(&self, itt: u32) -> ScsiResult<IscsiPdu> {
+        self.create_login_reject(
+            itt,
+            pdu::login_status::INITIATOR_ERROR,
+            0x00, // INITIATOR_ERROR (0x0200)
+        )
+    }
+
     /// Create a login reject for unsupported version - RFC 3720: UNSUPPORTED_VERSION (0x0205)
     ///
     /// This is used when the initiator's version range doesn't include the version supported by the target.
@@ -1032,14 +2020,15 @@ impl IscsiSession {
         )
     }
 
-    /// Generate a unique TSIH
-    fn generate_tsih(&self) -> u16 {
-        // Simple TSIH generation - in production, would be globally unique
-        use std::time::{SystemTime, UNIX_EPOCH};
-        let duration = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default();
-        ((duration.as_millis() & 0xFFFF) as u16).max(1)
+    /// Allocate a TSIH from the target's registry, or a process-wide counter without one
+    fn generate_tsih(&self) -> Option<u16> {
+        match &self.control {
+            Some(control) => control.allocate_tsih(),
+            None => {
+                static NEXT_TSIH: AtomicU16 = AtomicU16::new(1);
+                Some(NEXT_TSIH.fetch_add(1, Ordering::Relaxed).max(1))
+            }
+        }
     }
 
     /// Check if session is in full feature phase
@@ -1060,17 +2049,60 @@ impl IscsiSession {
     }
 
     /// Validate and update CmdSN from incoming PDU
+    ///
+    /// Only ExpCmdSN is delivered, advancing the window; see `check_cmd_sn`.
     pub fn validate_cmd_sn(&mut self, cmd_sn: u32) -> bool {
-        // Check if CmdSN is within window
-        let in_window = Self::sn_in_window(cmd_sn, self.exp_cmd_sn, self.max_cmd_sn);
+        self.check_cmd_sn(cmd_sn) == CmdSnCheck::Accepted
+    }
 
-        if in_window && cmd_sn == self.exp_cmd_sn {
-            // Expected command - advance window
+    /// Widen the command window so `depth` commands can be outstanding
+    ///
+    /// A closed window (MaxCmdSN one before ExpCmdSN) is reopened too.
+    pub fn open_command_window(&mut self, depth: u32) {
+        let max_cmd_sn = self.exp_cmd_sn.wrapping_add(depth.saturating_sub(1));
+        if Self::sn_in_window(self.max_cmd_sn.wrapping_add(1), self.exp_cmd_sn, max_cmd_sn) {
+            self.max_cmd_sn = max_cmd_sn;
+        }
+    }
+
+    /// Classify a request's CmdSN, taking it if the request is accepted
+    ///
+    /// Commands are delivered in CmdSN order (RFC 3720 Section 3.2.2.1): a
+    /// CmdSN before ExpCmdSN was already delivered and is a duplicate, one
+    /// later in the window waits for the commands before it, and one outside
+    /// the window is ignored.
+    pub fn check_cmd_sn(&mut self, cmd_sn: u32) -> CmdSnCheck {
+        if (cmd_sn.wrapping_sub(self.exp_cmd_sn) as i32) < 0 {
+            CmdSnCheck::Duplicate
+        } else if !Self::sn_in_window(cmd_sn, self.exp_cmd_sn, self.max_cmd_sn) {
+            CmdSnCheck::OutOfWindow
+        } else if cmd_sn != self.exp_cmd_sn {
+            CmdSnCheck::Early
+        } else {
+            // Expected command - advance window, unless it is being held
             self.exp_cmd_sn = self.exp_cmd_sn.wrapping_add(1);
-            self.max_cmd_sn = self.max_cmd_sn.wrapping_add(1);
+            if !self.command_window_held {
+                self.max_cmd_sn = self.max_cmd_sn.wrapping_add(1);
+            }
+            CmdSnCheck::Accepted
         }
+    }
 
-        in_window
+    /// Hold a request that arrived ahead of ExpCmdSN; false if its CmdSN is
+    /// already held
+    pub fn hold_command(&mut self, cmd_sn: u32, pdu: &IscsiPdu) -> bool {
+        match self.held_commands.entry(cmd_sn) {
+            std::collections::hash_map::Entry::Occupied(_) => false,
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(pdu.clone());
+                true
+            }
+        }
+    }
+
+    /// The held request whose turn it is, if it has arrived
+    pub fn take_ready_command(&mut self) -> Option<IscsiPdu> {
+        self.held_commands.remove(&self.exp_cmd_sn)
     }
 
     /// Check if a sequence number is within the command window
@@ -1082,48 +2114,177 @@ impl IscsiSession {
     }
 
     /// Process logout request
+    ///
+    /// Each connection carries a session of its own, so only this
+    /// connection's CID can be closed; other CIDs get CID_NOT_FOUND and the
+    /// session carries on. A logout removing the connection for recovery
+    /// (ErrorRecoveryLevel 2 only) leaves the session in
+    /// `retained_for_recovery`, to be resumed within Time2Retain.
     pub fn process_logout(&mut self, pdu: &IscsiPdu) -> ScsiResult<IscsiPdu> {
         let logout = pdu.parse_logout_request()?;
 
-        self.state = SessionState::Logout;
+        let response = match logout.reason {
+            pdu::logout_reason::CLOSE_SESSION => pdu::logout_response::SUCCESS,
+            pdu::logout_reason::CLOSE_CONNECTION | pdu::logout_reason::REMOVE_CONNECTION_FOR_RECOVERY
+                if logout.cid != self.cid =>
+            {
+                log::warn!("Logout for CID {} on connection {}: CID not found", logout.cid, self.cid);
+                pdu::logout_response::CID_NOT_FOUND
+            }
+            pdu::logout_reason::CLOSE_CONNECTION => pdu::logout_response::SUCCESS,
+            pdu::logout_reason::REMOVE_CONNECTION_FOR_RECOVERY if self.params.error_recovery_level < 2 => {
+                pdu::logout_response::CONNECTION_RECOVERY_NOT_SUPPORTED
+            }
+            pdu::logout_reason::REMOVE_CONNECTION_FOR_RECOVERY => {
+                self.retained_for_recovery = self.snapshot();
+                pdu::logout_response::SUCCESS
+            }
+            reason => {
+                log::warn!("Rejecting Logout Request with reserved reason code {}", reason);
+                return Ok(IscsiPdu::reject(
+                    pdu::reject_reason::INVALID_PDU_FIELD,
+                    self.next_stat_sn(),
+                    self.exp_cmd_sn,
+                    self.max_cmd_sn,
+                    &pdu.to_bytes()[..pdu::BHS_SIZE],
+                ));
+            }
+        };
+        if response == pdu::logout_response::SUCCESS {
+            self.state = SessionState::Logout;
+        }
 
         Ok(IscsiPdu::logout_response(
             logout.itt,
             self.next_stat_sn(),
             self.exp_cmd_sn,
             self.max_cmd_sn,
-            pdu::logout_response::SUCCESS,
+            response,
             self.params.default_time2wait,
             self.params.default_time2retain,
         ))
     }
 
     /// Process NOP-Out (ping) request
-    pub fn process_nop_out(&mut self, pdu: &IscsiPdu) -> ScsiResult<IscsiPdu> {
+    pub fn process_nop_out(&mut self, pdu: &IscsiPdu) -> ScsiResult<Option<IscsiPdu>> {
         let nop = pdu.parse_nop_out()?;
 
-        // Only respond if ITT is not 0xFFFFFFFF (unsolicited NOP-In)
+        // ITT 0xFFFFFFFF is the initiator answering a target-initiated NOP-In
         if nop.itt == 0xFFFF_FFFF {
-            // Target-initiated ping, initiator responding - no response needed
-            return Err(IscsiError::Protocol("Unsolicited NOP-Out response".to_string()));
+            if self.outstanding_ping_ttt == Some(nop.ttt) {
+                log::debug!("Keepalive NOP-In 0x{:08x} answered", nop.ttt);
+                self.outstanding_ping_ttt = None;
+            } else {
+                log::debug!("Ignoring NOP-Out for unknown ping TTT 0x{:08x}", nop.ttt);
+            }
+            return Ok(None);
         }
 
-        Ok(IscsiPdu::nop_in(
+        Ok(Some(IscsiPdu::nop_in(
             nop.itt,
             0xFFFF_FFFF, // TTT for response
             self.next_stat_sn(),
             self.exp_cmd_sn,
             self.max_cmd_sn,
             nop.lun,
-        ))
+        )))
+    }
+
+    /// Create a target-initiated NOP-In ping (RFC 3720 Section 10.19)
+    ///
+    /// The initiator must answer with a NOP-Out carrying the same TTT. StatSN
+    /// is not advanced because ITT is 0xFFFFFFFF.
+    pub fn create_nop_in_ping(&mut self) -> IscsiPdu {
+        let ttt = self.next_target_transfer_tag();
+        self.outstanding_ping_ttt = Some(ttt);
+        IscsiPdu::nop_in(0xFFFF_FFFF, ttt, self.stat_sn, self.exp_cmd_sn, self.max_cmd_sn, 0)
+    }
+
+    /// Create an unsolicited NOP-In advertising the current command window
+    ///
+    /// Sent when MaxCmdSN opens up while the initiator may have nothing
+    /// outstanding to learn it from. With ITT and TTT both 0xFFFFFFFF the
+    /// initiator sends no reply, and StatSN is not advanced.
+    pub fn create_window_update(&self) -> IscsiPdu {
+        IscsiPdu::nop_in(0xFFFF_FFFF, 0xFFFF_FFFF, self.stat_sn, self.exp_cmd_sn, self.max_cmd_sn, 0)
     }
 
     /// Handle SendTargets discovery request
-    pub fn handle_send_targets(&self, target_name: &str, target_address: &str) -> Vec<(String, String)> {
-        vec![
-            ("TargetName".to_string(), target_name.to_string()),
-            ("TargetAddress".to_string(), format!("{},1", target_address)),
-        ]
+    ///
+    /// `target_portals` are (address, TPGT) pairs in preference order, one
+    /// TargetAddress each.
+    ///
+    /// `query` is the SendTargets value: `All`, empty (the session's own
+    /// target) or a target name, which yields nothing unless it is ours.
+    pub fn handle_send_targets(&self, query: &str, target_name: &str, target_portals: &[(String, u16)]) -> Vec<(String, String)> {
+        if !query.is_empty() && query != "All" && query != target_name {
+            log::debug!("SendTargets for unknown target {}", query);
+            return Vec::new();
+        }
+        let mut params = vec![("TargetName".to_string(), target_name.to_string())];
+        for (address, tpgt) in target_portals {
+            params.push(("TargetAddress".to_string(), format!("{},{}", address, tpgt)));
+        }
+        params
+    }
+}
+
+impl IscsiSession {
+    /// Handle an `X-diagnostic.echo` Text key (full feature phase only)
+    ///
+    /// Echoes the payload along with the target's receive timestamp. Payloads
+    /// whose reply would not fit in one Text Response PDU are answered with
+    /// `Reject`.
+    pub fn handle_diagnostic_echo(&self, payload: &str) -> Vec<(String, String)> {
+        if self.state != SessionState::FullFeaturePhase {
+            return vec![(DIAGNOSTIC_ECHO_KEY.to_string(), "Reject".to_string())];
+        }
+
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        let params = vec![
+            (DIAGNOSTIC_ECHO_KEY.to_string(), payload.to_string()),
+            (DIAGNOSTIC_TIMESTAMP_KEY.to_string(), timestamp.to_string()),
+        ];
+
+        if serialize_text_parameters(&params).len() > self.params.max_xmit_data_segment_length as usize {
+            log::warn!("Rejecting {} payload of {} bytes: reply exceeds MaxRecvDataSegmentLength", DIAGNOSTIC_ECHO_KEY, payload.len());
+            return vec![(DIAGNOSTIC_ECHO_KEY.to_string(), "Reject".to_string())];
+        }
+
+        params
+    }
+
+    /// Answer the negotiation keys of a Text Request
+    ///
+    /// SendTargets and the diagnostic echo are handled by the caller; every
+    /// other key gets an answer so the initiator is never left waiting.
+    /// MaxRecvDataSegmentLength may be redeclared at any time. Other known
+    /// keys (MaxConnections, OFMarker, IFMarker and the rest) can only be
+    /// negotiated during login and are answered `Reject`; unknown keys are
+    /// `NotUnderstood`.
+    pub fn answer_text_keys(&mut self, params: &[(String, String)]) -> Vec<(String, String)> {
+        params.iter()
+            .filter(|(key, _)| key != "SendTargets" && key != DIAGNOSTIC_ECHO_KEY)
+            .map(|(key, value)| {
+                if key == "MaxRecvDataSegmentLength" {
+                    return self.apply_initiator_param(key, value).unwrap_or_else(|| {
+                        (key.clone(), self.params.max_recv_data_segment_length.to_string())
+                    });
+                }
+                let answer = if NEGOTIATION_KEYS.iter().any(|def| def.name == key) {
+                    log::warn!("Rejecting {}={} in a Text Request: only negotiable during login", key, value);
+                    "Reject"
+                } else {
+                    log::warn!("Unknown key {}={} in a Text Request - replying NotUnderstood", key, value);
+                    "NotUnderstood"
+                };
+                (key.clone(), answer.to_string())
+            })
+            .collect()
     }
 }
 
@@ -1183,6 +2344,36 @@ mod tests {
         assert_eq!(session.exp_cmd_sn, 1);
     }
 
+    #[test]
+    fn test_session_params_set_negotiated() {
+        let mut params = SessionParams::default();
+        assert!(params.set_negotiated("MaxBurstLength", "16384"));
+        assert!(params.set_negotiated("DataDigest", "CRC32C"));
+        assert!(params.set_negotiated("InitialR2T", "Yes"));
+        assert!(params.set_negotiated("TargetAlias", "disk"));
+        assert_eq!((params.max_burst_length, params.data_digest, params.initial_r2t), (16384, DigestType::CRC32C, true));
+        assert_eq!(params.target_alias, "disk");
+
+        assert!(!params.set_negotiated("ImmediateData", "Maybe"));
+        assert!(!params.set_negotiated("MaxRecvDataSegmentLength", "4096"));
+        assert!(!params.set_negotiated("X-unknown", "1"));
+        assert!(params.immediate_data);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_session_params_serialize() {
+        let params = SessionParams {
+            initiator_name: "iqn.test:host".to_string(),
+            header_digest: DigestType::CRC32C,
+            error_recovery_level: 1,
+            ..SessionParams::default()
+        };
+        let text = toml::to_string(&params).unwrap();
+        assert!(text.contains("header_digest = \"CRC32C\""));
+        assert_eq!(toml::from_str::<SessionParams>(&text).unwrap(), params);
+    }
+
     #[test]
     fn test_session_params_default() {
         let params = SessionParams::default();
@@ -1255,6 +2446,378 @@ mod tests {
         assert!(session.params.initial_r2t);
     }
 
+    #[test]
+    fn test_negotiation_conformance() {
+        let mut session = IscsiSession::new();
+        let reply = |key: &str, value: &str| Some((key.to_string(), value.to_string()));
+
+        // Out-of-range and malformed values are rejected without changing state
+        assert_eq!(session.apply_initiator_param("MaxRecvDataSegmentLength", "0"), reply("MaxRecvDataSegmentLength", "Reject"));
+        assert_eq!(session.params.max_xmit_data_segment_length, 8192);
+        assert_eq!(session.apply_initiator_param("ErrorRecoveryLevel", "3"), reply("ErrorRecoveryLevel", "Reject"));
+        assert_eq!(session.apply_initiator_param("ImmediateData", "Maybe"), reply("ImmediateData", "Reject"));
+        assert_eq!(session.apply_initiator_param("HeaderDigest", "MD5"), reply("HeaderDigest", "Reject"));
+        assert_eq!(session.apply_initiator_param("X-com.example.Unknown", "1"), reply("X-com.example.Unknown", "NotUnderstood"));
+
+        // MaxOutstandingR2T is bounded by the target's offer, not by 1
+        session.params.max_outstanding_r2t = 8;
+        assert_eq!(session.apply_initiator_param("MaxOutstandingR2T", "4"), None);
+        assert_eq!(session.params.max_outstanding_r2t, 4);
+
+        // FirstBurstLength never exceeds MaxBurstLength, whichever arrives first
+        assert_eq!(session.apply_initiator_param("FirstBurstLength", "65536"), None);
+        assert_eq!(session.apply_initiator_param("MaxBurstLength", "16384"), None);
+        assert_eq!(session.params.first_burst_length, 16384);
+
+        // In-order keys are OR-ed with the target's value
+        session.params.data_pdu_in_order = true;
+        session.apply_initiator_param("DataPDUInOrder", "No");
+        assert!(session.params.data_pdu_in_order);
+        session.apply_initiator_param("DataSequenceInOrder", "No");
+        assert!(session.params.data_sequence_in_order);
+        session.params.data_sequence_in_order = false;
+        session.apply_initiator_param("DataSequenceInOrder", "No");
+        assert!(!session.params.data_sequence_in_order);
+
+        // Keys without a session field are answered explicitly
+        assert_eq!(session.apply_initiator_param("MaxConnections", "4"), reply("MaxConnections", "1"));
+        assert_eq!(session.apply_initiator_param("OFMarker", "Yes"), reply("OFMarker", "No"));
+        assert_eq!(session.apply_initiator_param("IFMarkInt", "2048~8192"), reply("IFMarkInt", "Irrelevant"));
+    }
+
+    #[test]
+    fn test_login_response_carries_negotiation_replies() {
+        let data = serialize_text_parameters(&[
+            ("InitiatorName".to_string(), "iqn.test:initiator".to_string()),
+            ("TargetName".to_string(), "iqn.2025-12.test:disk1".to_string()),
+            ("MaxBurstLength".to_string(), "0".to_string()),
+            ("X-com.example.Unknown".to_string(), "1".to_string()),
+        ]);
+        let login = IscsiPdu::login_request([0x80, 1, 2, 3, 4, 5], 0, 0, 1, 0, 1, 3, true, data);
+
+        let mut session = IscsiSession::new();
+        let response = session.process_login(&login, "iqn.2025-12.test:disk1").unwrap();
+        assert_eq!(response.specific[16], pdu::login_status::SUCCESS);
+
+        let params = pdu::parse_text_parameters(&response.data).unwrap();
+        let values: Vec<&str> = params.iter()
+            .filter(|(k, _)| k == "MaxBurstLength")
+            .map(|(_, v)| v.as_str())
+            .collect();
+        assert_eq!(values, vec!["Reject"]);
+        assert!(params.contains(&("X-com.example.Unknown".to_string(), "NotUnderstood".to_string())));
+        assert_eq!(session.params.max_burst_length, 262144);
+    }
+
+    #[test]
+    fn test_login_text_continued_across_pdus() {
+        let data = serialize_text_parameters(&[
+            ("InitiatorName".to_string(), "iqn.test:initiator".to_string()),
+            ("TargetName".to_string(), "iqn.2025-12.test:disk1".to_string()),
+            ("MaxBurstLength".to_string(), "65536".to_string()),
+        ]);
+        // Split in the middle of the MaxBurstLength value
+        let split = data.len() - 4;
+        let isid = [0x80, 1, 2, 3, 4, 5];
+        let mut first = IscsiPdu::login_request(isid, 0, 0, 1, 0, 1, 3, false, data[..split].to_vec());
+        first.flags |= pdu::flags::CONTINUE_LOGIN;
+        let last = IscsiPdu::login_request(isid, 0, 0, 1, 0, 1, 3, true, data[split..].to_vec());
+
+        let mut session = IscsiSession::new();
+        let ack = session.process_login(&first, "iqn.2025-12.test:disk1").unwrap();
+        assert_eq!(ack.opcode, pdu::opcode::LOGIN_RESPONSE);
+        assert_eq!(ack.specific[16], pdu::login_status::SUCCESS);
+        assert_eq!(ack.flags & pdu::flags::TRANSIT, 0);
+        assert!(ack.data.is_empty());
+        assert_eq!(session.state, SessionState::Free, "nothing is applied until the final PDU");
+
+        let response = session.process_login(&last, "iqn.2025-12.test:disk1").unwrap();
+        assert_eq!(response.specific[16], pdu::login_status::SUCCESS);
+        assert_eq!(session.params.initiator_name, "iqn.test:initiator");
+        assert_eq!(session.params.max_burst_length, 65536);
+        assert!(session.pending_login_text.is_empty());
+
+        // C and T together are a protocol error
+        let mut session = IscsiSession::new();
+        let mut bad = IscsiPdu::login_request(isid, 0, 0, 1, 0, 1, 3, true, data.clone());
+        bad.flags |= pdu::flags::CONTINUE_LOGIN;
+        let response = session.process_login(&bad, "iqn.2025-12.test:disk1").unwrap();
+        assert_eq!(response.specific[16], pdu::login_status::INITIATOR_ERROR);
+    }
+
+    #[test]
+    fn test_initiator_compatibility_matrix() {
+        type Keys = &'static [(&'static str, &'static str)];
+        // CSG, NSG, transit and keys of each Login Request
+        type Pdu = (u8, u8, bool, Keys);
+        const DECLARATIVE: &[&str] = &["InitiatorName", "InitiatorAlias", "TargetName", "SessionType"];
+        const SECURITY: Keys = &[
+            ("InitiatorName", "iqn.1991-05.com.microsoft:host"),
+            ("InitiatorAlias", "host"),
+            ("SessionType", "Normal"),
+            ("TargetName", "iqn.2025-12.test:disk1"),
+            ("AuthMethod", "None"),
+        ];
+        // Initiator, its Login Requests, and answers it relies on
+        let profiles: &[(&str, &[Pdu], Keys)] = &[
+            ("Microsoft iSCSI Initiator", &[
+                (0, 1, true, SECURITY),
+                (1, 3, true, &[
+                    ("HeaderDigest", "CRC32C,None"),
+                    ("DataDigest", "CRC32C,None"),
+                    ("ErrorRecoveryLevel", "0"),
+                    ("InitialR2T", "No"),
+                    ("ImmediateData", "Yes"),
+                    ("MaxBurstLength", "262144"),
+                    ("FirstBurstLength", "65536"),
+                    ("MaxConnections", "4"),
+                    ("DataPDUInOrder", "Yes"),
+                    ("DataSequenceInOrder", "Yes"),
+                    ("MaxOutstandingR2T", "1"),
+                    ("DefaultTime2Wait", "0"),
+                    ("DefaultTime2Retain", "0"),
+                    ("MaxRecvDataSegmentLength", "65536"),
+                    ("IFMarker", "No"),
+                    ("OFMarker", "No"),
+                ]),
+            ], &[("AuthMethod", "None"), ("HeaderDigest", "CRC32C"), ("DataDigest", "CRC32C"), ("MaxConnections", "1")]),
+            ("open-iscsi", &[
+                (0, 1, true, SECURITY),
+                (1, 3, true, &[
+                    ("HeaderDigest", "None"),
+                    ("DataDigest", "None"),
+                    ("DefaultTime2Wait", "2"),
+                    ("DefaultTime2Retain", "0"),
+                    ("IFMarker", "No"),
+                    ("OFMarker", "No"),
+                    ("ErrorRecoveryLevel", "0"),
+                    ("InitialR2T", "No"),
+                    ("ImmediateData", "Yes"),
+                    ("MaxBurstLength", "16776192"),
+                    ("FirstBurstLength", "262144"),
+                    ("MaxOutstandingR2T", "1"),
+                    ("MaxConnections", "1"),
+                    ("DataPDUInOrder", "Yes"),
+                    ("DataSequenceInOrder", "Yes"),
+                    ("MaxRecvDataSegmentLength", "262144"),
+                ]),
+            ], &[("HeaderDigest", "None"), ("MaxConnections", "1"), ("IFMarker", "No")]),
+            ("Markers, negotiated over several PDUs", &[
+                (0, 1, true, SECURITY),
+                (1, 1, false, &[("HeaderDigest", "None,CRC32C"), ("OFMarker", "Yes"), ("OFMarkInt", "2048~8192")]),
+                (1, 3, true, &[("IFMarker", "Yes"), ("IFMarkInt", "2048~8192"), ("MaxConnections", "1")]),
+            ], &[("HeaderDigest", "None"), ("OFMarker", "No"), ("OFMarkInt", "Irrelevant"), ("IFMarkInt", "Irrelevant")]),
+        ];
+
+        for (name, pdus, expected) in profiles {
+            let mut session = IscsiSession::new();
+            let mut answers: Vec<(String, String)> = Vec::new();
+            for (cmd_sn, (csg, nsg, transit, keys)) in pdus.iter().enumerate() {
+                let data = serialize_text_parameters(&keys.iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect::<Vec<_>>());
+                let login = IscsiPdu::login_request([0x40, 0, 1, 0x37, 0, 1], 0, 1, cmd_sn as u32, 0, *csg, *nsg, *transit, data);
+                let response = session.process_login(&login, "iqn.2025-12.test:disk1").unwrap();
+                assert_eq!(response.specific[16], pdu::login_status::SUCCESS, "{}", name);
+                assert_eq!(response.flags & crate::pdu::flags::TRANSIT != 0, *transit, "{}: transit refused", name);
+
+                // Every negotiated key is answered, in the order it was proposed
+                let params = pdu::parse_text_parameters(&response.data).unwrap();
+                let proposed: Vec<&str> = keys.iter()
+                    .map(|(key, _)| *key)
+                    .filter(|key| !DECLARATIVE.contains(key))
+                    .collect();
+                let answered: Vec<&str> = params.iter()
+                    .map(|(key, _)| key.as_str())
+                    .filter(|key| proposed.contains(key))
+                    .collect();
+                assert_eq!(answered, proposed, "{}", name);
+                answers.extend(params);
+            }
+            assert_eq!(session.state, SessionState::FullFeaturePhase, "{}", name);
+
+            for (key, value) in *expected {
+                assert!(answers.contains(&(key.to_string(), value.to_string())), "{}: expected {}={}", name, key, value);
+            }
+            let mut keys: Vec<&str> = answers.iter().map(|(key, _)| key.as_str()).collect();
+            let total = keys.len();
+            keys.sort_unstable();
+            keys.dedup();
+            assert_eq!(keys.len(), total, "{}: a key was answered twice", name);
+        }
+    }
+
+    #[test]
+    fn test_text_request_keys_are_answered() {
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        let params: Vec<(String, String)> = [
+            ("SendTargets", "All"),
+            ("MaxConnections", "2"),
+            ("OFMarker", "Yes"),
+            ("IFMarkInt", "2048~8192"),
+            ("MaxRecvDataSegmentLength", "16384"),
+            ("X-com.example.Unknown", "1"),
+        ].iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+
+        let answers = session.answer_text_keys(&params);
+        let expected: Vec<(String, String)> = [
+            ("MaxConnections", "Reject"),
+            ("OFMarker", "Reject"),
+            ("IFMarkInt", "Reject"),
+            ("MaxRecvDataSegmentLength", "8192"),
+            ("X-com.example.Unknown", "NotUnderstood"),
+        ].iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        assert_eq!(answers, expected);
+        assert_eq!(session.params.max_xmit_data_segment_length, 16384);
+
+        let bad = [("MaxRecvDataSegmentLength".to_string(), "1".to_string())];
+        assert_eq!(session.answer_text_keys(&bad)[0].1, "Reject");
+        assert_eq!(session.params.max_xmit_data_segment_length, 16384);
+    }
+
+    #[test]
+    fn test_chap_algorithm_negotiation() {
+        let pairs = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+            list.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        let chap_session = |allow_md5: bool| {
+            let mut session = IscsiSession::new();
+            session.set_auth_config(AuthConfig::Chap {
+                credentials: crate::auth::ChapCredentials::new("user", "user-secret-123"),
+            });
+            session.set_allow_md5_chap(allow_md5);
+            session.handle_chap_auth(&pairs(&[("AuthMethod", "CHAP")])).unwrap();
+            session
+        };
+
+        // The initiator's first choice wins
+        let mut session = chap_session(true);
+        let (done, params) = session.handle_chap_auth(&pairs(&[("CHAP_A", "7,5")])).unwrap();
+        assert!(!done);
+        assert_eq!(params[0], ("CHAP_A".to_string(), "7".to_string()));
+        let state = session.chap_state.clone().unwrap();
+        assert_eq!(state.algorithm, ChapAlgorithm::Sha256);
+        assert_eq!(state.challenge.len(), 32);
+
+        let response = format!("0x{}", hex::encode(state.calculate_response("user-secret-123")));
+        let (done, _) = session.handle_chap_auth(&pairs(&[("CHAP_N", "user"), ("CHAP_R", &response)])).unwrap();
+        assert!(done);
+
+        // An MD5 response does not satisfy a SHA3-256 challenge
+        let mut session = chap_session(true);
+        session.handle_chap_auth(&pairs(&[("CHAP_A", "8,7,5")])).unwrap();
+        let mut md5_state = session.chap_state.clone().unwrap();
+        assert_eq!(md5_state.algorithm, ChapAlgorithm::Sha3_256);
+        md5_state.algorithm = ChapAlgorithm::Md5;
+        let response = format!("0x{}", hex::encode(md5_state.calculate_response("user-secret-123")));
+        assert!(session.handle_chap_auth(&pairs(&[("CHAP_N", "user"), ("CHAP_R", &response)])).is_err());
+
+        // MD5 still works by default, but not once forbidden
+        let mut session = chap_session(true);
+        let (_, params) = session.handle_chap_auth(&pairs(&[("CHAP_A", "5")])).unwrap();
+        assert_eq!(params[0].1, "5");
+        let mut session = chap_session(false);
+        assert!(session.handle_chap_auth(&pairs(&[("CHAP_A", "5")])).is_err());
+        let mut session = chap_session(false);
+        let (_, params) = session.handle_chap_auth(&pairs(&[("CHAP_A", "5,8")])).unwrap();
+        assert_eq!(params[0].1, "8");
+    }
+
+    #[test]
+    fn test_mutual_chap_rejects_reflection() {
+        let pairs = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+            list.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        let control = TargetControl::new(crate::control::TargetConfig::new("iqn.2025-12.test:disk1"));
+        let challenged_session = || {
+            let mut session = IscsiSession::new();
+            session.set_control(control.clone());
+            session.set_auth_config(AuthConfig::MutualChap {
+                target_credentials: crate::auth::ChapCredentials::new("user", "user-secret-123"),
+                initiator_credentials: crate::auth::ChapCredentials::new("target", "target-secret-123"),
+            });
+            session.handle_chap_auth(&pairs(&[("AuthMethod", "CHAP")])).unwrap();
+            session.handle_chap_auth(&pairs(&[("CHAP_A", "5")])).unwrap();
+            let state = session.chap_state.clone().unwrap();
+            let response = format!("0x{}", hex::encode(state.calculate_response("user-secret-123")));
+            (session, state, response)
+        };
+
+        // Echoing our challenge back is refused
+        let (mut session, state, response) = challenged_session();
+        let reflected = state.challenge_hex();
+        let result = session.handle_chap_auth(&pairs(&[
+            ("CHAP_N", "user"), ("CHAP_R", &response), ("CHAP_I", "7"), ("CHAP_C", &reflected),
+        ]));
+        assert!(result.is_err());
+        assert!(!session.chap_completed);
+
+        // So is the challenge another connection's login was given
+        let (mut session, _, response) = challenged_session();
+        let (_other, other_state, _) = challenged_session();
+        let result = session.handle_chap_auth(&pairs(&[
+            ("CHAP_N", "user"), ("CHAP_R", &response), ("CHAP_I", "7"), ("CHAP_C", &other_state.challenge_hex()),
+        ]));
+        assert!(result.is_err());
+
+        // A challenge given out by another target is not the target's own
+        let (mut session, _, response) = challenged_session();
+        let mut elsewhere = IscsiSession::new();
+        elsewhere.set_control(TargetControl::new(crate::control::TargetConfig::new("iqn.2025-12.test:disk2")));
+        elsewhere.set_auth_config(session.auth_config.clone());
+        elsewhere.handle_chap_auth(&pairs(&[("AuthMethod", "CHAP")])).unwrap();
+        elsewhere.handle_chap_auth(&pairs(&[("CHAP_A", "5")])).unwrap();
+        let foreign = elsewhere.chap_state.as_ref().unwrap().challenge_hex();
+        let (done, _) = session.handle_chap_auth(&pairs(&[
+            ("CHAP_N", "user"), ("CHAP_R", &response), ("CHAP_I", "7"), ("CHAP_C", &foreign),
+        ])).unwrap();
+        assert!(done);
+
+        // A challenge of the initiator's own is answered
+        let (mut session, _, response) = challenged_session();
+        let (done, params) = session.handle_chap_auth(&pairs(&[
+            ("CHAP_N", "user"), ("CHAP_R", &response), ("CHAP_I", "7"), ("CHAP_C", "0x00112233445566778899aabbccddeeff"),
+        ])).unwrap();
+        assert!(done);
+        assert_eq!(params[0], ("CHAP_N".to_string(), "target".to_string()));
+    }
+
+    #[test]
+    fn test_mutual_chap_response_transits() {
+        let isid = [0x80, 1, 2, 3, 4, 5];
+        let login = |cmd_sn: u32, transit: bool, params: &[(&str, &str)]| {
+            let params: Vec<(String, String)> =
+                params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            IscsiPdu::login_request(isid, 0, 0, cmd_sn, 0, 0, 1, transit, serialize_text_parameters(&params))
+        };
+        let mut session = IscsiSession::new();
+        session.set_auth_config(AuthConfig::MutualChap {
+            target_credentials: crate::auth::ChapCredentials::new("user", "user-secret-123"),
+            initiator_credentials: crate::auth::ChapCredentials::new("target", "target-secret-123"),
+        });
+
+        session.process_login(&login(1, false, &[
+            ("InitiatorName", "iqn.test:initiator"), ("SessionType", "Normal"), ("AuthMethod", "CHAP"),
+        ]), "iqn.2025-12.test:disk1").unwrap();
+        let response = session.process_login(&login(1, false, &[("CHAP_A", "5")]), "iqn.2025-12.test:disk1").unwrap();
+        assert_eq!(response.flags & pdu::flags::TRANSIT, 0, "challenge is sent without transiting");
+        let state = session.chap_state.clone().unwrap();
+        let answer = format!("0x{}", hex::encode(state.calculate_response("user-secret-123")));
+
+        // The response carrying the target's CHAP_R honours the initiator's transit request
+        let response = session.process_login(&login(1, true, &[
+            ("CHAP_N", "user"), ("CHAP_R", &answer), ("CHAP_I", "7"), ("CHAP_C", "0x00112233445566778899aabbccddeeff"),
+        ]), "iqn.2025-12.test:disk1").unwrap();
+        assert_eq!(response.specific[16], pdu::login_status::SUCCESS);
+        assert_eq!(response.flags & pdu::flags::TRANSIT, pdu::flags::TRANSIT);
+        assert_eq!(response.flags & 0x03, 1, "NSG: LoginOperationalNegotiation");
+        let params = pdu::parse_text_parameters(&response.data).unwrap();
+        assert_eq!(params[0], ("CHAP_N".to_string(), "target".to_string()));
+        assert_eq!(params[1].0, "CHAP_R");
+        assert_eq!(session.state, SessionState::LoginOperationalNegotiation);
+    }
+
     #[test]
     fn test_sequence_number_validation() {
         let mut session = IscsiSession::new();
@@ -1265,12 +2828,24 @@ mod tests {
         assert!(session.validate_cmd_sn(100));
         assert_eq!(session.exp_cmd_sn, 101); // Should advance
 
-        // Also in window
-        assert!(session.validate_cmd_sn(105));
-
-        // Out of window - invalid
-        assert!(!session.validate_cmd_sn(50));
-        assert!(!session.validate_cmd_sn(200));
+        // Also in window, but has to wait for 101..=104
+        assert_eq!(session.check_cmd_sn(105), CmdSnCheck::Early);
+        assert_eq!(session.exp_cmd_sn, 101);
+
+        // Already delivered, and out of window
+        assert_eq!(session.check_cmd_sn(100), CmdSnCheck::Duplicate);
+        assert_eq!(session.check_cmd_sn(50), CmdSnCheck::Duplicate);
+        assert_eq!(session.check_cmd_sn(200), CmdSnCheck::OutOfWindow);
+
+        // Held requests are handed back in CmdSN order
+        let early = IscsiPdu { itt: 7, ..IscsiPdu::new() };
+        assert!(session.hold_command(102, &early));
+        assert!(!session.hold_command(102, &early));
+        assert!(session.take_ready_command().is_none());
+        assert!(session.validate_cmd_sn(101));
+        assert_eq!(session.take_ready_command().map(|pdu| pdu.itt), Some(7));
+        assert!(session.validate_cmd_sn(102));
+        assert_eq!(session.exp_cmd_sn, 103);
     }
 
     #[test]
@@ -1329,14 +2904,52 @@ mod tests {
     #[test]
     fn test_send_targets() {
         let session = IscsiSession::new();
-        let targets = session.handle_send_targets(
-            "iqn.2025-12.local:storage",
-            "192.168.1.100:3260"
-        );
+        let portals = [("192.168.1.100:3260".to_string(), 1)];
+        let targets = session.handle_send_targets("All", "iqn.2025-12.local:storage", &portals);
 
         assert_eq!(targets.len(), 2);
         assert!(targets.iter().any(|(k, v)| k == "TargetName" && v == "iqn.2025-12.local:storage"));
         assert!(targets.iter().any(|(k, v)| k == "TargetAddress" && v == "192.168.1.100:3260,1"));
+
+        // A query naming this target, or none, gets the same record
+        assert_eq!(session.handle_send_targets("iqn.2025-12.local:storage", "iqn.2025-12.local:storage", &portals), targets);
+        assert_eq!(session.handle_send_targets("", "iqn.2025-12.local:storage", &portals), targets);
+        assert!(session.handle_send_targets("iqn.2025-12.local:other", "iqn.2025-12.local:storage", &portals).is_empty());
+    }
+
+    #[test]
+    fn test_send_targets_multiple_portals() {
+        let session = IscsiSession::new();
+        let targets = session.handle_send_targets(
+            "All",
+            "iqn.2025-12.local:storage",
+            &[("[fd00::1]:3260".to_string(), 1), ("10.0.0.1:3261".to_string(), 2)]
+        );
+
+        assert_eq!(targets, vec![
+            ("TargetName".to_string(), "iqn.2025-12.local:storage".to_string()),
+            ("TargetAddress".to_string(), "[fd00::1]:3260,1".to_string()),
+            ("TargetAddress".to_string(), "10.0.0.1:3261,2".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_diagnostic_echo() {
+        let mut session = IscsiSession::new();
+
+        // Not available before full feature phase
+        let reply = session.handle_diagnostic_echo("ping");
+        assert_eq!(reply, vec![(DIAGNOSTIC_ECHO_KEY.to_string(), "Reject".to_string())]);
+
+        session.state = SessionState::FullFeaturePhase;
+        let reply = session.handle_diagnostic_echo("ping");
+        assert_eq!(reply[0], (DIAGNOSTIC_ECHO_KEY.to_string(), "ping".to_string()));
+        assert_eq!(reply[1].0, DIAGNOSTIC_TIMESTAMP_KEY);
+        assert!(reply[1].1.parse::<u64>().unwrap() > 0);
+
+        // Oversized payload
+        let reply = session.handle_diagnostic_echo(&"x".repeat(9000));
+        assert_eq!(reply, vec![(DIAGNOSTIC_ECHO_KEY.to_string(), "Reject".to_string())]);
     }
 
     #[test]
@@ -1349,7 +2962,382 @@ mod tests {
         session.apply_initiator_param("HeaderDigest", "CRC32C");
         assert_eq!(session.params.header_digest, DigestType::CRC32C);
 
+        // The initiator's order of preference decides (RFC 3720 Section 5.2.2)
         session.apply_initiator_param("HeaderDigest", "None,CRC32C");
+        assert_eq!(session.params.header_digest, DigestType::None);
+
+        session.apply_initiator_param("HeaderDigest", "CRC32C,None");
         assert_eq!(session.params.header_digest, DigestType::CRC32C);
     }
+
+    #[test]
+    fn test_nop_in_ping() {
+        let mut session = IscsiSession::new();
+        session.stat_sn = 7;
+
+        let ping = session.create_nop_in_ping();
+        assert_eq!(ping.opcode, crate::pdu::opcode::NOP_IN);
+        assert_eq!(ping.itt, 0xFFFF_FFFF);
+        let ttt = u32::from_be_bytes(ping.specific[0..4].try_into().unwrap());
+        assert_ne!(ttt, 0xFFFF_FFFF);
+        assert_eq!(u32::from_be_bytes(ping.specific[4..8].try_into().unwrap()), 7);
+        assert_eq!(session.stat_sn, 7, "Ping must not advance StatSN");
+
+        let mut reply = IscsiPdu::new();
+        reply.opcode = crate::pdu::opcode::NOP_OUT;
+        reply.itt = 0xFFFF_FFFF;
+
+        // Reply for some other TTT leaves the ping outstanding
+        reply.specific[0..4].copy_from_slice(&(ttt + 1).to_be_bytes());
+        assert!(session.process_nop_out(&reply).unwrap().is_none());
+        assert_eq!(session.outstanding_ping_ttt, Some(ttt));
+
+        reply.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+        assert!(session.process_nop_out(&reply).unwrap().is_none());
+        assert_eq!(session.outstanding_ping_ttt, None);
+    }
+
+    #[test]
+    fn test_open_command_window() {
+        let mut session = IscsiSession::new();
+        session.exp_cmd_sn = 10;
+        session.max_cmd_sn = 10;
+
+        session.open_command_window(32);
+        assert_eq!(session.max_cmd_sn, 41);
+        assert!(session.validate_cmd_sn(10));
+        assert_eq!(session.check_cmd_sn(42), CmdSnCheck::Early);
+        assert_eq!(session.check_cmd_sn(43), CmdSnCheck::OutOfWindow);
+
+        // Never shrinks an already wider window
+        session.open_command_window(1);
+        assert_eq!(session.max_cmd_sn, 42);
+
+        // A held window closes as commands arrive, and reopens afterwards
+        session.command_window_held = true;
+        for cmd_sn in 11..=42 {
+            assert!(session.validate_cmd_sn(cmd_sn));
+        }
+        assert_eq!((session.exp_cmd_sn, session.max_cmd_sn), (43, 42));
+        assert!(!session.validate_cmd_sn(43));
+        session.command_window_held = false;
+        session.open_command_window(4);
+        assert_eq!(session.max_cmd_sn, 46);
+    }
+
+    #[test]
+    fn test_unsolicited_data_end() {
+        let mut session = IscsiSession::new();
+        session.params.initial_r2t = false;
+        session.params.first_burst_length = 65536;
+
+        // F bit clear: unsolicited Data-Out follows up to FirstBurstLength
+        assert_eq!(session.unsolicited_data_end(false, 8192, 262144), 65536);
+        assert_eq!(session.unsolicited_data_end(false, 0, 16384), 16384);
+        // F bit set: only the immediate data is unsolicited
+        assert_eq!(session.unsolicited_data_end(true, 8192, 262144), 8192);
+
+        session.params.initial_r2t = true;
+        assert_eq!(session.unsolicited_data_end(false, 8192, 262144), 8192);
+    }
+
+    #[test]
+    fn test_session_snapshot_roundtrip() {
+        let mut session = IscsiSession::new();
+        assert!(session.snapshot().is_none(), "only full feature sessions are snapshotted");
+
+        session.isid = [0x80, 1, 2, 3, 4, 5];
+        session.tsih = 42;
+        session.state = SessionState::FullFeaturePhase;
+        session.params.initiator_name = "iqn.test:initiator".to_string();
+        session.params.target_name = "iqn.2025-12.test:disk1".to_string();
+        session.params.max_burst_length = 131072;
+        session.params.immediate_data = false;
+        session.stat_sn = 17;
+
+        let snapshot = session.snapshot().unwrap();
+        assert!(snapshot.params.iter().all(|(key, _)| key != "MaxRecvDataSegmentLength"));
+        let parsed = SessionSnapshot::from_text(&snapshot.to_text()).unwrap();
+        assert_eq!(parsed, snapshot);
+
+        let mut resumed = IscsiSession::new();
+        resumed.restore(&parsed);
+        assert_eq!(resumed.tsih, 42);
+        assert_eq!(resumed.stat_sn, 17);
+        assert_eq!(resumed.params.max_burst_length, 131072);
+        assert!(!resumed.params.immediate_data);
+
+        assert!(SessionSnapshot::from_text("TSIH=0\n").is_err());
+    }
+
+    #[test]
+    fn test_login_with_tsih() {
+        let data = serialize_text_parameters(&[
+            ("InitiatorName".to_string(), "iqn.test:initiator".to_string()),
+            ("TargetName".to_string(), "iqn.2025-12.test:disk1".to_string()),
+        ]);
+        let isid = [0x80, 1, 2, 3, 4, 5];
+        let login = IscsiPdu::login_request(isid, 42, 0, 1, 0, 1, 3, true, data);
+
+        // Unknown session
+        let mut session = IscsiSession::new();
+        let response = session.process_login(&login, "iqn.2025-12.test:disk1").unwrap();
+        assert_eq!((response.specific[16], response.specific[17]), (pdu::login_status::INITIATOR_ERROR, 0x0A));
+
+        // Restored session keeps its TSIH
+        let mut session = IscsiSession::new();
+        session.restore(&SessionSnapshot {
+            isid,
+            tsih: 42,
+            initiator_name: "iqn.test:initiator".to_string(),
+            target_name: "iqn.2025-12.test:disk1".to_string(),
+            exp_cmd_sn: 1,
+            max_cmd_sn: 32,
+            stat_sn: 9,
+            params: Vec::new(),
+        });
+        let response = session.process_login(&login, "iqn.2025-12.test:disk1").unwrap();
+        assert_eq!(response.specific[16], pdu::login_status::SUCCESS);
+        assert_eq!(session.state, SessionState::FullFeaturePhase);
+        assert_eq!(session.tsih, 42);
+    }
+
+    #[test]
+    fn test_login_acl_patterns_and_networks() {
+        let data = serialize_text_parameters(&[
+            ("InitiatorName".to_string(), "iqn.2025-12.example:host-17".to_string()),
+            ("TargetName".to_string(), "iqn.2025-12.test:disk1".to_string()),
+        ]);
+        let login = IscsiPdu::login_request([0x80, 1, 2, 3, 4, 5], 0, 0, 1, 0, 1, 3, true, data);
+        let status = |patterns: &[&str], networks: &[&str], addr: Option<&str>| {
+            let mut session = IscsiSession::new();
+            session.set_allowed_initiators(Some(patterns.iter().map(|p| p.to_string()).collect()));
+            if !networks.is_empty() {
+                session.set_allowed_networks(Some(networks.iter().map(|n| n.parse().unwrap()).collect()));
+            }
+            if let Some(addr) = addr {
+                session.set_initiator_addr(addr.parse().unwrap());
+            }
+            let response = session.process_login(&login, "iqn.2025-12.test:disk1").unwrap();
+            (response.specific[16], response.specific[17])
+        };
+        let denied = (pdu::login_status::INITIATOR_ERROR, 0x02);
+
+        assert_eq!(status(&["iqn.2025-12.example:host-*"], &[], None).0, pdu::login_status::SUCCESS);
+        assert_eq!(status(&["iqn.2025-12.example:db-*", "iqn.2025-12.example:host-1?"], &[], None).0, pdu::login_status::SUCCESS);
+        assert_eq!(status(&["iqn.2025-12.example:host-1"], &[], None), denied);
+
+        // A matching IQN must also connect from an allowed network
+        let host = ["iqn.2025-12.example:host-*"];
+        assert_eq!(status(&host, &["10.0.0.0/8", "fd00::/8"], Some("10.4.0.9")).0, pdu::login_status::SUCCESS);
+        assert_eq!(status(&host, &["10.0.0.0/8", "fd00::/8"], Some("::ffff:10.4.0.9")).0, pdu::login_status::SUCCESS);
+        assert_eq!(status(&host, &["10.0.0.0/8"], Some("192.168.0.9")), denied);
+        assert_eq!(status(&host, &["10.0.0.0/8"], None), denied);
+    }
+
+    #[test]
+    fn test_login_rejects_illegal_stages() {
+        let login = |csg, nsg, transit, keys: &[(&str, &str)]| {
+            let mut params = vec![
+                ("InitiatorName".to_string(), "iqn.test:initiator".to_string()),
+                ("TargetName".to_string(), "iqn.2025-12.test:disk1".to_string()),
+            ];
+            params.extend(keys.iter().map(|(key, value)| (key.to_string(), value.to_string())));
+            IscsiPdu::login_request([0x80, 1, 2, 3, 4, 5], 0, 0, 1, 0, csg, nsg, transit, serialize_text_parameters(&params))
+        };
+        let status = |session: &mut IscsiSession, pdu: &IscsiPdu| {
+            let response = session.process_login(pdu, "iqn.2025-12.test:disk1").unwrap();
+            (response.specific[16], response.specific[17])
+        };
+        let initiator_error = (pdu::login_status::INITIATOR_ERROR, 0x00);
+
+        for (csg, nsg, transit) in [(2, 3, true), (3, 3, true), (3, 0, false), (1, 1, true), (1, 0, true), (0, 2, true), (0, 0, true)] {
+            let mut session = IscsiSession::new();
+            assert_eq!(status(&mut session, &login(csg, nsg, transit, &[])), initiator_error, "CSG={} NSG={}", csg, nsg);
+            assert_eq!(session.state, SessionState::Free);
+        }
+        // NSG is ignored without the T bit
+        let mut session = IscsiSession::new();
+        assert_eq!(status(&mut session, &login(1, 0, false, &[])).0, pdu::login_status::SUCCESS);
+
+        // No going back to SecurityNegotiation
+        let mut session = IscsiSession::new();
+        assert_eq!(status(&mut session, &login(0, 1, true, &[("AuthMethod", "None")])).0, pdu::login_status::SUCCESS);
+        assert_eq!(status(&mut session, &login(0, 1, true, &[])), initiator_error);
+
+        // Security keys belong to SecurityNegotiation
+        let mut session = IscsiSession::new();
+        assert_eq!(status(&mut session, &login(1, 3, true, &[("AuthMethod", "None")])), initiator_error);
+
+        // Operational keys may not ride along with a CHAP response
+        let mut session = IscsiSession::new();
+        session.chap_state = Some(ChapAuthState::for_challenge(1, vec![0; 16], false, ChapAlgorithm::Md5));
+        let chap_response = login(0, 1, true, &[("CHAP_N", "user"), ("CHAP_R", "0x00"), ("HeaderDigest", "None")]);
+        assert_eq!(status(&mut session, &chap_response), initiator_error);
+    }
+
+    #[test]
+    fn test_strict_login_keys() {
+        let login = |csg, nsg, transit, keys: &[(&str, &str)]| {
+            let params: Vec<_> = keys.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            IscsiPdu::login_request([0x80, 1, 2, 3, 4, 5], 0, 0, 1, 0, csg, nsg, transit, serialize_text_parameters(&params))
+        };
+        let status = |session: &mut IscsiSession, pdu: &IscsiPdu| {
+            session.process_login(pdu, "iqn.2025-12.test:disk1").unwrap().specific[16]
+        };
+        let first = login(0, 1, true, &[
+            ("InitiatorName", "iqn.test:initiator"), ("TargetName", "iqn.2025-12.test:disk1"), ("AuthMethod", "None"),
+        ]);
+        let redeclared = login(1, 3, true, &[("InitiatorName", "iqn.test:initiator"), ("MaxBurstLength", "65536")]);
+
+        // Permissive sessions put up with initiators repeating themselves
+        let mut session = IscsiSession::new();
+        assert_eq!(status(&mut session, &first), pdu::login_status::SUCCESS);
+        assert_eq!(status(&mut session, &redeclared), pdu::login_status::SUCCESS);
+        assert_eq!(session.state, SessionState::FullFeaturePhase);
+
+        let strict = || {
+            let mut session = IscsiSession::new();
+            session.set_strictness(Strictness::Strict);
+            session
+        };
+        let mut session = strict();
+        assert_eq!(status(&mut session, &first), pdu::login_status::SUCCESS);
+        assert_eq!(status(&mut session, &redeclared), pdu::login_status::INITIATOR_ERROR);
+        assert_eq!(status(&mut session, &login(1, 3, true, &[("MaxBurstLength", "65536")])), pdu::login_status::SUCCESS);
+
+        for keys in [&[("Initiator Name", "iqn.test:initiator")][..], &[("HeaderDigest", "None"), ("HeaderDigest", "None")]] {
+            let mut session = strict();
+            assert_eq!(status(&mut session, &login(0, 1, true, keys)), pdu::login_status::INITIATOR_ERROR, "{:?}", keys);
+        }
+    }
+
+    #[test]
+    fn test_pending_write_data_out_validation() {
+        let params = SessionParams::default();
+        let data_out = |ttt: u32, data_sn: u32, offset: u32, len: usize, final_flag: bool| pdu::ScsiDataOutPdu {
+            lun: 0,
+            itt: 1,
+            ttt,
+            exp_stat_sn: 0,
+            data_sn,
+            buffer_offset: offset,
+            data: vec![0; len],
+            final_flag,
+        };
+        let pending = || PendingWrite {
+            transfer_length: 4,
+            block_size: 512,
+            bytes_received: 512,
+            unsolicited_end: 1024,
+            unsolicited_offset: 512,
+            next_r2t_offset: 2048,
+            outstanding_r2ts: vec![OutstandingR2t { ttt: 7, r2t_sn: 0, offset: 1024, length: 1024, next_data_sn: 0, bytes_received: 0 }],
+            ..PendingWrite::default()
+        };
+
+        // Unsolicited data continues after the immediate data
+        let mut write = pending();
+        assert!(!write.accept_data_out(&data_out(0xFFFF_FFFF, 0, 512, 512, true), &params).unwrap());
+        // Solicited sequence: in order, DataSN from 0, F bit closes it
+        assert!(!write.accept_data_out(&data_out(7, 0, 1024, 512, false), &params).unwrap());
+        assert!(write.accept_data_out(&data_out(7, 1, 1536, 512, true), &params).unwrap());
+        assert_eq!(write.bytes_received, 2048);
+        assert!(write.outstanding_r2ts.is_empty());
+
+        let violations = [
+            data_out(0xFFFF_FFFF, 0, 512, 1024, false), // past FirstBurstLength
+            data_out(0xFFFF_FFFF, 1, 512, 512, false),  // DataSN gap
+            data_out(9, 0, 1024, 512, false),           // no such R2T
+            data_out(7, 1, 1024, 512, false),           // DataSN gap
+            data_out(7, 0, 1536, 512, false),           // out of order
+            data_out(7, 0, 1024, 2048, false),          // beyond the R2T
+            data_out(7, 0, 1024, 512, true),            // sequence ended early
+        ];
+        for (i, violation) in violations.iter().enumerate() {
+            assert!(pending().accept_data_out(violation, &params).is_err(), "violation {}", i);
+        }
+
+        // DataPDUInOrder=No accepts any offset within the R2T
+        let relaxed = SessionParams { data_pdu_in_order: false, ..SessionParams::default() };
+        assert!(pending().accept_data_out(&data_out(7, 0, 1536, 512, false), &relaxed).is_ok());
+
+        // DataSequenceInOrder=No accepts a later R2T's sequence first
+        let two_r2ts = || PendingWrite {
+            transfer_length: 6,
+            next_r2t_offset: 3072,
+            outstanding_r2ts: vec![
+                OutstandingR2t { ttt: 7, r2t_sn: 0, offset: 1024, length: 1024, next_data_sn: 0, bytes_received: 0 },
+                OutstandingR2t { ttt: 8, r2t_sn: 1, offset: 2048, length: 1024, next_data_sn: 0, bytes_received: 0 },
+            ],
+            ..pending()
+        };
+        assert!(two_r2ts().accept_data_out(&data_out(8, 0, 2048, 1024, true), &params).is_err());
+        let unordered = SessionParams { data_sequence_in_order: false, ..SessionParams::default() };
+        let mut write = two_r2ts();
+        assert!(write.accept_data_out(&data_out(8, 0, 2048, 1024, true), &unordered).unwrap());
+        assert!(write.accept_data_out(&data_out(7, 0, 1024, 1024, true), &unordered).unwrap());
+        assert_eq!(write.bytes_received, 2560);
+        assert!(write.outstanding_r2ts.is_empty());
+    }
+
+    #[test]
+    fn test_logout_reason_codes() {
+        use crate::pdu::{flags, logout_reason, logout_response, opcode};
+
+        let logout = |reason: u8, cid: u16| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::LOGOUT_REQUEST;
+            pdu.flags = flags::FINAL | reason;
+            pdu.itt = 0x40;
+            pdu.specific[0..2].copy_from_slice(&cid.to_be_bytes());
+            pdu
+        };
+        let session = || {
+            let mut session = IscsiSession::new();
+            session.state = SessionState::FullFeaturePhase;
+            session.session_type = SessionType::Normal;
+            session.tsih = 3;
+            session.cid = 7;
+            session
+        };
+
+        // Closing another connection's CID leaves this one running
+        let mut s = session();
+        let response = s.process_logout(&logout(logout_reason::CLOSE_CONNECTION, 8)).unwrap();
+        assert_eq!(response.logout_response_code(), logout_response::CID_NOT_FOUND);
+        assert_eq!(s.state, SessionState::FullFeaturePhase);
+
+        let response = s.process_logout(&logout(logout_reason::CLOSE_CONNECTION, 7)).unwrap();
+        assert_eq!(response.logout_response_code(), logout_response::SUCCESS);
+        assert_eq!(s.state, SessionState::Logout);
+        assert!(s.retained_for_recovery.is_none());
+
+        // The CID is ignored when closing the whole session
+        let mut s = session();
+        let response = s.process_logout(&logout(logout_reason::CLOSE_SESSION, 99)).unwrap();
+        assert_eq!(response.logout_response_code(), logout_response::SUCCESS);
+        assert_eq!(s.state, SessionState::Logout);
+
+        // Connection recovery needs ErrorRecoveryLevel 2
+        let mut s = session();
+        let response = s.process_logout(&logout(logout_reason::REMOVE_CONNECTION_FOR_RECOVERY, 7)).unwrap();
+        assert_eq!(response.logout_response_code(), logout_response::CONNECTION_RECOVERY_NOT_SUPPORTED);
+        assert_eq!(s.state, SessionState::FullFeaturePhase);
+
+        s.params.error_recovery_level = 2;
+        s.params.default_time2retain = 20;
+        let response = s.process_logout(&logout(logout_reason::REMOVE_CONNECTION_FOR_RECOVERY, 7)).unwrap();
+        assert_eq!(response.logout_response_code(), logout_response::SUCCESS);
+        assert_eq!(&response.to_bytes()[42..44], &[0, 20]);
+        assert_eq!(s.state, SessionState::Logout);
+        assert_eq!(s.retained_for_recovery.as_ref().map(|snapshot| snapshot.tsih), Some(3));
+
+        // Reserved reason codes are rejected
+        let mut s = session();
+        let response = s.process_logout(&logout(5, 7)).unwrap();
+        assert_eq!(response.opcode, opcode::REJECT);
+        assert_eq!(response.reject_reason(), pdu::reject_reason::INVALID_PDU_FIELD);
+        assert_eq!(s.state, SessionState::FullFeaturePhase);
+    }
 }
@@ -0,0 +1,300 @@
+//! Per-LUN I/O statistics
+//!
+//! The target wraps its block device in a `CountingDevice` so every read,
+//! write and start-stop cycle is counted, whichever path issued it. The
+//! counters back the LOG SENSE pages and `IscsiTarget::lun_stats`.
+
+use crate::error::ScsiResult;
+use crate::lun::Identity;
+use crate::protection::ProtectionInfo;
+use crate::scsi::{LbaStatus, ModeParameters, PowerCondition, ScsiBlockDevice};
+use crate::vpd::{BlockLimits, Designator};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Live I/O counters for one logical unit
+#[derive(Debug, Default)]
+pub(crate) struct LunStats {
+    read_requests: AtomicU64,
+    write_requests: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+    start_stop_cycles: AtomicU64,
+}
+
+impl LunStats {
+    /// Take a point-in-time copy of the counters
+    pub(crate) fn snapshot(&self) -> LunStatsSnapshot {
+        LunStatsSnapshot {
+            read_requests: self.read_requests.load(Ordering::SeqCst),
+            write_requests: self.write_requests.load(Ordering::SeqCst),
+            bytes_read: self.bytes_read.load(Ordering::SeqCst),
+            bytes_written: self.bytes_written.load(Ordering::SeqCst),
+            read_errors: self.read_errors.load(Ordering::SeqCst),
+            write_errors: self.write_errors.load(Ordering::SeqCst),
+            start_stop_cycles: self.start_stop_cycles.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Per-LUN I/O totals (see `IscsiTarget::lun_stats`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LunStatsSnapshot {
+    /// Read requests issued to the device, including VERIFY compares
+    pub read_requests: u64,
+    /// Write requests issued to the device (one per immediate data or Data-Out PDU)
+    pub write_requests: u64,
+    /// Bytes successfully read from the device
+    pub bytes_read: u64,
+    /// Bytes successfully written to the device
+    pub bytes_written: u64,
+    /// Read requests the device failed
+    pub read_errors: u64,
+    /// Write requests the device failed
+    pub write_errors: u64,
+    /// Times the unit was started again after being stopped
+    pub start_stop_cycles: u64,
+}
+
+/// Block device adapter that counts the I/O passing through it
+pub(crate) struct CountingDevice<D: ScsiBlockDevice> {
+    inner: D,
+    stats: LunStats,
+    /// A stop was requested, so the next start completes a cycle
+    stopped: bool,
+    /// Identification reported instead of the inner device's
+    identity: Identity,
+}
+
+impl<D: ScsiBlockDevice> CountingDevice<D> {
+    pub(crate) fn new(inner: D) -> Self {
+        CountingDevice {
+            inner,
+            stats: LunStats::default(),
+            stopped: false,
+            identity: Identity::default(),
+        }
+    }
+
+    pub(crate) fn set_identity(&mut self, identity: Identity) {
+        self.identity = identity;
+    }
+
+    pub(crate) fn stats(&self) -> &LunStats {
+        &self.stats
+    }
+
+    /// Whether the target supports ACA on this unit (INQUIRY NormACA)
+    pub(crate) fn norm_aca(&self) -> bool {
+        self.identity.norm_aca
+    }
+}
+
+impl<D: ScsiBlockDevice> Deref for CountingDevice<D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        &self.inner
+    }
+}
+
+impl<D: ScsiBlockDevice> DerefMut for CountingDevice<D> {
+    fn deref_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+}
+
+impl<D: ScsiBlockDevice> ScsiBlockDevice for CountingDevice<D> {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        self.stats.read_requests.fetch_add(1, Ordering::SeqCst);
+        let result = self.inner.read(lba, blocks, block_size);
+        match &result {
+            Ok(data) => self.stats.bytes_read.fetch_add(data.len() as u64, Ordering::SeqCst),
+            Err(_) => self.stats.read_errors.fetch_add(1, Ordering::SeqCst),
+        };
+        result
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        self.stats.write_requests.fetch_add(1, Ordering::SeqCst);
+        let result = self.inner.write(lba, data, block_size);
+        match &result {
+            Ok(()) => self.stats.bytes_written.fetch_add(data.len() as u64, Ordering::SeqCst),
+            Err(_) => self.stats.write_errors.fetch_add(1, Ordering::SeqCst),
+        };
+        result
+    }
+
+    fn capacity(&self) -> u64 {
+        self.inner.capacity()
+    }
+
+    fn block_size(&self) -> u32 {
+        self.inner.block_size()
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        self.inner.flush()
+    }
+
+    fn write_cache_enabled(&self) -> bool {
+        self.inner.write_cache_enabled()
+    }
+
+    fn set_write_cache(&mut self, enabled: bool) -> ScsiResult<()> {
+        self.inner.set_write_cache(enabled)
+    }
+
+    fn write_cache_changeable(&self) -> bool {
+        self.inner.write_cache_changeable()
+    }
+
+    fn power_condition(&self) -> PowerCondition {
+        self.inner.power_condition()
+    }
+
+    fn start_stop_unit(&mut self, condition: PowerCondition, load_eject: bool) -> ScsiResult<()> {
+        self.inner.start_stop_unit(condition, load_eject)?;
+        let stopping = condition == PowerCondition::Stopped;
+        if self.stopped && !stopping {
+            self.stats.start_stop_cycles.fetch_add(1, Ordering::SeqCst);
+        }
+        self.stopped = stopping;
+        Ok(())
+    }
+
+    fn prefetch(&self, lba: u64, blocks: u32) -> ScsiResult<bool> {
+        self.inner.prefetch(lba, blocks)
+    }
+
+    fn abort_write(&mut self, lba: u64, blocks: u32) -> ScsiResult<()> {
+        self.inner.abort_write(lba, blocks)
+    }
+
+    fn physical_block_exponent(&self) -> u8 {
+        self.inner.physical_block_exponent()
+    }
+
+    fn lowest_aligned_lba(&self) -> u16 {
+        self.inner.lowest_aligned_lba()
+    }
+
+    fn requires_aligned_writes(&self) -> bool {
+        self.inner.requires_aligned_writes()
+    }
+
+    fn thin_provisioned(&self) -> bool {
+        self.inner.thin_provisioned()
+    }
+
+    fn unmapped_reads_zero(&self) -> bool {
+        self.inner.unmapped_reads_zero()
+    }
+
+    fn lba_status(&self, lba: u64, blocks: u64) -> ScsiResult<Vec<LbaStatus>> {
+        self.inner.lba_status(lba, blocks)
+    }
+
+    fn protection_type(&self) -> u8 {
+        self.inner.protection_type()
+    }
+
+    fn read_with_pi(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<(Vec<u8>, Vec<ProtectionInfo>)> {
+        self.stats.read_requests.fetch_add(1, Ordering::SeqCst);
+        let result = self.inner.read_with_pi(lba, blocks, block_size);
+        match &result {
+            Ok((data, _)) => self.stats.bytes_read.fetch_add(data.len() as u64, Ordering::SeqCst),
+            Err(_) => self.stats.read_errors.fetch_add(1, Ordering::SeqCst),
+        };
+        result
+    }
+
+    fn write_with_pi(&mut self, lba: u64, data: &[u8], pi: &[ProtectionInfo], block_size: u32) -> ScsiResult<()> {
+        self.stats.write_requests.fetch_add(1, Ordering::SeqCst);
+        let result = self.inner.write_with_pi(lba, data, pi, block_size);
+        match &result {
+            Ok(()) => self.stats.bytes_written.fetch_add(data.len() as u64, Ordering::SeqCst),
+            Err(_) => self.stats.write_errors.fetch_add(1, Ordering::SeqCst),
+        };
+        result
+    }
+
+    fn bidirectional(&mut self, cdb: &[u8], data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+        self.inner.bidirectional(cdb, data_out)
+    }
+
+    fn vendor_id(&self) -> &str {
+        self.identity.vendor.as_deref().unwrap_or_else(|| self.inner.vendor_id())
+    }
+
+    fn product_id(&self) -> &str {
+        self.identity.product.as_deref().unwrap_or_else(|| self.inner.product_id())
+    }
+
+    fn product_rev(&self) -> &str {
+        self.identity.revision.as_deref().unwrap_or_else(|| self.inner.product_rev())
+    }
+
+    fn serial_number(&self) -> &str {
+        self.identity.serial.as_deref().unwrap_or_else(|| self.inner.serial_number())
+    }
+
+    fn designators(&self) -> Vec<Designator> {
+        // Designators built from the device's own vendor and serial follow the overrides
+        let derived_naa = Designator::naa_from_serial(self.inner.serial_number());
+        self.inner.designators().into_iter()
+            .map(|designator| match designator {
+                Designator::T10VendorId { vendor, identifier } => Designator::T10VendorId {
+                    vendor: if vendor == self.inner.vendor_id() { self.vendor_id().to_string() } else { vendor },
+                    identifier: if identifier == self.inner.serial_number() { self.serial_number().to_string() } else { identifier },
+                },
+                naa if naa == derived_naa => Designator::naa_from_serial(self.serial_number()),
+                other => other,
+            })
+            .collect()
+    }
+
+    fn block_limits(&self) -> BlockLimits {
+        self.inner.block_limits()
+    }
+
+    fn medium_rotation_rate(&self) -> u16 {
+        self.inner.medium_rotation_rate()
+    }
+
+    fn mode_parameters(&self) -> ModeParameters {
+        self.identity.mode_parameters.unwrap_or_else(|| self.inner.mode_parameters())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemBlockDevice;
+
+    #[test]
+    fn test_counting_device() {
+        let mut device = CountingDevice::new(MemBlockDevice::new(8, 512));
+        device.write(0, &[1u8; 1024], 512).unwrap();
+        device.read(0, 1, 512).unwrap();
+        assert!(device.read(8, 1, 512).is_err());
+        assert!(device.write(7, &[0u8; 1024], 512).is_err());
+
+        // Only a start following a stop completes a cycle
+        device.start_stop_unit(PowerCondition::Active, false).unwrap();
+        device.start_stop_unit(PowerCondition::Stopped, false).unwrap();
+        device.start_stop_unit(PowerCondition::Active, false).unwrap();
+
+        assert_eq!(device.stats().snapshot(), LunStatsSnapshot {
+            read_requests: 2,
+            write_requests: 2,
+            bytes_read: 512,
+            bytes_written: 1024,
+            read_errors: 1,
+            write_errors: 1,
+            start_stop_cycles: 1,
+        });
+    }
+}
@@ -2,36 +2,252 @@
 //!
 //! This module provides the main server structure, TCP listener, and connection handling.
 
+use crate::aca;
+use crate::audit::{AuditLog, AuditSink};
+use crate::cdb::{Cdb, ParsedWrite};
+use crate::control::{SessionInfo, SessionLookup, SessionRegistration, TargetConfig, TargetControl};
 use crate::error::{IscsiError, ScsiResult};
-use crate::pdu::{self, IscsiPdu, BHS_SIZE, opcode, flags, scsi_status, serialize_text_parameters};
-use crate::scsi::{ScsiBlockDevice, ScsiHandler, ScsiResponse};
-use crate::session::{IscsiSession, PendingWrite, SessionState};
+use crate::events::{AbortReason, TargetEvent, TargetObserver};
+use crate::intercept::{Intercepted, PduInterceptor};
+use crate::outbound::Outbound;
+use crate::log_context::{self, ConnectionContext, PduLogLevel};
+use crate::lun::{self, DeviceOpener, DeviceProvider, Identity, LunTable, SessionLuns, SharedDevice, UnitState};
+use crate::portal::{DiscoveryConfig, IpNetwork, Portal, PortalPreference, PortalStats, PortalStatsSnapshot, DEFAULT_TPGT};
+use crate::pdu::{self, Digests, IscsiPdu, PduLimits, ScsiCommandPdu, BHS_SIZE, opcode, flags, scsi_status, serialize_text_parameters, tmf_function};
+use crate::readahead::SequentialReads;
+use crate::reservation;
+use crate::protection::PiTransfer;
+use crate::scsi::{ModeParameters, ScsiBlockDevice, ScsiHandler, ScsiResponse, SenseData};
+use crate::trace::{ConnectionTrace, PduTrace};
+use crate::transport::{Framed, PduTransport, ReceivedPdu, SocketOptions, TcpKeepalive, Transport};
+use crate::stats::{CountingDevice, LunStatsSnapshot};
+use crate::session::{CmdSnCheck, IscsiSession, OutstandingR2t, PendingParameterList, PendingTextResponse, PendingWrite, SessionParams, SessionState, Strictness};
+use crate::worker::WorkerPool;
 use byteorder::{BigEndian, ByteOrder};
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::collections::{HashMap, VecDeque};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, atomic::{AtomicBool, AtomicU64, Ordering}};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// Default iSCSI port
 pub const ISCSI_PORT: u16 = 3260;
 
+/// Read timeout for PDUs once a session is in FullFeaturePhase
+const FULL_FEATURE_READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time a drained session has to log out before its connection is dropped
+const DRAIN_LOGOUT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Data carried by `TargetControl::ping_session()` NOP-Ins, echoed back by the initiator
+const PING_DATA: &[u8] = b"iscsi-ping";
+
+/// How long an unanswered `ping_session()` NOP-In is remembered
+const PING_RETENTION: Duration = Duration::from_secs(300);
+
+/// How often `shutdown_and_wait()` checks whether connections have closed
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Outcome of `IscsiTarget::shutdown_and_wait`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Sessions that logged out before the timeout
+    pub logged_out: Vec<SessionInfo>,
+    /// Sessions still established at the timeout, whose connections were closed
+    pub terminated: Vec<SessionInfo>,
+    /// Connections closed at the timeout, including ones not yet in a session
+    pub connections_closed: usize,
+}
+
+/// Target-initiated NOP-In keepalive settings
+///
+/// A full-feature connection idle for `interval` is sent a NOP-In ping; if
+/// the initiator's NOP-Out reply does not arrive within `timeout` the
+/// connection is dropped, freeing its connection and session slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before a ping is sent
+    pub interval: Duration,
+    /// Time allowed for the NOP-Out reply
+    pub timeout: Duration,
+}
+
+/// R2T retransmission settings for WRITEs waiting on Data-Out
+///
+/// A WRITE whose Data-Out makes no progress for `timeout` has its
+/// outstanding R2Ts sent again; after `retries` retransmissions without
+/// progress the task is aborted with CHECK CONDITION (see
+/// `ScsiBlockDevice::abort_write` for what becomes of the data received).
+/// R2Ts are only retransmitted at ErrorRecoveryLevel 1 or higher; at level 0
+/// the task is aborted at the first timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct R2tRetransmit {
+    /// Time without Data-Out before the R2Ts are retransmitted
+    pub timeout: Duration,
+    /// Retransmissions before the WRITE is aborted
+    pub retries: u32,
+}
+
+impl Default for R2tRetransmit {
+    fn default() -> Self {
+        R2tRetransmit {
+            timeout: Duration::from_secs(20),
+            retries: 2,
+        }
+    }
+}
+
+/// Deadline for commands running on the worker pool
+///
+/// A queued command the device has not completed within `timeout` of being
+/// queued (a hung network mount, a dying disk) is answered by the connection
+/// thread with CHECK CONDITION / ABORTED COMMAND, COMMAND TIMEOUT DURING
+/// PROCESSING, or with BUSY if `busy` is set, and a
+/// `TargetEvent::CommandTimedOut` is emitted.
+///
+/// The device calls of WRITEs and SYNCHRONIZE CACHE are made on the worker
+/// pool under the same deadline; other commands run inline on the connection
+/// thread have none (see `IscsiTargetBuilder::command_deadline`).
+///
+/// The deadline only answers the initiator; it does not stop the device
+/// call. The command's worker stays occupied until the device returns, and
+/// the late result is discarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandDeadline {
+    /// Time a command may run before it is answered without the device
+    pub timeout: Duration,
+    /// Answer with BUSY rather than CHECK CONDITION
+    pub busy: bool,
+}
+
+/// Bounds on a connection's queue of PDUs waiting to be sent
+///
+/// Responses are queued for a per-connection writer thread, so an initiator
+/// that stops reading stalls only its own connection. Once the queue holds
+/// half of `capacity` bytes the session's command window stops advancing;
+/// a response that finds it full for `stall_timeout` drops the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendQueueLimits {
+    /// Bytes of PDUs (headers and data segments) that may wait to be sent
+    pub capacity: usize,
+    /// Time a full queue may go without room before the connection is dropped
+    pub stall_timeout: Duration,
+}
+
+impl Default for SendQueueLimits {
+    fn default() -> Self {
+        SendQueueLimits {
+            capacity: 16 * 1024 * 1024,
+            stall_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Backend read-ahead for streaming reads
+///
+/// After `sequential_reads` READs on a LUN that each start where the last
+/// one ended, the connection calls the device's `prefetch` hook for the
+/// `window` blocks that follow, and tops the window up as the stream
+/// advances. The hook runs on the worker thread once the READ has been
+/// answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadAhead {
+    /// Follow-on READs before the stream is prefetched
+    pub sequential_reads: u32,
+    /// Blocks to keep prefetched ahead of the stream
+    pub window: u32,
+}
+
+impl Default for ReadAhead {
+    fn default() -> Self {
+        ReadAhead {
+            sequential_reads: 2,
+            window: 1024,
+        }
+    }
+}
+
+/// Limits on how long a full-feature session may hold its resources
+///
+/// A session that sends no commands for `idle`, or has been logged in for
+/// `lifetime`, is asked to log out with an Async Message; if it has not
+/// logged out within the logout timeout the connection is dropped, aborting
+/// its pending writes and freeing its session slot. NOP-Outs do not count
+/// as activity, so trickled pings cannot keep an idle session alive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionLimits {
+    /// Time without commands before the session is logged out
+    pub idle: Option<Duration>,
+    /// Time after login before the session is logged out
+    pub lifetime: Option<Duration>,
+}
+
+impl SessionLimits {
+    /// When the first limit runs out, given the last command and login times
+    fn deadline(&self, last_command: Instant, logged_in: Instant) -> Option<Instant> {
+        let idle = self.idle.map(|idle| last_command + idle);
+        let lifetime = self.lifetime.map(|lifetime| logged_in + lifetime);
+        idle.into_iter().chain(lifetime).min()
+    }
+}
+
+/// A configured portal together with its live counters
+#[derive(Debug, Clone)]
+struct PortalState {
+    config: Portal,
+    stats: Arc<PortalStats>,
+}
+
 /// iSCSI target server
 pub struct IscsiTarget<D: ScsiBlockDevice> {
-    bind_addr: String,
-    target_name: String,
-    target_alias: String,
-    device: Arc<Mutex<D>>,
-    running: Arc<AtomicBool>,
-    shutting_down: Arc<AtomicBool>,
-    auth_config: crate::auth::AuthConfig,
+    portals: Vec<PortalState>,
+    /// Negotiation baseline offered to every session
+    session_defaults: SessionParams,
+    /// Shared with every connection's thread
+    settings: Arc<ConnectionSettings<D>>,
     max_connections: u32,
     active_connections: Arc<std::sync::atomic::AtomicUsize>,
+    /// Open connections, so shutdown can close any that outstay it
+    open_connections: Arc<Mutex<HashMap<u64, CloseHandle>>>,
+    next_connection_id: AtomicU64,
+    socket_options: SocketOptions,
+    worker_threads: usize,
+    interceptor: Option<Arc<dyn PduInterceptor>>,
+    /// Worker pool for connections accepted outside `run`
+    stream_workers: OnceLock<Arc<WorkerPool>>,
+}
+
+/// Target settings and state every connection works with
+///
+/// Fixed when the target is built, apart from the flags and counters.
+struct ConnectionSettings<D: ScsiBlockDevice> {
+    luns: Arc<LunTable<D>>,
+    control: TargetControl,
+    running: AtomicBool,
+    shutting_down: AtomicBool,
     max_sessions: u32,
-    active_sessions: Arc<std::sync::atomic::AtomicUsize>,
-    allowed_initiators: Option<Vec<String>>,
+    active_sessions: std::sync::atomic::AtomicUsize,
+    observer: Option<Arc<dyn TargetObserver>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    discovery: DiscoveryConfig,
+    keepalive: Option<Keepalive>,
+    session_limits: SessionLimits,
+    send_queue: SendQueueLimits,
+    r2t_retransmit: R2tRetransmit,
+    read_ahead: Option<ReadAhead>,
+    command_deadline: Option<CommandDeadline>,
+    queue_depth: u32,
+    command_window: u32,
+    pdu_limits: PduLimits,
+    trace: Option<Arc<PduTrace>>,
 }
 
+/// Closes one open connection from another thread
+type CloseHandle = Box<dyn Fn() + Send>;
+
 impl<D: ScsiBlockDevice + Send + 'static> IscsiTarget<D> {
     /// Create a new builder for configuring the target
     pub fn builder() -> IscsiTargetBuilder<D> {
@@ -40,94 +256,269 @@ impl<D: ScsiBlockDevice + Send + 'static> IscsiTarget<D> {
 
     /// Run the iSCSI target server
     ///
-    /// This blocks the current thread and processes incoming connections.
+    /// This blocks the current thread and processes incoming connections. Each
+    /// portal gets its own accept thread, so a busy portal cannot delay
+    /// connections on another.
     pub fn run(&self) -> ScsiResult<()> {
-        log::info!("iSCSI target starting on {}", self.bind_addr);
-        log::info!("Target name: {}", self.target_name);
+        log::info!("iSCSI target starting on {}", self.portal_list());
+        log::info!("Target name: {}", self.settings.control.config().target_name);
 
-        let listener = TcpListener::bind(&self.bind_addr)
-            .map_err(IscsiError::Io)?;
+        let mut listeners = Vec::with_capacity(self.portals.len());
+        for portal in &self.portals {
+            let listener = TcpListener::bind(&portal.config.bind_addr)
+                .map_err(IscsiError::Io)?;
 
-        // Set non-blocking for graceful shutdown checking
-        listener.set_nonblocking(true)
-            .map_err(IscsiError::Io)?;
+            // Set non-blocking for graceful shutdown checking
+            listener.set_nonblocking(true)
+                .map_err(IscsiError::Io)?;
+
+            listeners.push((listener, portal));
+        }
 
-        self.running.store(true, Ordering::SeqCst);
+        self.settings.running.store(true, Ordering::SeqCst);
 
-        log::info!("iSCSI target listening on {}", self.bind_addr);
+        log::info!("iSCSI target listening on {}", self.portal_list());
 
-        while self.running.load(Ordering::SeqCst) {
-            match listener.accept() {
-                Ok((stream, addr)) => {
-                    log::info!("New connection from {}", addr);
+        let workers = Arc::new(WorkerPool::new(self.worker_threads));
+        thread::scope(|scope| {
+            for (listener, portal) in listeners {
+                let workers = &workers;
+                scope.spawn(move || self.accept_loop(listener, portal, workers));
+            }
+        });
 
-                    // Check connection limit
-                    let current = self.active_connections.fetch_add(1, Ordering::SeqCst);
-                    if current >= self.max_connections as usize {
-                        log::warn!("Connection rejected from {}: too many connections ({}/{})",
-                            addr, current + 1, self.max_connections);
-                        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+        log::info!("iSCSI target shutting down");
+        Ok(())
+    }
 
-                        // Send TOO_MANY_CONNECTIONS reject and close
-                        let _ = send_connection_limit_reject(stream);
+    /// Accept connections on one portal until the target is stopped
+    fn accept_loop(&self, listener: TcpListener, portal: &PortalState, workers: &Arc<WorkerPool>) {
+        while self.settings.running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    // Accepted sockets are served with blocking reads and timeouts
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        log::error!("Cannot serve connection from {}: {}", addr, e);
                         continue;
                     }
-
-                    log::debug!("Accepted connection from {} ({}/{} active)",
-                        addr, current + 1, self.max_connections);
-
-                    let device = Arc::clone(&self.device);
-                    let target_name = self.target_name.clone();
-                    let target_alias = self.target_alias.clone();
-                    let auth_config = self.auth_config.clone();
-                    let running = Arc::clone(&self.running);
-                    let shutting_down = Arc::clone(&self.shutting_down);
-                    let active_connections = Arc::clone(&self.active_connections);
-                    let max_sessions = self.max_sessions;
-                    let active_sessions = Arc::clone(&self.active_sessions);
-                    let allowed_initiators = self.allowed_initiators.clone();
-
-                    thread::spawn(move || {
-                        let session_entered = handle_connection(
-                            stream,
-                            device,
-                            &target_name,
-                            &target_alias,
-                            auth_config,
-                            running,
-                            shutting_down,
-                            max_sessions,
-                            Arc::clone(&active_sessions),
-                            allowed_initiators,
-                        ).unwrap_or(false); // Returns true if session was established
-
-                        log::info!("Connection closed from {}", addr);
-
-                        // Decrement connection count
-                        let prev = active_connections.fetch_sub(1, Ordering::SeqCst);
-                        log::debug!("Connection count: {} -> {}", prev, prev - 1);
-
-                        // Decrement session count if a session was established
-                        if session_entered {
-                            let prev = active_sessions.fetch_sub(1, Ordering::SeqCst);
-                            log::debug!("Session count: {} -> {}", prev, prev - 1);
-                        }
-                    });
+                    self.tune_socket(&stream);
+                    self.accept_connection(Framed::new(stream), addr, portal, workers);
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     // No connection available, sleep briefly and retry
                     thread::sleep(Duration::from_millis(100));
                 }
                 Err(e) => {
-                    log::error!("Accept error: {}", e);
+                    log::error!("Accept error on {}: {}", portal.config.bind_addr, e);
                 }
             }
         }
+    }
 
-        log::info!("iSCSI target shutting down");
+    /// Serve one connection over `stream` instead of a listening portal
+    ///
+    /// The connection is handled on its own thread and this returns once it
+    /// is started. With `duplex()` this drives the target entirely in memory.
+    /// `stop()` or `shutdown_and_wait()` end it like any other connection.
+    pub fn serve_stream<S: Transport>(&self, stream: S) -> ScsiResult<()> {
+        self.tune_socket(&stream);
+        self.serve_transport(Framed::new(stream))
+    }
+
+    /// Serve one connection over a PDU transport, such as an iSER backend
+    ///
+    /// Like `serve_stream`, but PDU framing is left to `transport`.
+    pub fn serve_transport<T: PduTransport>(&self, transport: T) -> ScsiResult<()> {
+        let (addr, portal) = self.stream_origin(&transport)?;
+        self.settings.running.store(true, Ordering::SeqCst);
+        let workers = self.stream_workers.get_or_init(|| Arc::new(WorkerPool::new(self.worker_threads)));
+        self.accept_connection(transport, addr, portal, workers);
+        Ok(())
+    }
+
+    /// Serve a connection the caller accepted, on the calling thread
+    ///
+    /// For embedders that own the listening socket (systemd socket
+    /// activation, inetd, SO_REUSEPORT sharding, Unix sockets): the stream
+    /// gets the same limits, login and session handling as connections
+    /// accepted by `run`. It is counted against the portal whose bind
+    /// address matches the stream's local address, or the first portal.
+    /// Sockets must be in blocking mode. Returns when the connection closes.
+    pub fn handle_connection<S: Transport>(&self, stream: S) -> ScsiResult<()> {
+        self.tune_socket(&stream);
+        self.handle_transport(Framed::new(stream))
+    }
+
+    /// Apply the configured socket options; a stream that refuses them is
+    /// still served
+    fn tune_socket<S: Transport>(&self, stream: &S) {
+        if let Err(e) = stream.apply_socket_options(&self.socket_options) {
+            log::warn!("Cannot apply socket options: {}", e);
+        }
+    }
+
+    /// Serve a connection over a PDU transport, on the calling thread
+    ///
+    /// Like `handle_connection`, but PDU framing is left to `transport`.
+    pub fn handle_transport<T: PduTransport>(&self, transport: T) -> ScsiResult<()> {
+        let (addr, portal) = self.stream_origin(&transport)?;
+        self.settings.running.store(true, Ordering::SeqCst);
+        let workers = self.stream_workers.get_or_init(|| Arc::new(WorkerPool::new(self.worker_threads)));
+        if let Some(connection) = self.admit_connection(transport, addr, portal, workers) {
+            connection();
+        }
         Ok(())
     }
 
+    /// Initiator address of an externally accepted stream and the portal it arrived on
+    fn stream_origin<T: PduTransport>(&self, stream: &T) -> ScsiResult<(std::net::SocketAddr, &PortalState)> {
+        let addr = stream.peer_addr().map_err(IscsiError::Io)?;
+        let local_addr = stream.local_addr().map_err(IscsiError::Io)?;
+        let portal = self.portals.iter()
+            .find(|portal| portal.config.bind_addr.parse::<std::net::SocketAddr>().is_ok_and(|bind| {
+                bind.port() == local_addr.port() && (bind.ip() == local_addr.ip() || bind.ip().is_unspecified())
+            }))
+            .unwrap_or(&self.portals[0]);
+        Ok((addr, portal))
+    }
+
+    /// Comma-separated portal addresses for log messages
+    fn portal_list(&self) -> String {
+        self.portals.iter()
+            .map(|portal| portal.config.bind_addr.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Check limits for a newly accepted connection and spawn its handler thread
+    fn accept_connection<T: PduTransport>(&self, stream: T, addr: std::net::SocketAddr, portal: &PortalState, workers: &Arc<WorkerPool>) {
+        if let Some(connection) = self.admit_connection(stream, addr, portal, workers) {
+            thread::spawn(connection);
+        }
+    }
+
+    /// Check limits for a newly accepted connection and return its handler
+    ///
+    /// Over-limit connections are sent a login reject and closed (None).
+    fn admit_connection<T: PduTransport>(
+        &self,
+        stream: T,
+        addr: std::net::SocketAddr,
+        portal: &PortalState,
+        workers: &Arc<WorkerPool>,
+    ) -> Option<impl FnOnce() + Send + 'static> {
+        log::info!("New connection from {} on portal {} (TPGT {})", addr, portal.config.bind_addr, portal.config.tpgt);
+        let stream = Intercepted::new(stream, self.interceptor.clone());
+        portal.stats.total_connections.fetch_add(1, Ordering::SeqCst);
+
+        // Check target-wide and per-portal connection limits
+        let current = self.active_connections.fetch_add(1, Ordering::SeqCst);
+        let portal_current = portal.stats.active_connections.fetch_add(1, Ordering::SeqCst);
+        let portal_full = portal.config.max_connections
+            .is_some_and(|max| portal_current >= max as usize);
+        if current >= self.max_connections as usize || portal_full {
+            if portal_full {
+                log::warn!("Connection rejected from {}: too many connections on portal {} ({}/{})",
+                    addr, portal.config.bind_addr, portal_current + 1, portal.config.max_connections.unwrap_or(0));
+            } else {
+                log::warn!("Connection rejected from {}: too many connections ({}/{})",
+                    addr, current + 1, self.max_connections);
+            }
+            self.active_connections.fetch_sub(1, Ordering::SeqCst);
+            portal.stats.active_connections.fetch_sub(1, Ordering::SeqCst);
+            portal.stats.rejected_connections.fetch_add(1, Ordering::SeqCst);
+
+            // Send TOO_MANY_CONNECTIONS reject and close
+            let _ = send_connection_limit_reject(stream);
+            return None;
+        }
+
+        log::debug!("Accepted connection from {} ({}/{} active)",
+            addr, current + 1, self.max_connections);
+
+        let settings = Arc::clone(&self.settings);
+        // Configuration is fixed for the connection; later changes are applied by draining
+        let config = self.settings.control.config();
+        let base_params = self.session_params(&config);
+        let active_connections = Arc::clone(&self.active_connections);
+        let workers = Arc::clone(workers);
+        let portal = portal.clone();
+
+        let context = ConnectionContext::new(addr);
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let open_connections = Arc::clone(&self.open_connections);
+        match stream.try_clone() {
+            Ok(handle) => {
+                let close: CloseHandle = Box::new(move || { let _ = handle.close(); });
+                open_connections.lock().unwrap_or_else(|e| e.into_inner()).insert(connection_id, close);
+            }
+            Err(e) => log::warn!("Cannot track connection from {} for shutdown: {}", addr, e),
+        }
+
+        Some(move || context.clone().in_scope(|| {
+            let session_entered = handle_connection(
+                stream,
+                &settings,
+                config,
+                base_params,
+                workers,
+                portal.clone(),
+                context,
+            ).unwrap_or(false); // Returns true if session was established
+
+            log::info!("Connection closed from {}", addr);
+            open_connections.lock().unwrap_or_else(|e| e.into_inner()).remove(&connection_id);
+
+            // Decrement connection count
+            let prev = active_connections.fetch_sub(1, Ordering::SeqCst);
+            portal.stats.active_connections.fetch_sub(1, Ordering::SeqCst);
+            log::debug!("Connection count: {} -> {}", prev, prev - 1);
+
+            // Decrement session count if a session was established
+            if session_entered {
+                let prev = settings.active_sessions.fetch_sub(1, Ordering::SeqCst);
+                portal.stats.active_sessions.fetch_sub(1, Ordering::SeqCst);
+                log::debug!("Session count: {} -> {}", prev, prev - 1);
+            }
+        }))
+    }
+
+    /// Initial parameters for a new session under `config`
+    fn session_params(&self, config: &TargetConfig) -> SessionParams {
+        SessionParams {
+            target_name: config.target_name.clone(),
+            target_alias: config.target_alias.clone(),
+            ..self.session_defaults.clone()
+        }
+    }
+
+    /// Handle for changing the configuration while the target runs
+    pub fn control(&self) -> TargetControl {
+        self.settings.control.clone()
+    }
+
+    /// Get per-portal load statistics, in portal configuration order
+    pub fn portal_stats(&self) -> Vec<PortalStatsSnapshot> {
+        self.portals.iter()
+            .map(|portal| portal.stats.snapshot(&portal.config))
+            .collect()
+    }
+
+    /// I/O totals for a logical unit, or None if the LUN does not exist or
+    /// each session opens its own device for it
+    pub fn lun_stats(&self, lun: u64) -> Option<LunStatsSnapshot> {
+        let device = self.settings.luns.device(lun)?;
+        let device = device.read().ok()?;
+        Some(device.stats().snapshot())
+    }
+
+    /// I_T nexus (`<InitiatorName>,i,0x<ISID>,t,0x<TPGT>`) holding a SCSI-2
+    /// reservation of a logical unit, or None if it is not reserved or does
+    /// not exist
+    pub fn reservation_holder(&self, lun: u64) -> Option<String> {
+        self.settings.luns.unit(lun)?.reservation.holder()
+    }
+
     /// Get the current number of active connections
     pub fn active_connection_count(&self) -> usize {
         self.active_connections.load(Ordering::SeqCst)
@@ -135,7 +526,7 @@ impl<D: ScsiBlockDevice + Send + 'static> IscsiTarget<D> {
 
     /// Get the current number of active sessions
     pub fn active_session_count(&self) -> usize {
-        self.active_sessions.load(Ordering::SeqCst)
+        self.settings.active_sessions.load(Ordering::SeqCst)
     }
 
     /// Initiate graceful shutdown - reject new logins but allow existing sessions to complete
@@ -148,7 +539,49 @@ impl<D: ScsiBlockDevice + Send + 'static> IscsiTarget<D> {
     /// This is useful for maintenance or when preparing to shut down the target cleanly.
     pub fn shutdown_gracefully(&self) {
         log::info!("Initiating graceful shutdown - new logins will be rejected");
-        self.shutting_down.store(true, Ordering::SeqCst);
+        self.settings.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Shut down, giving established sessions up to `timeout` to log out
+    ///
+    /// New logins are rejected and every session is sent an Async Message
+    /// asking it to log out. Once all connections have closed, or `timeout`
+    /// has passed, any connections still open are closed and the server is
+    /// stopped. The report lists which sessions logged out and which were cut
+    /// off.
+    pub fn shutdown_and_wait(&self, timeout: Duration) -> ShutdownReport {
+        self.shutdown_gracefully();
+        let deadline = Instant::now() + timeout;
+        let sessions = self.settings.control.sessions();
+        let asked = self.settings.control.drain_all();
+        log::info!("Asked {} session(s) to log out, waiting up to {:?}", asked, timeout);
+
+        while self.active_connection_count() > 0 && Instant::now() < deadline {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+        }
+
+        let terminated = self.settings.control.sessions();
+        let logged_out = sessions.into_iter()
+            .filter(|session| !terminated.iter().any(|t| t.tsih == session.tsih && t.isid == session.isid))
+            .collect();
+
+        let open: Vec<CloseHandle> = self.open_connections.lock().unwrap_or_else(|e| e.into_inner())
+            .drain()
+            .map(|(_, close)| close)
+            .collect();
+        for close in &open {
+            close();
+        }
+        if !open.is_empty() {
+            log::warn!("Closed {} connection(s) still open at shutdown ({} session(s))", open.len(), terminated.len());
+        }
+
+        self.stop();
+        ShutdownReport {
+            logged_out,
+            terminated,
+            connections_closed: open.len(),
+        }
     }
 
     /// Signal the server to stop immediately
@@ -158,84 +591,338 @@ impl<D: ScsiBlockDevice + Send + 'static> IscsiTarget<D> {
     /// wait for sessions to complete, then call stop().
     pub fn stop(&self) {
         log::info!("Stopping iSCSI target server");
-        self.running.store(false, Ordering::SeqCst);
+        self.settings.running.store(false, Ordering::SeqCst);
     }
 
     /// Check if the server is running
     pub fn is_running(&self) -> bool {
-        self.running.load(Ordering::SeqCst)
+        self.settings.running.load(Ordering::SeqCst)
     }
 
     /// Check if the server is in graceful shutdown mode
     pub fn is_shutting_down(&self) -> bool {
-        self.shutting_down.load(Ordering::SeqCst)
+        self.settings.shutting_down.load(Ordering::SeqCst)
     }
 }
 
 /// Send TOO_MANY_CONNECTIONS reject to a new connection
-fn send_connection_limit_reject(mut stream: TcpStream) -> ScsiResult<()> {
+fn send_connection_limit_reject<T: PduTransport>(mut stream: T) -> ScsiResult<()> {
     // Set short timeout for this rejection
     stream.set_read_timeout(Some(Duration::from_secs(2))).ok();
     stream.set_write_timeout(Some(Duration::from_secs(2))).ok();
 
     // Try to read login request to get ITT
-    let mut bhs = [0u8; 48];
-    if stream.read_exact(&mut bhs).is_ok() {
-        let itt = u32::from_be_bytes([bhs[16], bhs[17], bhs[18], bhs[19]]);
-
+    let session = crate::session::IscsiSession::new();
+    let received = stream.recv_pdu(session.max_recv_data_segment_limit(), Digests::NONE, &PduLimits::default(), None);
+    let itt = match received {
+        Ok(ReceivedPdu::Pdu(pdu)) => Some(pdu.itt),
+        Ok(ReceivedPdu::Oversized { header, .. } | ReceivedPdu::DataDigestError { header }) => {
+            Some(u32::from_be_bytes([header[16], header[17], header[18], header[19]]))
+        }
+        Err(_) => None,
+    };
+    if let Some(itt) = itt {
         // Create login reject with TOO_MANY_CONNECTIONS (0x0206)
-        let session = crate::session::IscsiSession::new();
         if let Ok(reject_pdu) = session.create_too_many_connections_reject(itt) {
-            let _ = write_pdu(&mut stream, &reject_pdu);
+            let _ = stream.send_pdu(&reject_pdu, Digests::NONE, None);
         }
     }
 
     // Close connection
-    let _ = stream.shutdown(Shutdown::Both);
+    let _ = stream.close();
     Ok(())
 }
 
+/// Send an Async Message asking the initiator to log out
+///
+/// Returns the time after which the connection is dropped if no logout arrives.
+fn request_logout<T: PduTransport>(
+    stream: &mut T,
+    session: &mut IscsiSession,
+    digests: Digests,
+    trace: Option<&ConnectionTrace>,
+) -> ScsiResult<Instant> {
+    let request = IscsiPdu::async_logout_request(
+        DRAIN_LOGOUT_TIMEOUT.as_secs() as u16,
+        session.next_stat_sn(),
+        session.exp_cmd_sn,
+        session.max_cmd_sn,
+    );
+    stream.send_pdu(&request, digests, trace)?;
+    Ok(Instant::now() + DRAIN_LOGOUT_TIMEOUT)
+}
+
 /// Handle a single iSCSI connection
-fn handle_connection<D: ScsiBlockDevice>(
-    mut stream: TcpStream,
-    device: Arc<Mutex<D>>,
-    target_name: &str,
-    target_alias: &str,
-    auth_config: crate::auth::AuthConfig,
-    running: Arc<AtomicBool>,
-    shutting_down: Arc<AtomicBool>,
-    max_sessions: u32,
-    active_sessions: Arc<std::sync::atomic::AtomicUsize>,
-    allowed_initiators: Option<Vec<String>>,
+fn handle_connection<D: ScsiBlockDevice + Send + 'static, T: PduTransport>(
+    stream: T,
+    settings: &ConnectionSettings<D>,
+    config: TargetConfig,
+    base_params: SessionParams,
+    workers: Arc<WorkerPool>,
+    portal: PortalState,
+    context: ConnectionContext,
 ) -> ScsiResult<bool> {
     // Get the local address that the client connected to
     let local_addr = stream.local_addr().map_err(IscsiError::Io)?;
-    // Set blocking mode and timeouts for the connection
-    stream.set_nonblocking(false).map_err(IscsiError::Io)?;
+    let peer_addr = stream.peer_addr().map_err(IscsiError::Io)?;
+    let trace = settings.trace.as_ref().map(|trace| trace.connection(peer_addr, local_addr));
+    let target_portals = settings.discovery.target_addresses(local_addr, &portal.config);
+    let mut stream = Outbound::new(stream, settings.send_queue, context.clone())?;
+    // Set timeouts for the connection
     // During login phase, use a shorter timeout to detect stalled logins quickly
     // This prevents resource leaks from clients that initiate login but never complete it
     stream.set_read_timeout(Some(Duration::from_secs(5))).map_err(IscsiError::Io)?;
     stream.set_write_timeout(Some(Duration::from_secs(5))).map_err(IscsiError::Io)?;
 
+    let luns = Arc::new(SessionLuns::new(Arc::clone(&settings.luns)));
     let mut session = IscsiSession::new();
-    session.params.target_name = target_name.to_string();
-    session.params.target_alias = target_alias.to_string();
-    session.set_auth_config(auth_config);
-    session.set_allowed_initiators(allowed_initiators.clone());
+    session.params = base_params;
+    session.lun_generation = luns.generation();
+    session.lun_changes_baseline = luns.changes();
+    let target_name = config.target_name.as_str();
+    session.set_auth_config(config.auth.clone());
+    session.set_allow_md5_chap(config.allow_md5_chap);
+    session.set_allowed_initiators(config.allowed_initiators.clone());
+    session.set_allowed_networks(config.allowed_networks.clone());
+    session.set_strictness(config.strictness);
+    session.set_initiator_addr(peer_addr.ip());
+    session.set_observer(settings.observer.clone());
+    session.set_control(settings.control.clone());
+    session.params.target_portal_group_tag = portal.config.tpgt;
+    session.discovery_only = portal.config.discovery_only;
 
     // Track whether this connection established a full session
     let mut session_entered = false;
+    let mut result = Ok(());
+    // When the outstanding keepalive ping (if any) times out
+    let mut ping_deadline: Option<Instant> = None;
+    let mut last_received = Instant::now();
+    // Activity and login times the session limits are measured from
+    let mut last_command = Instant::now();
+    let mut logged_in: Option<Instant> = None;
+    // Started on entering FullFeaturePhase; from then on PDUs arrive through it
+    let mut commands: Option<CommandQueue> = None;
+    // Negotiated digests, in effect once the final Login Response is sent
+    let mut digests = Digests::NONE;
+    // Normal sessions are registered for draining and snapshots
+    let mut registration: Option<SessionRegistration> = None;
+    // Set once the session is draining and has been asked to log out
+    let mut logout_deadline: Option<Instant> = None;
+    // NOP-In pings from `TargetControl::ping_session()` awaiting their echo, by TTT
+    let mut pings: HashMap<u32, (Instant, Sender<Duration>)> = HashMap::new();
 
     // Main connection loop
-    while running.load(Ordering::SeqCst) {
+    while settings.running.load(Ordering::SeqCst) {
+        if session.outstanding_ping_ttt.is_none() {
+            ping_deadline = None;
+        }
+        if let Some(registration) = &registration {
+            registration.update(session.exp_cmd_sn, session.max_cmd_sn, session.stat_sn);
+        }
+
+        if let Some(commands) = commands.as_mut() {
+            commands.release_deferred(&session);
+            commands.release_held(&mut session);
+
+            // Stop widening the command window while responses back up
+            let congested = stream.congested();
+            if congested != session.command_window_held {
+                if congested {
+                    log::warn!("Send queue backing up, holding MaxCmdSN at {}", session.max_cmd_sn);
+                } else {
+                    log::info!("Send queue drained, reopening the command window");
+                }
+                session.command_window_held = congested;
+                if !congested {
+                    // The initiator may be out of window with nothing outstanding,
+                    // so tell it the new MaxCmdSN instead of waiting for a response
+                    let held_at = session.max_cmd_sn;
+                    session.open_command_window(settings.command_window);
+                    if session.max_cmd_sn != held_at {
+                        if let Err(e) = stream.send_pdu(&session.create_window_update(), digests, trace.as_ref()) {
+                            result = Err(e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let received = match commands.as_mut() {
+            None => stream.recv_pdu(session.max_recv_data_segment_limit(), Digests::NONE, &settings.pdu_limits, trace.as_ref()),
+            Some(commands) => {
+                // Keepalive: ping connections idle for the interval
+                let keepalive_deadline = settings.keepalive.map(|keepalive| {
+                    ping_deadline.unwrap_or(last_received + keepalive.interval)
+                });
+                let write_deadline = session.pending_writes.values()
+                    .filter_map(|pending| pending.last_activity)
+                    .min()
+                    .map(|last| last + settings.r2t_retransmit.timeout);
+                let limit_deadline = match (logout_deadline, logged_in) {
+                    (None, Some(logged_in)) => settings.session_limits.deadline(last_command, logged_in),
+                    _ => None,
+                };
+                let command_deadline = commands.next_deadline();
+                let deadline = keepalive_deadline.into_iter()
+                    .chain(logout_deadline)
+                    .chain(write_deadline)
+                    .chain(limit_deadline)
+                    .chain(command_deadline)
+                    .min();
+                match commands.next_event(deadline) {
+                    Ok(ConnectionEvent::Received(received)) => {
+                        last_received = Instant::now();
+                        if !matches!(&received, Ok(ReceivedPdu::Pdu(pdu)) if pdu.opcode == opcode::NOP_OUT) {
+                            last_command = last_received;
+                        }
+                        received
+                    }
+                    Ok(ConnectionEvent::Completed { task, response }) => {
+                        last_command = Instant::now();
+                        if let Err(e) = commands.complete(&mut stream, &mut session, task, response) {
+                            result = Err(e);
+                            break;
+                        }
+                        continue;
+                    }
+                    Ok(ConnectionEvent::Ping(reply)) => {
+                        let ttt = session.next_target_transfer_tag();
+                        let mut ping = IscsiPdu::nop_in(0xFFFF_FFFF, ttt, session.stat_sn, session.exp_cmd_sn, session.max_cmd_sn, 0);
+                        ping.data = PING_DATA.to_vec();
+                        ping.data_length = PING_DATA.len() as u32;
+                        if let Err(e) = stream.send_pdu(&ping, digests, trace.as_ref()) {
+                            result = Err(e);
+                            break;
+                        }
+                        let now = Instant::now();
+                        pings.retain(|_, (sent, _)| now.duration_since(*sent) < PING_RETENTION);
+                        pings.insert(ttt, (now, reply));
+                        continue;
+                    }
+                    // The command window is reopened at the top of the loop
+                    Ok(ConnectionEvent::SendQueueDrained) => continue,
+                    Ok(ConnectionEvent::LogoutRequested) => {
+                        if logout_deadline.is_none() {
+                            log::info!("Asking {} to log out", session.params.initiator_name);
+                            match request_logout(&mut stream, &mut session, digests, trace.as_ref()) {
+                                Ok(deadline) => logout_deadline = Some(deadline),
+                                Err(e) => {
+                                    result = Err(e);
+                                    break;
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        let now = Instant::now();
+                        if logout_deadline.is_some_and(|deadline| now >= deadline) {
+                            log::warn!("No logout within {:?} of the request, dropping connection", DRAIN_LOGOUT_TIMEOUT);
+                            break;
+                        }
+                        if limit_deadline.is_some_and(|deadline| now >= deadline) {
+                            log::info!("Session of {} reached its idle or lifetime limit, asking it to log out", session.params.initiator_name);
+                            match request_logout(&mut stream, &mut session, digests, trace.as_ref()) {
+                                Ok(deadline) => logout_deadline = Some(deadline),
+                                Err(e) => {
+                                    result = Err(e);
+                                    break;
+                                }
+                            }
+                        }
+                        if command_deadline.is_some_and(|deadline| now >= deadline) {
+                            if let Err(e) = commands.expire(&mut stream, &mut session, now) {
+                                result = Err(e);
+                                break;
+                            }
+                        }
+                        if write_deadline.is_some_and(|deadline| now >= deadline) {
+                            let responses = expire_pending_writes(&mut session, &luns, settings.r2t_retransmit, now);
+                            commands.audit_sent(&responses);
+                            if let Err(e) = responses.iter().try_for_each(|pdu| stream.send_pdu(pdu, digests, trace.as_ref())) {
+                                result = Err(e);
+                                break;
+                            }
+                        }
+                        let Some(keepalive) = settings.keepalive else { continue };
+                        if keepalive_deadline.is_none_or(|deadline| now < deadline) {
+                            continue;
+                        }
+                        if ping_deadline.is_some() {
+                            log::warn!("No NOP-Out reply to keepalive within {:?}, dropping connection", keepalive.timeout);
+                            break;
+                        }
+                        let ping = session.create_nop_in_ping();
+                        log::debug!("Connection idle for {:?}, sending keepalive NOP-In", keepalive.interval);
+                        if let Err(e) = stream.send_pdu(&ping, digests, trace.as_ref()) {
+                            result = Err(e);
+                            break;
+                        }
+                        ping_deadline = Some(Instant::now() + keepalive.timeout);
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        };
+
         // Read PDU from stream
-        let pdu = match read_pdu(&mut stream) {
-            Ok(pdu) => pdu,
+        let pdu = match received {
+            Ok(ReceivedPdu::Pdu(pdu)) => pdu,
+            Ok(ReceivedPdu::Oversized { header, data_length }) => {
+                let limit = session.max_recv_data_segment_limit();
+                log::warn!(
+                    "Rejecting PDU (opcode 0x{:02x}): DataSegmentLength {} exceeds MaxRecvDataSegmentLength {}",
+                    header[0] & 0x3F, data_length, limit
+                );
+
+                if session.state != SessionState::FullFeaturePhase {
+                    // Only Login Responses may be sent during login, so fail the login
+                    let itt = u32::from_be_bytes([header[16], header[17], header[18], header[19]]);
+                    let response = session.create_initiator_error_reject(itt)?;
+                    let _ = stream.send_pdu(&response, Digests::NONE, trace.as_ref());
+                    break;
+                }
+
+                let reject = IscsiPdu::reject(
+                    pdu::reject_reason::PROTOCOL_ERROR,
+                    session.next_stat_sn(),
+                    session.exp_cmd_sn,
+                    session.max_cmd_sn,
+                    &header,
+                );
+                if let Err(e) = stream.send_pdu(&reject, digests, trace.as_ref()) {
+                    result = Err(e);
+                    break;
+                }
+                continue;
+            }
+            Ok(ReceivedPdu::DataDigestError { header }) => {
+                log::warn!("Data digest error on PDU (opcode 0x{:02x}), rejecting", header[0] & 0x3F);
+                let reject = IscsiPdu::reject(
+                    pdu::reject_reason::DATA_DIGEST_ERROR,
+                    session.next_stat_sn(),
+                    session.exp_cmd_sn,
+                    session.max_cmd_sn,
+                    &header,
+                );
+                if let Err(e) = stream.send_pdu(&reject, digests, trace.as_ref()) {
+                    result = Err(e);
+                    break;
+                }
+                continue;
+            }
             Err(IscsiError::Io(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
                 log::debug!("Connection closed by initiator");
                 break;
             }
             Err(IscsiError::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                if session.state != SessionState::FullFeaturePhase {
+                    // The login-phase read timeout expired: drop the stalled login
+                    log::debug!("Login stalled, closing");
+                    break;
+                }
                 continue;
             }
             Err(IscsiError::Io(ref e)) if e.kind() == std::io::ErrorKind::TimedOut => {
@@ -248,17 +935,60 @@ fn handle_connection<D: ScsiBlockDevice>(
             }
         };
 
-        log::debug!("Received PDU: {} (opcode 0x{:02x})", pdu.opcode_name(), pdu.opcode);
+        log_context::log_pdu(settings.control.pdu_logging(), "Received", &pdu);
+
+        if pdu.opcode == opcode::NOP_OUT {
+            if let Some(registration) = &registration {
+                registration.heartbeat(last_received);
+            }
+            // An echo of a `ping_session()` NOP-In completes the ping
+            let ttt = BigEndian::read_u32(&pdu.specific[0..4]);
+            if pdu.itt == 0xFFFF_FFFF {
+                if let Some((sent, reply)) = pings.remove(&ttt) {
+                    if pdu.data != PING_DATA {
+                        log::warn!("NOP-Out answering ping 0x{:08x} did not echo its data", ttt);
+                    }
+                    let _ = reply.send(last_received.duration_since(sent));
+                    continue;
+                }
+            }
+        }
+
+        if let Some(commands) = commands.as_mut() {
+            commands.audit_received(&pdu);
+            let head_of_queue = pdu.opcode == opcode::SCSI_COMMAND
+                && matches!(pdu.flags & pdu::task_attribute::MASK, pdu::task_attribute::HEAD_OF_QUEUE | pdu::task_attribute::ACA);
+            let dispatched = if commands.defer(&mut session, &pdu) {
+                Ok(true)
+            } else if is_queueable(&pdu) {
+                commands.submit(&mut stream, &mut session, &pdu, &luns, &workers).map(|()| true)
+            } else if head_of_queue {
+                // HEAD OF QUEUE (and ACA) tasks go ahead of queued commands
+                Ok(false)
+            } else if matches!(pdu.opcode, opcode::SCSI_COMMAND | opcode::LOGOUT_REQUEST | opcode::TASK_MANAGEMENT_REQUEST) {
+                // Everything else sees the effects of earlier queued commands
+                commands.drain(&mut stream, &mut session).map(|()| false)
+            } else {
+                Ok(false)
+            };
+            match dispatched {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
 
         // Process PDU based on session state
-        let target_address = local_addr.to_string();
-        let prev_state = session.state.clone();
+        let prev_state = session.state;
         let response = match session.state {
             SessionState::Free | SessionState::SecurityNegotiation | SessionState::LoginOperationalNegotiation => {
-                handle_login_phase(&mut session, &pdu, target_name, &target_address, &shutting_down, max_sessions, &active_sessions)?
+                handle_login_phase(&mut session, &pdu, target_name, settings, &target_portals, &portal)
             }
             SessionState::FullFeaturePhase => {
-                handle_full_feature_phase(&mut session, &pdu, &device, target_name, &target_address)?
+                handle_full_feature_phase(&mut session, &pdu, &luns, target_name, &target_portals)
             }
             SessionState::Logout => {
                 log::info!("Session logout complete");
@@ -269,125 +999,708 @@ fn handle_connection<D: ScsiBlockDevice>(
                 break;
             }
         };
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        // Login settles the identifiers that label traced PDUs
+        if prev_state != SessionState::FullFeaturePhase {
+            if let Some(trace) = &trace {
+                trace.set_session(&session.params.initiator_name, session.isid, session.tsih, session.cid);
+            }
+        }
 
         // Adjust timeout when transitioning to FullFeaturePhase
         if prev_state != SessionState::FullFeaturePhase && session.state == SessionState::FullFeaturePhase {
             log::info!("Session entered FullFeaturePhase, increasing timeout");
-            stream.set_read_timeout(Some(Duration::from_secs(300))).ok();
+            context.set_session(&session.params.initiator_name, session.isid, session.tsih, session.cid);
+            stream.set_read_timeout(Some(FULL_FEATURE_READ_TIMEOUT)).ok();
             stream.set_write_timeout(Some(Duration::from_secs(30))).ok();
 
             // Track that a session was established and increment counter
             session_entered = true;
-            let count = active_sessions.fetch_add(1, Ordering::SeqCst);
+            logged_in = Some(Instant::now());
+            last_command = Instant::now();
+            luns.set_initiator(&session.params.initiator_name);
+            let count = settings.active_sessions.fetch_add(1, Ordering::SeqCst);
+            portal.stats.active_sessions.fetch_add(1, Ordering::SeqCst);
             log::debug!("Session count: {} -> {}", count, count + 1);
+
+            session.open_command_window(settings.command_window);
+            session.inline_deadline = settings.command_deadline.map(|deadline| InlineDeadline {
+                workers: Arc::clone(&workers),
+                deadline,
+                context: context.clone(),
+            });
+            let audit_log = settings.audit.clone().map(|sink| AuditLog::new(sink, &session.params.initiator_name));
+            match CommandQueue::start(&stream, settings, session.max_recv_data_segment_limit(), session.digests(), trace.clone(), audit_log, context.clone()) {
+                Ok(queue) => {
+                    if let Some(snapshot) = session.snapshot() {
+                        let events = queue.sender.clone();
+                        registration = Some(settings.control.register_session(
+                            snapshot,
+                            session.cid,
+                            session.params.clone(),
+                            session.initiator_addr,
+                            move || { let _ = events.send(ConnectionEvent::LogoutRequested); },
+                        ));
+                        let events = queue.sender.clone();
+                        if let Some(registration) = &registration {
+                            registration.set_ping(move |reply| { let _ = events.send(ConnectionEvent::Ping(reply)); });
+                        }
+                    }
+                    let events = queue.sender.clone();
+                    stream.on_drained(move || { let _ = events.send(ConnectionEvent::SendQueueDrained); });
+                    commands = Some(queue);
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
         }
 
         // Send response(s)
-        for resp_pdu in response {
-            log::debug!("Sending PDU: {} (opcode 0x{:02x})", resp_pdu.opcode_name(), resp_pdu.opcode);
-            write_pdu(&mut stream, &resp_pdu)?;
+        if let Some(commands) = commands.as_mut() {
+            commands.audit_sent(&response);
+        }
+        if let Err(e) = response.iter().try_for_each(|resp_pdu| {
+            log_context::log_pdu(settings.control.pdu_logging(), "Sending", resp_pdu);
+            stream.send_pdu(resp_pdu, digests, trace.as_ref())
+        }) {
+            result = Err(e);
+            break;
+        }
+        if session.state == SessionState::FullFeaturePhase {
+            digests = session.digests();
+        }
+
+        if let Some(snapshot) = session.retained_for_recovery.take() {
+            settings.control.retain_session(snapshot, Duration::from_secs(session.params.default_time2retain.into()));
         }
 
         // If we've transitioned to Logout state, break immediately after sending response
-        // This prevents blocking on the next read_pdu() call with a long timeout
+        // This prevents blocking on the next recv_pdu() call with a long timeout
         if matches!(session.state, SessionState::Logout | SessionState::Failed) {
             log::info!("Session ending (state: {:?})", session.state);
             break;
         }
     }
 
+    // Any R2T sequences still open were cut off by the connection going away
+    abort_pending_writes(&mut session, &luns, AbortReason::ConnectionLost);
+    // Losing the I_T nexus releases its SCSI-2 reservations and ends its ACA
+    if session_entered {
+        let nexus = reservation::nexus(&session);
+        for unit in luns.units() {
+            unit.reservation.release_nexus(&nexus);
+            unit.aca.release_nexus(&nexus);
+        }
+    }
+    // A session claimed by a login that never reached full feature phase
+    // can still be resumed by its initiator
+    if !session_entered {
+        if let Some(claim) = session.claimed_session.take() {
+            settings.control.unclaim(claim);
+        }
+    }
+    // A TSIH allocated to a login that never registered is free again
+    if registration.is_none() && session.tsih != 0 {
+        settings.control.release_tsih(session.tsih);
+    }
+
     // Clean shutdown
-    let _ = stream.shutdown(Shutdown::Both);
-    Ok(session_entered)
+    let _ = stream.close();
+    result.map(|()| session_entered)
 }
 
-/// Read a PDU from the TCP stream
-fn read_pdu(stream: &mut TcpStream) -> ScsiResult<IscsiPdu> {
-    // Read 48-byte BHS
-    let mut bhs = [0u8; BHS_SIZE];
-    stream.read_exact(&mut bhs).map_err(IscsiError::Io)?;
+/// Input to a full-feature connection's main loop
+enum ConnectionEvent {
+    /// A PDU (or read error) from the connection's reader thread
+    Received(ScsiResult<ReceivedPdu>),
+    /// A queued command finished executing on the worker pool
+    Completed { task: u64, response: ScsiResult<ScsiResponse> },
+    /// A configuration change no longer admits the session
+    LogoutRequested,
+    /// `TargetControl::ping_session()` wants a round trip measured
+    Ping(Sender<Duration>),
+    /// The send queue drained below half full
+    SendQueueDrained,
+}
 
-    // Parse AHS length and data segment length from BHS
-    let ahs_length = bhs[4] as usize * 4;
-    let data_length = ((bhs[5] as u32) << 16) | ((bhs[6] as u32) << 8) | (bhs[7] as u32);
-    let padded_data_len = (data_length as usize).div_ceil(4) * 4;
+/// Per-connection queue of SCSI commands executing on the worker pool
+///
+/// A reader thread feeds incoming PDUs into the same channel the workers
+/// report completions on, so the connection thread stays the only writer and
+/// assigns StatSN in the order responses go out.
+struct CommandQueue {
+    events: Receiver<ConnectionEvent>,
+    sender: Sender<ConnectionEvent>,
+    /// PDUs received while draining, handled before new events
+    backlog: VecDeque<ScsiResult<ReceivedPdu>>,
+    /// A logout request that arrived while draining
+    logout_requested: bool,
+    /// Ping requests that arrived while draining
+    ping_requests: Vec<Sender<Duration>>,
+    /// SCSI Commands held back until an ORDERED task before them completes
+    deferred: VecDeque<IscsiPdu>,
+    /// ORDERED tasks that may still be waiting on Data-Out
+    ordered: Vec<u32>,
+    /// Commands occupying a worker, including ones answered at their deadline
+    in_flight: u32,
+    /// Commands still awaiting their response, by task number
+    running: HashMap<u64, RunningCommand>,
+    next_task: u64,
+    depth: u32,
+    deadline: Option<CommandDeadline>,
+    digests: Digests,
+    trace: Option<ConnectionTrace>,
+    /// Audit records for this connection's commands (None = auditing off)
+    audit: Option<AuditLog>,
+    /// Streaming READ detection (None = read-ahead off)
+    read_ahead: Option<SequentialReads>,
+    /// Entered by the reader thread and by workers running this connection's commands
+    context: ConnectionContext,
+}
 
-    // Read remaining data (AHS + data segment + padding)
-    let total_len = BHS_SIZE + ahs_length + padded_data_len;
-    let mut full_pdu = vec![0u8; total_len];
-    full_pdu[..BHS_SIZE].copy_from_slice(&bhs);
+impl CommandQueue {
+    /// Start the reader thread for a connection entering FullFeaturePhase
+    fn start<T: PduTransport, D: ScsiBlockDevice>(
+        stream: &T,
+        settings: &ConnectionSettings<D>,
+        max_data_segment: u32,
+        digests: Digests,
+        trace: Option<ConnectionTrace>,
+        audit: Option<AuditLog>,
+        context: ConnectionContext,
+    ) -> ScsiResult<Self> {
+        let limits = settings.pdu_limits;
+        let mut reader = stream.try_clone().map_err(IscsiError::Io)?;
+        let (sender, events) = mpsc::channel();
+        let reader_trace = trace.clone();
+
+        let reader_events = sender.clone();
+        let reader_context = context.clone();
+        thread::Builder::new()
+            .name("iscsi-reader".to_string())
+            .spawn(move || reader_context.in_scope(|| loop {
+                let received = reader.recv_pdu(max_data_segment, digests, &limits, reader_trace.as_ref());
+                let keep_reading = match &received {
+                    Ok(_) => true,
+                    Err(IscsiError::Io(e)) => e.kind() == std::io::ErrorKind::WouldBlock,
+                    Err(_) => false,
+                };
+                if reader_events.send(ConnectionEvent::Received(received)).is_err() || !keep_reading {
+                    break;
+                }
+            }))
+            .map_err(IscsiError::Io)?;
 
-    if total_len > BHS_SIZE {
-        stream.read_exact(&mut full_pdu[BHS_SIZE..]).map_err(IscsiError::Io)?;
+        Ok(Self {
+            events,
+            sender,
+            backlog: VecDeque::new(),
+            logout_requested: false,
+            ping_requests: Vec::new(),
+            deferred: VecDeque::new(),
+            ordered: Vec::new(),
+            in_flight: 0,
+            running: HashMap::new(),
+            next_task: 0,
+            deadline: settings.command_deadline,
+            depth: settings.queue_depth,
+            digests,
+            trace,
+            audit,
+            read_ahead: settings.read_ahead.map(SequentialReads::new),
+            context,
+        })
     }
 
-    let pdu = IscsiPdu::from_bytes(&full_pdu)?;
-
-    // Log received PDU header details
-    if full_pdu.len() >= 48 {
-        log::debug!("Received PDU header hex: {}", full_pdu[0..48].iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "));
-        log::debug!("  [0] Opcode: 0x{:02x}", full_pdu[0]);
-        log::debug!("  [1] Flags: 0x{:02x}", full_pdu[1]);
-        log::debug!("  [5-7] DataSegmentLength: {} bytes", (full_pdu[5] as u32) << 16 | (full_pdu[6] as u32) << 8 | full_pdu[7] as u32);
+    /// Start timing a SCSI Command for the audit log
+    fn audit_received(&mut self, pdu: &IscsiPdu) {
+        if let Some(audit) = self.audit.as_mut() {
+            audit.received(pdu);
+        }
     }
 
-    Ok(pdu)
-}
+    /// Record the commands completed by PDUs about to be sent
+    fn audit_sent(&mut self, pdus: &[IscsiPdu]) {
+        if let Some(audit) = self.audit.as_mut() {
+            pdus.iter().for_each(|pdu| audit.sent(pdu));
+        }
+    }
 
-/// Write a PDU to the TCP stream
-fn write_pdu(stream: &mut TcpStream, pdu: &IscsiPdu) -> ScsiResult<()> {
-    let bytes = pdu.to_bytes();
+    /// When the oldest queued command overruns the command deadline
+    fn next_deadline(&self) -> Option<Instant> {
+        let deadline = self.deadline?;
+        self.running.values().map(|command| command.started + deadline.timeout).min()
+    }
 
-    // Log PDU header in detail
-    if bytes.len() >= 48 {
-        log::debug!("PDU Header hex: {}", bytes[0..48].iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "));
-        log::debug!("  [0] Opcode: 0x{:02x}", bytes[0]);
-        log::debug!("  [1] Flags: 0x{:02x}", bytes[1]);
-        log::debug!("  [5-7] DataSegmentLength: {} bytes", (bytes[5] as u32) << 16 | (bytes[6] as u32) << 8 | bytes[7] as u32);
-        log::debug!("  Data segment ({} bytes): {:?}", bytes.len() - 48, String::from_utf8_lossy(&bytes[48..]));
+    /// Answer every queued command that has overrun the command deadline
+    fn expire(&mut self, stream: &mut impl PduTransport, session: &mut IscsiSession, now: Instant) -> ScsiResult<()> {
+        let Some(deadline) = self.deadline else { return Ok(()) };
+        let mut expired: Vec<_> = self.running.iter()
+            .filter(|(_, command)| now >= command.started + deadline.timeout)
+            .map(|(&task, _)| task)
+            .collect();
+        expired.sort_unstable();
+        for task in expired {
+            let command = self.running.remove(&task).expect("expired command is running");
+            let elapsed = now.saturating_duration_since(command.started);
+            log::warn!(
+                "Command 0x{:02x} ITT=0x{:08x} to LUN 0x{:016x} still running after {:?}, answering without the device",
+                command.opcode, command.itt, command.lun, elapsed
+            );
+            session.notify(TargetEvent::CommandTimedOut { itt: command.itt, lun: command.lun, opcode: command.opcode, elapsed });
+            let pdu = status_response(session, command.itt, &deadline_response(deadline));
+            self.audit_sent(std::slice::from_ref(&pdu));
+            stream.send_pdu(&pdu, self.digests, self.trace.as_ref())?;
+        }
+        Ok(())
     }
 
-    stream.write_all(&bytes).map_err(IscsiError::Io)?;
-    stream.flush().map_err(IscsiError::Io)?;
-    Ok(())
-}
+    /// Next event, waiting no later than `deadline`
+    fn next_event(&mut self, deadline: Option<Instant>) -> Result<ConnectionEvent, RecvTimeoutError> {
+        if let Some(received) = self.backlog.pop_front() {
+            return Ok(ConnectionEvent::Received(received));
+        }
+        if std::mem::take(&mut self.logout_requested) {
+            return Ok(ConnectionEvent::LogoutRequested);
+        }
+        if let Some(reply) = self.ping_requests.pop() {
+            return Ok(ConnectionEvent::Ping(reply));
+        }
+        match deadline {
+            Some(deadline) => self.events.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => self.events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        }
+    }
 
-/// Handle PDUs during login phase
-fn handle_login_phase(
-    session: &mut IscsiSession,
-    pdu: &IscsiPdu,
-    target_name: &str,
-    target_address: &str,
-    shutting_down: &Arc<AtomicBool>,
-    max_sessions: u32,
-    active_sessions: &Arc<std::sync::atomic::AtomicUsize>,
-) -> ScsiResult<Vec<IscsiPdu>> {
-    match pdu.opcode {
-        opcode::LOGIN_REQUEST => {
-            // Check if target is shutting down - reject new login attempts
-            if shutting_down.load(Ordering::SeqCst) && session.state == SessionState::Free {
-                log::warn!("Login rejected: target is shutting down");
-                let response = session.create_shutdown_reject(pdu.itt)?;
-                return Ok(vec![response]);
+    /// Hold back a SCSI Command that must wait for an ORDERED task
+    ///
+    /// An ORDERED task waits for every earlier command, including writes
+    /// still receiving Data-Out, and everything after it waits for the
+    /// ORDERED task. HEAD OF QUEUE tasks never wait. Returns whether the
+    /// command was taken care of: deferred, or held or dropped for its CmdSN.
+    fn defer(&mut self, session: &mut IscsiSession, pdu: &IscsiPdu) -> bool {
+        if pdu.opcode != opcode::SCSI_COMMAND {
+            return false;
+        }
+        self.ordered.retain(|itt| has_pending_data_out(session, *itt));
+        let must_wait = match pdu.flags & pdu::task_attribute::MASK {
+            pdu::task_attribute::HEAD_OF_QUEUE => false,
+            _ if !self.deferred.is_empty() || !self.ordered.is_empty() => true,
+            pdu::task_attribute::ORDERED => {
+                !session.pending_writes.is_empty() || !session.pending_parameter_lists.is_empty()
             }
-
+            _ => false,
+        };
+        if must_wait {
+            log::debug!("Deferring ITT=0x{:08x} behind an ORDERED task", pdu.itt);
+            // CmdSN ordering is delivery order: take it now, not when the task
+            // runs. A command not accepted has been held or dropped already.
+            if sequence_command(session, pdu) != CmdSnCheck::Accepted {
+                return true;
+            }
+            session.deferred_commands.insert(pdu.itt);
+            self.deferred.push_back(pdu.clone());
+        } else if pdu.flags & pdu::task_attribute::MASK == pdu::task_attribute::ORDERED {
+            self.ordered.push(pdu.itt);
+        }
+        must_wait
+    }
+
+    /// Requeue deferred commands once no ORDERED task is outstanding
+    ///
+    /// They are handled again in arrival order, ahead of anything received
+    /// since, and the first ORDERED one among them defers the rest anew.
+    fn release_deferred(&mut self, session: &IscsiSession) {
+        if self.deferred.is_empty() {
+            return;
+        }
+        self.ordered.retain(|itt| has_pending_data_out(session, *itt));
+        let barrier = !self.ordered.is_empty()
+            || self.deferred.front().is_some_and(|pdu| {
+                pdu.flags & pdu::task_attribute::MASK == pdu::task_attribute::ORDERED
+                    && (!session.pending_writes.is_empty() || !session.pending_parameter_lists.is_empty())
+            });
+        if barrier {
+            return;
+        }
+        while let Some(pdu) = self.deferred.pop_back() {
+            self.backlog.push_front(Ok(ReceivedPdu::Pdu(pdu)));
+        }
+    }
+
+    /// Requeue the request that arrived early for the CmdSN now expected
+    ///
+    /// It is handled ahead of anything received since; the one after it
+    /// follows once it has taken its CmdSN.
+    fn release_held(&mut self, session: &mut IscsiSession) {
+        if let Some(pdu) = session.take_ready_command() {
+            log::debug!("Releasing held {} ITT=0x{:08x}", pdu.opcode_name(), pdu.itt);
+            self.backlog.push_front(Ok(ReceivedPdu::Pdu(pdu)));
+        }
+    }
+
+    /// Queue a command on the worker pool, or reject it with TASK SET FULL
+    fn submit<D: ScsiBlockDevice + Send + 'static>(
+        &mut self,
+        stream: &mut impl PduTransport,
+        session: &mut IscsiSession,
+        pdu: &IscsiPdu,
+        luns: &Arc<SessionLuns<D>>,
+        workers: &WorkerPool,
+    ) -> ScsiResult<()> {
+        let cmd = pdu.parse_scsi_command()?;
+
+        if sequence_command(session, pdu) != CmdSnCheck::Accepted {
+            return Ok(());
+        }
+
+        let (lun, device, unit) = match resolve_lun(session, &cmd, luns) {
+            Ok(resolved) => resolved,
+            Err(response) => {
+                let pdu = status_response(session, cmd.itt, &response);
+                self.audit_sent(std::slice::from_ref(&pdu));
+                return stream.send_pdu(&pdu, self.digests, self.trace.as_ref());
+            }
+        };
+
+        if self.in_flight >= self.depth {
+            log::warn!("Task set full ({} commands queued), rejecting ITT=0x{:08x}", self.in_flight, cmd.itt);
+            let response = ScsiResponse {
+                status: scsi_status::TASK_SET_FULL,
+                data: Vec::new(),
+                sense: None,
+            };
+            let pdu = status_response(session, cmd.itt, &response);
+            self.audit_sent(std::slice::from_ref(&pdu));
+            return stream.send_pdu(&pdu, self.digests, self.trace.as_ref());
+        }
+
+        if let Some(response) = check_aca(session, &cmd, &unit) {
+            let pdu = status_response(session, cmd.itt, &response);
+            self.audit_sent(std::slice::from_ref(&pdu));
+            return stream.send_pdu(&pdu, self.digests, self.trace.as_ref());
+        }
+
+        if let Some(response) = check_reservation(session, &cmd.cdb, &unit) {
+            let pdu = status_response(session, cmd.itt, &response);
+            self.audit_sent(std::slice::from_ref(&pdu));
+            return stream.send_pdu(&pdu, self.digests, self.trace.as_ref());
+        }
+
+        self.in_flight += 1;
+        let task = self.next_task;
+        self.next_task += 1;
+        self.running.insert(task, RunningCommand {
+            itt: cmd.itt,
+            lun,
+            opcode: cmd.cdb[0],
+            read: cmd.read,
+            expected_length: cmd.expected_data_length,
+            started: Instant::now(),
+        });
+        let prefetch = self.read_ahead.as_mut().and_then(|reads| reads.observe(lun, &cmd.cdb));
+        let changes = luns.changes();
+        let luns = Arc::clone(luns);
+        let events = self.sender.clone();
+        let context = self.context.clone();
+        workers.execute(move || context.in_scope(|| {
+            // The LUN may have been removed or its medium swapped while the
+            // command was queued
+            let changed = luns.changed_since(lun, changes).filter(|(_, kind)| kind.fails_in_flight());
+            let response = if !luns.is_current(lun, &device) {
+                Ok(ScsiResponse::check_condition(SenseData::logical_unit_not_supported()))
+            } else if let Some((_, kind)) = changed {
+                Ok(ScsiResponse::check_condition(kind.sense()))
+            } else {
+                panic::catch_unwind(AssertUnwindSafe(|| execute_command(&cmd.cdb, &device)))
+                    .unwrap_or_else(|_| Err(IscsiError::scsi("SCSI command handler panicked".to_string())))
+            };
+            let succeeded = matches!(&response, Ok(response) if response.status == scsi_status::GOOD);
+            let _ = events.send(ConnectionEvent::Completed { task, response });
+            // Prefetch after answering so the READ is not held up
+            if let Some((lba, blocks)) = prefetch.filter(|_| succeeded) {
+                prefetch_ahead(&device, lba, blocks);
+            }
+        }));
+        Ok(())
+    }
+
+    /// Send the response for a completed command, unless it was already
+    /// answered at its deadline
+    fn complete(
+        &mut self,
+        stream: &mut impl PduTransport,
+        session: &mut IscsiSession,
+        task: u64,
+        response: ScsiResult<ScsiResponse>,
+    ) -> ScsiResult<()> {
+        self.in_flight -= 1;
+        let Some(command) = self.running.remove(&task) else {
+            log::info!("Discarding late result of a command answered at its deadline");
+            return Ok(());
+        };
+        let responses = command_response(session, command.itt, command.read, command.expected_length, &response?);
+        self.audit_sent(&responses);
+        responses.iter().try_for_each(|pdu| stream.send_pdu(pdu, self.digests, self.trace.as_ref()))
+    }
+
+    /// Wait for every queued command to be answered, keeping PDUs that
+    /// arrive meanwhile
+    fn drain(&mut self, stream: &mut impl PduTransport, session: &mut IscsiSession) -> ScsiResult<()> {
+        while !self.running.is_empty() {
+            let event = match self.next_deadline() {
+                Some(deadline) => self.events.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self.events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match event {
+                Ok(ConnectionEvent::Completed { task, response }) => self.complete(stream, session, task, response)?,
+                Ok(ConnectionEvent::Received(received)) => self.backlog.push_back(received),
+                Ok(ConnectionEvent::LogoutRequested) => self.logout_requested = true,
+                Ok(ConnectionEvent::Ping(reply)) => self.ping_requests.push(reply),
+                // Congestion is checked again at the top of the connection loop
+                Ok(ConnectionEvent::SendQueueDrained) => {}
+                Err(RecvTimeoutError::Timeout) => self.expire(stream, session, Instant::now())?,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A command queued on the worker pool and not yet answered
+#[derive(Debug)]
+struct RunningCommand {
+    itt: u32,
+    lun: u64,
+    opcode: u8,
+    read: bool,
+    expected_length: u32,
+    started: Instant,
+}
+
+/// Worker pool and deadline for the device calls of commands the connection
+/// thread handles itself: WRITEs, their FUA flush and SYNCHRONIZE CACHE
+#[derive(Debug, Clone)]
+pub(crate) struct InlineDeadline {
+    workers: Arc<WorkerPool>,
+    deadline: CommandDeadline,
+    context: ConnectionContext,
+}
+
+/// Make a device call for a command handled on the connection thread
+///
+/// Under a command deadline the call runs on the worker pool, and the
+/// deadline is returned if the device has not answered in time.
+fn call_device<R: Send + 'static>(
+    session: &IscsiSession,
+    call: impl FnOnce() -> R + Send + 'static,
+) -> Result<R, CommandDeadline> {
+    let Some(inline) = &session.inline_deadline else {
+        return Ok(call());
+    };
+    let context = inline.context.clone();
+    inline.workers.run_within(inline.deadline.timeout, move || context.in_scope(call)).ok_or(inline.deadline)
+}
+
+/// Status for a command answered at the deadline without the device
+fn deadline_response(deadline: CommandDeadline) -> ScsiResponse {
+    if deadline.busy {
+        ScsiResponse { status: scsi_status::BUSY, data: Vec::new(), sense: None }
+    } else {
+        ScsiResponse::check_condition(SenseData::command_timeout())
+    }
+}
+
+/// Answer a command whose device call overran the deadline on the
+/// connection thread
+fn device_timed_out(session: &mut IscsiSession, deadline: CommandDeadline, itt: u32, lun: u64, opcode: u8) -> IscsiPdu {
+    log::warn!(
+        "Command 0x{:02x} ITT=0x{:08x} to LUN 0x{:016x} still in the device after {:?}, answering without it",
+        opcode, itt, lun, deadline.timeout
+    );
+    session.notify(TargetEvent::CommandTimedOut { itt, lun, opcode, elapsed: deadline.timeout });
+    status_response(session, itt, &deadline_response(deadline))
+}
+
+/// Ask the device to prefetch blocks a streaming READ will want next,
+/// clipped to the end of the medium
+fn prefetch_ahead<D: ScsiBlockDevice>(device: &RwLock<CountingDevice<D>>, lba: u64, blocks: u32) {
+    let Ok(device) = device.read() else { return };
+    let capacity = device.capacity();
+    if lba >= capacity {
+        return;
+    }
+    let blocks = blocks.min((capacity - lba).min(u32::MAX.into()) as u32);
+    if let Err(e) = device.prefetch(lba, blocks) {
+        log::debug!("Read-ahead of {} blocks at LBA {} failed: {}", blocks, lba, e);
+    }
+}
+
+/// Take a request's CmdSN, unless that happened when it was deferred
+///
+/// Anything but `Accepted` means the request must not run now and gets no
+/// response: duplicates and CmdSNs outside the window are dropped, and an
+/// early request is held by the session until its turn. Immediate requests
+/// are always delivered, even through a closed window.
+fn sequence_command(session: &mut IscsiSession, pdu: &IscsiPdu) -> CmdSnCheck {
+    if session.deferred_commands.remove(&pdu.itt) || !takes_cmd_sn(pdu) {
+        return CmdSnCheck::Accepted;
+    }
+    let cmd_sn = BigEndian::read_u32(&pdu.specific[4..8]);
+    let check = session.check_cmd_sn(cmd_sn);
+    match check {
+        CmdSnCheck::Accepted => {}
+        CmdSnCheck::Duplicate => log::debug!(
+            "Dropping duplicate {} ITT=0x{:08x} CmdSN {} (ExpCmdSN {})",
+            pdu.opcode_name(), pdu.itt, cmd_sn, session.exp_cmd_sn
+        ),
+        CmdSnCheck::Early => {
+            if session.hold_command(cmd_sn, pdu) {
+                log::debug!(
+                    "Holding {} ITT=0x{:08x} CmdSN {} until ExpCmdSN {} catches up",
+                    pdu.opcode_name(), pdu.itt, cmd_sn, session.exp_cmd_sn
+                );
+            } else {
+                log::debug!("Dropping duplicate {} ITT=0x{:08x} CmdSN {} (already held)", pdu.opcode_name(), pdu.itt, cmd_sn);
+            }
+        }
+        CmdSnCheck::OutOfWindow => log::warn!(
+            "Ignoring {} ITT=0x{:08x}: CmdSN {} outside window {}..={}",
+            pdu.opcode_name(), pdu.itt, cmd_sn, session.exp_cmd_sn, session.max_cmd_sn
+        ),
+    }
+    check
+}
+
+/// Whether a request consumes a CmdSN
+///
+/// Immediate requests carry the current CmdSN without advancing it, and
+/// NOP-Outs answering a target ping carry none at all.
+fn takes_cmd_sn(pdu: &IscsiPdu) -> bool {
+    !(pdu.immediate || (pdu.opcode == opcode::NOP_OUT && pdu.itt == 0xFFFF_FFFF))
+}
+
+/// Whether the command `itt` is still receiving Data-Out
+fn has_pending_data_out(session: &IscsiSession, itt: u32) -> bool {
+    session.pending_writes.contains_key(&itt) || session.pending_parameter_lists.contains_key(&itt)
+}
+
+/// Whether a PDU is a SCSI command that can run on the worker pool
+///
+/// Only SIMPLE (or untagged) commands without Data-Out or session state
+/// qualify: writes, MODE SELECT and REQUEST SENSE, other LUNs, and ORDERED
+/// tasks run inline on the connection thread once the queue has drained.
+/// HEAD OF QUEUE and ACA tasks also run inline, but without draining.
+fn is_queueable(pdu: &IscsiPdu) -> bool {
+    if pdu.opcode != opcode::SCSI_COMMAND
+        || !pdu.data.is_empty()
+        || pdu.flags & pdu::task_attribute::MASK > pdu::task_attribute::SIMPLE
+    {
+        return false;
+    }
+    let Ok(cmd) = pdu.parse_scsi_command() else {
+        return false;
+    };
+    !cmd.write
+        && !aca::naca(&cmd.cdb)
+        && !matches!(Cdb::decode(&cmd.cdb), Some(Cdb::Write(_)))
+        && !matches!(cmd.cdb[0], 0x03 | 0x15 | 0x55 | 0xa0)
+        && !reservation::is_reservation_command(cmd.cdb[0])
+}
+
+/// Handle PDUs during login phase
+fn handle_login_phase<D: ScsiBlockDevice>(
+    session: &mut IscsiSession,
+    pdu: &IscsiPdu,
+    target_name: &str,
+    settings: &ConnectionSettings<D>,
+    target_portals: &[(String, u16)],
+    portal: &PortalState,
+) -> ScsiResult<Vec<IscsiPdu>> {
+    match pdu.opcode {
+        opcode::LOGIN_REQUEST => {
+            // Check if target is shutting down - reject new login attempts
+            if settings.shutting_down.load(Ordering::SeqCst) && session.state == SessionState::Free {
+                log::warn!("Login rejected: target is shutting down");
+                let response = session.create_shutdown_reject(pdu.itt)?;
+                return Ok(vec![response]);
+            }
+
             // Check session limit - reject if at capacity
             // Note: We check before processing login, but actual session count is incremented
             // only when entering FullFeaturePhase (see handle_connection)
             if session.state == SessionState::Free {
-                let current_sessions = active_sessions.load(Ordering::SeqCst);
+                let current_sessions = settings.active_sessions.load(Ordering::SeqCst);
                 log::debug!(
                     "Session limit check: current={}, max={}, state={:?}",
-                    current_sessions, max_sessions, session.state
+                    current_sessions, settings.max_sessions, session.state
                 );
-                if current_sessions >= max_sessions as usize {
+                if current_sessions >= settings.max_sessions as usize {
                     log::warn!(
                         "Login rejected: session limit reached ({}/{} active)",
-                        current_sessions, max_sessions
+                        current_sessions, settings.max_sessions
                     );
+                    portal.stats.rejected_logins.fetch_add(1, Ordering::SeqCst);
                     let response = session.create_out_of_resources_reject(pdu.itt)?;
                     return Ok(vec![response]);
                 }
+
+                if let Some(portal_max) = portal.config.max_sessions {
+                    let portal_sessions = portal.stats.active_sessions.load(Ordering::SeqCst);
+                    if portal_sessions >= portal_max as usize {
+                        log::warn!(
+                            "Login rejected: session limit reached on portal {} ({}/{} active)",
+                            portal.config.bind_addr, portal_sessions, portal_max
+                        );
+                        portal.stats.rejected_logins.fetch_add(1, Ordering::SeqCst);
+                        let response = session.create_out_of_resources_reject(pdu.itt)?;
+                        return Ok(vec![response]);
+                    }
+                }
+            }
+
+            // Keys may be split across PDUs with the C bit; look at the whole
+            // text once the final PDU arrives
+            let pdu = match session.assemble_login_request(pdu) {
+                Ok(Some(pdu)) => pdu,
+                Ok(None) => return Ok(vec![session.create_login_continue_ack(pdu)]),
+                Err(e) => {
+                    log::warn!("Login rejected: {}", e);
+                    return Ok(vec![session.create_initiator_error_reject(pdu.itt)?]);
+                }
+            };
+            let pdu = pdu.as_ref();
+
+            // A login with a non-zero TSIH continues an existing session: one
+            // from before a restart, retained for recovery, or still live on
+            // another connection (RFC 3720 Section 5.3.4). Nothing is claimed
+            // or drained until the login has authenticated.
+            if session.state == SessionState::Free {
+                let login = pdu.parse_login_request()?;
+                let lookup = login.parameters.iter()
+                    .find(|(key, _)| key == "InitiatorName" && login.tsih != 0)
+                    .map(|(_, name)| settings.control.lookup_session(login.isid, login.tsih, name, login.cid));
+                match lookup {
+                    // Claimed by process_login() once the initiator authenticates
+                    Some(SessionLookup::Resume(snapshot)) => {
+                        log::debug!("Login continues session TSIH {} for {}", snapshot.tsih, snapshot.initiator_name);
+                        session.tsih = snapshot.tsih;
+                    }
+                    Some(SessionLookup::TooManyConnections) => {
+                        let response = session.create_too_many_connections_reject(pdu.itt)?;
+                        return Ok(vec![response]);
+                    }
+                    // process_login() answers SESSION_DOES_NOT_EXIST
+                    Some(SessionLookup::NotFound) | None => {}
+                }
             }
 
             let response = session.process_login(pdu, target_name)?;
@@ -395,7 +1708,7 @@ fn handle_login_phase(
         }
         opcode::TEXT_REQUEST => {
             // Text request during login (e.g., SendTargets for discovery)
-            handle_text_request(session, pdu, target_name, target_address)
+            handle_text_request(session, pdu, target_name, target_portals)
         }
         _ => {
             log::warn!(
@@ -410,33 +1723,49 @@ fn handle_login_phase(
 }
 
 /// Handle PDUs during full feature phase
-fn handle_full_feature_phase<D: ScsiBlockDevice>(
+fn handle_full_feature_phase<D: ScsiBlockDevice + 'static>(
     session: &mut IscsiSession,
     pdu: &IscsiPdu,
-    device: &Arc<Mutex<D>>,
+    luns: &SessionLuns<D>,
     target_name: &str,
-    target_address: &str,
+    target_portals: &[(String, u16)],
 ) -> ScsiResult<Vec<IscsiPdu>> {
+    let sequenced = matches!(
+        pdu.opcode,
+        opcode::NOP_OUT | opcode::LOGOUT_REQUEST | opcode::TEXT_REQUEST | opcode::TASK_MANAGEMENT_REQUEST
+    );
+    if sequenced && sequence_command(session, pdu) != CmdSnCheck::Accepted {
+        return Ok(Vec::new());
+    }
+
     match pdu.opcode {
         opcode::SCSI_COMMAND => {
-            handle_scsi_command(session, pdu, device)
+            handle_scsi_command(session, pdu, luns).map(|responses| track_naca_tasks(session, luns, responses))
         }
         opcode::SCSI_DATA_OUT => {
-            handle_scsi_data_out(session, pdu, device)
+            handle_scsi_data_out(session, pdu, luns).map(|responses| track_naca_tasks(session, luns, responses))
         }
         opcode::NOP_OUT => {
             let response = session.process_nop_out(pdu)?;
-            Ok(vec![response])
+            Ok(response.into_iter().collect())
         }
         opcode::LOGOUT_REQUEST => {
             let response = session.process_logout(pdu)?;
+            // RFC 3720 10.14: outstanding tasks are terminated before the
+            // Logout Response is sent, so the initiator sees a quiesced session
+            if session.state == SessionState::Logout {
+                abort_pending_writes(session, luns, AbortReason::Logout);
+            }
             Ok(vec![response])
         }
         opcode::TEXT_REQUEST => {
-            handle_text_request(session, pdu, target_name, target_address)
+            handle_text_request(session, pdu, target_name, target_portals)
         }
         opcode::TASK_MANAGEMENT_REQUEST => {
-            handle_task_management(session, pdu)
+            handle_task_management(session, pdu, luns)
+        }
+        opcode::SNACK_REQUEST => {
+            handle_snack_request(session, pdu)
         }
         _ => {
             log::warn!("Unsupported opcode 0x{:02x} in full feature phase", pdu.opcode);
@@ -445,11 +1774,87 @@ fn handle_full_feature_phase<D: ScsiBlockDevice>(
     }
 }
 
+/// Handle a SNACK Request
+///
+/// No PDUs are kept for retransmission, so every SNACK is answered with a
+/// Reject (SNACK Reject) rather than left to stall an ERL>0 initiator. An
+/// unknown SNACK type is a protocol error.
+fn handle_snack_request(session: &mut IscsiSession, pdu: &IscsiPdu) -> ScsiResult<Vec<IscsiPdu>> {
+    let snack = pdu.parse_snack_request()?;
+    let reason = if snack.snack_type <= pdu::snack_type::RDATA {
+        log::warn!(
+            "Rejecting SNACK type {} for ITT 0x{:08x} (BegRun {}, RunLength {}): retransmission is not supported",
+            snack.snack_type, snack.itt, snack.beg_run, snack.run_length
+        );
+        pdu::reject_reason::SNACK_REJECT
+    } else {
+        log::warn!("Rejecting SNACK with unknown type {}", snack.snack_type);
+        pdu::reject_reason::PROTOCOL_ERROR
+    };
+    let header = pdu.to_bytes();
+    Ok(vec![IscsiPdu::reject(
+        reason,
+        session.next_stat_sn(),
+        session.exp_cmd_sn,
+        session.max_cmd_sn,
+        &header[..BHS_SIZE],
+    )])
+}
+
+/// Abort all WRITEs still waiting on Data-Out
+///
+/// Tells the device through `abort_write` about the partially written range and
+/// notifies the observer for each aborted task.
+fn abort_pending_writes<D: ScsiBlockDevice>(
+    session: &mut IscsiSession,
+    luns: &SessionLuns<D>,
+    reason: AbortReason,
+) {
+    for (itt, pending) in session.take_pending_writes() {
+        abort_pending_write(session, luns, itt, pending, reason);
+    }
+}
+
+/// Abort a single WRITE already removed from the session's pending writes
+fn abort_pending_write<D: ScsiBlockDevice>(
+    session: &mut IscsiSession,
+    luns: &SessionLuns<D>,
+    itt: u32,
+    pending: PendingWrite,
+    reason: AbortReason,
+) {
+    log::warn!(
+        "Aborting WRITE ITT=0x{:08x} ({:?}): {}/{} bytes received",
+        itt, reason, pending.bytes_received, pending.total_bytes()
+    );
+
+    // A removed LUN has no device left to tell
+    if let Some(device) = luns.get(pending.lun) {
+        match device.write() {
+            Ok(mut device_guard) => {
+                if let Err(e) = device_guard.abort_write(pending.lba, pending.transfer_length) {
+                    log::error!("abort_write failed for aborted WRITE ITT=0x{:08x}: {}", itt, e);
+                }
+            }
+            Err(_) => log::error!("Device lock poisoned while aborting WRITE ITT=0x{:08x}", itt),
+        }
+    }
+
+    session.notify(TargetEvent::WriteAborted {
+        itt,
+        lun: pending.lun,
+        lba: pending.lba,
+        transfer_length: pending.transfer_length,
+        bytes_received: pending.bytes_received,
+        reason,
+    });
+}
+
 /// Handle SCSI Command PDU
-fn handle_scsi_command<D: ScsiBlockDevice>(
+fn handle_scsi_command<D: ScsiBlockDevice + 'static>(
     session: &mut IscsiSession,
     pdu: &IscsiPdu,
-    device: &Arc<Mutex<D>>,
+    luns: &SessionLuns<D>,
 ) -> ScsiResult<Vec<IscsiPdu>> {
     let cmd = pdu.parse_scsi_command()?;
 
@@ -458,118 +1863,122 @@ fn handle_scsi_command<D: ScsiBlockDevice>(
         cmd.cdb[0], cmd.lun, cmd.itt, cmd.expected_data_length, cmd.read, cmd.write, cmd.final_flag, pdu.data.len()
     );
 
-    // Validate LUN - only LUN 0 is supported
-    // iSCSI LUNs are encoded per RFC 3720 section 3.4.6.1
-    // For simplicity, we check if the raw LUN value is 0
-    // LUN 0 is always encoded as 0x0000000000000000 regardless of addressing method
-    if cmd.lun != 0 {
-        log::warn!("Command 0x{:02x} to invalid LUN: 0x{:016x}", cmd.cdb[0], cmd.lun);
-        let sense = crate::scsi::SenseData::new(
-            crate::scsi::sense_key::ILLEGAL_REQUEST,
-            crate::scsi::asc::LOGICAL_UNIT_NOT_SUPPORTED,
-            0,
-        );
-        return Ok(vec![IscsiPdu::scsi_response(
-            cmd.itt,
-            session.next_stat_sn(),
-            session.exp_cmd_sn,
-            session.max_cmd_sn,
-            pdu::scsi_status::CHECK_CONDITION,
-            0,
-            0,
-            Some(&sense.to_bytes()),
-        )]);
+    if sequence_command(session, pdu) != CmdSnCheck::Accepted {
+        return Ok(Vec::new());
     }
 
-    // Validate command sequence number
-    let cmd_sn = BigEndian::read_u32(&pdu.specific[4..8]);
-    if !session.validate_cmd_sn(cmd_sn) {
-        log::warn!("Invalid CmdSN: {}, expected: {}", cmd_sn, session.exp_cmd_sn);
+    // REPORT LUNS lists the table as it is now, whichever LUN it is sent to,
+    // and tells the initiator about every change so far
+    if cmd.cdb[0] == 0xa0 {
+        session.lun_generation = luns.generation();
+        let response = ScsiHandler::report_luns(&cmd.cdb, &luns.luns())?;
+        return Ok(command_response(session, cmd.itt, cmd.read, cmd.expected_data_length, &response));
+    }
+
+    let (lun, device, unit) = match resolve_lun(session, &cmd, luns) {
+        Ok(resolved) => resolved,
+        Err(response) => return Ok(vec![status_response(session, cmd.itt, &response)]),
+    };
+    let device = &device;
+
+    if let Some(response) = check_aca(session, &cmd, &unit) {
+        return Ok(vec![status_response(session, cmd.itt, &response)]);
+    }
+
+    if let Some(response) = check_reservation(session, &cmd.cdb, &unit) {
+        return Ok(vec![status_response(session, cmd.itt, &response)]);
+    }
+
+    // A failure from here on puts the unit into ACA
+    if aca::naca(&cmd.cdb) && device.read().is_ok_and(|device| device.norm_aca()) {
+        session.naca_tasks.insert(cmd.itt, lun);
+    }
+
+    // Commands with both Data-Out and Data-In are run by the backend
+    if cmd.read && cmd.write {
+        return handle_bidirectional_command(session, pdu, &cmd, lun, device);
     }
 
     // Check command type
     let opcode = cmd.cdb[0];
     log::debug!("Processing SCSI opcode 0x{:02x}", opcode);
     let is_sync_cache = opcode == 0x35 || opcode == 0x91;
-    let is_write_cmd = matches!(opcode, 0x0a | 0x2a | 0x8a);
 
     // Handle WRITE commands separately (they use immediate data or Data-Out PDUs)
-    if is_write_cmd {
-        // Extract LBA and transfer length from CDB
-        let (lba, transfer_length) = match opcode {
-            0x0a | 0x2a => {
-                // WRITE(6) or WRITE(10)
-                if opcode == 0x0a && cmd.cdb.len() >= 6 {
-                    // WRITE(6): LBA is 21 bits in bytes 1-3
-                    let lba_21 = ((cmd.cdb[1] as u32 & 0x1F) << 16)
-                               | ((cmd.cdb[2] as u32) << 8)
-                               | (cmd.cdb[3] as u32);
-                    let length = cmd.cdb[4] as u32;
-                    (lba_21 as u64, length)
-                } else if opcode == 0x2a && cmd.cdb.len() >= 10 {
-                    // WRITE(10): LBA is 32 bits in bytes 2-5
-                    let lba = BigEndian::read_u32(&cmd.cdb[2..6]) as u64;
-                    let length = BigEndian::read_u16(&cmd.cdb[7..9]) as u32;
-                    (lba, length)
-                } else {
-                    (0, 0)
-                }
+    if let Some(Cdb::Write(ParsedWrite { lba, blocks: transfer_length, fua, .. })) = Cdb::decode(&cmd.cdb) {
+        // Reject writes to a stopped unit, past the end of the medium or
+        // with protection the unit is not formatted for before any immediate
+        // data reaches the device or an R2T is issued
+        let range_check = call_device(session, {
+            let (device, cdb) = (Arc::clone(device), cmd.cdb.clone());
+            move || -> ScsiResult<_> {
+                let device_guard = device.read().map_err(|_| {
+                    IscsiError::scsi("Device lock poisoned".to_string())
+                })?;
+                let protection = ScsiHandler::check_medium_access(&cdb, &*device_guard)
+                    .and_then(|()| PiTransfer::from_cdb(&cdb, lba, device_guard.protection_type()));
+                Ok((protection, device_guard.block_size()))
             }
-            0x8a => {
-                // WRITE(16): LBA is 64 bits in bytes 2-9
-                if cmd.cdb.len() >= 16 {
-                    let lba = BigEndian::read_u64(&cmd.cdb[2..10]);
-                    let length = BigEndian::read_u32(&cmd.cdb[10..14]);
-                    (lba, length)
-                } else {
-                    (0, 0)
-                }
+        });
+        let (range_check, block_size) = match range_check {
+            Ok(checked) => checked?,
+            Err(deadline) => return Ok(vec![device_timed_out(session, deadline, cmd.itt, lun, opcode)]),
+        };
+        let protection = match range_check {
+            Ok(protection) => protection,
+            Err(sense) => {
+                log::warn!(
+                    "WRITE rejected: ITT=0x{:08x}, LBA={}, blocks={}",
+                    cmd.itt, lba, transfer_length
+                );
+                return Ok(vec![status_response(session, cmd.itt, &ScsiResponse::check_condition(sense))]);
             }
-            _ => (0, 0),
         };
 
         if transfer_length > 0 {
-            let device_guard = device.lock().map_err(|_| {
-                IscsiError::Scsi("Device lock poisoned".to_string())
-            })?;
-            let block_size = device_guard.block_size();
-            drop(device_guard);
-
-            let expected_data_len = transfer_length as usize * block_size as usize;
+            let transfer_block_size = protection.map_or(block_size, |p| p.transfer_block_size(block_size));
+            let expected_data_len = transfer_length as usize * transfer_block_size as usize;
             let bytes_received = pdu.data.len() as u32;
+            let complete = bytes_received as usize == expected_data_len;
 
-            // Write immediate data if present
-            if !pdu.data.is_empty() {
+            // Write immediate data if present; a protected WRITE is written
+            // once all of its data has been gathered
+            let immediate = (!pdu.data.is_empty() && (protection.is_none() || complete)).then(|| {
                 log::debug!(
                     "WRITE command with immediate data: ITT=0x{:08x}, LBA={}, {} bytes (expected {})",
                     cmd.itt, lba, pdu.data.len(), expected_data_len
                 );
-
-                let mut device_guard = device.lock().map_err(|_| {
-                    IscsiError::Scsi("Device lock poisoned".to_string())
-                })?;
-
-                let write_result = device_guard.write(lba, &pdu.data, block_size);
-                drop(device_guard);
-
-                if let Err(e) = write_result {
-                    log::error!("Write failed: {}", e);
-                    let sense = crate::scsi::SenseData::medium_error();
-                    return Ok(vec![IscsiPdu::scsi_response(
-                        cmd.itt,
-                        session.next_stat_sn(),
-                        session.exp_cmd_sn,
-                        session.max_cmd_sn,
-                        pdu::scsi_status::CHECK_CONDITION,
-                        0,
-                        0,
-                        Some(&sense.to_bytes()),
-                    )]);
+                pdu.data.clone()
+            });
+            if immediate.is_some() || (complete && fua) {
+                let device = Arc::clone(device);
+                let written = call_device(session, move || -> ScsiResult<_> {
+                    if let Some(data) = immediate {
+                        let mut device_guard = device.write().map_err(|_| {
+                            IscsiError::scsi("Device lock poisoned".to_string())
+                        })?;
+                        let write_result =
+                            ScsiHandler::write_blocks(&mut *device_guard, lba, &data, block_size, protection.as_ref());
+                        drop(device_guard);
+                        if write_result.is_err() {
+                            return Ok(write_result);
+                        }
+                    }
+                    Ok(force_unit_access(fua && complete, &device))
+                });
+                match written {
+                    Ok(written) => {
+                        if let Err(response) = written? {
+                            log::error!("Write failed: ITT=0x{:08x}, status=0x{:02x}", cmd.itt, response.status);
+                            return Ok(vec![status_response(session, cmd.itt, &response)]);
+                        }
+                    }
+                    Err(deadline) => return Ok(vec![device_timed_out(session, deadline, cmd.itt, lun, opcode)]),
                 }
             }
 
             // If all data has been received, send success response
-            if bytes_received as usize == expected_data_len {
+            if complete {
                 log::debug!(
                     "Write complete: ITT=0x{:08x}, {} bytes written",
                     cmd.itt, bytes_received
@@ -586,67 +1995,36 @@ fn handle_scsi_command<D: ScsiBlockDevice>(
                 )]);
             }
 
-            // Need more data - generate TTT and store pending write
-            let ttt = session.next_target_transfer_tag();
-            let remaining_bytes = expected_data_len as u32 - bytes_received;
+            // Need more data - store pending write
+            let unsolicited_end = session.unsolicited_data_end(cmd.final_flag, bytes_received, expected_data_len as u32);
+            let remaining_bytes = expected_data_len as u32 - unsolicited_end;
 
             log::debug!(
-                "WRITE needs R2T: ITT=0x{:08x}, TTT=0x{:08x}, received={}, remaining={}, total={}",
-                cmd.itt, ttt, bytes_received, remaining_bytes, expected_data_len
+                "WRITE needs more data: ITT=0x{:08x}, received={}, unsolicited up to {}, R2T for {}, total={}",
+                cmd.itt, bytes_received, unsolicited_end, remaining_bytes, expected_data_len
             );
 
-            // Store pending write
             session.pending_writes.insert(cmd.itt, PendingWrite {
                 lba,
                 transfer_length,
                 block_size,
                 bytes_received,
-                ttt,
-                r2t_sn: 0,
-                lun: cmd.lun,
+                lun,
+                lun_field: cmd.lun,
+                unsolicited_end,
+                unsolicited_offset: bytes_received,
+                next_r2t_offset: unsolicited_end,
+                last_activity: Some(Instant::now()),
+                protection,
+                protected_data: if protection.is_some() { pdu.data.clone() } else { Vec::new() },
+                lun_changes: luns.changes(),
+                fua,
+                opcode,
+                ..PendingWrite::default()
             });
 
-            // Send R2T to request the remaining data
-            // RFC 3720: R2T requests data starting at buffer_offset (bytes already received)
-            // with desired_data_transfer_length being the remaining bytes needed
-            // We may need to send multiple R2Ts if remaining data > MaxBurstLength
-            let max_burst = session.params.max_burst_length;
-            let mut responses = Vec::new();
-            let mut offset = bytes_received;
-            let mut r2t_sn = 0u32;
-
-            while offset < expected_data_len as u32 {
-                let remaining = expected_data_len as u32 - offset;
-                let request_len = remaining.min(max_burst);
-
-                log::debug!(
-                    "Sending R2T: ITT=0x{:08x}, TTT=0x{:08x}, R2TSN={}, offset={}, len={}",
-                    cmd.itt, ttt, r2t_sn, offset, request_len
-                );
-
-                let r2t = IscsiPdu::r2t(
-                    cmd.lun,
-                    cmd.itt,
-                    ttt,
-                    session.stat_sn, // StatSN is not incremented for R2T
-                    session.exp_cmd_sn,
-                    session.max_cmd_sn,
-                    r2t_sn,
-                    offset,
-                    request_len,
-                );
-                responses.push(r2t);
-
-                offset += request_len;
-                r2t_sn += 1;
-            }
-
-            // Update pending write with next R2T sequence number
-            if let Some(pending) = session.pending_writes.get_mut(&cmd.itt) {
-                pending.r2t_sn = r2t_sn;
-            }
-
-            return Ok(responses);
+            // Send R2T(s) for whatever the initiator will not send unsolicited
+            return Ok(issue_r2ts(session, cmd.itt));
         }
 
         // For write commands with no transfer, send immediate success
@@ -662,6 +2040,44 @@ fn handle_scsi_command<D: ScsiBlockDevice>(
         )]);
     }
 
+    // MODE SELECT parameter lists and VERIFY compare data that did not fit in
+    // immediate data are fetched with R2T and applied from handle_scsi_data_out()
+    let data_out_length = {
+        let device_guard = device.read().map_err(|_| {
+            IscsiError::scsi("Device lock poisoned".to_string())
+        })?;
+        ScsiHandler::data_out_length(&cmd.cdb, &*device_guard)
+    };
+    let data_out_length = match data_out_length {
+        Ok(length) => length,
+        Err(sense) => {
+            return Ok(vec![status_response(session, cmd.itt, &ScsiResponse::check_condition(sense))]);
+        }
+    };
+    if let Some(param_len) = data_out_length {
+        let received = pdu.data.len().min(param_len);
+        if received < param_len {
+            let mut data = pdu.data[..received].to_vec();
+            data.resize(param_len, 0);
+            let unsolicited_end = session.unsolicited_data_end(cmd.final_flag, received as u32, param_len as u32);
+            session.pending_parameter_lists.insert(cmd.itt, PendingParameterList {
+                cdb: cmd.cdb.to_vec(),
+                data,
+                bytes_received: received as u32,
+                lun,
+                lun_field: cmd.lun,
+                unsolicited_end,
+                unsolicited_data_sn: 0,
+                unsolicited_offset: received as u32,
+                next_r2t_offset: unsolicited_end,
+                r2t_sn: 0,
+                outstanding_r2ts: Vec::new(),
+                bidi_read_length: None,
+            });
+            return Ok(issue_r2ts(session, cmd.itt));
+        }
+    }
+
     // Handle non-write commands (reads, inquiries, etc.)
     let response = if opcode == 0x03 {
         // REQUEST SENSE (0x03) - return stored sense data instead of calling handler
@@ -671,21 +2087,18 @@ fn handle_scsi_command<D: ScsiBlockDevice>(
         } else {
             let alloc_len = cmd.cdb[4] as usize;
 
-            // Return the stored sense data, or NO_SENSE if none is stored
+            // Return the stored sense data, or the power condition if none is stored
             let mut data = match &session.last_sense_data {
                 Some(sense_bytes) => {
                     log::info!("Returning stored sense data: {:02x?}", sense_bytes);
                     sense_bytes.clone()
                 }
                 None => {
-                    log::warn!("No stored sense data - returning NO_SENSE");
-                    // No stored sense data - return NO_SENSE
-                    let sense = crate::scsi::SenseData::new(
-                        crate::scsi::sense_key::NO_SENSE,
-                        crate::scsi::asc::NO_ADDITIONAL_SENSE,
-                        0,
-                    );
-                    sense.to_bytes()
+                    // No stored sense data - report the power condition
+                    let device_guard = device.read().map_err(|_| {
+                        IscsiError::scsi("Device lock poisoned".to_string())
+                    })?;
+                    ScsiHandler::power_condition_sense(&*device_guard).to_bytes()
                 }
             };
 
@@ -693,195 +2106,670 @@ fn handle_scsi_command<D: ScsiBlockDevice>(
             ScsiResponse::good(data)
         }
     } else if is_sync_cache {
-        // SYNCHRONIZE CACHE needs mutable access to call flush()
-        let mut device_guard = device.lock().map_err(|_| {
-            IscsiError::Scsi("Device lock poisoned".to_string())
-        })?;
-
-        log::debug!("Calling flush() for SYNCHRONIZE CACHE command");
-        device_guard.flush()?;
-
-        ScsiResponse::good_no_data()
+        let (device, cdb) = (Arc::clone(device), cmd.cdb.clone());
+        match call_device(session, move || execute_command(&cdb, &device)) {
+            Ok(response) => response?,
+            Err(deadline) => return Ok(vec![device_timed_out(session, deadline, cmd.itt, lun, opcode)]),
+        }
+    } else if data_out_length.is_some() {
+        execute_with_data_out(&cmd.cdb, &pdu.data, device)?
     } else {
-        // Other commands use immutable access
-        let device_guard = device.lock().map_err(|_| {
-            IscsiError::Scsi("Device lock poisoned".to_string())
-        })?;
+        execute_command(&cmd.cdb, device)?
+    };
 
-        let resp = ScsiHandler::handle_command(&cmd.cdb, &*device_guard, None)?;
+    Ok(command_response(session, cmd.itt, cmd.read, cmd.expected_data_length, &response))
+}
 
-        if !resp.data.is_empty() {
-            log::debug!("SCSI command returned {} bytes, first 16: {:02x?}",
-                        resp.data.len(), &resp.data[..resp.data.len().min(16)]);
+/// Decode a command's LUN field and look up the device it addresses
+///
+/// The LUN number is what the rest of the command's handling keys on. A
+/// field no LUN of this target can be decoded from, or a LUN the target
+/// does not export, fails with LOGICAL UNIT NOT SUPPORTED.
+/// The first command after a change to the LUN table gets REPORTED LUNS DATA
+/// HAS CHANGED instead, and the first to a LUN whose backing store changed
+/// gets that change's unit attention; INQUIRY and REQUEST SENSE leave them
+/// pending.
+fn resolve_lun<D: ScsiBlockDevice>(
+    session: &mut IscsiSession,
+    cmd: &ScsiCommandPdu,
+    luns: &SessionLuns<D>,
+) -> Result<(u64, SharedDevice<D>, Arc<UnitState>), ScsiResponse> {
+    let Some(lun) = lun::decode_lun(cmd.lun) else {
+        log::warn!("Command 0x{:02x} to undecodable LUN field: 0x{:016x}", cmd.cdb[0], cmd.lun);
+        return Err(ScsiResponse::check_condition(SenseData::logical_unit_not_supported()));
+    };
+    let (device, unit) = match luns.open(lun).map(|device| device.zip(luns.unit(lun))) {
+        Ok(Some(resolved)) => resolved,
+        Ok(None) => {
+            log::warn!("Command 0x{:02x} to invalid LUN: 0x{:016x}", cmd.cdb[0], cmd.lun);
+            return Err(ScsiResponse::check_condition(SenseData::logical_unit_not_supported()));
+        }
+        Err(e) => {
+            log::error!("Cannot open LUN 0x{:016x}: {}", cmd.lun, e);
+            return Err(ScsiResponse::from_error(&e));
         }
-
-        resp
     };
 
-    // Build response PDU(s)
-    let mut responses = Vec::new();
+    if matches!(cmd.cdb[0], 0x03 | 0x12) {
+        return Ok((lun, device, unit));
+    }
+    let generation = luns.generation();
+    if session.lun_generation != generation {
+        session.lun_generation = generation;
+        return Err(ScsiResponse::check_condition(SenseData::reported_luns_data_changed()));
+    }
+    // Keyed by number, so every address of a LUN shares what was reported
+    let seen = session.lun_changes_seen.get(&lun).copied().unwrap_or(session.lun_changes_baseline);
+    if let Some((notice, kind)) = luns.changed_since(lun, seen) {
+        session.lun_changes_seen.insert(lun, notice);
+        return Err(ScsiResponse::check_condition(kind.sense()));
+    }
+    Ok((lun, device, unit))
+}
 
-    if cmd.read && !response.data.is_empty() {
-        // Send data with Data-In PDU(s)
-        let max_data_seg = session.params.max_xmit_data_segment_length as usize;
-        let mut offset = 0u32;
-        let mut data_sn = 0u32;
+/// Execute a command that needs no Data-Out against the device
+///
+/// Safe to run on a worker thread: it touches no session state.
+fn execute_command<D: ScsiBlockDevice>(cdb: &[u8], device: &RwLock<CountingDevice<D>>) -> ScsiResult<ScsiResponse> {
+    let opcode = cdb.first().copied().unwrap_or(0);
 
-        log::debug!("Large read: total_data={} bytes, max_data_seg={} bytes, will send {} PDUs",
-                    response.data.len(), max_data_seg, (response.data.len() + max_data_seg - 1) / max_data_seg);
+    if opcode == 0x35 || opcode == 0x91 {
+        // SYNCHRONIZE CACHE needs mutable access to call flush()
+        let mut device_guard = device.write().map_err(|_| {
+            IscsiError::scsi("Device lock poisoned".to_string())
+        })?;
 
-        while offset < response.data.len() as u32 {
-            let remaining = response.data.len() - offset as usize;
-            let chunk_size = remaining.min(max_data_seg);
-            let is_final = offset as usize + chunk_size >= response.data.len();
+        log::debug!("Calling flush() for SYNCHRONIZE CACHE command");
+        if let Err(e) = device_guard.flush() {
+            log::error!("SYNCHRONIZE CACHE failed: {}", e);
+            return Ok(ScsiResponse::from_error(&e));
+        }
 
-            let chunk = response.data[offset as usize..offset as usize + chunk_size].to_vec();
+        return Ok(ScsiResponse::good_no_data());
+    }
 
-            log::debug!("Sending Data-In PDU: offset={}, chunk_size={}, is_final={}, data_sn={}, first 16 bytes: {:02x?}",
-                        offset, chunk_size, is_final, data_sn, &chunk[..chunk.len().min(16)]);
+    if opcode == 0x1B {
+        // START STOP UNIT changes the power condition, so needs mutable access
+        let mut device_guard = device.write().map_err(|_| {
+            IscsiError::scsi("Device lock poisoned".to_string())
+        })?;
+        return ScsiHandler::handle_start_stop_unit(cdb, &mut *device_guard);
+    }
 
-            // StatSN should only be incremented for the final PDU (with F and S bits set)
-            // For non-final PDUs, StatSN is reserved and set to 0
-            let pdu_stat_sn = if is_final { session.next_stat_sn() } else { 0 };
+    if opcode == 0x4D {
+        // LOG SENSE reports the counters kept by the device wrapper
+        let device_guard = device.read().map_err(|_| {
+            IscsiError::scsi("Device lock poisoned".to_string())
+        })?;
+        return ScsiHandler::handle_log_sense(cdb, &device_guard.stats().snapshot());
+    }
 
-            let data_in = IscsiPdu::scsi_data_in(
-                cmd.itt,
-                0xFFFF_FFFF, // TTT
-                pdu_stat_sn,
-                session.exp_cmd_sn,
-                session.max_cmd_sn,
-                data_sn,
-                offset,
-                chunk,
-                is_final,
-                if is_final { Some(response.status) } else { None },
-            );
+    // Other commands use immutable access
+    let device_guard = device.read().map_err(|_| {
+        IscsiError::scsi("Device lock poisoned".to_string())
+    })?;
 
-            responses.push(data_in);
-            offset += chunk_size as u32;
-            data_sn += 1;
-        }
-    } else {
-        // No data or write command - send SCSI Response
-        let sense_data = response.sense.as_ref().map(|s| s.to_bytes());
-
-        if response.status == pdu::scsi_status::CHECK_CONDITION {
-            if let Some(ref sd) = response.sense {
-                let sense_bytes = sd.to_bytes();
-                log::info!(
-                    "Sending CHECK CONDITION with sense data: sense_key=0x{:02x}, asc=0x{:02x}, ascq=0x{:02x}",
-                    sd.sense_key, sd.asc, sd.ascq
-                );
-                log::debug!("Sense data bytes: {:02x?}", sense_bytes);
-                // Store the FULL sense data (including response code) for REQUEST SENSE
-                session.last_sense_data = Some(sense_bytes);
-            } else {
-                log::warn!("CHECK CONDITION status but no sense data available!");
-            }
-        } else {
-            // Clear sense data when status is GOOD
-            session.last_sense_data = None;
-        }
+    let mut resp = ScsiHandler::handle_command(cdb, &*device_guard, None)?;
+    // Standard INQUIRY data reports ACA support in NormACA (byte 3, bit 5)
+    if opcode == 0x12 && cdb.get(1).is_some_and(|evpd| evpd & 0x01 == 0) && device_guard.norm_aca() && resp.data.len() > 3 {
+        resp.data[3] |= 0x20;
+    }
 
-        // RFC 3720: Response field indicates whether the target successfully processed the command
-        // Use 0x00 (Command Completed at Target) for all SCSI status values
-        // libiscsi should parse sense data from the data segment for CHECK_CONDITION
-        let response_code = 0; // Command Completed at Target
+    if !resp.data.is_empty() {
+        log::debug!("SCSI command returned {} bytes, first 16: {:02x?}",
+                    resp.data.len(), &resp.data[..resp.data.len().min(16)]);
+    }
 
-        // Include sense data in the response PDU per RFC 3720 Section 10.4.7.
-        // We also store it for REQUEST SENSE retrieval, as libiscsi will call REQUEST SENSE
-        // to retrieve the actual sense data from the task structure.
-        let pdu_sense_data = sense_data.as_deref();
+    Ok(resp)
+}
 
-        let scsi_resp = IscsiPdu::scsi_response(
-            cmd.itt,
-            session.next_stat_sn(),
-            session.exp_cmd_sn,
-            session.max_cmd_sn,
-            response.status,
-            response_code,
-            0, // residual count
-            pdu_sense_data,
-        );
-        responses.push(scsi_resp);
+/// Apply the LUN's SCSI-2 reservation to a command from this session
+///
+/// RESERVE and RELEASE are executed here; other commands get RESERVATION
+/// CONFLICT while another I_T nexus holds the unit. None lets the command run.
+fn check_reservation(session: &IscsiSession, cdb: &[u8], unit: &UnitState) -> Option<ScsiResponse> {
+    let reservation = &unit.reservation;
+    let nexus = reservation::nexus(session);
+    if reservation::is_reservation_command(cdb[0]) {
+        return Some(reservation.execute(&nexus, cdb));
     }
+    if reservation.conflicts(&nexus, cdb) {
+        log::info!("Reservation conflict: opcode 0x{:02x} from {}", cdb[0], nexus);
+        return Some(reservation::conflict());
+    }
+    None
+}
 
-    Ok(responses)
+/// Apply the LUN's ACA condition to a command from this session
+///
+/// While the unit is in ACA, commands get ACA ACTIVE unless they are ACA
+/// tasks from the faulting I_T nexus. None lets the command run.
+fn check_aca(session: &IscsiSession, cmd: &ScsiCommandPdu, unit: &UnitState) -> Option<ScsiResponse> {
+    let aca = &unit.aca;
+    let nexus = reservation::nexus(session);
+    if aca.blocks(&nexus, cmd.task_attribute) {
+        log::info!("ACA active: opcode 0x{:02x} from {} refused", cmd.cdb[0], nexus);
+        return Some(aca::aca_active());
+    }
+    if cmd.task_attribute == pdu::task_attribute::ACA && aca.holder().is_none() {
+        return Some(aca_not_established());
+    }
+    None
 }
 
-/// Handle SCSI Data-Out PDU (write data from initiator)
-fn handle_scsi_data_out<D: ScsiBlockDevice>(
+/// Put a LUN into ACA when a command sent with NACA set ends in CHECK
+/// CONDITION among `responses`
+fn track_naca_tasks<D: ScsiBlockDevice>(
     session: &mut IscsiSession,
-    pdu: &IscsiPdu,
-    device: &Arc<Mutex<D>>,
-) -> ScsiResult<Vec<IscsiPdu>> {
-    let data_out = pdu.parse_scsi_data_out()?;
+    luns: &SessionLuns<D>,
+    responses: Vec<IscsiPdu>,
+) -> Vec<IscsiPdu> {
+    for response in &responses {
+        let status = match response.opcode {
+            opcode::SCSI_RESPONSE => response.specific[1],
+            opcode::SCSI_DATA_IN if response.flags & flags::STATUS != 0 => response.version_or_reserved as u8,
+            _ => continue,
+        };
+        let Some(lun) = session.naca_tasks.remove(&response.itt) else { continue };
+        if status != scsi_status::CHECK_CONDITION {
+            continue;
+        }
+        if let Some(unit) = luns.unit(lun) {
+            unit.aca.establish(&reservation::nexus(session));
+        }
+    }
+    responses
+}
+
+/// Response to an ACA task while its LUN is not in ACA
+///
+/// SAM answers an ACA task outside an ACA condition with INVALID MESSAGE
+/// ERROR; without `IscsiTargetBuilder::aca` no condition is ever established.
+fn aca_not_established() -> ScsiResponse {
+    ScsiResponse::check_condition(crate::scsi::SenseData::new(
+        crate::scsi::sense_key::ILLEGAL_REQUEST,
+        crate::scsi::asc::INVALID_MESSAGE_ERROR,
+        0,
+    ))
+}
+
+/// Build the Data-In and/or SCSI Response PDUs completing a command
+///
+/// Read data is capped at the initiator's ExpectedDataTransferLength, and the
+/// final PDU reports any underflow or overflow against it.
+fn command_response(
+    session: &mut IscsiSession,
+    itt: u32,
+    read: bool,
+    expected_length: u32,
+    response: &ScsiResponse,
+) -> Vec<IscsiPdu> {
+    // Without the READ flag the initiator expects no Data-In, so nothing it
+    // asked for is left untransferred
+    let produced = if read { response.data.len() as u32 } else { expected_length };
+    let data = &response.data[..response.data.len().min(expected_length as usize)];
+
+    if read && !data.is_empty() {
+        let mut responses = data_in_pdus(session, itt, data, Some(response.status));
+        if let Some(last) = responses.last_mut() {
+            last.set_residual(expected_length, produced);
+        }
+        responses
+    } else {
+        // No data or write command - send SCSI Response
+        let mut pdu = status_response(session, itt, response);
+        pdu.set_residual(expected_length, produced);
+        vec![pdu]
+    }
+}
+
+/// Split read data into Data-In PDUs, the last carrying `status` if given
+///
+/// Each sequence holds at most MaxBurstLength bytes and ends with the F bit.
+/// DataSN runs on across sequences: unlike Data-Out, Data-In numbers PDUs
+/// within the whole command (RFC 7143 11.7.5).
+fn data_in_pdus(session: &mut IscsiSession, itt: u32, data: &[u8], status: Option<u8>) -> Vec<IscsiPdu> {
+    let mut responses = Vec::new();
+    let max_data_seg = session.params.max_xmit_data_segment_length as usize;
+    let max_burst = (session.params.max_burst_length as usize).max(1);
+    let mut offset = 0u32;
+    let mut data_sn = 0u32;
+
+    log::debug!("Large read: total_data={} bytes, max_data_seg={} bytes, max_burst={} bytes, {} sequence(s)",
+                data.len(), max_data_seg, max_burst, data.len().div_ceil(max_burst));
+
+    while offset < data.len() as u32 {
+        let remaining = data.len() - offset as usize;
+        let burst_remaining = max_burst - offset as usize % max_burst;
+        let chunk_size = remaining.min(max_data_seg).min(burst_remaining);
+        let is_last = offset as usize + chunk_size >= data.len();
+        let is_final = is_last || chunk_size == burst_remaining;
+
+        let chunk = data[offset as usize..offset as usize + chunk_size].to_vec();
+
+        log::debug!("Sending Data-In PDU: offset={}, chunk_size={}, is_final={}, data_sn={}",
+                    offset, chunk_size, is_final, data_sn);
+
+        // StatSN is only taken by the PDU carrying status (F and S bits set);
+        // in the others it is reserved and set to 0
+        let status = if is_last { status } else { None };
+        let pdu_stat_sn = if status.is_some() { session.next_stat_sn() } else { 0 };
+
+        responses.push(IscsiPdu::scsi_data_in(
+            itt,
+            0xFFFF_FFFF, // TTT
+            pdu_stat_sn,
+            session.exp_cmd_sn,
+            session.max_cmd_sn,
+            data_sn,
+            offset,
+            chunk,
+            is_final,
+            status,
+        ));
+        offset += chunk_size as u32;
+        data_sn += 1;
+    }
+
+    responses
+}
+
+/// Start a bidirectional command, gathering its Data-Out with R2T if needed
+///
+/// Without an Expected Bidirectional Read Data Length AHS the initiator
+/// expects no Data-In.
+fn handle_bidirectional_command<D: ScsiBlockDevice>(
+    session: &mut IscsiSession,
+    pdu: &IscsiPdu,
+    cmd: &ScsiCommandPdu,
+    lun: u64,
+    device: &Arc<RwLock<CountingDevice<D>>>,
+) -> ScsiResult<Vec<IscsiPdu>> {
+    let read_length = cmd.bidi_read_data_length.unwrap_or(0);
+    let length = cmd.expected_data_length as usize;
+    let received = pdu.data.len().min(length);
+    if received < length {
+        let mut data = pdu.data[..received].to_vec();
+        data.resize(length, 0);
+        let unsolicited_end = session.unsolicited_data_end(cmd.final_flag, received as u32, length as u32);
+        session.pending_parameter_lists.insert(cmd.itt, PendingParameterList {
+            cdb: cmd.cdb.to_vec(),
+            data,
+            bytes_received: received as u32,
+            lun,
+            lun_field: cmd.lun,
+            unsolicited_end,
+            unsolicited_data_sn: 0,
+            unsolicited_offset: received as u32,
+            next_r2t_offset: unsolicited_end,
+            r2t_sn: 0,
+            outstanding_r2ts: Vec::new(),
+            bidi_read_length: Some(read_length),
+        });
+        return Ok(issue_r2ts(session, cmd.itt));
+    }
+    execute_bidirectional(session, cmd.itt, &cmd.cdb, &pdu.data[..length], read_length, device)
+}
+
+/// Run a bidirectional command on the backend once all its Data-Out is in
+///
+/// Its Data-In carries no status: a SCSI Response follows, reporting the
+/// bidirectional read residual against `read_length`.
+fn execute_bidirectional<D: ScsiBlockDevice>(
+    session: &mut IscsiSession,
+    itt: u32,
+    cdb: &[u8],
+    data_out: &[u8],
+    read_length: u32,
+    device: &Arc<RwLock<CountingDevice<D>>>,
+) -> ScsiResult<Vec<IscsiPdu>> {
+    let result = device.write().map_err(|_| {
+        IscsiError::scsi("Device lock poisoned".to_string())
+    })?.bidirectional(cdb, data_out);
+    let data = match result {
+        Ok(data) => data,
+        Err(e) => {
+            log::warn!("Bidirectional command 0x{:02x} failed: ITT=0x{:08x}: {}", cdb[0], itt, e);
+            return Ok(vec![status_response(session, itt, &ScsiResponse::from_error(&e))]);
+        }
+    };
+
+    let mut responses = data_in_pdus(session, itt, &data[..data.len().min(read_length as usize)], None);
+    let mut response = status_response(session, itt, &ScsiResponse::good_no_data());
+    response.set_bidi_read_residual(read_length, data.len() as u32);
+    responses.push(response);
+    Ok(responses)
+}
+
+/// Issue R2Ts for a pending WRITE or parameter list, up to
+/// MaxOutstandingR2T open sequences
+///
+/// Each R2T gets its own TTT so its Data-Out sequence (and DataSN) can be
+/// tracked independently. Further R2Ts are issued as sequences complete.
+fn issue_r2ts(session: &mut IscsiSession, itt: u32) -> Vec<IscsiPdu> {
+    let max_outstanding = session.params.max_outstanding_r2t.max(1) as usize;
+    let max_burst = session.params.max_burst_length;
+    let mut responses = Vec::new();
+
+    loop {
+        let (outstanding, next_offset, total, lun) = if let Some(pending) = session.pending_writes.get(&itt) {
+            (pending.outstanding_r2ts.len(), pending.next_r2t_offset, pending.total_bytes(), pending.lun_field)
+        } else if let Some(pending) = session.pending_parameter_lists.get(&itt) {
+            (pending.outstanding_r2ts.len(), pending.next_r2t_offset, pending.data.len() as u32, pending.lun_field)
+        } else {
+            break;
+        };
+        if outstanding >= max_outstanding || next_offset >= total {
+            break;
+        }
+        let offset = next_offset;
+        let length = (total - offset).min(max_burst);
+
+        let ttt = session.next_target_transfer_tag();
+        let (next_r2t_offset, next_r2t_sn, outstanding_r2ts) = match session.pending_writes.get_mut(&itt) {
+            Some(pending) => (&mut pending.next_r2t_offset, &mut pending.r2t_sn, &mut pending.outstanding_r2ts),
+            None => {
+                let pending = session.pending_parameter_lists.get_mut(&itt).expect("pending parameter list present");
+                (&mut pending.next_r2t_offset, &mut pending.r2t_sn, &mut pending.outstanding_r2ts)
+            }
+        };
+        let r2t_sn = *next_r2t_sn;
+        *next_r2t_sn += 1;
+        *next_r2t_offset += length;
+        outstanding_r2ts.push(OutstandingR2t {
+            ttt,
+            r2t_sn,
+            offset,
+            length,
+            next_data_sn: 0,
+            bytes_received: 0,
+        });
+
+        log::debug!(
+            "Sending R2T: ITT=0x{:08x}, TTT=0x{:08x}, R2TSN={}, offset={}, len={}",
+            itt, ttt, r2t_sn, offset, length
+        );
+        responses.push(IscsiPdu::r2t(
+            lun,
+            itt,
+            ttt,
+            session.stat_sn, // StatSN is not incremented for R2T
+            session.exp_cmd_sn,
+            session.max_cmd_sn,
+            r2t_sn,
+            offset,
+            length,
+        ));
+    }
+
+    responses
+}
+
+/// Retransmit the R2Ts of WRITEs whose Data-Out has stalled, and abort the
+/// ones that have run out of retransmissions
+fn expire_pending_writes<D: ScsiBlockDevice>(
+    session: &mut IscsiSession,
+    luns: &SessionLuns<D>,
+    retransmit: R2tRetransmit,
+    now: Instant,
+) -> Vec<IscsiPdu> {
+    let mut stalled: Vec<u32> = session.pending_writes.iter()
+        .filter(|(_, pending)| pending.last_activity.is_some_and(|last| now >= last + retransmit.timeout))
+        .map(|(itt, _)| *itt)
+        .collect();
+    stalled.sort_unstable();
+
+    let mut responses = Vec::new();
+    for itt in stalled {
+        let (stat_sn, exp_cmd_sn, max_cmd_sn) = (session.stat_sn, session.exp_cmd_sn, session.max_cmd_sn);
+        // R2T retransmission is recovery within a command (RFC 3720 Section 6.1.4.1)
+        let recovers = session.params.error_recovery_level >= 1;
+        let pending = session.pending_writes.get_mut(&itt).expect("stalled write present");
+
+        if recovers && pending.retransmits < retransmit.retries {
+            pending.restart_r2t_sequences(now);
+            log::warn!(
+                "No Data-Out for WRITE ITT=0x{:08x} within {:?}, retransmitting {} R2T(s) (attempt {}/{})",
+                itt, retransmit.timeout, pending.outstanding_r2ts.len(), pending.retransmits, retransmit.retries
+            );
+            for r2t in &pending.outstanding_r2ts {
+                responses.push(IscsiPdu::r2t(
+                    pending.lun_field,
+                    itt,
+                    r2t.ttt,
+                    stat_sn,
+                    exp_cmd_sn,
+                    max_cmd_sn,
+                    r2t.r2t_sn,
+                    r2t.offset,
+                    r2t.length,
+                ));
+            }
+            continue;
+        }
+
+        let pending = session.pending_writes.remove(&itt).expect("stalled write present");
+        abort_pending_write(session, luns, itt, pending, AbortReason::Timeout);
+        let sense = SenseData::initiator_response_timeout();
+        responses.push(status_response(session, itt, &ScsiResponse::check_condition(sense)));
+    }
+
+    responses
+}
+
+/// Build the SCSI Response PDU for a command that returns no Data-In
+///
+/// Sense data is stored on the session for a following REQUEST SENSE.
+fn status_response(session: &mut IscsiSession, itt: u32, response: &ScsiResponse) -> IscsiPdu {
+    let sense_data = response.sense.as_ref().map(|s| s.to_bytes());
+
+    if response.status == pdu::scsi_status::CHECK_CONDITION {
+        if let Some(ref sd) = response.sense {
+            let sense_bytes = sd.to_bytes();
+            log::info!(
+                "Sending CHECK CONDITION with sense data: sense_key=0x{:02x}, asc=0x{:02x}, ascq=0x{:02x}",
+                sd.sense_key, sd.asc, sd.ascq
+            );
+            log::debug!("Sense data bytes: {:02x?}", sense_bytes);
+            // Store the FULL sense data (including response code) for REQUEST SENSE
+            session.last_sense_data = Some(sense_bytes);
+        } else {
+            log::warn!("CHECK CONDITION status but no sense data available!");
+        }
+    } else {
+        // Clear sense data when status is GOOD
+        session.last_sense_data = None;
+    }
+
+    // RFC 3720: Response field indicates whether the target successfully processed the command
+    // Use 0x00 (Command Completed at Target) for all SCSI status values
+    // libiscsi should parse sense data from the data segment for CHECK_CONDITION
+    let response_code = 0; // Command Completed at Target
+
+    // Include sense data in the response PDU per RFC 3720 Section 10.4.7.
+    // We also store it for REQUEST SENSE retrieval, as libiscsi will call REQUEST SENSE
+    // to retrieve the actual sense data from the task structure.
+    IscsiPdu::scsi_response(
+        itt,
+        session.next_stat_sn(),
+        session.exp_cmd_sn,
+        session.max_cmd_sn,
+        response.status,
+        response_code,
+        0, // residual count
+        sense_data.as_deref(),
+    )
+}
+
+/// Run a command whose Data-Out buffer (MODE SELECT parameter list, VERIFY
+/// compare data) has been received in full
+fn execute_with_data_out<D: ScsiBlockDevice>(
+    cdb: &[u8],
+    data: &[u8],
+    device: &Arc<RwLock<CountingDevice<D>>>,
+) -> ScsiResult<ScsiResponse> {
+    if ScsiHandler::mode_select_parameter_length(cdb).is_some() {
+        // MODE SELECT may change device settings, so needs mutable access
+        let mut device_guard = device.write().map_err(|_| {
+            IscsiError::scsi("Device lock poisoned".to_string())
+        })?;
+        return ScsiHandler::handle_mode_select(cdb, data, &mut *device_guard);
+    }
+
+    let device_guard = device.read().map_err(|_| {
+        IscsiError::scsi("Device lock poisoned".to_string())
+    })?;
+    ScsiHandler::handle_command(cdb, &*device_guard, Some(data))
+}
+
+/// Handle Data-Out carrying a MODE SELECT parameter list or VERIFY compare data
+fn handle_parameter_list_data_out<D: ScsiBlockDevice>(
+    session: &mut IscsiSession,
+    pdu: &IscsiPdu,
+    data_out: &crate::pdu::ScsiDataOutPdu,
+    luns: &SessionLuns<D>,
+) -> ScsiResult<Vec<IscsiPdu>> {
+    let params = &session.params;
+    let Some(pending) = session.pending_parameter_lists.get_mut(&data_out.itt) else {
+        return Ok(vec![]);
+    };
+    let Some(device) = luns.get(pending.lun) else {
+        log::warn!("LUN of parameter list ITT=0x{:08x} was removed", data_out.itt);
+        session.pending_parameter_lists.remove(&data_out.itt);
+        let response = ScsiResponse::check_condition(SenseData::logical_unit_not_supported());
+        return Ok(vec![status_response(session, data_out.itt, &response)]);
+    };
+
+    let ended = match pending.accept_data_out(data_out, params) {
+        Ok(ended) => ended,
+        Err(e) => {
+            log::warn!("Data-Out protocol error for parameter list ITT=0x{:08x}: {}", data_out.itt, e);
+            return Ok(reject_data_out(session, pdu, data_out, luns));
+        }
+    };
+    // Each R2T sequence that ends makes room for the next R2T
+    if (pending.bytes_received as usize) < pending.data.len() {
+        if ended {
+            return Ok(issue_r2ts(session, data_out.itt));
+        }
+        return Ok(vec![]);
+    }
+
+    let pending = session.pending_parameter_lists.remove(&data_out.itt)
+        .expect("pending parameter list present");
+    if let Some(read_length) = pending.bidi_read_length {
+        return execute_bidirectional(session, data_out.itt, &pending.cdb, &pending.data, read_length, &device);
+    }
+    let response = execute_with_data_out(&pending.cdb, &pending.data, &device)?;
+
+    Ok(vec![status_response(session, data_out.itt, &response)])
+}
+
+/// Handle SCSI Data-Out PDU (write data from initiator)
+fn handle_scsi_data_out<D: ScsiBlockDevice + 'static>(
+    session: &mut IscsiSession,
+    pdu: &IscsiPdu,
+    luns: &SessionLuns<D>,
+) -> ScsiResult<Vec<IscsiPdu>> {
+    let mut data_out = pdu.parse_scsi_data_out()?;
 
     log::debug!(
         "SCSI Data-Out: ITT=0x{:08x}, TTT=0x{:08x}, DataSN={}, Offset={}, Len={}, Final={}",
         data_out.itt, data_out.ttt, data_out.data_sn, data_out.buffer_offset, data_out.data.len(), data_out.final_flag
     );
 
-    // Look up the pending write command
-    let pending_write = session.pending_writes.get_mut(&data_out.itt);
+    if session.pending_parameter_lists.contains_key(&data_out.itt) {
+        return handle_parameter_list_data_out(session, pdu, &data_out, luns);
+    }
 
-    if pending_write.is_none() {
+    // Look up the pending write command
+    let params = session.params.clone();
+    let Some(pending) = session.pending_writes.get_mut(&data_out.itt) else {
         log::warn!("Received Data-Out for unknown ITT=0x{:08x}", data_out.itt);
         return Ok(vec![]);
+    };
+    let Some(device) = luns.get(pending.lun) else {
+        let pending = session.pending_writes.remove(&data_out.itt).expect("pending write present");
+        abort_pending_write(session, luns, data_out.itt, pending, AbortReason::LunRemoved);
+        let response = ScsiResponse::check_condition(SenseData::logical_unit_not_supported());
+        return Ok(vec![status_response(session, data_out.itt, &response)]);
+    };
+    if let Some((notice, kind)) = luns.changed_since(pending.lun, pending.lun_changes).filter(|(_, kind)| kind.fails_in_flight()) {
+        let pending = session.pending_writes.remove(&data_out.itt).expect("pending write present");
+        session.lun_changes_seen.insert(pending.lun, notice);
+        abort_pending_write(session, luns, data_out.itt, pending, AbortReason::LunChanged);
+        let response = ScsiResponse::check_condition(kind.sense());
+        return Ok(vec![status_response(session, data_out.itt, &response)]);
     }
 
-    let pending = pending_write.unwrap();
+    let sequence_completed = match pending.accept_data_out(&data_out, &params) {
+        Ok(completed) => completed,
+        Err(e) => {
+            log::warn!("Data-Out protocol error for ITT=0x{:08x}: {}", data_out.itt, e);
+            return Ok(reject_data_out(session, pdu, &data_out, luns));
+        }
+    };
+
     let block_size = pending.block_size;
-    let transfer_length = pending.transfer_length;
     let base_lba = pending.lba;
-    let total_expected = transfer_length * block_size;
+    let total_expected = pending.total_bytes();
+    let protection = pending.protection;
+    let fua = pending.fua;
 
     // Calculate the LBA for this chunk based on buffer_offset
     // buffer_offset is the byte offset from the start of the transfer
-    let lba = base_lba + (data_out.buffer_offset as u64 / block_size as u64);
+    let lba = base_lba + (data_out.buffer_offset as u64 / pending.transfer_block_size() as u64);
 
     log::debug!(
-        "Writing Data-Out: ITT=0x{:08x}, buffer_offset={}, LBA={}, {} bytes (base_lba={})",
-        data_out.itt, data_out.buffer_offset, lba, data_out.data.len(), base_lba
+        "Writing Data-Out: ITT=0x{:08x}, buffer_offset={}, LBA={}, {} bytes (base_lba={}), {}/{} bytes received",
+        data_out.itt, data_out.buffer_offset, lba, data_out.data.len(), base_lba,
+        pending.bytes_received, total_expected
     );
+    let all_received = pending.bytes_received >= total_expected;
 
-    // Write the data
-    let mut device_guard = device.lock().map_err(|_| {
-        IscsiError::Scsi("Device lock poisoned".to_string())
-    })?;
-
-    let write_result = device_guard.write(lba, &data_out.data, block_size);
-    drop(device_guard);
-
-    // Update bytes received - track the highest offset written
-    // This handles out-of-order Data-Out PDUs correctly
-    let end_offset = data_out.buffer_offset + data_out.data.len() as u32;
-    if end_offset > pending.bytes_received {
-        pending.bytes_received = end_offset;
-    }
+    let write = if protection.is_some() {
+        pending.gather(data_out.buffer_offset, &data_out.data);
+        all_received.then(|| (base_lba, std::mem::take(&mut pending.protected_data)))
+    } else {
+        Some((lba, std::mem::take(&mut data_out.data)))
+    };
+    let (lun, opcode) = (pending.lun, pending.opcode);
 
-    log::debug!(
-        "Updated bytes received: {}/{} bytes",
-        pending.bytes_received,
-        total_expected
-    );
+    // Write the data
+    let written = call_device(session, move || -> ScsiResult<_> {
+        let write_result = match write {
+            Some((lba, data)) => {
+                let mut device_guard = device.write().map_err(|_| {
+                    IscsiError::scsi("Device lock poisoned".to_string())
+                })?;
+                ScsiHandler::write_blocks(&mut *device_guard, lba, &data, block_size, protection.as_ref())
+            }
+            None => Ok(()),
+        };
+        Ok(write_result.and_then(|()| force_unit_access(fua && all_received, &device)))
+    });
+    let write_result = match written {
+        Ok(write_result) => write_result?,
+        Err(deadline) => {
+            // The device still has the data; abort_write would wait behind it
+            session.pending_writes.remove(&data_out.itt);
+            return Ok(vec![device_timed_out(session, deadline, data_out.itt, lun, opcode)]);
+        }
+    };
 
     let (status, sense) = match write_result {
         Ok(()) => (scsi_status::GOOD, None),
-        Err(e) => {
-            log::error!("Write failed: {}", e);
-            let sense = crate::scsi::SenseData::medium_error();
-            (pdu::scsi_status::CHECK_CONDITION, Some(sense.to_bytes()))
+        Err(response) => {
+            log::error!("Write failed: ITT=0x{:08x}, status=0x{:02x}", data_out.itt, response.status);
+            (response.status, response.sense.map(|sense| sense.to_bytes()))
         }
     };
 
-    // Check if all data has been received
-    // The final flag indicates the last PDU for this R2T sequence
-    // We complete when all expected bytes are received
-    if pending.bytes_received >= total_expected {
+    // Complete when all expected bytes are received, or on error
+    if all_received || status != scsi_status::GOOD {
         log::debug!(
-            "Write complete: ITT=0x{:08x}, {} bytes total",
-            data_out.itt, pending.bytes_received
+            "Write finished: ITT=0x{:08x}, status=0x{:02x}",
+            data_out.itt, status
         );
 
         // Remove the pending write
@@ -899,78 +2787,188 @@ fn handle_scsi_data_out<D: ScsiBlockDevice>(
         );
 
         Ok(vec![response])
-    } else if status != scsi_status::GOOD {
-        // Error occurred - remove pending write and send error response
-        session.pending_writes.remove(&data_out.itt);
-
-        let response = IscsiPdu::scsi_response(
-            data_out.itt,
-            session.next_stat_sn(),
-            session.exp_cmd_sn,
-            session.max_cmd_sn,
-            status,
-            0,
-            0,
-            sense.as_deref(),
-        );
-
-        Ok(vec![response])
+    } else if sequence_completed {
+        // A Data-Out sequence finished - the next R2T may go out
+        Ok(issue_r2ts(session, data_out.itt))
     } else {
         // More data expected, no response yet
         Ok(vec![])
     }
 }
 
+/// Flush a finished WRITE with FUA set to stable storage before its status
+/// goes out
+fn force_unit_access<D: ScsiBlockDevice>(fua: bool, device: &RwLock<CountingDevice<D>>) -> Result<(), ScsiResponse> {
+    if !fua {
+        return Ok(());
+    }
+    let mut device_guard = device.write().unwrap_or_else(|e| e.into_inner());
+    device_guard.flush().map_err(|e| {
+        log::error!("FUA flush failed: {}", e);
+        ScsiResponse::from_error(&e)
+    })
+}
+
+/// Reject a Data-Out that violates the R2T/DataSN rules and terminate its task
+///
+/// The offending PDU is answered with a Reject (Protocol Error), and the task
+/// ends with CHECK CONDITION / ABORTED COMMAND. For a WRITE the device is
+/// told first through `abort_write`; a parameter list is dropped unapplied.
+fn reject_data_out<D: ScsiBlockDevice>(
+    session: &mut IscsiSession,
+    pdu: &IscsiPdu,
+    data_out: &crate::pdu::ScsiDataOutPdu,
+    luns: &SessionLuns<D>,
+) -> Vec<IscsiPdu> {
+    if let Some(pending) = session.pending_writes.remove(&data_out.itt) {
+        abort_pending_write(session, luns, data_out.itt, pending, AbortReason::ProtocolError);
+    }
+    session.pending_parameter_lists.remove(&data_out.itt);
+
+    let header = pdu.to_bytes();
+    let reject = IscsiPdu::reject(
+        pdu::reject_reason::PROTOCOL_ERROR,
+        session.next_stat_sn(),
+        session.exp_cmd_sn,
+        session.max_cmd_sn,
+        &header[..BHS_SIZE],
+    );
+
+    // RFC 3720 Section 10.4.7.2: unexpected unsolicited data (0x0C/0x0C)
+    // or incorrect amount of data (0x0C/0x0D)
+    let ascq = if data_out.ttt == 0xFFFF_FFFF { 0x0C } else { 0x0D };
+    let sense = crate::scsi::SenseData::new(
+        crate::scsi::sense_key::ABORTED_COMMAND,
+        crate::scsi::asc::WRITE_ERROR,
+        ascq,
+    );
+    vec![reject, status_response(session, data_out.itt, &ScsiResponse::check_condition(sense))]
+}
+
 /// Handle Text Request (e.g., SendTargets for discovery)
 fn handle_text_request(
     session: &mut IscsiSession,
     pdu: &IscsiPdu,
     target_name: &str,
-    target_address: &str,
+    target_portals: &[(String, u16)],
 ) -> ScsiResult<Vec<IscsiPdu>> {
     let text_req = pdu.parse_text_request()?;
 
     log::debug!("Text Request: ITT=0x{:08x}, params: {:?}", text_req.itt, text_req.parameters);
 
+    // A TTT other than 0xFFFFFFFF asks for the next part of a split response
+    if text_req.ttt != 0xFFFF_FFFF {
+        let pending = session.pending_text_response.take_if(|pending| {
+            pending.itt == text_req.itt && pending.ttt == text_req.ttt
+        });
+        let Some(pending) = pending else {
+            log::warn!("Text Request with unknown TTT 0x{:08x}", text_req.ttt);
+            let header = pdu.to_bytes();
+            return Ok(vec![IscsiPdu::reject(
+                pdu::reject_reason::INVALID_PDU_FIELD,
+                session.next_stat_sn(),
+                session.exp_cmd_sn,
+                session.max_cmd_sn,
+                &header[..BHS_SIZE],
+            )]);
+        };
+        return Ok(vec![text_response_segment(session, pending.itt, pending.remaining)]);
+    }
+    // A new request abandons any response still being continued
+    session.pending_text_response = None;
+
     // Check for SendTargets request (discovery)
-    let is_send_targets = text_req.parameters.iter()
-        .any(|(k, v)| k == "SendTargets" && (v == "All" || v.is_empty()));
+    let send_targets = text_req.parameters.iter()
+        .find(|(k, _)| k == "SendTargets")
+        .map(|(_, v)| v.as_str());
 
-    let response_params = if is_send_targets {
+    let echo_payload = text_req.parameters.iter()
+        .find(|(k, _)| k == crate::session::DIAGNOSTIC_ECHO_KEY)
+        .map(|(_, v)| v.as_str());
+
+    let mut response_params = if let Some(query) = send_targets {
         // Return target list for any SendTargets request
         // (RFC 3720: Discovery works even if SessionType isn't explicitly set)
-        session.handle_send_targets(target_name, target_address)
+        session.handle_send_targets(query, target_name, target_portals)
+    } else if let Some(payload) = echo_payload {
+        session.handle_diagnostic_echo(payload)
     } else {
-        // Echo back or handle other text parameters
         vec![]
     };
+    response_params.extend(session.answer_text_keys(&text_req.parameters));
 
     let response_data = serialize_text_parameters(&response_params);
+    Ok(vec![text_response_segment(session, text_req.itt, response_data)])
+}
+
+/// Build the next Text Response PDU from `data`
+///
+/// Text beyond the initiator's MaxRecvDataSegmentLength is kept in the
+/// session and the PDU is sent with the C bit and a TTT the initiator uses to
+/// ask for the rest (RFC 3720 Section 10.11.2). Splits fall after a complete
+/// key=value pair where one fits.
+fn text_response_segment(session: &mut IscsiSession, itt: u32, mut data: Vec<u8>) -> IscsiPdu {
+    let limit = session.params.max_xmit_data_segment_length as usize;
+    if data.len() <= limit {
+        return IscsiPdu::text_response(
+            itt,
+            0xFFFF_FFFF, // TTT
+            session.next_stat_sn(),
+            session.exp_cmd_sn,
+            session.max_cmd_sn,
+            true, // final
+            data,
+        );
+    }
 
-    let response = IscsiPdu::text_response(
-        text_req.itt,
-        0xFFFF_FFFF, // TTT
+    let split = data[..limit].iter()
+        .rposition(|&b| b == 0)
+        .map_or(limit, |nul| nul + 1);
+    let remaining = data.split_off(split);
+    let ttt = session.next_target_transfer_tag();
+    log::debug!("Text Response for ITT 0x{:08x} continues, {} bytes left", itt, remaining.len());
+    session.pending_text_response = Some(PendingTextResponse { itt, ttt, remaining });
+
+    let mut response = IscsiPdu::text_response(
+        itt,
+        ttt,
         session.next_stat_sn(),
         session.exp_cmd_sn,
         session.max_cmd_sn,
-        true, // final
-        response_data,
+        false,
+        data,
     );
-
-    Ok(vec![response])
+    response.flags |= flags::CONTINUE;
+    response
 }
 
 /// Handle Task Management Request
-fn handle_task_management(
+///
+/// CLEAR ACA and the resets end ACA conditions; beyond that, requests are
+/// acknowledged without effect.
+fn handle_task_management<D: ScsiBlockDevice>(
     session: &mut IscsiSession,
     pdu: &IscsiPdu,
+    luns: &SessionLuns<D>,
 ) -> ScsiResult<Vec<IscsiPdu>> {
-    // For now, just acknowledge task management requests
-    // A full implementation would handle ABORT TASK, LUN RESET, etc.
-
     let function = pdu.flags & 0x7F;
     log::debug!("Task Management: function={}", function);
 
+    let nexus = reservation::nexus(session);
+    let units = match function {
+        tmf_function::CLEAR_ACA | tmf_function::LOGICAL_UNIT_RESET => {
+            lun::decode_lun(pdu.lun).and_then(|lun| luns.unit(lun)).into_iter().collect()
+        }
+        tmf_function::TARGET_WARM_RESET | tmf_function::TARGET_COLD_RESET => luns.units(),
+        _ => Vec::new(),
+    };
+    for unit in units {
+        match function {
+            tmf_function::CLEAR_ACA => unit.aca.clear(&nexus),
+            _ => unit.aca.reset(),
+        }
+    }
+
     // Build response
     let mut response = IscsiPdu::new();
     response.opcode = opcode::TASK_MANAGEMENT_RESPONSE;
@@ -992,12 +2990,46 @@ fn handle_task_management(
 /// Builder for configuring an iSCSI target
 pub struct IscsiTargetBuilder<D: ScsiBlockDevice> {
     bind_addr: Option<String>,
+    extra_portals: Vec<Portal>,
+    advertise_addrs: Vec<String>,
     target_name: Option<String>,
     target_alias: Option<String>,
+    identity: Identity,
+    max_recv_data_segment_length: Option<u32>,
+    max_burst_length: Option<u32>,
+    first_burst_length: Option<u32>,
+    initial_r2t: Option<bool>,
+    immediate_data: Option<bool>,
+    data_sequence_in_order: Option<bool>,
+    max_outstanding_r2t: Option<u32>,
     auth_config: crate::auth::AuthConfig,
+    chap_accounts: crate::auth::ChapAccounts,
+    chap_provider: Option<Arc<dyn crate::auth::ChapSecretProvider>>,
+    mutual_chap_credentials: Option<crate::auth::ChapCredentials>,
+    allow_md5_chap: bool,
+    strictness: Strictness,
+    observer: Option<Arc<dyn TargetObserver>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    portal_preference: PortalPreference,
+    discovery_exclusions: Vec<String>,
     max_connections: Option<u32>,
     max_sessions: Option<u32>,
     allowed_initiators: Option<Vec<String>>,
+    allowed_networks: Vec<String>,
+    keepalive: Option<Keepalive>,
+    session_limits: SessionLimits,
+    send_queue: SendQueueLimits,
+    socket_options: SocketOptions,
+    r2t_retransmit: Option<R2tRetransmit>,
+    read_ahead: Option<ReadAhead>,
+    command_deadline: Option<CommandDeadline>,
+    queue_depth: Option<u32>,
+    command_window: Option<u32>,
+    worker_threads: Option<usize>,
+    pdu_limits: Option<PduLimits>,
+    trace_path: Option<PathBuf>,
+    pdu_logging: PduLogLevel,
+    interceptor: Option<Arc<dyn PduInterceptor>>,
     _phantom: std::marker::PhantomData<D>,
 }
 
@@ -1005,22 +3037,92 @@ impl<D: ScsiBlockDevice> IscsiTargetBuilder<D> {
     fn new() -> Self {
         Self {
             bind_addr: None,
+            extra_portals: Vec::new(),
+            advertise_addrs: Vec::new(),
             target_name: None,
             target_alias: None,
+            identity: Identity::default(),
+            max_recv_data_segment_length: None,
+            max_burst_length: None,
+            first_burst_length: None,
+            initial_r2t: None,
+            immediate_data: None,
+            data_sequence_in_order: None,
+            max_outstanding_r2t: None,
             auth_config: crate::auth::AuthConfig::None,
+            chap_accounts: crate::auth::ChapAccounts::new(),
+            chap_provider: None,
+            mutual_chap_credentials: None,
+            allow_md5_chap: true,
+            strictness: Strictness::Permissive,
+            observer: None,
+            audit: None,
+            portal_preference: PortalPreference::BindOrder,
+            discovery_exclusions: Vec::new(),
             max_connections: None,
             max_sessions: None,
             allowed_initiators: None,
+            allowed_networks: Vec::new(),
+            keepalive: None,
+            session_limits: SessionLimits::default(),
+            send_queue: SendQueueLimits::default(),
+            socket_options: SocketOptions::default(),
+            r2t_retransmit: None,
+            read_ahead: Some(ReadAhead::default()),
+            command_deadline: None,
+            queue_depth: None,
+            command_window: None,
+            worker_threads: None,
+            pdu_limits: None,
+            trace_path: None,
+            pdu_logging: PduLogLevel::None,
+            interceptor: None,
             _phantom: std::marker::PhantomData,
         }
     }
 
     /// Set the bind address (default: 0.0.0.0:3260)
+    ///
+    /// This is the primary portal, with TPGT 1. Use `add_portal()` to listen
+    /// on additional addresses.
     pub fn bind_addr(mut self, addr: &str) -> Self {
         self.bind_addr = Some(addr.to_string());
         self
     }
 
+    /// Advertise `addr` in SendTargets instead of the primary portal's address
+    ///
+    /// For NAT and dual-stack deployments where the bind address is not what
+    /// initiators should dial: a DNS name or IP literal, with an optional
+    /// port (default: the bind port). IPv6 literals may be bare or bracketed.
+    /// May be called repeatedly; use `Portal::with_advertise_addr` for other portals.
+    pub fn advertise_addr(mut self, addr: &str) -> Self {
+        self.advertise_addrs.push(addr.to_string());
+        self
+    }
+
+    /// Listen on an additional portal with the given Target Portal Group Tag
+    ///
+    /// If only `add_portal()` is used, the default 0.0.0.0:3260 portal is not bound.
+    pub fn add_portal(mut self, addr: &str, tpgt: u16) -> Self {
+        self.extra_portals.push(Portal::new(addr, tpgt));
+        self
+    }
+
+    /// Listen on a fully configured portal (limits, discovery-only, etc.)
+    pub fn portal(mut self, portal: Portal) -> Self {
+        self.extra_portals.push(portal);
+        self
+    }
+
+    /// Listen on a portal that only accepts discovery sessions
+    ///
+    /// Discovery-only portals are not advertised in SendTargets responses.
+    pub fn add_discovery_portal(mut self, addr: &str, tpgt: u16) -> Self {
+        self.extra_portals.push(Portal::discovery(addr, tpgt));
+        self
+    }
+
     /// Set the iSCSI target name (IQN format)
     ///
     /// Example: iqn.2025-12.local:storage.disk1
@@ -1035,85 +3137,711 @@ impl<D: ScsiBlockDevice> IscsiTargetBuilder<D> {
         self
     }
 
+    /// Report this INQUIRY vendor identification (up to 8 characters) for
+    /// every LUN instead of the device's `vendor_id()`
+    pub fn scsi_vendor(mut self, vendor: &str) -> Self {
+        self.identity.vendor = Some(vendor.to_string());
+        self
+    }
+
+    /// Report this INQUIRY product identification (up to 16 characters) for
+    /// every LUN instead of the device's `product_id()`
+    pub fn scsi_product(mut self, product: &str) -> Self {
+        self.identity.product = Some(product.to_string());
+        self
+    }
+
+    /// Report this INQUIRY product revision (up to 4 characters) for every
+    /// LUN instead of the device's `product_rev()`
+    pub fn scsi_revision(mut self, revision: &str) -> Self {
+        self.identity.revision = Some(revision.to_string());
+        self
+    }
+
+    /// Report this unit serial number instead of the device's `serial_number()`
+    ///
+    /// LUN 0 reports it as given and LUN n as "serial-n", so multipath can
+    /// tell the LUNs apart. The T10 vendor ID and NAA designators the device
+    /// derives from its own serial follow the override.
+    pub fn scsi_serial(mut self, serial: &str) -> Self {
+        self.identity.serial = Some(serial.to_string());
+        self
+    }
+
     /// Set the authentication configuration
     pub fn with_auth(mut self, auth_config: crate::auth::AuthConfig) -> Self {
         self.auth_config = auth_config;
         self
     }
 
-    /// Set the maximum number of concurrent connections (default: 16)
+    /// Add an initiator CHAP account (may be called repeatedly)
     ///
-    /// When this limit is reached, new login attempts will be rejected
-    /// with TOO_MANY_CONNECTIONS (0x0206) status code.
-    pub fn max_connections(mut self, max: u32) -> Self {
-        self.max_connections = Some(max);
+    /// Each initiator host can log in with its own username/secret. Cannot be
+    /// combined with `with_auth()` or `chap_secret_provider()`. Secrets are
+    /// validated at `build()` (see `ChapCredentials::validate`).
+    pub fn chap_account(mut self, username: &str, secret: &str) -> Self {
+        self.chap_accounts.add(username, secret);
         self
     }
 
-    /// Set the maximum number of concurrent sessions (default: 256)
-    pub fn max_sessions(mut self, max: u32) -> Self {
-        self.max_sessions = Some(max);
+    /// Look up initiator CHAP secrets through a custom provider
+    ///
+    /// Use this for accounts kept in an external store. Cannot be combined
+    /// with `with_auth()` or `chap_account()`.
+    pub fn chap_secret_provider(mut self, provider: Arc<dyn crate::auth::ChapSecretProvider>) -> Self {
+        self.chap_provider = Some(provider);
         self
     }
 
-    /// Set Access Control List - allowed initiator IQNs (default: allow all)
+    /// Set the credentials the target presents to initiators for mutual CHAP
     ///
-    /// When set, only the specified initiator IQNs will be allowed to access the target.
-    /// Authentication must still succeed, but then the initiator IQN is checked against this list.
-    /// If the initiator is not in the list, login will be rejected with AUTHORIZATION_FAILURE (0x0202).
-    pub fn allowed_initiators(mut self, initiators: Vec<String>) -> Self {
-        self.allowed_initiators = Some(initiators);
+    /// Applies to accounts configured with `chap_account()` or `chap_secret_provider()`.
+    pub fn mutual_chap_credentials(mut self, credentials: crate::auth::ChapCredentials) -> Self {
+        self.mutual_chap_credentials = Some(credentials);
         self
     }
 
-    /// Build the target with the specified storage device
-    pub fn build(self, device: D) -> ScsiResult<IscsiTarget<D>> {
-        let bind_addr = self.bind_addr.unwrap_or_else(|| format!("0.0.0.0:{}", ISCSI_PORT));
-        let target_name = self.target_name.unwrap_or_else(|| {
-            "iqn.2025-12.local:storage.default".to_string()
-        });
-        let target_alias = self.target_alias.unwrap_or_else(|| "iSCSI Target".to_string());
+    /// Allow or forbid MD5 CHAP (default: allowed)
+    ///
+    /// CHAP negotiates the first algorithm in the initiator's CHAP_A list
+    /// that the target allows. With MD5 forbidden, initiators that only
+    /// offer CHAP_A=5 fail authentication.
+    pub fn allow_md5_chap(mut self, allow: bool) -> Self {
+        self.allow_md5_chap = allow;
+        self
+    }
 
-        // Validate IQN format (basic check)
-        if !target_name.starts_with("iqn.") && !target_name.starts_with("eui.") && !target_name.starts_with("naa.") {
-            return Err(IscsiError::Config(
-                "target_name must be in IQN, EUI, or NAA format (e.g., iqn.2025-12.local:storage.disk1)".to_string()
-            ));
-        }
+    /// How to treat initiators that violate RFC 3720 (default: `Strictness::Permissive`)
+    ///
+    /// `Strictness::Strict` suits conformance testing; see `Strictness` for
+    /// what it rejects.
+    pub fn strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
 
-        let max_connections = self.max_connections.unwrap_or(16);
-        let max_sessions = self.max_sessions.unwrap_or(256);
+    /// Support Auto Contingent Allegiance (default: off)
+    ///
+    /// INQUIRY then reports NormACA, and a command sent with the NACA bit
+    /// that fails with CHECK CONDITION holds its LUN in ACA until CLEAR ACA.
+    /// Some AIX and HP-UX initiators expect this.
+    pub fn aca(mut self, enabled: bool) -> Self {
+        self.identity.norm_aca = enabled;
+        self
+    }
 
-        Ok(IscsiTarget {
-            bind_addr,
-            target_name,
-            target_alias,
-            device: Arc::new(Mutex::new(device)),
-            running: Arc::new(AtomicBool::new(false)),
-            shutting_down: Arc::new(AtomicBool::new(false)),
-            auth_config: self.auth_config,
-            max_connections,
-            active_connections: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
-            max_sessions,
-            active_sessions: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
-            allowed_initiators: self.allowed_initiators,
-        })
+    /// Report these values in the Read-Write Error Recovery,
+    /// Disconnect-Reconnect and Informational Exceptions mode pages instead
+    /// of each device's own (see `ScsiBlockDevice::mode_parameters`)
+    pub fn mode_parameters(mut self, params: ModeParameters) -> Self {
+        self.identity.mode_parameters = Some(params);
+        self
     }
-}
 
-// ============================================================================
+    /// Register an observer for target events (aborted writes, etc.)
+    pub fn observer(mut self, observer: Arc<dyn TargetObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Record every completed SCSI command with an audit sink
+    ///
+    /// Records are delivered in batches from connection threads (see
+    /// `crate::audit`). Without a sink no per-command tracking is done.
+    pub fn audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = Some(sink);
+        self
+    }
+
+    /// Set the order in which SendTargets lists portals (default: bind order)
+    pub fn portal_preference(mut self, preference: PortalPreference) -> Self {
+        self.portal_preference = preference;
+        self
+    }
+
+    /// Never advertise portals in this network (CIDR, e.g. "10.99.0.0/16")
+    ///
+    /// Use for management-only addresses that initiators should not use as
+    /// a data path. May be called repeatedly.
+    pub fn exclude_from_discovery(mut self, network: &str) -> Self {
+        self.discovery_exclusions.push(network.to_string());
+        self
+    }
+
+    /// Set the MaxRecvDataSegmentLength the target declares (default: 8192)
+    ///
+    /// PDUs whose data segment exceeds this are rejected. Must be between 512
+    /// and 16777215 (RFC 3720 Section 12.12).
+    pub fn max_recv_data_segment_length(mut self, bytes: u32) -> Self {
+        self.max_recv_data_segment_length = Some(bytes);
+        self
+    }
+
+    /// Set the MaxBurstLength the target offers (default: 262144)
+    ///
+    /// The session uses the smaller of this and the initiator's value. Must be
+    /// between 512 and 16777215 (RFC 3720 Section 12.13).
+    pub fn max_burst_length(mut self, bytes: u32) -> Self {
+        self.max_burst_length = Some(bytes);
+        self
+    }
+
+    /// Set the FirstBurstLength the target offers (default: 65536)
+    ///
+    /// The session uses the smaller of this and the initiator's value. Must be
+    /// at least 512 and no larger than MaxBurstLength (RFC 3720 Section 12.14).
+    pub fn first_burst_length(mut self, bytes: u32) -> Self {
+        self.first_burst_length = Some(bytes);
+        self
+    }
+
+    /// Require an R2T before any unsolicited Data-Out (default: false)
+    ///
+    /// InitialR2T is OR-ed with the initiator's value, so `true` always wins.
+    pub fn initial_r2t(mut self, required: bool) -> Self {
+        self.initial_r2t = Some(required);
+        self
+    }
+
+    /// Allow immediate data in SCSI Command PDUs (default: true)
+    ///
+    /// ImmediateData is AND-ed with the initiator's value, so `false` always wins.
+    pub fn immediate_data(mut self, allowed: bool) -> Self {
+        self.immediate_data = Some(allowed);
+        self
+    }
+
+    /// Require Data-Out sequences in increasing offset order (default: true)
+    ///
+    /// DataSequenceInOrder is OR-ed with the initiator's value, so only
+    /// `false` lets an initiator proposing No answer the R2Ts of a command in
+    /// any order.
+    pub fn data_sequence_in_order(mut self, required: bool) -> Self {
+        self.data_sequence_in_order = Some(required);
+        self
+    }
+
+    /// Set the MaxOutstandingR2T the target offers (default: 1)
+    ///
+    /// The session uses the smaller of this and the initiator's value. Must be
+    /// between 1 and 65535 (RFC 3720 Section 12.17).
+    pub fn max_outstanding_r2t(mut self, count: u32) -> Self {
+        self.max_outstanding_r2t = Some(count);
+        self
+    }
+
+    /// Set the maximum number of concurrent connections (default: 16)
+    ///
+    /// When this limit is reached, new login attempts will be rejected
+    /// with TOO_MANY_CONNECTIONS (0x0206) status code.
+    pub fn max_connections(mut self, max: u32) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Set the maximum number of concurrent sessions (default: 256)
+    pub fn max_sessions(mut self, max: u32) -> Self {
+        self.max_sessions = Some(max);
+        self
+    }
+
+    /// Set Access Control List - allowed initiator IQNs (default: allow all)
+    ///
+    /// When set, only the specified initiator IQNs will be allowed to access the target.
+    /// Entries may use `*` and `?` wildcards (e.g. "iqn.2025-12.example:host-*").
+    /// Authentication must still succeed, but then the initiator IQN is checked against this list.
+    /// If the initiator is not in the list, login will be rejected with AUTHORIZATION_FAILURE (0x0202).
+    pub fn allowed_initiators(mut self, initiators: Vec<String>) -> Self {
+        self.allowed_initiators = Some(initiators);
+        self
+    }
+
+    /// Only admit initiators connecting from this network (CIDR, e.g. "10.20.0.0/16")
+    ///
+    /// May be called repeatedly; once any network is given, logins from
+    /// addresses outside all of them are rejected with AUTHORIZATION_FAILURE
+    /// (0x0202), whatever their IQN.
+    pub fn allow_network(mut self, network: &str) -> Self {
+        self.allowed_networks.push(network.to_string());
+        self
+    }
+
+    /// Enable NOP-In keepalive pings on idle full-feature connections (default: off)
+    ///
+    /// After `interval` without a PDU from the initiator the target sends a
+    /// NOP-In ping and drops the connection if no NOP-Out reply arrives within
+    /// `timeout`.
+    pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keepalive = Some(Keepalive { interval, timeout });
+        self
+    }
+
+    /// Log out sessions that send no commands for `timeout` (default: off)
+    ///
+    /// The target sends an Async Message logout request and drops the
+    /// connection if the initiator does not log out. NOP-Outs are not
+    /// counted as commands.
+    pub fn session_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.session_limits.idle = Some(timeout);
+        self
+    }
+
+    /// Log out sessions once they have been logged in for `lifetime` (default: off)
+    pub fn session_lifetime(mut self, lifetime: Duration) -> Self {
+        self.session_limits.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Disable Nagle's algorithm on accepted connections (default: on)
+    ///
+    /// With TCP_NODELAY a response PDU goes out at once instead of waiting
+    /// for the initiator's delayed ACK, which dominates small-I/O latency.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.socket_options.nodelay = nodelay;
+        self
+    }
+
+    /// Set the socket receive and send buffer sizes in bytes (SO_RCVBUF,
+    /// SO_SNDBUF; default: the system's)
+    pub fn socket_buffers(mut self, recv: usize, send: usize) -> Self {
+        self.socket_options.recv_buffer = Some(recv);
+        self.socket_options.send_buffer = Some(send);
+        self
+    }
+
+    /// Enable TCP keepalive probes on accepted connections (default: off)
+    ///
+    /// After `idle` without traffic the kernel probes every `interval` and
+    /// drops the connection after `retries` unanswered probes. Unlike
+    /// `keepalive()` this also covers connections still logging in.
+    pub fn tcp_keepalive(mut self, idle: Duration, interval: Duration, retries: u32) -> Self {
+        self.socket_options.keepalive = Some(TcpKeepalive { idle, interval, retries });
+        self
+    }
+
+    /// Mark storage traffic with an IP TOS byte / IPv6 traffic class
+    ///
+    /// DSCP is the upper six bits, so `dscp << 2`.
+    pub fn ip_tos(mut self, tos: u8) -> Self {
+        self.socket_options.tos = Some(tos);
+        self
+    }
+
+    /// Set how long a WRITE may wait on Data-Out before its R2Ts are
+    /// retransmitted, and how many retransmissions are made before the
+    /// task is aborted (default: 20 seconds, 2 retries)
+    ///
+    /// Sessions at ErrorRecoveryLevel 0 get no retransmissions: the task is
+    /// aborted once the timeout passes.
+    pub fn r2t_retransmit(mut self, timeout: Duration, retries: u32) -> Self {
+        self.r2t_retransmit = Some(R2tRetransmit { timeout, retries });
+        self
+    }
+
+    /// Prefetch `window` blocks ahead of a LUN once `sequential_reads`
+    /// READs have followed on from each other (default: 2 READs, 1024 blocks)
+    ///
+    /// Only backends that implement `ScsiBlockDevice::prefetch` benefit,
+    /// such as object-store devices with expensive random access.
+    pub fn read_ahead(mut self, sequential_reads: u32, window: u32) -> Self {
+        self.read_ahead = Some(ReadAhead { sequential_reads, window });
+        self
+    }
+
+    /// Bound each connection's queue of unsent PDUs to `capacity` bytes, and
+    /// drop connections whose queue stays full for `stall_timeout`
+    /// (default: 16 MiB, 30 seconds)
+    pub fn send_queue(mut self, capacity: usize, stall_timeout: Duration) -> Self {
+        self.send_queue = SendQueueLimits { capacity, stall_timeout };
+        self
+    }
+
+    /// Never call the device's `prefetch` hook for streaming reads
+    pub fn disable_read_ahead(mut self) -> Self {
+        self.read_ahead = None;
+        self
+    }
+
+    /// Answer queued commands the device has not completed within `timeout`
+    /// without waiting for it (default: off)
+    ///
+    /// The command gets CHECK CONDITION with COMMAND TIMEOUT DURING
+    /// PROCESSING sense, or BUSY if `busy` is set. SIMPLE commands without
+    /// Data-Out, such as READs, run on the worker pool and are timed from
+    /// when they are queued. WRITEs and SYNCHRONIZE CACHE keep their place on
+    /// the connection thread but make their device calls (each write of
+    /// their data, the FUA flush, the cache flush) on the pool, each timed on
+    /// its own; a WRITE's wait for Data-Out is bounded by `r2t_retransmit`
+    /// instead. The rest of what runs inline has no deadline: other commands
+    /// with Data-Out such as MODE SELECT, other ORDERED, HEAD OF QUEUE and
+    /// ACA tasks, reservation commands, and task management.
+    ///
+    /// Answering a command does not free its worker: that waits for the
+    /// device to return. A device that never returns keeps one pool worker
+    /// per stalled command, so a single hung LUN can still use up the pool
+    /// shared with other sessions (see `worker_threads`).
+    pub fn command_deadline(mut self, timeout: Duration, busy: bool) -> Self {
+        self.command_deadline = Some(CommandDeadline { timeout, busy });
+        self
+    }
+
+    /// Set hard limits on the AHS and data segment lengths of received PDUs
+    ///
+    /// A PDU beyond these limits closes the connection without its AHS or
+    /// data being read. Data segments above the negotiated
+    /// MaxRecvDataSegmentLength but within the limit are still discarded
+    /// and rejected. Default: [`PduLimits::default()`].
+    pub fn pdu_limits(mut self, limits: PduLimits) -> Self {
+        self.pdu_limits = Some(limits);
+        self
+    }
+
+    /// Capture every PDU sent and received to a pcapng file at `path`
+    ///
+    /// A diagnostic mode for analysing protocol problems offline: the file
+    /// opens in Wireshark with each PDU decoded as iSCSI, annotated with the
+    /// connection's initiator name, ISID, TSIH and CID. The file is created
+    /// (or truncated) by `build()`. Off by default; it records all data,
+    /// including CHAP exchanges and block contents.
+    pub fn pdu_trace(mut self, path: impl Into<PathBuf>) -> Self {
+        self.trace_path = Some(path.into());
+        self
+    }
+
+    /// How much of each PDU to log at debug level (default: `PduLogLevel::None`)
+    ///
+    /// Change it on the running target with `TargetControl::set_pdu_logging`.
+    pub fn pdu_logging(mut self, level: PduLogLevel) -> Self {
+        self.pdu_logging = level;
+        self
+    }
+
+    /// Pass every PDU sent and received through `interceptor`
+    ///
+    /// For fault-injection tests and protocol experiments (see
+    /// `crate::intercept`): the interceptor can change, drop or corrupt each
+    /// PDU. Sent PDUs are copied for it, so leave it unset in production.
+    pub fn pdu_interceptor(mut self, interceptor: Arc<dyn PduInterceptor>) -> Self {
+        self.interceptor = Some(interceptor);
+        self
+    }
+
+    /// Set the number of SCSI commands each connection may have queued (default: 32)
+    ///
+    /// Commands beyond this depth complete with TASK SET FULL status.
+    pub fn queue_depth(mut self, depth: u32) -> Self {
+        self.queue_depth = Some(depth);
+        self
+    }
+
+    /// Set how many CmdSNs ahead of ExpCmdSN initiators may send (default: the queue depth)
+    ///
+    /// MaxCmdSN is advertised as ExpCmdSN + window - 1; commands outside the
+    /// window are silently ignored.
+    pub fn command_window(mut self, window: u32) -> Self {
+        self.command_window = Some(window);
+        self
+    }
+
+    /// Set the number of threads executing queued SCSI commands (default: 4)
+    ///
+    /// The pool is shared by all connections to the target. A command
+    /// answered at its `command_deadline` keeps its thread until the device
+    /// returns.
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.worker_threads = Some(threads);
+        self
+    }
+
+    /// Build the target with the specified storage device as LUN 0
+    pub fn build(self, device: D) -> ScsiResult<IscsiTarget<D>>
+    where
+        D: 'static,
+    {
+        self.build_with_luns(LunTable::new(Arc::new(RwLock::new(CountingDevice::new(device)))))
+    }
+
+    fn build_with_luns(self, luns: LunTable<D>) -> ScsiResult<IscsiTarget<D>>
+    where
+        D: 'static,
+    {
+        let mut portals = Vec::new();
+        match self.bind_addr {
+            Some(addr) => portals.push(Portal::new(&addr, DEFAULT_TPGT)),
+            None if self.extra_portals.is_empty() => {
+                portals.push(Portal::new(&format!("0.0.0.0:{}", ISCSI_PORT), DEFAULT_TPGT));
+            }
+            None => {}
+        }
+        for portal in self.extra_portals {
+            if portals.iter().any(|p| p.bind_addr == portal.bind_addr) {
+                return Err(IscsiError::Config(format!(
+                    "portal {} configured more than once", portal.bind_addr
+                )));
+            }
+            portals.push(portal);
+        }
+        if let Some(primary) = portals.first_mut() {
+            primary.advertise_addrs.extend(self.advertise_addrs);
+        }
+        for portal in &portals {
+            portal.advertised_addresses()?;
+        }
+        let target_name = self.target_name.unwrap_or_else(|| {
+            "iqn.2025-12.local:storage.default".to_string()
+        });
+        let target_alias = self.target_alias.unwrap_or_else(|| "iSCSI Target".to_string());
+
+        let max_recv_data_segment_length = self.max_recv_data_segment_length.unwrap_or(8192);
+        if !(512..=16_777_215).contains(&max_recv_data_segment_length) {
+            return Err(IscsiError::Config(format!(
+                "max_recv_data_segment_length must be between 512 and 16777215, got {}",
+                max_recv_data_segment_length
+            )));
+        }
+
+        let defaults = SessionParams::default();
+        let max_burst_length = self.max_burst_length.unwrap_or(defaults.max_burst_length);
+        if !(512..=16_777_215).contains(&max_burst_length) {
+            return Err(IscsiError::Config(format!(
+                "max_burst_length must be between 512 and 16777215, got {}",
+                max_burst_length
+            )));
+        }
+        let first_burst_length = self.first_burst_length
+            .unwrap_or_else(|| defaults.first_burst_length.min(max_burst_length));
+        if !(512..=max_burst_length).contains(&first_burst_length) {
+            return Err(IscsiError::Config(format!(
+                "first_burst_length must be between 512 and max_burst_length ({}), got {}",
+                max_burst_length, first_burst_length
+            )));
+        }
+        let max_outstanding_r2t = self.max_outstanding_r2t.unwrap_or(defaults.max_outstanding_r2t);
+        if !(1..=65535).contains(&max_outstanding_r2t) {
+            return Err(IscsiError::Config(format!(
+                "max_outstanding_r2t must be between 1 and 65535, got {}",
+                max_outstanding_r2t
+            )));
+        }
+        let session_defaults = SessionParams {
+            max_recv_data_segment_length,
+            max_burst_length,
+            first_burst_length,
+            max_outstanding_r2t,
+            initial_r2t: self.initial_r2t.unwrap_or(defaults.initial_r2t),
+            immediate_data: self.immediate_data.unwrap_or(defaults.immediate_data),
+            data_sequence_in_order: self.data_sequence_in_order.unwrap_or(defaults.data_sequence_in_order),
+            ..defaults
+        };
+
+        let max_connections = self.max_connections.unwrap_or(16);
+        let max_sessions = self.max_sessions.unwrap_or(256);
+
+        let allowed_networks = self.allowed_networks.iter()
+            .map(|network| network.parse::<IpNetwork>())
+            .collect::<ScsiResult<Vec<_>>>()?;
+        let allowed_networks = (!allowed_networks.is_empty()).then_some(allowed_networks);
+        let excluded = self.discovery_exclusions.iter()
+            .map(|network| network.parse::<IpNetwork>())
+            .collect::<ScsiResult<Vec<_>>>()?;
+        let mut discovery = DiscoveryConfig {
+            portals: Vec::new(),
+            preference: self.portal_preference,
+            excluded,
+            overrides: Vec::new(),
+        };
+        discovery.set_portals(&portals);
+
+        self.chap_accounts.validate()?;
+
+        let provider: Option<Arc<dyn crate::auth::ChapSecretProvider>> =
+            match (self.chap_provider, self.chap_accounts.is_empty()) {
+                (Some(_), false) => {
+                    return Err(IscsiError::Config(
+                        "chap_account() and chap_secret_provider() cannot be combined".to_string()
+                    ));
+                }
+                (Some(provider), true) => Some(provider),
+                (None, false) => Some(Arc::new(self.chap_accounts)),
+                (None, true) => None,
+            };
+
+        let auth_config = match provider {
+            Some(provider) => {
+                if self.auth_config.requires_auth() {
+                    return Err(IscsiError::Config(
+                        "CHAP accounts cannot be combined with with_auth()".to_string()
+                    ));
+                }
+                crate::auth::AuthConfig::ChapProvider {
+                    provider,
+                    mutual_credentials: self.mutual_chap_credentials,
+                }
+            }
+            None => {
+                if self.mutual_chap_credentials.is_some() {
+                    return Err(IscsiError::Config(
+                        "mutual_chap_credentials() requires chap_account() or chap_secret_provider()".to_string()
+                    ));
+                }
+                self.auth_config
+            }
+        };
+
+        let config = TargetConfig {
+            target_name,
+            target_alias,
+            auth: auth_config,
+            allow_md5_chap: self.allow_md5_chap,
+            allowed_initiators: self.allowed_initiators,
+            allowed_networks,
+            strictness: self.strictness,
+            luns: None,
+        };
+        config.validate()?;
+        self.identity.validate()?;
+
+        if let Some(keepalive) = self.keepalive {
+            if keepalive.interval.is_zero() || keepalive.timeout.is_zero() {
+                return Err(IscsiError::Config(
+                    "Keepalive interval and timeout must be non-zero".to_string()
+                ));
+            }
+        }
+
+        if [self.session_limits.idle, self.session_limits.lifetime].into_iter().flatten().any(|limit| limit.is_zero()) {
+            return Err(IscsiError::Config("Session idle timeout and lifetime must be non-zero".to_string()));
+        }
+
+        self.socket_options.validate()?;
+
+        let r2t_retransmit = self.r2t_retransmit.unwrap_or_default();
+        if r2t_retransmit.timeout.is_zero() {
+            return Err(IscsiError::Config("R2T retransmit timeout must be non-zero".to_string()));
+        }
+
+        if self.send_queue.capacity == 0 || self.send_queue.stall_timeout.is_zero() {
+            return Err(IscsiError::Config("Send queue capacity and stall timeout must be non-zero".to_string()));
+        }
+
+        if self.command_deadline.is_some_and(|deadline| deadline.timeout.is_zero()) {
+            return Err(IscsiError::Config("Command deadline must be non-zero".to_string()));
+        }
+
+        if self.read_ahead.is_some_and(|read_ahead| read_ahead.sequential_reads == 0 || read_ahead.window == 0) {
+            return Err(IscsiError::Config("Read-ahead needs at least one sequential READ and a non-zero window".to_string()));
+        }
+
+        let queue_depth = self.queue_depth.unwrap_or(32);
+        if queue_depth == 0 {
+            return Err(IscsiError::Config("queue_depth must be at least 1".to_string()));
+        }
+
+        let command_window = self.command_window.unwrap_or(queue_depth);
+        if command_window == 0 {
+            return Err(IscsiError::Config("command_window must be at least 1".to_string()));
+        }
+
+        let worker_threads = self.worker_threads.unwrap_or(4);
+        if worker_threads == 0 {
+            return Err(IscsiError::Config("worker_threads must be at least 1".to_string()));
+        }
+
+        let pdu_limits = self.pdu_limits.unwrap_or_default();
+        if pdu_limits.max_data_segment_length < max_recv_data_segment_length {
+            return Err(IscsiError::Config(format!(
+                "PDU data segment limit {} is below MaxRecvDataSegmentLength {}",
+                pdu_limits.max_data_segment_length, max_recv_data_segment_length
+            )));
+        }
+
+        let trace = match &self.trace_path {
+            Some(path) => Some(Arc::new(PduTrace::create(path).map_err(|e| {
+                IscsiError::Config(format!("Cannot create PDU trace {}: {}", path.display(), e))
+            })?)),
+            None => None,
+        };
+
+        let mut luns = luns;
+        luns.set_identity(self.identity);
+
+        let control = TargetControl::new(config);
+        control.set_pdu_logging(self.pdu_logging);
+        let luns = Arc::new(luns);
+        control.attach_luns(luns.clone());
+
+        Ok(IscsiTarget {
+            portals: portals.into_iter()
+                .map(|config| PortalState { config, stats: Arc::new(PortalStats::default()) })
+                .collect(),
+            session_defaults,
+            settings: Arc::new(ConnectionSettings {
+                luns,
+                control,
+                running: AtomicBool::new(false),
+                shutting_down: AtomicBool::new(false),
+                max_sessions,
+                active_sessions: std::sync::atomic::AtomicUsize::new(0),
+                observer: self.observer,
+                audit: self.audit,
+                discovery,
+                keepalive: self.keepalive,
+                session_limits: self.session_limits,
+                send_queue: self.send_queue,
+                r2t_retransmit,
+                read_ahead: self.read_ahead,
+                command_deadline: self.command_deadline,
+                queue_depth,
+                command_window,
+                pdu_limits,
+                trace,
+            }),
+            max_connections,
+            active_connections: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+            open_connections: Arc::new(Mutex::new(HashMap::new())),
+            next_connection_id: AtomicU64::new(0),
+            socket_options: self.socket_options,
+            worker_threads,
+            interceptor: self.interceptor,
+            stream_workers: OnceLock::new(),
+        })
+    }
+}
+
+impl IscsiTargetBuilder<Box<dyn ScsiBlockDevice>> {
+    /// Build the target with devices opened for each session by `provider`
+    ///
+    /// A session opens its own device for each of the provider's LUNs on its
+    /// first command to the LUN. LUNs added later with
+    /// `TargetControl::add_lun()` are shared by every session.
+    pub fn build_with_provider(self, provider: Arc<dyn DeviceProvider>) -> ScsiResult<IscsiTarget<Box<dyn ScsiBlockDevice>>> {
+        let luns = provider.luns();
+        let opener: DeviceOpener<Box<dyn ScsiBlockDevice>> =
+            Arc::new(move |initiator: &str, lun| provider.open(initiator, lun));
+        self.build_with_luns(LunTable::with_opener(&luns, opener)?)
+    }
+}
+
+// ============================================================================
 // Unit Tests
 // ============================================================================
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::ScsiDeviceError;
+    use crate::lun::{self, ChangeKind};
+    use crate::protection::ProtectionInfo;
+    use std::sync::Mutex;
 
     /// Mock device for testing
     struct MockDevice {
         capacity: u64,
         block_size: u32,
         data: Vec<u8>,
+        write_cache: bool,
+        protection_type: u8,
+        flushes: u32,
     }
 
     impl MockDevice {
@@ -1123,6 +3851,9 @@ mod tests {
                 capacity,
                 block_size,
                 data: vec![0u8; size],
+                write_cache: false,
+                protection_type: 0,
+                flushes: 0,
             }
         }
     }
@@ -1132,7 +3863,7 @@ mod tests {
             let offset = (lba * block_size as u64) as usize;
             let len = (blocks * block_size) as usize;
             if offset + len > self.data.len() {
-                return Err(IscsiError::Scsi("Read out of bounds".into()));
+                return Err(ScsiDeviceError::OutOfRange { lba }.into());
             }
             Ok(self.data[offset..offset + len].to_vec())
         }
@@ -1140,7 +3871,7 @@ mod tests {
         fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
             let offset = (lba * block_size as u64) as usize;
             if offset + data.len() > self.data.len() {
-                return Err(IscsiError::Scsi("Write out of bounds".into()));
+                return Err(ScsiDeviceError::OutOfRange { lba }.into());
             }
             self.data[offset..offset + data.len()].copy_from_slice(data);
             Ok(())
@@ -1153,6 +3884,28 @@ mod tests {
         fn block_size(&self) -> u32 {
             self.block_size
         }
+
+        fn flush(&mut self) -> ScsiResult<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+
+        fn write_cache_enabled(&self) -> bool {
+            self.write_cache
+        }
+
+        fn set_write_cache(&mut self, enabled: bool) -> ScsiResult<()> {
+            self.write_cache = enabled;
+            Ok(())
+        }
+
+        fn write_cache_changeable(&self) -> bool {
+            true
+        }
+
+        fn protection_type(&self) -> u8 {
+            self.protection_type
+        }
     }
 
     #[test]
@@ -1162,8 +3915,8 @@ mod tests {
             .build(device)
             .unwrap();
 
-        assert_eq!(target.bind_addr, "0.0.0.0:3260");
-        assert!(target.target_name.starts_with("iqn."));
+        assert_eq!(target.portals[0].config, Portal::new("0.0.0.0:3260", 1));
+        assert!(target.settings.control.config().target_name.starts_with("iqn."));
     }
 
     #[test]
@@ -1176,9 +3929,9 @@ mod tests {
             .build(device)
             .unwrap();
 
-        assert_eq!(target.bind_addr, "127.0.0.1:3260");
-        assert_eq!(target.target_name, "iqn.2025-12.test:disk1");
-        assert_eq!(target.target_alias, "Test Disk");
+        assert_eq!(target.portals[0].config, Portal::new("127.0.0.1:3260", 1));
+        assert_eq!(target.settings.control.config().target_name, "iqn.2025-12.test:disk1");
+        assert_eq!(target.settings.control.config().target_alias, "Test Disk");
     }
 
     #[test]
@@ -1192,32 +3945,2890 @@ mod tests {
     }
 
     #[test]
-    fn test_running_flag() {
-        let device = MockDevice::new(1000, 512);
+    fn test_builder_chap_accounts() {
         let target = IscsiTarget::builder()
-            .build(device)
+            .chap_account("host-a", "secret-a-123456")
+            .chap_account("host-b", "secret-b-123456")
+            .build(MockDevice::new(1000, 512))
             .unwrap();
 
-        assert!(!target.is_running());
-        target.running.store(true, Ordering::SeqCst);
-        assert!(target.is_running());
-        target.stop();
-        assert!(!target.is_running());
+        let config = target.settings.control.config();
+        let provider = config.auth.secret_provider().unwrap();
+        assert!(provider.contains("host-a"));
+        assert!(provider.contains("host-b"));
+        assert!(!target.settings.control.config().auth.is_mutual());
+
+        let target = IscsiTarget::builder()
+            .chap_account("host-a", "secret-a-123456")
+            .mutual_chap_credentials(crate::auth::ChapCredentials::new("target", "target-secret"))
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert!(target.settings.control.config().auth.is_mutual());
     }
 
     #[test]
-    fn test_pdu_roundtrip() {
-        // Test that PDU serialization/deserialization works correctly
-        let mut pdu = IscsiPdu::new();
-        pdu.opcode = opcode::NOP_IN;
-        pdu.flags = flags::FINAL;
-        pdu.itt = 0x12345678;
-
-        let bytes = pdu.to_bytes();
-        let parsed = IscsiPdu::from_bytes(&bytes).unwrap();
+    fn test_builder_chap_accounts_conflict() {
+        let result = IscsiTarget::builder()
+            .with_auth(crate::auth::AuthConfig::Chap {
+                credentials: crate::auth::ChapCredentials::new("user", "secret"),
+            })
+            .chap_account("host-a", "secret-a-123456")
+            .build(MockDevice::new(1000, 512));
+        assert!(result.is_err());
 
-        assert_eq!(parsed.opcode, opcode::NOP_IN);
+        let result = IscsiTarget::builder()
+            .mutual_chap_credentials(crate::auth::ChapCredentials::new("target", "target-secret"))
+            .build(MockDevice::new(1000, 512));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_builder_multiple_portals() {
+        let target = IscsiTarget::builder()
+            .bind_addr("10.0.0.5:3260")
+            .add_portal("10.1.0.5:3260", 2)
+            .add_discovery_portal("192.168.0.5:3260", 3)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+
+        let configs: Vec<Portal> = target.portals.iter().map(|p| p.config.clone()).collect();
+        assert_eq!(configs, vec![
+            Portal::new("10.0.0.5:3260", 1),
+            Portal::new("10.1.0.5:3260", 2),
+            Portal::discovery("192.168.0.5:3260", 3),
+        ]);
+        assert_eq!(target.settings.discovery.portals.len(), 2);
+
+        let target = IscsiTarget::builder()
+            .add_portal("127.0.0.1:3261", 5)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.portals.len(), 1);
+        assert_eq!(target.portals[0].config, Portal::new("127.0.0.1:3261", 5));
+
+        let result = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:3260")
+            .add_portal("127.0.0.1:3260", 2)
+            .build(MockDevice::new(1000, 512));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_builder_discovery_portals() {
+        let target = IscsiTarget::builder()
+            .bind_addr("10.0.0.5:3260")
+            .portal_preference(PortalPreference::Ipv6First)
+            .exclude_from_discovery("172.16.0.0/12")
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+
+        assert_eq!(target.settings.discovery.portals, vec![("10.0.0.5:3260".parse().unwrap(), 1)]);
+        assert_eq!(target.settings.discovery.preference, PortalPreference::Ipv6First);
+        assert_eq!(target.settings.discovery.excluded.len(), 1);
+
+        let result = IscsiTarget::builder()
+            .exclude_from_discovery("172.16.0.0/40")
+            .build(MockDevice::new(1000, 512));
+        assert!(result.is_err());
+
+        // Advertise overrides replace the primary portal's bind address
+        let target = IscsiTarget::builder()
+            .bind_addr("[::]:3260")
+            .advertise_addr("san.example.com")
+            .advertise_addr("2001:db8::10")
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert!(target.settings.discovery.portals.is_empty());
+        assert_eq!(target.settings.discovery.overrides, vec![
+            ("san.example.com:3260".to_string(), 1),
+            ("[2001:db8::10]:3260".to_string(), 1),
+        ]);
+
+        let result = IscsiTarget::builder()
+            .advertise_addr("san.example.com:http")
+            .build(MockDevice::new(1000, 512));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_builder_max_recv_data_segment_length() {
+        let target = IscsiTarget::builder()
+            .max_recv_data_segment_length(65536)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.session_params(&target.settings.control.config()).max_recv_data_segment_length, 65536);
+
+        for invalid in [0, 511, 16_777_216] {
+            let result = IscsiTarget::builder()
+                .max_recv_data_segment_length(invalid)
+                .build(MockDevice::new(1000, 512));
+            assert!(result.is_err(), "{} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn test_builder_negotiation_baseline() {
+        let target = IscsiTarget::builder()
+            .max_burst_length(131072)
+            .first_burst_length(16384)
+            .initial_r2t(true)
+            .immediate_data(false)
+            .max_outstanding_r2t(8)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        let mut session = IscsiSession::new();
+        session.params = target.session_params(&target.settings.control.config());
+        let offered = session.generate_response_params();
+        for (key, value) in [
+            ("MaxBurstLength", "131072"),
+            ("FirstBurstLength", "16384"),
+            ("InitialR2T", "Yes"),
+            ("ImmediateData", "No"),
+            ("MaxOutstandingR2T", "8"),
+            ("DataSequenceInOrder", "Yes"),
+        ] {
+            assert!(offered.contains(&(key.to_string(), value.to_string())), "{}={} not offered", key, value);
+        }
+
+        let target = IscsiTarget::builder()
+            .data_sequence_in_order(false)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert!(!target.session_params(&target.settings.control.config()).data_sequence_in_order);
+
+        // FirstBurstLength follows a smaller MaxBurstLength unless set explicitly
+        let target = IscsiTarget::builder()
+            .max_burst_length(4096)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.session_params(&target.settings.control.config()).first_burst_length, 4096);
+
+        let invalid = [
+            IscsiTarget::builder().max_burst_length(511),
+            IscsiTarget::builder().max_burst_length(4096).first_burst_length(8192),
+            IscsiTarget::builder().max_outstanding_r2t(0),
+        ];
+        for builder in invalid {
+            assert!(builder.build(MockDevice::new(1000, 512)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_builder_scsi_identity() {
+        let target = IscsiTarget::builder()
+            .scsi_vendor("ACME")
+            .scsi_product("Block Appliance")
+            .scsi_revision("2.1")
+            .scsi_serial("ACME0001")
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        target.control().add_lun(1, MockDevice::new(1000, 512)).unwrap();
+
+        let inquiry = |lun: u64| {
+            let device = target.settings.luns.device(lun).unwrap();
+            let standard = execute_command(&[0x12, 0, 0, 0, 36, 0], &device).unwrap().data;
+            let serial = execute_command(&[0x12, 1, 0x80, 0, 64, 0], &device).unwrap().data;
+            (String::from_utf8_lossy(&standard[8..36]).into_owned(), String::from_utf8_lossy(&serial[4..]).into_owned())
+        };
+        assert_eq!(inquiry(0), ("ACME    Block Appliance 2.1 ".to_string(), "ACME0001".to_string()));
+        assert_eq!(inquiry(1).1, "ACME0001-1");
+
+        let result = IscsiTarget::builder()
+            .scsi_product("A product name over sixteen characters")
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_mode_parameters() {
+        let params = ModeParameters { read_retry_count: 8, dexcpt: false, mrie: 6, ..ModeParameters::default() };
+        let target = IscsiTarget::builder()
+            .mode_parameters(params)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        target.control().add_lun(1, MockDevice::new(1000, 512)).unwrap();
+        for lun in [0, 1] {
+            let device = target.settings.luns.device(lun).unwrap();
+            let error_recovery = execute_command(&[0x1A, 0, 0x01, 0, 255, 0], &device).unwrap().data;
+            assert_eq!(error_recovery[7], 8);
+            let exceptions = execute_command(&[0x1A, 0, 0x1C, 0, 255, 0], &device).unwrap().data;
+            assert_eq!(&exceptions[6..8], &[0x00, 0x06]);
+        }
+
+        let result = IscsiTarget::builder()
+            .mode_parameters(ModeParameters { mrie: 7, ..ModeParameters::default() })
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_aca_condition() {
+        let mut counting = CountingDevice::new(MockDevice::new(1000, 512));
+        counting.set_identity(Identity { norm_aca: true, ..Identity::default() });
+        let device = Arc::new(RwLock::new(counting));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let session = |initiator: &str| {
+            let mut session = IscsiSession::new();
+            session.state = SessionState::FullFeaturePhase;
+            session.params.initiator_name = initiator.to_string();
+            session.open_command_window(16);
+            session
+        };
+        let (mut faulted, mut other) = (session("iqn.test:a"), session("iqn.test:b"));
+        let command = |itt: u32, attribute: u8, cdb: &[u8]| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_COMMAND;
+            pdu.flags = flags::FINAL | attribute;
+            pdu.itt = itt;
+            pdu.specific[4..8].copy_from_slice(&itt.to_be_bytes());
+            pdu.specific[12..12 + cdb.len()].copy_from_slice(cdb);
+            pdu
+        };
+        let status = |session: &mut IscsiSession, pdu: &IscsiPdu| {
+            handle_full_feature_phase(session, pdu, &luns, "iqn.test", &[]).unwrap()[0].specific[1]
+        };
+        let tur = [0u8; 6];
+
+        let inquiry = execute_command(&[0x12, 0, 0, 0, 36, 0], &device).unwrap().data;
+        assert_eq!(inquiry[3] & 0x20, 0x20, "NormACA");
+
+        // A READ past the end with NACA set fails and puts the LUN into ACA
+        let read = [0x28, 0, 0, 0, 0x10, 0, 0, 0, 1, 0x04];
+        assert_eq!(status(&mut faulted, &command(1, pdu::task_attribute::SIMPLE, &read)), scsi_status::CHECK_CONDITION);
+        assert!(faulted.naca_tasks.is_empty());
+        assert_eq!(status(&mut faulted, &command(2, pdu::task_attribute::SIMPLE, &tur)), scsi_status::ACA_ACTIVE);
+        assert_eq!(status(&mut other, &command(1, pdu::task_attribute::ACA, &tur)), scsi_status::ACA_ACTIVE);
+        assert_eq!(status(&mut faulted, &command(3, pdu::task_attribute::ACA, &tur)), scsi_status::GOOD);
+
+        // CLEAR ACA from the faulting nexus lets other tasks run again
+        let mut clear = IscsiPdu::new();
+        clear.opcode = opcode::TASK_MANAGEMENT_REQUEST;
+        clear.immediate = true;
+        clear.flags = flags::FINAL | tmf_function::CLEAR_ACA;
+        clear.itt = 4;
+        let response = handle_full_feature_phase(&mut other, &clear, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[0], 0, "function complete");
+        assert!(luns.unit(0).unwrap().aca.holder().is_some(), "only the faulting nexus clears ACA");
+        handle_full_feature_phase(&mut faulted, &clear, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(status(&mut other, &command(2, pdu::task_attribute::SIMPLE, &tur)), scsi_status::GOOD);
+        assert_eq!(status(&mut faulted, &command(4, pdu::task_attribute::ACA, &tur)), scsi_status::CHECK_CONDITION);
+
+        // Without NACA a failure establishes nothing
+        let read = [0x28, 0, 0, 0, 0x10, 0, 0, 0, 1, 0];
+        assert_eq!(status(&mut faulted, &command(5, pdu::task_attribute::SIMPLE, &read)), scsi_status::CHECK_CONDITION);
+        assert_eq!(status(&mut faulted, &command(6, pdu::task_attribute::SIMPLE, &tur)), scsi_status::GOOD);
+    }
+
+    #[test]
+    fn test_builder_allowed_networks() {
+        let target = IscsiTarget::builder()
+            .allow_network("10.0.0.0/8")
+            .allow_network("fd00::/8")
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        let networks = target.control().config().allowed_networks.unwrap();
+        assert_eq!(networks, vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]);
+
+        let target = IscsiTarget::builder().build(MockDevice::new(1000, 512)).unwrap();
+        assert!(target.control().config().allowed_networks.is_none());
+
+        let result = IscsiTarget::builder()
+            .allow_network("10.0.0.0/33")
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_keepalive() {
+        let target = IscsiTarget::builder()
+            .keepalive(Duration::from_secs(30), Duration::from_secs(10))
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.keepalive, Some(Keepalive {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }));
+
+        let result = IscsiTarget::builder()
+            .keepalive(Duration::ZERO, Duration::from_secs(10))
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_session_limits() {
+        let target = IscsiTarget::builder()
+            .session_idle_timeout(Duration::from_secs(600))
+            .session_lifetime(Duration::from_secs(86400))
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.session_limits, SessionLimits {
+            idle: Some(Duration::from_secs(600)),
+            lifetime: Some(Duration::from_secs(86400)),
+        });
+
+        let result = IscsiTarget::builder()
+            .session_idle_timeout(Duration::ZERO)
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_read_ahead() {
+        let target = IscsiTarget::builder().build(MockDevice::new(1000, 512)).unwrap();
+        assert_eq!(target.settings.read_ahead, Some(ReadAhead::default()));
+
+        let target = IscsiTarget::builder()
+            .read_ahead(4, 256)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.read_ahead, Some(ReadAhead { sequential_reads: 4, window: 256 }));
+
+        let target = IscsiTarget::builder()
+            .disable_read_ahead()
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.read_ahead, None);
+
+        let result = IscsiTarget::builder()
+            .read_ahead(2, 0)
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_send_queue() {
+        let target = IscsiTarget::builder().build(MockDevice::new(1000, 512)).unwrap();
+        assert_eq!(target.settings.send_queue, SendQueueLimits::default());
+
+        let target = IscsiTarget::builder()
+            .send_queue(1 << 20, Duration::from_secs(5))
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.send_queue, SendQueueLimits { capacity: 1 << 20, stall_timeout: Duration::from_secs(5) });
+
+        let result = IscsiTarget::builder()
+            .send_queue(0, Duration::from_secs(5))
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_command_deadline() {
+        let target = IscsiTarget::builder().build(MockDevice::new(1000, 512)).unwrap();
+        assert_eq!(target.settings.command_deadline, None);
+
+        let target = IscsiTarget::builder()
+            .command_deadline(Duration::from_secs(30), true)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.command_deadline, Some(CommandDeadline { timeout: Duration::from_secs(30), busy: true }));
+
+        let result = IscsiTarget::builder()
+            .command_deadline(Duration::ZERO, false)
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_socket_options() {
+        let target = IscsiTarget::builder()
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.socket_options, SocketOptions::default());
+        assert!(target.socket_options.nodelay);
+
+        let target = IscsiTarget::builder()
+            .tcp_nodelay(false)
+            .socket_buffers(1 << 20, 1 << 20)
+            .tcp_keepalive(Duration::from_secs(60), Duration::from_secs(10), 5)
+            .ip_tos(0x28 << 2)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.socket_options, SocketOptions {
+            nodelay: false,
+            recv_buffer: Some(1 << 20),
+            send_buffer: Some(1 << 20),
+            keepalive: Some(TcpKeepalive { idle: Duration::from_secs(60), interval: Duration::from_secs(10), retries: 5 }),
+            tos: Some(0xA0),
+        });
+
+        let result = IscsiTarget::builder()
+            .tcp_keepalive(Duration::from_secs(60), Duration::ZERO, 5)
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_r2t_retransmit() {
+        let target = IscsiTarget::builder()
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.r2t_retransmit, R2tRetransmit::default());
+
+        let target = IscsiTarget::builder()
+            .r2t_retransmit(Duration::from_secs(5), 0)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.r2t_retransmit, R2tRetransmit { timeout: Duration::from_secs(5), retries: 0 });
+
+        let result = IscsiTarget::builder()
+            .r2t_retransmit(Duration::ZERO, 3)
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_pdu_limits() {
+        let target = IscsiTarget::builder()
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.pdu_limits, PduLimits::default());
+
+        let limits = PduLimits { max_data_segment_length: 65536, ..PduLimits::default() };
+        let target = IscsiTarget::builder()
+            .pdu_limits(limits)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.pdu_limits, limits);
+
+        // The hard limit may not undercut the advertised MaxRecvDataSegmentLength
+        let result = IscsiTarget::builder()
+            .max_recv_data_segment_length(131072)
+            .pdu_limits(limits)
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_pdu_trace() {
+        let target = IscsiTarget::builder()
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert!(target.settings.trace.is_none());
+
+        let result = IscsiTarget::builder()
+            .pdu_trace("/nonexistent-dir/trace.pcapng")
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_pdu_logging_level() {
+        let target = IscsiTarget::builder()
+            .pdu_logging(PduLogLevel::Full)
+            .build(crate::backends::MemBlockDevice::new(64, 512))
+            .unwrap();
+        let control = target.control();
+        assert_eq!(control.pdu_logging(), PduLogLevel::Full);
+
+        // Changes reach a connection that is already logged in
+        let (initiator, stream) = crate::transport::duplex();
+        target.serve_stream(stream).unwrap();
+        let mut client = crate::client::IscsiClient::from_stream(initiator);
+        client.login("iqn.2025-12.local:initiator", &control.config().target_name).unwrap();
+        for level in [PduLogLevel::Headers, PduLogLevel::None, PduLogLevel::Full] {
+            control.set_pdu_logging(level);
+            assert_eq!(control.pdu_logging(), level);
+            client.write_blocks(0, &[0x42; 512]).unwrap();
+            assert_eq!(client.read_blocks(0, 1).unwrap(), vec![0x42; 512]);
+        }
+        client.logout().ok();
+        target.stop();
+    }
+
+    #[test]
+    fn test_drained_send_queue_reopens_command_window() {
+        use crate::transport::DuplexStream;
+        use std::net::SocketAddr;
+        use std::sync::Condvar;
+
+        /// Sends wait while the initiator is not reading
+        struct Gated {
+            inner: Framed<DuplexStream>,
+            open: Arc<(Mutex<bool>, Condvar)>,
+        }
+
+        impl PduTransport for Gated {
+            fn recv_pdu(&mut self, max: u32, digests: Digests, limits: &PduLimits, trace: Option<&ConnectionTrace>) -> ScsiResult<ReceivedPdu> {
+                self.inner.recv_pdu(max, digests, limits, trace)
+            }
+
+            fn send_pdu(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>) -> ScsiResult<()> {
+                let (open, changed) = &*self.open;
+                let _open = changed.wait_while(open.lock().unwrap(), |open| !*open).unwrap();
+                self.inner.send_pdu(pdu, digests, trace)
+            }
+
+            fn try_clone(&self) -> std::io::Result<Self> {
+                Ok(Gated { inner: self.inner.try_clone()?, open: Arc::clone(&self.open) })
+            }
+
+            fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+                self.inner.set_read_timeout(timeout)
+            }
+
+            fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+                self.inner.set_write_timeout(timeout)
+            }
+
+            fn close(&self) -> std::io::Result<()> {
+                self.inner.close()
+            }
+
+            fn local_addr(&self) -> std::io::Result<SocketAddr> {
+                self.inner.local_addr()
+            }
+
+            fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+                self.inner.peer_addr()
+            }
+        }
+
+        // Five queued SCSI Responses congest the connection
+        let target = IscsiTarget::builder()
+            .command_window(4)
+            .send_queue(10 * BHS_SIZE, Duration::from_secs(10))
+            .build(crate::backends::MemBlockDevice::new(64, 512))
+            .unwrap();
+        let (initiator, stream) = crate::transport::duplex();
+        let open = Arc::new((Mutex::new(true), Condvar::new()));
+        target.serve_transport(Gated { inner: Framed::new(stream), open: Arc::clone(&open) }).unwrap();
+        initiator.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut client = crate::client::IscsiClient::from_stream(initiator);
+        client.login("iqn.2025-12.local:initiator", &target.control().config().target_name).unwrap();
+        let first = client.cmd_sn();
+
+        // The initiator stops reading and sends TEST UNIT READYs one by one
+        *open.0.lock().unwrap() = false;
+        for n in 0..8 {
+            let mut command = IscsiPdu::new();
+            command.opcode = opcode::SCSI_COMMAND;
+            command.flags = flags::FINAL;
+            command.itt = n;
+            command.specific[4..8].copy_from_slice(&first.wrapping_add(n).to_be_bytes());
+            command.specific[8..12].copy_from_slice(&client.exp_stat_sn().to_be_bytes());
+            client.send_pdu(&command).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        *open.0.lock().unwrap() = true;
+        open.1.notify_all();
+        let max_cmd_sn = |pdu: &IscsiPdu| u32::from_be_bytes(pdu.specific[12..16].try_into().unwrap());
+        let mut held = 0;
+        for n in 0..8 {
+            let response = client.recv_pdu().unwrap();
+            assert_eq!((response.opcode, response.itt), (opcode::SCSI_RESPONSE, n));
+            held = max_cmd_sn(&response);
+        }
+        // Held since the fifth command: one more command fits the window
+        assert_eq!(held, first.wrapping_add(8));
+
+        // Once the queue drains the initiator learns the reopened window
+        // without having to send anything
+        let update = client.recv_pdu().unwrap();
+        assert_eq!(update.opcode, opcode::NOP_IN);
+        assert_eq!(update.itt, 0xFFFF_FFFF);
+        assert_eq!(&update.specific[0..4], &[0xFF; 4]);
+        assert_eq!(max_cmd_sn(&update), first.wrapping_add(8 + 3));
+        client.logout().ok();
+        target.stop();
+    }
+
+    #[test]
+    fn test_reads_execute_concurrently() {
+        use std::sync::atomic::AtomicUsize;
+
+        /// Records how many reads were in progress at once
+        struct ConcurrencyProbe {
+            active: AtomicUsize,
+            peak: AtomicUsize,
+        }
+
+        impl ScsiBlockDevice for ConcurrencyProbe {
+            fn read(&self, _lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+                self.peak.fetch_max(active, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(200));
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                Ok(vec![0u8; (blocks * block_size) as usize])
+            }
+
+            fn write(&mut self, _lba: u64, _data: &[u8], _block_size: u32) -> ScsiResult<()> {
+                Ok(())
+            }
+
+            fn capacity(&self) -> u64 {
+                1000
+            }
+
+            fn block_size(&self) -> u32 {
+                512
+            }
+        }
+
+        let device = Arc::new(RwLock::new(CountingDevice::new(ConcurrencyProbe {
+            active: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        })));
+        let read_10 = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let device = Arc::clone(&device);
+                thread::spawn(move || execute_command(&read_10, &device).unwrap())
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap().data.len(), 512);
+        }
+
+        assert_eq!(device.read().unwrap().peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_builder_command_queue() {
+        let target = IscsiTarget::builder()
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.queue_depth, 32);
+        assert_eq!(target.settings.command_window, 32);
+        assert_eq!(target.worker_threads, 4);
+
+        let target = IscsiTarget::builder()
+            .queue_depth(8)
+            .worker_threads(2)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.queue_depth, 8);
+        assert_eq!(target.settings.command_window, 8);
+        assert_eq!(target.worker_threads, 2);
+
+        let target = IscsiTarget::builder()
+            .command_window(64)
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        assert_eq!(target.settings.command_window, 64);
+
+        let result = IscsiTarget::builder()
+            .queue_depth(0)
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+
+        let result = IscsiTarget::builder()
+            .command_window(0)
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+
+        let result = IscsiTarget::builder()
+            .worker_threads(0)
+            .build(MockDevice::new(1000, 512));
+        assert!(matches!(result, Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_running_flag() {
+        let device = MockDevice::new(1000, 512);
+        let target = IscsiTarget::builder()
+            .build(device)
+            .unwrap();
+
+        assert!(!target.is_running());
+        target.settings.running.store(true, Ordering::SeqCst);
+        assert!(target.is_running());
+        target.stop();
+        assert!(!target.is_running());
+    }
+
+    #[test]
+    fn test_pdu_roundtrip() {
+        // Test that PDU serialization/deserialization works correctly
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::NOP_IN;
+        pdu.flags = flags::FINAL;
+        pdu.itt = 0x12345678;
+
+        let bytes = pdu.to_bytes();
+        let parsed = IscsiPdu::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed.opcode, opcode::NOP_IN);
         assert_eq!(parsed.flags, flags::FINAL);
         assert_eq!(parsed.itt, 0x12345678);
     }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<TargetEvent>>,
+    }
+
+    impl TargetObserver for RecordingObserver {
+        fn on_event(&self, event: &TargetEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_data_out_sequencing() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let observer = Arc::new(RecordingObserver::default());
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.set_observer(Some(observer.clone()));
+        session.params.max_burst_length = 1024;
+        session.params.max_outstanding_r2t = 1;
+
+        let data_out = |ttt: u32, data_sn: u32, offset: u32, last: bool| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_DATA_OUT;
+            pdu.flags = if last { flags::FINAL } else { 0 };
+            pdu.itt = 0x70;
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            pdu.specific[16..20].copy_from_slice(&data_sn.to_be_bytes());
+            pdu.specific[20..24].copy_from_slice(&offset.to_be_bytes());
+            pdu.data = vec![0x55; 512];
+            pdu
+        };
+        let ttt_of = |pdu: &IscsiPdu| u32::from_be_bytes(pdu.specific[0..4].try_into().unwrap());
+
+        // WRITE (10) of 4 blocks, no immediate data: two bursts of 1024 bytes
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::WRITE;
+        command.itt = 0x70;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&2048u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x2A, 0, 0, 0, 0, 0x20, 0, 0, 4, 0]);
+
+        // MaxOutstandingR2T=1: only the first burst is requested up front
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::R2T);
+        let first = ttt_of(&response[0]);
+
+        let response = handle_full_feature_phase(&mut session, &data_out(first, 0, 0, false), &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty());
+        let response = handle_full_feature_phase(&mut session, &data_out(first, 1, 512, true), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::R2T);
+        assert_eq!(&response[0].specific[16..20], &1u32.to_be_bytes(), "R2TSN");
+        assert_eq!(&response[0].specific[20..24], &1024u32.to_be_bytes(), "R2T buffer offset");
+        let second = ttt_of(&response[0]);
+        assert_ne!(first, second);
+
+        // DataSN restarts per sequence; repeating the old DataSN is a gap
+        let response = handle_full_feature_phase(&mut session, &data_out(second, 2, 1024, false), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 2);
+        assert_eq!(response[0].opcode, opcode::REJECT);
+        assert_eq!(response[0].reject_reason(), pdu::reject_reason::PROTOCOL_ERROR);
+        assert_eq!(response[1].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[1].specific[1], scsi_status::CHECK_CONDITION);
+        assert!(session.pending_writes.is_empty());
+
+        let events = observer.events.lock().unwrap();
+        assert_eq!(*events, vec![TargetEvent::WriteAborted {
+            itt: 0x70,
+            lun: 0,
+            lba: 0x20,
+            transfer_length: 4,
+            bytes_received: 1024,
+            reason: AbortReason::ProtocolError,
+        }]);
+    }
+
+    #[test]
+    fn test_stalled_write_retransmits_then_aborts() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let observer = Arc::new(RecordingObserver::default());
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.params.error_recovery_level = 1;
+        session.set_observer(Some(observer.clone()));
+        let retransmit = R2tRetransmit { timeout: Duration::from_secs(5), retries: 1 };
+
+        // WRITE (10) of 2 blocks, no immediate data
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::WRITE;
+        command.itt = 0x80;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&1024u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x2A, 0, 0, 0, 0, 0x30, 0, 0, 2, 0]);
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::R2T);
+        let r2t = response[0].clone();
+
+        // Half the sequence arrives, then the initiator goes quiet
+        let mut data_out = IscsiPdu::new();
+        data_out.opcode = opcode::SCSI_DATA_OUT;
+        data_out.itt = 0x80;
+        data_out.specific[0..4].copy_from_slice(&r2t.specific[0..4]);
+        data_out.data = vec![0x11; 512];
+        assert!(handle_full_feature_phase(&mut session, &data_out, &luns, "iqn.test", &[]).unwrap().is_empty());
+
+        // Nothing is due before the timeout
+        assert!(expire_pending_writes(&mut session, &luns, retransmit, Instant::now()).is_empty());
+
+        // The same R2T goes out again and the sequence restarts
+        let later = Instant::now() + Duration::from_secs(6);
+        let response = expire_pending_writes(&mut session, &luns, retransmit, later);
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::R2T);
+        assert_eq!(response[0].specific[0..28], r2t.specific[0..28]);
+        assert_eq!(session.pending_writes[&0x80].bytes_received, 0);
+
+        // Retries exhausted: the task is aborted
+        let response = expire_pending_writes(&mut session, &luns, retransmit, later + Duration::from_secs(6));
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::CHECK_CONDITION);
+        let data = &response[0].data;
+        assert_eq!((data[2] & 0x0F, data[12], data[13]), (crate::scsi::sense_key::ABORTED_COMMAND, 0x4B, 0x06));
+        assert!(session.pending_writes.is_empty());
+
+        let events = observer.events.lock().unwrap();
+        assert_eq!(*events, vec![TargetEvent::WriteAborted {
+            itt: 0x80,
+            lun: 0,
+            lba: 0x30,
+            transfer_length: 2,
+            bytes_received: 0,
+            reason: AbortReason::Timeout,
+        }]);
+    }
+
+    #[test]
+    fn test_stalled_write_at_erl_0_aborts_without_retransmit() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        assert_eq!(session.params.error_recovery_level, 0);
+        let retransmit = R2tRetransmit { timeout: Duration::from_secs(5), retries: 2 };
+
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::WRITE;
+        command.itt = 0x81;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&1024u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x2A, 0, 0, 0, 0, 0x30, 0, 0, 2, 0]);
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::R2T);
+
+        let response = expire_pending_writes(&mut session, &luns, retransmit, Instant::now() + Duration::from_secs(6));
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::CHECK_CONDITION);
+        let data = &response[0].data;
+        assert_eq!((data[2] & 0x0F, data[12], data[13]), (crate::scsi::sense_key::ABORTED_COMMAND, 0x4B, 0x06));
+        assert!(session.pending_writes.is_empty());
+    }
+
+    #[test]
+    fn test_send_targets_continuation() {
+        let mut session = IscsiSession::new();
+        session.params.max_xmit_data_segment_length = 512;
+        let portals: Vec<(String, u16)> = (0..40)
+            .map(|i| (format!("10.0.{}.1:3260", i), i as u16 + 1))
+            .collect();
+
+        let text_request = |itt: u32, ttt: u32, data: &[u8]| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::TEXT_REQUEST;
+            pdu.flags = flags::FINAL;
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            pdu.data = data.to_vec();
+            pdu.data_length = data.len() as u32;
+            pdu
+        };
+
+        let mut response = handle_text_request(&mut session, &text_request(0x50, 0xFFFF_FFFF, b"SendTargets=All\0"), "iqn.test", &portals).unwrap();
+        let mut text = Vec::new();
+        let mut pdus = 0;
+        loop {
+            let pdu = response.pop().unwrap();
+            assert!(pdu.data.len() <= 512);
+            // Each part ends on a key=value boundary
+            assert_eq!(pdu.data.last(), Some(&0));
+            text.extend_from_slice(&pdu.data);
+            pdus += 1;
+            if pdu.flags & flags::CONTINUE == 0 {
+                assert_eq!(pdu.flags & flags::FINAL, flags::FINAL);
+                assert_eq!(BigEndian::read_u32(&pdu.specific[0..4]), 0xFFFF_FFFF);
+                break;
+            }
+            assert_eq!(pdu.flags & flags::FINAL, 0);
+            let ttt = BigEndian::read_u32(&pdu.specific[0..4]);
+            assert_ne!(ttt, 0xFFFF_FFFF);
+            response = handle_text_request(&mut session, &text_request(0x50, ttt, &[]), "iqn.test", &portals).unwrap();
+        }
+        assert!(pdus > 1);
+        assert!(session.pending_text_response.is_none());
+        assert_eq!(text, serialize_text_parameters(&session.handle_send_targets("All", "iqn.test", &portals)));
+
+        // A TTT the target never issued is rejected
+        let response = handle_text_request(&mut session, &text_request(0x51, 0x1234, &[]), "iqn.test", &portals).unwrap();
+        assert_eq!(response[0].opcode, opcode::REJECT);
+        assert_eq!(response[0].reject_reason(), pdu::reject_reason::INVALID_PDU_FIELD);
+
+        // SendTargets naming another target lists nothing
+        let response = handle_text_request(&mut session, &text_request(0x52, 0xFFFF_FFFF, b"SendTargets=iqn.other\0"), "iqn.test", &portals[..1]).unwrap();
+        assert_eq!(response[0].opcode, opcode::TEXT_RESPONSE);
+        assert!(response[0].data.is_empty());
+        let response = handle_text_request(&mut session, &text_request(0x53, 0xFFFF_FFFF, b"SendTargets=iqn.test\0"), "iqn.test", &portals[..1]).unwrap();
+        assert_eq!(pdu::parse_text_parameters(&response[0].data).unwrap()[0], ("TargetName".to_string(), "iqn.test".to_string()));
+    }
+
+    #[test]
+    fn test_logout_aborts_pending_writes() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let observer = Arc::new(RecordingObserver::default());
+
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.set_observer(Some(observer.clone()));
+        session.pending_writes.insert(0x10, PendingWrite {
+            lba: 8,
+            transfer_length: 4,
+            block_size: 512,
+            bytes_received: 512,
+            r2t_sn: 1,
+            lun: 0,
+            ..PendingWrite::default()
+        });
+
+        // Closing a CID other than this connection's leaves the WRITE running
+        let mut logout = IscsiPdu::new();
+        logout.opcode = opcode::LOGOUT_REQUEST;
+        logout.flags = flags::FINAL | pdu::logout_reason::CLOSE_CONNECTION;
+        logout.itt = 0x1F;
+        logout.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        logout.specific[0..2].copy_from_slice(&5u16.to_be_bytes());
+        let response = handle_full_feature_phase(&mut session, &logout, &luns, "iqn.test", &[("127.0.0.1:3260".to_string(), 1)]).unwrap();
+        assert_eq!(response[0].logout_response_code(), pdu::logout_response::CID_NOT_FOUND);
+        assert_eq!(session.pending_writes.len(), 1);
+        assert!(observer.events.lock().unwrap().is_empty());
+
+        let mut logout = IscsiPdu::new();
+        logout.opcode = opcode::LOGOUT_REQUEST;
+        logout.flags = flags::FINAL;
+        logout.itt = 0x20;
+        logout.specific[4..8].copy_from_slice(&2u32.to_be_bytes());
+
+        let response = handle_full_feature_phase(&mut session, &logout, &luns, "iqn.test", &[("127.0.0.1:3260".to_string(), 1)]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::LOGOUT_RESPONSE);
+        assert_eq!(response[0].logout_response_code(), pdu::logout_response::SUCCESS);
+        assert!(session.pending_writes.is_empty());
+
+        let events = observer.events.lock().unwrap();
+        assert_eq!(*events, vec![TargetEvent::WriteAborted {
+            itt: 0x10,
+            lun: 0,
+            lba: 8,
+            transfer_length: 4,
+            bytes_received: 512,
+            reason: AbortReason::Logout,
+        }]);
+    }
+
+    #[test]
+    fn test_mode_select_parameter_list_via_r2t() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+
+        // MODE SELECT (10), PF=1, 28-byte parameter list: header + Caching page with WCE
+        let mut params = vec![0u8; 8];
+        params.extend_from_slice(&[0x08, 0x12, 0x04]);
+        params.resize(28, 0);
+
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::WRITE;
+        command.itt = 0x30;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&28u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x55, 0x10, 0, 0, 0, 0, 0, 0, 28, 0]);
+
+        // No immediate data: the target asks for the parameter list
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::R2T);
+        let ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+
+        let mut data_out = IscsiPdu::new();
+        data_out.opcode = opcode::SCSI_DATA_OUT;
+        data_out.flags = flags::FINAL;
+        data_out.itt = 0x30;
+        data_out.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+        data_out.data = params.clone();
+
+        let response = handle_full_feature_phase(&mut session, &data_out, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert!(session.pending_parameter_lists.is_empty());
+        assert!(device.read().unwrap().write_cache);
+
+        // Immediate data path: turn the cache back off
+        params[10] = 0;
+        command.itt = 0x31;
+        command.specific[4..8].copy_from_slice(&2u32.to_be_bytes());
+        command.data = params;
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert!(!device.read().unwrap().write_cache);
+    }
+
+    #[test]
+    fn test_misaligned_write_through_lun_table() {
+        use crate::backends::{BlockGeometry, GeometryBlockDevice, MemBlockDevice};
+
+        let geometry = BlockGeometry { aligned_writes: true, ..BlockGeometry::emulated_512() };
+        let device = GeometryBlockDevice::new(MemBlockDevice::new(64, 512), geometry).unwrap();
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::new(RwLock::new(CountingDevice::new(device))))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+
+        let write = |itt: u32, cmd_sn: u32, lba: u8, blocks: u8| {
+            let mut command = IscsiPdu::new();
+            command.opcode = opcode::SCSI_COMMAND;
+            command.flags = flags::FINAL | flags::WRITE;
+            command.itt = itt;
+            command.specific[0..4].copy_from_slice(&(u32::from(blocks) * 512).to_be_bytes());
+            command.specific[4..8].copy_from_slice(&cmd_sn.to_be_bytes());
+            command.specific[12..22].copy_from_slice(&[0x2A, 0, 0, 0, 0, lba, 0, 0, blocks, 0]);
+            command.data = vec![0xA5; usize::from(blocks) * 512];
+            command
+        };
+
+        // WRITE (10) of one 512-byte block inside a 4096-byte physical block
+        let response = handle_full_feature_phase(&mut session, &write(0x40, 1, 1, 1), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::CHECK_CONDITION);
+        // UNALIGNED WRITE COMMAND
+        let data = &response[0].data;
+        assert_eq!((data[2] & 0x0F, data[12], data[13]), (crate::scsi::sense_key::ILLEGAL_REQUEST, 0x21, 0x04));
+
+        // A whole physical block goes through
+        let response = handle_full_feature_phase(&mut session, &write(0x41, 2, 8, 8), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+    }
+
+    #[test]
+    fn test_bidirectional_command() {
+        /// Implements XDWRITEREAD (10): writes the Data-Out and returns it
+        /// XORed with the blocks it replaced
+        struct XorDevice(MockDevice);
+
+        impl ScsiBlockDevice for XorDevice {
+            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                self.0.read(lba, blocks, block_size)
+            }
+
+            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+                self.0.write(lba, data, block_size)
+            }
+
+            fn capacity(&self) -> u64 {
+                self.0.capacity()
+            }
+
+            fn block_size(&self) -> u32 {
+                self.0.block_size()
+            }
+
+            fn bidirectional(&mut self, cdb: &[u8], data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+                let lba = u32::from_be_bytes(cdb[2..6].try_into().unwrap()) as u64;
+                let old = self.0.read(lba, (data_out.len() / 512) as u32, 512)?;
+                self.0.write(lba, data_out, 512)?;
+                Ok(old.iter().zip(data_out).map(|(a, b)| a ^ b).collect())
+            }
+        }
+
+        let mut mock = MockDevice::new(100, 512);
+        mock.data[..512].fill(0x0F);
+        let device = Arc::new(RwLock::new(CountingDevice::new(XorDevice(mock))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.open_command_window(8);
+
+        // XDWRITEREAD (10) of one block at LBA 0, asking for only 256 bytes back
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::READ | flags::WRITE;
+        command.itt = 0x40;
+        command.specific[0..4].copy_from_slice(&512u32.to_be_bytes());
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x53, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        command.ahs.push(pdu::Ahs::BidiReadDataLength(256));
+
+        // Without immediate data the Data-Out is fetched with R2T
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::R2T);
+        let mut data_out = IscsiPdu::new();
+        data_out.opcode = opcode::SCSI_DATA_OUT;
+        data_out.flags = flags::FINAL;
+        data_out.itt = 0x40;
+        data_out.specific[0..4].copy_from_slice(&response[0].specific[0..4]);
+        data_out.data = vec![0xFF; 512];
+
+        // Data-In without status, then a SCSI Response with the read overflow
+        let response = handle_full_feature_phase(&mut session, &data_out, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 2);
+        assert_eq!(response[0].opcode, opcode::SCSI_DATA_IN);
+        assert_eq!(response[0].flags & flags::STATUS, 0);
+        assert_eq!(response[0].data, vec![0xF0; 256]);
+        assert_eq!(response[1].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[1].specific[1], scsi_status::GOOD);
+        assert_eq!(response[1].flags & flags::BIDI_READ_RESIDUAL_OVERFLOW, flags::BIDI_READ_RESIDUAL_OVERFLOW);
+        assert_eq!(response[1].bidi_read_residual_count(), 256);
+        assert_eq!(response[1].residual_count(), 0);
+        assert_eq!(device.read().unwrap().read(0, 1, 512).unwrap(), vec![0xFF; 512]);
+
+        // Immediate data, and more Data-In room than the command fills
+        command.itt = 0x41;
+        command.specific[4..8].copy_from_slice(&2u32.to_be_bytes());
+        command.ahs = vec![pdu::Ahs::BidiReadDataLength(1024)];
+        command.data = vec![0xFF; 512];
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].data, vec![0; 512]);
+        assert_eq!(response[1].flags & flags::BIDI_READ_RESIDUAL_UNDERFLOW, flags::BIDI_READ_RESIDUAL_UNDERFLOW);
+        assert_eq!(response[1].bidi_read_residual_count(), 512);
+
+        // A backend without bidirectional support refuses the command
+        let plain = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(100, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(plain)));
+        command.itt = 0x42;
+        command.specific[4..8].copy_from_slice(&3u32.to_be_bytes());
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].specific[1], scsi_status::CHECK_CONDITION);
+        assert_eq!(response[0].data[12], 0x20, "INVALID COMMAND OPERATION CODE");
+    }
+
+    #[test]
+    fn test_read_residuals() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.exp_cmd_sn = 0x60;
+        session.max_cmd_sn = 0x60;
+
+        // CmdSN follows the ITT
+        let command = |itt: u32, edtl: u32, cdb: &[u8]| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_COMMAND;
+            pdu.flags = flags::FINAL | flags::READ;
+            pdu.itt = itt;
+            pdu.specific[4..8].copy_from_slice(&itt.to_be_bytes());
+            pdu.specific[0..4].copy_from_slice(&edtl.to_be_bytes());
+            pdu.specific[12..12 + cdb.len()].copy_from_slice(cdb);
+            pdu
+        };
+        let residual_flags = |pdu: &IscsiPdu| pdu.flags & (flags::RESIDUAL_OVERFLOW | flags::RESIDUAL_UNDERFLOW);
+
+        // INQUIRY with a 255-byte allocation length returns less: underflow
+        let response = handle_full_feature_phase(&mut session, &command(0x60, 255, &[0x12, 0, 0, 0, 255, 0]), &luns, "iqn.test", &[]).unwrap();
+        let last = response.last().unwrap();
+        assert_eq!(last.opcode, opcode::SCSI_DATA_IN);
+        assert_eq!(residual_flags(last), flags::RESIDUAL_UNDERFLOW);
+        assert_eq!(last.residual_count(), 255 - last.data.len() as u32);
+
+        // READ (10) of 2 blocks with room for only one: overflow, data capped
+        let response = handle_full_feature_phase(&mut session, &command(0x61, 512, &[0x28, 0, 0, 0, 0, 0, 0, 0, 2, 0]), &luns, "iqn.test", &[]).unwrap();
+        let total: usize = response.iter().map(|pdu| pdu.data.len()).sum();
+        assert_eq!(total, 512);
+        let last = response.last().unwrap();
+        assert_eq!(residual_flags(last), flags::RESIDUAL_OVERFLOW);
+        assert_eq!(last.residual_count(), 512);
+
+        // An exact-length read carries no residual
+        let response = handle_full_feature_phase(&mut session, &command(0x62, 1024, &[0x28, 0, 0, 0, 0, 0, 0, 0, 2, 0]), &luns, "iqn.test", &[]).unwrap();
+        let last = response.last().unwrap();
+        assert_eq!(residual_flags(last), 0);
+        assert_eq!(last.residual_count(), 0);
+
+        // Failed read: no data transferred at all
+        let response = handle_full_feature_phase(&mut session, &command(0x63, 512, &[0x28, 0, 0, 0, 0x10, 0, 0, 0, 1, 0]), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(residual_flags(&response[0]), flags::RESIDUAL_UNDERFLOW);
+        assert_eq!(response[0].residual_count(), 512);
+    }
+
+    #[test]
+    fn test_read_sequences_respect_max_burst_length() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.params.max_xmit_data_segment_length = 768;
+        session.params.max_burst_length = 1024;
+
+        // READ (10) of 5 blocks: sequences of 1024, 1024 and 512 bytes
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::READ;
+        command.itt = 0x70;
+        command.specific[0..4].copy_from_slice(&2560u32.to_be_bytes());
+        command.specific[4..8].copy_from_slice(&session.exp_cmd_sn.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x28, 0, 0, 0, 0, 0, 0, 0, 5, 0]);
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+
+        let lengths: Vec<usize> = response.iter().map(|pdu| pdu.data.len()).collect();
+        assert_eq!(lengths, [768, 256, 768, 256, 512]);
+        let final_flags: Vec<bool> = response.iter().map(|pdu| pdu.flags & flags::FINAL != 0).collect();
+        assert_eq!(final_flags, [false, true, false, true, true]);
+        let status_flags: Vec<bool> = response.iter().map(|pdu| pdu.flags & flags::STATUS != 0).collect();
+        assert_eq!(status_flags, [false, false, false, false, true]);
+        // DataSN and the buffer offset run on across sequences
+        let data_sns: Vec<u32> = response.iter().map(|pdu| BigEndian::read_u32(&pdu.specific[16..20])).collect();
+        assert_eq!(data_sns, [0, 1, 2, 3, 4]);
+        let offsets: Vec<u32> = response.iter().map(|pdu| BigEndian::read_u32(&pdu.specific[20..24])).collect();
+        assert_eq!(offsets, [0, 768, 1024, 1792, 2048]);
+    }
+
+    #[test]
+    fn test_extended_cdb_reaches_handler() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+
+        // A 32-byte variable-length CDB: unsupported, but parsed as one command
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = opcode::SCSI_COMMAND;
+        pdu.flags = flags::FINAL;
+        pdu.itt = 0x70;
+        pdu.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        pdu.specific[12] = 0x7F;
+        pdu.specific[19] = 0x18;
+        pdu.ahs.push(crate::pdu::Ahs::ExtendedCdb(vec![0; 16]));
+        let pdu = IscsiPdu::from_bytes(&pdu.to_bytes()).unwrap();
+        assert_eq!(pdu.parse_scsi_command().unwrap().cdb.len(), 32);
+
+        let response = handle_full_feature_phase(&mut session, &pdu, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::CHECK_CONDITION);
+        assert_eq!(response[0].itt, 0x70);
+    }
+
+    #[test]
+    fn test_write_past_end_rejected_before_device() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(100, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+
+        // WRITE (10) of 4 blocks at LBA 98 with all data immediate
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::WRITE;
+        command.itt = 0x50;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&2048u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x2A, 0, 0, 0, 0, 98, 0, 0, 4, 0]);
+        command.data = vec![0xAA; 2048];
+
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::CHECK_CONDITION);
+        assert_eq!(response[0].data[12], crate::scsi::asc::LBA_OUT_OF_RANGE);
+        assert_eq!(&response[0].data[3..7], &98u32.to_be_bytes());
+        assert!(session.pending_writes.is_empty());
+        assert!(device.read().unwrap().data.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_write_12_and_read_12() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(100, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+
+        // WRITE (12) of 2 blocks at LBA 10 with all data immediate
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::WRITE;
+        command.itt = 0x51;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&1024u32.to_be_bytes());
+        command.specific[12..24].copy_from_slice(&[0xAA, 0, 0, 0, 0, 10, 0, 0, 0, 2, 0, 0]);
+        command.data = vec![0xC3; 1024];
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert!(device.read().unwrap().data[10 * 512..12 * 512].iter().all(|&b| b == 0xC3));
+
+        // READ (12) of the same blocks
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::READ;
+        command.itt = 0x52;
+        command.specific[4..8].copy_from_slice(&2u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&1024u32.to_be_bytes());
+        command.specific[12..24].copy_from_slice(&[0xA8, 0, 0, 0, 0, 10, 0, 0, 0, 2, 0, 0]);
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::SCSI_DATA_IN);
+        assert_eq!(response[0].data, vec![0xC3; 1024]);
+    }
+
+    #[test]
+    fn test_fua_write_flushes_before_status() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(100, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        let flushes = || device.read().unwrap().flushes;
+
+        // WRITE (10) of 2 blocks at LBA 10 with the first block immediate
+        let write = |itt: u32, cmd_sn: u32, fua: bool| {
+            let mut command = IscsiPdu::new();
+            command.opcode = opcode::SCSI_COMMAND;
+            command.flags = flags::FINAL | flags::WRITE;
+            command.itt = itt;
+            command.specific[4..8].copy_from_slice(&cmd_sn.to_be_bytes());
+            command.specific[0..4].copy_from_slice(&1024u32.to_be_bytes());
+            command.specific[12..22].copy_from_slice(&[0x2A, if fua { 0x08 } else { 0 }, 0, 0, 0, 10, 0, 0, 2, 0]);
+            command.data = vec![0xC3; 512];
+            command
+        };
+        let data_out = |itt: u32, ttt: u32| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_DATA_OUT;
+            pdu.flags = flags::FINAL;
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            pdu.specific[20..24].copy_from_slice(&512u32.to_be_bytes());
+            pdu.data = vec![0xC3; 512];
+            pdu
+        };
+
+        let response = handle_full_feature_phase(&mut session, &write(0x70, 1, false), &luns, "iqn.test", &[]).unwrap();
+        let ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+        let response = handle_full_feature_phase(&mut session, &data_out(0x70, ttt), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert_eq!(flushes(), 0);
+
+        // With FUA, only once the last of the data is written
+        let response = handle_full_feature_phase(&mut session, &write(0x71, 2, true), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::R2T);
+        assert_eq!(flushes(), 0);
+        let ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+        let response = handle_full_feature_phase(&mut session, &data_out(0x71, ttt), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert_eq!(flushes(), 1);
+
+        // All data immediate
+        let mut command = write(0x72, 3, true);
+        command.data = vec![0xC3; 1024];
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert_eq!(flushes(), 2);
+    }
+
+    #[test]
+    fn test_verify_bytchk_via_r2t() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        device.write().unwrap().data[5 * 512 + 600] = 0x42;
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+
+        // VERIFY (10) BYTCHK=1 of 2 blocks at LBA 5, first block immediate
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::WRITE;
+        command.itt = 0x90;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&1024u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x2F, 0x02, 0, 0, 0, 5, 0, 0, 2, 0]);
+        command.data = vec![0u8; 512];
+
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::R2T);
+        assert_eq!(&response[0].specific[20..24], &512u32.to_be_bytes(), "R2T buffer offset");
+
+        let mut data_out = IscsiPdu::new();
+        data_out.opcode = opcode::SCSI_DATA_OUT;
+        data_out.flags = flags::FINAL;
+        data_out.itt = 0x90;
+        data_out.specific[0..4].copy_from_slice(&response[0].specific[0..4]);
+        data_out.specific[20..24].copy_from_slice(&512u32.to_be_bytes());
+        data_out.data = vec![0u8; 512];
+
+        // The medium differs at byte 600 of the transfer
+        let response = handle_full_feature_phase(&mut session, &data_out, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].specific[1], scsi_status::CHECK_CONDITION);
+        assert_eq!(response[0].data[2], crate::scsi::sense_key::MISCOMPARE);
+        assert_eq!(&response[0].data[3..7], &600u32.to_be_bytes());
+        assert!(session.pending_parameter_lists.is_empty());
+    }
+
+    #[test]
+    fn test_parameter_list_r2ts_respect_max_outstanding() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.params.max_burst_length = 512;
+        session.params.max_outstanding_r2t = 2;
+
+        // VERIFY (10) BYTCHK=1 of 4 blocks at LBA 0, no immediate data
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::WRITE;
+        command.itt = 0x91;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&2048u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x2F, 0x02, 0, 0, 0, 0, 0, 0, 4, 0]);
+
+        // Only MaxOutstandingR2T sequences are requested up front, each with its own TTT
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        let offsets: Vec<&[u8]> = response.iter().map(|r2t| &r2t.specific[20..24]).collect();
+        assert_eq!(offsets, vec![&0u32.to_be_bytes()[..], &512u32.to_be_bytes()[..]]);
+        let mut ttts: HashMap<u32, [u8; 4]> = HashMap::new();
+        for r2t in &response {
+            let offset = u32::from_be_bytes(r2t.specific[20..24].try_into().unwrap());
+            ttts.insert(offset, r2t.specific[0..4].try_into().unwrap());
+        }
+        assert_ne!(ttts[&0], ttts[&512], "outstanding R2Ts share a TTT");
+
+        let data_out = |ttt: [u8; 4], offset: u32| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_DATA_OUT;
+            pdu.flags = flags::FINAL;
+            pdu.itt = 0x91;
+            pdu.specific[0..4].copy_from_slice(&ttt);
+            pdu.specific[20..24].copy_from_slice(&offset.to_be_bytes());
+            pdu.data = vec![0u8; 512];
+            pdu
+        };
+
+        // Each completed sequence releases one more R2T
+        for (offset, next_offset, r2t_sn) in [(0u32, 1024u32, 2u32), (512, 1536, 3)] {
+            let response =
+                handle_full_feature_phase(&mut session, &data_out(ttts[&offset], offset), &luns, "iqn.test", &[]).unwrap();
+            assert_eq!(response.len(), 1);
+            assert_eq!(response[0].opcode, opcode::R2T);
+            assert_eq!(&response[0].specific[16..20], &r2t_sn.to_be_bytes(), "R2TSN");
+            assert_eq!(&response[0].specific[20..24], &next_offset.to_be_bytes(), "R2T buffer offset");
+            ttts.insert(next_offset, response[0].specific[0..4].try_into().unwrap());
+        }
+
+        let response = handle_full_feature_phase(&mut session, &data_out(ttts[&1024], 1024), &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty(), "all sequences already requested");
+        let response = handle_full_feature_phase(&mut session, &data_out(ttts[&1536], 1536), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert!(session.pending_parameter_lists.is_empty());
+    }
+
+    #[test]
+    fn test_parameter_list_data_out_checked_like_write() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.params.initial_r2t = false;
+        session.params.first_burst_length = 512;
+
+        // VERIFY (10) BYTCHK=1 of 4 blocks, no immediate data, unsolicited Data-Out to follow
+        let verify = |itt: u32, cmd_sn: u32| {
+            let mut command = IscsiPdu::new();
+            command.opcode = opcode::SCSI_COMMAND;
+            command.flags = flags::WRITE;
+            command.itt = itt;
+            command.specific[4..8].copy_from_slice(&cmd_sn.to_be_bytes());
+            command.specific[0..4].copy_from_slice(&2048u32.to_be_bytes());
+            command.specific[12..22].copy_from_slice(&[0x2F, 0x02, 0, 0, 0, 0, 0, 0, 4, 0]);
+            command
+        };
+        let data_out = |itt: u32, ttt: u32, data_sn: u32, offset: u32| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_DATA_OUT;
+            pdu.flags = flags::FINAL;
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            pdu.specific[16..20].copy_from_slice(&data_sn.to_be_bytes());
+            pdu.specific[20..24].copy_from_slice(&offset.to_be_bytes());
+            pdu.data = vec![0u8; 512];
+            pdu
+        };
+        let assert_rejected = |response: &[IscsiPdu]| {
+            assert_eq!(response.len(), 2);
+            assert_eq!(response[0].opcode, opcode::REJECT);
+            assert_eq!(response[0].reject_reason(), pdu::reject_reason::PROTOCOL_ERROR);
+            assert_eq!(response[1].opcode, opcode::SCSI_RESPONSE);
+            assert_eq!(response[1].specific[1], scsi_status::CHECK_CONDITION);
+        };
+
+        // Only the data past FirstBurstLength is requested with R2T
+        let response = handle_full_feature_phase(&mut session, &verify(0x92, 1), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(&response[0].specific[20..24], &512u32.to_be_bytes(), "R2T buffer offset");
+        let ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+        let response = handle_full_feature_phase(&mut session, &data_out(0x92, 0xFFFF_FFFF, 0, 0), &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty());
+
+        // A repeated unsolicited PDU is not counted twice
+        let response = handle_full_feature_phase(&mut session, &data_out(0x92, 0xFFFF_FFFF, 0, 0), &luns, "iqn.test", &[]).unwrap();
+        assert_rejected(&response);
+        assert!(session.pending_parameter_lists.is_empty());
+        let response = handle_full_feature_phase(&mut session, &data_out(0x92, ttt, 0, 512), &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty(), "the task has ended");
+
+        // Unsolicited data beyond FirstBurstLength
+        handle_full_feature_phase(&mut session, &verify(0x93, 2), &luns, "iqn.test", &[]).unwrap();
+        let response = handle_full_feature_phase(&mut session, &data_out(0x93, 0xFFFF_FFFF, 0, 512), &luns, "iqn.test", &[]).unwrap();
+        assert_rejected(&response);
+
+        // Data for the R2T's window out of DataSN order
+        let response = handle_full_feature_phase(&mut session, &verify(0x94, 3), &luns, "iqn.test", &[]).unwrap();
+        let ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+        let response = handle_full_feature_phase(&mut session, &data_out(0x94, ttt, 1, 512), &luns, "iqn.test", &[]).unwrap();
+        assert_rejected(&response);
+        assert!(session.pending_parameter_lists.is_empty());
+    }
+
+    #[test]
+    fn test_scsi2_reservation_conflicts() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let session_for = |initiator: &str| {
+            let mut session = IscsiSession::new();
+            session.state = SessionState::FullFeaturePhase;
+            session.params.initiator_name = initiator.to_string();
+            session.isid = [0x02, 0x3d, 0, 0, 0, 1];
+            session
+        };
+        let mut holder = session_for("iqn.test:holder");
+        let mut other = session_for("iqn.test:other");
+
+        let command = |itt: u32, cdb: &[u8], data: Vec<u8>| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_COMMAND;
+            pdu.flags = flags::FINAL | if data.is_empty() { flags::READ } else { flags::WRITE };
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&(data.len().max(512) as u32).to_be_bytes());
+            pdu.specific[12..12 + cdb.len()].copy_from_slice(cdb);
+            pdu.data = data;
+            pdu
+        };
+        // Each nexus numbers its own commands
+        let status = |session: &mut IscsiSession, pdu: &IscsiPdu| {
+            let mut pdu = pdu.clone();
+            pdu.specific[4..8].copy_from_slice(&session.exp_cmd_sn.to_be_bytes());
+            let response = handle_full_feature_phase(session, &pdu, &luns, "iqn.test", &[]).unwrap();
+            let last = response.last().unwrap();
+            match last.opcode {
+                opcode::SCSI_RESPONSE => last.specific[1],
+                _ => last.version_or_reserved as u8,
+            }
+        };
+
+        assert_eq!(status(&mut holder, &command(1, &[0x16, 0, 0, 0, 0, 0], vec![])), scsi_status::GOOD);
+        assert_eq!(status(&mut other, &command(2, &[0x16, 0, 0, 0, 0, 0], vec![])), scsi_status::RESERVATION_CONFLICT);
+
+        // The other nexus may identify the unit but not touch the medium
+        assert_eq!(status(&mut other, &command(3, &[0x12, 0, 0, 0, 36, 0], vec![])), scsi_status::GOOD);
+        let read = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        assert_eq!(status(&mut other, &command(4, &read, vec![])), scsi_status::RESERVATION_CONFLICT);
+        let write = [0x2A, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        assert_eq!(status(&mut other, &command(5, &write, vec![0xEE; 512])), scsi_status::RESERVATION_CONFLICT);
+        assert_eq!(device.read().unwrap().data[0], 0, "conflicting immediate data is not written");
+        assert_eq!(status(&mut holder, &command(6, &read, vec![])), scsi_status::GOOD);
+
+        // A RELEASE from the other nexus changes nothing; the holder's releases the unit
+        assert_eq!(status(&mut other, &command(7, &[0x17, 0, 0, 0, 0, 0], vec![])), scsi_status::GOOD);
+        assert_eq!(status(&mut other, &command(8, &read, vec![])), scsi_status::RESERVATION_CONFLICT);
+        assert_eq!(status(&mut holder, &command(9, &[0x57, 0, 0, 0, 0, 0, 0, 0, 0, 0], vec![])), scsi_status::GOOD);
+        assert_eq!(status(&mut other, &command(10, &read, vec![])), scsi_status::GOOD);
+        assert!(!is_queueable(&command(11, &[0x16, 0, 0, 0, 0, 0], vec![])));
+    }
+
+    #[test]
+    fn test_reservation_spans_per_session_devices() {
+        let opener: DeviceOpener<MockDevice> = Arc::new(|_: &str, _| Ok(MockDevice::new(1000, 512)));
+        let table = Arc::new(LunTable::with_opener(&[0], opener).unwrap());
+        let session_for = |tpgt: u16| {
+            let mut session = IscsiSession::new();
+            session.state = SessionState::FullFeaturePhase;
+            session.params.initiator_name = "iqn.test:host".to_string();
+            session.params.target_portal_group_tag = tpgt;
+            session.isid = [0x02, 0x3d, 0, 0, 0, 1];
+            (session, SessionLuns::new(Arc::clone(&table)))
+        };
+        // Same initiator port, reaching the unit through two portal groups
+        let (mut holder, holder_luns) = session_for(1);
+        let (mut other, other_luns) = session_for(2);
+
+        let command = |session: &IscsiSession, itt: u32, cdb: &[u8]| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_COMMAND;
+            pdu.flags = flags::FINAL | flags::READ;
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&512u32.to_be_bytes());
+            pdu.specific[4..8].copy_from_slice(&session.exp_cmd_sn.to_be_bytes());
+            pdu.specific[12..12 + cdb.len()].copy_from_slice(cdb);
+            pdu
+        };
+        let read = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+
+        let pdu = command(&holder, 1, &[0x16, 0, 0, 0, 0, 0]);
+        let response = handle_full_feature_phase(&mut holder, &pdu, &holder_luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert_eq!(table.unit(0).unwrap().reservation.holder().as_deref(), Some("iqn.test:host,i,0x023d00000001,t,0x0001"));
+
+        // The other session has its own device, but the unit is still reserved
+        let pdu = command(&other, 1, &read);
+        let response = handle_full_feature_phase(&mut other, &pdu, &other_luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::RESERVATION_CONFLICT);
+        assert!(!Arc::ptr_eq(&holder_luns.get(0).unwrap(), &other_luns.get(0).unwrap()));
+    }
+
+    #[test]
+    fn test_write_with_unsolicited_data_out() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.params.initial_r2t = false;
+        session.params.first_burst_length = 1024;
+        session.params.data_pdu_in_order = false;
+
+        let data_out = |ttt: u32, data_sn: u32, offset: u32, fill: u8, last: bool| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_DATA_OUT;
+            pdu.flags = if last { flags::FINAL } else { 0 };
+            pdu.itt = 0x40;
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            pdu.specific[16..20].copy_from_slice(&data_sn.to_be_bytes());
+            pdu.specific[20..24].copy_from_slice(&offset.to_be_bytes());
+            pdu.data = vec![fill; 512];
+            pdu
+        };
+
+        // WRITE (10) of 8 blocks with 512 bytes immediate data, F bit clear
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::WRITE;
+        command.itt = 0x40;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&4096u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x2A, 0, 0, 0, 0, 0x10, 0, 0, 8, 0]);
+        command.data = vec![1u8; 512];
+
+        // R2T only covers what lies beyond FirstBurstLength
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::R2T);
+        assert_eq!(&response[0].specific[20..24], &1024u32.to_be_bytes(), "R2T buffer offset");
+        assert_eq!(&response[0].specific[24..28], &3072u32.to_be_bytes(), "R2T desired length");
+        let ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+
+        // Unsolicited Data-Out completes the first burst
+        let response = handle_full_feature_phase(&mut session, &data_out(0xFFFF_FFFF, 0, 512, 2, true), &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty());
+
+        // Solicited data, delivered out of order (DataPDUInOrder=No)
+        for (data_sn, (offset, fill)) in [(3584, 8), (1024, 3), (1536, 4), (2048, 5), (2560, 6)].into_iter().enumerate() {
+            let response = handle_full_feature_phase(&mut session, &data_out(ttt, data_sn as u32, offset, fill, false), &luns, "iqn.test", &[]).unwrap();
+            assert!(response.is_empty(), "Write completed early at offset {}", offset);
+        }
+        let response = handle_full_feature_phase(&mut session, &data_out(ttt, 5, 3072, 7, true), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert!(session.pending_writes.is_empty());
+
+        let device = device.read().unwrap();
+        for block in 0..8 {
+            let offset = (16 + block) * 512;
+            assert_eq!(device.data[offset], block as u8 + 1, "block {}", block);
+        }
+    }
+
+    #[test]
+    fn test_protected_write_straddling_data_out() {
+        let mut mock = MockDevice::new(100, 512);
+        mock.protection_type = 1;
+        let device = Arc::new(RwLock::new(CountingDevice::new(mock)));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+
+        let data: Vec<u8> = (0..3 * 512).map(|i| (i / 512 + 1) as u8).collect();
+        let interleaved = |first_ref_tag: u32| {
+            let pi: Vec<_> = data
+                .chunks(512)
+                .zip(first_ref_tag..)
+                .map(|(block, ref_tag)| ProtectionInfo::generate(block, 0, ref_tag))
+                .collect();
+            crate::protection::interleave(&data, &pi, 512)
+        };
+        let write = |itt: u32, cmd_sn: u32, wire: &[u8]| {
+            // WRITE (10) of 3 blocks at LBA 4 with WRPROTECT=1: 520 bytes per block
+            let mut command = IscsiPdu::new();
+            command.opcode = opcode::SCSI_COMMAND;
+            command.flags = flags::FINAL | flags::WRITE;
+            command.itt = itt;
+            command.specific[4..8].copy_from_slice(&cmd_sn.to_be_bytes());
+            command.specific[0..4].copy_from_slice(&1560u32.to_be_bytes());
+            command.specific[12..22].copy_from_slice(&[0x2A, 0x20, 0, 0, 0, 4, 0, 0, 3, 0]);
+            command.data = wire[..700].to_vec();
+            command
+        };
+        let data_out = |itt: u32, ttt: u32, data_sn: u32, offset: usize, chunk: &[u8], last: bool| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_DATA_OUT;
+            pdu.flags = if last { flags::FINAL } else { 0 };
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            pdu.specific[16..20].copy_from_slice(&data_sn.to_be_bytes());
+            pdu.specific[20..24].copy_from_slice(&(offset as u32).to_be_bytes());
+            pdu.data = chunk.to_vec();
+            pdu
+        };
+        let mut send = |itt: u32, cmd_sn: u32, wire: &[u8]| {
+            let response = handle_full_feature_phase(&mut session, &write(itt, cmd_sn, wire), &luns, "iqn.test", &[]).unwrap();
+            assert_eq!(response[0].opcode, opcode::R2T);
+            assert_eq!(&response[0].specific[20..24], &700u32.to_be_bytes(), "R2T buffer offset");
+            assert_eq!(&response[0].specific[24..28], &860u32.to_be_bytes(), "R2T desired length");
+            let ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+
+            // Neither Data-Out ends on a block boundary
+            let response = handle_full_feature_phase(&mut session, &data_out(itt, ttt, 0, 700, &wire[700..1100], false), &luns, "iqn.test", &[]).unwrap();
+            assert!(response.is_empty());
+            let response = handle_full_feature_phase(&mut session, &data_out(itt, ttt, 1, 1100, &wire[1100..], true), &luns, "iqn.test", &[]).unwrap();
+            assert_eq!(response[0].opcode, opcode::SCSI_RESPONSE);
+            response[0].clone()
+        };
+
+        // Reference tags starting at the LBA: written without the PI
+        let response = send(0x60, 1, &interleaved(4));
+        assert_eq!(response.specific[1], scsi_status::GOOD);
+        assert_eq!(&device.read().unwrap().data[4 * 512..7 * 512], &data[..]);
+
+        // Misplaced blocks are refused with the LBA of the first bad one
+        device.write().unwrap().data.fill(0);
+        let response = send(0x61, 2, &interleaved(9));
+        assert_eq!(response.specific[1], scsi_status::CHECK_CONDITION);
+        assert_eq!(response.data[2] & 0x0F, crate::scsi::sense_key::ABORTED_COMMAND);
+        assert_eq!(&response.data[12..14], &[0x10, 0x03]);
+        assert_eq!(&response.data[3..7], &4u32.to_be_bytes());
+        assert!(device.read().unwrap().data.iter().all(|&b| b == 0));
+        assert!(session.pending_writes.is_empty());
+    }
+
+    #[test]
+    fn test_lun_hot_plug() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let table = Arc::new(LunTable::new(Arc::clone(&device)));
+        let luns = SessionLuns::new(Arc::clone(&table));
+        let observer = Arc::new(RecordingObserver::default());
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.set_observer(Some(observer.clone()));
+        session.exp_cmd_sn = 1;
+        session.max_cmd_sn = 64;
+
+        // CmdSN follows the ITT
+        let command = |itt: u32, lun: u64, pdu_flags: u8, edtl: u32, cdb: &[u8]| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_COMMAND;
+            pdu.flags = flags::FINAL | pdu_flags;
+            pdu.itt = itt;
+            pdu.lun = lun::encode_lun(lun);
+            pdu.specific[4..8].copy_from_slice(&itt.to_be_bytes());
+            pdu.specific[0..4].copy_from_slice(&edtl.to_be_bytes());
+            pdu.specific[12..12 + cdb.len()].copy_from_slice(cdb);
+            pdu
+        };
+        let data_out = |itt: u32, ttt: u32| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_DATA_OUT;
+            pdu.flags = flags::FINAL;
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            pdu.data = vec![0x5A; 512];
+            pdu
+        };
+        let sense = |pdu: &IscsiPdu| (pdu.specific[1], pdu.data[2] & 0x0F, pdu.data[12], pdu.data[13]);
+        let test_unit_ready = [0u8; 6];
+        let write_10 = [0x2A, 0, 0, 0, 0, 0x10, 0, 0, 1, 0];
+        let send = |session: &mut IscsiSession, pdu: &IscsiPdu| {
+            handle_full_feature_phase(session, pdu, &luns, "iqn.test", &[]).unwrap()
+        };
+
+        table.insert(1, MockDevice::new(100, 512)).unwrap();
+
+        // The first command after the change reports it, once
+        let response = send(&mut session, &command(1, 1, 0, 0, &test_unit_ready));
+        assert_eq!(sense(&response[0]), (scsi_status::CHECK_CONDITION, crate::scsi::sense_key::UNIT_ATTENTION, 0x3F, 0x0E));
+        let response = send(&mut session, &command(2, 1, 0, 0, &test_unit_ready));
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+
+        // REPORT LUNS lists the new LUN
+        let response = send(&mut session, &command(3, 0, flags::READ, 64, &[0xA0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0, 0]));
+        let data = &response[0].data;
+        assert_eq!(&data[0..4], &16u32.to_be_bytes());
+        assert_eq!(&data[16..24], &lun::encode_lun(1).to_be_bytes());
+
+        // WRITEs to both LUNs wait on Data-Out when LUN 1 goes away
+        let response = send(&mut session, &command(4, 0, flags::WRITE, 512, &write_10));
+        assert_eq!(response[0].opcode, opcode::R2T);
+        let lun0_ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+        let response = send(&mut session, &command(5, 1, flags::WRITE, 512, &write_10));
+        assert_eq!(response[0].opcode, opcode::R2T);
+        let lun1_ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+        table.remove(1).unwrap();
+
+        let response = send(&mut session, &data_out(5, lun1_ttt));
+        assert_eq!(sense(&response[0]), (scsi_status::CHECK_CONDITION, crate::scsi::sense_key::ILLEGAL_REQUEST, 0x25, 0));
+        assert!(matches!(
+            observer.events.lock().unwrap().as_slice(),
+            [TargetEvent::WriteAborted { itt: 5, reason: AbortReason::LunRemoved, .. }]
+        ));
+
+        // LUN 0 is not disturbed
+        let response = send(&mut session, &data_out(4, lun0_ttt));
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert_eq!(device.read().unwrap().data[16 * 512], 0x5A);
+
+        let response = send(&mut session, &command(6, 1, 0, 0, &test_unit_ready));
+        assert_eq!(sense(&response[0]), (scsi_status::CHECK_CONDITION, crate::scsi::sense_key::ILLEGAL_REQUEST, 0x25, 0));
+        let response = send(&mut session, &command(7, 0, 0, 0, &test_unit_ready));
+        assert_eq!(sense(&response[0]), (scsi_status::CHECK_CONDITION, crate::scsi::sense_key::UNIT_ATTENTION, 0x3F, 0x0E));
+        let response = send(&mut session, &command(8, 0, 0, 0, &test_unit_ready));
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+    }
+
+    #[test]
+    fn test_lun_change_notices() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let table = Arc::new(LunTable::new(Arc::clone(&device)));
+        table.insert(1, MockDevice::new(100, 512)).unwrap();
+        let luns = SessionLuns::new(Arc::clone(&table));
+        let observer = Arc::new(RecordingObserver::default());
+        let new_session = || {
+            let mut session = IscsiSession::new();
+            session.state = SessionState::FullFeaturePhase;
+            session.set_observer(Some(observer.clone()));
+            session.exp_cmd_sn = 1;
+            session.max_cmd_sn = 64;
+            session.lun_generation = table.generation();
+            session
+        };
+        let mut alice = new_session();
+        let mut bob = new_session();
+
+        // CmdSN follows the ITT
+        let command = |itt: u32, lun: u64, pdu_flags: u8, edtl: u32, cdb: &[u8]| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_COMMAND;
+            pdu.flags = flags::FINAL | pdu_flags;
+            pdu.itt = itt;
+            pdu.lun = lun::encode_lun(lun);
+            pdu.specific[4..8].copy_from_slice(&itt.to_be_bytes());
+            pdu.specific[0..4].copy_from_slice(&edtl.to_be_bytes());
+            pdu.specific[12..12 + cdb.len()].copy_from_slice(cdb);
+            pdu
+        };
+        let data_out = |itt: u32, ttt: u32| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_DATA_OUT;
+            pdu.flags = flags::FINAL;
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&ttt.to_be_bytes());
+            pdu.data = vec![0x5A; 512];
+            pdu
+        };
+        let sense = |pdu: &IscsiPdu| (pdu.specific[1], pdu.data[2] & 0x0F, pdu.data[12], pdu.data[13]);
+        let test_unit_ready = [0u8; 6];
+        let inquiry = [0x12, 0, 0, 0, 36, 0];
+        let write_10 = [0x2A, 0, 0, 0, 0, 0x10, 0, 0, 1, 0];
+        let send = |session: &mut IscsiSession, pdu: &IscsiPdu| {
+            handle_full_feature_phase(session, pdu, &luns, "iqn.test", &[]).unwrap()
+        };
+        let ua = |asc: u8, ascq: u8| (scsi_status::CHECK_CONDITION, crate::scsi::sense_key::UNIT_ATTENTION, asc, ascq);
+
+        // Each session is told once, on its next command to that LUN
+        table.notify_changed(1, ChangeKind::Resized).unwrap();
+        let response = send(&mut alice, &command(1, 0, 0, 0, &test_unit_ready));
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        let response = send(&mut alice, &command(2, 1, flags::READ, 36, &inquiry));
+        assert_eq!(response[0].opcode, opcode::SCSI_DATA_IN, "INQUIRY leaves the unit attention pending");
+        let response = send(&mut alice, &command(3, 1, 0, 0, &test_unit_ready));
+        assert_eq!(sense(&response[0]), ua(0x2A, 0x09));
+        let response = send(&mut alice, &command(4, 1, 0, 0, &test_unit_ready));
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        let response = send(&mut bob, &command(1, 1, 0, 0, &test_unit_ready));
+        assert_eq!(sense(&response[0]), ua(0x2A, 0x09));
+        // However the LUN is addressed
+        let mut flat = command(2, 1, 0, 0, &test_unit_ready);
+        flat.lun = lun::encode_lun_as(1, lun::AddressMethod::Flat).unwrap();
+        let response = send(&mut bob, &flat);
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+
+        // A resize leaves a WRITE waiting on Data-Out alone
+        let response = send(&mut alice, &command(5, 1, flags::WRITE, 512, &write_10));
+        let ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+        table.notify_changed(1, ChangeKind::Resized).unwrap();
+        let response = send(&mut alice, &data_out(5, ttt));
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        let response = send(&mut alice, &command(6, 1, 0, 0, &test_unit_ready));
+        assert_eq!(sense(&response[0]), ua(0x2A, 0x09));
+
+        // A medium change fails it, and that reports the change
+        let response = send(&mut alice, &command(7, 1, flags::WRITE, 512, &write_10));
+        let ttt = u32::from_be_bytes(response[0].specific[0..4].try_into().unwrap());
+        table.notify_changed(1, ChangeKind::MediumChanged).unwrap();
+        let response = send(&mut alice, &data_out(7, ttt));
+        assert_eq!(sense(&response[0]), ua(0x28, 0x00));
+        assert!(alice.pending_writes.is_empty());
+        assert!(matches!(
+            observer.events.lock().unwrap().as_slice(),
+            [TargetEvent::WriteAborted { itt: 7, reason: AbortReason::LunChanged, .. }]
+        ));
+        let response = send(&mut alice, &command(8, 1, 0, 0, &test_unit_ready));
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+
+        // Bob only hears about the latest change
+        let response = send(&mut bob, &command(3, 1, 0, 0, &test_unit_ready));
+        assert_eq!(sense(&response[0]), ua(0x28, 0x00));
+        let response = send(&mut bob, &command(4, 1, 0, 0, &test_unit_ready));
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+
+        // Changes from before a session started are not reported to it
+        let mut carol = new_session();
+        carol.lun_changes_baseline = table.changes();
+        let response = send(&mut carol, &command(1, 1, 0, 0, &test_unit_ready));
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert!(table.notify_changed(2, ChangeKind::Resized).is_err());
+
+        // A field that decodes to no LUN of this target is refused outright
+        let mut other_bus = command(2, 1, 0, 0, &test_unit_ready);
+        other_bus.lun = 0x0101_0000_0000_0000;
+        let response = send(&mut carol, &other_bus);
+        assert_eq!(sense(&response[0]), (scsi_status::CHECK_CONDITION, crate::scsi::sense_key::ILLEGAL_REQUEST, 0x25, 0));
+    }
+
+    #[test]
+    fn test_log_sense_reports_lun_counters() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+
+        // WRITE (10) of 2 blocks carried entirely as immediate data
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL | flags::WRITE;
+        command.itt = 0x90;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        command.specific[0..4].copy_from_slice(&1024u32.to_be_bytes());
+        command.specific[12..22].copy_from_slice(&[0x2A, 0, 0, 0, 0, 0x08, 0, 0, 2, 0]);
+        command.data = vec![0x5A; 1024];
+        let response = handle_full_feature_phase(&mut session, &command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+
+        execute_command(&[0x28, 0, 0, 0, 0, 0x08, 0, 0, 1, 0], &device).unwrap();
+        execute_command(&[0x1B, 0, 0, 0, 0x00, 0], &device).unwrap();
+        execute_command(&[0x1B, 0, 0, 0, 0x01, 0], &device).unwrap();
+
+        let stats = device.read().unwrap().stats().snapshot();
+        assert_eq!(stats.bytes_written, 1024);
+        assert_eq!(stats.bytes_read, 512);
+        assert_eq!(stats.start_stop_cycles, 1);
+
+        // Write error counter page, cumulative values: total bytes processed
+        let response = execute_command(&[0x4D, 0, 0x42, 0, 0, 0, 0, 0x01, 0, 0], &device).unwrap();
+        assert_eq!(response.status, scsi_status::GOOD);
+        assert_eq!(&response.data[16..28], &[0, 0x05, 0x02, 8, 0, 0, 0, 0, 0, 0, 0x04, 0x00]);
+
+        // Start-stop cycle counter page: accumulated cycles
+        let response = execute_command(&[0x4D, 0, 0x4E, 0, 0, 0, 0, 0x01, 0, 0], &device).unwrap();
+        assert_eq!(&response.data[12..20], &[0, 0x04, 0x03, 4, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_task_attributes() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.exp_cmd_sn = 1;
+        session.open_command_window(16);
+        let (_initiator, target) = crate::transport::duplex();
+        let settings = IscsiTarget::builder().queue_depth(16).build(MockDevice::new(1000, 512)).unwrap().settings;
+        let mut queue = CommandQueue::start(&Framed::new(target), &settings, 8192, Digests::NONE, None, None, ConnectionContext::new(([127, 0, 0, 1], 0).into())).unwrap();
+
+        let command = |itt: u32, attribute: u8, cdb: &[u8]| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_COMMAND;
+            let direction = if cdb[0] == 0x2A { flags::WRITE } else { flags::READ };
+            pdu.flags = flags::FINAL | direction | attribute;
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&512u32.to_be_bytes());
+            pdu.specific[4..8].copy_from_slice(&itt.to_be_bytes());
+            pdu.specific[12..12 + cdb.len()].copy_from_slice(cdb);
+            pdu
+        };
+        let read = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        let write = [0x2A, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+
+        // A SIMPLE write waiting for Data-Out
+        let write_command = command(1, pdu::task_attribute::SIMPLE, &write);
+        assert!(!queue.defer(&mut session, &write_command));
+        let r2t = handle_full_feature_phase(&mut session, &write_command, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(r2t[0].opcode, opcode::R2T);
+
+        // ORDERED waits for it, and the SIMPLE command after waits for the ORDERED one
+        assert!(queue.defer(&mut session, &command(2, pdu::task_attribute::ORDERED, &read)));
+        assert!(queue.defer(&mut session, &command(3, pdu::task_attribute::SIMPLE, &read)));
+        assert_eq!(session.exp_cmd_sn, 4, "deferred commands take their CmdSN on arrival");
+
+        // HEAD OF QUEUE goes ahead and is not queued behind anything
+        let head = command(4, pdu::task_attribute::HEAD_OF_QUEUE, &read);
+        assert!(!queue.defer(&mut session, &head));
+        assert!(!is_queueable(&head));
+        let response = handle_full_feature_phase(&mut session, &head, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response.last().unwrap().itt, 4);
+
+        // ACA tasks are refused: no ACA condition is ever established
+        let response = handle_full_feature_phase(&mut session, &command(5, pdu::task_attribute::ACA, &read), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::CHECK_CONDITION);
+        assert_eq!(response[0].data[12], crate::scsi::asc::INVALID_MESSAGE_ERROR);
+
+        queue.release_deferred(&session);
+        assert!(queue.backlog.is_empty(), "the write is still outstanding");
+
+        let mut data_out = IscsiPdu::new();
+        data_out.opcode = opcode::SCSI_DATA_OUT;
+        data_out.flags = flags::FINAL;
+        data_out.itt = 1;
+        data_out.specific[0..4].copy_from_slice(&r2t[0].specific[0..4]);
+        data_out.data = vec![0x5A; 512];
+        let response = handle_full_feature_phase(&mut session, &data_out, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+
+        // Released in arrival order; the ORDERED read now runs, then the SIMPLE one
+        queue.release_deferred(&session);
+        let released: Vec<u32> = queue.backlog.iter().map(|received| match received {
+            Ok(ReceivedPdu::Pdu(pdu)) => pdu.itt,
+            _ => panic!("unexpected backlog entry"),
+        }).collect();
+        assert_eq!(released, vec![2, 3]);
+        for itt in [2, 3] {
+            let Some(Ok(ReceivedPdu::Pdu(pdu))) = queue.backlog.pop_front() else { unreachable!() };
+            assert!(!queue.defer(&mut session, &pdu), "ITT {}", itt);
+            let response = handle_full_feature_phase(&mut session, &pdu, &luns, "iqn.test", &[]).unwrap();
+            assert_eq!(response.last().unwrap().itt, itt);
+        }
+        assert_eq!(session.exp_cmd_sn, 6, "CmdSN is not taken twice");
+        assert!(session.deferred_commands.is_empty());
+    }
+
+    #[test]
+    fn test_command_deadline() {
+        // READs stall until the test lets them through
+        struct StallingDevice {
+            gate: Mutex<mpsc::Receiver<()>>,
+        }
+
+        impl ScsiBlockDevice for StallingDevice {
+            fn read(&self, _lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                let _ = self.gate.lock().unwrap().recv();
+                Ok(vec![0; (blocks * block_size) as usize])
+            }
+
+            fn write(&mut self, _lba: u64, _data: &[u8], _block_size: u32) -> ScsiResult<()> {
+                Ok(())
+            }
+
+            fn capacity(&self) -> u64 {
+                1000
+            }
+
+            fn block_size(&self) -> u32 {
+                512
+            }
+        }
+
+        let (release, gate) = mpsc::channel();
+        let device = Arc::new(RwLock::new(CountingDevice::new(StallingDevice { gate: Mutex::new(gate) })));
+        let luns = Arc::new(SessionLuns::new(Arc::new(LunTable::new(device))));
+        let observer = Arc::new(RecordingObserver::default());
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.set_observer(Some(observer.clone()));
+        session.exp_cmd_sn = 1;
+        session.open_command_window(16);
+        let (initiator, target) = crate::transport::duplex();
+        let (mut initiator, mut target) = (Framed::new(initiator), Framed::new(target));
+        let deadline = CommandDeadline { timeout: Duration::from_secs(5), busy: false };
+        #[derive(Default)]
+        struct Recorder(std::sync::Mutex<Vec<crate::audit::AuditRecord>>);
+        impl AuditSink for Recorder {
+            fn record(&self, records: &[crate::audit::AuditRecord]) {
+                self.0.lock().unwrap().extend_from_slice(records);
+            }
+        }
+        let audit = Arc::new(Recorder::default());
+        let audit_log = AuditLog::new(audit.clone(), "iqn.test");
+        let settings = IscsiTarget::builder()
+            .queue_depth(16)
+            .command_deadline(deadline.timeout, deadline.busy)
+            .build(MockDevice::new(1000, 512))
+            .unwrap()
+            .settings;
+        let mut queue = CommandQueue::start(&target, &settings, 8192, Digests::NONE, None, Some(audit_log), ConnectionContext::new(([127, 0, 0, 1], 0).into())).unwrap();
+        let workers = WorkerPool::new(1);
+
+        let mut read = IscsiPdu::new();
+        read.opcode = opcode::SCSI_COMMAND;
+        read.flags = flags::FINAL | flags::READ;
+        read.itt = 0x40;
+        read.specific[0..4].copy_from_slice(&512u32.to_be_bytes());
+        read.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        read.specific[12..22].copy_from_slice(&[0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        let started = Instant::now();
+        queue.audit_received(&read);
+        queue.submit(&mut target, &mut session, &read, &luns, &workers).unwrap();
+        let expires = queue.next_deadline().unwrap();
+        assert!(expires >= started + deadline.timeout);
+
+        // Nothing is due before the deadline
+        queue.expire(&mut target, &mut session, started).unwrap();
+        assert_eq!(queue.running.len(), 1);
+
+        // At the deadline the command is answered without the device
+        queue.expire(&mut target, &mut session, expires).unwrap();
+        let ReceivedPdu::Pdu(response) = initiator.recv_pdu(8192, Digests::NONE, &PduLimits::default(), None).unwrap() else {
+            panic!("oversized response");
+        };
+        assert_eq!((response.opcode, response.itt), (opcode::SCSI_RESPONSE, 0x40));
+        assert_eq!(response.specific[1], scsi_status::CHECK_CONDITION);
+        assert_eq!((response.data[2] & 0x0F, response.data[12], response.data[13]), (crate::scsi::sense_key::ABORTED_COMMAND, 0x2E, 0x02));
+        assert!(matches!(
+            observer.events.lock().unwrap().as_slice(),
+            [TargetEvent::CommandTimedOut { itt: 0x40, opcode: 0x28, .. }]
+        ));
+        assert_eq!(queue.next_deadline(), None);
+        queue.drain(&mut target, &mut session).unwrap();
+
+        // The late result is dropped once the device returns
+        release.send(()).unwrap();
+        let Ok(ConnectionEvent::Completed { task, response }) = queue.next_event(None) else {
+            panic!("expected the completion");
+        };
+        queue.complete(&mut target, &mut session, task, response).unwrap();
+        assert_eq!(queue.in_flight, 0);
+        assert_eq!(session.stat_sn, 1, "only the deadline response took a StatSN");
+
+        // The audit log records the deadline response, once
+        drop(queue);
+        let records = audit.0.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].status, scsi_status::CHECK_CONDITION);
+    }
+
+    #[test]
+    fn test_write_device_calls_under_deadline() {
+        // Writes and flushes stall until the test lets them through
+        struct StallingDevice {
+            gate: Mutex<mpsc::Receiver<()>>,
+        }
+
+        impl ScsiBlockDevice for StallingDevice {
+            fn read(&self, _lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                Ok(vec![0; (blocks * block_size) as usize])
+            }
+
+            fn write(&mut self, _lba: u64, _data: &[u8], _block_size: u32) -> ScsiResult<()> {
+                let _ = self.gate.lock().unwrap().recv();
+                Ok(())
+            }
+
+            fn flush(&mut self) -> ScsiResult<()> {
+                let _ = self.gate.lock().unwrap().recv();
+                Ok(())
+            }
+
+            fn capacity(&self) -> u64 {
+                1000
+            }
+
+            fn block_size(&self) -> u32 {
+                512
+            }
+        }
+
+        let (release, gate) = mpsc::channel();
+        let device = Arc::new(RwLock::new(CountingDevice::new(StallingDevice { gate: Mutex::new(gate) })));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(device)));
+        let observer = Arc::new(RecordingObserver::default());
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.set_observer(Some(observer.clone()));
+        session.open_command_window(16);
+        let deadline = CommandDeadline { timeout: Duration::from_millis(100), busy: false };
+        session.inline_deadline = Some(InlineDeadline {
+            workers: Arc::new(WorkerPool::new(1)),
+            deadline,
+            context: ConnectionContext::new(([127, 0, 0, 1], 0).into()),
+        });
+
+        let command = |itt: u32, cdb: &[u8], data: Vec<u8>| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SCSI_COMMAND;
+            let write = cdb[0] == 0x2A;
+            pdu.flags = flags::FINAL | if write { flags::WRITE } else { 0 };
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&(if write { 512u32 } else { 0 }).to_be_bytes());
+            pdu.specific[4..8].copy_from_slice(&itt.to_be_bytes());
+            pdu.specific[12..12 + cdb.len()].copy_from_slice(cdb);
+            pdu.data = data;
+            pdu
+        };
+        let write_fua = [0x2A, 0x08, 0, 0, 0, 0, 0, 0, 1, 0];
+        let timed_out = |response: &IscsiPdu| {
+            response.specific[1] == scsi_status::CHECK_CONDITION
+                && (response.data[2] & 0x0F, response.data[12], response.data[13]) == (crate::scsi::sense_key::ABORTED_COMMAND, 0x2E, 0x02)
+        };
+
+        // A device that keeps up is waited for: the write, then the FUA flush
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        let response = handle_full_feature_phase(&mut session, &command(1, &write_fua, vec![0x55; 512]), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+
+        // Immediate data the device does not take in time
+        let response = handle_full_feature_phase(&mut session, &command(2, &write_fua, vec![0x55; 512]), &luns, "iqn.test", &[]).unwrap();
+        assert!(timed_out(&response[0]));
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+
+        // Solicited data the device does not take in time ends the WRITE
+        let response = handle_full_feature_phase(&mut session, &command(3, &write_fua, Vec::new()), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::R2T);
+        let mut data_out = IscsiPdu::new();
+        data_out.opcode = opcode::SCSI_DATA_OUT;
+        data_out.flags = flags::FINAL;
+        data_out.itt = 3;
+        data_out.specific[0..4].copy_from_slice(&response[0].specific[0..4]);
+        data_out.data = vec![0x55; 512];
+        let response = handle_full_feature_phase(&mut session, &data_out, &luns, "iqn.test", &[]).unwrap();
+        assert!(timed_out(&response[0]));
+        assert!(session.pending_writes.is_empty());
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+
+        // A cache flush the device does not finish in time
+        let response = handle_full_feature_phase(&mut session, &command(4, &[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0], Vec::new()), &luns, "iqn.test", &[]).unwrap();
+        assert!(timed_out(&response[0]));
+        release.send(()).unwrap();
+
+        let events = observer.events.lock().unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                TargetEvent::CommandTimedOut { itt: 2, opcode: 0x2A, .. },
+                TargetEvent::CommandTimedOut { itt: 3, opcode: 0x2A, .. },
+                TargetEvent::CommandTimedOut { itt: 4, opcode: 0x35, .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_command_window_enforced() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.open_command_window(4);
+        assert_eq!(session.max_cmd_sn, 4);
+
+        // SCSI commands carry an all-zero CDB: TEST UNIT READY
+        let request = |opcode: u8, itt: u32, cmd_sn: u32| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode;
+            pdu.flags = flags::FINAL;
+            pdu.itt = itt;
+            pdu.specific[4..8].copy_from_slice(&cmd_sn.to_be_bytes());
+            pdu
+        };
+        let max_cmd_sn = |pdu: &IscsiPdu| u32::from_be_bytes(pdu.specific[12..16].try_into().unwrap());
+
+        // Beyond MaxCmdSN: silently ignored (RFC 7143 Section 4.2.2.1)
+        let response = handle_full_feature_phase(&mut session, &request(opcode::SCSI_COMMAND, 5, 5), &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty());
+        assert!(session.held_commands.is_empty());
+        // Already consumed: a retransmission, silently dropped
+        let response = handle_full_feature_phase(&mut session, &request(opcode::SCSI_COMMAND, 0, 0), &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty());
+        assert_eq!(session.exp_cmd_sn, 1);
+
+        // Each accepted command slides the whole window forward
+        let response = handle_full_feature_phase(&mut session, &request(opcode::SCSI_COMMAND, 1, 1), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        assert_eq!(max_cmd_sn(&response[0]), 5);
+
+        // Non-immediate NOP-Outs take a CmdSN too; immediate ones are always answered
+        let response = handle_full_feature_phase(&mut session, &request(opcode::NOP_OUT, 2, 9), &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty());
+        let response = handle_full_feature_phase(&mut session, &request(opcode::NOP_OUT, 2, 2), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::NOP_IN);
+        assert_eq!(session.exp_cmd_sn, 3);
+        let mut ping = request(opcode::NOP_OUT, 3, 3);
+        ping.immediate = true;
+        let response = handle_full_feature_phase(&mut session, &ping, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::NOP_IN);
+        assert_eq!(session.exp_cmd_sn, 3);
+        assert_eq!(max_cmd_sn(&response[0]), 6);
+
+        // A retransmitted command is recognised by its CmdSN alone
+        let mut write = request(opcode::SCSI_COMMAND, 7, 3);
+        write.flags |= flags::WRITE;
+        write.specific[0..4].copy_from_slice(&512u32.to_be_bytes());
+        write.specific[12..22].copy_from_slice(&[0x2A, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        let response = handle_full_feature_phase(&mut session, &write, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::R2T);
+        let response = handle_full_feature_phase(&mut session, &write, &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty());
+        assert_eq!(session.exp_cmd_sn, 4);
+
+        // A command ahead of a gap waits for it, then runs in CmdSN order
+        let response = handle_full_feature_phase(&mut session, &request(opcode::SCSI_COMMAND, 8, 5), &luns, "iqn.test", &[]).unwrap();
+        assert!(response.is_empty());
+        assert_eq!(session.exp_cmd_sn, 4);
+        assert!(session.take_ready_command().is_none());
+        let response = handle_full_feature_phase(&mut session, &request(opcode::SCSI_COMMAND, 9, 4), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].itt, 9);
+        let held = session.take_ready_command().expect("CmdSN 5 is next");
+        let response = handle_full_feature_phase(&mut session, &held, &luns, "iqn.test", &[]).unwrap();
+        assert_eq!((response[0].itt, response[0].specific[1]), (8, scsi_status::GOOD));
+        assert_eq!(session.exp_cmd_sn, 6);
+
+        // Once the write is gone its ITT may be reused with a fresh CmdSN
+        session.pending_writes.remove(&7);
+        let response = handle_full_feature_phase(&mut session, &request(opcode::SCSI_COMMAND, 7, 6), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!((response[0].itt, response[0].specific[1]), (7, scsi_status::GOOD));
+        assert_eq!(session.exp_cmd_sn, 7);
+    }
+
+    #[test]
+    fn test_snack_rejected() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        session.params.error_recovery_level = 1;
+        let snack = |snack_type: u8| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::SNACK_REQUEST;
+            pdu.flags = flags::FINAL | snack_type;
+            pdu.itt = 0x42;
+            pdu
+        };
+
+        for snack_type in [pdu::snack_type::DATA_R2T, pdu::snack_type::STATUS, pdu::snack_type::RDATA] {
+            let response = handle_full_feature_phase(&mut session, &snack(snack_type), &luns, "iqn.test", &[]).unwrap();
+            assert_eq!(response.len(), 1);
+            assert_eq!(response[0].opcode, opcode::REJECT);
+            assert_eq!(response[0].reject_reason(), pdu::reject_reason::SNACK_REJECT);
+            assert_eq!(IscsiPdu::from_bytes(&response[0].data).unwrap().itt, 0x42, "rejected header returned");
+        }
+
+        let response = handle_full_feature_phase(&mut session, &snack(0x0F), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].reject_reason(), pdu::reject_reason::PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn test_immediate_requests_bypass_closed_window() {
+        let device = Arc::new(RwLock::new(CountingDevice::new(MockDevice::new(1000, 512))));
+        let luns = SessionLuns::new(Arc::new(LunTable::new(Arc::clone(&device))));
+        let mut session = IscsiSession::new();
+        session.state = SessionState::FullFeaturePhase;
+        // MaxCmdSN = ExpCmdSN - 1: the window is closed
+        session.exp_cmd_sn = 5;
+        session.max_cmd_sn = 4;
+
+        let request = |opcode: u8, itt: u32, immediate: bool| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode;
+            pdu.immediate = immediate;
+            pdu.flags = flags::FINAL;
+            pdu.itt = itt;
+            pdu.specific[4..8].copy_from_slice(&5u32.to_be_bytes());
+            pdu
+        };
+
+        for opcode in [opcode::NOP_OUT, opcode::TASK_MANAGEMENT_REQUEST, opcode::SCSI_COMMAND, opcode::LOGOUT_REQUEST] {
+            let response = handle_full_feature_phase(&mut session, &request(opcode, 1, false), &luns, "iqn.test", &[]).unwrap();
+            assert!(response.is_empty(), "non-immediate 0x{:02x} ignored", opcode);
+        }
+
+        let response = handle_full_feature_phase(&mut session, &request(opcode::NOP_OUT, 2, true), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::NOP_IN);
+        let response = handle_full_feature_phase(&mut session, &request(opcode::TASK_MANAGEMENT_REQUEST, 3, true), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].opcode, opcode::TASK_MANAGEMENT_RESPONSE);
+        assert_eq!(&response[0].specific[8..12], &5u32.to_be_bytes(), "ExpCmdSN");
+        let response = handle_full_feature_phase(&mut session, &request(opcode::SCSI_COMMAND, 4, true), &luns, "iqn.test", &[]).unwrap();
+        assert_eq!(response[0].specific[1], scsi_status::GOOD);
+        let response = handle_full_feature_phase(&mut session, &request(opcode::LOGOUT_REQUEST, 5, true), &luns, "iqn.test", &[("127.0.0.1:3260".to_string(), 1)]).unwrap();
+        assert_eq!(response[0].logout_response_code(), pdu::logout_response::SUCCESS);
+
+        assert_eq!((session.exp_cmd_sn, session.max_cmd_sn), (5, 4), "immediate requests take no CmdSN");
+    }
+
+    /// A message-based transport: PDUs cross a channel whole, with no framing
+    struct MessageTransport {
+        inbox: Arc<Mutex<Receiver<IscsiPdu>>>,
+        outbox: Sender<IscsiPdu>,
+        read_timeout: Arc<Mutex<Option<Duration>>>,
+        closed: Arc<AtomicBool>,
+    }
+
+    impl PduTransport for MessageTransport {
+        fn recv_pdu(&mut self, _: u32, _: Digests, _: &PduLimits, _: Option<&ConnectionTrace>) -> ScsiResult<ReceivedPdu> {
+            let timeout = self.read_timeout.lock().unwrap().unwrap_or(Duration::MAX);
+            let start = Instant::now();
+            let inbox = self.inbox.lock().unwrap();
+            while !self.closed.load(Ordering::SeqCst) {
+                match inbox.recv_timeout(Duration::from_millis(20)) {
+                    Ok(pdu) => return Ok(ReceivedPdu::Pdu(pdu)),
+                    Err(RecvTimeoutError::Timeout) if start.elapsed() < timeout => {}
+                    Err(RecvTimeoutError::Timeout) => return Err(IscsiError::Io(std::io::ErrorKind::WouldBlock.into())),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            Err(IscsiError::Io(std::io::ErrorKind::UnexpectedEof.into()))
+        }
+
+        fn send_pdu(&mut self, pdu: &IscsiPdu, _: Digests, _: Option<&ConnectionTrace>) -> ScsiResult<()> {
+            self.outbox.send(pdu.clone()).map_err(|_| IscsiError::Io(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn try_clone(&self) -> std::io::Result<Self> {
+            Ok(Self {
+                inbox: Arc::clone(&self.inbox),
+                outbox: self.outbox.clone(),
+                read_timeout: Arc::clone(&self.read_timeout),
+                closed: Arc::clone(&self.closed),
+            })
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+            *self.read_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _: Option<Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn close(&self) -> std::io::Result<()> {
+            self.closed.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+            Ok(crate::transport::LOCAL_TARGET_ADDR)
+        }
+
+        fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+            Ok(crate::transport::LOCAL_INITIATOR_ADDR)
+        }
+    }
+
+    #[test]
+    fn test_session_over_message_transport() {
+        let target = IscsiTarget::builder()
+            .target_name("iqn.test:messages")
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        let (to_target, inbox) = mpsc::channel();
+        let (outbox, from_target) = mpsc::channel();
+        target.serve_transport(MessageTransport {
+            inbox: Arc::new(Mutex::new(inbox)),
+            outbox,
+            read_timeout: Arc::new(Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
+        }).unwrap();
+        let recv = || from_target.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let params = [
+            ("InitiatorName", "iqn.test:initiator"),
+            ("TargetName", "iqn.test:messages"),
+            ("SessionType", "Normal"),
+        ].map(|(key, value)| (key.to_string(), value.to_string()));
+        to_target.send(IscsiPdu::login_request(
+            [0x80, 0, 0, 0, 0, 1], 0, 0, 1, 0,
+            flags::CSG_LOGIN_OP_NEG, flags::NSG_FULL_FEATURE, true,
+            serialize_text_parameters(&params),
+        )).unwrap();
+        let response = recv();
+        assert_eq!(response.opcode, opcode::LOGIN_RESPONSE);
+        assert_eq!(response.specific[16], 0, "login status class");
+
+        // TEST UNIT READY
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL;
+        command.itt = 1;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        to_target.send(command).unwrap();
+        let response = recv();
+        assert_eq!(response.opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(response.specific[1], scsi_status::GOOD);
+
+        drop(to_target);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while target.active_connection_count() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(target.active_connection_count(), 0);
+        target.stop();
+    }
+
+    #[test]
+    fn test_device_provider_opens_per_initiator() {
+        struct HomeVolumes {
+            opened: Mutex<Vec<(String, u64)>>,
+        }
+
+        impl DeviceProvider for HomeVolumes {
+            fn open(&self, initiator_iqn: &str, lun: u64) -> ScsiResult<Box<dyn ScsiBlockDevice>> {
+                self.opened.lock().unwrap().push((initiator_iqn.to_string(), lun));
+                let blocks = if initiator_iqn.ends_with(":alice") { 100 } else { 200 };
+                Ok(Box::new(MockDevice::new(blocks, 512)))
+            }
+        }
+
+        let provider = Arc::new(HomeVolumes { opened: Mutex::new(Vec::new()) });
+        let target = IscsiTarget::builder()
+            .target_name("iqn.test:homes")
+            .build_with_provider(provider.clone())
+            .unwrap();
+        assert!(target.lun_stats(0).is_none());
+
+        // Each initiator reads its own volume's capacity, twice
+        let last_lba = |initiator: &str| {
+            let (to_target, inbox) = mpsc::channel();
+            let (outbox, from_target) = mpsc::channel();
+            target.serve_transport(MessageTransport {
+                inbox: Arc::new(Mutex::new(inbox)),
+                outbox,
+                read_timeout: Arc::new(Mutex::new(None)),
+                closed: Arc::new(AtomicBool::new(false)),
+            }).unwrap();
+            let recv = || from_target.recv_timeout(Duration::from_secs(5)).unwrap();
+
+            let params = [
+                ("InitiatorName", initiator),
+                ("TargetName", "iqn.test:homes"),
+                ("SessionType", "Normal"),
+            ].map(|(key, value)| (key.to_string(), value.to_string()));
+            to_target.send(IscsiPdu::login_request(
+                [0x80, 0, 0, 0, 0, 1], 0, 0, 1, 0,
+                flags::CSG_LOGIN_OP_NEG, flags::NSG_FULL_FEATURE, true,
+                serialize_text_parameters(&params),
+            )).unwrap();
+            assert_eq!(recv().specific[16], 0, "login status class");
+
+            let mut lbas = Vec::new();
+            for itt in 1..=2u32 {
+                let mut command = IscsiPdu::new();
+                command.opcode = opcode::SCSI_COMMAND;
+                command.flags = flags::FINAL | flags::READ;
+                command.itt = itt;
+                command.specific[0..4].copy_from_slice(&8u32.to_be_bytes());
+                command.specific[4..8].copy_from_slice(&itt.to_be_bytes());
+                command.specific[12] = 0x25;
+                to_target.send(command).unwrap();
+                let response = recv();
+                assert_eq!(response.opcode, opcode::SCSI_DATA_IN);
+                lbas.push(u32::from_be_bytes(response.data[0..4].try_into().unwrap()));
+            }
+            lbas
+        };
+        assert_eq!(last_lba("iqn.test:alice"), vec![99, 99]);
+        assert_eq!(last_lba("iqn.test:bob"), vec![199, 199]);
+
+        assert_eq!(*provider.opened.lock().unwrap(), vec![
+            ("iqn.test:alice".to_string(), 0),
+            ("iqn.test:bob".to_string(), 0),
+        ]);
+        target.stop();
+    }
+
+    #[test]
+    fn test_pdu_interceptor_rewrites_commands() {
+        /// Turns TEST UNIT READY into an unsupported opcode
+        struct BadOpcode;
+
+        impl PduInterceptor for BadOpcode {
+            fn on_receive(&self, pdu: &mut IscsiPdu) -> crate::intercept::Intercept {
+                if pdu.opcode == opcode::SCSI_COMMAND && pdu.specific[12] == 0x00 {
+                    pdu.specific[12] = 0xFF;
+                }
+                crate::intercept::Intercept::Pass
+            }
+        }
+
+        let target = IscsiTarget::builder()
+            .target_name("iqn.test:messages")
+            .pdu_interceptor(Arc::new(BadOpcode))
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        let (to_target, inbox) = mpsc::channel();
+        let (outbox, from_target) = mpsc::channel();
+        target.serve_transport(MessageTransport {
+            inbox: Arc::new(Mutex::new(inbox)),
+            outbox,
+            read_timeout: Arc::new(Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
+        }).unwrap();
+        let recv = || from_target.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let params = [
+            ("InitiatorName", "iqn.test:initiator"),
+            ("TargetName", "iqn.test:messages"),
+            ("SessionType", "Normal"),
+        ].map(|(key, value)| (key.to_string(), value.to_string()));
+        to_target.send(IscsiPdu::login_request(
+            [0x80, 0, 0, 0, 0, 1], 0, 0, 1, 0,
+            flags::CSG_LOGIN_OP_NEG, flags::NSG_FULL_FEATURE, true,
+            serialize_text_parameters(&params),
+        )).unwrap();
+        assert_eq!(recv().specific[16], 0, "login status class");
+
+        let mut command = IscsiPdu::new();
+        command.opcode = opcode::SCSI_COMMAND;
+        command.flags = flags::FINAL;
+        command.itt = 1;
+        command.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        to_target.send(command).unwrap();
+        let response = recv();
+        assert_eq!(response.specific[1], scsi_status::CHECK_CONDITION);
+        assert_eq!(response.data[2] & 0x0F, crate::scsi::sense_key::ILLEGAL_REQUEST);
+        target.stop();
+    }
+
+    #[test]
+    fn test_idle_session_asked_to_log_out() {
+        let target = IscsiTarget::builder()
+            .target_name("iqn.test:messages")
+            .session_idle_timeout(Duration::from_millis(600))
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        let (to_target, inbox) = mpsc::channel();
+        let (outbox, from_target) = mpsc::channel();
+        target.serve_transport(MessageTransport {
+            inbox: Arc::new(Mutex::new(inbox)),
+            outbox,
+            read_timeout: Arc::new(Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
+        }).unwrap();
+        let recv = || from_target.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let params = [
+            ("InitiatorName", "iqn.test:initiator"),
+            ("TargetName", "iqn.test:messages"),
+            ("SessionType", "Normal"),
+        ].map(|(key, value)| (key.to_string(), value.to_string()));
+        let logged_in = Instant::now();
+        to_target.send(IscsiPdu::login_request(
+            [0x80, 0, 0, 0, 0, 1], 0, 0, 1, 0,
+            flags::CSG_LOGIN_OP_NEG, flags::NSG_FULL_FEATURE, true,
+            serialize_text_parameters(&params),
+        )).unwrap();
+        assert_eq!(recv().specific[16], 0, "login status class");
+
+        // A NOP-Out ping is answered but does not count as activity
+        std::thread::sleep(Duration::from_millis(400));
+        let mut ping = IscsiPdu::new();
+        ping.opcode = opcode::NOP_OUT;
+        ping.flags = flags::FINAL;
+        ping.itt = 5;
+        ping.specific[0..4].copy_from_slice(&0xFFFF_FFFFu32.to_be_bytes());
+        ping.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        to_target.send(ping).unwrap();
+        assert_eq!(recv().opcode, opcode::NOP_IN);
+
+        let request = recv();
+        assert!(logged_in.elapsed() < Duration::from_millis(900), "idle time measured from the last command");
+        assert_eq!(request.opcode, opcode::ASYNC_MESSAGE);
+        assert_eq!(request.specific[16], pdu::async_event::LOGOUT_REQUEST);
+
+        let mut logout = IscsiPdu::new();
+        logout.opcode = opcode::LOGOUT_REQUEST;
+        logout.flags = flags::FINAL;
+        logout.itt = 6;
+        logout.specific[4..8].copy_from_slice(&2u32.to_be_bytes());
+        to_target.send(logout).unwrap();
+        let response = recv();
+        assert_eq!(response.opcode, opcode::LOGOUT_RESPONSE);
+        assert_eq!(response.logout_response_code(), pdu::logout_response::SUCCESS);
+        target.stop();
+    }
+
+    #[test]
+    fn test_ping_session() {
+        let target = IscsiTarget::builder()
+            .target_name("iqn.test:messages")
+            .build(MockDevice::new(1000, 512))
+            .unwrap();
+        let (to_target, inbox) = mpsc::channel();
+        let (outbox, from_target) = mpsc::channel();
+        target.serve_transport(MessageTransport {
+            inbox: Arc::new(Mutex::new(inbox)),
+            outbox,
+            read_timeout: Arc::new(Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
+        }).unwrap();
+        let recv = || from_target.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let params = [
+            ("InitiatorName", "iqn.test:initiator"),
+            ("TargetName", "iqn.test:messages"),
+            ("SessionType", "Normal"),
+        ].map(|(key, value)| (key.to_string(), value.to_string()));
+        to_target.send(IscsiPdu::login_request(
+            [0x80, 0, 0, 0, 0, 1], 0, 0, 1, 0,
+            flags::CSG_LOGIN_OP_NEG, flags::NSG_FULL_FEATURE, true,
+            serialize_text_parameters(&params),
+        )).unwrap();
+        assert_eq!(recv().specific[16], 0, "login status class");
+
+        let control = target.control();
+        let tsih = control.sessions()[0].tsih;
+        assert_eq!(control.sessions()[0].last_heartbeat, None);
+        assert!(matches!(control.ping_session(tsih + 1, Duration::from_secs(1)), Err(IscsiError::Session(_))));
+
+        let pinger = control.clone();
+        let ping = thread::spawn(move || pinger.ping_session(tsih, Duration::from_secs(5)));
+        let nop_in = recv();
+        assert_eq!(nop_in.opcode, opcode::NOP_IN);
+        assert_eq!(nop_in.itt, 0xFFFF_FFFF);
+        assert_eq!(nop_in.data, PING_DATA);
+
+        let mut echo = IscsiPdu::new();
+        echo.opcode = opcode::NOP_OUT;
+        echo.immediate = true;
+        echo.flags = flags::FINAL;
+        echo.itt = 0xFFFF_FFFF;
+        echo.specific[0..4].copy_from_slice(&nop_in.specific[0..4]);
+        echo.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        echo.data = nop_in.data.clone();
+        echo.data_length = echo.data.len() as u32;
+        to_target.send(echo).unwrap();
+        let round_trip = ping.join().unwrap().unwrap();
+        assert!(round_trip < Duration::from_secs(5));
+        assert!(control.sessions()[0].last_heartbeat.is_some());
+
+        // An unanswered ping times out
+        let result = control.ping_session(tsih, Duration::from_millis(100));
+        assert!(matches!(result, Err(IscsiError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut));
+        target.stop();
+    }
+
+    #[test]
+    fn test_streaming_reads_prefetch_ahead() {
+        /// Records the ranges it is asked to prefetch
+        struct Prefetching {
+            inner: MockDevice,
+            prefetched: Arc<Mutex<Vec<(u64, u32)>>>,
+        }
+
+        impl ScsiBlockDevice for Prefetching {
+            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                self.inner.read(lba, blocks, block_size)
+            }
+
+            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+                self.inner.write(lba, data, block_size)
+            }
+
+            fn capacity(&self) -> u64 {
+                self.inner.capacity()
+            }
+
+            fn block_size(&self) -> u32 {
+                self.inner.block_size()
+            }
+
+            fn prefetch(&self, lba: u64, blocks: u32) -> ScsiResult<bool> {
+                self.prefetched.lock().unwrap().push((lba, blocks));
+                Ok(false)
+            }
+        }
+
+        let prefetched = Arc::new(Mutex::new(Vec::new()));
+        let target = IscsiTarget::builder()
+            .target_name("iqn.test:messages")
+            .read_ahead(2, 64)
+            .build(Prefetching { inner: MockDevice::new(64, 512), prefetched: Arc::clone(&prefetched) })
+            .unwrap();
+        let (to_target, inbox) = mpsc::channel();
+        let (outbox, from_target) = mpsc::channel();
+        target.serve_transport(MessageTransport {
+            inbox: Arc::new(Mutex::new(inbox)),
+            outbox,
+            read_timeout: Arc::new(Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
+        }).unwrap();
+        let recv = || from_target.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let params = [
+            ("InitiatorName", "iqn.test:initiator"),
+            ("TargetName", "iqn.test:messages"),
+            ("SessionType", "Normal"),
+        ].map(|(key, value)| (key.to_string(), value.to_string()));
+        to_target.send(IscsiPdu::login_request(
+            [0x80, 0, 0, 0, 0, 1], 0, 0, 1, 0,
+            flags::CSG_LOGIN_OP_NEG, flags::NSG_FULL_FEATURE, true,
+            serialize_text_parameters(&params),
+        )).unwrap();
+        assert_eq!(recv().specific[16], 0, "login status class");
+
+        // READ (10) of 8 blocks at LBA 0, 8 and 16
+        for itt in 1..=3u32 {
+            let mut command = IscsiPdu::new();
+            command.opcode = opcode::SCSI_COMMAND;
+            command.flags = flags::FINAL | flags::READ;
+            command.itt = itt;
+            command.specific[0..4].copy_from_slice(&4096u32.to_be_bytes());
+            command.specific[4..8].copy_from_slice(&itt.to_be_bytes());
+            command.specific[12] = 0x28;
+            command.specific[14..18].copy_from_slice(&((itt - 1) * 8).to_be_bytes());
+            command.specific[19..21].copy_from_slice(&8u16.to_be_bytes());
+            to_target.send(command).unwrap();
+            assert_eq!(recv().opcode, opcode::SCSI_DATA_IN);
+        }
+
+        // The third READ continues the stream; the window stops at the end of the medium
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while prefetched.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*prefetched.lock().unwrap(), vec![(24, 40)]);
+        target.stop();
+    }
 }
@@ -0,0 +1,234 @@
+//! Test suite configuration
+//!
+//! Reads the INI files of the C test suite (`iscsi-test-suite/config/*.ini`)
+//! and the flat TOML of `test-config.toml`: `[section]` headers,
+//! `key = value` lines, `#`/`;` comments and optionally quoted values.
+
+use crate::error::{IscsiError, ScsiResult};
+use std::path::Path;
+use std::time::Duration;
+
+/// How the suite authenticates its logins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SuiteAuth {
+    None,
+    /// One-way CHAP with the initiator's name and secret
+    Chap { username: String, secret: String },
+    /// Mutual CHAP; `target_*` are the credentials the target must answer with
+    MutualChap { username: String, secret: String, target_username: String, target_secret: String },
+}
+
+/// Settings for a test suite run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuiteConfig {
+    /// Target portal, `host:port`
+    pub portal: String,
+    /// Target name; tests that need a login are skipped when empty
+    pub iqn: String,
+    pub initiator_iqn: String,
+    pub lun: u64,
+    pub auth: SuiteAuth,
+    /// Block size assumed before READ CAPACITY has been answered
+    pub block_size: u32,
+    /// Blocks moved by the large transfer tests (TI-007, TI-008)
+    pub large_transfer_blocks: u32,
+    /// Socket read and write timeout for external targets
+    pub timeout: Duration,
+    /// Iterations of the random access tests (TI-005, TI-006)
+    pub stress_iterations: u32,
+    /// 0 = failures only, 1 = every test, 2 = also messages of passed tests
+    pub verbosity: u8,
+    pub stop_on_fail: bool,
+    pub generate_report: bool,
+}
+
+impl Default for SuiteConfig {
+    fn default() -> Self {
+        SuiteConfig {
+            portal: "127.0.0.1:3260".to_string(),
+            iqn: String::new(),
+            initiator_iqn: "iqn.2025-12.local:initiator".to_string(),
+            lun: 0,
+            auth: SuiteAuth::None,
+            block_size: 512,
+            large_transfer_blocks: 1024,
+            timeout: Duration::from_secs(30),
+            stress_iterations: 100,
+            verbosity: 1,
+            stop_on_fail: false,
+            generate_report: true,
+        }
+    }
+}
+
+impl SuiteConfig {
+    /// Read a configuration file
+    pub fn load(path: impl AsRef<Path>) -> ScsiResult<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            IscsiError::Config(format!("Cannot read test suite config {}: {}", path.display(), e))
+        })?;
+        Self::parse(&text)
+    }
+
+    /// Parse configuration text; unknown sections and keys are ignored
+    pub fn parse(text: &str) -> ScsiResult<Self> {
+        let mut config = SuiteConfig::default();
+        let mut auth_method = "none".to_string();
+        let (mut username, mut password) = (String::new(), String::new());
+        let (mut mutual_username, mut mutual_password) = (String::new(), String::new());
+        let mut section = String::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                section = name.trim().to_string();
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(IscsiError::Config(format!("Line {}: expected key = value, got {:?}", number + 1, line)));
+            };
+            let key = key.trim();
+            let value = unquote(value.trim());
+            let invalid = || IscsiError::Config(format!("Line {}: invalid value {:?} for {}", number + 1, value, key));
+
+            match (section.as_str(), key) {
+                ("target", "portal") => config.portal = value.to_string(),
+                ("target", "iqn") => config.iqn = value.to_string(),
+                ("target", "initiator_iqn") => config.initiator_iqn = value.to_string(),
+                ("target", "lun") => config.lun = value.parse().map_err(|_| invalid())?,
+                ("authentication", "auth_method") => auth_method = value.to_ascii_lowercase(),
+                ("authentication", "username") => username = value.to_string(),
+                ("authentication", "password") => password = value.to_string(),
+                ("authentication", "mutual_username") => mutual_username = value.to_string(),
+                ("authentication", "mutual_password") => mutual_password = value.to_string(),
+                ("test_parameters", "block_size") => config.block_size = value.parse().map_err(|_| invalid())?,
+                ("test_parameters", "large_transfer_blocks") => {
+                    config.large_transfer_blocks = value.parse().map_err(|_| invalid())?
+                }
+                ("test_parameters", "stress_iterations") => {
+                    config.stress_iterations = value.parse().map_err(|_| invalid())?
+                }
+                // test-config.toml keeps the timeout under [options]
+                ("test_parameters" | "options", "timeout") => {
+                    config.timeout = Duration::from_secs(value.parse().map_err(|_| invalid())?)
+                }
+                ("options", "verbosity") => config.verbosity = value.parse().map_err(|_| invalid())?,
+                ("options", "stop_on_fail") => config.stop_on_fail = parse_bool(value).ok_or_else(invalid)?,
+                ("options", "generate_report") => config.generate_report = parse_bool(value).ok_or_else(invalid)?,
+                _ => log::debug!("Ignoring test suite config key [{}] {}", section, key),
+            }
+        }
+
+        config.auth = match auth_method.as_str() {
+            "none" | "" => SuiteAuth::None,
+            "chap" => SuiteAuth::Chap { username, secret: password },
+            "mutual_chap" => SuiteAuth::MutualChap {
+                username,
+                secret: password,
+                target_username: mutual_username,
+                target_secret: mutual_password,
+            },
+            other => return Err(IscsiError::Config(format!("Unknown auth_method {:?}", other))),
+        };
+        if config.block_size == 0 || config.large_transfer_blocks == 0 {
+            return Err(IscsiError::Config("block_size and large_transfer_blocks must be non-zero".to_string()));
+        }
+        Ok(config)
+    }
+}
+
+/// Drop a `#` or `;` comment that is not inside quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ini() {
+        let config = SuiteConfig::parse(
+            "[target]\n\
+             # Target portal address\n\
+             portal = 10.0.0.5:3262\n\
+             iqn = iqn.2025-12.local:storage.mutual-chap\n\
+             lun = 1\n\
+             \n\
+             [authentication]\n\
+             auth_method = mutual_chap\n\
+             username = init-user\n\
+             password = init-secret ; trailing comment\n\
+             mutual_username = target-user\n\
+             mutual_password = target-secret\n\
+             \n\
+             [test_parameters]\n\
+             large_transfer_blocks = 64\n\
+             timeout = 5\n\
+             \n\
+             [options]\n\
+             stop_on_fail = true\n\
+             generate_report = false\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.portal, "10.0.0.5:3262");
+        assert_eq!(config.lun, 1);
+        assert_eq!(config.auth, SuiteAuth::MutualChap {
+            username: "init-user".to_string(),
+            secret: "init-secret".to_string(),
+            target_username: "target-user".to_string(),
+            target_secret: "target-secret".to_string(),
+        });
+        assert_eq!(config.large_transfer_blocks, 64);
+        assert_eq!(config.timeout, Duration::from_secs(5));
+        assert!(config.stop_on_fail);
+        assert!(!config.generate_report);
+        assert_eq!(config.block_size, 512);
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let config = SuiteConfig::parse(&std::fs::read_to_string("test-config.toml").unwrap()).unwrap();
+        assert_eq!(config.portal, "127.0.0.1:3261");
+        assert_eq!(config.iqn, "iqn.2025-12.local:storage.memory-disk");
+        assert_eq!(config.initiator_iqn, "iqn.2025-12.local:initiator");
+        assert_eq!(config.auth, SuiteAuth::None);
+        assert_eq!(config.timeout, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(SuiteConfig::parse("[target]\nportal"), Err(IscsiError::Config(_))));
+        assert!(matches!(SuiteConfig::parse("[target]\nlun = x"), Err(IscsiError::Config(_))));
+        assert!(matches!(SuiteConfig::parse("[authentication]\nauth_method = kerberos"), Err(IscsiError::Config(_))));
+        assert!(matches!(SuiteConfig::parse("[options]\nstop_on_fail = maybe"), Err(IscsiError::Config(_))));
+    }
+}
@@ -0,0 +1,402 @@
+//! Conformance test suite for iSCSI targets
+//!
+//! A port of the C `iscsi-test-suite`: the discovery (TD), login (TL), SCSI
+//! command (TC) and I/O (TI) tests of `iscsi-test-plan.md`, run through
+//! `IscsiClient` against an external target over TCP or against an
+//! `IscsiTarget` in the same process. The I/O tests overwrite data on the
+//! LUN under test.
+//!
+//! ```no_run
+//! use iscsi_target::testsuite::{SuiteConfig, TestSuite};
+//!
+//! let config = SuiteConfig::load("iscsi-test-suite/config/test_config.ini")?;
+//! let report = TestSuite::new(config).run(|result| println!("{}", result));
+//! println!("{}", report.summary());
+//! # Ok::<(), iscsi_target::IscsiError>(())
+//! ```
+
+mod cases;
+mod config;
+
+pub use cases::TestCase;
+pub use config::{SuiteAuth, SuiteConfig};
+
+use crate::client::IscsiClient;
+use crate::error::{IscsiError, ScsiResult};
+use crate::scsi::ScsiBlockDevice;
+use crate::target::IscsiTarget;
+use crate::transport::{duplex, Transport};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Opens a new connection to the target under test
+pub type Connector = Box<dyn Fn() -> ScsiResult<IscsiClient> + Send + Sync>;
+
+/// Result of one test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestStatus {
+    Pass,
+    Fail,
+    /// The test does not apply to this target or configuration
+    Skip,
+    /// The test could not run, e.g. the connection failed
+    Error,
+}
+
+impl fmt::Display for TestStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TestStatus::Pass => "PASS",
+            TestStatus::Fail => "FAIL",
+            TestStatus::Skip => "SKIP",
+            TestStatus::Error => "ERROR",
+        })
+    }
+}
+
+/// Outcome of one test case
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    /// Test plan ID, e.g. `TI-001`
+    pub id: &'static str,
+    pub name: &'static str,
+    pub category: &'static str,
+    pub status: TestStatus,
+    /// Why the test failed or was skipped, or detail about a pass
+    pub message: Option<String>,
+    pub duration: Duration,
+}
+
+impl fmt::Display for TestResult {
+    /// The console line of the C suite, with the message indented below it
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "  {}: {:<40} [{}]  ({:.3}s)", self.id, self.name, self.status, self.duration.as_secs_f64())?;
+        match &self.message {
+            Some(message) => write!(f, "\n    └─ {}", message),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Runs the test cases against one target
+pub struct TestSuite {
+    config: SuiteConfig,
+    connector: Connector,
+    filters: Vec<String>,
+}
+
+impl TestSuite {
+    /// Test the target at `config.portal` over TCP
+    pub fn new(config: SuiteConfig) -> Self {
+        let portal = config.portal.clone();
+        let timeout = config.timeout;
+        Self::with_connector(config, Box::new(move || {
+            let client = IscsiClient::connect_timeout(&portal, timeout)?;
+            Ok(client)
+        }))
+    }
+
+    /// Test `target` in process, serving each connection over `duplex()`
+    pub fn for_target<D: ScsiBlockDevice + Send + 'static>(config: SuiteConfig, target: Arc<IscsiTarget<D>>) -> Self {
+        let timeout = config.timeout;
+        Self::with_connector(config, Box::new(move || {
+            let (initiator, stream) = duplex();
+            initiator.set_read_timeout(Some(timeout)).map_err(IscsiError::Io)?;
+            target.serve_stream(stream)?;
+            Ok(IscsiClient::from_stream(initiator))
+        }))
+    }
+
+    /// Test whatever target `connector` connects to
+    pub fn with_connector(config: SuiteConfig, connector: Connector) -> Self {
+        TestSuite { config, connector, filters: Vec::new() }
+    }
+
+    /// Run only the tests matching `filter`; may be called repeatedly
+    ///
+    /// `filter` is a category of the C suite (`discovery`, `commands`, `io`
+    /// or `all`), an ID prefix such as `TL` or a test ID such as `TC-008`.
+    pub fn only(mut self, filter: &str) -> ScsiResult<Self> {
+        let prefixes: &[&str] = match filter.to_ascii_lowercase().as_str() {
+            "all" => &[""],
+            "discovery" => &["TD-", "TL-"],
+            "commands" => &["TC-"],
+            "io" => &["TI-"],
+            _ => &[],
+        };
+        let filter = filter.to_ascii_uppercase();
+        if !prefixes.is_empty() {
+            self.filters.extend(prefixes.iter().map(|prefix| prefix.to_string()));
+        } else if cases::CASES.iter().any(|case| case.id.starts_with(&filter)) {
+            self.filters.push(filter);
+        } else {
+            return Err(IscsiError::Config(format!("No test matches {:?}", filter)));
+        }
+        Ok(self)
+    }
+
+    /// The test cases that `run` will execute, in order
+    pub fn cases(&self) -> impl Iterator<Item = &'static TestCase> + '_ {
+        cases::CASES.iter().filter(move |case| {
+            self.filters.is_empty() || self.filters.iter().any(|filter| case.id.starts_with(filter.as_str()))
+        })
+    }
+
+    /// Run the selected tests, calling `progress` as each one finishes
+    ///
+    /// With `stop_on_fail` the run ends at the first failure or error.
+    pub fn run(&self, mut progress: impl FnMut(&TestResult)) -> SuiteReport {
+        let started = Instant::now();
+        let mut results = Vec::new();
+        for case in self.cases() {
+            let result = case.run(&self.config, &self.connector);
+            progress(&result);
+            let failed = matches!(result.status, TestStatus::Fail | TestStatus::Error);
+            results.push(result);
+            if failed && self.config.stop_on_fail {
+                break;
+            }
+        }
+        SuiteReport {
+            portal: self.config.portal.clone(),
+            iqn: self.config.iqn.clone(),
+            lun: self.config.lun,
+            results,
+            duration: started.elapsed(),
+            finished: SystemTime::now(),
+        }
+    }
+}
+
+/// Results of a test suite run
+#[derive(Debug, Clone)]
+pub struct SuiteReport {
+    pub portal: String,
+    pub iqn: String,
+    pub lun: u64,
+    pub results: Vec<TestResult>,
+    pub duration: Duration,
+    pub finished: SystemTime,
+}
+
+impl SuiteReport {
+    /// Number of results with `status`
+    pub fn count(&self, status: TestStatus) -> usize {
+        self.results.iter().filter(|result| result.status == status).count()
+    }
+
+    /// True when no test failed or errored
+    pub fn success(&self) -> bool {
+        self.count(TestStatus::Fail) == 0 && self.count(TestStatus::Error) == 0
+    }
+
+    /// The closing lines of the console output
+    pub fn summary(&self) -> String {
+        format!(
+            "Results: {} passed, {} failed, {} skipped, {} errors\nDuration: {:.1} seconds",
+            self.count(TestStatus::Pass),
+            self.count(TestStatus::Fail),
+            self.count(TestStatus::Skip),
+            self.count(TestStatus::Error),
+            self.duration.as_secs_f64()
+        )
+    }
+
+    /// The detailed report, in the format of the C suite's report files
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("iSCSI Target Test Suite - Detailed Report\n");
+        out.push_str("==========================================\n");
+        out.push_str(&format!("Date: {}\n", format_time(self.finished, "-", " ", ":")));
+        out.push_str(&format!("Target: {}\n", self.portal));
+        if !self.iqn.is_empty() {
+            out.push_str(&format!("IQN: {}\n", self.iqn));
+        }
+        out.push_str(&format!("LUN: {}\n\n", self.lun));
+        out.push_str("Test Results:\n-------------\n");
+
+        let mut category = "";
+        for result in &self.results {
+            if result.category != category {
+                category = result.category;
+                out.push_str(&format!("\n[{}]\n", category));
+            }
+            out.push_str(&format!(
+                "  {}: {} - {} ({:.3}s)\n",
+                result.id, result.name, result.status, result.duration.as_secs_f64()
+            ));
+            if let Some(message) = &result.message {
+                out.push_str(&format!("    Message: {}\n", message));
+            }
+        }
+
+        out.push_str("\n\nSummary by Category:\n--------------------\n");
+        let mut categories: Vec<&str> = Vec::new();
+        for result in &self.results {
+            if !categories.contains(&result.category) {
+                categories.push(result.category);
+            }
+        }
+        for category in categories {
+            let in_category = || self.results.iter().filter(move |result| result.category == category);
+            let count = |status| in_category().filter(|result| result.status == status).count();
+            out.push_str(&format!(
+                "{}: {}/{} passed, {} failed, {} skipped, {} errors\n",
+                category,
+                count(TestStatus::Pass),
+                in_category().count(),
+                count(TestStatus::Fail),
+                count(TestStatus::Skip),
+                count(TestStatus::Error)
+            ));
+        }
+
+        out.push_str("\nSummary:\n--------\n");
+        out.push_str(&format!("Total:   {}\n", self.results.len()));
+        out.push_str(&format!("Passed:  {}\n", self.count(TestStatus::Pass)));
+        out.push_str(&format!("Failed:  {}\n", self.count(TestStatus::Fail)));
+        out.push_str(&format!("Skipped: {}\n", self.count(TestStatus::Skip)));
+        out.push_str(&format!("Errors:  {}\n", self.count(TestStatus::Error)));
+        out.push_str(&format!("Duration: {:.1} seconds\n", self.duration.as_secs_f64()));
+        out
+    }
+
+    /// Write `render()` to `dir/test_report_YYYYMMDD_HHMMSS.txt` (UTC)
+    pub fn write_report(&self, dir: impl AsRef<Path>) -> ScsiResult<PathBuf> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("test_report_{}.txt", format_time(self.finished, "", "_", "")));
+        std::fs::write(&path, self.render())?;
+        Ok(path)
+    }
+}
+
+/// Format `time` as UTC `YYYY<d>MM<d>DD<sep>hh<t>mm<t>ss`
+fn format_time(time: SystemTime, date_sep: &str, sep: &str, time_sep: &str) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{d}{:02}{d}{:02}{}{:02}{t}{:02}{t}{:02}",
+        year, month, day, sep, rem / 3600, rem / 60 % 60, rem % 60,
+        d = date_sep, t = time_sep
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ChapCredentials;
+    use crate::backends::MemBlockDevice;
+
+    const TARGET: &str = "iqn.2025-12.local:suite";
+
+    fn suite_config() -> SuiteConfig {
+        SuiteConfig {
+            iqn: TARGET.to_string(),
+            timeout: Duration::from_secs(10),
+            stress_iterations: 20,
+            large_transfer_blocks: 256,
+            ..SuiteConfig::default()
+        }
+    }
+
+    fn memory_target(mutual_chap: bool) -> Arc<IscsiTarget<MemBlockDevice>> {
+        let mut builder = IscsiTarget::builder().target_name(TARGET);
+        if mutual_chap {
+            builder = builder
+                .chap_account("init-user", "init-secret-pass")
+                .mutual_chap_credentials(ChapCredentials::new("target-user", "target-secret-pass"));
+        }
+        Arc::new(builder.build(MemBlockDevice::new(32768, 512)).unwrap())
+    }
+
+    #[test]
+    fn test_format_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_767_225_600 + 3 * 3600 + 4 * 60 + 5);
+        assert_eq!(format_time(time, "", "_", ""), "20260101_030405");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_secs(951_782_400), "-", " ", ":"), "2000-02-29 00:00:00");
+    }
+
+    #[test]
+    fn test_only_filters_cases() {
+        let suite = TestSuite::new(suite_config());
+        assert_eq!(suite.cases().count(), 33);
+        let suite = suite.only("commands").unwrap().only("TI-009").unwrap();
+        let ids: Vec<_> = suite.cases().map(|case| case.id).collect();
+        assert_eq!(ids.len(), 10);
+        assert!(ids.iter().take(9).all(|id| id.starts_with("TC-")));
+        assert_eq!(ids[9], "TI-009");
+        assert!(matches!(TestSuite::new(suite_config()).only("TX"), Err(IscsiError::Config(_))));
+    }
+
+    #[test]
+    fn test_suite_passes_against_memory_target() {
+        let target = memory_target(false);
+        let mut seen = 0;
+        let report = TestSuite::for_target(suite_config(), target).run(|_| seen += 1);
+
+        assert_eq!(seen, report.results.len());
+        let failures: Vec<String> = report.results.iter()
+            .filter(|result| matches!(result.status, TestStatus::Fail | TestStatus::Error))
+            .map(|result| result.to_string())
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+        assert_eq!(report.count(TestStatus::Pass), 30);
+
+        let rendered = report.render();
+        assert!(rendered.contains("[I/O Operation Tests]\n  TI-001: Single Block Read - PASS"));
+        assert!(rendered.contains("SCSI Command Tests: 9/9 passed, 0 failed, 0 skipped, 0 errors"));
+        assert!(report.summary().starts_with("Results: 30 passed, 0 failed, 3 skipped, 0 errors"));
+    }
+
+    #[test]
+    fn test_suite_logs_in_with_mutual_chap() {
+        let target = memory_target(true);
+        let config = SuiteConfig {
+            auth: SuiteAuth::MutualChap {
+                username: "init-user".to_string(),
+                secret: "init-secret-pass".to_string(),
+                target_username: "target-user".to_string(),
+                target_secret: "target-secret-pass".to_string(),
+            },
+            ..suite_config()
+        };
+        let report = TestSuite::for_target(config, target).only("TD-003").unwrap().only("TL-001").unwrap().run(|_| {});
+        let statuses: Vec<_> = report.results.iter().map(|result| result.status).collect();
+        assert_eq!(statuses, [TestStatus::Pass, TestStatus::Pass]);
+    }
+
+    #[test]
+    fn test_stop_on_fail_and_report_file() {
+        // Nothing listens on port 1, so the first test fails and the run stops
+        let config = SuiteConfig {
+            portal: "127.0.0.1:1".to_string(),
+            stop_on_fail: true,
+            ..suite_config()
+        };
+        let report = TestSuite::new(config).run(|_| {});
+        assert_eq!(report.results.len(), 1);
+        assert!(!report.success());
+
+        let dir = std::env::temp_dir().join(format!("iscsi-suite-{}", std::process::id()));
+        let path = report.write_report(&dir).unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("test_report_") && name.ends_with(".txt") && name.len() == 31);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), report.render());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,786 @@
+//! Connection transports
+//!
+//! The target runs each connection over a `PduTransport`, which moves whole
+//! PDUs. Byte streams implement `Transport` and are wrapped in `Framed`,
+//! which applies iSCSI's TCP framing: TCP for connections accepted on a
+//! portal, or anything else handed to `IscsiTarget::serve_stream` or
+//! `IscsiTarget::handle_connection`, such as a Unix socket. Message-based
+//! transports such as iSER implement `PduTransport` directly and are served
+//! with `IscsiTarget::serve_transport` or `IscsiTarget::handle_transport`.
+//! `duplex()` gives an in-memory pair, so tests can drive a target without
+//! listening on a port.
+//!
+//! `SocketOptions` tunes TCP connections (Nagle, buffer sizes, keepalive,
+//! IP TOS) before they are served.
+
+use crate::error::{IscsiError, ProtocolErrorKind, ScsiResult};
+use crate::pdu::{self, Digests, IscsiPdu, PduLimits, BHS_SIZE, DIGEST_SIZE};
+use crate::trace::{ConnectionTrace, Direction};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Byte stream a target connection runs over
+///
+/// Mirrors the parts of `TcpStream` the connection handler needs. Handles
+/// returned by `try_clone` share the connection and its read timeout, and
+/// `close` ends it for every handle.
+pub trait Transport: Read + Write + Send + Sized + 'static {
+    /// Another handle to the same connection (used by the reader thread)
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Fail reads that wait longer than `timeout` with `WouldBlock` or `TimedOut`
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Fail writes that wait longer than `timeout`
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Close both directions; blocked and later reads see end of stream
+    fn close(&self) -> io::Result<()>;
+
+    /// Address the initiator connected to (used for SendTargets and traces)
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Address of the initiator
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Apply socket tuning; transports that are not TCP sockets ignore it
+    fn apply_socket_options(&self, _options: &SocketOptions) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// TCP keepalive probing of idle connections (SO_KEEPALIVE)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// Idle time before the first probe (TCP_KEEPIDLE)
+    pub idle: Duration,
+    /// Time between unanswered probes (TCP_KEEPINTVL)
+    pub interval: Duration,
+    /// Unanswered probes before the connection is dropped (TCP_KEEPCNT)
+    pub retries: u32,
+}
+
+/// Socket settings applied to each accepted TCP connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Send small PDUs at once instead of coalescing them (TCP_NODELAY)
+    pub nodelay: bool,
+    /// Receive buffer size in bytes (SO_RCVBUF), or the system default
+    pub recv_buffer: Option<usize>,
+    /// Send buffer size in bytes (SO_SNDBUF), or the system default
+    pub send_buffer: Option<usize>,
+    /// TCP keepalive probing, or none
+    pub keepalive: Option<TcpKeepalive>,
+    /// IPv4 TOS byte or IPv6 traffic class; DSCP is the upper six bits
+    /// (e.g. 0xB8 for Expedited Forwarding)
+    pub tos: Option<u8>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            recv_buffer: None,
+            send_buffer: None,
+            keepalive: None,
+            tos: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Check the settings can be applied
+    pub fn validate(&self) -> ScsiResult<()> {
+        for (name, size) in [("receive", self.recv_buffer), ("send", self.send_buffer)] {
+            if size.is_some_and(|size| size == 0 || size > i32::MAX as usize) {
+                return Err(IscsiError::Config(format!("Socket {} buffer size must be 1..={} bytes", name, i32::MAX)));
+            }
+        }
+        if let Some(keepalive) = self.keepalive {
+            if keepalive.idle.as_secs() == 0 || keepalive.interval.as_secs() == 0 || keepalive.retries == 0 {
+                return Err(IscsiError::Config(
+                    "TCP keepalive idle time and interval must be at least a second, with at least one probe".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn apply_socket_options(&self, options: &SocketOptions) -> io::Result<()> {
+        self.set_nodelay(options.nodelay)?;
+        sockopt::apply(self, options)
+    }
+}
+
+/// Socket options std does not expose, set with setsockopt(2)
+#[cfg(unix)]
+mod sockopt {
+    use super::{SocketOptions, TcpKeepalive};
+    use std::io;
+    use std::net::TcpStream;
+    use std::os::unix::io::AsRawFd;
+
+    pub(super) fn apply(stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+        if let Some(size) = options.recv_buffer {
+            set(stream, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)?;
+        }
+        if let Some(size) = options.send_buffer {
+            set(stream, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)?;
+        }
+        if let Some(keepalive) = options.keepalive {
+            set_keepalive(stream, &keepalive)?;
+        }
+        if let Some(tos) = options.tos {
+            if stream.local_addr()?.is_ipv4() {
+                set(stream, libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int)?;
+            } else {
+                set(stream, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as libc::c_int)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_keepalive(stream: &TcpStream, keepalive: &TcpKeepalive) -> io::Result<()> {
+        set(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        let idle = keepalive.idle.as_secs().min(libc::c_int::MAX as u64) as libc::c_int;
+        let interval = keepalive.interval.as_secs().min(libc::c_int::MAX as u64) as libc::c_int;
+        let retries = keepalive.retries.min(libc::c_int::MAX as u32) as libc::c_int;
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        set(stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle)?;
+        #[cfg(target_vendor = "apple")]
+        set(stream, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, idle)?;
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_vendor = "apple"))]
+        {
+            set(stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, interval)?;
+            set(stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, retries)?;
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_vendor = "apple")))]
+        log::debug!("Keepalive timing ({}s/{}s/{}) left to the system", idle, interval, retries);
+        Ok(())
+    }
+
+    fn set(stream: &TcpStream, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        // SAFETY: the descriptor is open for the lifetime of `stream` and
+        // `value` outlives the call, which reads exactly size_of::<c_int>() bytes
+        let result = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Only TCP_NODELAY is available without setsockopt(2)
+#[cfg(not(unix))]
+mod sockopt {
+    use super::SocketOptions;
+    use std::io;
+    use std::net::TcpStream;
+
+    pub(super) fn apply(_stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+        if options.recv_buffer.is_some() || options.send_buffer.is_some() || options.keepalive.is_some() || options.tos.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "socket tuning is only supported on Unix"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(LOCAL_TARGET_ADDR)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(LOCAL_INITIATOR_ADDR)
+    }
+}
+
+/// Connection carrying whole iSCSI PDUs
+///
+/// The target's connection loop sees its connection only through this
+/// trait. `Framed` implements it for any byte-stream `Transport`; an iSER
+/// backend, which receives each PDU as one RDMA message, can implement it
+/// directly. As with `Transport`, handles returned by `try_clone` share the
+/// connection and its read timeout, and `close` ends it for every handle.
+pub trait PduTransport: Send + Sized + 'static {
+    /// Receive the next PDU
+    ///
+    /// Data segments longer than `max_data_segment` must be discarded and
+    /// reported as `ReceivedPdu::Oversized`. A read timeout surfaces as an
+    /// `IscsiError::Io` of kind `WouldBlock` or `TimedOut`, and a closed
+    /// connection as `UnexpectedEof`. Received bytes go to `trace`, if any.
+    fn recv_pdu(
+        &mut self,
+        max_data_segment: u32,
+        digests: Digests,
+        limits: &PduLimits,
+        trace: Option<&ConnectionTrace>,
+    ) -> ScsiResult<ReceivedPdu>;
+
+    /// Send one PDU, recording its bytes in `trace`, if any
+    fn send_pdu(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>) -> ScsiResult<()>;
+
+    /// Another handle to the same connection (used by the reader thread)
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Fail receives that wait longer than `timeout` with `WouldBlock` or `TimedOut`
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Fail sends that wait longer than `timeout`
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Close the connection; blocked and later receives see end of stream
+    fn close(&self) -> io::Result<()>;
+
+    /// Address the initiator connected to (used for SendTargets and traces)
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Address of the initiator
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Send one PDU with a wrong data digest, for fault injection
+    ///
+    /// Transports without digests send the PDU unchanged.
+    fn send_pdu_with_bad_data_digest(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>) -> ScsiResult<()> {
+        self.send_pdu(pdu, digests, trace)
+    }
+}
+
+/// Outcome of receiving one PDU
+#[derive(Debug)]
+pub enum ReceivedPdu {
+    /// A complete PDU within the receive limit
+    Pdu(IscsiPdu),
+    /// The data segment exceeded MaxRecvDataSegmentLength; it was read and
+    /// discarded so the stream stays in sync, only the header is kept
+    Oversized { header: [u8; BHS_SIZE], data_length: u32 },
+    /// The data segment failed its CRC32C data digest and was discarded
+    DataDigestError { header: [u8; BHS_SIZE] },
+}
+
+/// A byte-stream `Transport` carrying PDUs with TCP framing
+///
+/// PDUs are sent back to back: BHS, AHS, header digest, padded data segment
+/// and data digest (RFC 3720 section 10.2).
+#[derive(Debug)]
+pub struct Framed<S> {
+    stream: S,
+}
+
+impl<S: Transport> Framed<S> {
+    /// Frame PDUs over `stream`
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Unwrap the underlying stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Transport> PduTransport for Framed<S> {
+    fn recv_pdu(
+        &mut self,
+        max_data_segment: u32,
+        digests: Digests,
+        limits: &PduLimits,
+        trace: Option<&ConnectionTrace>,
+    ) -> ScsiResult<ReceivedPdu> {
+        let mut wire = trace.map(|_| Vec::new());
+        let received = read_pdu(&mut self.stream, max_data_segment, digests, limits, &mut wire);
+        if let (Some(trace), Some(wire)) = (trace, wire) {
+            if !wire.is_empty() {
+                trace.record(Direction::Inbound, &wire);
+            }
+        }
+        received
+    }
+
+    fn send_pdu(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>) -> ScsiResult<()> {
+        write_pdu(&mut self.stream, pdu, digests, false, trace)
+    }
+
+    fn send_pdu_with_bad_data_digest(&mut self, pdu: &IscsiPdu, digests: Digests, trace: Option<&ConnectionTrace>) -> ScsiResult<()> {
+        write_pdu(&mut self.stream, pdu, digests, true, trace)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        self.stream.try_clone().map(Self::new)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.stream.close()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+}
+
+/// Append bytes read from the wire to the trace capture, if tracing
+fn capture(wire: &mut Option<Vec<u8>>, bytes: &[u8]) {
+    if let Some(wire) = wire {
+        wire.extend_from_slice(bytes);
+    }
+}
+
+/// Read a PDU from a byte stream
+///
+/// Data segments larger than `max_data_segment` are never buffered. A header
+/// digest mismatch is an error, since the PDU boundaries can't be trusted.
+fn read_pdu(
+    stream: &mut impl Read,
+    max_data_segment: u32,
+    digests: Digests,
+    limits: &PduLimits,
+    wire: &mut Option<Vec<u8>>,
+) -> ScsiResult<ReceivedPdu> {
+    // Read 48-byte BHS
+    let mut bhs = [0u8; BHS_SIZE];
+    stream.read_exact(&mut bhs).map_err(IscsiError::Io)?;
+    capture(wire, &bhs);
+
+    // Check AHS and data segment lengths before allocating for them
+    let (ahs_length, data_length) = limits.check_header(&bhs)?;
+    let padded_data_len = (data_length as usize).div_ceil(4) * 4;
+    let data_digest_len = if digests.data && data_length > 0 { DIGEST_SIZE } else { 0 };
+
+    // Read AHS and check the header digest
+    let mut full_pdu = vec![0u8; BHS_SIZE + ahs_length];
+    full_pdu[..BHS_SIZE].copy_from_slice(&bhs);
+    stream.read_exact(&mut full_pdu[BHS_SIZE..]).map_err(IscsiError::Io)?;
+    capture(wire, &full_pdu[BHS_SIZE..]);
+    if digests.header {
+        let mut digest = [0u8; DIGEST_SIZE];
+        stream.read_exact(&mut digest).map_err(IscsiError::Io)?;
+        capture(wire, &digest);
+        if pdu::crc32c(&full_pdu).to_le_bytes() != digest {
+            return Err(IscsiError::protocol(ProtocolErrorKind::Digest, format!(
+                "Header digest error on PDU (opcode 0x{:02x})", bhs[0] & 0x3F
+            )));
+        }
+    }
+
+    if data_length > max_data_segment {
+        let discard = (padded_data_len + data_digest_len) as u64;
+        let mut data = Read::by_ref(stream).take(discard);
+        let discarded = match wire {
+            Some(wire) => std::io::copy(&mut data, wire),
+            None => std::io::copy(&mut data, &mut std::io::sink()),
+        }.map_err(IscsiError::Io)?;
+        if discarded < discard {
+            return Err(IscsiError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        return Ok(ReceivedPdu::Oversized { header: bhs, data_length });
+    }
+
+    // Read data segment + padding
+    let data_start = full_pdu.len();
+    full_pdu.resize(data_start + padded_data_len, 0);
+    stream.read_exact(&mut full_pdu[data_start..]).map_err(IscsiError::Io)?;
+    capture(wire, &full_pdu[data_start..]);
+    if data_digest_len > 0 {
+        let mut digest = [0u8; DIGEST_SIZE];
+        stream.read_exact(&mut digest).map_err(IscsiError::Io)?;
+        capture(wire, &digest);
+        if pdu::crc32c(&full_pdu[data_start..]).to_le_bytes() != digest {
+            return Ok(ReceivedPdu::DataDigestError { header: bhs });
+        }
+    }
+
+    Ok(ReceivedPdu::Pdu(IscsiPdu::parse(&full_pdu, limits)?))
+}
+
+/// Write a PDU to a byte stream
+///
+/// With `bad_data_digest` the data digest, if any, is sent inverted.
+fn write_pdu(
+    stream: &mut impl Write,
+    pdu: &IscsiPdu,
+    digests: Digests,
+    bad_data_digest: bool,
+    trace: Option<&ConnectionTrace>,
+) -> ScsiResult<()> {
+    let mut bytes = pdu.to_bytes_with_digests(digests);
+    if bad_data_digest && digests.data && !pdu.data.is_empty() {
+        // The data digest ends the PDU
+        let digest_start = bytes.len() - DIGEST_SIZE;
+        bytes[digest_start..].iter_mut().for_each(|b| *b = !*b);
+    }
+    if let Some(trace) = trace {
+        trace.record(Direction::Outbound, &bytes);
+    }
+
+    stream.write_all(&bytes).map_err(IscsiError::Io)?;
+    stream.flush().map_err(IscsiError::Io)?;
+    Ok(())
+}
+
+/// Nominal target address of connections without an IP address (duplex pairs, Unix sockets)
+pub const LOCAL_TARGET_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3260));
+
+/// Nominal initiator address of connections without an IP address
+pub const LOCAL_INITIATOR_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 49152));
+
+/// One direction of a duplex pair
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    buffer: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of a duplex pair, shared by all its handles
+#[derive(Debug)]
+struct DuplexEnd {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl Drop for DuplexEnd {
+    fn drop(&mut self) {
+        // The last handle is gone: the peer reads end of stream and its writes fail
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+/// In-memory byte stream; one end of a `duplex()` pair
+///
+/// Writes never block. Reads block until data arrives, the read timeout
+/// passes (`WouldBlock`, as a timed-out socket read reports on Unix), or
+/// either end is closed (end of stream).
+#[derive(Debug)]
+pub struct DuplexStream {
+    end: Arc<DuplexEnd>,
+}
+
+/// Create a connected pair of in-memory streams: (initiator end, target end)
+///
+/// Hand the target end to `IscsiTarget::serve_stream` and the initiator end
+/// to `IscsiClient::from_stream`.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let to_target = Arc::new(Pipe::default());
+    let to_initiator = Arc::new(Pipe::default());
+    let initiator = DuplexEnd {
+        incoming: Arc::clone(&to_initiator),
+        outgoing: Arc::clone(&to_target),
+        read_timeout: Mutex::new(None),
+        local_addr: LOCAL_INITIATOR_ADDR,
+        peer_addr: LOCAL_TARGET_ADDR,
+    };
+    let target = DuplexEnd {
+        incoming: to_target,
+        outgoing: to_initiator,
+        read_timeout: Mutex::new(None),
+        local_addr: LOCAL_TARGET_ADDR,
+        peer_addr: LOCAL_INITIATOR_ADDR,
+    };
+    (DuplexStream { end: Arc::new(initiator) }, DuplexStream { end: Arc::new(target) })
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let timeout = *self.end.read_timeout.lock().unwrap_or_else(|e| e.into_inner());
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        let pipe = &self.end.incoming;
+        let mut state = pipe.lock();
+        while state.buffer.is_empty() && !state.closed {
+            state = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    pipe.ready.wait_timeout(state, remaining).unwrap_or_else(|e| e.into_inner()).0
+                }
+                None => pipe.ready.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+
+        let count = buf.len().min(state.buffer.len());
+        for (slot, byte) in buf.iter_mut().zip(state.buffer.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pipe = &self.end.outgoing;
+        let mut state = pipe.lock();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.buffer.extend(buf);
+        drop(state);
+        pipe.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for DuplexStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(DuplexStream { end: Arc::clone(&self.end) })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "zero read timeout"));
+        }
+        *self.end.read_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        // Writes never block
+        Ok(())
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.end.incoming.close();
+        self.end.outgoing.close();
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.end.local_addr)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.end.peer_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_duplex_stream() {
+        let (mut initiator, mut target) = duplex();
+        initiator.write_all(b"login").unwrap();
+        let mut buf = [0u8; 5];
+        target.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"login");
+        assert_eq!(target.local_addr().unwrap(), LOCAL_TARGET_ADDR);
+        assert_eq!(target.peer_addr().unwrap(), LOCAL_INITIATOR_ADDR);
+
+        // Reads time out like a socket read
+        target.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let err = target.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // A cloned handle reads what the peer writes from another thread
+        let mut reader = target.try_clone().unwrap();
+        reader.set_read_timeout(None).unwrap();
+        let writer = thread::spawn(move || initiator.write_all(b"data").unwrap());
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"data");
+        writer.join().unwrap();
+
+        // Dropping the initiator end (moved into the thread) ends the stream
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(target.write(b"x").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_duplex_close_wakes_reader() {
+        let (initiator, mut target) = duplex();
+        let closer = target.try_clone().unwrap();
+        let reader = thread::spawn(move || {
+            let mut buf = [0u8; 1];
+            target.read(&mut buf).unwrap()
+        });
+        thread::sleep(Duration::from_millis(20));
+        closer.close().unwrap();
+        assert_eq!(reader.join().unwrap(), 0);
+        drop(initiator);
+    }
+
+    #[test]
+    fn test_framed_round_trip() {
+        let (initiator, target) = duplex();
+        let (mut initiator, mut target) = (Framed::new(initiator), Framed::new(target));
+        let digests = Digests { header: true, data: true };
+
+        let mut pdu = IscsiPdu::new();
+        pdu.opcode = crate::pdu::opcode::NOP_OUT;
+        pdu.itt = 7;
+        pdu.data = b"ping!".to_vec();
+        initiator.send_pdu(&pdu, digests, None).unwrap();
+        match target.recv_pdu(8192, digests, &PduLimits::default(), None).unwrap() {
+            ReceivedPdu::Pdu(received) => {
+                assert_eq!(received.itt, 7);
+                assert_eq!(received.data, b"ping!");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // An oversized data segment is skipped, leaving the next PDU intact
+        initiator.send_pdu(&pdu, digests, None).unwrap();
+        pdu.itt = 8;
+        initiator.send_pdu(&pdu, digests, None).unwrap();
+        let received = target.recv_pdu(4, digests, &PduLimits::default(), None).unwrap();
+        assert!(matches!(received, ReceivedPdu::Oversized { data_length: 5, .. }));
+        let received = target.recv_pdu(8192, digests, &PduLimits::default(), None).unwrap();
+        assert!(matches!(received, ReceivedPdu::Pdu(pdu) if pdu.itt == 8));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_options_applied() {
+        use std::os::unix::io::AsRawFd;
+
+        let get = |stream: &TcpStream, level: libc::c_int, name: libc::c_int| {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY: `value` and `len` are valid for the duration of the call
+            let result = unsafe {
+                libc::getsockopt(stream.as_raw_fd(), level, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+            };
+            assert_eq!(result, 0);
+            value
+        };
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _initiator = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let options = SocketOptions {
+            recv_buffer: Some(256 * 1024),
+            send_buffer: Some(128 * 1024),
+            keepalive: Some(TcpKeepalive { idle: Duration::from_secs(60), interval: Duration::from_secs(5), retries: 4 }),
+            tos: Some(0xB8),
+            ..SocketOptions::default()
+        };
+        options.validate().unwrap();
+        stream.apply_socket_options(&options).unwrap();
+
+        assert!(stream.nodelay().unwrap());
+        // The kernel may round or double the requested sizes
+        assert!(get(&stream, libc::SOL_SOCKET, libc::SO_RCVBUF) >= 128 * 1024);
+        assert!(get(&stream, libc::SOL_SOCKET, libc::SO_SNDBUF) >= 64 * 1024);
+        assert_eq!(get(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(get(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 60);
+            assert_eq!(get(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 5);
+            assert_eq!(get(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 4);
+        }
+        assert_eq!(get(&stream, libc::IPPROTO_IP, libc::IP_TOS) & 0xFC, 0xB8);
+
+        let invalid = SocketOptions { recv_buffer: Some(0), ..SocketOptions::default() };
+        assert!(matches!(invalid.validate(), Err(IscsiError::Config(_))));
+        let invalid = SocketOptions {
+            keepalive: Some(TcpKeepalive { idle: Duration::from_millis(500), interval: Duration::from_secs(5), retries: 4 }),
+            ..SocketOptions::default()
+        };
+        assert!(matches!(invalid.validate(), Err(IscsiError::Config(_))));
+    }
+}
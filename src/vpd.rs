@@ -0,0 +1,205 @@
+//! INQUIRY Vital Product Data (VPD) pages
+//!
+//! Each supported page is an entry in `VPD_PAGES`; the Supported VPD Pages
+//! page (0x00) is generated from that registry, so adding a page is a single
+//! table entry plus its builder.
+
+use crate::scsi::ScsiBlockDevice;
+use byteorder::{BigEndian, ByteOrder};
+
+/// VPD page codes
+pub mod page {
+    pub const SUPPORTED_PAGES: u8 = 0x00;
+    pub const UNIT_SERIAL_NUMBER: u8 = 0x80;
+    pub const DEVICE_IDENTIFICATION: u8 = 0x83;
+    pub const EXTENDED_INQUIRY_DATA: u8 = 0x86;
+    pub const BLOCK_LIMITS: u8 = 0xB0;
+    pub const BLOCK_DEVICE_CHARACTERISTICS: u8 = 0xB1;
+    pub const LOGICAL_BLOCK_PROVISIONING: u8 = 0xB2;
+}
+
+/// Peripheral qualifier/device type byte: connected direct access block device
+const PERIPHERAL_DEVICE_TYPE: u8 = 0x00;
+
+/// Protocol identifier for iSCSI (SPC-4 Table 362)
+const PROTOCOL_ISCSI: u8 = 0x05;
+
+/// Identification descriptor reported in the Device Identification page (0x83)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Designator {
+    /// NAA identifier (8 or 16 bytes, including the NAA nibble)
+    Naa(Vec<u8>),
+    /// EUI-64 identifier
+    Eui64([u8; 8]),
+    /// T10 vendor ID based: vendor identification plus vendor-specific identifier
+    T10VendorId { vendor: String, identifier: String },
+    /// SCSI name string for the target device, e.g. the target IQN
+    ScsiName(String),
+}
+
+impl Designator {
+    /// Default NAA identifier derived from the unit serial number
+    ///
+    /// NAA 6 (IEEE Registered Extended) with a zero OUI and the remaining
+    /// bits taken from an MD5 of the serial, so it is stable per serial.
+    pub fn naa_from_serial(serial: &str) -> Self {
+        let mut naa = md5::compute(serial.as_bytes()).0;
+        naa[0] = 0x60;
+        naa[1] = 0x00;
+        naa[2] = 0x00;
+        naa[3] &= 0x0F;
+        Designator::Naa(naa.to_vec())
+    }
+
+    /// Encode as an identification descriptor (SPC-4 7.8.6.1)
+    fn to_bytes(&self) -> Vec<u8> {
+        // (protocol identifier/code set, PIV/association/designator type, designator)
+        let (byte0, byte1, designator) = match self {
+            Designator::Naa(naa) => (0x01, 0x03, naa.clone()),
+            Designator::Eui64(eui) => (0x01, 0x02, eui.to_vec()),
+            Designator::T10VendorId { vendor, identifier } => {
+                let mut designator = pad_ascii(vendor, 8);
+                designator.extend_from_slice(identifier.as_bytes());
+                (0x02, 0x01, designator)
+            }
+            Designator::ScsiName(name) => {
+                // Null terminated, padded to a multiple of 4 bytes
+                let mut designator = name.as_bytes().to_vec();
+                designator.push(0);
+                designator.resize(designator.len().div_ceil(4) * 4, 0);
+                // UTF-8, PIV=1, association=target device, type=SCSI name string
+                ((PROTOCOL_ISCSI << 4) | 0x03, 0x80 | 0x20 | 0x08, designator)
+            }
+        };
+
+        let mut data = vec![byte0, byte1, 0x00, designator.len() as u8];
+        data.extend_from_slice(&designator);
+        data
+    }
+}
+
+/// Limits reported in the Block Limits page (0xB0)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockLimits {
+    /// Maximum blocks per READ/WRITE command (0 = no limit reported)
+    pub max_transfer_length: u32,
+    /// Preferred blocks per command (0 = not reported)
+    pub optimal_transfer_length: u32,
+    /// Preferred transfer alignment in blocks (0 = one physical block)
+    pub optimal_transfer_granularity: u16,
+}
+
+impl Default for BlockLimits {
+    fn default() -> Self {
+        Self {
+            max_transfer_length: 65535,
+            optimal_transfer_length: 128,
+            optimal_transfer_granularity: 0,
+        }
+    }
+}
+
+type PageBuilder = fn(&dyn ScsiBlockDevice) -> Vec<u8>;
+
+/// Registry of supported VPD pages, in ascending page code order
+const VPD_PAGES: &[(u8, PageBuilder)] = &[
+    (page::SUPPORTED_PAGES, supported_pages),
+    (page::UNIT_SERIAL_NUMBER, unit_serial_number),
+    (page::DEVICE_IDENTIFICATION, device_identification),
+    (page::EXTENDED_INQUIRY_DATA, extended_inquiry_data),
+    (page::BLOCK_LIMITS, block_limits),
+    (page::BLOCK_DEVICE_CHARACTERISTICS, block_device_characteristics),
+    (page::LOGICAL_BLOCK_PROVISIONING, logical_block_provisioning),
+];
+
+/// Build the given VPD page, or `None` if it is not supported
+pub fn build_page(page_code: u8, device: &dyn ScsiBlockDevice) -> Option<Vec<u8>> {
+    VPD_PAGES.iter()
+        .find(|(code, _)| *code == page_code)
+        .map(|(_, build)| build(device))
+}
+
+/// Page header followed by `payload`, with a 16-bit page length
+fn page_with_payload(page_code: u8, payload: &[u8]) -> Vec<u8> {
+    let mut data = vec![PERIPHERAL_DEVICE_TYPE, page_code, 0, 0];
+    BigEndian::write_u16(&mut data[2..4], payload.len() as u16);
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Space-pad (or truncate) an ASCII field to `len` bytes
+fn pad_ascii(value: &str, len: usize) -> Vec<u8> {
+    let mut field: Vec<u8> = value.bytes().take(len).collect();
+    field.resize(len, b' ');
+    field
+}
+
+/// Supported VPD Pages (0x00)
+fn supported_pages(_device: &dyn ScsiBlockDevice) -> Vec<u8> {
+    let codes: Vec<u8> = VPD_PAGES.iter().map(|(code, _)| *code).collect();
+    page_with_payload(page::SUPPORTED_PAGES, &codes)
+}
+
+/// Unit Serial Number (0x80)
+fn unit_serial_number(device: &dyn ScsiBlockDevice) -> Vec<u8> {
+    page_with_payload(page::UNIT_SERIAL_NUMBER, device.serial_number().as_bytes())
+}
+
+/// Device Identification (0x83)
+fn device_identification(device: &dyn ScsiBlockDevice) -> Vec<u8> {
+    let descriptors: Vec<u8> = device.designators()
+        .iter()
+        .flat_map(Designator::to_bytes)
+        .collect();
+    page_with_payload(page::DEVICE_IDENTIFICATION, &descriptors)
+}
+
+/// Block Limits (0xB0)
+fn block_limits(device: &dyn ScsiBlockDevice) -> Vec<u8> {
+    let limits = device.block_limits();
+    let granularity = match limits.optimal_transfer_granularity {
+        0 => 1u16 << device.physical_block_exponent().min(15),
+        blocks => blocks,
+    };
+
+    let mut payload = vec![0u8; 0x3C];
+    BigEndian::write_u16(&mut payload[2..4], granularity);
+    BigEndian::write_u32(&mut payload[4..8], limits.max_transfer_length);
+    BigEndian::write_u32(&mut payload[8..12], limits.optimal_transfer_length);
+    page_with_payload(page::BLOCK_LIMITS, &payload)
+}
+
+/// Extended INQUIRY Data (0x86): supported protection type and checks, and
+/// the task attributes the target honors
+fn extended_inquiry_data(device: &dyn ScsiBlockDevice) -> Vec<u8> {
+    let mut payload = vec![0u8; 0x3C];
+    // SPT: 000b Type 1, 010b Type 2, 100b Type 3
+    let protection_type = device.protection_type();
+    if (1..=3).contains(&protection_type) {
+        payload[0] = ((protection_type - 1) << 4) | 0x06; // GRD_CHK, APP_CHK
+        if protection_type != 3 {
+            payload[0] |= 0x01; // REF_CHK
+        }
+    }
+    payload[1] = 0x1C; // HEADSUP, ORDSUP, SIMPSUP
+    page_with_payload(page::EXTENDED_INQUIRY_DATA, &payload)
+}
+
+/// Block Device Characteristics (0xB1)
+fn block_device_characteristics(device: &dyn ScsiBlockDevice) -> Vec<u8> {
+    let mut payload = vec![0u8; 0x3C];
+    BigEndian::write_u16(&mut payload[0..2], device.medium_rotation_rate());
+    page_with_payload(page::BLOCK_DEVICE_CHARACTERISTICS, &payload)
+}
+
+/// Logical Block Provisioning (0xB2)
+fn logical_block_provisioning(device: &dyn ScsiBlockDevice) -> Vec<u8> {
+    let mut payload = vec![0u8; 4];
+    if device.thin_provisioned() {
+        if device.unmapped_reads_zero() {
+            payload[1] |= 0x04; // LBPRZ
+        }
+        payload[2] = 0x02; // PROVISIONING TYPE: thin provisioned
+    }
+    page_with_payload(page::LOGICAL_BLOCK_PROVISIONING, &payload)
+}
@@ -0,0 +1,116 @@
+//! Worker thread pool for executing queued SCSI commands
+//!
+//! Shared by all connections of a target. Threads exit once the pool and
+//! every clone of its handle have been dropped.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed-size pool of worker threads
+#[derive(Debug)]
+pub(crate) struct WorkerPool {
+    jobs: Sender<Job>,
+}
+
+impl WorkerPool {
+    /// Start a pool with `threads` workers
+    pub(crate) fn new(threads: usize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("iscsi-worker-{}", i))
+                .spawn(move || loop {
+                    // The lock is released before the job runs
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => break,
+                    };
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to spawn worker thread");
+        }
+
+        Self { jobs }
+    }
+
+    /// Queue a job for execution on the next free worker
+    pub(crate) fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        if self.jobs.send(Box::new(job)).is_err() {
+            log::error!("Worker pool has shut down, dropping job");
+        }
+    }
+
+    /// Run `job` on the pool and wait up to `timeout` for its result
+    ///
+    /// Returns None if the job has not finished in time; it keeps its worker
+    /// until it returns, and its result is dropped. A panic in the job is
+    /// resumed on the calling thread.
+    pub(crate) fn run_within<R: Send + 'static>(
+        &self,
+        timeout: Duration,
+        job: impl FnOnce() -> R + Send + 'static,
+    ) -> Option<R> {
+        let (result, finished) = mpsc::channel();
+        self.execute(move || {
+            let _ = result.send(panic::catch_unwind(AssertUnwindSafe(job)));
+        });
+        match finished.recv_timeout(timeout) {
+            Ok(Ok(value)) => Some(value),
+            Ok(Err(payload)) => panic::resume_unwind(payload),
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_jobs_run_concurrently() {
+        let pool = WorkerPool::new(2);
+        let barrier = Arc::new(Barrier::new(3));
+        let (done, finished) = mpsc::channel();
+
+        // Both jobs must be running at once to get past the barrier
+        for i in 0..2 {
+            let barrier = Arc::clone(&barrier);
+            let done = done.clone();
+            pool.execute(move || {
+                barrier.wait();
+                done.send(i).unwrap();
+            });
+        }
+        barrier.wait();
+
+        let mut results: Vec<i32> = finished.iter().take(2).collect();
+        results.sort();
+        assert_eq!(results, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_run_within_gives_up_at_timeout() {
+        let pool = WorkerPool::new(1);
+        assert_eq!(pool.run_within(Duration::from_secs(5), || 7), Some(7));
+
+        // The stalled job is not waited for, and still holds the worker
+        let (release, gate) = mpsc::channel::<()>();
+        let stalled = pool.run_within(Duration::from_millis(20), move || gate.recv().is_ok());
+        assert_eq!(stalled, None);
+        assert_eq!(pool.run_within(Duration::from_millis(20), || 8), None);
+        release.send(()).unwrap();
+        assert_eq!(pool.run_within(Duration::from_secs(5), || 9), Some(9));
+    }
+}
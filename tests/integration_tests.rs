@@ -2,15 +2,17 @@
 //!
 //! These tests replicate the functionality of the C-based test suite but in pure Rust.
 //! They test:
-//! - Discovery and login
+//! - Discovery and login, including one-way and mutual CHAP
 //! - SCSI commands
 //! - I/O operations
 //! - Parameter negotiation
-//! - Error handling
+//! - Error handling, including faults injected with `FaultyBlockDevice`
 //! - Arbitrary PDU transmission (for testing edge cases)
 
-use iscsi_target::{IscsiClient, IscsiTarget, ScsiBlockDevice, ScsiResult};
-use iscsi_target::pdu::{opcode, IscsiPdu};
+use iscsi_target::session::DigestType;
+use iscsi_target::{duplex, AuthFailure, ChapCredentials, DiscoveredTarget, FaultInjector, FaultyBlockDevice, IscsiClient, IscsiError, LoginOptions, IscsiTarget, MemBlockDevice, ScsiBlockDevice, ScsiDeviceError, ScsiResult, SenseCode, Strictness};
+use iscsi_target::pdu::builder::PduBuilder;
+use iscsi_target::pdu::{login_status, opcode, IscsiPdu};
 use once_cell::sync::Lazy;
 use std::env;
 use std::time::Duration;
@@ -170,7 +172,7 @@ fn execute_scsi_command(client: &mut IscsiClient, cdb: &[u8], data: Option<&[u8]
 }
 
 /// Perform discovery with helpful error message on failure
-fn discover_targets(client: &mut IscsiClient) -> Vec<(String, String)> {
+fn discover_targets(client: &mut IscsiClient) -> Vec<DiscoveredTarget> {
     client.discover(initiator_iqn())
         .unwrap_or_else(|e| {
             panic!(
@@ -209,73 +211,6 @@ fn write10_cdb(lba: u32, num_blocks: u16) -> Vec<u8> {
     cdb
 }
 
-// ============================================================================
-// Test Storage Implementation
-// ============================================================================
-
-/// Simple in-memory storage for testing
-struct TestStorage {
-    data: Vec<u8>,
-}
-
-impl TestStorage {
-    fn new(size_mb: usize) -> Self {
-        TestStorage {
-            data: vec![0u8; size_mb * 1024 * 1024],
-        }
-    }
-}
-
-impl ScsiBlockDevice for TestStorage {
-    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-        let offset = (lba * block_size as u64) as usize;
-        let len = (blocks * block_size) as usize;
-        if offset + len > self.data.len() {
-            return Err(iscsi_target::IscsiError::Scsi(
-                "Read beyond storage capacity".to_string(),
-            ));
-        }
-        Ok(self.data[offset..offset + len].to_vec())
-    }
-
-    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-        let offset = (lba * block_size as u64) as usize;
-        if offset + data.len() > self.data.len() {
-            return Err(iscsi_target::IscsiError::Scsi(
-                "Write beyond storage capacity".to_string(),
-            ));
-        }
-        self.data[offset..offset + data.len()].copy_from_slice(data);
-        Ok(())
-    }
-
-    fn capacity(&self) -> u64 {
-        (self.data.len() / 512) as u64
-    }
-
-    fn block_size(&self) -> u32 {
-        512
-    }
-}
-
-/// Start an iSCSI target server in a background thread
-fn start_test_target() -> Result<std::thread::JoinHandle<()>, Box<dyn std::error::Error>> {
-    let storage = TestStorage::new(100); // 100 MB
-    let target = IscsiTarget::builder()
-        .bind_addr(target_addr())
-        .target_name(target_iqn())
-        .build(storage)?;
-
-    let handle = std::thread::spawn(move || {
-        let _ = target.run();
-    });
-
-    // Give the server time to start
-    std::thread::sleep(Duration::from_millis(500));
-
-    Ok(handle)
-}
-
 // Note: These tests are designed to be run with `cargo test -- --test-threads=1`
 // and require a running iSCSI target. They should be run as integration tests.
 
@@ -389,7 +324,7 @@ fn test_discovery_basic() {
     );
 
     // Verify we discovered our expected target
-    let found = targets.iter().any(|(iqn, _)| iqn == target_iqn());
+    let found = targets.iter().any(|target| target.iqn == target_iqn());
     assert!(found,
         "Expected target '{}' not found in discovery results\n\
          \n\
@@ -402,14 +337,16 @@ fn test_discovery_basic() {
          Fix by updating test-config.toml with correct IQN from discovery",
         target_iqn(),
         targets.iter()
-            .map(|(iqn, addr)| format!("  - {} at {}", iqn, addr))
+            .map(|target| format!("  - {} at {:?}", target.iqn, target.portals))
             .collect::<Vec<_>>()
             .join("\n")
     );
 
     println!("✓ Discovery successful: {} target(s)", targets.len());
-    for (iqn, addr) in &targets {
-        println!("  - {} at {}", iqn, addr);
+    for target in &targets {
+        for (addr, tpgt) in &target.portals {
+            println!("  - {} at {} (TPGT {})", target.iqn, addr, tpgt);
+        }
     }
 }
 
@@ -444,6 +381,24 @@ fn test_login_basic() {
     println!("✓ Login and logout successful");
 }
 
+/// TL-002: In-band diagnostic echo (X-diagnostic.echo)
+#[test]
+#[ignore]
+fn test_diagnostic_echo() {
+    let mut client = connect_to_target();
+    login_to_target(&mut client);
+
+    let payload = "x".repeat(1024);
+    let reply = client.diagnostic_echo(&payload)
+        .unwrap_or_else(|e| panic!("X-diagnostic.echo failed: {}", e));
+
+    assert_eq!(reply.payload, payload, "Target should echo the payload unchanged");
+    assert!(reply.target_timestamp_us.is_some(), "Target should report its receive time");
+    println!("✓ Echo round trip: {:?}", reply.round_trip);
+
+    client.logout().ok();
+}
+
 /// TC-001: INQUIRY Command (SCSI)
 #[test]
 #[ignore]
@@ -638,29 +593,13 @@ fn test_scsi_invalid_lun() {
     let mut client = connect_to_target();
     login_to_target(&mut client);
 
-    // Send INQUIRY to invalid LUN 99
-    // Need to construct raw PDU to specify LUN
-    let mut pdu = IscsiPdu::new();
-    pdu.opcode = opcode::SCSI_COMMAND;
-    pdu.flags = 0x80; // Final
-    pdu.lun = 99 << 48; // LUN 99 (invalid - should be LUN 0)
-    pdu.itt = 1;
-
-    // INQUIRY CDB
-    let cdb = vec![0x12, 0x00, 0x00, 0x00, 0xFF, 0x00];
-    pdu.data = cdb;
-
-    client.send_raw_pdu(&pdu)
-        .expect("Failed to send raw PDU to invalid LUN");
+    // TEST UNIT READY to LUN 99, peripheral device addressed like every client LUN
+    client.set_lun(99);
+    let error = client.execute_command(&[0; 6], None, 0).expect_err("Target accepted a command to LUN 99");
+    assert_eq!(error.sense_code(), Some(SenseCode::LOGICAL_UNIT_NOT_SUPPORTED),
+        "Expected LOGICAL UNIT NOT SUPPORTED, got {}", error);
 
-    let response = client.recv_pdu()
-        .expect("Failed to receive response from invalid LUN command");
-
-    assert_eq!(response.opcode, opcode::SCSI_RESPONSE,
-        "Expected SCSI Response, got opcode 0x{:02x}", response.opcode);
-
-    println!("✓ Invalid LUN command handled - target returned response");
-    println!("  (Should contain CHECK CONDITION with LOGICAL_UNIT_NOT_SUPPORTED sense code)");
+    println!("✓ Invalid LUN command rejected with LOGICAL UNIT NOT SUPPORTED");
 
     client.logout().ok();
 }
@@ -1320,7 +1259,7 @@ fn test_login_invalid_max_recv_data_size() {
             pdu.data = params.as_bytes().to_vec();
 
             // Pad to 4-byte boundary
-            while pdu.data.len() % 4 != 0 {
+            while !pdu.data.len().is_multiple_of(4) {
                 pdu.data.push(0);
             }
 
@@ -1341,6 +1280,200 @@ fn test_login_invalid_max_recv_data_size() {
     }
 }
 
+// ============================================================================
+// Fault injection (in-process target, no running target required)
+// ============================================================================
+
+/// Target serving a `FaultyBlockDevice` over an in-memory connection
+fn faulty_target() -> (IscsiTarget<FaultyBlockDevice<MemBlockDevice>>, IscsiClient, FaultInjector) {
+    let device = FaultyBlockDevice::new(MemBlockDevice::new(1024, 512));
+    let faults = device.injector();
+    let target = IscsiTarget::builder()
+        .target_name("iqn.2025-12.local:faulty")
+        .build(device)
+        .expect("Failed to build target");
+    let (initiator, stream) = duplex();
+    target.serve_stream(stream).expect("Failed to serve connection");
+    let mut client = IscsiClient::from_stream(initiator);
+    client.login("iqn.2025-12.local:initiator", "iqn.2025-12.local:faulty").expect("Login failed");
+    (target, client, faults)
+}
+
+#[test]
+fn test_fault_medium_error_range() {
+    let (target, mut client, faults) = faulty_target();
+    faults.medium_error(100..104);
+
+    let error = client.read_blocks(98, 4).expect_err("READ over bad blocks should fail");
+    assert_eq!(error.sense_code(), Some(SenseCode::MEDIUM_ERROR));
+    let error = client.write_blocks(103, &[0xAA; 512]).expect_err("WRITE to a bad block should fail");
+    assert_eq!(error.sense_code(), Some(SenseCode::MEDIUM_ERROR));
+
+    // The session carries on past the failed commands
+    assert_eq!(client.read_blocks(104, 2).unwrap(), vec![0; 1024]);
+    faults.clear();
+    assert_eq!(client.read_blocks(100, 4).unwrap(), vec![0; 2048]);
+    assert_eq!(faults.injected_faults(), 2);
+
+    client.logout().ok();
+    target.stop();
+}
+
+#[test]
+fn test_fault_every_nth_write() {
+    let (target, mut client, faults) = faulty_target();
+    faults.fail_every_nth_write(2, ScsiDeviceError::NotReady);
+
+    client.write_blocks(0, &[1; 512]).unwrap();
+    let error = client.write_blocks(1, &[2; 512]).expect_err("Second WRITE should fail");
+    assert_eq!(error.sense_code(), Some(SenseCode::NOT_READY));
+    // An initiator retrying NOT READY gets the write through
+    client.write_blocks(1, &[2; 512]).unwrap();
+
+    assert_eq!(client.read_blocks(0, 2).unwrap(), [vec![1; 512], vec![2; 512]].concat());
+    client.logout().ok();
+    target.stop();
+}
+
+#[test]
+fn test_fault_latency() {
+    let (target, mut client, faults) = faulty_target();
+    faults.latency(Duration::from_millis(30), Duration::from_millis(20));
+
+    let start = std::time::Instant::now();
+    client.write_blocks(7, &[3; 512]).unwrap();
+    assert_eq!(client.read_blocks(7, 1).unwrap(), vec![3; 512]);
+    assert!(start.elapsed() >= Duration::from_millis(60));
+
+    client.logout().ok();
+    target.stop();
+}
+
+// ============================================================================
+// CHAP authentication (in-process target, no running target required)
+// ============================================================================
+
+const CHAP_TARGET: &str = "iqn.2025-12.local:chap";
+const CHAP_INITIATOR: &str = "iqn.2025-12.local:initiator";
+
+/// Target requiring CHAP as `initiator-user`, answering mutual CHAP as
+/// `target-user` when `mutual` is set
+fn chap_target(mutual: bool) -> IscsiTarget<MemBlockDevice> {
+    let mut builder = IscsiTarget::builder()
+        .target_name(CHAP_TARGET)
+        .chap_account("initiator-user", "initiator-secret");
+    if mutual {
+        builder = builder.mutual_chap_credentials(ChapCredentials::new("target-user", "target-secret-1"));
+    }
+    builder.build(MemBlockDevice::new(64, 512)).expect("Failed to build CHAP target")
+}
+
+fn chap_client(target: &IscsiTarget<MemBlockDevice>) -> IscsiClient {
+    let (initiator, stream) = duplex();
+    target.serve_stream(stream).expect("Failed to serve connection");
+    IscsiClient::from_stream(initiator)
+}
+
+#[test]
+fn test_chap_one_way_login() {
+    let target = chap_target(false);
+
+    let mut client = chap_client(&target);
+    client.login_chap(CHAP_INITIATOR, CHAP_TARGET, "initiator-user", "initiator-secret")
+        .expect("One-way CHAP login failed");
+    client.write_blocks(1, &[0x42; 512]).unwrap();
+    assert_eq!(client.read_blocks(1, 1).unwrap(), vec![0x42; 512]);
+    client.logout().ok();
+
+    let mut client = chap_client(&target);
+    assert!(client.login_chap(CHAP_INITIATOR, CHAP_TARGET, "initiator-user", "wrong-secret-0").is_err());
+    let mut client = chap_client(&target);
+    assert!(client.login(CHAP_INITIATOR, CHAP_TARGET).is_err(), "Target should require CHAP");
+    target.stop();
+}
+
+#[test]
+fn test_chap_mutual_login() {
+    let target = chap_target(true);
+
+    let mut client = chap_client(&target);
+    client.login_mutual_chap(CHAP_INITIATOR, CHAP_TARGET, "initiator-user", "initiator-secret", "target-user", "target-secret-1")
+        .expect("Mutual CHAP login failed");
+    assert!(client.is_logged_in());
+    assert_eq!(client.read_capacity().unwrap().block_size, 512);
+    client.logout().ok();
+
+    // The initiator refuses a target that cannot prove the expected secret
+    let mut client = chap_client(&target);
+    let error = client.login_mutual_chap(CHAP_INITIATOR, CHAP_TARGET, "initiator-user", "initiator-secret", "target-user", "other-secret-1")
+        .expect_err("Target answered with an unexpected secret");
+    assert!(error.to_string().contains("mutual CHAP"), "unexpected error: {}", error);
+    assert!(matches!(error, IscsiError::Auth { reason: AuthFailure::BadResponse, .. }), "unexpected error: {:?}", error);
+
+    // ... and one that answers under another CHAP name
+    let mut client = chap_client(&target);
+    let error = client.login_mutual_chap(CHAP_INITIATOR, CHAP_TARGET, "initiator-user", "initiator-secret", "other-target", "target-secret-1")
+        .expect_err("Target answered under an unexpected name");
+    assert!(matches!(error, IscsiError::Auth { reason: AuthFailure::BadResponse, .. }), "unexpected error: {:?}", error);
+    target.stop();
+}
+
+#[test]
+fn test_strict_target_accepts_client() {
+    let target = IscsiTarget::builder()
+        .target_name(CHAP_TARGET)
+        .chap_account("initiator-user", "initiator-secret")
+        .mutual_chap_credentials(ChapCredentials::new("target-user", "target-secret-1"))
+        .strictness(Strictness::Strict)
+        .build(MemBlockDevice::new(64, 512))
+        .expect("Failed to build strict target");
+
+    let mut client = chap_client(&target);
+    client.login_mutual_chap(CHAP_INITIATOR, CHAP_TARGET, "initiator-user", "initiator-secret", "target-user", "target-secret-1")
+        .expect("Client logins should conform to RFC 3720");
+    client.write_blocks(0, &[5; 1024]).unwrap();
+    assert_eq!(client.read_blocks(0, 2).unwrap(), vec![5; 1024]);
+    client.logout().ok();
+    target.stop();
+}
+
+#[test]
+fn test_negotiated_params_introspection() {
+    let target = IscsiTarget::builder()
+        .target_name(CHAP_TARGET)
+        .max_burst_length(131072)
+        .max_recv_data_segment_length(32768)
+        .build(MemBlockDevice::new(64, 512))
+        .expect("Failed to build target");
+    let mut client = chap_client(&target);
+    client.set_data_digest(true);
+    client.login(CHAP_INITIATOR, CHAP_TARGET).expect("Login failed");
+    // Sessions are registered before their first command is served
+    client.read_capacity().unwrap();
+
+    let params = client.negotiated_params().expect("No parameters after login").clone();
+    assert_eq!(params.data_digest, DigestType::CRC32C);
+    assert_eq!(params.header_digest, DigestType::None);
+    assert_eq!(params.max_burst_length, 131072);
+    assert_eq!(params.max_xmit_data_segment_length, 32768);
+    assert_eq!(params.error_recovery_level, 0);
+    assert_eq!(params.target_name, CHAP_TARGET);
+
+    let sessions = target.control().sessions();
+    assert_eq!(sessions.len(), 1);
+    let theirs = &sessions[0].params;
+    assert_eq!(theirs.initiator_name, CHAP_INITIATOR);
+    assert_eq!(theirs.data_digest, params.data_digest);
+    assert_eq!(theirs.max_burst_length, params.max_burst_length);
+    assert_eq!(theirs.first_burst_length, params.first_burst_length);
+    assert_eq!(theirs.initial_r2t, params.initial_r2t);
+    assert_eq!(theirs.max_xmit_data_segment_length, params.max_recv_data_segment_length);
+
+    client.logout().ok();
+    assert!(client.negotiated_params().is_none());
+    target.stop();
+}
+
 // ============================================================================
 // Unit tests (these don't require a running target)
 // ============================================================================
@@ -1384,3 +1517,168 @@ fn test_pdu_data_padding() {
     let parsed = IscsiPdu::from_bytes(&bytes).expect("Failed to parse PDU");
     assert_eq!(parsed.data, b"ABC");
 }
+
+/// Answers XDWRITEREAD (10) with the Data-Out XORed with the blocks it replaces
+struct XorDevice(MemBlockDevice);
+
+impl ScsiBlockDevice for XorDevice {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        self.0.read(lba, blocks, block_size)
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        self.0.write(lba, data, block_size)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.0.capacity()
+    }
+
+    fn block_size(&self) -> u32 {
+        self.0.block_size()
+    }
+
+    fn bidirectional(&mut self, cdb: &[u8], data_out: &[u8]) -> ScsiResult<Vec<u8>> {
+        let lba = u32::from_be_bytes([cdb[2], cdb[3], cdb[4], cdb[5]]) as u64;
+        let old = self.0.read(lba, (data_out.len() / 512) as u32, 512)?;
+        self.0.write(lba, data_out, 512)?;
+        Ok(old.iter().zip(data_out).map(|(a, b)| a ^ b).collect())
+    }
+}
+
+#[test]
+fn test_bidirectional_xdwriteread() {
+    let target = IscsiTarget::builder()
+        .target_name("iqn.2025-12.local:xor")
+        .build(XorDevice(MemBlockDevice::new(64, 512)))
+        .expect("Failed to build target");
+    let (initiator, stream) = duplex();
+    target.serve_stream(stream).expect("Failed to serve connection");
+    let mut client = IscsiClient::from_stream(initiator);
+    client.login("iqn.2025-12.local:initiator", "iqn.2025-12.local:xor").expect("Login failed");
+
+    client.write_blocks(4, &[0x0F; 1024]).unwrap();
+    let xdwriteread = [0x53, 0, 0, 0, 0, 4, 0, 0, 2, 0];
+    let xor = client.execute_bidirectional(&xdwriteread, &[0xFF; 1024], 1024).unwrap();
+    assert_eq!(xor, vec![0xF0; 1024]);
+    assert_eq!(client.read_blocks(4, 2).unwrap(), vec![0xFF; 1024]);
+
+    client.logout().ok();
+    target.stop();
+}
+
+#[test]
+fn test_write_answers_r2ts() {
+    let data: Vec<u8> = (0..65536u32).map(|i| (i / 512 + i % 7) as u8).collect();
+    // (immediate data, client InitialR2T): exercises immediate, unsolicited
+    // Data-Out and solicited sequences, each burst split into PDUs
+    for (immediate_data, initial_r2t) in [(true, true), (true, false), (false, false), (false, true)] {
+        let target = IscsiTarget::builder()
+            .target_name("iqn.2025-12.local:r2t")
+            .max_burst_length(16384)
+            .first_burst_length(8192)
+            .immediate_data(immediate_data)
+            .max_outstanding_r2t(2)
+            .build(MemBlockDevice::new(256, 512))
+            .expect("Failed to build target");
+        let (initiator, stream) = duplex();
+        target.serve_stream(stream).expect("Failed to serve connection");
+        let mut client = IscsiClient::from_stream(initiator);
+        let options = LoginOptions {
+            max_recv_data_segment_length: 4096,
+            initial_r2t,
+            extra: vec![("MaxOutstandingR2T".to_string(), "2".to_string())],
+            ..LoginOptions::default()
+        };
+        client.login_with_options("iqn.2025-12.local:initiator", "iqn.2025-12.local:r2t", &options)
+            .expect("Login failed");
+        assert_eq!(client.negotiated_params().unwrap().initial_r2t, initial_r2t);
+
+        client.write_blocks(8, &data).unwrap_or_else(|e| panic!("{:?}: {}", (immediate_data, initial_r2t), e));
+        assert_eq!(client.read_blocks(8, 128).unwrap(), data, "{:?}", (immediate_data, initial_r2t));
+        client.logout().ok();
+        target.stop();
+    }
+}
+
+#[test]
+fn test_login_with_options() {
+    let target = IscsiTarget::builder()
+        .target_name("iqn.2025-12.local:options")
+        .build(MemBlockDevice::new(64, 512))
+        .expect("Failed to build target");
+    let login = |options: &LoginOptions| {
+        let (initiator, stream) = duplex();
+        target.serve_stream(stream).expect("Failed to serve connection");
+        let mut client = IscsiClient::from_stream(initiator);
+        let reply = client.login_with_options("iqn.2025-12.local:initiator", "iqn.2025-12.local:options", options)
+            .expect("Login failed");
+        (client, reply)
+    };
+    let answer = |reply: &[(String, String)], key: &str| reply.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+
+    let (mut client, reply) = login(&LoginOptions {
+        header_digest: "CRC32C,None".to_string(),
+        max_recv_data_segment_length: 4096,
+        extra: vec![("MaxOutstandingR2T".to_string(), "4".to_string())],
+        ..LoginOptions::default()
+    });
+    assert_eq!(answer(&reply, "HeaderDigest").as_deref(), Some("CRC32C"));
+    assert!(answer(&reply, "MaxOutstandingR2T").is_some());
+    assert_eq!(client.negotiated_params().unwrap().max_recv_data_segment_length, 4096);
+
+    // Header digests are in use from the first full feature PDU, and reads
+    // arrive in segments the client's MaxRecvDataSegmentLength allows
+    client.write_blocks(0, &[0x3C; 4096]).unwrap();
+    assert_eq!(client.read_blocks(0, 8).unwrap(), vec![0x3C; 4096]);
+    client.logout().ok();
+
+    let (client, reply) = login(&LoginOptions { immediate_data: false, ..LoginOptions::default() });
+    assert_eq!(answer(&reply, "ImmediateData").as_deref(), Some("No"));
+    assert!(!client.negotiated_params().unwrap().immediate_data);
+    target.stop();
+}
+
+#[test]
+fn test_malformed_login_pdus() {
+    let target = IscsiTarget::builder()
+        .target_name("iqn.2025-12.local:malformed")
+        .build(MemBlockDevice::new(64, 512))
+        .expect("Failed to build target");
+    // Send one malformed Login Request on a fresh connection
+    let send = |build: &dyn Fn([u8; 6]) -> PduBuilder| {
+        let (initiator, stream) = duplex();
+        target.serve_stream(stream).expect("Failed to serve connection");
+        let mut client = IscsiClient::from_stream(initiator);
+        let request = build(client.isid())
+            .key("InitiatorName", "iqn.2025-12.local:initiator")
+            .key("TargetName", "iqn.2025-12.local:malformed");
+        client.send_malformed(&request)
+    };
+    let status = |response: &IscsiPdu| {
+        assert_eq!(response.opcode, opcode::LOGIN_RESPONSE);
+        u16::from_be_bytes([response.specific[16], response.specific[17]])
+    };
+
+    // Reserved stage 2, a transition back to security negotiation, and full
+    // feature phase as the current stage are initiator errors
+    for (csg, nsg) in [(2, 3), (1, 0), (3, 3)] {
+        let response = send(&|isid| PduBuilder::login(isid, csg, nsg, true)).unwrap();
+        assert_eq!(status(&response), login_status::INITIATOR_ERROR_GENERIC, "CSG {} NSG {}", csg, nsg);
+    }
+
+    // Out-of-range and repeated keys may be refused or settled, but must be answered
+    let response = send(&|isid| PduBuilder::login(isid, 1, 3, true).zero_max_recv_data_segment_length()).unwrap();
+    status(&response);
+    let response = send(&|isid| PduBuilder::login(isid, 1, 3, true).contradicting_key("HeaderDigest", "None", "CRC32C")).unwrap();
+    status(&response);
+
+    // Cutting the data segment short drops the keys after the cut
+    let response = send(&|isid| PduBuilder::login(isid, 0, 1, true).data_length(8)).unwrap();
+    assert_eq!(status(&response), login_status::MISSING_PARAMETER);
+
+    // Data that never arrives stalls the login until the target drops it
+    let error = send(&|isid| PduBuilder::login(isid, 0, 1, true).data_length(0x00FF_FFFF)).unwrap_err();
+    assert!(matches!(error, IscsiError::Io(_)), "unexpected error: {}", error);
+    target.stop();
+}
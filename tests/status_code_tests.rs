@@ -185,60 +185,16 @@ fn test_all_rfc_3720_status_codes_have_messages() {
 mod integration {
     use iscsi_target::IscsiClient;
 
-    fn target_addr() -> &'static str {
-        "127.0.0.1:3261"
-    }
-
     /// Test that wrong target name returns TARGET_NOT_FOUND (0x0203)
     #[test]
     fn test_server_returns_target_not_found() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
-
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
-            }
-        }
-
-        impl ScsiBlockDevice for TestStorage {
-            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-                let offset = (lba * block_size as u64) as usize;
-                let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                Ok(self.data[offset..offset + len].to_vec())
-            }
-
-            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-                let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                self.data[offset..offset + data.len()].copy_from_slice(data);
-                Ok(())
-            }
-
-            fn capacity(&self) -> u64 {
-                (self.data.len() / 512) as u64
-            }
-
-            fn block_size(&self) -> u32 {
-                512
-            }
-        }
-
         // Start target with specific IQN
-        let storage = TestStorage::new(10);
+        let storage = MemBlockDevice::new(10 * 2048, 512);
         let target = IscsiTarget::builder()
             .bind_addr("127.0.0.1:13261")
             .target_name("iqn.2025-12.test:correct-name")
@@ -280,55 +236,15 @@ mod integration {
     #[test]
     fn test_server_returns_missing_parameter() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
         use iscsi_target::pdu::IscsiPdu;
         use std::io::{Read as IoRead, Write as IoWrite};
         use std::net::TcpStream;
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
-
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
-            }
-        }
-
-        impl ScsiBlockDevice for TestStorage {
-            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-                let offset = (lba * block_size as u64) as usize;
-                let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                Ok(self.data[offset..offset + len].to_vec())
-            }
-
-            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-                let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                self.data[offset..offset + data.len()].copy_from_slice(data);
-                Ok(())
-            }
-
-            fn capacity(&self) -> u64 {
-                (self.data.len() / 512) as u64
-            }
-
-            fn block_size(&self) -> u32 {
-                512
-            }
-        }
-
         // Start target
-        let storage = TestStorage::new(10);
+        let storage = MemBlockDevice::new(10 * 2048, 512);
         let target = IscsiTarget::builder()
             .bind_addr("127.0.0.1:13263")
             .target_name("iqn.2025-12.test:missing-param")
@@ -353,7 +269,7 @@ mod integration {
         let params = "TargetName=iqn.2025-12.test:missing-param\0AuthMethod=None\0";
         let padded_params = {
             let mut p = params.to_string();
-            while p.len() % 4 != 0 {
+            while !p.len().is_multiple_of(4) {
                 p.push('\0');
             }
             p.into_bytes()
@@ -399,54 +315,14 @@ mod integration {
     #[test]
     fn test_server_returns_auth_failure() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult, AuthConfig, ChapCredentials};
+        use iscsi_target::{IscsiTarget, MemBlockDevice, AuthConfig, ChapCredentials};
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
-
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
-            }
-        }
-
-        impl ScsiBlockDevice for TestStorage {
-            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-                let offset = (lba * block_size as u64) as usize;
-                let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                Ok(self.data[offset..offset + len].to_vec())
-            }
-
-            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-                let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                self.data[offset..offset + data.len()].copy_from_slice(data);
-                Ok(())
-            }
-
-            fn capacity(&self) -> u64 {
-                (self.data.len() / 512) as u64
-            }
-
-            fn block_size(&self) -> u32 {
-                512
-            }
-        }
-
         // Start target with CHAP authentication required
-        let storage = TestStorage::new(10);
+        let storage = MemBlockDevice::new(10 * 2048, 512);
         let auth_config = AuthConfig::Chap {
-            credentials: ChapCredentials::new("testuser", "testpass"),
+            credentials: ChapCredentials::new("testuser", "testpass-123456"),
         };
 
         let target = IscsiTarget::builder()
@@ -492,52 +368,12 @@ mod integration {
     #[test]
     fn test_server_returns_service_unavailable_on_shutdown() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
-
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
-            }
-        }
-
-        impl ScsiBlockDevice for TestStorage {
-            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-                let offset = (lba * block_size as u64) as usize;
-                let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                Ok(self.data[offset..offset + len].to_vec())
-            }
-
-            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-                let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                self.data[offset..offset + data.len()].copy_from_slice(data);
-                Ok(())
-            }
-
-            fn capacity(&self) -> u64 {
-                (self.data.len() / 512) as u64
-            }
-
-            fn block_size(&self) -> u32 {
-                512
-            }
-        }
-
         // Start target in background thread
-        let storage = TestStorage::new(10);
+        let storage = MemBlockDevice::new(10 * 2048, 512);
         let target = IscsiTarget::builder()
             .bind_addr("127.0.0.1:13260")
             .target_name("iqn.2025-12.test:shutdown")
@@ -588,55 +424,15 @@ mod integration {
     #[test]
     fn test_server_returns_session_type_not_supported() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
         use iscsi_target::pdu::IscsiPdu;
         use std::io::{Read as IoRead, Write as IoWrite};
         use std::net::TcpStream;
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
-
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
-            }
-        }
-
-        impl ScsiBlockDevice for TestStorage {
-            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-                let offset = (lba * block_size as u64) as usize;
-                let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                Ok(self.data[offset..offset + len].to_vec())
-            }
-
-            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-                let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                self.data[offset..offset + data.len()].copy_from_slice(data);
-                Ok(())
-            }
-
-            fn capacity(&self) -> u64 {
-                (self.data.len() / 512) as u64
-            }
-
-            fn block_size(&self) -> u32 {
-                512
-            }
-        }
-
         // Start target
-        let storage = TestStorage::new(10);
+        let storage = MemBlockDevice::new(10 * 2048, 512);
         let target = IscsiTarget::builder()
             .bind_addr("127.0.0.1:13271")
             .target_name("iqn.2025-12.test:session-type")
@@ -661,7 +457,7 @@ mod integration {
         let params = "InitiatorName=iqn.2025-12.test:initiator\0TargetName=iqn.2025-12.test:session-type\0SessionType=InvalidType\0AuthMethod=None\0";
         let padded_params = {
             let mut p = params.to_string();
-            while p.len() % 4 != 0 {
+            while !p.len().is_multiple_of(4) {
                 p.push('\0');
             }
             p.into_bytes()
@@ -707,52 +503,12 @@ mod integration {
     #[test]
     fn test_server_returns_too_many_connections() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
-
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
-            }
-        }
-
-        impl ScsiBlockDevice for TestStorage {
-            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-                let offset = (lba * block_size as u64) as usize;
-                let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                Ok(self.data[offset..offset + len].to_vec())
-            }
-
-            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-                let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                self.data[offset..offset + data.len()].copy_from_slice(data);
-                Ok(())
-            }
-
-            fn capacity(&self) -> u64 {
-                (self.data.len() / 512) as u64
-            }
-
-            fn block_size(&self) -> u32 {
-                512
-            }
-        }
-
         // Start target with low connection limit for testing
-        let storage = TestStorage::new(10);
+        let storage = MemBlockDevice::new(10 * 2048, 512);
         let target = IscsiTarget::builder()
             .bind_addr("127.0.0.1:13272")
             .target_name("iqn.2025-12.test:conn-limit")
@@ -854,55 +610,15 @@ mod integration {
     #[test]
     fn test_server_returns_invalid_request_during_login() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
         use iscsi_target::pdu::{IscsiPdu, opcode};
         use std::io::{Read as IoRead, Write as IoWrite};
         use std::net::TcpStream;
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
-
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
-            }
-        }
-
-        impl ScsiBlockDevice for TestStorage {
-            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-                let offset = (lba * block_size as u64) as usize;
-                let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                Ok(self.data[offset..offset + len].to_vec())
-            }
-
-            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-                let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                self.data[offset..offset + data.len()].copy_from_slice(data);
-                Ok(())
-            }
-
-            fn capacity(&self) -> u64 {
-                (self.data.len() / 512) as u64
-            }
-
-            fn block_size(&self) -> u32 {
-                512
-            }
-        }
-
         // Start target
-        let storage = TestStorage::new(10);
+        let storage = MemBlockDevice::new(10 * 2048, 512);
         let target = IscsiTarget::builder()
             .bind_addr("127.0.0.1:13273")
             .target_name("iqn.2025-12.test:invalid-pdu")
@@ -965,55 +681,15 @@ mod integration {
     #[test]
     fn test_server_returns_unsupported_version() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
         use iscsi_target::pdu::IscsiPdu;
         use std::io::{Read as IoRead, Write as IoWrite};
         use std::net::TcpStream;
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
-
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
-            }
-        }
-
-        impl ScsiBlockDevice for TestStorage {
-            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-                let offset = (lba * block_size as u64) as usize;
-                let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                Ok(self.data[offset..offset + len].to_vec())
-            }
-
-            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-                let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                self.data[offset..offset + data.len()].copy_from_slice(data);
-                Ok(())
-            }
-
-            fn capacity(&self) -> u64 {
-                (self.data.len() / 512) as u64
-            }
-
-            fn block_size(&self) -> u32 {
-                512
-            }
-        }
-
         // Start target
-        let storage = TestStorage::new(10);
+        let storage = MemBlockDevice::new(10 * 2048, 512);
         let target = IscsiTarget::builder()
             .bind_addr("127.0.0.1:13274")
             .target_name("iqn.2025-12.test:version-test")
@@ -1039,7 +715,7 @@ mod integration {
         let params = "InitiatorName=iqn.2025-12.test:initiator\0TargetName=iqn.2025-12.test:version-test\0SessionType=Normal\0AuthMethod=None\0";
         let padded_params = {
             let mut p = params.to_string();
-            while p.len() % 4 != 0 {
+            while !p.len().is_multiple_of(4) {
                 p.push('\0');
             }
             p.into_bytes()
@@ -1089,52 +765,12 @@ mod integration {
     #[test]
     fn test_server_returns_out_of_resources() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
-
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
-            }
-        }
-
-        impl ScsiBlockDevice for TestStorage {
-            fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
-                let offset = (lba * block_size as u64) as usize;
-                let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                Ok(self.data[offset..offset + len].to_vec())
-            }
-
-            fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
-                let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
-                self.data[offset..offset + data.len()].copy_from_slice(data);
-                Ok(())
-            }
-
-            fn capacity(&self) -> u64 {
-                (self.data.len() / 512) as u64
-            }
-
-            fn block_size(&self) -> u32 {
-                512
-            }
-        }
-
         // Start target with low session limit for testing
-        let storage = TestStorage::new(10);
+        let storage = MemBlockDevice::new(10 * 2048, 512);
         let target = IscsiTarget::builder()
             .bind_addr("127.0.0.1:13275")
             .target_name("iqn.2025-12.test:resource-limit")
@@ -1197,37 +833,338 @@ mod integration {
     #[test]
     fn test_server_returns_authorization_failure() {
         let _ = env_logger::builder().is_test(true).try_init();
-        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
         use std::thread;
         use std::time::Duration;
 
-        struct TestStorage {
-            data: Vec<u8>,
-        }
+        // Start target with ACL restricting access
+        let storage = MemBlockDevice::new(10 * 2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13276")
+            .target_name("iqn.2025-12.test:acl-test")
+            .allowed_initiators(vec![
+                "iqn.test:allowed-initiator".to_string(),
+            ])
+            .build(storage)
+            .expect("Failed to create target");
 
-        impl TestStorage {
-            fn new(size_mb: usize) -> Self {
-                TestStorage {
-                    data: vec![0u8; size_mb * 1024 * 1024],
-                }
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        // Login with allowed initiator should succeed
+        let mut client_allowed = IscsiClient::connect("127.0.0.1:13276")
+            .expect("Failed to connect allowed client");
+        client_allowed.login("iqn.test:allowed-initiator", "iqn.2025-12.test:acl-test")
+            .expect("Login with allowed initiator should succeed");
+        client_allowed.logout().ok();
+
+        // Give server time to cleanup
+        thread::sleep(Duration::from_millis(200));
+
+        // Login with non-allowed initiator should fail with AUTHORIZATION_FAILURE
+        let mut client_denied = IscsiClient::connect("127.0.0.1:13276")
+            .expect("Failed to connect denied client");
+        let result = client_denied.login("iqn.test:denied-initiator", "iqn.2025-12.test:acl-test");
+
+        match result {
+            Err(iscsi_target::IscsiError::Protocol { kind, ref message }) => {
+                assert_eq!(
+                    kind,
+                    iscsi_target::ProtocolErrorKind::LoginRejected { status_class: 0x02, status_detail: 0x02 },
+                    "Expected AUTHORIZATION_FAILURE, got: {}",
+                    message
+                );
+                assert!(message.contains("Authorization failure"), "{}", message);
             }
+            Ok(_) => panic!("Login should have failed with AUTHORIZATION_FAILURE"),
+            Err(e) => panic!("Expected Protocol error with AUTHORIZATION_FAILURE, got: {:?}", e),
+        }
+
+        // Cleanup
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    /// Test that a discovery-only portal rejects normal logins with
+    /// SESSION_TYPE_NOT_SUPPORTED (0x0209) but still answers SendTargets
+    #[test]
+    fn test_server_discovery_only_portal() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{DiscoveredTarget, IscsiTarget, MemBlockDevice};
+        use std::thread;
+        use std::time::Duration;
+
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13277")
+            .add_portal("127.0.0.1:13278", 2)
+            .add_discovery_portal("127.0.0.1:13279", 3)
+            .target_name("iqn.2025-12.test:portals")
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        // Discovery on the discovery-only portal lists the data portals only
+        let mut client = IscsiClient::connect("127.0.0.1:13279")
+            .expect("Failed to connect");
+        let targets = client.discover("iqn.test:initiator").expect("Discovery failed");
+        assert_eq!(targets, vec![DiscoveredTarget {
+            iqn: "iqn.2025-12.test:portals".to_string(),
+            portals: vec![
+                ("127.0.0.1:13277".parse().unwrap(), 1),
+                ("127.0.0.1:13278".parse().unwrap(), 2),
+            ],
+        }]);
+
+        // Normal login on the discovery-only portal is refused
+        let mut client = IscsiClient::connect("127.0.0.1:13279")
+            .expect("Failed to connect");
+        let result = client.login("iqn.test:initiator", "iqn.2025-12.test:portals");
+        let err = result.expect_err("Normal login on discovery-only portal should fail").to_string();
+        assert!(err.contains("detail=0x09"), "Error should be SESSION_TYPE_NOT_SUPPORTED: {}", err);
+
+        // Normal login on the secondary data portal succeeds
+        let mut client = IscsiClient::connect("127.0.0.1:13278")
+            .expect("Failed to connect");
+        client.login("iqn.test:initiator", "iqn.2025-12.test:portals")
+            .expect("Login on data portal should succeed");
+
+        // Cleanup
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    /// Test that a saturated portal rejects with TOO_MANY_CONNECTIONS (0x0206)
+    /// while another portal keeps accepting, and per-portal stats track it
+    #[test]
+    fn test_server_per_portal_connection_limit() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, MemBlockDevice, Portal};
+        use std::thread;
+        use std::time::Duration;
+
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .portal(Portal::new("127.0.0.1:13280", 1).with_max_connections(1))
+            .add_portal("127.0.0.1:13281", 2)
+            .target_name("iqn.2025-12.test:portal-limits")
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        // Occupy the only connection slot on the replication portal
+        let mut first = IscsiClient::connect("127.0.0.1:13280")
+            .expect("Failed to connect");
+        first.login("iqn.test:initiator", "iqn.2025-12.test:portal-limits")
+            .expect("First login should succeed");
+
+        let mut second = IscsiClient::connect("127.0.0.1:13280")
+            .expect("Failed to connect");
+        let err = second.login("iqn.test:initiator", "iqn.2025-12.test:portal-limits")
+            .expect_err("Second connection on saturated portal should fail")
+            .to_string();
+        assert!(err.contains("detail=0x06"), "Error should be TOO_MANY_CONNECTIONS: {}", err);
+
+        // The production portal is unaffected
+        let mut other = IscsiClient::connect("127.0.0.1:13281")
+            .expect("Failed to connect");
+        other.login("iqn.test:initiator", "iqn.2025-12.test:portal-limits")
+            .expect("Login on other portal should succeed");
+
+        let stats = target.portal_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].bind_addr, "127.0.0.1:13280");
+        assert_eq!(stats[0].active_connections, 1);
+        assert_eq!(stats[0].active_sessions, 1);
+        assert_eq!(stats[0].rejected_connections, 1);
+        assert_eq!(stats[1].active_sessions, 1);
+        assert_eq!(stats[1].rejected_connections, 0);
+
+        // Cleanup
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    /// Test that a PDU larger than the declared MaxRecvDataSegmentLength is
+    /// answered with a Reject (PROTOCOL_ERROR) and the connection stays usable
+    #[test]
+    fn test_server_rejects_oversized_data_segment() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
+        use iscsi_target::pdu::{self, IscsiPdu, opcode, flags};
+        use std::thread;
+        use std::time::Duration;
+
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13282")
+            .target_name("iqn.2025-12.test:mrdsl")
+            .max_recv_data_segment_length(1024)
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = IscsiClient::connect("127.0.0.1:13282")
+            .expect("Failed to connect");
+        client.login("iqn.test:initiator", "iqn.2025-12.test:mrdsl")
+            .expect("Login should succeed");
+
+        let nop_out = |client: &IscsiClient, itt: u32, data: Vec<u8>| {
+            let mut pdu = IscsiPdu::new();
+            pdu.opcode = opcode::NOP_OUT;
+            pdu.immediate = true;
+            pdu.flags = flags::FINAL;
+            pdu.itt = itt;
+            pdu.specific[0..4].copy_from_slice(&0xFFFF_FFFFu32.to_be_bytes());
+            pdu.specific[4..8].copy_from_slice(&client.cmd_sn().to_be_bytes());
+            pdu.specific[8..12].copy_from_slice(&client.exp_stat_sn().to_be_bytes());
+            pdu.data = data;
+            pdu
+        };
+
+        // 4 KiB ping exceeds the 1 KiB limit
+        let oversized = nop_out(&client, 0x100, vec![0xAB; 4096]);
+        client.send_raw_pdu(&oversized).expect("Failed to send NOP-Out");
+        let response = client.recv_pdu().expect("Expected a Reject PDU");
+        assert_eq!(response.opcode, opcode::REJECT, "Oversized PDU should be rejected");
+        assert_eq!(response.reject_reason(), pdu::reject_reason::PROTOCOL_ERROR);
+        assert_eq!(&response.data[16..20], &0x100u32.to_be_bytes(), "Reject should carry the rejected header");
+
+        // The connection is still in sync and usable
+        let ping = nop_out(&client, 0x101, vec![0xCD; 512]);
+        client.send_raw_pdu(&ping).expect("Failed to send NOP-Out");
+        let response = client.recv_pdu().expect("Expected a NOP-In");
+        assert_eq!(response.opcode, opcode::NOP_IN);
+        assert_eq!(response.itt, 0x101);
+
+        // Cleanup
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    #[test]
+    fn test_server_keepalive_drops_unresponsive_connection() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
+        use iscsi_target::pdu::{IscsiPdu, opcode, flags};
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13283")
+            .target_name("iqn.2025-12.test:keepalive")
+            .keepalive(Duration::from_millis(300), Duration::from_millis(300))
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = IscsiClient::connect("127.0.0.1:13283")
+            .expect("Failed to connect");
+        client.login("iqn.test:initiator", "iqn.2025-12.test:keepalive")
+            .expect("Login should succeed");
+
+        // Idle connection gets a ping; answering it keeps the connection up
+        for _ in 0..2 {
+            let ping = client.recv_pdu().expect("Expected a keepalive NOP-In");
+            assert_eq!(ping.opcode, opcode::NOP_IN);
+            assert_eq!(ping.itt, 0xFFFF_FFFF, "Target-initiated NOP-In uses the reserved ITT");
+            let ttt = &ping.specific[0..4];
+            assert_ne!(ttt, &0xFFFF_FFFFu32.to_be_bytes(), "Ping must carry a TTT");
+
+            let mut reply = IscsiPdu::new();
+            reply.opcode = opcode::NOP_OUT;
+            reply.immediate = true;
+            reply.flags = flags::FINAL;
+            reply.itt = 0xFFFF_FFFF;
+            reply.specific[0..4].copy_from_slice(ttt);
+            reply.specific[4..8].copy_from_slice(&client.cmd_sn().to_be_bytes());
+            reply.specific[8..12].copy_from_slice(&client.exp_stat_sn().to_be_bytes());
+            client.send_raw_pdu(&reply).expect("Failed to send NOP-Out");
         }
+        assert_eq!(target.active_session_count(), 1);
 
-        impl ScsiBlockDevice for TestStorage {
+        // Ignore the next ping: the target drops the connection
+        let ping = client.recv_pdu().expect("Expected a keepalive NOP-In");
+        assert_eq!(ping.opcode, opcode::NOP_IN);
+        let start = Instant::now();
+        assert!(client.recv_pdu().is_err(), "Connection should be closed");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(target.active_session_count(), 0, "Session slot should be released");
+
+        // Cleanup
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    #[test]
+    fn test_server_queued_commands_and_task_set_full() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, ScsiBlockDevice, ScsiResult};
+        use iscsi_target::pdu::{IscsiPdu, opcode, flags, scsi_status};
+        use std::thread;
+        use std::time::Duration;
+
+        /// Storage whose reads take long enough to overlap other PDUs
+        struct SlowStorage {
+            data: Vec<u8>,
+        }
+
+        impl ScsiBlockDevice for SlowStorage {
             fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+                thread::sleep(Duration::from_millis(500));
                 let offset = (lba * block_size as u64) as usize;
                 let len = (blocks * block_size) as usize;
-                if offset + len > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
                 Ok(self.data[offset..offset + len].to_vec())
             }
 
             fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
                 let offset = (lba * block_size as u64) as usize;
-                if offset + data.len() > self.data.len() {
-                    return Err(iscsi_target::IscsiError::Scsi("Out of bounds".into()));
-                }
                 self.data[offset..offset + data.len()].copy_from_slice(data);
                 Ok(())
             }
@@ -1241,14 +1178,12 @@ mod integration {
             }
         }
 
-        // Start target with ACL restricting access
-        let storage = TestStorage::new(10);
+        let storage = SlowStorage { data: vec![0u8; 1024 * 1024] };
         let target = IscsiTarget::builder()
-            .bind_addr("127.0.0.1:13276")
-            .target_name("iqn.2025-12.test:acl-test")
-            .allowed_initiators(vec![
-                "iqn.test:allowed-initiator".to_string(),
-            ])
+            .bind_addr("127.0.0.1:13284")
+            .target_name("iqn.2025-12.test:queue")
+            .queue_depth(1)
+            .worker_threads(2)
             .build(storage)
             .expect("Failed to create target");
 
@@ -1262,35 +1197,663 @@ mod integration {
         // Give target time to start
         thread::sleep(Duration::from_millis(500));
 
-        // Login with allowed initiator should succeed
-        let mut client_allowed = IscsiClient::connect("127.0.0.1:13276")
-            .expect("Failed to connect allowed client");
-        client_allowed.login("iqn.test:allowed-initiator", "iqn.2025-12.test:acl-test")
-            .expect("Login with allowed initiator should succeed");
-        client_allowed.logout().ok();
+        let mut client = IscsiClient::connect("127.0.0.1:13284")
+            .expect("Failed to connect");
+        client.login("iqn.test:initiator", "iqn.2025-12.test:queue")
+            .expect("Login should succeed");
+
+        // Two one-block READ(10)s without waiting: the second overflows the queue
+        for (itt, cmd_sn) in [(1u32, client.cmd_sn()), (2, client.cmd_sn().wrapping_add(1))] {
+            let mut read = IscsiPdu::new();
+            read.opcode = opcode::SCSI_COMMAND;
+            read.flags = flags::FINAL | flags::READ | 0x01; // SIMPLE task
+            read.itt = itt;
+            read.specific[0..4].copy_from_slice(&512u32.to_be_bytes());
+            read.specific[4..8].copy_from_slice(&cmd_sn.to_be_bytes());
+            read.specific[8..12].copy_from_slice(&client.exp_stat_sn().to_be_bytes());
+            read.specific[12..22].copy_from_slice(&[0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+            client.send_raw_pdu(&read).expect("Failed to send READ(10)");
+        }
 
-        // Give server time to cleanup
-        thread::sleep(Duration::from_millis(200));
+        // A NOP-Out sent while the first READ is still executing
+        let mut nop = IscsiPdu::new();
+        nop.opcode = opcode::NOP_OUT;
+        nop.immediate = true;
+        nop.flags = flags::FINAL;
+        nop.itt = 3;
+        nop.specific[0..4].copy_from_slice(&0xFFFF_FFFFu32.to_be_bytes());
+        nop.specific[4..8].copy_from_slice(&client.cmd_sn().wrapping_add(2).to_be_bytes());
+        nop.specific[8..12].copy_from_slice(&client.exp_stat_sn().to_be_bytes());
+        client.send_raw_pdu(&nop).expect("Failed to send NOP-Out");
+
+        let full = client.recv_pdu().expect("Expected SCSI Response");
+        assert_eq!(full.opcode, opcode::SCSI_RESPONSE);
+        assert_eq!(full.itt, 2);
+        assert_eq!(full.specific[1], scsi_status::TASK_SET_FULL);
+
+        let pong = client.recv_pdu().expect("Expected NOP-In");
+        assert_eq!(pong.opcode, opcode::NOP_IN);
+        assert_eq!(pong.itt, 3, "NOP-Out must not wait for the queued READ");
+
+        let data_in = client.recv_pdu().expect("Expected Data-In");
+        assert_eq!(data_in.opcode, opcode::SCSI_DATA_IN);
+        assert_eq!(data_in.itt, 1);
+        assert_eq!(data_in.data.len(), 512);
 
-        // Login with non-allowed initiator should fail with AUTHORIZATION_FAILURE
-        let mut client_denied = IscsiClient::connect("127.0.0.1:13276")
-            .expect("Failed to connect denied client");
-        let result = client_denied.login("iqn.test:denied-initiator", "iqn.2025-12.test:acl-test");
+        // Cleanup
+        target.stop();
+        target_thread.join().ok();
+    }
 
-        match result {
-            Err(iscsi_target::IscsiError::Protocol(ref msg)) => {
-                assert!(
-                    msg.contains("AUTHORIZATION_FAILURE") || msg.contains("Authorization failure"),
-                    "Expected AUTHORIZATION_FAILURE error, got: {}",
-                    msg
-                );
+    #[test]
+    fn test_server_client_helpers_with_data_digest() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
+        use std::thread;
+        use std::time::Duration;
+
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13285")
+            .target_name("iqn.2025-12.test:client")
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = IscsiClient::connect("127.0.0.1:13285")
+            .expect("Failed to connect");
+        client.set_data_digest(true);
+        client.login("iqn.test:initiator", "iqn.2025-12.test:client")
+            .expect("Login should succeed");
+        assert!(client.data_digest(), "Target should accept CRC32C data digests");
+
+        let inquiry = client.inquiry().expect("INQUIRY should succeed");
+        assert_eq!(inquiry.peripheral_device_type, 0x00);
+        assert!(!inquiry.vendor.is_empty());
+
+        let capacity = client.read_capacity().expect("READ CAPACITY should succeed");
+        assert_eq!(capacity.blocks, 2048);
+        assert_eq!(capacity.block_size, 512);
+
+        let pattern: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        client.write_blocks(100, &pattern).expect("WRITE should succeed");
+        let data = client.read_blocks(100, 8).expect("READ should succeed");
+        assert_eq!(data, pattern);
+
+        // Out of range reads surface the sense data
+        let err = client.read_blocks(2048, 1).expect_err("READ past the end should fail");
+        assert!(err.to_string().contains("sense key 0x05"), "unexpected error: {}", err);
+
+        client.logout().expect("Logout should succeed");
+
+        // Cleanup
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    #[test]
+    fn test_server_client_chap_login() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
+        use std::thread;
+        use std::time::Duration;
+
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13286")
+            .target_name("iqn.2025-12.test:chap-client")
+            .chap_account("alice", "alice-secret-1")
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        // Wrong secret is rejected
+        let mut client = IscsiClient::connect("127.0.0.1:13286")
+            .expect("Failed to connect");
+        let result = client.login_chap(
+            "iqn.test:initiator", "iqn.2025-12.test:chap-client", "alice", "wrong-secret-1",
+        );
+        assert!(result.is_err(), "Login with the wrong secret should fail");
+
+        let mut client = IscsiClient::connect("127.0.0.1:13286")
+            .expect("Failed to connect");
+        client.login_chap(
+            "iqn.test:initiator", "iqn.2025-12.test:chap-client", "alice", "alice-secret-1",
+        ).expect("CHAP login should succeed");
+        assert!(client.is_logged_in());
+
+        let capacity = client.read_capacity().expect("READ CAPACITY should succeed");
+        assert_eq!(capacity.blocks, 2048);
+        client.logout().expect("Logout should succeed");
+
+        // Cleanup
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    #[test]
+    fn test_server_apply_config_drains_removed_initiator() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
+        use iscsi_target::pdu::{opcode, async_event};
+        use std::thread;
+        use std::time::Duration;
+
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13287")
+            .target_name("iqn.2025-12.test:reload")
+            .allowed_initiators(vec!["iqn.test:alice".to_string(), "iqn.test:bob".to_string()])
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        let mut alice = IscsiClient::connect("127.0.0.1:13287")
+            .expect("Failed to connect");
+        alice.login("iqn.test:alice", "iqn.2025-12.test:reload")
+            .expect("Alice should log in");
+        let mut bob = IscsiClient::connect("127.0.0.1:13287")
+            .expect("Failed to connect");
+        bob.login("iqn.test:bob", "iqn.2025-12.test:reload")
+            .expect("Bob should log in");
+
+        // Drop Bob from the ACL while both sessions are running
+        let control = target.control();
+        let mut config = control.config();
+        config.allowed_initiators = Some(vec!["iqn.test:alice".to_string()]);
+        let changes = control.apply_config(config).expect("Config should apply");
+        assert!(changes.acl_changed);
+        assert_eq!(changes.drained_sessions, 1);
+
+        let request = bob.recv_pdu().expect("Bob should be asked to log out");
+        assert_eq!(request.opcode, opcode::ASYNC_MESSAGE);
+        assert_eq!(request.specific[16], async_event::LOGOUT_REQUEST);
+        bob.logout().expect("Bob should log out");
+
+        // Alice is unaffected
+        alice.read_capacity().expect("Alice's session should keep working");
+
+        // Bob can no longer log in
+        let mut bob = IscsiClient::connect("127.0.0.1:13287")
+            .expect("Failed to connect");
+        assert!(bob.login("iqn.test:bob", "iqn.2025-12.test:reload").is_err());
+
+        alice.logout().expect("Alice should log out");
+
+        // Cleanup
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    #[test]
+    fn test_server_pdu_trace_capture() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
+        use std::thread;
+        use std::time::Duration;
+
+        let trace_path = std::env::temp_dir().join(format!("iscsi-trace-{}.pcapng", std::process::id()));
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13288")
+            .target_name("iqn.2025-12.test:trace")
+            .pdu_trace(&trace_path)
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = IscsiClient::connect("127.0.0.1:13288")
+            .expect("Failed to connect");
+        client.login("iqn.test:tracer", "iqn.2025-12.test:trace")
+            .expect("Login should succeed");
+        client.read_capacity().expect("READ CAPACITY should succeed");
+        client.logout().expect("Logout should succeed");
+
+        target.stop();
+        target_thread.join().ok();
+
+        let capture = std::fs::read(&trace_path).expect("Trace file should exist");
+        std::fs::remove_file(&trace_path).ok();
+
+        // pcapng section header, then one packet per PDU in each direction
+        assert_eq!(&capture[0..4], &[0x0A, 0x0D, 0x0D, 0x0A]);
+        let mut offset = 0;
+        let mut packets = 0;
+        while offset < capture.len() {
+            let block_type = u32::from_le_bytes(capture[offset..offset + 4].try_into().unwrap());
+            let length = u32::from_le_bytes(capture[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            if block_type == 6 {
+                packets += 1;
             }
-            Ok(_) => panic!("Login should have failed with AUTHORIZATION_FAILURE"),
-            Err(e) => panic!("Expected Protocol error with AUTHORIZATION_FAILURE, got: {:?}", e),
+            offset += length;
         }
+        assert_eq!(offset, capture.len());
+        // Login, READ CAPACITY and Logout requests and responses
+        assert!(packets >= 6, "expected at least 6 packets, got {}", packets);
 
-        // Cleanup
+        let text = String::from_utf8_lossy(&capture);
+        assert!(text.contains("initiator->target"));
+        assert!(text.contains("target->initiator initiator=iqn.test:tracer"));
+    }
+
+    /// Test that a login with a non-zero TSIH is matched against live sessions
+    #[test]
+    fn test_server_login_with_existing_tsih() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::pdu::IscsiPdu;
+        use iscsi_target::{IscsiTarget, NullBlockDevice};
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::thread;
+        use std::time::Duration;
+
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13289")
+            .target_name("iqn.2025-12.test:tsih")
+            .build(NullBlockDevice::new(2048, 512))
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+
+        // Give target time to start
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = IscsiClient::connect("127.0.0.1:13289")
+            .expect("Failed to connect");
+        client.login("iqn.test:tsih", "iqn.2025-12.test:tsih")
+            .expect("Login should succeed");
+
+        let sessions = target.control().sessions();
+        assert_eq!(sessions.len(), 1);
+        let live = &sessions[0];
+        assert_ne!(live.tsih, 0);
+        assert_eq!(target.control().find_session("iqn.test:tsih", live.isid), Some(live.tsih));
+
+        let login_status = |tsih: u16, cid: u16| {
+            let mut stream = TcpStream::connect("127.0.0.1:13289").expect("Failed to connect");
+            let mut params = b"InitiatorName=iqn.test:tsih\0TargetName=iqn.2025-12.test:tsih\0AuthMethod=None\0".to_vec();
+            params.resize(params.len().div_ceil(4) * 4, 0);
+            let login_pdu = IscsiPdu::login_request(live.isid, tsih, cid, 0, 0, 0, 1, true, params);
+            stream.write_all(&login_pdu.to_bytes()).expect("Failed to write PDU");
+            let mut bhs = [0u8; 48];
+            stream.read_exact(&mut bhs).expect("Failed to read response BHS");
+            (bhs[36], bhs[37])
+        };
+
+        // A second connection to the session: TOO_MANY_CONNECTIONS (0x0206)
+        assert_eq!(login_status(live.tsih, live.cid.wrapping_add(1)), (0x02, 0x06));
+        // A TSIH nobody holds: SESSION_DOES_NOT_EXIST (0x020A)
+        assert_eq!(login_status(live.tsih.wrapping_add(1).max(1), live.cid), (0x02, 0x0A));
+
+        // The original session is unaffected
+        client.read_capacity().expect("READ CAPACITY should succeed");
+        client.logout().expect("Logout should succeed");
+
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    /// Test that a login continuing a session cannot drain it without authenticating
+    #[test]
+    fn test_server_failed_chap_does_not_reinstate() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::pdu::IscsiPdu;
+        use iscsi_target::{duplex, IscsiTarget, NullBlockDevice, Transport};
+        use std::time::Duration;
+
+        let target = IscsiTarget::builder()
+            .target_name("iqn.2025-12.test:reinstate")
+            .chap_account("alice", "alice-secret-1")
+            .build(NullBlockDevice::new(2048, 512))
+            .expect("Failed to create target");
+
+        let (initiator_end, target_end) = duplex();
+        initiator_end.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        target.serve_stream(target_end).expect("serve_stream should start");
+        let mut client = IscsiClient::from_stream(initiator_end);
+        client.login_chap("iqn.test:alice", "iqn.2025-12.test:reinstate", "alice", "alice-secret-1")
+            .expect("CHAP login should succeed");
+        let live = target.control().sessions().remove(0);
+
+        // Same ISID, TSIH and CID as the live session, but the wrong secret
+        let (initiator_end, target_end) = duplex();
+        initiator_end.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        target.serve_stream(target_end).expect("serve_stream should start");
+        let mut mallory = IscsiClient::from_stream(initiator_end);
+        let mut login = |keys: &str, cmd_sn: u32| {
+            let mut data = keys.replace(' ', "\0").into_bytes();
+            data.push(0);
+            let pdu = IscsiPdu::login_request(live.isid, live.tsih, live.cid, cmd_sn, 0, 0, 1, false, data);
+            mallory.send_raw_pdu(&pdu).expect("Failed to send login");
+            mallory.recv_pdu().expect("Failed to read login response")
+        };
+        let response = login("InitiatorName=iqn.test:alice TargetName=iqn.2025-12.test:reinstate AuthMethod=CHAP", 0);
+        assert_eq!(response.specific[16], 0x00);
+        let response = login("CHAP_A=7,5", 0);
+        assert_eq!(response.specific[16], 0x00);
+        let response = login("CHAP_N=alice CHAP_R=0x00112233445566778899aabbccddeeff", 0);
+        assert_eq!((response.specific[16], response.specific[17]), (0x02, 0x01));
+
+        // The live session was neither drained nor taken over
+        let sessions = target.control().sessions();
+        assert_eq!(sessions.len(), 1);
+        assert!(!sessions[0].draining);
+        client.read_capacity().expect("READ CAPACITY should succeed");
+        client.logout().expect("Logout should succeed");
+
+        target.stop();
+    }
+
+    /// Test a login shaped like the Microsoft iSCSI initiator's, with header digests afterwards
+    #[test]
+    fn test_server_windows_style_login() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::pdu::{self, Digests, IscsiPdu};
+        use iscsi_target::{IscsiTarget, NullBlockDevice};
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::thread;
+        use std::time::Duration;
+
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13291")
+            .target_name("iqn.2025-12.test:windows")
+            .build(NullBlockDevice::new(2048, 512))
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let mut stream = TcpStream::connect("127.0.0.1:13291").expect("Failed to connect");
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut exchange = |request: &IscsiPdu, header_digest: bool| {
+            let digests = Digests { header: header_digest, data: false };
+            stream.write_all(&request.to_bytes_with_digests(digests)).expect("Failed to write PDU");
+            let mut bhs = [0u8; 48];
+            stream.read_exact(&mut bhs).expect("Failed to read BHS");
+            let data_length = u32::from_be_bytes([0, bhs[5], bhs[6], bhs[7]]) as usize;
+            let digest_length = if header_digest { 4 } else { 0 };
+            let mut rest = vec![0u8; digest_length + data_length.div_ceil(4) * 4];
+            stream.read_exact(&mut rest).expect("Failed to read data segment");
+            let params = pdu::parse_text_parameters(&rest[digest_length..digest_length + data_length]).unwrap_or_default();
+            (bhs, params)
+        };
+        let login = |csg: u8, nsg: u8, cmd_sn: u32, keys: &[(&str, &str)]| {
+            let data = pdu::serialize_text_parameters(&keys.iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<Vec<_>>());
+            IscsiPdu::login_request([0x40, 0, 1, 0x37, 0, 1], 0, 1, cmd_sn, 0, csg, nsg, true, data)
+        };
+
+        // Security stage: AuthMethod=None is answered and the transition granted
+        let (bhs, params) = exchange(&login(0, 1, 0, &[
+            ("InitiatorName", "iqn.1991-05.com.microsoft:host"),
+            ("InitiatorAlias", "host"),
+            ("SessionType", "Normal"),
+            ("TargetName", "iqn.2025-12.test:windows"),
+            ("AuthMethod", "None"),
+        ]), false);
+        assert_eq!((bhs[36], bhs[37]), (0, 0));
+        assert_eq!(bhs[1] & 0x80, 0x80, "security stage transit refused");
+        assert_eq!(params, vec![("AuthMethod".to_string(), "None".to_string())]);
+
+        let (bhs, params) = exchange(&login(1, 3, 0, &[
+            ("HeaderDigest", "CRC32C,None"),
+            ("DataDigest", "None"),
+            ("ErrorRecoveryLevel", "0"),
+            ("MaxConnections", "4"),
+            ("MaxRecvDataSegmentLength", "65536"),
+            ("IFMarker", "No"),
+            ("OFMarker", "No"),
+        ]), false);
+        assert_eq!((bhs[36], bhs[37]), (0, 0));
+        assert_eq!(bhs[1] & 0x83, 0x83, "full feature phase transit refused");
+        let answered: Vec<&str> = params.iter().take(7).map(|(key, value)| {
+            assert_ne!(value, "NotUnderstood", "{} not understood", key);
+            key.as_str()
+        }).collect();
+        assert_eq!(answered, vec![
+            "HeaderDigest", "DataDigest", "ErrorRecoveryLevel", "MaxConnections",
+            "MaxRecvDataSegmentLength", "IFMarker", "OFMarker",
+        ]);
+        assert!(params.contains(&("HeaderDigest".to_string(), "CRC32C".to_string())));
+        assert!(params.contains(&("MaxConnections".to_string(), "1".to_string())));
+
+        // Full Feature Phase runs with header digests
+        let mut nop_out = IscsiPdu::new();
+        nop_out.opcode = pdu::opcode::NOP_OUT;
+        nop_out.immediate = true;
+        nop_out.flags = pdu::flags::FINAL;
+        nop_out.itt = 0x1234;
+        nop_out.specific[0..4].copy_from_slice(&0xFFFF_FFFFu32.to_be_bytes());
+        nop_out.specific[4..8].copy_from_slice(&1u32.to_be_bytes());
+        let (bhs, _) = exchange(&nop_out, true);
+        assert_eq!(bhs[0] & 0x3F, pdu::opcode::NOP_IN);
+        assert_eq!(u32::from_be_bytes([bhs[16], bhs[17], bhs[18], bhs[19]]), 0x1234);
+
+        target.stop();
+        target_thread.join().ok();
+    }
+
+    /// Test that shutdown_and_wait reports sessions that logged out and closes the rest
+    #[test]
+    fn test_server_shutdown_and_wait() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let storage = MemBlockDevice::new(10 * 2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13290")
+            .target_name("iqn.2025-12.test:shutdown-wait")
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = std::sync::Arc::new(target);
+        let target_clone = target.clone();
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let mut polite = IscsiClient::connect("127.0.0.1:13290").expect("Failed to connect");
+        polite.login("iqn.test:polite", "iqn.2025-12.test:shutdown-wait").expect("Login failed");
+        let mut idle = IscsiClient::connect("127.0.0.1:13290").expect("Failed to connect");
+        idle.login("iqn.test:idle", "iqn.2025-12.test:shutdown-wait").expect("Login failed");
+
+        // One initiator logs out shortly after shutdown starts, the other never does
+        let polite_thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            polite.logout().ok();
+        });
+
+        let started = Instant::now();
+        let report = target.shutdown_and_wait(Duration::from_secs(2));
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        polite_thread.join().unwrap();
+
+        assert_eq!(report.logged_out.len(), 1);
+        assert_eq!(report.logged_out[0].initiator_name, "iqn.test:polite");
+        assert_eq!(report.terminated.len(), 1);
+        assert_eq!(report.terminated[0].initiator_name, "iqn.test:idle");
+        assert_eq!(report.connections_closed, 1);
+        assert!(!target.is_running());
+
+        // The idle initiator's connection is gone
+        assert!(idle.read_capacity().is_err());
+        target_thread.join().ok();
+    }
+
+    /// Test that an audit sink receives a record for each SCSI command
+    #[test]
+    fn test_server_audit_sink() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{AuditRecord, AuditSink, IscsiTarget, MemBlockDevice};
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<AuditRecord>>);
+
+        impl AuditSink for Recorder {
+            fn record(&self, records: &[AuditRecord]) {
+                self.0.lock().unwrap().extend_from_slice(records);
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .bind_addr("127.0.0.1:13292")
+            .target_name("iqn.2025-12.test:audit")
+            .audit_sink(recorder.clone())
+            .build(storage)
+            .expect("Failed to create target");
+
+        let target = Arc::new(target);
+        let target_clone = target.clone();
+        let target_thread = thread::spawn(move || {
+            target_clone.run()
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = IscsiClient::connect("127.0.0.1:13292").expect("Failed to connect");
+        client.login("iqn.test:auditor", "iqn.2025-12.test:audit").expect("Login failed");
+        client.write_blocks(16, &[0xA5; 1024]).expect("WRITE should succeed");
+        client.read_blocks(16, 2).expect("READ should succeed");
+        assert!(client.read_blocks(4096, 1).is_err(), "READ past the end should fail");
+        client.logout().expect("Logout should succeed");
+
+        // Records are handed over when the connection closes
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while target.active_connection_count() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
         target.stop();
         target_thread.join().ok();
+
+        let records = recorder.0.lock().unwrap();
+        let io: Vec<_> = records.iter()
+            .filter(|record| matches!(record.opcode, 0x28 | 0x2A | 0x88 | 0x8A))
+            .map(|record| (record.lba, record.blocks, record.status))
+            .collect();
+        assert_eq!(io, vec![(Some(16), 2, 0x00), (Some(16), 2, 0x00), (Some(4096), 1, 0x02)]);
+        assert!(records.iter().all(|record| &*record.initiator_name == "iqn.test:auditor"));
+    }
+
+    #[test]
+    fn test_server_over_duplex_stream() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{duplex, IscsiTarget, MemBlockDevice, Transport};
+        use std::time::{Duration, Instant};
+
+        // No listener: the target serves an in-memory stream
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .target_name("iqn.2025-12.test:duplex")
+            .build(storage)
+            .expect("Failed to create target");
+
+        let (initiator_end, target_end) = duplex();
+        initiator_end.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        target.serve_stream(target_end).expect("serve_stream should start");
+
+        let mut client = IscsiClient::from_stream(initiator_end);
+        client.login("iqn.test:duplex", "iqn.2025-12.test:duplex").expect("Login failed");
+        client.write_blocks(8, &[0x3C; 2048]).expect("WRITE should succeed");
+        let data = client.read_blocks(8, 4).expect("READ should succeed");
+        assert_eq!(data, vec![0x3C; 2048]);
+        client.logout().expect("Logout should succeed");
+        drop(client);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while target.active_connection_count() > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(target.active_connection_count(), 0);
+        assert_eq!(target.lun_stats(0).unwrap().bytes_written, 2048);
+        target.stop();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_server_handles_caller_accepted_connection() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        use iscsi_target::{IscsiTarget, MemBlockDevice};
+        use std::os::unix::net::UnixStream;
+        use std::thread;
+        use std::time::Duration;
+
+        let storage = MemBlockDevice::new(2048, 512);
+        let target = IscsiTarget::builder()
+            .target_name("iqn.2025-12.test:embedded")
+            .build(storage)
+            .expect("Failed to create target");
+
+        // The embedder owns the socket; the connection runs on its thread
+        let (initiator_end, target_end) = UnixStream::pair().unwrap();
+        initiator_end.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        thread::scope(|scope| {
+            let connection = scope.spawn(|| target.handle_connection(target_end));
+
+            let mut client = IscsiClient::from_stream(initiator_end);
+            client.login("iqn.test:embedder", "iqn.2025-12.test:embedded").expect("Login failed");
+            assert_eq!(target.active_session_count(), 1);
+            client.write_blocks(0, &[0x7E; 512]).expect("WRITE should succeed");
+            assert_eq!(client.read_blocks(0, 1).expect("READ should succeed"), vec![0x7E; 512]);
+            client.logout().expect("Logout should succeed");
+            drop(client);
+
+            connection.join().unwrap().expect("handle_connection should succeed");
+        });
+        assert_eq!(target.active_connection_count(), 0);
+        assert_eq!(target.active_session_count(), 0);
     }
 }